@@ -118,6 +118,7 @@ func main() {
 	router.GET("/passes/:id", respondToGetSinglePass)
 	router.POST("/passes", respondToPostPasses)
 	router.DELETE("/passes/:id", respondToDeletePass)
+	router.GET("/tours/:id", respondToGetTour)
 	router.GET("/primes/v1/:num", makeRespondToCheckPrime(false))
 	router.GET("/primes/v2/:num", makeRespondToCheckPrime(true))
 	router.GET("/ping", func(c *gin.Context) {
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS tour_pass;
+DROP TABLE IF EXISTS tour;
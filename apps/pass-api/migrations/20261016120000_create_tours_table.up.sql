@@ -0,0 +1,26 @@
+CREATE TABLE IF NOT EXISTS tour (
+                                    id SERIAL PRIMARY KEY,
+                                    name VARCHAR(255) NOT NULL UNIQUE
+);
+
+CREATE TABLE IF NOT EXISTS tour_pass (
+                                         tour_id INT NOT NULL REFERENCES tour (id) ON DELETE CASCADE,
+                                         position INT NOT NULL,
+                                         pass_id INT NOT NULL REFERENCES mountain_pass (id) ON DELETE CASCADE,
+                                         PRIMARY KEY (tour_id, position)
+);
+
+INSERT INTO tour (name) VALUES ('Four Passes'), ('Engadin Loop');
+
+INSERT INTO tour_pass (tour_id, position, pass_id)
+SELECT t.id, stops.position, p.id
+FROM (VALUES ('Four Passes', 1, 'Grimsel Pass'),
+             ('Four Passes', 2, 'Furka Pass'),
+             ('Four Passes', 3, 'Gotthard Pass'),
+             ('Four Passes', 4, 'Susten Pass'),
+             ('Engadin Loop', 1, 'Julier Pass'),
+             ('Engadin Loop', 2, 'Albula Pass'),
+             ('Engadin Loop', 3, 'Flüela Pass'),
+             ('Engadin Loop', 4, 'Bernina Pass')) AS stops (tour, position, pass)
+JOIN tour t ON t.name = stops.tour
+JOIN mountain_pass p ON p.name = stops.pass;
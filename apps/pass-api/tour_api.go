@@ -0,0 +1,43 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the Apache License Version 2.0.
+// This product includes software developed at Datadog (https://www.datadoghq.com/).
+// Copyright 2024 Datadog, Inc.
+
+package main
+
+import (
+	"github.com/gin-gonic/gin"
+	"github.com/sirupsen/logrus"
+	"net/http"
+)
+
+type tour struct {
+	ID     int            `json:"id"`
+	Name   string         `json:"name"`
+	Passes []mountainPass `json:"passes" db:"-"`
+}
+
+func respondToGetTour(c *gin.Context) {
+	ctx := c.Request.Context()
+	logrus.WithContext(ctx).Info("Fetching tour")
+
+	id := c.Param("id")
+	var t tour
+	err := db.GetContext(ctx, &t, "SELECT id, name FROM tour WHERE id=$1", id)
+	if err != nil {
+		c.JSON(http.StatusNotFound, gin.H{"error": "Tour not found"})
+		return
+	}
+
+	// The passes in the order they're ridden
+	t.Passes = []mountainPass{}
+	err = db.SelectContext(ctx, &t.Passes,
+		"SELECT p.* FROM mountain_pass p JOIN tour_pass tp ON tp.pass_id = p.id WHERE tp.tour_id=$1 ORDER BY tp.position",
+		id)
+	if err != nil {
+		c.JSON(http.StatusInternalServerError, gin.H{"error": err.Error()})
+		return
+	}
+
+	c.IndentedJSON(http.StatusOK, t)
+}
@@ -0,0 +1,7 @@
+// Compiles the gRPC API. protoc comes from protoc-bin-vendored so builds don't depend on
+// one being installed.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    tonic_build::compile_protos("proto/pass_image.proto")?;
+    Ok(())
+}
@@ -0,0 +1,215 @@
+// ! # pass-image-cli
+// ! Renders images to local files with the same core as pass-image-api, for generating
+// ! assets in CI and documentation. A render is described either on the command line:
+// !
+// !   pass-image-cli --long 8.1 --lat 46.6 --size 512 --filter sepia -o scheidegg.png
+// !
+// ! where every --name value pair is a field of the POST /images body, or by a JSON spec
+// ! file holding one such body, or a list of them, each with an "output" path:
+// !
+// !   pass-image-cli --spec renders.json
+// !
+// ! Argument values are read as JSON where they parse as JSON (numbers, booleans, ...) and
+// ! as strings otherwise, so strings that look like JSON need quoting, e.g.
+// ! --border_color '"000000"'. @path reads the value from a JSON file, e.g. for GeoJSON
+// ! overlays. Tiles come from the same TILESET_<NAME>_SOURCE configuration as the
+// ! service, so MBTiles or tile directories make renders fully offline.
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::env;
+use std::fs;
+use tile_render::fetcher::TileSources;
+use tile_render::request::ImageRequest;
+use tile_render::tiles::fetch_image_from_point;
+use tile_render::{coverage, icc, registry, watermark, wmts};
+
+const USAGE: &str = "Usage:
+  pass-image-cli --long <long> --lat <lat> --size <px> [--<param> <value>...] -o <file>
+  pass-image-cli --spec <file.json>";
+
+// A render and where to write it
+#[derive(Debug, Deserialize)]
+struct Render {
+    output: String,
+    #[serde(flatten)]
+    request: ImageRequest,
+}
+
+// A spec file holds a single render or a list of them
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Spec {
+    One(Box<Render>),
+    Many(Vec<Render>),
+}
+
+impl Spec {
+    fn into_renders(self) -> Vec<Render> {
+        match self {
+            Spec::One(render) => vec![*render],
+            Spec::Many(renders) => renders,
+        }
+    }
+}
+
+// Turns the command line into the renders it asks for
+fn parse_args(args: &[String]) -> Result<Vec<Render>> {
+    if let [flag, path] = args {
+        if flag == "--spec" {
+            let spec: Spec = serde_json::from_value(read_json(path)?)
+                .with_context(|| format!("parsing spec {}", path))?;
+            return Ok(spec.into_renders());
+        }
+    }
+
+    let mut fields = Map::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let name = match arg.as_str() {
+            "-o" => "output",
+            "--size" => "size_px",
+            flag => flag
+                .strip_prefix("--")
+                .filter(|name| !name.is_empty())
+                .ok_or_else(|| anyhow!("Unexpected argument {}", arg))?,
+        };
+        let value = args
+            .next()
+            .ok_or_else(|| anyhow!("{} needs a value", arg))?;
+        fields.insert(name.to_string(), parse_value(value)?);
+    }
+    if fields.is_empty() {
+        return Err(anyhow!("Nothing to render"));
+    }
+    let render = serde_json::from_value(Value::Object(fields))?;
+    Ok(vec![render])
+}
+
+// Reads an argument as JSON if it is JSON, from a file if it starts with @, and as a
+// string otherwise
+fn parse_value(value: &str) -> Result<Value> {
+    match value.strip_prefix('@') {
+        Some(path) => read_json(path),
+        None => Ok(serde_json::from_str(value).unwrap_or_else(|_| Value::from(value))),
+    }
+}
+
+fn read_json(path: &str) -> Result<Value> {
+    let text = fs::read_to_string(path).with_context(|| format!("reading {}", path))?;
+    serde_json::from_str(&text).with_context(|| format!("parsing {}", path))
+}
+
+async fn render(sources: &TileSources, render: &Render) -> Result<()> {
+    let request = &render.request;
+    let options = request.render_options()?;
+    let image = fetch_image_from_point(
+        sources,
+        request.center(),
+        request.radius,
+        request.size_px,
+        request.tileset(),
+        &options,
+    )
+    .await?;
+    fs::write(&render.output, image).with_context(|| format!("writing {}", render.output))
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.is_empty() || args.iter().any(|a| a == "--help" || a == "-h") {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    }
+    let renders = match parse_args(&args) {
+        Ok(renders) => renders,
+        Err(e) => {
+            eprintln!("{:#}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let setup = async {
+        watermark::init_from_env().await?;
+        registry::init_from_env()?;
+        wmts::init_from_env().await?;
+        coverage::init_from_env()?;
+        icc::init_from_env()?;
+        TileSources::from_env()
+    };
+    let sources = match setup.await {
+        Ok(sources) => sources,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut failed = false;
+    for r in &renders {
+        match render(&sources, r).await {
+            Ok(()) => println!("Wrote {}", r.output),
+            Err(e) => {
+                eprintln!("Failed to render {}: {:#}", r.output, e);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let renders = parse_args(&args(
+            "--long 8.1 --lat 46.6 --size 512 --filter sepia -o a.png",
+        ))
+        .unwrap();
+        assert_eq!(renders.len(), 1);
+        assert_eq!(renders[0].output, "a.png");
+        assert_eq!(renders[0].request.size_px, 512);
+        assert_eq!(renders[0].request.params.filter.as_deref(), Some("sepia"));
+        assert!(renders[0].request.render_options().is_ok());
+
+        assert!(parse_args(&args("--long 8.1 --lat 46.6 --size")).is_err());
+        assert!(parse_args(&args("--long 8.1 --lat 46.6 --size 512")).is_err());
+        assert!(parse_args(&args("8.1 46.6")).is_err());
+    }
+
+    #[test]
+    fn test_parse_spec() {
+        let one: Spec = serde_json::from_str(
+            r#"{"output": "a.png", "long": 8.1, "lat": 46.6, "size_px": 256, "slope": true}"#,
+        )
+        .unwrap();
+        assert_eq!(one.into_renders()[0].request.params.slope, Some(true));
+
+        let many: Spec = serde_json::from_str(
+            r#"[{"output": "a.png", "long": 8.1, "lat": 46.6, "size_px": 256},
+                {"output": "b.png", "long": 7.4, "lat": 46.9, "size_px": 512, "radius": 2.0}]"#,
+        )
+        .unwrap();
+        let renders = many.into_renders();
+        assert_eq!(renders.len(), 2);
+        assert_eq!(renders[1].request.radius, 2.0);
+    }
+
+    #[test]
+    fn test_parse_value() {
+        assert_eq!(parse_value("512").unwrap(), Value::from(512));
+        assert_eq!(parse_value("true").unwrap(), Value::from(true));
+        assert_eq!(parse_value("sepia").unwrap(), Value::from("sepia"));
+        assert!(parse_value("@/nonexistent.json").is_err());
+    }
+}
@@ -0,0 +1,241 @@
+// ! # pass-image-load
+// ! A load generator for pass-image-api. It sends a randomized but realistic mix of raw
+// ! tile and image requests (see the scenario module) and reports throughput and latency
+// ! percentiles per kind of request, along with how many were throttled:
+// !
+// !   pass-image-load --target http://localhost:8080 --requests 2000 --concurrency 32
+// !
+// ! Hot spots make most requests hit tiles that were fetched before, which exercises the
+// ! tile cache, and spreading requests over --api-keys exercises the per-key quotas.
+// ! The same --seed sends the same requests, so runs before and after a change compare.
+// ! It exits with 1 if any request failed other than by being throttled.
+
+mod report;
+mod scenario;
+
+use anyhow::{anyhow, Context, Result};
+use futures::{stream, StreamExt};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use report::{Outcome, Report};
+use scenario::{Request, Scenario, Weighted};
+use std::env;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "Usage:
+  pass-image-load [--target <url>] [--requests <n>] [--concurrency <n>] [--seed <n>]
+                  [--tile-share <0-1>] [--hot-spot-share <0-1>] [--bbox <w,s,e,n>]
+                  [--zooms <z:weight,...>] [--tilesets <name:weight,...>]
+                  [--api-keys <key,...>]";
+
+// Matches the service's usage::API_KEY_HEADER
+const API_KEY_HEADER: &str = "X-Api-Key";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_FAILURES_SHOWN: usize = 5;
+
+#[derive(Debug)]
+struct Config {
+    target: String,
+    requests: usize,
+    concurrency: usize,
+    seed: u64,
+    api_keys: Vec<String>,
+    scenario: Scenario,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            target: "http://localhost:8080".to_string(),
+            requests: 1000,
+            concurrency: 16,
+            seed: rand::thread_rng().gen(),
+            api_keys: Vec::new(),
+            scenario: Scenario::default(),
+        }
+    }
+}
+
+fn share(value: &str) -> Result<f64> {
+    let share: f64 = value.parse()?;
+    if !(0.0..=1.0).contains(&share) {
+        return Err(anyhow!("{} isn't between 0 and 1", value));
+    }
+    Ok(share)
+}
+
+fn parse_args(args: &[String]) -> Result<Config> {
+    let mut config = Config::default();
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| anyhow!("{} needs a value", flag))?;
+        let invalid = || format!("Invalid {} {}", flag, value);
+        match flag.as_str() {
+            "--target" => config.target = value.trim_end_matches('/').to_string(),
+            "--requests" => config.requests = value.parse().with_context(invalid)?,
+            "--concurrency" => config.concurrency = value.parse().with_context(invalid)?,
+            "--seed" => config.seed = value.parse().with_context(invalid)?,
+            "--tile-share" => config.scenario.tile_share = share(value).with_context(invalid)?,
+            "--hot-spot-share" => {
+                config.scenario.hot_spot_share = share(value).with_context(invalid)?
+            }
+            "--bbox" => {
+                let bbox: Vec<f64> = value
+                    .split(',')
+                    .map(|v| v.trim().parse())
+                    .collect::<Result<_, _>>()
+                    .with_context(invalid)?;
+                config.scenario.bbox = bbox
+                    .try_into()
+                    .map_err(|_| anyhow!("--bbox needs four values: west,south,east,north"))?;
+            }
+            "--zooms" => {
+                config.scenario.zooms = Weighted::parse_zooms(value).with_context(invalid)?
+            }
+            "--tilesets" => {
+                config.scenario.tilesets = Weighted::parse(value).with_context(invalid)?
+            }
+            "--api-keys" => config.api_keys = value.split(',').map(str::to_string).collect(),
+            _ => return Err(anyhow!("Unexpected argument {}", flag)),
+        }
+    }
+    if config.concurrency == 0 {
+        return Err(anyhow!("--concurrency must be at least 1"));
+    }
+    Ok(config)
+}
+
+async fn send(
+    client: &reqwest::Client,
+    config: &Config,
+    request: &Request,
+    key: Option<&str>,
+) -> Outcome {
+    let mut builder = client.get(format!("{}{}", config.target, request.path()));
+    if let Some(key) = key {
+        builder = builder.header(API_KEY_HEADER, key);
+    }
+    let started = Instant::now();
+    let status = match builder.send().await {
+        // Read the body too, so the latency covers the whole response
+        Ok(response) => {
+            let status = response.status().as_u16();
+            response.bytes().await.ok().map(|_| status)
+        }
+        Err(_) => None,
+    };
+    Outcome {
+        status,
+        latency: started.elapsed(),
+    }
+}
+
+async fn run(config: &Config) -> Result<Report> {
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()?;
+
+    // The requests are all drawn up front, so the mix doesn't depend on timing
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let requests: Vec<(Request, Option<&str>)> = (0..config.requests)
+        .map(|_| {
+            let request = config.scenario.next(&mut rng);
+            let key = (!config.api_keys.is_empty())
+                .then(|| config.api_keys[rng.gen_range(0..config.api_keys.len())].as_str());
+            (request, key)
+        })
+        .collect();
+
+    let started = Instant::now();
+    let mut report = Report::default();
+    let mut outcomes = stream::iter(&requests)
+        .map(|(request, key)| {
+            let client = &client;
+            async move { (request, send(client, config, request, *key).await) }
+        })
+        .buffer_unordered(config.concurrency);
+    let (mut done, mut shown) = (0, 0);
+    while let Some((request, outcome)) = outcomes.next().await {
+        // A few examples are enough to see what's going wrong
+        if !outcome.succeeded() && outcome.status != Some(429) && shown < MAX_FAILURES_SHOWN {
+            let status = outcome
+                .status
+                .map_or("no response".to_string(), |s| s.to_string());
+            eprintln!("{} failed: {}", request.path(), status);
+            shown += 1;
+        }
+        report.record(request.kind(), outcome);
+        done += 1;
+        if done % 500 == 0 {
+            eprintln!("{} of {} requests done", done, requests.len());
+        }
+    }
+    report.finish(started.elapsed());
+    Ok(report)
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|a| a == "--help" || a == "-h") {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    }
+    let config = match parse_args(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{:#}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    eprintln!(
+        "Sending {} requests to {} with concurrency {} (seed {})",
+        config.requests, config.target, config.concurrency, config.seed
+    );
+    match run(&config).await {
+        // Throttling is expected under load, but errors and timeouts aren't
+        Ok(report) => {
+            println!("{}", report);
+            if report.failed() > 0 {
+                std::process::exit(1);
+            }
+        }
+        Err(e) => {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let config = parse_args(&args(
+            "--target http://api:8080/ --requests 50 --seed 4 --zooms 12:1 --api-keys a,b",
+        ))
+        .unwrap();
+        assert_eq!(config.target, "http://api:8080");
+        assert_eq!((config.requests, config.seed), (50, 4));
+        assert_eq!(config.api_keys, vec!["a", "b"]);
+        assert_eq!(
+            config.scenario.zooms,
+            Weighted::parse_zooms("12:1").unwrap()
+        );
+
+        assert!(parse_args(&args("--requests")).is_err());
+        assert!(parse_args(&args("--tile-share 2")).is_err());
+        assert!(parse_args(&args("--bbox 1,2,3")).is_err());
+        assert!(parse_args(&args("--concurrency 0")).is_err());
+        assert!(parse_args(&args("--frobnicate 1")).is_err());
+    }
+}
@@ -0,0 +1,137 @@
+// ! # report
+// ! Collects the outcome of every request and summarises them per kind of request:
+// ! how many succeeded, were throttled (429) or failed, and latency percentiles.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+// What happened to a request: its HTTP status, or None if it never got one
+#[derive(Debug, Clone, Copy)]
+pub struct Outcome {
+    pub status: Option<u16>,
+    pub latency: Duration,
+}
+
+impl Outcome {
+    pub fn succeeded(&self) -> bool {
+        self.status.is_some_and(|s| (200..300).contains(&s))
+    }
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    latencies: Vec<Duration>,
+    ok: usize,
+    throttled: usize,
+    failed: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    kinds: BTreeMap<&'static str, Stats>,
+    elapsed: Duration,
+}
+
+// The latency below which the given fraction of requests finished
+fn percentile(sorted: &[Duration], fraction: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (fraction * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+impl Report {
+    pub fn record(&mut self, kind: &'static str, outcome: Outcome) {
+        let stats = self.kinds.entry(kind).or_default();
+        stats.latencies.push(outcome.latency);
+        match outcome.status {
+            _ if outcome.succeeded() => stats.ok += 1,
+            Some(429) => stats.throttled += 1,
+            _ => stats.failed += 1,
+        }
+    }
+
+    pub fn finish(&mut self, elapsed: Duration) {
+        self.elapsed = elapsed;
+        for stats in self.kinds.values_mut() {
+            stats.latencies.sort();
+        }
+    }
+
+    pub fn failed(&self) -> usize {
+        self.kinds.values().map(|s| s.failed).sum()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        writeln!(
+            f,
+            "{:<6} {:>7} {:>7} {:>9} {:>7} {:>9} {:>9} {:>9} {:>9}",
+            "kind", "count", "ok", "throttled", "failed", "p50 ms", "p90 ms", "p99 ms", "max ms"
+        )?;
+        let mut total = 0;
+        for (kind, stats) in &self.kinds {
+            let l = &stats.latencies;
+            total += l.len();
+            writeln!(
+                f,
+                "{:<6} {:>7} {:>7} {:>9} {:>7} {:>9.1} {:>9.1} {:>9.1} {:>9.1}",
+                kind,
+                l.len(),
+                stats.ok,
+                stats.throttled,
+                stats.failed,
+                ms(percentile(l, 0.5)),
+                ms(percentile(l, 0.9)),
+                ms(percentile(l, 0.99)),
+                ms(l.last().copied().unwrap_or_default()),
+            )?;
+        }
+        write!(
+            f,
+            "{} requests in {:.1}s ({:.1} req/s)",
+            total,
+            self.elapsed.as_secs_f64(),
+            total as f64 / self.elapsed.as_secs_f64().max(0.001)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile() {
+        let sorted: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&sorted, 0.5), Duration::from_millis(50));
+        assert_eq!(percentile(&sorted, 0.99), Duration::from_millis(99));
+        assert_eq!(percentile(&sorted, 1.0), Duration::from_millis(100));
+        assert_eq!(percentile(&sorted[..1], 0.5), Duration::from_millis(1));
+        assert_eq!(percentile(&[], 0.5), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_report() {
+        let mut report = Report::default();
+        let at = |status, ms| Outcome {
+            status,
+            latency: Duration::from_millis(ms),
+        };
+        report.record("tile", at(Some(200), 30));
+        report.record("tile", at(Some(429), 10));
+        report.record("image", at(None, 500));
+        report.finish(Duration::from_secs(1));
+        assert_eq!(report.failed(), 1);
+
+        let text = report.to_string();
+        assert!(text.contains("3 requests in 1.0s"), "{}", text);
+        let tile = text.lines().find(|l| l.starts_with("tile")).unwrap();
+        let columns: Vec<&str> = tile.split_whitespace().collect();
+        assert_eq!(&columns[1..5], &["2", "1", "1", "0"]);
+    }
+}
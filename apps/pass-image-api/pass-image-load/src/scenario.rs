@@ -0,0 +1,291 @@
+// ! # scenario
+// ! Generates the requests a load test sends. Real traffic isn't uniform: most of it
+// ! lands on a few popular passes, maps are mostly looked at around zoom 13, and some
+// ! tilesets are far more popular than others. A Scenario draws requests to match, from
+// ! a seed so a run can be repeated exactly.
+// !
+// ! Hot spots are picked with Zipf weights, so the first pass gets twice the traffic of
+// ! the second and so on, and requests around them are jittered by a few hundred meters
+// ! so the same tiles come up again and again, as they would for the tile cache. The rest
+// ! are spread evenly over the background bbox.
+
+use anyhow::{anyhow, Context, Result};
+use rand::rngs::StdRng;
+use rand::Rng;
+use tile_geometry::coordinates::{lat_long_to_tile_coords, LatLong, MAX_ZOOM};
+
+// Popular passes from the sample data, most popular first
+const HOT_SPOTS: [LatLong; 6] = [
+    LatLong(46.6556, 8.1021),  // Grosse Scheidegg
+    LatLong(46.5614, 8.3375),  // Grimsel
+    LatLong(46.5725, 8.4153),  // Furka
+    LatLong(46.5833, 9.8833),  // Albula
+    LatLong(46.4108, 10.0275), // Bernina
+    LatLong(46.7578, 8.1378),  // Brünig
+];
+
+// How far requests around a hot spot stray from it, in degrees (a few hundred meters)
+const HOT_SPOT_JITTER: f64 = 0.004;
+
+// The sizes images are rendered at, as for thumbnails, cards and full pages
+const IMAGE_SIZES: [(u32, u32); 3] = [(256, 5), (512, 3), (1024, 1)];
+
+// A weighted choice, e.g. osm:0.8,swisstopo:0.2
+#[derive(Debug, Clone, PartialEq)]
+pub struct Weighted<T> {
+    choices: Vec<(T, f64)>,
+}
+
+impl<T: Clone> Weighted<T> {
+    pub fn new(choices: Vec<(T, f64)>) -> Result<Weighted<T>> {
+        if choices.iter().any(|(_, w)| !w.is_finite() || *w < 0.0) {
+            return Err(anyhow!("Weights can't be negative"));
+        }
+        if choices.iter().map(|(_, w)| w).sum::<f64>() <= 0.0 {
+            return Err(anyhow!("At least one weight must be positive"));
+        }
+        Ok(Weighted { choices })
+    }
+
+    pub fn pick(&self, rng: &mut impl Rng) -> T {
+        let total: f64 = self.choices.iter().map(|(_, w)| w).sum();
+        let mut at = rng.gen::<f64>() * total;
+        for (choice, weight) in &self.choices {
+            if at < *weight {
+                return choice.clone();
+            }
+            at -= weight;
+        }
+        // Only reachable through rounding
+        self.choices[self.choices.len() - 1].0.clone()
+    }
+}
+
+impl Weighted<String> {
+    // Parses name:weight pairs separated by commas
+    pub fn parse(value: &str) -> Result<Weighted<String>> {
+        let choices = value
+            .split(',')
+            .map(|pair| {
+                let (name, weight) = pair
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("Expected name:weight, got {}", pair))?;
+                let weight = weight
+                    .parse()
+                    .with_context(|| format!("Invalid weight in {}", pair))?;
+                Ok((name.trim().to_string(), weight))
+            })
+            .collect::<Result<_>>()?;
+        Weighted::new(choices)
+    }
+}
+
+impl Weighted<u32> {
+    // Parses zoom:weight pairs separated by commas
+    pub fn parse_zooms(value: &str) -> Result<Weighted<u32>> {
+        let choices = Weighted::parse(value)?
+            .choices
+            .into_iter()
+            .map(|(zoom, weight)| {
+                let zoom: u32 = zoom
+                    .parse()
+                    .with_context(|| format!("Invalid zoom {}", zoom))?;
+                if zoom > MAX_ZOOM {
+                    return Err(anyhow!("Zoom {} is past {}", zoom, MAX_ZOOM));
+                }
+                Ok((zoom, weight))
+            })
+            .collect::<Result<_>>()?;
+        Weighted::new(choices)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scenario {
+    // The fraction of requests for raw tiles rather than rendered images
+    pub tile_share: f64,
+    // The fraction of requests around a hot spot rather than anywhere in the bbox
+    pub hot_spot_share: f64,
+    // west, south, east, north
+    pub bbox: [f64; 4],
+    pub zooms: Weighted<u32>,
+    pub tilesets: Weighted<String>,
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Scenario {
+            tile_share: 0.7,
+            hot_spot_share: 0.8,
+            // The Swiss Alps
+            bbox: [5.96, 45.82, 10.49, 47.81],
+            zooms: Weighted::parse_zooms("10:1,12:2,13:4,14:4,15:2,16:1")
+                .expect("the default zooms parse"),
+            tilesets: Weighted::parse("osm:0.8,swisstopo:0.2").expect("the default tilesets parse"),
+        }
+    }
+}
+
+// One request, as a path and query under the API's base URL
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    Tile {
+        tileset: String,
+        z: u32,
+        x: u32,
+        y: u32,
+    },
+    Image {
+        tileset: String,
+        center: LatLong,
+        size_px: u32,
+        radius_km: f32,
+    },
+}
+
+impl Request {
+    // What the request is reported under
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Request::Tile { .. } => "tile",
+            Request::Image { .. } => "image",
+        }
+    }
+
+    pub fn path(&self) -> String {
+        match self {
+            Request::Tile { tileset, z, x, y } => {
+                format!("/tiles/{}/{}/{}/{}.png", tileset, z, x, y)
+            }
+            Request::Image {
+                tileset,
+                center,
+                size_px,
+                radius_km,
+            } => format!(
+                "/images/{:.5}/{:.5}/{}?radius={:.2}&tileset={}",
+                center.1, center.0, size_px, radius_km, tileset
+            ),
+        }
+    }
+}
+
+impl Scenario {
+    fn location(&self, rng: &mut StdRng) -> LatLong {
+        if rng.gen::<f64>() < self.hot_spot_share {
+            let zipf = Weighted {
+                choices: (0..HOT_SPOTS.len())
+                    .map(|i| (i, 1.0 / (i + 1) as f64))
+                    .collect(),
+            };
+            let spot = HOT_SPOTS[zipf.pick(rng)];
+            LatLong(
+                spot.0 + rng.gen_range(-HOT_SPOT_JITTER..=HOT_SPOT_JITTER),
+                spot.1 + rng.gen_range(-HOT_SPOT_JITTER..=HOT_SPOT_JITTER),
+            )
+        } else {
+            let [west, south, east, north] = self.bbox;
+            LatLong(rng.gen_range(south..=north), rng.gen_range(west..=east))
+        }
+    }
+
+    pub fn next(&self, rng: &mut StdRng) -> Request {
+        let tileset = self.tilesets.pick(rng);
+        let center = self.location(rng);
+        if rng.gen::<f64>() < self.tile_share {
+            let z = self.zooms.pick(rng);
+            let tile = lat_long_to_tile_coords(&center, z);
+            Request::Tile {
+                tileset,
+                z,
+                x: tile.x as u32,
+                y: tile.y as u32,
+            }
+        } else {
+            let sizes = Weighted {
+                choices: IMAGE_SIZES.iter().map(|&(s, w)| (s, w as f64)).collect(),
+            };
+            Request::Image {
+                tileset,
+                center,
+                size_px: sizes.pick(rng),
+                // Half a kilometer to five, evenly over the order of magnitude
+                radius_km: 10f32.powf(rng.gen_range(-0.3..0.7)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_weighted() {
+        let tilesets = Weighted::parse("osm:3, swisstopo:1").unwrap();
+        let mut rng = StdRng::seed_from_u64(1);
+        let osm = (0..4000)
+            .filter(|_| tilesets.pick(&mut rng) == "osm")
+            .count();
+        assert!((2800..3200).contains(&osm), "{}", osm);
+
+        assert!(Weighted::parse("osm").is_err());
+        assert!(Weighted::parse("osm:-1").is_err());
+        assert!(Weighted::parse("osm:0").is_err());
+        assert!(Weighted::parse_zooms("13:1,30:1").is_err());
+    }
+
+    #[test]
+    fn test_scenario_is_repeatable() {
+        let scenario = Scenario::default();
+        let run = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..100)
+                .map(|_| scenario.next(&mut rng))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+    }
+
+    #[test]
+    fn test_scenario_mix() {
+        let scenario = Scenario {
+            tile_share: 1.0,
+            hot_spot_share: 1.0,
+            zooms: Weighted::parse_zooms("14:1").unwrap(),
+            ..Default::default()
+        };
+        let mut rng = StdRng::seed_from_u64(3);
+        let requests: Vec<Request> = (0..1000).map(|_| scenario.next(&mut rng)).collect();
+        assert!(requests.iter().all(|r| r.kind() == "tile"));
+
+        // Traffic piles up on a handful of tiles around the hot spots
+        let mut paths: Vec<String> = requests.iter().map(Request::path).collect();
+        paths.sort();
+        paths.dedup();
+        assert!(paths.len() < 100, "{} distinct tiles", paths.len());
+    }
+
+    #[test]
+    fn test_paths() {
+        let tile = Request::Tile {
+            tileset: "osm".to_string(),
+            z: 13,
+            x: 4280,
+            y: 2896,
+        };
+        assert_eq!(tile.path(), "/tiles/osm/13/4280/2896.png");
+        let image = Request::Image {
+            tileset: "swisstopo".to_string(),
+            center: LatLong(46.6556, 8.1021),
+            size_px: 512,
+            radius_km: 1.5,
+        };
+        assert_eq!(
+            image.path(),
+            "/images/8.10210/46.65560/512?radius=1.50&tileset=swisstopo"
+        );
+    }
+}
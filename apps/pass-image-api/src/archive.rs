@@ -0,0 +1,77 @@
+// ! # archive
+// ! Maps as they looked at a given date, on top of tile_render::archive. A request's
+// ! ?date=YYYY or ?date=YYYY-MM-DD renders tilesets with an archive, e.g. swisstopo, from
+// ! their edition for that date; tilesets without one render as they are today. An
+// ! invalid date is a 400. Jobs a request submits render as of today.
+
+use crate::request::bad_request;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use std::collections::HashMap;
+use tile_render::archive::{self, Date};
+
+// Middleware running a request as of the date it asked for
+pub async fn scope_request(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let date = web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.get("date").cloned());
+    match date {
+        Some(date) => {
+            let date = Date::parse(&date).map_err(bad_request)?;
+            archive::scope(date, next.call(req)).await
+        }
+        None => next.call(req).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, App, HttpResponse};
+    use tile_render::tiles::TileSet;
+
+    // Reports the edition swisstopo tiles would be fetched from
+    #[actix_web::get("/edition")]
+    async fn swisstopo_edition() -> HttpResponse {
+        let time = archive::variant(TileSet::Swisstopo).map(|(time, _)| time);
+        HttpResponse::Ok().body(time.unwrap_or_else(|| "current".to_string()))
+    }
+
+    async fn edition(uri: &str) -> (u16, String) {
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(scope_request))
+                .service(swisstopo_edition),
+        )
+        .await;
+        let req = test::TestRequest::get().uri(uri).to_request();
+        match test::try_call_service(&app, req).await {
+            Ok(response) => {
+                let status = response.status().as_u16();
+                let body = test::read_body(response).await;
+                (status, String::from_utf8(body.to_vec()).unwrap())
+            }
+            Err(e) => (e.as_response_error().status_code().as_u16(), e.to_string()),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_date_from_request() {
+        assert_eq!(edition("/edition").await, (200, "current".to_string()));
+        assert_eq!(
+            edition("/edition?date=1938").await,
+            (200, "19381231".to_string())
+        );
+        assert_eq!(
+            edition("/edition?date=1938-04-01").await,
+            (200, "19381231".to_string())
+        );
+        assert_eq!(edition("/edition?date=April").await.0, 400);
+    }
+}
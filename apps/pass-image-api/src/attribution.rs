@@ -0,0 +1,111 @@
+// ! # attribution
+// ! GET /attribution returns the legal metadata for the tilesets an image was rendered
+// ! from, so clients embedding our images can show compliant notices next to them:
+// ! ?tileset= takes a tileset name or the same spec as ?blend=, e.g. osm,swisstopo:0.5.
+// ! Without it every tileset is listed. See tile_render::legal for what's in an entry.
+
+use crate::request::bad_request;
+use actix_web::http::header::{CacheControl, CacheDirective};
+use actix_web::{get, web, Error, HttpResponse};
+use serde::{Deserialize, Serialize};
+use tile_render::legal::{self, Legal};
+use tile_render::tiles::{parse_blend, TileSet};
+
+// The metadata only changes with a release, so clients may hold on to it for a day
+const MAX_AGE_SECS: u32 = 24 * 60 * 60;
+
+#[derive(Debug, Deserialize)]
+struct AttributionQuery {
+    tileset: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Attributions {
+    providers: Vec<Legal>,
+}
+
+#[get("/attribution")]
+async fn get_attribution(query: web::Query<AttributionQuery>) -> Result<HttpResponse, Error> {
+    let tilesets = match query.tileset.as_deref() {
+        None => TileSet::all(),
+        Some(spec) => {
+            let mut tilesets: Vec<TileSet> = Vec::new();
+            for layer in parse_blend(spec).map_err(bad_request)? {
+                if !tilesets.contains(&layer.tileset) {
+                    tilesets.push(layer.tileset);
+                }
+            }
+            tilesets
+        }
+    };
+    Ok(HttpResponse::Ok()
+        .insert_header(CacheControl(vec![
+            CacheDirective::Public,
+            CacheDirective::MaxAge(MAX_AGE_SECS),
+        ]))
+        .json(Attributions {
+            providers: tilesets.into_iter().map(legal::legal).collect(),
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+    use serde_json::Value;
+
+    async fn attribution(uri: &str) -> Result<Value, u16> {
+        let app = test::init_service(App::new().service(get_attribution)).await;
+        let req = test::TestRequest::get().uri(uri).to_request();
+        match test::try_call_service(&app, req).await {
+            Ok(response) if response.status().is_success() => {
+                Ok(test::read_body_json(response).await)
+            }
+            Ok(response) => Err(response.status().as_u16()),
+            Err(e) => Err(e.as_response_error().status_code().as_u16()),
+        }
+    }
+
+    fn tilesets(attributions: &Value) -> Vec<&str> {
+        attributions["providers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| p["tileset"].as_str().unwrap())
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn test_attribution() {
+        // Tilesets that need an API token aren't offered without one
+        let all = attribution("/attribution").await.unwrap();
+        assert_eq!(
+            tilesets(&all),
+            vec![
+                "osm",
+                "swisstopo",
+                "terrain",
+                "debug",
+                "bing",
+                "esri",
+                "esri_roads",
+                "esri_labels",
+                "hybrid"
+            ]
+        );
+
+        let blend = attribution("/attribution?tileset=swisstopo:0.5,osm,swisstopo")
+            .await
+            .unwrap();
+        assert_eq!(tilesets(&blend), vec!["swisstopo", "osm"]);
+        let swisstopo = &blend["providers"][0];
+        assert_eq!(swisstopo["notice"], "(c) swisstopo");
+        assert_eq!(swisstopo["required"], true);
+        assert!(swisstopo["license_url"]
+            .as_str()
+            .unwrap()
+            .starts_with("https://"));
+
+        assert_eq!(attribution("/attribution?tileset=mars").await, Err(400));
+    }
+}
@@ -0,0 +1,398 @@
+// ! # coords
+// ! Tile listings for hybrid clients that draw their own maps. GET /coords/tiles returns
+// ! the proxy URLs of every tile covering a viewport at a zoom, so a lightweight client
+// ! can fetch and place tiles itself while upstream keys stay on the server and tile
+// ! fetches still go through our proxy. URLs include the prefix the API is mounted under.
+// !
+// ! GET /tiles/stream takes the same query and sends the tiles themselves back in one
+// ! response, each as soon as it's fetched, so clients can start drawing before the
+// ! slowest tile arrives. Tiles come in the order they're fetched, as the parts of a
+// ! multipart/mixed response, or with ?format=ndjson as lines of JSON with the PNG in
+// ! base64:
+// !
+// !     {"z": 13, "x": 4279, "y": 2892, "png": "iVBORw0KGgo..."}
+// !
+// ! Each part has the tile's z/x/y in an X-Tile header. A tile that can't be fetched
+// ! doesn't end the stream: it comes as a text/plain part, or a line with an "error", in
+// ! its place.
+
+use crate::export::Pyramid;
+use crate::request::bad_request;
+use actix_web::error::{ErrorBadRequest, ErrorForbidden};
+use actix_web::{get, web, Error, HttpRequest, HttpResponse};
+use anyhow::anyhow;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use bytes::Bytes;
+use futures::{stream, StreamExt};
+use log::warn;
+use opentelemetry::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::convert::Infallible;
+use tile_render::fetcher::{TileFetcher, TileSources};
+use tile_render::tiles::TileSet;
+use uuid::Uuid;
+
+// The most tiles one listing may hold, e.g. a 4K screen at one zoom with room to spare
+const MAX_VIEWPORT_TILES: u64 = 512;
+
+// How many tiles a stream fetches at once
+const STREAM_CONCURRENCY: usize = 8;
+
+#[derive(Debug, Deserialize)]
+struct TilesQuery {
+    // west,south,east,north in degrees
+    bbox: String,
+    zoom: u32,
+    tileset: Option<String>,
+    // multipart or ndjson, for /tiles/stream
+    format: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct TileUrl {
+    z: u32,
+    x: u32,
+    y: u32,
+    url: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TileListing {
+    tileset: String,
+    zoom: u32,
+    // The first and last tile columns and rows, so clients can lay the tiles out
+    x_range: (u32, u32),
+    y_range: (u32, u32),
+    tiles: Vec<TileUrl>,
+}
+
+fn parse_bbox(value: &str) -> anyhow::Result<[f64; 4]> {
+    let bbox: Vec<f64> = value
+        .split(',')
+        .map(|v| v.trim().parse())
+        .collect::<Result<_, _>>()
+        .map_err(|_| anyhow!("bbox must be four numbers: west,south,east,north"))?;
+    bbox.try_into()
+        .map_err(|_| anyhow!("bbox must be four numbers: west,south,east,north"))
+}
+
+// Where the proxy serves a tile, including the prefix the API is mounted under
+fn tile_path(req: &HttpRequest, tileset: TileSet, z: u32, x: u32, y: u32) -> String {
+    let (z, x, y) = (z.to_string(), x.to_string(), y.to_string());
+    req.url_for("tile", [tileset.name(), &z, &x, &y])
+        .map(|url| url.path().to_string())
+        .unwrap_or_else(|_| format!("/tiles/{}/{}/{}/{}.png", tileset.name(), z, x, y))
+}
+
+// The tileset and tiles a viewport query asks for
+fn viewport(query: &TilesQuery) -> Result<(TileSet, Pyramid), Error> {
+    let tileset = match query.tileset.as_deref() {
+        None => TileSet::Osm,
+        Some(name) => TileSet::lookup(name)
+            .ok_or_else(|| ErrorBadRequest(format!("Unknown tileset {}", name)))?,
+    };
+    // The proxy won't serve them, so listing them would only hand out broken URLs
+    if tileset.is_licensed() {
+        return Err(ErrorForbidden(format!(
+            "Raw tiles from {} can't be proxied; request a rendered image instead",
+            tileset.name()
+        )));
+    }
+    let bbox = parse_bbox(&query.bbox).map_err(bad_request)?;
+    let pyramid = Pyramid::new(bbox, query.zoom, query.zoom).map_err(bad_request)?;
+    let count = pyramid.tile_count();
+    if count > MAX_VIEWPORT_TILES {
+        return Err(ErrorBadRequest(format!(
+            "The viewport covers {} tiles, more than the limit of {}; lower the zoom",
+            count, MAX_VIEWPORT_TILES
+        )));
+    }
+    Ok((tileset, pyramid))
+}
+
+#[get("/coords/tiles")]
+async fn get_viewport_tiles(
+    req: HttpRequest,
+    query: web::Query<TilesQuery>,
+) -> Result<HttpResponse, Error> {
+    let (tileset, pyramid) = viewport(&query)?;
+    let (x_range, y_range) = pyramid.range(query.zoom);
+    let tiles = pyramid
+        .tiles()
+        .map(|(z, x, y)| TileUrl {
+            z,
+            x,
+            y,
+            url: tile_path(&req, tileset, z, x, y),
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(TileListing {
+        tileset: tileset.name().to_string(),
+        zoom: query.zoom,
+        x_range,
+        y_range,
+        tiles,
+    }))
+}
+
+// How a stream's tiles are sent
+#[derive(Debug, Clone, PartialEq)]
+enum StreamFormat {
+    Multipart { boundary: String },
+    Ndjson,
+}
+
+impl StreamFormat {
+    fn from_query(format: Option<&str>) -> Result<StreamFormat, Error> {
+        match format {
+            None | Some("multipart") => Ok(StreamFormat::Multipart {
+                boundary: Uuid::new_v4().simple().to_string(),
+            }),
+            Some("ndjson") => Ok(StreamFormat::Ndjson),
+            Some(other) => Err(ErrorBadRequest(format!(
+                "Unknown format {}: expected multipart or ndjson",
+                other
+            ))),
+        }
+    }
+
+    fn content_type(&self) -> String {
+        match self {
+            StreamFormat::Multipart { boundary } => {
+                format!("multipart/mixed; boundary={}", boundary)
+            }
+            StreamFormat::Ndjson => "application/x-ndjson".to_string(),
+        }
+    }
+
+    // A tile's part of the stream, or what went wrong fetching it
+    fn part(&self, (z, x, y): (u32, u32, u32), tile: Result<Bytes, &str>) -> Bytes {
+        match self {
+            StreamFormat::Multipart { boundary } => {
+                let (content_type, body) = match tile {
+                    Ok(png) => ("image/png", png),
+                    Err(e) => ("text/plain", Bytes::from(e.to_string())),
+                };
+                let head = format!(
+                    "--{}\r\nContent-Type: {}\r\nX-Tile: {}/{}/{}\r\n\r\n",
+                    boundary, content_type, z, x, y
+                );
+                [head.as_bytes(), &body[..], b"\r\n"].concat().into()
+            }
+            StreamFormat::Ndjson => {
+                let line = match tile {
+                    Ok(png) => json!({"z": z, "x": x, "y": y, "png": BASE64.encode(png)}),
+                    Err(e) => json!({"z": z, "x": x, "y": y, "error": e}),
+                };
+                format!("{}\n", line).into()
+            }
+        }
+    }
+
+    // What follows the last tile
+    fn end(&self) -> Option<Bytes> {
+        match self {
+            StreamFormat::Multipart { boundary } => Some(format!("--{}--\r\n", boundary).into()),
+            StreamFormat::Ndjson => None,
+        }
+    }
+}
+
+// Streams every tile covering a viewport, as each is fetched
+#[get("/tiles/stream")]
+async fn stream_tiles(
+    query: web::Query<TilesQuery>,
+    sources: web::Data<TileSources>,
+) -> Result<HttpResponse, Error> {
+    let (tileset, pyramid) = viewport(&query)?;
+    let format = StreamFormat::from_query(query.format.as_deref())?;
+    let tiles: Vec<(u32, u32, u32)> = pyramid.tiles().collect();
+
+    let cx = Context::current();
+    let content_type = format.content_type();
+    let end = format.end();
+    let parts = stream::iter(tiles)
+        .map(move |(z, x, y)| {
+            let (sources, format, cx) = (sources.clone(), format.clone(), cx.clone());
+            async move {
+                let tile = sources.fetch(tileset, x, y, z, cx).await;
+                // Upstream errors can carry URLs with keys in them, so they stay in the log
+                let tile = tile.map_err(|e| {
+                    warn!(
+                        "Streaming {}/{}/{}/{} failed: {:#}",
+                        tileset.name(),
+                        z,
+                        x,
+                        y,
+                        e
+                    );
+                    "Couldn't fetch the tile"
+                });
+                format.part((z, x, y), tile)
+            }
+        })
+        .buffer_unordered(STREAM_CONCURRENCY)
+        .chain(stream::iter(end))
+        .map(Ok::<_, Infallible>);
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .streaming(parts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::images::get_tile;
+    use actix_web::{test, App};
+    use tile_render::fetcher::MemoryFetcher;
+
+    async fn listing(uri: &str) -> Result<TileListing, u16> {
+        let app = test::init_service(
+            App::new().service(
+                web::scope("/maps")
+                    .service(get_viewport_tiles)
+                    .service(get_tile),
+            ),
+        )
+        .await;
+        let req = test::TestRequest::get().uri(uri).to_request();
+        match test::try_call_service(&app, req).await {
+            Ok(response) if response.status().is_success() => {
+                Ok(test::read_body_json(response).await)
+            }
+            Ok(response) => Err(response.status().as_u16()),
+            Err(e) => Err(e.as_response_error().status_code().as_u16()),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_viewport_tiles() {
+        // Around the Grosse Scheidegg, two columns by two rows at zoom 13
+        let tiles = listing("/maps/coords/tiles?bbox=8.07,46.64,8.12,46.67&zoom=13")
+            .await
+            .unwrap();
+        assert_eq!(tiles.tileset, "osm");
+        assert_eq!((tiles.x_range, tiles.y_range), ((4279, 4280), (2892, 2893)));
+        assert_eq!(tiles.tiles.len(), 4);
+        assert_eq!(
+            tiles.tiles[0],
+            TileUrl {
+                z: 13,
+                x: 4279,
+                y: 2892,
+                url: "/maps/tiles/osm/13/4279/2892.png".to_string(),
+            }
+        );
+
+        let debug = listing("/maps/coords/tiles?bbox=8.07,46.64,8.12,46.67&zoom=13&tileset=debug")
+            .await
+            .unwrap();
+        assert!(debug.tiles[0].url.starts_with("/maps/tiles/debug/"));
+    }
+
+    #[actix_web::test]
+    async fn test_viewport_tiles_refused() {
+        let refused = |uri: &'static str| async move { listing(uri).await.unwrap_err() };
+        assert_eq!(refused("/maps/coords/tiles?bbox=8,46,9&zoom=13").await, 400);
+        assert_eq!(
+            refused("/maps/coords/tiles?bbox=9,46,8,47&zoom=13").await,
+            400
+        );
+        assert_eq!(
+            refused("/maps/coords/tiles?bbox=8,46,9,47&zoom=25").await,
+            400
+        );
+        // Switzerland at zoom 15 is tens of thousands of tiles
+        assert_eq!(
+            refused("/maps/coords/tiles?bbox=6,46,10,47.8&zoom=15").await,
+            400
+        );
+        assert_eq!(
+            refused("/maps/coords/tiles?bbox=8,46,8.1,46.1&zoom=13&tileset=mars").await,
+            400
+        );
+        assert_eq!(
+            refused("/maps/coords/tiles?bbox=8,46,8.1,46.1&zoom=13&tileset=swisstopo").await,
+            403
+        );
+    }
+
+    async fn stream(uri: &str) -> (u16, String, Bytes) {
+        // Every tile around the Grosse Scheidegg but the top left one
+        let fetcher = [(4280, 2892), (4279, 2893), (4280, 2893)]
+            .into_iter()
+            .fold(MemoryFetcher::default(), |fetcher, (x, y)| {
+                fetcher.with_tile(TileSet::Osm, x, y, 13, Bytes::from_static(b"png"))
+            });
+        let sources = TileSources::default().with_source(TileSet::Osm, Box::new(fetcher));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(sources))
+                .service(stream_tiles),
+        )
+        .await;
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+        let status = response.status().as_u16();
+        let content_type = response
+            .headers()
+            .get("content-type")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        (status, content_type, test::read_body(response).await)
+    }
+
+    #[actix_web::test]
+    async fn test_stream_tiles() {
+        let (status, content_type, body) =
+            stream("/tiles/stream?bbox=8.07,46.64,8.12,46.67&zoom=13&format=ndjson").await;
+        assert_eq!(
+            (status, content_type.as_str()),
+            (200, "application/x-ndjson")
+        );
+        let lines: Vec<serde_json::Value> = std::str::from_utf8(&body)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        let missing: Vec<_> = lines.iter().filter(|l| l.get("error").is_some()).collect();
+        assert_eq!(missing.len(), 1);
+        assert_eq!(
+            (&missing[0]["x"], &missing[0]["y"]),
+            (&json!(4279), &json!(2892))
+        );
+        assert!(lines
+            .iter()
+            .filter_map(|l| l["png"].as_str())
+            .all(|png| png == BASE64.encode(b"png")));
+
+        let (status, content_type, body) =
+            stream("/tiles/stream?bbox=8.07,46.64,8.12,46.67&zoom=13").await;
+        assert_eq!(status, 200);
+        let boundary = content_type
+            .strip_prefix("multipart/mixed; boundary=")
+            .unwrap();
+        let body = String::from_utf8_lossy(&body);
+        assert_eq!(body.matches(&format!("--{}\r\n", boundary)).count(), 4);
+        assert_eq!(body.matches("Content-Type: image/png").count(), 3);
+        assert!(body.contains("X-Tile: 13/4279/2892"));
+        assert!(body.ends_with(&format!("\r\n--{}--\r\n", boundary)));
+
+        assert_eq!(
+            stream("/tiles/stream?bbox=8.07,46.64,8.12,46.67&zoom=13&format=xml")
+                .await
+                .0,
+            400
+        );
+        assert_eq!(
+            stream("/tiles/stream?bbox=8.07,46.64,8.12,46.67&zoom=13&tileset=swisstopo")
+                .await
+                .0,
+            403
+        );
+    }
+}
@@ -0,0 +1,188 @@
+// ! # diff
+// ! POST /diff renders the same area twice and shows where the two renders differ, e.g.
+// ! two tilesets, or swisstopo before and after an update. The body is a POST /images body
+// ! with a "before" and an "after" side, each naming a tileset and/or an archive date,
+// ! which default to the body's tileset and today:
+// !
+// !     {"long": 8.1021, "lat": 46.6556, "size_px": 512, "tileset": "swisstopo",
+// !      "before": {"date": "1990"}, "after": {}}
+// !
+// ! The response is the difference image as a PNG, with the percentage of pixels that
+// ! changed in X-Changed-Percent. An optional "threshold" (default 32) sets how far a
+// ! channel must move for its pixel to count as changed. See tile_render::diff.
+
+use crate::limits::BodyLimits;
+use crate::memory;
+use crate::output::Output;
+use crate::request::{bad_request, parse_body, render_failed};
+use crate::usage::{self, UsageTracker};
+use actix_web::error::ErrorBadRequest;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{post, web, Error, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use tile_render::archive::Date;
+use tile_render::diff::{render_difference, Side, DEFAULT_THRESHOLD};
+use tile_render::fetcher::TileSources;
+use tile_render::request::ImageRequest;
+use tile_render::tiles::{tile_count_for_point, TileSet};
+
+pub const CHANGED_PERCENT_HEADER: &str = "X-Changed-Percent";
+
+#[derive(Debug, Deserialize)]
+struct SideRequest {
+    tileset: Option<String>,
+    date: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DiffRequest {
+    before: SideRequest,
+    after: SideRequest,
+    #[serde(default)]
+    threshold: Option<u8>,
+    #[serde(flatten)]
+    request: ImageRequest,
+}
+
+fn side(side: &SideRequest, request: &ImageRequest) -> Result<Side, Error> {
+    let tileset = match &side.tileset {
+        Some(name) => TileSet::lookup(name)
+            .ok_or_else(|| ErrorBadRequest(format!("Unknown tileset {}", name)))?,
+        None => request.tileset(),
+    };
+    let date = side
+        .date
+        .as_deref()
+        .map(Date::parse)
+        .transpose()
+        .map_err(bad_request)?;
+    Ok(Side { tileset, date })
+}
+
+#[post("/diff")]
+async fn post_diff(
+    req: HttpRequest,
+    body: web::Bytes,
+    limits: web::Data<BodyLimits>,
+    usage: web::Data<UsageTracker>,
+    sources: web::Data<TileSources>,
+) -> Result<HttpResponse, Error> {
+    let api_key = usage::api_key(&req);
+    if let Err(e) = usage.check(&api_key).await {
+        return Ok(HttpResponse::TooManyRequests().body(e));
+    }
+    let diff: DiffRequest = parse_body(&body, &limits)?;
+    let request = &diff.request;
+    if let Some(rejected) = memory::reject_render(&req, request.size_px) {
+        return Ok(rejected);
+    }
+    let options = request.render_options().map_err(bad_request)?;
+    let (before, after) = (side(&diff.before, request)?, side(&diff.after, request)?);
+    if before == after {
+        return Err(ErrorBadRequest("before and after are the same"));
+    }
+
+    let center = request.center();
+    let rendered = render_difference(
+        sources.get_ref(),
+        center,
+        request.radius,
+        request.size_px,
+        before,
+        after,
+        &options,
+        diff.threshold.unwrap_or(DEFAULT_THRESHOLD),
+    )
+    .await;
+    let (image, changed_percent) = match rendered {
+        Ok(rendered) => rendered,
+        Err(e) => return Ok(render_failed(e)),
+    };
+    // Both sides fetched their tiles
+    let tiles = [before, after].map(|side| {
+        tile_count_for_point(
+            center,
+            request.radius,
+            request.size_px,
+            side.tileset,
+            &options,
+        )
+    });
+    usage
+        .record(&api_key, tiles.iter().sum::<u32>() as u64)
+        .await;
+
+    let mut response = Output::Png.respond(image, None).await;
+    let name = HeaderName::try_from(CHANGED_PERCENT_HEADER).expect("a valid header name");
+    if let Ok(value) = HeaderValue::from_str(&format!("{:.2}", changed_percent)) {
+        response.headers_mut().insert(name, value);
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+    use bytes::Bytes;
+    use image::{Rgba, RgbaImage};
+    use rusqlite::Connection;
+    use serde_json::json;
+    use tile_render::fetcher::MemoryFetcher;
+    use tile_render::tiles::encode_png;
+
+    #[actix_web::test]
+    async fn test_diff() {
+        let tile = |color| encode_png(RgbaImage::from_pixel(256, 256, Rgba(color)));
+        let sources = TileSources::default()
+            .with_source(
+                TileSet::Osm,
+                Box::new(MemoryFetcher::default().with_fallback(tile([255, 0, 0, 255]))),
+            )
+            .with_source(
+                TileSet::Esri,
+                Box::new(MemoryFetcher::default().with_fallback(tile([0, 0, 255, 255]))),
+            );
+        let usage = UsageTracker::new(Connection::open_in_memory().unwrap(), None, None).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(usage))
+                .app_data(web::Data::new(sources))
+                .app_data(web::Data::new(BodyLimits::from_env()))
+                .service(post_diff),
+        )
+        .await;
+        let diff = |before: serde_json::Value, after: serde_json::Value| {
+            test::TestRequest::post()
+                .uri("/diff")
+                .set_json(json!({
+                    "long": 8.1021, "lat": 46.6556, "size_px": 64,
+                    "before": before, "after": after,
+                }))
+                .to_request()
+        };
+
+        let response = test::call_service(&app, diff(json!({}), json!({"tileset": "esri"}))).await;
+        assert!(response.status().is_success());
+        assert_eq!(
+            response.headers().get(CHANGED_PERCENT_HEADER).unwrap(),
+            "100.00"
+        );
+        let body: Bytes = test::read_body(response).await;
+        let image = image::load_from_memory(&body).unwrap().to_rgba8();
+        assert_eq!(*image.get_pixel(0, 0), Rgba([255, 0, 255, 255]));
+
+        // The sides must differ, and be sides we can render
+        for before in [
+            json!({}),
+            json!({"date": "last year"}),
+            json!({"tileset": "nope"}),
+        ] {
+            let status = match test::try_call_service(&app, diff(before, json!({}))).await {
+                Ok(response) => response.status().as_u16(),
+                Err(e) => e.as_response_error().status_code().as_u16(),
+            };
+            assert_eq!(status, 400);
+        }
+    }
+}
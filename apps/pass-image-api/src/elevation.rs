@@ -0,0 +1,150 @@
+// ! # elevation
+// ! GET /elevation?lat=&lon= samples the terrain height at a point, in meters, from the
+// ! DEM tiles the terrain tileset serves. POST /elevation does the same for a batch of
+// ! up to tile_render::dem::MAX_ELEVATION_POINTS points, {"points": [{"lat", "lon"}]}, for
+// ! elevation profiles along a route. The tiles go through the usual fetcher, so they're
+// ! cached like any others, and each is fetched once however many points fall in it.
+// ! Points with no data come back with a null elevation.
+
+use crate::limits::BodyLimits;
+use crate::request::{bad_request, parse_body};
+use actix_web::error::ErrorBadGateway;
+use actix_web::{get, post, web, Error, HttpResponse};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tile_render::coordinates::LatLong;
+use tile_render::dem;
+use tile_render::fetcher::TileSources;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+struct Point {
+    lat: f64,
+    lon: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct PointsRequest {
+    points: Vec<Point>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct Elevation {
+    lat: f64,
+    lon: f64,
+    elevation: Option<f32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Elevations {
+    points: Vec<Elevation>,
+}
+
+async fn sample(points: &[Point], sources: &TileSources) -> Result<Vec<Elevation>, Error> {
+    let lat_longs: Vec<LatLong> = points.iter().map(|p| LatLong(p.lat, p.lon)).collect();
+    dem::validate_points(&lat_longs).map_err(bad_request)?;
+    let heights = dem::elevations(sources, &lat_longs).await.map_err(|e| {
+        warn!("Failed to sample elevations: {:#}", e);
+        ErrorBadGateway("Failed to fetch elevation data")
+    })?;
+    Ok(points
+        .iter()
+        .zip(heights)
+        .map(|(point, height)| Elevation {
+            lat: point.lat,
+            lon: point.lon,
+            elevation: Some(height).filter(|h| !h.is_nan()),
+        })
+        .collect())
+}
+
+#[get("/elevation")]
+async fn get_elevation(
+    query: web::Query<Point>,
+    sources: web::Data<TileSources>,
+) -> Result<HttpResponse, Error> {
+    let mut elevations = sample(&[query.into_inner()], &sources).await?;
+    Ok(HttpResponse::Ok().json(elevations.remove(0)))
+}
+
+#[post("/elevation")]
+async fn post_elevations(
+    body: web::Bytes,
+    limits: web::Data<BodyLimits>,
+    sources: web::Data<TileSources>,
+) -> Result<HttpResponse, Error> {
+    let request: PointsRequest = parse_body(&body, &limits)?;
+    let points = sample(&request.points, &sources).await?;
+    Ok(HttpResponse::Ok().json(Elevations { points }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+    use bytes::Bytes;
+    use image::{ImageFormat, Rgba, RgbaImage};
+    use serde_json::json;
+    use std::io::Cursor;
+    use tile_render::fetcher::MemoryFetcher;
+    use tile_render::tiles::TileSet;
+
+    // A Terrarium tile that's 1200m high everywhere
+    fn flat_tile() -> Bytes {
+        let value = 1200.0 + 32768.0;
+        let (r, g) = ((value / 256.0f32).floor() as u8, (value % 256.0) as u8);
+        let image = RgbaImage::from_pixel(256, 256, Rgba([r, g, 0, 255]));
+        let mut png = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        Bytes::from(png)
+    }
+
+    #[actix_web::test]
+    async fn test_elevation() {
+        let fetcher = MemoryFetcher::default().with_fallback(flat_tile());
+        let sources = TileSources::default().with_source(TileSet::Terrain, Box::new(fetcher));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(sources))
+                .app_data(web::Data::new(BodyLimits::from_env()))
+                .service(get_elevation)
+                .service(post_elevations),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/elevation?lat=46.65&lon=8.1")
+            .to_request();
+        let elevation: Elevation = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            elevation,
+            Elevation {
+                lat: 46.65,
+                lon: 8.1,
+                elevation: Some(1200.0)
+            }
+        );
+
+        let req = test::TestRequest::post()
+            .uri("/elevation")
+            .set_json(json!({"points": [{"lat": 46.65, "lon": 8.1}, {"lat": 46.7, "lon": 8.2}]}))
+            .to_request();
+        let elevations: Elevations = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(elevations.points.len(), 2);
+        assert!(elevations
+            .points
+            .iter()
+            .all(|p| p.elevation == Some(1200.0)));
+
+        // Points off the map are the caller's mistake
+        let req = test::TestRequest::get()
+            .uri("/elevation?lat=89&lon=8.1")
+            .to_request();
+        let status = match test::try_call_service(&app, req).await {
+            Ok(response) => response.status(),
+            Err(e) => e.as_response_error().status_code(),
+        };
+        assert_eq!(status, 400);
+    }
+}
@@ -0,0 +1,543 @@
+// ! # export
+// ! Offline regions for mobile apps. POST /export/mbtiles takes a bounding box and a zoom
+// ! range, fetches every tile covering the box at each zoom through the same TileSources
+// ! renders use, and sends them back as an MBTiles file. The file is built on disk first,
+// ! so a tile that can't be fetched fails the export with a 502 rather than a truncated
+// ! download, and is then streamed out in chunks with its size in Content-Length.
+// !
+// ! Tiles are written in the same order every time, so exporting the same tiles again
+// ! gives the same file, and its content hash is the export's ETag. Finished files are
+// ! kept for EXPORT_TTL, by a hash of the canonical request, so the same export asked for
+// ! again, e.g. resuming a download with a Range and the ETag in If-Range, is answered
+// ! from the file rather than fetched again. Files are read on the blocking thread pool.
+// !
+// ! Exports are capped at MAX_EXPORT_TILES tiles, checked before anything is fetched, and
+// ! the ones that are built count against the API key's tile quota. Licensed tilesets
+// ! can't be exported, as their raw tiles can't be proxied either. Tiles are fetched on
+// ! the BatchPool (see the pools module), so exports can't tie up the workers single
+// ! images are rendered on.
+
+use crate::limits::BodyLimits;
+use crate::output::{self, ByteRange};
+use crate::pools::{BatchPool, PoolBusy};
+use crate::request::{bad_request, parse_body};
+use crate::storage::{self, content_hash};
+use crate::usage::{self, UsageTracker};
+use actix_web::body::SizedStream;
+use actix_web::error::{ErrorBadRequest, ErrorInternalServerError};
+use actix_web::http::header::{
+    ContentDisposition, DispositionParam, DispositionType, ETag, EntityTag, HeaderName,
+    ACCEPT_RANGES, CONTENT_RANGE, IF_RANGE, RANGE,
+};
+use actix_web::{post, web, Error, HttpRequest, HttpResponse};
+use anyhow::{anyhow, Context as _, Result};
+use bytes::Bytes;
+use futures::{stream, Stream, StreamExt};
+use log::{info, warn};
+use opentelemetry::Context;
+use rusqlite::{params, Connection};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tile_render::coordinates::{lat_long_to_pixel, LatLong};
+use tile_render::fetcher::{TileFetcher, TileSources};
+use tile_render::tiles::TileSet;
+use uuid::Uuid;
+
+const DEFAULT_MAX_EXPORT_TILES: u64 = 10_000;
+
+// Upstream tile servers mostly stop here
+pub const MAX_ZOOM: u32 = 19;
+
+// Web Mercator stops short of the poles
+const MAX_LATITUDE: f64 = 85.051_128;
+
+// How many tiles are fetched at once, and the size of the chunks the file is sent in
+const FETCH_CONCURRENCY: usize = 8;
+const CHUNK_BYTES: usize = 64 * 1024;
+
+// How long a finished export is kept for downloads to resume from
+const EXPORT_TTL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone, Copy)]
+pub struct ExportLimits {
+    pub max_tiles: u64,
+}
+
+impl ExportLimits {
+    // Reads MAX_EXPORT_TILES, falling back to the default
+    pub fn from_env() -> ExportLimits {
+        ExportLimits {
+            max_tiles: env::var("MAX_EXPORT_TILES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_MAX_EXPORT_TILES),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportRequest {
+    // West, south, east and north edges in degrees
+    pub bbox: [f64; 4],
+    pub min_zoom: u32,
+    pub max_zoom: u32,
+    #[serde(default)]
+    pub tileset: Option<String>,
+    // Names the region in the file's metadata and the download's filename
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+// The tiles covering a bounding box at every zoom in a range
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pyramid {
+    west: f64,
+    south: f64,
+    east: f64,
+    north: f64,
+    pub min_zoom: u32,
+    pub max_zoom: u32,
+}
+
+impl Pyramid {
+    pub fn new(bbox: [f64; 4], min_zoom: u32, max_zoom: u32) -> Result<Pyramid> {
+        let [west, south, east, north] = bbox;
+        if !bbox.iter().all(|v| v.is_finite()) {
+            return Err(anyhow!("bbox must be four numbers"));
+        }
+        if !(-180.0..=180.0).contains(&west) || !(-180.0..=180.0).contains(&east) || west >= east {
+            return Err(anyhow!(
+                "bbox longitudes must be from -180 to 180, west before east"
+            ));
+        }
+        if !(-90.0..=90.0).contains(&south) || !(-90.0..=90.0).contains(&north) || south >= north {
+            return Err(anyhow!(
+                "bbox latitudes must be from -90 to 90, south before north"
+            ));
+        }
+        if min_zoom > max_zoom || max_zoom > MAX_ZOOM {
+            return Err(anyhow!(
+                "Zooms must go from min_zoom up to max_zoom, at most {}",
+                MAX_ZOOM
+            ));
+        }
+        Ok(Pyramid {
+            west,
+            south: south.max(-MAX_LATITUDE),
+            east,
+            north: north.min(MAX_LATITUDE),
+            min_zoom,
+            max_zoom,
+        })
+    }
+
+    // The first and last tile columns and rows covering the box at a zoom
+    pub fn range(&self, zoom: u32) -> ((u32, u32), (u32, u32)) {
+        let last = (1u32 << zoom) - 1;
+        let tile = |point: LatLong| {
+            let (x, y) = lat_long_to_pixel(&point, zoom);
+            let index = |v: f64| ((v / 256.0).floor().max(0.0) as u32).min(last);
+            (index(x), index(y))
+        };
+        let (left, top) = tile(LatLong(self.north, self.west));
+        let (right, bottom) = tile(LatLong(self.south, self.east));
+        ((left, right), (top, bottom))
+    }
+
+    pub fn tile_count(&self) -> u64 {
+        (self.min_zoom..=self.max_zoom)
+            .map(|zoom| {
+                let ((left, right), (top, bottom)) = self.range(zoom);
+                (right - left + 1) as u64 * (bottom - top + 1) as u64
+            })
+            .sum()
+    }
+
+    // Every tile as (z, x, y), lowest zoom first
+    pub fn tiles(&self) -> impl Iterator<Item = (u32, u32, u32)> + '_ {
+        (self.min_zoom..=self.max_zoom).flat_map(move |zoom| {
+            let ((left, right), (top, bottom)) = self.range(zoom);
+            (left..=right).flat_map(move |x| (top..=bottom).map(move |y| (zoom, x, y)))
+        })
+    }
+
+    // As MBTiles metadata has it
+    pub fn bounds(&self) -> String {
+        format!("{},{},{},{}", self.west, self.south, self.east, self.north)
+    }
+}
+
+// A new MBTiles file being filled with tiles
+struct MbTilesWriter {
+    conn: Connection,
+}
+
+impl MbTilesWriter {
+    fn create(path: &Path, metadata: &[(&str, String)]) -> Result<MbTilesWriter> {
+        let conn = Connection::open(path)
+            .with_context(|| format!("creating MBTiles file {}", path.display()))?;
+        conn.execute_batch(
+            "CREATE TABLE metadata (name TEXT, value TEXT);
+             CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER,
+                                 tile_data BLOB);
+             CREATE UNIQUE INDEX tile_index ON tiles (zoom_level, tile_column, tile_row);
+             BEGIN;",
+        )?;
+        for (name, value) in metadata {
+            conn.execute("INSERT INTO metadata VALUES (?1, ?2)", params![name, value])?;
+        }
+        Ok(MbTilesWriter { conn })
+    }
+
+    // MBTiles number rows from the bottom (TMS), so y is flipped
+    fn insert(&self, z: u32, x: u32, y: u32, png: &[u8]) -> Result<()> {
+        let row = (1u64 << z) - 1 - y as u64;
+        self.conn.execute(
+            "INSERT INTO tiles VALUES (?1, ?2, ?3, ?4)",
+            params![z, x, row, png],
+        )?;
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        self.conn.execute_batch("COMMIT;")?;
+        Ok(())
+    }
+}
+
+// Fetches every tile in the pyramid into a new MBTiles file
+pub async fn export(
+    fetcher: &dyn TileFetcher,
+    tileset: TileSet,
+    pyramid: &Pyramid,
+    name: &str,
+    path: &Path,
+) -> Result<()> {
+    let metadata = [
+        ("name", name.to_string()),
+        ("format", "png".to_string()),
+        ("type", "baselayer".to_string()),
+        ("bounds", pyramid.bounds()),
+        ("minzoom", pyramid.min_zoom.to_string()),
+        ("maxzoom", pyramid.max_zoom.to_string()),
+        ("attribution", tileset.attribution().to_string()),
+    ];
+    let writer = MbTilesWriter::create(path, &metadata)?;
+
+    let cx = Context::current();
+    let mut fetched = stream::iter(pyramid.tiles())
+        .map(|(z, x, y)| {
+            let cx = cx.clone();
+            async move {
+                let png = fetcher.fetch(tileset, x, y, z, cx).await?;
+                anyhow::Ok((z, x, y, png))
+            }
+        })
+        // In order, so the same tiles make the same file
+        .buffered(FETCH_CONCURRENCY);
+    while let Some(tile) = fetched.next().await {
+        let (z, x, y, png) = tile?;
+        writer.insert(z, x, y, &png)?;
+    }
+    writer.finish()
+}
+
+// An MBTiles file that has been built, on disk until it expires
+#[derive(Debug, Clone)]
+struct Finished {
+    path: PathBuf,
+    len: u64,
+    hash: String,
+    built: Instant,
+}
+
+// Finished exports by the hash of their request, dropped, files and all, after a while
+pub struct ExportCache {
+    ttl: Duration,
+    finished: Mutex<HashMap<String, Finished>>,
+}
+
+impl Default for ExportCache {
+    fn default() -> ExportCache {
+        ExportCache::new(EXPORT_TTL)
+    }
+}
+
+impl ExportCache {
+    pub fn new(ttl: Duration) -> ExportCache {
+        ExportCache {
+            ttl,
+            finished: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<Finished> {
+        let mut finished = self.finished.lock().unwrap();
+        self.expire(&mut finished);
+        finished.get(key).cloned()
+    }
+
+    fn insert(&self, key: String, export: Finished) {
+        let mut finished = self.finished.lock().unwrap();
+        self.expire(&mut finished);
+        // Two requests for the same export can both build it
+        if let Some(old) = finished.insert(key, export) {
+            let _ = fs::remove_file(old.path);
+        }
+    }
+
+    // Files already being sent stay readable once they're unlinked
+    fn expire(&self, finished: &mut HashMap<String, Finished>) {
+        finished.retain(|_, export| {
+            let fresh = export.built.elapsed() < self.ttl;
+            if !fresh {
+                let _ = fs::remove_file(&export.path);
+            }
+            fresh
+        });
+    }
+}
+
+impl Drop for ExportCache {
+    fn drop(&mut self) {
+        for export in self.finished.get_mut().unwrap().values() {
+            let _ = fs::remove_file(&export.path);
+        }
+    }
+}
+
+// Reads a file, or part of one, out in chunks on the blocking thread pool
+fn chunks(file: impl Read + Send + 'static) -> impl Stream<Item = Result<Bytes, std::io::Error>> {
+    stream::unfold(Some(file), |file| async move {
+        let mut file = file?;
+        let read = web::block(move || {
+            let mut chunk = vec![0; CHUNK_BYTES];
+            let read = file.read(&mut chunk)?;
+            chunk.truncate(read);
+            std::io::Result::Ok((file, chunk))
+        })
+        .await
+        .map_err(std::io::Error::other)
+        .and_then(|read| read);
+        match read {
+            Ok((_, chunk)) if chunk.is_empty() => None,
+            Ok((file, chunk)) => Some((Ok(Bytes::from(chunk)), Some(file))),
+            Err(e) => Some((Err(e), None)),
+        }
+    })
+}
+
+#[post("/export/mbtiles")]
+async fn export_mbtiles(
+    req: HttpRequest,
+    body: web::Bytes,
+    limits: web::Data<BodyLimits>,
+    export_limits: web::Data<ExportLimits>,
+    cache: web::Data<ExportCache>,
+    usage: web::Data<UsageTracker>,
+    sources: web::Data<TileSources>,
+    pool: web::Data<BatchPool>,
+) -> Result<HttpResponse, Error> {
+    let api_key = usage::api_key(&req);
+    if let Err(e) = usage.check(&api_key).await {
+        return Ok(HttpResponse::TooManyRequests().body(e));
+    }
+
+    let request: ExportRequest = parse_body(&body, &limits)?;
+    let tileset = match request.tileset.as_deref() {
+        None => TileSet::Osm,
+        Some(name) => TileSet::lookup(name)
+            .ok_or_else(|| ErrorBadRequest(format!("Unknown tileset {}", name)))?,
+    };
+    if tileset.is_licensed() {
+        return Ok(HttpResponse::Forbidden().body(format!(
+            "Tiles from {} can't be exported; request a rendered image instead",
+            tileset.name()
+        )));
+    }
+    let pyramid =
+        Pyramid::new(request.bbox, request.min_zoom, request.max_zoom).map_err(bad_request)?;
+    let tiles = pyramid.tile_count();
+    if tiles > export_limits.max_tiles {
+        return Err(ErrorBadRequest(format!(
+            "Export of {} tiles exceeds the limit of {}; shrink the bbox or zoom range",
+            tiles, export_limits.max_tiles
+        )));
+    }
+
+    let safe = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    let name = match request.name {
+        Some(name) if !name.is_empty() && name.chars().all(safe) => name,
+        Some(_) => {
+            return Err(ErrorBadRequest(
+                "name may only contain letters, digits, '-' and '_'",
+            ))
+        }
+        None => "export".to_string(),
+    };
+
+    // Everything the file is made from, with the tileset named and the box clamped
+    let key = content_hash(
+        format!(
+            "{}/{}/{}/{}/{}",
+            tileset.name(),
+            pyramid.bounds(),
+            pyramid.min_zoom,
+            pyramid.max_zoom,
+            name
+        )
+        .as_bytes(),
+    );
+    let finished = match cache.get(&key) {
+        Some(finished) => finished,
+        None => {
+            info!(
+                tileset = tileset.name(),
+                tiles = tiles;
+                "Exporting MBTiles"
+            );
+            let path = env::temp_dir().join(format!("export-{}.mbtiles", Uuid::new_v4()));
+            let built = {
+                let (sources, name, path) = (sources.clone(), name.clone(), path.clone());
+                pool.run(move || async move {
+                    export(sources.get_ref(), tileset, &pyramid, &name, &path).await?;
+                    let mut file = File::open(&path)?;
+                    let len = file.metadata()?.len();
+                    let hash = storage::read_hash(&mut file)?;
+                    anyhow::Ok(Finished {
+                        path,
+                        len,
+                        hash,
+                        built: Instant::now(),
+                    })
+                })
+                .await
+                .and_then(|built| built)
+            };
+            let finished = match built {
+                Ok(finished) => finished,
+                Err(e) => {
+                    let _ = fs::remove_file(&path);
+                    if e.is::<PoolBusy>() {
+                        return Ok(PoolBusy.response());
+                    }
+                    warn!("MBTiles export failed: {:#}", e);
+                    return Ok(HttpResponse::BadGateway().body("Couldn't fetch every tile"));
+                }
+            };
+            usage.record(&api_key, tiles).await;
+            cache.insert(key, finished.clone());
+            finished
+        }
+    };
+
+    let header = |name: HeaderName| req.headers().get(name).and_then(|val| val.to_str().ok());
+    let (len, etag) = (finished.len, EntityTag::new_strong(finished.hash));
+    let (mut response, start, end) =
+        match output::requested_range(header(RANGE), header(IF_RANGE), &etag.to_string(), len) {
+            ByteRange::Whole => (HttpResponse::Ok(), 0, len),
+            ByteRange::Part(start, end) => {
+                let mut response = HttpResponse::PartialContent();
+                response.insert_header((CONTENT_RANGE, output::content_range(start, end, len)));
+                (response, start, end)
+            }
+            ByteRange::Unsatisfiable => return Ok(output::range_not_satisfiable(len)),
+        };
+    let file = web::block(move || {
+        let mut file = File::open(&finished.path)?;
+        file.seek(SeekFrom::Start(start))?;
+        std::io::Result::Ok(file.take(end - start))
+    })
+    .await?
+    .map_err(|e| ErrorInternalServerError(e.to_string()))?;
+    Ok(response
+        .content_type("application/vnd.sqlite3")
+        .insert_header(ETag(etag))
+        .insert_header((ACCEPT_RANGES, "bytes"))
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(format!("{}.mbtiles", name))],
+        })
+        .body(SizedStream::new(end - start, chunks(file))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tile_render::fetcher::{MbTilesFetcher, MemoryFetcher};
+
+    #[test]
+    fn test_pyramid() {
+        // Around the Grimsel and Furka passes
+        let pyramid = Pyramid::new([8.3, 46.5, 8.5, 46.6], 8, 10).unwrap();
+        assert_eq!(pyramid.range(8), ((133, 134), (90, 90)));
+        assert_eq!(pyramid.tile_count(), 10);
+        assert_eq!(pyramid.range(10), ((535, 536), (361, 362)));
+        let tiles: Vec<(u32, u32, u32)> = pyramid.tiles().collect();
+        assert_eq!(tiles.len() as u64, pyramid.tile_count());
+        assert_eq!(tiles[0], (8, 133, 90));
+        assert_eq!(*tiles.last().unwrap(), (10, 536, 362));
+
+        // The whole world at zoom 1 is every tile, with the poles clamped
+        let world = Pyramid::new([-180.0, -90.0, 180.0, 90.0], 0, 1).unwrap();
+        assert_eq!(world.tile_count(), 5);
+
+        assert!(Pyramid::new([8.5, 46.5, 8.3, 46.6], 8, 10).is_err());
+        assert!(Pyramid::new([8.3, 46.6, 8.5, 46.5], 8, 10).is_err());
+        assert!(Pyramid::new([8.3, 46.5, 8.5, 46.6], 10, 8).is_err());
+        assert!(Pyramid::new([8.3, 46.5, 8.5, 46.6], 8, MAX_ZOOM + 1).is_err());
+        assert!(Pyramid::new([f64::NAN, 46.5, 8.5, 46.6], 8, 10).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_export() {
+        let pyramid = Pyramid::new([8.3, 46.5, 8.5, 46.6], 8, 10).unwrap();
+        let fetcher = MemoryFetcher::default()
+            .with_tile(TileSet::Osm, 536, 362, 10, Bytes::from_static(b"corner"))
+            .with_fallback(Bytes::from_static(b"png"));
+        let path = env::temp_dir().join(format!("export-test-{}.mbtiles", Uuid::new_v4()));
+        export(&fetcher, TileSet::Osm, &pyramid, "grimsel", &path)
+            .await
+            .unwrap();
+
+        // The file reads back the way tile sources read MBTiles
+        let exported = MbTilesFetcher::open(path.to_str().unwrap()).unwrap();
+        let corner = exported
+            .fetch(TileSet::Osm, 536, 362, 10, Context::new())
+            .await
+            .unwrap();
+        assert_eq!(corner, Bytes::from_static(b"corner"));
+        assert!(exported
+            .fetch(TileSet::Osm, 537, 362, 10, Context::new())
+            .await
+            .is_err());
+
+        let conn = Connection::open(&path).unwrap();
+        let count: u64 = conn
+            .query_row("SELECT COUNT(*) FROM tiles", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, pyramid.tile_count());
+        let bounds: String = conn
+            .query_row(
+                "SELECT value FROM metadata WHERE name = 'bounds'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(bounds, "8.3,46.5,8.5,46.6");
+        fs::remove_file(&path).unwrap();
+
+        // A missing tile fails the export
+        let empty = MemoryFetcher::default();
+        assert!(export(&empty, TileSet::Osm, &pyramid, "grimsel", &path)
+            .await
+            .is_err());
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,195 @@
+// ! # faults
+// ! Opt-in fault injection for chaos tests and demos, on top of tile_render::faults. With
+// ! FAULT_INJECTION=true, upstream tile fetches can be slowed down, failed or corrupted
+// ! per tileset, either for a single request with the X-Inject-Faults header or for every
+// ! request with the /admin/faults endpoints. Without it the header is ignored and the
+// ! endpoints are 404s, so production can't be made to misbehave.
+
+use crate::request::bad_request;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{delete, get, put, web, Error, HttpResponse};
+use log::{info, warn};
+use std::env;
+use tile_render::faults::{self, FaultPlan};
+use tile_render::fetcher::TileSources;
+
+pub const FAULTS_HEADER: &str = "X-Inject-Faults";
+
+// Whether FAULT_INJECTION is on
+pub fn enabled_from_env() -> bool {
+    let enabled = env::var("FAULT_INJECTION").is_ok_and(|v| v == "true");
+    if enabled {
+        warn!("Fault injection is enabled");
+    }
+    enabled
+}
+
+fn enabled(req: &ServiceRequest) -> bool {
+    req.app_data::<web::Data<TileSources>>()
+        .is_some_and(|sources| sources.faults_enabled())
+}
+
+// Middleware running a request under the fault plan in its X-Inject-Faults header. Only
+// fetches made while handling the request see it; a job it submits renders without.
+pub async fn scope_request(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let header = req
+        .headers()
+        .get(FAULTS_HEADER)
+        .and_then(|val| val.to_str().ok());
+    match header {
+        Some(header) if enabled(&req) => {
+            let plan = faults::parse(header).map_err(bad_request)?;
+            info!("Injecting faults into {}: {}", req.path(), header);
+            faults::scope(plan, next.call(req)).await
+        }
+        _ => next.call(req).await,
+    }
+}
+
+fn not_found() -> HttpResponse {
+    HttpResponse::NotFound().finish()
+}
+
+#[get("/admin/faults")]
+async fn get_faults(sources: web::Data<TileSources>) -> HttpResponse {
+    if !sources.faults_enabled() {
+        return not_found();
+    }
+    HttpResponse::Ok().json(faults::plan())
+}
+
+// Replaces the plan for every request, e.g. {"osm": {"latency_ms": 500, "error_rate": 0.2}}
+#[put("/admin/faults")]
+async fn put_faults(
+    plan: web::Json<FaultPlan>,
+    sources: web::Data<TileSources>,
+) -> Result<HttpResponse, Error> {
+    if !sources.faults_enabled() {
+        return Ok(not_found());
+    }
+    let plan = plan.into_inner();
+    faults::set_plan(plan.clone()).map_err(bad_request)?;
+    warn!("Fault plan set: {:?}", plan);
+    Ok(HttpResponse::Ok().json(plan))
+}
+
+#[delete("/admin/faults")]
+async fn delete_faults(sources: web::Data<TileSources>) -> HttpResponse {
+    if !sources.faults_enabled() {
+        return not_found();
+    }
+    faults::set_plan(FaultPlan::new()).expect("an empty plan is valid");
+    info!("Fault plan cleared");
+    HttpResponse::NoContent().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{middleware::from_fn, test, App};
+    use bytes::Bytes;
+    use opentelemetry::Context;
+    use tile_render::fetcher::{MemoryFetcher, TileFetcher};
+    use tile_render::tiles::TileSet;
+
+    // Fetches a tile under the request's plan, reporting whether it came through intact
+    #[actix_web::get("/tile")]
+    async fn fetch_tile(sources: web::Data<TileSources>) -> HttpResponse {
+        match sources.fetch(TileSet::Osm, 1, 2, 3, Context::new()).await {
+            Ok(tile) if tile == Bytes::from_static(b"tile") => HttpResponse::Ok().finish(),
+            Ok(_) => HttpResponse::UnprocessableEntity().finish(),
+            Err(_) => HttpResponse::BadGateway().finish(),
+        }
+    }
+
+    fn sources(faults: bool) -> web::Data<TileSources> {
+        let fetcher = MemoryFetcher::default().with_fallback(Bytes::from_static(b"tile"));
+        let sources = TileSources::default().with_source(TileSet::Osm, Box::new(fetcher));
+        web::Data::new(if faults {
+            sources.with_faults()
+        } else {
+            sources
+        })
+    }
+
+    async fn status(faults: bool, header: Option<&str>) -> u16 {
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(scope_request))
+                .app_data(sources(faults))
+                .service(fetch_tile),
+        )
+        .await;
+        let mut req = test::TestRequest::get().uri("/tile");
+        if let Some(header) = header {
+            req = req.insert_header((FAULTS_HEADER, header));
+        }
+        match test::try_call_service(&app, req.to_request()).await {
+            Ok(response) => response.status().as_u16(),
+            Err(e) => e.as_response_error().status_code().as_u16(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_header_injects_faults() {
+        assert_eq!(status(true, None).await, 200);
+        assert_eq!(status(true, Some("osm=error:1")).await, 502);
+        assert_eq!(status(true, Some("*=corrupt:1")).await, 422);
+        assert_eq!(status(true, Some("swisstopo=error:1")).await, 200);
+        assert_eq!(status(true, Some("osm=explode:1")).await, 400);
+
+        // Ignored unless fault injection is on
+        assert_eq!(status(false, Some("osm=error:1")).await, 200);
+    }
+
+    // The process-wide plan is shared by every test, so it's only touched here
+    #[actix_web::test]
+    async fn test_admin_endpoints() {
+        let put = |body: serde_json::Value| {
+            test::TestRequest::put()
+                .uri("/admin/faults")
+                .set_json(body)
+                .to_request()
+        };
+        let plan = serde_json::json!({"osm": {"latency_ms": 10, "error_rate": 1.0}});
+
+        let disabled =
+            test::init_service(App::new().app_data(sources(false)).service(put_faults)).await;
+        let response = test::call_service(&disabled, put(plan.clone())).await;
+        assert_eq!(response.status(), 404);
+        assert!(faults::plan().is_empty());
+
+        let app = test::init_service(
+            App::new()
+                .app_data(sources(true))
+                .service(get_faults)
+                .service(put_faults)
+                .service(delete_faults)
+                .service(fetch_tile),
+        )
+        .await;
+        let invalid = serde_json::json!({"osm": {"error_rate": 2.0}});
+        assert_eq!(test::call_service(&app, put(invalid)).await.status(), 400);
+        assert!(test::call_service(&app, put(plan))
+            .await
+            .status()
+            .is_success());
+        let req = test::TestRequest::get().uri("/admin/faults").to_request();
+        let current: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(current["osm"]["error_rate"], 1.0);
+        let req = test::TestRequest::get().uri("/tile").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 502);
+
+        let req = test::TestRequest::delete()
+            .uri("/admin/faults")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 204);
+        let req = test::TestRequest::get().uri("/tile").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+    }
+}
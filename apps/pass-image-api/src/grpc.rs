@@ -0,0 +1,511 @@
+// ! # grpc
+// ! A gRPC API alongside the REST one, for services in the stack that would rather not
+// ! build query strings. It's defined in proto/pass_image.proto: RenderImage renders one
+// ! image and streams progress as tiles arrive, RenderBatch does the same for several
+// ! renders in turn, and EstimateRequest says how many upstream tiles a render would use.
+// ! Requests go through the same IP filter, body limits and usage quotas as REST ones.
+// !
+// ! tonic needs Send futures but the render pipeline's aren't (awc's aren't), so renders
+// ! run on a RenderWorker thread with its own actix runtime and report back over channels.
+
+// tonic::Status is big, but it's what every handler here returns
+#![allow(clippy::result_large_err)]
+
+use crate::ip_filter::IpFilter;
+use crate::limits::BodyLimits;
+use crate::usage::{UsageTracker, ANONYMOUS_KEY, API_KEY_HEADER};
+use actix_web::web;
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use futures::future::LocalBoxFuture;
+use log::{info, warn};
+use opentelemetry::Context;
+use std::env;
+use std::net::SocketAddr;
+use tile_render::fetcher::{TileFetcher, TileSources};
+use tile_render::request::{ImageRequest, RenderParams};
+use tile_render::tiles::{
+    fetch_image_from_point, tile_count_for_point, Format, RenderOptions, TileSet,
+};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::metadata::MetadataMap;
+use tonic::{Request, Response, Status};
+
+pub mod proto {
+    tonic::include_proto!("passimage.v1");
+}
+
+use proto::pass_image_server::{PassImage, PassImageServer};
+use proto::{
+    render_event, render_result, Estimate, Progress, RenderBatchRequest, RenderEvent,
+    RenderRequest, RenderResult,
+};
+
+// How many events can queue up for a slow client before rendering waits for it
+const EVENT_BUFFER: usize = 32;
+
+// Where the gRPC server listens, if anywhere. It's off unless GRPC_PORT is set, and a
+// port of 0 turns it off too.
+pub fn addr_from_env() -> Result<Option<SocketAddr>> {
+    let Ok(port) = env::var("GRPC_PORT") else {
+        return Ok(None);
+    };
+    let port = port
+        .parse::<u16>()
+        .map_err(|e| anyhow!("Invalid GRPC_PORT {}: {}", port, e))?;
+    Ok((port != 0).then(|| SocketAddr::from(([0, 0, 0, 0], port))))
+}
+
+// A render that has been checked and is ready to go
+struct Render {
+    request: ImageRequest,
+    options: RenderOptions,
+    tiles: u32,
+}
+
+impl Render {
+    fn new(request: RenderRequest, limits: &BodyLimits) -> Result<Render, Status> {
+        let request = image_request(request, limits)?;
+        let options = request
+            .render_options()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        // Results carry their image as a png
+        if options.format != Format::Png {
+            return Err(Status::invalid_argument("gRPC renders are always PNGs"));
+        }
+        let tiles = tile_count_for_point(
+            request.center(),
+            request.radius,
+            request.size_px,
+            request.tileset(),
+            &options,
+        );
+        Ok(Render {
+            request,
+            options,
+            tiles,
+        })
+    }
+}
+
+// Turns a RenderRequest into the ImageRequest a POST /images body would have given
+fn image_request(request: RenderRequest, limits: &BodyLimits) -> Result<ImageRequest, Status> {
+    let geojson = [&request.overlay, &request.focus_area, &request.crop];
+    limits
+        .check(geojson.map(String::as_str).concat().as_bytes())
+        .map_err(Status::invalid_argument)?;
+    let parse = |name: &str, geojson: &str| match geojson {
+        "" => Ok(None),
+        geojson => serde_json::from_str(geojson)
+            .map(Some)
+            .map_err(|e| Status::invalid_argument(format!("Invalid {}: {}", name, e))),
+    };
+
+    // The params are formatted as query parameters, so they're read the way GET /images
+    // reads its query string
+    let query = serde_urlencoded::to_string(&request.params)
+        .map_err(|e| Status::invalid_argument(e.to_string()))?;
+    let params: RenderParams = serde_urlencoded::from_str(&query)
+        .map_err(|e| Status::invalid_argument(format!("Invalid params: {}", e)))?;
+
+    Ok(ImageRequest {
+        long: request.long,
+        lat: request.lat,
+        size_px: request.size_px,
+        radius: request.radius.unwrap_or(1.0),
+        tileset: Some(request.tileset).filter(|t| !t.is_empty()),
+        overlay: parse("overlay", &request.overlay)?,
+        focus_area: parse("focus_area", &request.focus_area)?,
+        crop: parse("crop", &request.crop)?,
+        params,
+    })
+}
+
+// Works out which API key a request should be accounted against
+fn api_key(metadata: &MetadataMap) -> String {
+    metadata
+        .get(API_KEY_HEADER.to_lowercase().as_str())
+        .and_then(|val| val.to_str().ok())
+        .filter(|key| !key.is_empty())
+        .unwrap_or(ANONYMOUS_KEY)
+        .to_string()
+}
+
+enum WorkerEvent {
+    TileFetched,
+    Finished(Result<Bytes>),
+}
+
+struct RenderJob {
+    render: Render,
+    events: mpsc::UnboundedSender<WorkerEvent>,
+}
+
+// Counts tiles as they arrive, to report progress
+struct CountingFetcher<'a> {
+    inner: &'a dyn TileFetcher,
+    events: &'a mpsc::UnboundedSender<WorkerEvent>,
+}
+
+impl TileFetcher for CountingFetcher<'_> {
+    fn fetch(
+        &self,
+        tileset: TileSet,
+        x: u32,
+        y: u32,
+        z: u32,
+        cx: Context,
+    ) -> LocalBoxFuture<'_, Result<Bytes>> {
+        Box::pin(async move {
+            let tile = self.inner.fetch(tileset, x, y, z, cx).await;
+            let _ = self.events.send(WorkerEvent::TileFetched);
+            tile
+        })
+    }
+}
+
+// A thread that renders images for the gRPC handlers
+#[derive(Clone)]
+pub struct RenderWorker {
+    jobs: mpsc::UnboundedSender<RenderJob>,
+}
+
+impl RenderWorker {
+    pub fn start(sources: web::Data<TileSources>) -> RenderWorker {
+        let (jobs, mut queue) = mpsc::unbounded_channel::<RenderJob>();
+        std::thread::spawn(move || {
+            actix_web::rt::System::new().block_on(async move {
+                while let Some(job) = queue.recv().await {
+                    let sources = sources.clone();
+                    actix_web::rt::spawn(async move {
+                        let fetcher = CountingFetcher {
+                            inner: sources.get_ref(),
+                            events: &job.events,
+                        };
+                        let Render {
+                            request, options, ..
+                        } = &job.render;
+                        let result = fetch_image_from_point(
+                            &fetcher,
+                            request.center(),
+                            request.radius,
+                            request.size_px,
+                            request.tileset(),
+                            options,
+                        )
+                        .await;
+                        let _ = job.events.send(WorkerEvent::Finished(result));
+                    });
+                }
+            })
+        });
+        RenderWorker { jobs }
+    }
+
+    fn submit(&self, render: Render) -> mpsc::UnboundedReceiver<WorkerEvent> {
+        let (events, receiver) = mpsc::unbounded_channel();
+        if let Err(mpsc::error::SendError(job)) = self.jobs.send(RenderJob { render, events }) {
+            let _ = job.events.send(WorkerEvent::Finished(Err(anyhow!(
+                "The render worker has stopped"
+            ))));
+        }
+        receiver
+    }
+}
+
+#[derive(Clone)]
+pub struct PassImageService {
+    worker: RenderWorker,
+    usage: web::Data<UsageTracker>,
+    ip_rules: web::Data<IpFilter>,
+    limits: BodyLimits,
+}
+
+impl PassImageService {
+    pub fn new(
+        worker: RenderWorker,
+        usage: web::Data<UsageTracker>,
+        ip_rules: web::Data<IpFilter>,
+        limits: BodyLimits,
+    ) -> PassImageService {
+        PassImageService {
+            worker,
+            usage,
+            ip_rules,
+            limits,
+        }
+    }
+
+    // Applies the IP filter, returning the API key to account the request against
+    fn admit<T>(&self, request: &Request<T>, method: &str) -> Result<String, Status> {
+        if let Some(peer) = request.remote_addr() {
+            let forwarded_for = request
+                .metadata()
+                .get("x-forwarded-for")
+                .and_then(|val| val.to_str().ok());
+            let client = self.ip_rules.client_ip(peer.ip(), forwarded_for);
+            if !self.ip_rules.permits(&client, method) {
+                warn!("Rejected gRPC request for {} from {}", method, client);
+                return Err(Status::permission_denied("Forbidden"));
+            }
+        }
+        Ok(api_key(request.metadata()))
+    }
+
+    // Renders an image, streaming its progress and result. Returns false if the client
+    // has gone away.
+    async fn render(
+        &self,
+        index: u32,
+        render: Render,
+        api_key: &str,
+        out: &mpsc::Sender<Result<RenderEvent, Status>>,
+    ) -> bool {
+        let event = |event| RenderEvent {
+            index,
+            event: Some(event),
+        };
+        let finished = |outcome| {
+            event(render_event::Event::Result(RenderResult {
+                outcome: Some(outcome),
+            }))
+        };
+
+        if let Err(e) = self.usage.check(api_key).await {
+            let error = render_result::Outcome::Error(e);
+            return out.send(Ok(finished(error))).await.is_ok();
+        }
+
+        let tiles = render.tiles;
+        let mut events = self.worker.submit(render);
+        let mut done = 0;
+        while let Some(worker_event) = events.recv().await {
+            let event = match worker_event {
+                WorkerEvent::TileFetched => {
+                    done += 1;
+                    // Terrain tiles for contours and slope shading aren't in the estimate
+                    event(render_event::Event::Progress(Progress {
+                        tiles_done: done,
+                        tiles_total: tiles.max(done),
+                    }))
+                }
+                WorkerEvent::Finished(Ok(png)) => {
+                    self.usage.record(api_key, tiles as u64).await;
+                    finished(render_result::Outcome::Png(png.to_vec()))
+                }
+                WorkerEvent::Finished(Err(e)) => {
+                    warn!("gRPC render failed: {}", e);
+                    finished(render_result::Outcome::Error(e.to_string()))
+                }
+            };
+            if out.send(Ok(event)).await.is_err() {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[tonic::async_trait]
+impl PassImage for PassImageService {
+    type RenderImageStream = ReceiverStream<Result<RenderEvent, Status>>;
+    type RenderBatchStream = ReceiverStream<Result<RenderEvent, Status>>;
+
+    async fn render_image(
+        &self,
+        request: Request<RenderRequest>,
+    ) -> Result<Response<Self::RenderImageStream>, Status> {
+        let api_key = self.admit(&request, "RenderImage")?;
+        let render = Render::new(request.into_inner(), &self.limits)?;
+        info!(
+            latitude = render.request.lat,
+            longitude = render.request.long;
+            "Rendering image over gRPC"
+        );
+
+        let (out, stream) = mpsc::channel(EVENT_BUFFER);
+        let service = self.clone();
+        tokio::spawn(async move { service.render(0, render, &api_key, &out).await });
+        Ok(Response::new(ReceiverStream::new(stream)))
+    }
+
+    async fn render_batch(
+        &self,
+        request: Request<RenderBatchRequest>,
+    ) -> Result<Response<Self::RenderBatchStream>, Status> {
+        let api_key = self.admit(&request, "RenderBatch")?;
+        // Check every render up front, so a bad one fails the batch before any work
+        let renders = request
+            .into_inner()
+            .renders
+            .into_iter()
+            .map(|r| Render::new(r, &self.limits))
+            .collect::<Result<Vec<_>, Status>>()?;
+        info!(renders = renders.len(); "Rendering batch over gRPC");
+
+        let (out, stream) = mpsc::channel(EVENT_BUFFER);
+        let service = self.clone();
+        tokio::spawn(async move {
+            for (index, render) in renders.into_iter().enumerate() {
+                if !service.render(index as u32, render, &api_key, &out).await {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(stream)))
+    }
+
+    async fn estimate_request(
+        &self,
+        request: Request<RenderRequest>,
+    ) -> Result<Response<Estimate>, Status> {
+        let api_key = self.admit(&request, "EstimateRequest")?;
+        let render = Render::new(request.into_inner(), &self.limits)?;
+        Ok(Response::new(Estimate {
+            tiles: render.tiles,
+            within_quota: self.usage.check(&api_key).await.is_ok(),
+        }))
+    }
+}
+
+// Serves the gRPC API on a thread of its own until the process exits
+pub fn serve(addr: SocketAddr, service: PassImageService) {
+    std::thread::spawn(move || {
+        let runtime = match tokio::runtime::Runtime::new() {
+            Ok(runtime) => runtime,
+            Err(e) => return warn!("Couldn't start the gRPC runtime: {}", e),
+        };
+        info!("Serving gRPC on {}", addr);
+        let server = tonic::transport::Server::builder()
+            .add_service(PassImageServer::new(service))
+            .serve(addr);
+        if let Err(e) = runtime.block_on(server) {
+            warn!("gRPC server stopped: {}", e);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+    use std::collections::HashMap;
+    use tile_render::fetcher::MemoryFetcher;
+    use tile_render::tiles::encode_png;
+    use tokio_stream::StreamExt;
+
+    const LIMITS: BodyLimits = BodyLimits {
+        max_body_bytes: 4096,
+        max_vertices: 2,
+    };
+
+    fn scheidegg() -> RenderRequest {
+        RenderRequest {
+            long: 8.1,
+            lat: 46.6,
+            size_px: 256,
+            ..Default::default()
+        }
+    }
+
+    fn service() -> PassImageService {
+        let tile = encode_png(RgbaImage::from_pixel(256, 256, Rgba([255, 0, 0, 255])));
+        let sources = TileSources::default().with_source(
+            TileSet::Osm,
+            Box::new(MemoryFetcher::default().with_fallback(tile)),
+        );
+        let usage =
+            UsageTracker::new(rusqlite::Connection::open_in_memory().unwrap(), None, None).unwrap();
+        PassImageService::new(
+            RenderWorker::start(web::Data::new(sources)),
+            web::Data::new(usage),
+            web::Data::new(IpFilter::default()),
+            LIMITS,
+        )
+    }
+
+    #[test]
+    fn test_image_request() {
+        let mut request = scheidegg();
+        request.params = HashMap::from([
+            ("filter".to_string(), "sepia".to_string()),
+            ("contours".to_string(), "50".to_string()),
+        ]);
+        request.overlay = r#"{"type": "Point", "coordinates": [8.1, 46.6]}"#.to_string();
+        let parsed = image_request(request, &LIMITS).unwrap();
+        assert_eq!(parsed.radius, 1.0);
+        assert_eq!(parsed.params.filter.as_deref(), Some("sepia"));
+        assert_eq!(parsed.params.contours, Some(50.0));
+        assert_eq!(parsed.render_options().unwrap().overlays.len(), 1);
+
+        let mut request = scheidegg();
+        request.params = HashMap::from([("contours".to_string(), "lots".to_string())]);
+        assert!(image_request(request, &LIMITS).is_err());
+
+        let mut request = scheidegg();
+        request.overlay =
+            r#"{"type": "MultiPoint", "coordinates": [[1, 2], [3, 4], [5, 6]]}"#.to_string();
+        assert!(image_request(request, &LIMITS).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_render_image_streams_progress() {
+        let service = service();
+        let response = service
+            .render_image(Request::new(scheidegg()))
+            .await
+            .unwrap();
+        let events: Vec<RenderEvent> = response.into_inner().map(Result::unwrap).collect().await;
+
+        let (last, progress) = events.split_last().unwrap();
+        assert!(!progress.is_empty());
+        assert!(progress.iter().all(|e| matches!(
+            e.event,
+            Some(render_event::Event::Progress(Progress { tiles_done, tiles_total }))
+                if tiles_done <= tiles_total
+        )));
+        match &last.event {
+            Some(render_event::Event::Result(RenderResult {
+                outcome: Some(render_result::Outcome::Png(png)),
+            })) => assert!(image::load_from_memory(png).is_ok()),
+            other => panic!("Expected an image, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_render_batch_and_estimate() {
+        let service = service();
+        let mut second = scheidegg();
+        second.size_px = 128;
+        let batch = RenderBatchRequest {
+            renders: vec![scheidegg(), second],
+        };
+        let events: Vec<RenderEvent> = service
+            .render_batch(Request::new(batch))
+            .await
+            .unwrap()
+            .into_inner()
+            .map(Result::unwrap)
+            .filter(|e| matches!(e.event, Some(render_event::Event::Result(_))))
+            .collect()
+            .await;
+        assert_eq!(events.iter().map(|e| e.index).collect::<Vec<_>>(), [0, 1]);
+
+        let estimate = service
+            .estimate_request(Request::new(scheidegg()))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(estimate.tiles > 0);
+        assert!(estimate.within_quota);
+
+        let mut bad = scheidegg();
+        bad.params = HashMap::from([("filter".to_string(), "neon".to_string())]);
+        let batch = RenderBatchRequest {
+            renders: vec![scheidegg(), bad],
+        };
+        let status = service.render_batch(Request::new(batch)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+}
@@ -0,0 +1,98 @@
+// ! # hints
+// ! Retina images from one URL, by Client Hints. GET /images takes its size in CSS pixels;
+// ! a browser that sends Sec-CH-DPR (or the older DPR) gets the image rendered at that
+// ! many device pixels per CSS pixel, over the same area, so it's sharper rather than
+// ! just bigger. Sec-CH-Width (or Width), the width the image will be shown at in device
+// ! pixels, wins over the DPR. The ratio the image was rendered at comes back in
+// ! Content-DPR.
+// !
+// ! Image responses carry Accept-CH, so browsers start sending the hints, and vary on them,
+// ! so shared caches keep the variants apart. Requests that set ?scale= or ?sizes= have
+// ! said what they want, and the hints are left alone.
+
+use actix_web::http::header::{HeaderName, HeaderValue, VARY};
+use actix_web::{HttpRequest, HttpResponse};
+
+// The most device pixels per CSS pixel an image is rendered at
+const MAX_DPR: f32 = 4.0;
+
+const HINTS: &str = "Sec-CH-DPR, Sec-CH-Width";
+
+// The size to render a GET image at, given its size in CSS pixels, and the DPR that
+// comes to if the hints changed it
+pub fn negotiate(req: &HttpRequest, size_px: u32) -> (u32, Option<f32>) {
+    let hint = |names: [&str; 2]| {
+        names
+            .iter()
+            .find_map(|name| req.headers().get(*name))
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<f32>().ok())
+            .filter(|value| value.is_finite() && *value > 0.0)
+    };
+    let max_px = (size_px as f32 * MAX_DPR).round();
+    let hinted = match (hint(["Sec-CH-Width", "Width"]), hint(["Sec-CH-DPR", "DPR"])) {
+        (Some(width), _) => width.round().clamp(1.0, max_px) as u32,
+        (None, Some(dpr)) => (size_px as f32 * dpr.min(MAX_DPR)).round().max(1.0) as u32,
+        (None, None) => return (size_px, None),
+    };
+    if hinted == size_px {
+        return (size_px, None);
+    }
+    (hinted, Some(hinted as f32 / size_px as f32))
+}
+
+// Asks for the hints, varies on them, and says what DPR the image was rendered at
+pub fn respond(mut response: HttpResponse, dpr: Option<f32>) -> HttpResponse {
+    let headers = response.headers_mut();
+    headers.insert(
+        HeaderName::from_static("accept-ch"),
+        HeaderValue::from_static(HINTS),
+    );
+    headers.append(VARY, HeaderValue::from_static(HINTS));
+    if let Some(dpr) = dpr.filter(|_| response.status().is_success()) {
+        let dpr = format!("{:.2}", dpr);
+        let dpr = dpr.trim_end_matches('0').trim_end_matches('.');
+        if let Ok(value) = HeaderValue::from_str(dpr) {
+            response
+                .headers_mut()
+                .insert(HeaderName::from_static("content-dpr"), value);
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_negotiate() {
+        let negotiate = |headers: &[(&str, &str)]| {
+            let mut req = TestRequest::get();
+            for header in headers {
+                req = req.insert_header(*header);
+            }
+            negotiate(&req.to_http_request(), 256)
+        };
+        assert_eq!(negotiate(&[]), (256, None));
+        assert_eq!(negotiate(&[("Sec-CH-DPR", "2")]), (512, Some(2.0)));
+        assert_eq!(negotiate(&[("DPR", "1.5")]), (384, Some(1.5)));
+        assert_eq!(negotiate(&[("Sec-CH-DPR", "1")]), (256, None));
+        assert_eq!(negotiate(&[("Sec-CH-DPR", "10")]), (1024, Some(4.0)));
+        assert_eq!(negotiate(&[("Sec-CH-DPR", "nope")]), (256, None));
+        // The width wins, within limits
+        assert_eq!(
+            negotiate(&[("Sec-CH-DPR", "2"), ("Sec-CH-Width", "640")]),
+            (640, Some(2.5))
+        );
+        assert_eq!(negotiate(&[("Width", "100000")]), (1024, Some(4.0)));
+
+        let response = respond(HttpResponse::Ok().finish(), Some(2.5));
+        assert_eq!(response.headers().get("content-dpr").unwrap(), "2.5");
+        assert_eq!(response.headers().get("accept-ch").unwrap(), HINTS);
+        assert_eq!(response.headers().get(VARY).unwrap(), HINTS);
+        let response = respond(HttpResponse::Ok().finish(), None);
+        assert!(!response.headers().contains_key("content-dpr"));
+    }
+}
@@ -0,0 +1,443 @@
+// ! # history
+// ! A record of recent render requests, so a slow or failed render a user reports can be
+// ! looked up and run again. With HISTORY_DB_PATH set, every GET and POST /images request
+// ! is kept in SQLite with its query string or body, the key it was made with, the status
+// ! it got and how long it took. Only the newest HISTORY_MAX_ENTRIES are kept. Keys are
+// ! kept as their fingerprint, as POST /sign puts in URLs, so the history never holds one.
+// !
+// ! GET /admin/history lists them, newest first, and POST /admin/history/{id}/replay
+// ! renders one again and returns the image, PNG or AVIF as the request asked, with the
+// ! new render's time in Server-Timing. Replays render the requested size alone, whatever
+// ! ?sizes= and ?output= asked for.
+
+use crate::images;
+use crate::limits::BodyLimits;
+use crate::request::{apply_preset, ImageRequest};
+use crate::signing::UrlSigner;
+use crate::usage::ANONYMOUS_KEY;
+use actix_web::error::ErrorBadRequest;
+use actix_web::http::StatusCode;
+use actix_web::{get, post, web, Error, HttpRequest, HttpResponse, Responder};
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use log::warn;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tile_render::avif;
+use tile_render::fetcher::TileSources;
+use tile_render::tiles::fetch_image_from_point;
+
+const DEFAULT_MAX_ENTRIES: u64 = 1000;
+
+// The most entries one listing returns
+const MAX_LISTED: u32 = 500;
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Entry {
+    pub id: i64,
+    // Unix time the request came in
+    pub at: u64,
+    // The fingerprint of the key the request was made with, or anonymous
+    pub key_fingerprint: String,
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    // The JSON body of a POST
+    pub body: Option<String>,
+    pub status: u16,
+    pub duration_ms: u64,
+}
+
+pub struct RequestHistory {
+    conn: Mutex<Connection>,
+    max_entries: u64,
+}
+
+impl RequestHistory {
+    pub fn new(conn: Connection, max_entries: u64) -> Result<RequestHistory> {
+        // Histories from before keys were fingerprinted hold the keys themselves
+        if conn.prepare("SELECT api_key FROM history").is_ok() {
+            conn.execute("DROP TABLE history", [])
+                .context("dropping history with API keys")?;
+        }
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS history (
+                id              INTEGER PRIMARY KEY AUTOINCREMENT,
+                at              INTEGER NOT NULL,
+                key_fingerprint TEXT NOT NULL,
+                method          TEXT NOT NULL,
+                path            TEXT NOT NULL,
+                query           TEXT NOT NULL,
+                body            TEXT,
+                status          INTEGER NOT NULL,
+                duration_ms     INTEGER NOT NULL
+            )",
+            [],
+        )
+        .context("creating history table")?;
+
+        Ok(RequestHistory {
+            conn: Mutex::new(conn),
+            max_entries,
+        })
+    }
+
+    // Opens the database at HISTORY_DB_PATH, if one is configured, keeping
+    // HISTORY_MAX_ENTRIES requests
+    pub fn from_env() -> Result<Option<RequestHistory>> {
+        let Ok(path) = env::var("HISTORY_DB_PATH") else {
+            return Ok(None);
+        };
+        let conn =
+            Connection::open(&path).with_context(|| format!("opening history db at {}", path))?;
+        let max_entries = env::var("HISTORY_MAX_ENTRIES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_MAX_ENTRIES);
+        RequestHistory::new(conn, max_entries).map(Some)
+    }
+
+    // Records a request made with an API key and how it went, dropping the oldest entries
+    // past the limit
+    pub fn record(
+        &self,
+        req: &HttpRequest,
+        signer: &UrlSigner,
+        api_key: &str,
+        body: Option<&[u8]>,
+        status: StatusCode,
+        elapsed: Duration,
+    ) {
+        let body = body.map(|body| String::from_utf8_lossy(body).into_owned());
+        let key_fingerprint = match api_key {
+            ANONYMOUS_KEY => ANONYMOUS_KEY.to_string(),
+            api_key => signer.fingerprint(api_key),
+        };
+        let conn = self.conn.lock().unwrap();
+        let result = conn
+            .execute(
+                "INSERT INTO history
+                 (at, key_fingerprint, method, path, query, body, status, duration_ms)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    now() as i64,
+                    key_fingerprint,
+                    req.method().as_str(),
+                    req.path(),
+                    req.query_string(),
+                    body,
+                    status.as_u16(),
+                    elapsed.as_millis() as i64,
+                ],
+            )
+            .and_then(|_| {
+                conn.execute(
+                    "DELETE FROM history WHERE id <= (SELECT MAX(id) FROM history) - ?1",
+                    params![self.max_entries as i64],
+                )
+            });
+        if let Err(e) = result {
+            warn!("Couldn't record {} in the history: {}", req.path(), e);
+        }
+    }
+
+    // The newest entries, newest first
+    pub fn list(&self, limit: u32) -> Result<Vec<Entry>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, at, key_fingerprint, method, path, query, body, status, duration_ms
+             FROM history ORDER BY id DESC LIMIT ?1",
+        )?;
+        let rows = stmt.query_map(params![limit], entry_from_row)?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    pub fn get(&self, id: i64) -> Result<Option<Entry>> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn
+            .query_row(
+                "SELECT id, at, key_fingerprint, method, path, query, body, status, duration_ms
+                 FROM history WHERE id = ?1",
+                params![id],
+                entry_from_row,
+            )
+            .optional()?)
+    }
+}
+
+fn entry_from_row(row: &Row) -> rusqlite::Result<Entry> {
+    Ok(Entry {
+        id: row.get(0)?,
+        at: row.get::<_, i64>(1)? as u64,
+        key_fingerprint: row.get(2)?,
+        method: row.get(3)?,
+        path: row.get(4)?,
+        query: row.get(5)?,
+        body: row.get(6)?,
+        status: row.get(7)?,
+        duration_ms: row.get::<_, i64>(8)? as u64,
+    })
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// A recorded request, parsed back into what to render the way GET and POST /images
+// parse it, presets and GPX overlays included. GETs have the point, and the size unless a
+// preset gives it, after /images in their path, wherever the API is mounted.
+fn replay_request(entry: &Entry, limits: &BodyLimits) -> Result<ImageRequest, Error> {
+    let mut query: HashMap<String, String> = serde_urlencoded::from_str(&entry.query)
+        .map_err(|e| ErrorBadRequest(format!("Invalid recorded query: {}", e)))?;
+    if let Some(body) = &entry.body {
+        let preset = apply_preset(&mut query)?;
+        return images::post_request(body.as_bytes(), &query, preset, limits);
+    }
+
+    let invalid = || ErrorBadRequest(format!("{} isn't an image path", entry.path));
+    let segments: Vec<&str> = entry.path.split('/').collect();
+    let images = segments
+        .iter()
+        .rposition(|segment| *segment == "images")
+        .ok_or_else(invalid)?;
+    let (long, lat, size_px) = match &segments[images + 1..] {
+        [long, lat] => (long, lat, None),
+        [long, lat, size_px] => (long, lat, Some(size_px.parse().map_err(|_| invalid())?)),
+        _ => return Err(invalid()),
+    };
+    let long = long.parse().map_err(|_| invalid())?;
+    let lat = lat.parse().map_err(|_| invalid())?;
+    let (size_px, params) = images::get_params(&mut query, size_px)?;
+    Ok(images::get_request((long, lat, size_px), &query, params))
+}
+
+async fn render(request: &ImageRequest, sources: &TileSources) -> Result<Bytes> {
+    let options = request.render_options()?;
+    fetch_image_from_point(
+        sources,
+        request.center(),
+        request.radius,
+        request.size_px,
+        request.tileset(),
+        &options,
+    )
+    .await
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    limit: Option<u32>,
+}
+
+#[get("/admin/history")]
+async fn get_history(
+    query: web::Query<HistoryQuery>,
+    history: Option<web::Data<RequestHistory>>,
+) -> impl Responder {
+    let Some(history) = history else {
+        return HttpResponse::NotFound().body("Request history isn't enabled");
+    };
+    let limit = query.limit.unwrap_or(100).min(MAX_LISTED);
+    match history.list(limit) {
+        Ok(entries) => HttpResponse::Ok().json(entries),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[post("/admin/history/{id}/replay")]
+async fn replay(
+    path: web::Path<i64>,
+    history: Option<web::Data<RequestHistory>>,
+    limits: web::Data<BodyLimits>,
+    sources: web::Data<TileSources>,
+) -> impl Responder {
+    let Some(history) = history else {
+        return HttpResponse::NotFound().body("Request history isn't enabled");
+    };
+    let id = path.into_inner();
+    let entry = match history.get(id) {
+        Ok(Some(entry)) => entry,
+        Ok(None) => return HttpResponse::NotFound().body(format!("No request {}", id)),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    let request = match replay_request(&entry, &limits) {
+        Ok(request) => request,
+        Err(e) => return HttpResponse::from_error(e),
+    };
+
+    let started = Instant::now();
+    match render(&request, sources.get_ref()).await {
+        Ok(image) => HttpResponse::Ok()
+            .content_type(avif::image_type(&image).0)
+            .insert_header((
+                "Server-Timing",
+                format!("render;dur={}", started.elapsed().as_millis()),
+            ))
+            .body(image),
+        Err(e) => {
+            warn!("Replaying request {} failed: {:#}", id, e);
+            HttpResponse::InternalServerError().body(format!("{:#}", e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+    use serde_json::json;
+    use tile_render::tiles::TileSet;
+
+    fn history(max_entries: u64) -> RequestHistory {
+        RequestHistory::new(Connection::open_in_memory().unwrap(), max_entries).unwrap()
+    }
+
+    fn signer() -> UrlSigner {
+        UrlSigner::new(b"key".to_vec(), Duration::from_secs(60))
+    }
+
+    fn record(history: &RequestHistory, uri: &str, body: Option<&[u8]>) {
+        let req = test::TestRequest::get().uri(uri).to_http_request();
+        let elapsed = Duration::from_millis(40);
+        history.record(
+            &req,
+            &signer(),
+            ANONYMOUS_KEY,
+            body,
+            StatusCode::OK,
+            elapsed,
+        );
+    }
+
+    #[test]
+    fn test_record_keeps_the_newest() {
+        let history = history(2);
+        record(&history, "/images/8.1/46.6/256", None);
+        record(&history, "/images/8.2/46.6/256?filter=sepia", None);
+        record(&history, "/images/8.3/46.6/256", None);
+
+        let entries = history.list(10).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].path, "/images/8.3/46.6/256");
+        assert_eq!(entries[1].query, "filter=sepia");
+        assert_eq!(entries[1].duration_ms, 40);
+        assert_eq!(entries[1].key_fingerprint, ANONYMOUS_KEY);
+        assert_eq!(
+            history.get(entries[1].id).unwrap(),
+            Some(entries[1].clone())
+        );
+        assert_eq!(history.get(1).unwrap(), None);
+
+        // Keys are only kept as their fingerprint
+        let req = test::TestRequest::get()
+            .uri("/images/8.1/46.6/256")
+            .to_http_request();
+        let elapsed = Duration::from_millis(40);
+        history.record(&req, &signer(), "secret", None, StatusCode::OK, elapsed);
+        let entry = &history.list(1).unwrap()[0];
+        assert_eq!(entry.key_fingerprint, signer().fingerprint("secret"));
+        assert!(!serde_json::to_string(entry).unwrap().contains("secret"));
+    }
+
+    #[test]
+    fn test_parse_replays() {
+        let history = history(10);
+        record(
+            &history,
+            "/maps/images/8.1/46.6/300?radius=2.5&tileset=debug&filter=sepia",
+            None,
+        );
+        record(
+            &history,
+            "/images",
+            Some(br#"{"long": 8.1, "lat": 46.6, "size_px": 128, "tileset": "debug"}"#),
+        );
+        record(&history, "/images/east/46.6/300", None);
+        record(&history, "/images/8.1/46.6?preset=card", None);
+        record(&history, "/images/8.1/46.6/300?preset=hero", None);
+        record(
+            &history,
+            "/images?preset=hero",
+            Some(br#"{"long": 8.1, "lat": 46.6, "format": "avif"}"#),
+        );
+        let gpx = concat!(
+            "<gpx><trk><trkseg>",
+            r#"<trkpt lat="46.6" lon="8.1"/><trkpt lat="46.7" lon="8.2"/>"#,
+            "</trkseg></trk></gpx>",
+        );
+        let body = serde_json::to_vec(&json!({
+            "long": 8.1, "lat": 46.6, "size_px": 128, "overlay": gpx,
+        }))
+        .unwrap();
+        record(&history, "/images", Some(&body));
+
+        let limits = BodyLimits::from_env();
+        let mut entries = history.list(10).unwrap();
+        entries.reverse();
+        let requests: Vec<_> = entries
+            .iter()
+            .map(|entry| replay_request(entry, &limits))
+            .collect();
+        let get = requests[0].as_ref().unwrap();
+        assert_eq!((get.long, get.lat), (8.1, 46.6));
+        assert_eq!((get.radius, get.size_px), (2.5, 300));
+        assert_eq!(get.tileset(), TileSet::Debug);
+        assert_eq!(get.params.filter.as_deref(), Some("sepia"));
+        let post = requests[1].as_ref().unwrap();
+        assert_eq!((post.size_px, post.tileset()), (128, TileSet::Debug));
+        assert!(requests[2].is_err());
+
+        // Presets fill in what the request left out, as they did when it was made
+        let preset = requests[3].as_ref().unwrap();
+        assert_eq!((preset.size_px, preset.radius), (320, 1.0));
+        let sized = requests[4].as_ref().unwrap();
+        assert_eq!((sized.size_px, sized.radius), (300, 4.0));
+        let posted = requests[5].as_ref().unwrap();
+        assert_eq!((posted.size_px, posted.radius), (1600, 4.0));
+        assert!(posted.params.format.is_some());
+
+        // GPX overlays are read as they were
+        let overlay = requests[6].as_ref().unwrap().overlay.as_ref().unwrap();
+        assert_eq!(overlay["type"], "MultiLineString");
+    }
+
+    #[actix_web::test]
+    async fn test_replay_endpoint() {
+        let history = history(10);
+        record(&history, "/images/8.1/46.6/64?tileset=debug", None);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(history))
+                .app_data(web::Data::new(BodyLimits::from_env()))
+                .app_data(web::Data::new(TileSources::default()))
+                .service(get_history)
+                .service(replay),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/admin/history").to_request();
+        let entries: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let id = entries[0]["id"].as_i64().unwrap();
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/admin/history/{}/replay", id))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert!(response.status().is_success());
+        assert!(response.headers().contains_key("Server-Timing"));
+        let image = image::load_from_memory(&test::read_body(response).await).unwrap();
+        assert!(image.width() >= 64);
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/admin/history/{}/replay", id + 1))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 404);
+    }
+}
@@ -0,0 +1,503 @@
+// ! # images
+// ! The core rendering endpoints: GET /images/{long}/{lat}/{size_px} for a point and
+// ! query parameters, POST /images for a JSON body that can carry GeoJSON overlays, and
+// ! GET /tiles/{tileset}/{z}/{x}/{y}.png to proxy raw tiles from unlicensed tilesets.
+// ! Both image endpoints take ?sizes= to render more sizes from the same tiles at once
+// ! and ?report= for a breakdown of the render, and are recorded in the request history
+// ! if it's enabled. ?format=avif encodes images as AVIF rather than PNG; see
+// ! tile_render::avif. With ?format=geojson-extent they send back where the image would
+// ! lie in the world, as a GeoJSON polygon, instead of rendering it. GET /images also takes
+// ! its size from Client Hints; see the hints module. With ?preset= a request fills in
+// ! what it leaves out from a named preset, and GET /images/{long}/{lat} takes its size
+// ! from it; see the presets module. Single images are taken from the render cache when
+// ! it's enabled; see the render_cache module. With prefetching on, the tiles around a
+// ! single image are fetched into the tile cache after it's rendered; see the prefetch
+// ! module.
+
+use crate::export::MAX_ZOOM;
+use crate::hints;
+use crate::history::RequestHistory;
+use crate::limits::BodyLimits;
+use crate::memory;
+use crate::output::{Output, Report};
+use crate::prefetch;
+use crate::render_cache::{self, RenderCache};
+use crate::request::{
+    apply_preset, bad_request, parse_image_request, parse_preset_request, parse_sizes,
+    query_params, render_failed, RenderParams,
+};
+use crate::sign;
+use crate::signing::UrlSigner;
+use crate::storage::ResultStore;
+use crate::usage::{self, UsageTracker};
+use actix_web::error::ErrorBadRequest;
+use actix_web::{get, post, web, Error, HttpRequest, HttpResponse, Responder};
+use log::info;
+use opentelemetry::Context;
+use std::collections::HashMap;
+use std::time::Instant;
+use tile_render::coordinates::LatLong;
+use tile_render::extent::image_extent;
+use tile_render::fetcher::{TileFetcher, TileSources};
+use tile_render::presets::Preset;
+use tile_render::request::ImageRequest;
+use tile_render::tiles::{
+    fetch_image_from_point, fetch_image_variants_from_point, tile_count_for_point, RenderOptions,
+    TileSet,
+};
+
+// Whether ?format= asks for where the image would lie rather than the image. Other
+// formats are checked along with the rest of the RenderParams.
+fn wants_extent(request: &ImageRequest) -> bool {
+    request.params.format.as_deref() == Some("geojson-extent")
+}
+
+// The polygon the image would cover, as a GeoJSON Feature. Nothing is fetched or
+// rendered, so it isn't counted as usage. Every size in ?sizes= covers the same area.
+fn extent_response(
+    center: LatLong,
+    radius_km: f32,
+    size_px: u32,
+    options: &RenderOptions,
+) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/geo+json")
+        .json(image_extent(center, radius_km, size_px, options).to_geojson())
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/images/{long}/{lat}/{size_px}", name = "image")]
+async fn get_image(
+    req: HttpRequest,
+    path: web::Path<(f64, f64, u32)>,
+    query: web::Query<HashMap<String, String>>,
+    usage: web::Data<UsageTracker>,
+    signer: web::Data<UrlSigner>,
+    sources: web::Data<TileSources>,
+    store: Option<web::Data<ResultStore>>,
+    history: Option<web::Data<RequestHistory>>,
+    render_cache: Option<web::Data<RenderCache>>,
+) -> HttpResponse {
+    let (long, lat, size_px) = path.into_inner();
+    serve_get(
+        &req,
+        (long, lat, Some(size_px)),
+        query.into_inner(),
+        &usage,
+        &signer,
+        &sources,
+        store.as_ref(),
+        history,
+        render_cache.as_deref(),
+    )
+    .await
+}
+
+// GET /images without a size, which takes it from the ?preset=
+#[allow(clippy::too_many_arguments)]
+#[get("/images/{long}/{lat}", name = "preset_image")]
+async fn get_preset_image(
+    req: HttpRequest,
+    path: web::Path<(f64, f64)>,
+    query: web::Query<HashMap<String, String>>,
+    usage: web::Data<UsageTracker>,
+    signer: web::Data<UrlSigner>,
+    sources: web::Data<TileSources>,
+    store: Option<web::Data<ResultStore>>,
+    history: Option<web::Data<RequestHistory>>,
+    render_cache: Option<web::Data<RenderCache>>,
+) -> HttpResponse {
+    let (long, lat) = path.into_inner();
+    serve_get(
+        &req,
+        (long, lat, None),
+        query.into_inner(),
+        &usage,
+        &signer,
+        &sources,
+        store.as_ref(),
+        history,
+        render_cache.as_deref(),
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn serve_get(
+    req: &HttpRequest,
+    (long, lat, size_px): (f64, f64, Option<u32>),
+    mut query: HashMap<String, String>,
+    usage: &UsageTracker,
+    signer: &UrlSigner,
+    sources: &TileSources,
+    store: Option<&web::Data<ResultStore>>,
+    history: Option<web::Data<RequestHistory>>,
+    render_cache: Option<&RenderCache>,
+) -> HttpResponse {
+    let started = Instant::now();
+    let api_key = match sign::request_api_key(req, signer, usage).await {
+        Ok(api_key) => api_key,
+        Err(forbidden) => return forbidden,
+    };
+    // Whether the hints apply is up to the request, not its preset
+    let hinted = !query.contains_key("scale") && !query.contains_key("sizes");
+    let response = match get_params(&mut query, size_px) {
+        Ok((size_px, params)) => {
+            let (size_px, dpr) = if hinted {
+                hints::negotiate(req, size_px)
+            } else {
+                (size_px, None)
+            };
+            let request = get_request((long, lat, size_px), &query, params);
+            let response = render_get(
+                req,
+                &api_key,
+                &request,
+                &query,
+                usage,
+                sources,
+                store,
+                render_cache,
+            )
+            .await;
+            hints::respond(response, dpr)
+        }
+        Err(e) => HttpResponse::from_error(e),
+    };
+    if let Some(history) = history {
+        let elapsed = started.elapsed();
+        history.record(req, signer, &api_key, None, response.status(), elapsed);
+    }
+    response
+}
+
+// Applies the request's preset, if it names one, and reads its size and RenderParams.
+// A size in the path wins over the preset's.
+pub fn get_params(
+    query: &mut HashMap<String, String>,
+    size_px: Option<u32>,
+) -> Result<(u32, RenderParams), Error> {
+    let preset = apply_preset(query)?;
+    let size_px = size_px
+        .or(preset.and_then(Preset::size_px))
+        .ok_or_else(|| ErrorBadRequest("No size_px: give one in the path or a preset with one"))?;
+    Ok((size_px, query_params(query)?))
+}
+
+// The render a GET request asks for, canonicalized
+pub fn get_request(
+    (long, lat, size_px): (f64, f64, u32),
+    query: &HashMap<String, String>,
+    params: RenderParams,
+) -> ImageRequest {
+    let mut request = ImageRequest {
+        long,
+        lat,
+        size_px,
+        radius: query
+            .get("radius")
+            .and_then(|r| r.parse().ok())
+            .unwrap_or(1.0),
+        tileset: query.get("tileset").cloned(),
+        overlay: None,
+        focus_area: None,
+        crop: None,
+        params,
+    };
+    request.canonicalize();
+    request
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn render_get(
+    req: &HttpRequest,
+    api_key: &str,
+    request: &ImageRequest,
+    query: &HashMap<String, String>,
+    usage: &UsageTracker,
+    sources: &TileSources,
+    store: Option<&web::Data<ResultStore>>,
+    render_cache: Option<&RenderCache>,
+) -> HttpResponse {
+    if let Err(e) = usage.check(api_key).await {
+        return HttpResponse::TooManyRequests().body(e);
+    }
+    if let Some(rejected) = memory::reject_render(req, request.size_px) {
+        return rejected;
+    }
+    let output = match Output::from_query(query.get("output").map(String::as_str), store) {
+        Ok(output) => output,
+        Err(e) => return HttpResponse::from_error(e),
+    };
+    let report = match Report::from_query(query.get("report").map(String::as_str), output) {
+        Ok(report) => report,
+        Err(e) => return HttpResponse::from_error(e),
+    };
+
+    let (center, radius, size_px) = (request.center(), request.radius, request.size_px);
+    let tileset = request.tileset();
+
+    info!(
+        latitude = request.lat,
+        longitude = request.long;
+        "Fetching image"
+    );
+
+    let options = match request.render_options() {
+        Ok(options) => options,
+        Err(e) => return HttpResponse::from_error(bad_request(e)),
+    };
+    if wants_extent(request) {
+        return extent_response(center, radius, size_px, &options);
+    }
+    if let Some(sizes) = query.get("sizes") {
+        let sizes = match parse_sizes(size_px, sizes) {
+            Ok(sizes) => sizes,
+            Err(e) => return HttpResponse::from_error(bad_request(e)),
+        };
+        let (rendered, render_report) = report
+            .collect(fetch_image_variants_from_point(
+                sources, center, radius, &sizes, tileset, &options,
+            ))
+            .await;
+        let response = match rendered {
+            Ok(images) => {
+                let largest = sizes.iter().copied().max().unwrap_or(size_px);
+                let tiles = tile_count_for_point(center, radius, largest, tileset, &options);
+                usage.record(api_key, tiles as u64).await;
+                output.respond_variants(&sizes, images, store).await
+            }
+            Err(e) => render_failed(e),
+        };
+        return report.respond(response, render_report);
+    }
+    // A report describes a render, so requests for one always render
+    let render_cache = render_cache.filter(|_| report == Report::Off);
+    let render = fetch_image_from_point(sources, center, radius, size_px, tileset, &options);
+    let (rendered, render_report) = report
+        .collect(render_cache::render(render_cache, request, render))
+        .await;
+    let response = match rendered {
+        Ok(image) => {
+            let tiles = tile_count_for_point(center, radius, size_px, tileset, &options);
+            usage.record(api_key, tiles as u64).await;
+            prefetch::after_render(req, center, radius, size_px, tileset, &options);
+            output.respond(image, store).await
+        }
+        Err(e) => render_failed(e),
+    };
+    report.respond(response, render_report)
+}
+
+// Renders an image described by a JSON body, which unlike the GET variant can carry a
+// GeoJSON overlay
+#[allow(clippy::too_many_arguments)]
+#[post("/images")]
+async fn post_image(
+    req: HttpRequest,
+    body: web::Bytes,
+    query: web::Query<HashMap<String, String>>,
+    limits: web::Data<BodyLimits>,
+    usage: web::Data<UsageTracker>,
+    signer: web::Data<UrlSigner>,
+    sources: web::Data<TileSources>,
+    store: Option<web::Data<ResultStore>>,
+    history: Option<web::Data<RequestHistory>>,
+    render_cache: Option<web::Data<RenderCache>>,
+) -> Result<HttpResponse, Error> {
+    let started = Instant::now();
+    let result = render_post(
+        &req,
+        &body,
+        query.into_inner(),
+        &limits,
+        &usage,
+        &sources,
+        store.as_ref(),
+        render_cache.as_deref(),
+    )
+    .await;
+    if let Some(history) = history {
+        let status = match &result {
+            Ok(response) => response.status(),
+            Err(e) => e.as_response_error().status_code(),
+        };
+        let api_key = usage::api_key(&req);
+        let elapsed = started.elapsed();
+        history.record(&req, &signer, &api_key, Some(&body[..]), status, elapsed);
+    }
+    result
+}
+
+// The render a POST body asks for, with the preset its query names already applied to
+// the query, canonicalized
+pub fn post_request(
+    body: &[u8],
+    query: &HashMap<String, String>,
+    preset: Option<&Preset>,
+    limits: &BodyLimits,
+) -> Result<ImageRequest, Error> {
+    let mut request = match preset {
+        Some(preset) => parse_preset_request(body, limits, preset)?,
+        None => parse_image_request(body, limits)?,
+    };
+    // ?format= and ?quality= apply to bodies that don't set them
+    let params = query_params(query)?;
+    request.params.format = request.params.format.take().or(params.format);
+    request.params.quality = request.params.quality.or(params.quality);
+    request.canonicalize();
+    Ok(request)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn render_post(
+    req: &HttpRequest,
+    body: &[u8],
+    mut query: HashMap<String, String>,
+    limits: &BodyLimits,
+    usage: &UsageTracker,
+    sources: &TileSources,
+    store: Option<&web::Data<ResultStore>>,
+    render_cache: Option<&RenderCache>,
+) -> Result<HttpResponse, Error> {
+    let api_key = usage::api_key(req);
+    if let Err(e) = usage.check(&api_key).await {
+        return Ok(HttpResponse::TooManyRequests().body(e));
+    }
+    let preset = apply_preset(&mut query)?;
+    let output = Output::from_query(query.get("output").map(String::as_str), store)?;
+    let report = Report::from_query(query.get("report").map(String::as_str), output)?;
+
+    let request = post_request(body, &query, preset, limits)?;
+    if let Some(rejected) = memory::reject_render(req, request.size_px) {
+        return Ok(rejected);
+    }
+    let options = request.render_options().map_err(bad_request)?;
+    let center = request.center();
+    if wants_extent(&request) {
+        return Ok(extent_response(
+            center,
+            request.radius,
+            request.size_px,
+            &options,
+        ));
+    }
+    let sizes = query
+        .get("sizes")
+        .map(|sizes| parse_sizes(request.size_px, sizes))
+        .transpose()
+        .map_err(bad_request)?;
+
+    info!(
+        latitude = request.lat,
+        longitude = request.long;
+        "Fetching image"
+    );
+
+    if let Some(sizes) = sizes {
+        let (rendered, render_report) = report
+            .collect(fetch_image_variants_from_point(
+                sources,
+                center,
+                request.radius,
+                &sizes,
+                request.tileset(),
+                &options,
+            ))
+            .await;
+        let response = match rendered {
+            Ok(images) => {
+                let largest = sizes.iter().copied().max().unwrap_or(request.size_px);
+                let tiles = tile_count_for_point(
+                    center,
+                    request.radius,
+                    largest,
+                    request.tileset(),
+                    &options,
+                );
+                usage.record(&api_key, tiles as u64).await;
+                output.respond_variants(&sizes, images, store).await
+            }
+            Err(e) => render_failed(e),
+        };
+        return Ok(report.respond(response, render_report));
+    }
+    let render_cache = render_cache.filter(|_| report == Report::Off);
+    let render = fetch_image_from_point(
+        sources,
+        center,
+        request.radius,
+        request.size_px,
+        request.tileset(),
+        &options,
+    );
+    let (rendered, render_report) = report
+        .collect(render_cache::render(render_cache, &request, render))
+        .await;
+    let response = match rendered {
+        Ok(image) => {
+            let tiles = tile_count_for_point(
+                center,
+                request.radius,
+                request.size_px,
+                request.tileset(),
+                &options,
+            );
+            usage.record(&api_key, tiles as u64).await;
+            prefetch::after_render(
+                req,
+                center,
+                request.radius,
+                request.size_px,
+                request.tileset(),
+                &options,
+            );
+            output.respond(image, store).await
+        }
+        Err(e) => render_failed(e),
+    };
+    Ok(report.respond(response, render_report))
+}
+
+// Proxies a single raw tile, with the content type its tileset serves. Licensed
+// tilesets can't be fetched this way, as the raw tiles would come without the
+// attribution we're required to show. Tiles count against the API key's quota.
+#[get("/tiles/{tileset}/{z}/{x}/{y}.png", name = "tile")]
+async fn get_tile(
+    req: HttpRequest,
+    path: web::Path<(String, u32, u32, u32)>,
+    usage: web::Data<UsageTracker>,
+    sources: web::Data<TileSources>,
+) -> impl Responder {
+    let (name, z, x, y) = path.into_inner();
+    let Some(tileset) = TileSet::lookup(&name) else {
+        return HttpResponse::NotFound().body(format!("Unknown tileset {}", name));
+    };
+    if z > MAX_ZOOM || x >= 1 << z || y >= 1 << z {
+        return HttpResponse::BadRequest().body(format!(
+            "No tile {}/{}/{}: zooms go up to {}, and x and y below 2^zoom",
+            z, x, y, MAX_ZOOM
+        ));
+    }
+
+    if tileset.is_licensed() {
+        return HttpResponse::Forbidden().body(format!(
+            "Raw tiles from {} can't be proxied; request a rendered image instead",
+            tileset.name()
+        ));
+    }
+
+    let api_key = usage::api_key(&req);
+    if let Err(e) = usage.check(&api_key).await {
+        return HttpResponse::TooManyRequests().body(e);
+    }
+    match sources.fetch(tileset, x, y, z, Context::current()).await {
+        Ok(tile) => {
+            usage.record(&api_key, 1).await;
+            HttpResponse::Ok()
+                .content_type(tileset.tile_content_type(&tile))
+                .body(tile)
+        }
+        Err(_) => HttpResponse::BadGateway().into(),
+    }
+}
@@ -0,0 +1,329 @@
+// ! # ip_filter
+// ! CIDR-based allow and deny lists, checked before any handler runs. There's a global
+// ! pair of lists for the whole API, and a separate allowlist for /admin endpoints so those
+// ! can be locked to cluster-internal ranges. Unless ADMIN_IP_ALLOWLIST says otherwise,
+// ! they are: only loopback and private (RFC 1918 and IPv6 unique local) addresses may use
+// ! them. When we sit behind a load balancer the client address comes from
+// ! X-Forwarded-For, but only for hops we've been told to trust.
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::ErrorForbidden;
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use std::env;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// Paths the admin allowlist applies to
+const ADMIN_PREFIX: &str = "/admin";
+
+// Who may use /admin endpoints if ADMIN_IP_ALLOWLIST isn't set: loopback and private
+// networks
+const DEFAULT_ADMIN_ALLOWLIST: &str =
+    "127.0.0.0/8, ::1, 10.0.0.0/8, 172.16.0.0/12, 192.168.0.0/16, fc00::/7";
+
+// An IP network, e.g. 10.0.0.0/8. A bare address is treated as a single-host network.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+
+        let network: IpAddr = addr
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("Invalid address in CIDR {}", s))?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .trim()
+                .parse()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| anyhow!("Invalid prefix length in CIDR {}", s))?,
+            None => max_len,
+        };
+
+        Ok(Cidr {
+            network: network.to_canonical(),
+            prefix_len,
+        })
+    }
+}
+
+// Parses a comma separated list of CIDRs
+fn parse_list(list: &str) -> Result<Vec<Cidr>> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|c| !c.is_empty())
+        .map(Cidr::from_str)
+        .collect()
+}
+
+#[derive(Debug, Default)]
+pub struct IpFilter {
+    // If non-empty, only these networks may use the API at all
+    allow: Vec<Cidr>,
+    // These networks may never use the API
+    deny: Vec<Cidr>,
+    // Only these networks may use /admin endpoints
+    admin_allow: Vec<Cidr>,
+    // Proxies whose X-Forwarded-For entries we believe
+    trusted_proxies: Vec<Cidr>,
+}
+
+impl IpFilter {
+    // Builds the filter from IP_ALLOWLIST, IP_DENYLIST, ADMIN_IP_ALLOWLIST and TRUSTED_PROXIES,
+    // each a comma separated list of CIDRs.
+    pub fn from_env() -> Result<IpFilter> {
+        IpFilter::from_vars(|name| env::var(name).ok())
+    }
+
+    // As from_env, with the variables looked up by var
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<IpFilter> {
+        let list = |name: &str, default: &str| -> Result<Vec<Cidr>> {
+            let value = var(name).unwrap_or_else(|| default.to_string());
+            parse_list(&value).map_err(|e| anyhow!("{}: {}", name, e))
+        };
+
+        let filter = IpFilter {
+            allow: list("IP_ALLOWLIST", "")?,
+            deny: list("IP_DENYLIST", "")?,
+            admin_allow: list("ADMIN_IP_ALLOWLIST", DEFAULT_ADMIN_ALLOWLIST)?,
+            trusted_proxies: list("TRUSTED_PROXIES", "")?,
+        };
+        info!(
+            "IP filter: {} allowed, {} denied, {} admin allowed, {} trusted proxies",
+            filter.allow.len(),
+            filter.deny.len(),
+            filter.admin_allow.len(),
+            filter.trusted_proxies.len()
+        );
+        Ok(filter)
+    }
+
+    fn is_trusted_proxy(&self, ip: &IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|c| c.contains(ip))
+    }
+
+    // Works out who the client really is. We start from the peer, and while the hop we're
+    // looking at is a trusted proxy, step back through X-Forwarded-For (right to left).
+    pub fn client_ip(&self, peer: IpAddr, forwarded_for: Option<&str>) -> IpAddr {
+        let mut client = peer;
+        if let Some(forwarded_for) = forwarded_for {
+            for hop in forwarded_for.rsplit(',') {
+                if !self.is_trusted_proxy(&client) {
+                    break;
+                }
+                match hop.trim().parse::<IpAddr>() {
+                    Ok(ip) => client = ip,
+                    Err(_) => break,
+                }
+            }
+        }
+        client
+    }
+
+    // Is the client allowed to request the given path?
+    pub fn permits(&self, client: &IpAddr, path: &str) -> bool {
+        if self.deny.iter().any(|c| c.contains(client)) {
+            return false;
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|c| c.contains(client)) {
+            return false;
+        }
+        if path.starts_with(ADMIN_PREFIX) && !self.admin_allow.iter().any(|c| c.contains(client)) {
+            return false;
+        }
+        true
+    }
+}
+
+// Middleware rejecting requests from clients the IpFilter doesn't permit
+pub async fn check(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    if let (Some(filter), Some(peer)) = (req.app_data::<web::Data<IpFilter>>(), req.peer_addr()) {
+        let forwarded_for = req
+            .headers()
+            .get("X-Forwarded-For")
+            .and_then(|val| val.to_str().ok());
+        let client = filter.client_ip(peer.ip(), forwarded_for);
+        // Relative to where the API is mounted, so /admin is still /admin under a prefix
+        let path = req.match_info().unprocessed();
+
+        if !filter.permits(&client, path) {
+            warn!("Rejected request for {} from {}", req.path(), client);
+            return Err(ErrorForbidden("Forbidden"));
+        }
+    }
+
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.contains(&ip("10.20.30.40")));
+        assert!(!cidr.contains(&ip("11.0.0.1")));
+        assert!(cidr.contains(&ip("::ffff:10.0.0.1")));
+
+        let single: Cidr = "192.168.1.1".parse().unwrap();
+        assert!(single.contains(&ip("192.168.1.1")));
+        assert!(!single.contains(&ip("192.168.1.2")));
+
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(&ip("8.8.8.8")));
+
+        let v6: Cidr = "fd00::/8".parse().unwrap();
+        assert!(v6.contains(&ip("fd12::1")));
+        assert!(!v6.contains(&ip("fe80::1")));
+        assert!(!v6.contains(&ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn test_cidr_parse_errors() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("not-an-ip/8".parse::<Cidr>().is_err());
+        assert_eq!(parse_list("10.0.0.0/8, ,192.168.0.0/16").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_client_ip_honours_trusted_proxies_only() {
+        let filter = IpFilter {
+            trusted_proxies: parse_list("10.0.0.0/8").unwrap(),
+            ..Default::default()
+        };
+
+        // Untrusted peer - ignore the header entirely
+        assert_eq!(
+            filter.client_ip(ip("203.0.113.9"), Some("1.2.3.4")),
+            ip("203.0.113.9")
+        );
+
+        // Trusted peer - take the last hop that isn't a trusted proxy
+        assert_eq!(
+            filter.client_ip(ip("10.0.0.2"), Some("1.2.3.4, 5.6.7.8, 10.0.0.3")),
+            ip("5.6.7.8")
+        );
+    }
+
+    #[test]
+    fn test_permits() {
+        let filter = IpFilter {
+            deny: parse_list("10.66.0.0/16").unwrap(),
+            admin_allow: parse_list("10.0.0.0/8").unwrap(),
+            ..Default::default()
+        };
+
+        assert!(filter.permits(&ip("8.8.8.8"), "/images/1/2/3"));
+        assert!(!filter.permits(&ip("8.8.8.8"), "/admin/usage"));
+        assert!(filter.permits(&ip("10.1.2.3"), "/admin/usage"));
+        assert!(!filter.permits(&ip("10.66.1.1"), "/admin/usage"));
+    }
+
+    #[test]
+    fn test_admin_is_internal_by_default() {
+        let filter = IpFilter::from_vars(|_| None).unwrap();
+        assert!(filter.permits(&ip("8.8.8.8"), "/images/1/2/3"));
+        assert!(!filter.permits(&ip("8.8.8.8"), "/admin/usage"));
+        assert!(!filter.permits(&ip("2001:db8::1"), "/admin/usage"));
+        for internal in [
+            "127.0.0.1",
+            "::1",
+            "10.1.2.3",
+            "172.20.0.1",
+            "192.168.1.1",
+            "fd12::1",
+        ] {
+            assert!(
+                filter.permits(&ip(internal), "/admin/usage"),
+                "{}",
+                internal
+            );
+        }
+
+        // An empty list closes them to everyone
+        let closed =
+            IpFilter::from_vars(|name| (name == "ADMIN_IP_ALLOWLIST").then(String::new)).unwrap();
+        assert!(!closed.permits(&ip("10.1.2.3"), "/admin/usage"));
+        assert!(closed.permits(&ip("10.1.2.3"), "/images/1/2/3"));
+    }
+
+    #[actix_web::test]
+    async fn test_check_under_prefix() {
+        use actix_web::{middleware::from_fn, test, App, HttpResponse};
+
+        // The admin allowlist applies to /admin under wherever the API is mounted
+        for prefix in ["", "/maps"] {
+            let filter = IpFilter {
+                deny: parse_list("10.66.0.0/16").unwrap(),
+                admin_allow: parse_list("10.0.0.0/8").unwrap(),
+                ..Default::default()
+            };
+            let app = test::init_service(
+                App::new().service(
+                    web::scope(prefix)
+                        .wrap(from_fn(check))
+                        .app_data(web::Data::new(filter))
+                        .route("/admin/usage", web::get().to(HttpResponse::Ok))
+                        .route("/images", web::get().to(HttpResponse::Ok)),
+                ),
+            )
+            .await;
+            let permitted = |path: &str, peer: &str| {
+                let req = test::TestRequest::get()
+                    .uri(&format!("{}{}", prefix, path))
+                    .peer_addr(format!("{}:1234", peer).parse().unwrap())
+                    .to_request();
+                test::try_call_service(&app, req)
+            };
+
+            assert!(permitted("/images", "8.8.8.8").await.is_ok());
+            assert!(permitted("/admin/usage", "8.8.8.8").await.is_err());
+            assert!(permitted("/admin/usage", "10.1.2.3").await.is_ok());
+            assert!(permitted("/images", "10.66.1.1").await.is_err());
+        }
+    }
+}
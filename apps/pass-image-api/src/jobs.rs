@@ -0,0 +1,688 @@
+// ! # jobs
+// ! An asynchronous alternative to GET /images. A client POSTs the render it wants and
+// ! gets a job ID back straight away; the image is rendered in the background and kept
+// ! in memory for a while. Once it's done, the job status includes a signed, expiring
+// ! URL for the result that can be handed to a browser as-is.
+// !
+// ! Instead of polling, a client can include a callback_url, which gets a signed POST
+// ! when the render finishes or fails, with the result URL and timings.
+// !
+// ! Jobs render on the BatchPool (see the pools module), not the server's workers, and a
+// ! job submitted while the pool's queue is full is turned away with a 503.
+// !
+// ! Jobs are kept in SQLite, in memory unless JOB_DB_PATH points at a file. With a file,
+// ! jobs, their status and their results survive a restart, such as a rollout replacing
+// ! the pod, and jobs that were still pending when the last process stopped are rendered
+// ! again at startup, with their callbacks, by recover. They're fed to the BatchPool as
+// ! its queue has room, so however many there are, none fail for a full queue. The file
+// ! belongs to one replica at a time: another process using it would take its pending
+// ! jobs for orphans.
+
+use crate::limits::BodyLimits;
+use crate::memory;
+use crate::output;
+use crate::pools::{BatchPool, PoolBusy};
+use crate::request::{bad_request, parse_body, read_gpx_overlay, ImageRequest};
+use crate::signing::{UrlSigner, WebhookSigner};
+use crate::usage::{self, UsageTracker};
+use crate::webhook;
+use crate::ImageApiConfig;
+use actix_web::error::{ErrorBadRequest, ErrorInternalServerError};
+use actix_web::{get, post, web, Error, HttpRequest, HttpResponse, Responder};
+use anyhow::{anyhow, Context as _, Result};
+use bytes::Bytes;
+use log::{info, warn};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tile_render::avif;
+use tile_render::fetcher::TileSources;
+use tile_render::tiles::{fetch_image_from_point, tile_count_for_point};
+use tile_render::{transport, url_guard};
+use uuid::Uuid;
+
+// How long finished jobs hang around if JOB_RETENTION_SECS isn't set
+const DEFAULT_RETENTION_SECS: u64 = 3600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Pending,
+    Done,
+    Failed,
+}
+
+impl JobStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Pending => "pending",
+            JobStatus::Done => "done",
+            JobStatus::Failed => "failed",
+        }
+    }
+
+    fn from_name(name: &str) -> JobStatus {
+        match name {
+            "done" => JobStatus::Done,
+            "failed" => JobStatus::Failed,
+            _ => JobStatus::Pending,
+        }
+    }
+}
+
+// A job as it was submitted, with what's needed to render it again after a restart
+#[derive(Debug, Clone, PartialEq)]
+struct Submission {
+    id: String,
+    // The POST /jobs body
+    body: Bytes,
+    api_key: String,
+    // Where the service was reached, for the callback's result URL
+    base_url: String,
+    result_path: String,
+    submitted: SystemTime,
+}
+
+// The POST /jobs body: a POST /images body, optionally with a URL to call back
+#[derive(Deserialize)]
+struct JobRequest {
+    #[serde(default)]
+    callback_url: Option<String>,
+    #[serde(flatten)]
+    request: ImageRequest,
+}
+
+// Reads a POST /jobs body within the limits, GPX overlay and all, as it's submitted and
+// again when it's resumed
+fn parse_job(body: &[u8], limits: &BodyLimits) -> Result<JobRequest, Error> {
+    let mut job: JobRequest = parse_body(body, limits)?;
+    read_gpx_overlay(&mut job.request, limits)?;
+    Ok(job)
+}
+
+// What a callback URL is sent when its job finishes
+#[derive(Debug, Serialize)]
+struct JobCallback {
+    id: String,
+    status: JobStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result_url: Option<String>,
+    submitted_at: u64,
+    finished_at: u64,
+    duration_ms: u64,
+}
+
+#[derive(Serialize)]
+struct JobResponse {
+    id: String,
+    status: JobStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result_url: Option<String>,
+}
+
+// Keeps track of submitted jobs and their results
+pub struct JobStore {
+    conn: Mutex<Connection>,
+    retention: Duration,
+    persistent: bool,
+}
+
+impl JobStore {
+    pub fn new(conn: Connection, retention: Duration, persistent: bool) -> Result<JobStore> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS jobs (
+                id           TEXT PRIMARY KEY,
+                status       TEXT NOT NULL,
+                error        TEXT,
+                result       BLOB,
+                body         BLOB NOT NULL,
+                api_key      TEXT NOT NULL,
+                base_url     TEXT NOT NULL,
+                result_path  TEXT NOT NULL,
+                submitted_at INTEGER NOT NULL
+            )",
+            [],
+        )
+        .context("creating jobs table")?;
+
+        Ok(JobStore {
+            conn: Mutex::new(conn),
+            retention,
+            persistent,
+        })
+    }
+
+    // Opens the database at JOB_DB_PATH, or one in memory without it
+    pub fn from_env() -> Result<JobStore> {
+        let retention = env::var("JOB_RETENTION_SECS")
+            .ok()
+            .and_then(|t| t.parse().ok())
+            .unwrap_or(DEFAULT_RETENTION_SECS);
+        let retention = Duration::from_secs(retention);
+
+        match env::var("JOB_DB_PATH") {
+            Ok(path) => {
+                let conn = Connection::open(&path)
+                    .with_context(|| format!("opening job db at {}", path))?;
+                JobStore::new(conn, retention, true)
+            }
+            Err(_) => JobStore::new(Connection::open_in_memory()?, retention, false),
+        }
+    }
+
+    // Registers a new pending job. Expired jobs are dropped on the way.
+    fn create(&self, submission: &Submission) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let submitted_at = unix_secs(submission.submitted);
+        conn.execute(
+            "DELETE FROM jobs WHERE submitted_at <= ?1",
+            params![submitted_at.saturating_sub(self.retention.as_secs()) as i64],
+        )?;
+        conn.execute(
+            "INSERT INTO jobs (id, status, body, api_key, base_url, result_path, submitted_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                submission.id,
+                JobStatus::Pending.as_str(),
+                &submission.body[..],
+                submission.api_key,
+                submission.base_url,
+                submission.result_path,
+                submitted_at as i64,
+            ],
+        )?;
+        Ok(())
+    }
+
+    // Forgets a job that never got to run
+    fn remove(&self, id: &str) {
+        let conn = self.conn.lock().unwrap();
+        if let Err(e) = conn.execute("DELETE FROM jobs WHERE id = ?1", params![id]) {
+            warn!(job_id = id; "Couldn't remove job: {}", e);
+        }
+    }
+
+    fn complete(&self, id: &str, result: &anyhow::Result<Bytes>) {
+        let (status, error, image) = match result {
+            Ok(image) => (JobStatus::Done, None, Some(&image[..])),
+            Err(e) => (JobStatus::Failed, Some(e.to_string()), None),
+        };
+        let conn = self.conn.lock().unwrap();
+        let updated = conn.execute(
+            "UPDATE jobs SET status = ?2, error = ?3, result = ?4 WHERE id = ?1",
+            params![id, status.as_str(), error, image],
+        );
+        if let Err(e) = updated {
+            warn!(job_id = id; "Couldn't store job result: {}", e);
+        }
+    }
+
+    fn status(&self, id: &str) -> Result<Option<(JobStatus, Option<String>)>> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn
+            .query_row(
+                "SELECT status, error FROM jobs WHERE id = ?1",
+                params![id],
+                |row| Ok((JobStatus::from_name(&row.get::<_, String>(0)?), row.get(1)?)),
+            )
+            .optional()?)
+    }
+
+    // Drops finished jobs and their results early, to free memory, returning how many
+    // bytes of results were dropped. Their status and result URLs 404 as if they'd expired.
+    // Results in a JOB_DB_PATH file aren't in memory, so they're kept.
+    pub fn shed(&self) -> usize {
+        if self.persistent {
+            return 0;
+        }
+        let conn = self.conn.lock().unwrap();
+        let shed = conn
+            .query_row(
+                "SELECT COALESCE(SUM(LENGTH(result)), 0) FROM jobs WHERE status != ?1",
+                params![JobStatus::Pending.as_str()],
+                |row| row.get::<_, i64>(0),
+            )
+            .and_then(|freed| {
+                conn.execute(
+                    "DELETE FROM jobs WHERE status != ?1",
+                    params![JobStatus::Pending.as_str()],
+                )?;
+                Ok(freed as usize)
+            });
+        shed.unwrap_or_else(|e| {
+            warn!("Couldn't shed finished jobs: {}", e);
+            0
+        })
+    }
+
+    fn result(&self, id: &str) -> Result<Option<Bytes>> {
+        let conn = self.conn.lock().unwrap();
+        let result: Option<Option<Vec<u8>>> = conn
+            .query_row(
+                "SELECT result FROM jobs WHERE id = ?1",
+                params![id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(result.flatten().map(Bytes::from))
+    }
+
+    // The jobs still pending, oldest first. At startup, before any job has been
+    // submitted, these are the ones the last process didn't finish.
+    fn pending(&self) -> Result<Vec<Submission>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, body, api_key, base_url, result_path, submitted_at FROM jobs
+             WHERE status = ?1 ORDER BY submitted_at",
+        )?;
+        let rows = stmt.query_map(params![JobStatus::Pending.as_str()], |row| {
+            Ok(Submission {
+                id: row.get(0)?,
+                body: Bytes::from(row.get::<_, Vec<u8>>(1)?),
+                api_key: row.get(2)?,
+                base_url: row.get(3)?,
+                result_path: row.get(4)?,
+                submitted: UNIX_EPOCH + Duration::from_secs(row.get::<_, i64>(5)? as u64),
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+}
+
+// Where a job's result is served, including the prefix the API is mounted under
+fn result_path(req: &HttpRequest, id: &str) -> String {
+    req.url_for("job_result", [id])
+        .map(|url| url.path().to_string())
+        .unwrap_or_else(|_| format!("/jobs/{}/result", id))
+}
+
+fn job_response(
+    req: &HttpRequest,
+    id: &str,
+    status: JobStatus,
+    error: Option<String>,
+    signer: &UrlSigner,
+) -> JobResponse {
+    JobResponse {
+        id: id.to_string(),
+        status,
+        error,
+        result_url: (status == JobStatus::Done).then(|| signer.sign(&result_path(req, id))),
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Everything a job needs to render and report back
+#[derive(Clone)]
+struct JobContext {
+    store: web::Data<JobStore>,
+    signer: web::Data<UrlSigner>,
+    usage: web::Data<UsageTracker>,
+    sources: web::Data<TileSources>,
+    webhooks: Option<web::Data<WebhookSigner>>,
+}
+
+// Renders a job on the pool, stores how it went and calls its callback URL
+fn spawn_job(
+    pool: &BatchPool,
+    context: JobContext,
+    submission: Submission,
+    job: JobRequest,
+) -> Result<(), PoolBusy> {
+    pool.spawn(move || run_job(context, submission, job))
+}
+
+// Renders a job and reports how it went, to the store and its callback
+async fn run_job(context: JobContext, submission: Submission, job: JobRequest) {
+    let JobRequest {
+        callback_url,
+        request,
+    } = job;
+    let job_id = submission.id.as_str();
+    let started = Instant::now();
+    let center = request.center();
+    let rendered = async {
+        let options = request.render_options()?;
+        let image = fetch_image_from_point(
+            context.sources.get_ref(),
+            center,
+            request.radius,
+            request.size_px,
+            request.tileset(),
+            &options,
+        )
+        .await?;
+        let tiles = tile_count_for_point(
+            center,
+            request.radius,
+            request.size_px,
+            request.tileset(),
+            &options,
+        );
+        Ok::<_, anyhow::Error>((image, tiles))
+    }
+    .await;
+    let result = match rendered {
+        Ok((image, tiles)) => {
+            context
+                .usage
+                .record(&submission.api_key, tiles as u64)
+                .await;
+            Ok(image)
+        }
+        Err(e) => {
+            warn!(job_id = job_id; "Render job failed: {}", e);
+            Err(e)
+        }
+    };
+    let duration_ms = started.elapsed().as_millis() as u64;
+    let status = if result.is_ok() {
+        JobStatus::Done
+    } else {
+        JobStatus::Failed
+    };
+    let error = result.as_ref().err().map(|e| e.to_string());
+    context.store.complete(job_id, &result);
+
+    let Some(url) = callback_url else {
+        return;
+    };
+    let Some(webhooks) = context.webhooks else {
+        warn!(job_id = job_id; "Job callback skipped: WEBHOOK_SIGNING_KEY isn't set");
+        return;
+    };
+    let callback = JobCallback {
+        result_url: (status == JobStatus::Done).then(|| {
+            format!(
+                "{}{}",
+                submission.base_url,
+                context.signer.sign(&submission.result_path)
+            )
+        }),
+        id: submission.id.clone(),
+        status,
+        error,
+        submitted_at: unix_secs(submission.submitted),
+        finished_at: unix_secs(SystemTime::now()),
+        duration_ms,
+    };
+    let delivered = async {
+        let body = serde_json::to_vec(&callback)?;
+        let transport = transport::guarded()?;
+        webhook::deliver(
+            transport.as_ref(),
+            &webhooks,
+            &url,
+            body.into(),
+            webhook::BACKOFF,
+        )
+        .await
+    };
+    if let Err(e) = delivered.await {
+        warn!(job_id = job_id; "Job callback failed: {}", e);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+#[post("/jobs")]
+async fn submit_job(
+    req: HttpRequest,
+    body: web::Bytes,
+    limits: web::Data<BodyLimits>,
+    store: web::Data<JobStore>,
+    signer: web::Data<UrlSigner>,
+    usage: web::Data<UsageTracker>,
+    sources: web::Data<TileSources>,
+    pool: web::Data<BatchPool>,
+    webhooks: Option<web::Data<WebhookSigner>>,
+) -> Result<HttpResponse, Error> {
+    let api_key = usage::api_key(&req);
+    if let Err(e) = usage.check(&api_key).await {
+        return Ok(HttpResponse::TooManyRequests().body(e));
+    }
+
+    let job = parse_job(&body, &limits)?;
+    if let Some(rejected) = memory::reject_render(&req, job.request.size_px) {
+        return Ok(rejected);
+    }
+    job.request.render_options().map_err(bad_request)?;
+    // Callbacks are checked now so a bad URL fails the request, not the job. They're
+    // checked again before sending, in case the host has moved.
+    if let Some(url) = &job.callback_url {
+        if webhooks.is_none() {
+            return Err(ErrorBadRequest(
+                "callback_url needs WEBHOOK_SIGNING_KEY to be configured",
+            ));
+        }
+        url_guard::validate_url(url).await.map_err(bad_request)?;
+    }
+    let id = Uuid::new_v4().to_string();
+    let base_url = {
+        let connection = req.connection_info();
+        format!("{}://{}", connection.scheme(), connection.host())
+    };
+    let submission = Submission {
+        result_path: result_path(&req, &id),
+        id: id.clone(),
+        body,
+        api_key,
+        base_url,
+        submitted: SystemTime::now(),
+    };
+    if let Err(e) = store.create(&submission) {
+        warn!(job_id = id.as_str(); "Couldn't store render job: {:#}", e);
+        return Err(ErrorInternalServerError("The job couldn't be stored"));
+    }
+
+    info!(job_id = id.as_str(); "Accepted render job");
+
+    let context = JobContext {
+        store: store.clone(),
+        signer: signer.clone(),
+        usage,
+        sources,
+        webhooks,
+    };
+    if let Err(busy) = spawn_job(&pool, context, submission, job) {
+        store.remove(&id);
+        return Ok(busy.response());
+    }
+
+    Ok(HttpResponse::Accepted().json(job_response(&req, &id, JobStatus::Pending, None, &signer)))
+}
+
+// Renders the jobs the last process accepted but didn't finish again, callbacks and all.
+// Jobs whose body no longer parses fail instead. The others are fed to the pool in the
+// background as its queue has room, rather than failing when there are more of them than
+// fit. This should be called once at startup, before any job is submitted.
+pub fn recover(config: &ImageApiConfig) -> Result<()> {
+    let store = config.job_store.clone();
+    let pool = config.batch_pool.clone();
+    let context = JobContext {
+        store: store.clone(),
+        signer: config.url_signer.clone(),
+        usage: config.usage_tracker.clone(),
+        sources: config.tile_sources.clone(),
+        webhooks: config.webhook_signer.clone(),
+    };
+    let mut resumed = Vec::new();
+    for submission in store.pending().context("reading pending jobs")? {
+        match parse_job(&submission.body, &config.body_limits) {
+            Ok(job) => resumed.push((submission, job)),
+            Err(e) => store.complete(&submission.id, &Err(anyhow!("Couldn't be resumed: {}", e))),
+        }
+    }
+    if resumed.is_empty() {
+        return Ok(());
+    }
+    actix_web::rt::spawn(async move {
+        for (submission, job) in resumed {
+            let id = submission.id.clone();
+            info!(job_id = id.as_str(); "Resuming render job after a restart");
+            let context = context.clone();
+            let queued = pool
+                .spawn_waiting(move || run_job(context, submission, job))
+                .await;
+            if let Err(e) = queued {
+                store.complete(&id, &Err(anyhow!("Couldn't be resumed: {}", e)));
+            }
+        }
+    });
+    Ok(())
+}
+
+#[get("/jobs/{id}")]
+async fn get_job(
+    req: HttpRequest,
+    path: web::Path<String>,
+    store: web::Data<JobStore>,
+    signer: web::Data<UrlSigner>,
+) -> impl Responder {
+    let id = path.into_inner();
+    match store.status(&id) {
+        Ok(Some((status, error))) => {
+            HttpResponse::Ok().json(job_response(&req, &id, status, error, &signer))
+        }
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            warn!(job_id = id.as_str(); "Couldn't read job: {:#}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SignedQuery {
+    expires: u64,
+    signature: String,
+}
+
+// Serves a finished job's image. This is deliberately only reachable with a valid
+// signature, so the URL can be given to a browser.
+#[get("/jobs/{id}/result", name = "job_result")]
+async fn get_job_result(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<SignedQuery>,
+    store: web::Data<JobStore>,
+    signer: web::Data<UrlSigner>,
+) -> impl Responder {
+    let id = path.into_inner();
+    if let Err(e) = signer.verify(&result_path(&req, &id), query.expires, &query.signature) {
+        return HttpResponse::Forbidden().body(e.to_string());
+    }
+
+    match store.result(&id) {
+        Ok(Some(image)) => HttpResponse::Ok()
+            .content_type(avif::image_type(&image).0)
+            .insert_header(output::etag(&image))
+            .body(image),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            warn!(job_id = id.as_str(); "Couldn't read job result: {:#}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn submission(id: &str) -> Submission {
+        Submission {
+            id: id.to_string(),
+            body: Bytes::from_static(br#"{"long": 8.1, "lat": 46.6, "size_px": 64}"#),
+            api_key: "key".to_string(),
+            base_url: "http://localhost:8080".to_string(),
+            result_path: format!("/jobs/{}/result", id),
+            // Fixed, so the submission read back compares equal to a new one
+            submitted: UNIX_EPOCH + Duration::from_secs(1_760_000_000),
+        }
+    }
+
+    #[test]
+    fn test_store_survives_restart() {
+        let path = env::temp_dir().join(format!("jobs-{}.db", Uuid::new_v4()));
+        let open = || {
+            JobStore::new(
+                Connection::open(&path).unwrap(),
+                Duration::from_secs(60),
+                true,
+            )
+            .unwrap()
+        };
+        let store = open();
+        store.create(&submission("done")).unwrap();
+        store.create(&submission("pending")).unwrap();
+        store.complete("done", &Ok(Bytes::from_static(b"png")));
+        assert_eq!(store.shed(), 0);
+        drop(store);
+
+        let store = open();
+        assert_eq!(store.status("done").unwrap(), Some((JobStatus::Done, None)));
+        assert_eq!(store.result("done").unwrap().unwrap(), &b"png"[..]);
+        assert_eq!(store.pending().unwrap(), vec![submission("pending")]);
+        store.complete("pending", &Err(anyhow!("no tiles")));
+        assert_eq!(
+            store.status("pending").unwrap(),
+            Some((JobStatus::Failed, Some("no tiles".to_string())))
+        );
+        assert!(store.pending().unwrap().is_empty());
+        assert_eq!(store.status("missing").unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+
+        // In memory, finished results are shed under pressure
+        let store = JobStore::new(
+            Connection::open_in_memory().unwrap(),
+            Duration::from_secs(60),
+            false,
+        )
+        .unwrap();
+        store.create(&submission("done")).unwrap();
+        store.create(&submission("pending")).unwrap();
+        store.complete("done", &Ok(Bytes::from_static(b"png")));
+        assert_eq!(store.shed(), 3);
+        assert_eq!(store.status("done").unwrap(), None);
+        assert!(store.status("pending").unwrap().is_some());
+    }
+
+    #[actix_web::test]
+    async fn test_recover_gpx_job() {
+        let config = crate::tests::config("");
+        let gpx = concat!(
+            "<gpx><trk><trkseg>",
+            r#"<trkpt lat="46.6" lon="8.1"/><trkpt lat="46.61" lon="8.11"/>"#,
+            "</trkseg></trk></gpx>",
+        );
+        let body = serde_json::json!({"long": 8.1, "lat": 46.6, "size_px": 64, "overlay": gpx});
+        let pending = Submission {
+            body: Bytes::from(serde_json::to_vec(&body).unwrap()),
+            ..submission("gpx")
+        };
+        config.job_store.create(&pending).unwrap();
+
+        recover(&config).unwrap();
+        for _ in 0..100 {
+            match config.job_store.status("gpx").unwrap() {
+                Some((JobStatus::Done, _)) => return,
+                Some((JobStatus::Pending, _)) => {
+                    actix_web::rt::time::sleep(Duration::from_millis(50)).await
+                }
+                status => panic!("The GPX job wasn't resumed: {:?}", status),
+            }
+        }
+        panic!("The GPX job should finish");
+    }
+}
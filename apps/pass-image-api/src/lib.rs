@@ -0,0 +1,399 @@
+// ! # pass-image-api
+// ! Map images of mountain passes over HTTP, rendered with tile-render. Every endpoint is
+// ! bundled into one actix Scope by image_api_scope. The service's own binary serves it,
+// ! and other Rust services in the stack can mount it under their own server instead of
+// ! running a separate deployment:
+// !
+// !     let config = ImageApiConfig {
+// !         prefix: "/maps".to_string(),
+// !         ..ImageApiConfig::from_env().await?
+// !     };
+// !     HttpServer::new(move || App::new().service(image_api_scope(config.clone())))
+
+use crate::export::{ExportCache, ExportLimits};
+use crate::history::RequestHistory;
+use crate::ip_filter::IpFilter;
+use crate::jobs::JobStore;
+use crate::limits::BodyLimits;
+use crate::memory::MemoryGuard;
+use crate::passes::PassApi;
+use crate::pools::BatchPool;
+use crate::prefetch::Prefetcher;
+use crate::refresh::CacheRefresher;
+use crate::render_cache::RenderCache;
+use crate::signing::{UrlSigner, WebhookSigner};
+use crate::sprites::IconSet;
+use crate::storage::ResultStore;
+use crate::usage::UsageTracker;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::{middleware::from_fn, web, Error, Scope};
+use anyhow::{Context, Result};
+use tile_render::fetcher::TileSources;
+use tile_render::{coverage, icc, presets, registry, watermark, wmts};
+
+pub mod archive;
+pub mod attribution;
+pub mod coords;
+pub mod diff;
+pub mod elevation;
+pub mod export;
+pub mod faults;
+pub mod grpc;
+pub mod hints;
+pub mod history;
+pub mod images;
+pub mod ip_filter;
+pub mod jobs;
+pub mod limits;
+pub mod locale;
+pub mod memory;
+pub mod output;
+pub mod overview;
+pub mod passes;
+pub mod pools;
+pub mod prefetch;
+pub mod queue;
+pub mod refresh;
+pub mod render_cache;
+pub mod request;
+pub mod seed;
+pub mod sign;
+pub mod signing;
+pub mod sprites;
+pub mod storage;
+pub mod tile_cache;
+pub mod upstreams;
+pub mod usage;
+pub mod webhook;
+
+// Everything the image API needs to serve requests. Cloning it shares the state, so one
+// config can be handed to every server worker.
+#[derive(Clone)]
+pub struct ImageApiConfig {
+    // Where the scope is mounted, e.g. "/maps". Empty to serve from the root
+    pub prefix: String,
+    pub tile_sources: web::Data<TileSources>,
+    pub usage_tracker: web::Data<UsageTracker>,
+    pub job_store: web::Data<JobStore>,
+    pub pass_api: web::Data<PassApi>,
+    pub url_signer: web::Data<UrlSigner>,
+    pub webhook_signer: Option<web::Data<WebhookSigner>>,
+    pub result_store: Option<web::Data<ResultStore>>,
+    pub request_history: Option<web::Data<RequestHistory>>,
+    pub render_cache: Option<web::Data<RenderCache>>,
+    // Refreshes popular cached tiles on a schedule. The service's binary runs it
+    // alongside the server; it isn't part of the scope.
+    pub cache_refresher: Option<web::Data<CacheRefresher>>,
+    // Sheds caches and turns away large renders near the soft memory limit. The
+    // service's binary runs its checks alongside the server.
+    pub memory_guard: Option<web::Data<MemoryGuard>>,
+    // Warms the tile cache around the images served
+    pub prefetcher: Option<web::Data<Prefetcher>>,
+    pub marker_icons: web::Data<IconSet>,
+    pub ip_rules: web::Data<IpFilter>,
+    pub body_limits: BodyLimits,
+    pub export_limits: web::Data<ExportLimits>,
+    // Finished MBTiles exports, kept for a while so downloads can resume from them
+    pub export_cache: web::Data<ExportCache>,
+    // Runs render jobs and exports on threads of their own, away from the server's
+    // workers
+    pub batch_pool: web::Data<BatchPool>,
+}
+
+impl ImageApiConfig {
+    // Configures everything from the environment, as the service itself is. This also
+    // loads the watermark, tileset registry, WMTS capabilities, coverage, presets and color
+    // profiles, which every render in the process shares.
+    pub async fn from_env() -> Result<ImageApiConfig> {
+        watermark::init_from_env()
+            .await
+            .context("Failed to load watermark")?;
+        registry::init_from_env().context("Invalid tileset registry")?;
+        wmts::init_from_env()
+            .await
+            .context("Failed to load WMTS capabilities")?;
+        coverage::init_from_env().context("Invalid tileset coverage")?;
+        presets::init_from_env().context("Invalid presets")?;
+        icc::init_from_env().context("Invalid ICC profile")?;
+        let cache_refresher =
+            CacheRefresher::from_env().context("Invalid tile cache refresh configuration")?;
+        let popular = cache_refresher.as_ref().map(CacheRefresher::popular);
+        let job_store =
+            web::Data::new(JobStore::from_env().context("Failed to open job database")?);
+        let render_cache = RenderCache::from_env()
+            .context("Invalid render cache configuration")?
+            .map(web::Data::new);
+        let memory_guard =
+            MemoryGuard::from_env(job_store.clone(), popular.clone(), render_cache.clone())
+                .context("Invalid soft memory limit configuration")?;
+        Ok(ImageApiConfig {
+            prefix: String::new(),
+            tile_sources: web::Data::new(
+                tile_cache::sources_from_env(popular)
+                    .context("Invalid tile source configuration")?,
+            ),
+            usage_tracker: web::Data::new(
+                UsageTracker::from_env().context("Failed to open usage database")?,
+            ),
+            job_store,
+            pass_api: web::Data::new(PassApi::from_env()),
+            url_signer: web::Data::new(UrlSigner::from_env()),
+            webhook_signer: WebhookSigner::from_env().map(web::Data::new),
+            result_store: ResultStore::from_env()
+                .context("Invalid output store configuration")?
+                .map(web::Data::new),
+            request_history: RequestHistory::from_env()
+                .context("Failed to open request history database")?
+                .map(web::Data::new),
+            render_cache,
+            cache_refresher: cache_refresher.map(web::Data::new),
+            memory_guard: memory_guard.map(web::Data::new),
+            prefetcher: Prefetcher::from_env()
+                .context("Invalid prefetch configuration")?
+                .map(web::Data::new),
+            marker_icons: web::Data::new(
+                IconSet::from_env().context("Invalid marker icon configuration")?,
+            ),
+            ip_rules: web::Data::new(
+                IpFilter::from_env().context("Invalid IP filter configuration")?,
+            ),
+            body_limits: BodyLimits::from_env(),
+            export_limits: web::Data::new(ExportLimits::from_env()),
+            export_cache: web::Data::new(ExportCache::default()),
+            batch_pool: web::Data::new(
+                BatchPool::from_env().context("Invalid batch pool configuration")?,
+            ),
+        })
+    }
+}
+
+// The image API's endpoints, with their state and the IP filter, mounted at the
+// config's prefix
+pub fn image_api_scope(
+    config: ImageApiConfig,
+) -> Scope<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = Error,
+        InitError = (),
+    >,
+> {
+    web::scope(&config.prefix)
+        .wrap(from_fn(faults::scope_request))
+        .wrap(from_fn(locale::scope_request))
+        .wrap(from_fn(archive::scope_request))
+        .wrap(from_fn(output::report_degradations))
+        .wrap(from_fn(output::byte_ranges))
+        .wrap(from_fn(output::not_modified))
+        .wrap(from_fn(ip_filter::check))
+        .app_data(config.ip_rules)
+        .app_data(config.job_store)
+        .app_data(config.pass_api)
+        .app_data(config.export_limits)
+        .app_data(config.export_cache)
+        .app_data(config.batch_pool)
+        .app_data(config.url_signer)
+        .app_data(config.usage_tracker)
+        .app_data(config.marker_icons)
+        .app_data(config.tile_sources)
+        .configure(|cfg| {
+            if let Some(store) = config.result_store {
+                cfg.app_data(store);
+            }
+            if let Some(signer) = config.webhook_signer {
+                cfg.app_data(signer);
+            }
+            if let Some(history) = config.request_history {
+                cfg.app_data(history);
+            }
+            if let Some(render_cache) = config.render_cache {
+                cfg.app_data(render_cache);
+            }
+            if let Some(guard) = config.memory_guard {
+                cfg.app_data(guard);
+            }
+            if let Some(prefetcher) = config.prefetcher {
+                cfg.app_data(prefetcher);
+            }
+        })
+        .app_data(web::Data::new(config.body_limits))
+        .app_data(web::PayloadConfig::new(config.body_limits.max_body_bytes))
+        .service(images::get_image)
+        .service(images::get_preset_image)
+        .service(images::post_image)
+        .service(images::get_tile)
+        .service(coords::get_viewport_tiles)
+        .service(coords::stream_tiles)
+        .service(elevation::get_elevation)
+        .service(elevation::post_elevations)
+        .service(attribution::get_attribution)
+        .service(overview::get_overview)
+        .service(diff::post_diff)
+        .service(export::export_mbtiles)
+        .service(passes::get_pass_image)
+        .service(passes::get_tour_image)
+        .service(sprites::get_sprite)
+        .service(sign::sign_image)
+        .service(jobs::submit_job)
+        .service(jobs::get_job)
+        .service(jobs::get_job_result)
+        .service(usage::get_usage)
+        .service(history::get_history)
+        .service(history::replay)
+        .service(upstreams::get_upstreams)
+        .service(faults::get_faults)
+        .service(faults::put_faults)
+        .service(faults::delete_faults)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+    use bytes::Bytes;
+    use image::{ImageFormat, Rgba, RgbaImage};
+    use rusqlite::Connection;
+    use std::io::Cursor;
+    use std::time::Duration;
+    use tile_render::fetcher::MemoryFetcher;
+    use tile_render::tiles::TileSet;
+
+    // A config serving a green tile for every OSM tile, also used by other modules' tests
+    pub fn config(prefix: &str) -> ImageApiConfig {
+        let mut tile = Vec::new();
+        RgbaImage::from_pixel(256, 256, Rgba([0, 128, 0, 255]))
+            .write_to(&mut Cursor::new(&mut tile), ImageFormat::Png)
+            .unwrap();
+        let fetcher = MemoryFetcher::default().with_fallback(Bytes::from(tile));
+        let usage = UsageTracker::new(Connection::open_in_memory().unwrap(), None, None);
+        ImageApiConfig {
+            prefix: prefix.to_string(),
+            tile_sources: web::Data::new(
+                TileSources::default().with_source(TileSet::Osm, Box::new(fetcher)),
+            ),
+            usage_tracker: web::Data::new(usage.unwrap()),
+            job_store: web::Data::new(JobStore::from_env().unwrap()),
+            pass_api: web::Data::new(PassApi::new("http://pass-api.invalid")),
+            url_signer: web::Data::new(UrlSigner::new(b"key".to_vec(), Duration::from_secs(60))),
+            webhook_signer: None,
+            result_store: None,
+            request_history: None,
+            render_cache: None,
+            cache_refresher: None,
+            memory_guard: None,
+            prefetcher: None,
+            marker_icons: web::Data::new(IconSet::parse("marker:2850dc").unwrap()),
+            ip_rules: web::Data::new(IpFilter::default()),
+            body_limits: BodyLimits::from_env(),
+            export_limits: web::Data::new(ExportLimits::from_env()),
+            export_cache: web::Data::new(ExportCache::default()),
+            batch_pool: web::Data::new(BatchPool::new(1, 8)),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_scope_under_prefix() {
+        let app = test::init_service(App::new().service(image_api_scope(config("/maps")))).await;
+
+        let req = test::TestRequest::get()
+            .uri("/maps/sprites/sprite.json")
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let req = test::TestRequest::get()
+            .uri("/sprites/sprite.json")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 404);
+
+        // Job result URLs point under the prefix, and their signatures check out there
+        let req = test::TestRequest::post()
+            .uri("/maps/jobs")
+            .set_payload(r#"{"long": 8.1, "lat": 46.6, "size_px": 64}"#)
+            .to_request();
+        let job: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let status_uri = format!("/maps/jobs/{}", job["id"].as_str().unwrap());
+        let mut result_url = None;
+        for _ in 0..100 {
+            let req = test::TestRequest::get().uri(&status_uri).to_request();
+            let job: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+            if let Some(url) = job["result_url"].as_str() {
+                result_url = Some(url.to_string());
+                break;
+            }
+            assert_eq!(job["status"], "pending");
+            actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+        }
+        let result_url = result_url.expect("the job should finish");
+        assert!(result_url.starts_with(&format!("{}/result?", status_uri)));
+        let req = test::TestRequest::get().uri(&result_url).to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+
+    #[actix_web::test]
+    async fn test_tile_proxy() {
+        let app = test::init_service(App::new().service(image_api_scope(config("/maps")))).await;
+        let status = |uri: &'static str| {
+            let app = &app;
+            async move {
+                let req = test::TestRequest::get().uri(uri).to_request();
+                test::call_service(app, req).await.status()
+            }
+        };
+        assert_eq!(status("/maps/tiles/osm/1/1/0.png").await, 200);
+        assert_eq!(status("/maps/tiles/nope/1/1/0.png").await, 404);
+        // Tiles off the edge of the world, or past the deepest zoom, aren't fetched
+        assert_eq!(status("/maps/tiles/osm/1/2/0.png").await, 400);
+        assert_eq!(status("/maps/tiles/osm/1/0/2.png").await, 400);
+        assert_eq!(status("/maps/tiles/osm/40/0/0.png").await, 400);
+    }
+
+    #[actix_web::test]
+    async fn test_export_resumes() {
+        use actix_web::http::header::{CONTENT_RANGE, ETAG, IF_RANGE, RANGE};
+
+        let config = config("/maps");
+        let usage = config.usage_tracker.clone();
+        let app = test::init_service(App::new().service(image_api_scope(config))).await;
+        let export = || {
+            test::TestRequest::post()
+                .uri("/maps/export/mbtiles")
+                .set_payload(r#"{"bbox": [8.3, 46.5, 8.5, 46.6], "min_zoom": 8, "max_zoom": 9}"#)
+        };
+        let response = test::call_service(&app, export().to_request()).await;
+        assert_eq!(response.status(), 200);
+        let etag = response.headers().get(ETAG).unwrap().clone();
+        let whole = test::read_body(response).await;
+
+        // Exporting the same tiles again gives the same file, which a download resumes
+        let resumed = export()
+            .insert_header((RANGE, "bytes=100-"))
+            .insert_header((IF_RANGE, etag.clone()))
+            .to_request();
+        let response = test::call_service(&app, resumed).await;
+        assert_eq!(response.status(), 206);
+        assert_eq!(response.headers().get(ETAG), Some(&etag));
+        assert_eq!(
+            response
+                .headers()
+                .get(CONTENT_RANGE)
+                .unwrap()
+                .to_str()
+                .unwrap(),
+            format!("bytes 100-{}/{}", whole.len() - 1, whole.len())
+        );
+        assert_eq!(test::read_body(response).await, whole.slice(100..));
+
+        let past_end = export()
+            .insert_header((RANGE, format!("bytes={}-", whole.len())))
+            .to_request();
+        assert_eq!(test::call_service(&app, past_end).await.status(), 416);
+
+        // The file was only built, and its tiles only counted, once
+        let pyramid = crate::export::Pyramid::new([8.3, 46.5, 8.5, 46.6], 8, 9).unwrap();
+        let listed = usage.list(&crate::usage::current_month()).await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].tiles, pyramid.tile_count());
+    }
+}
@@ -0,0 +1,133 @@
+// ! # limits
+// ! Limits on what clients can POST at us. Overlay payloads are the obvious way to make
+// ! the service chew through memory, so bodies are capped in size, and geometry is capped
+// ! in vertex count with a cheap scan of the raw bytes before we ever build a JSON tree.
+// ! GPX overlays' points count as vertices too.
+
+use std::env;
+
+// 1 MiB is plenty for a few detailed GPS tracks
+const DEFAULT_MAX_BODY_BYTES: usize = 1024 * 1024;
+const DEFAULT_MAX_VERTICES: usize = 20_000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct BodyLimits {
+    pub max_body_bytes: usize,
+    pub max_vertices: usize,
+}
+
+impl BodyLimits {
+    // Reads MAX_BODY_BYTES and MAX_OVERLAY_VERTICES, falling back to the defaults
+    pub fn from_env() -> BodyLimits {
+        let limit = |name: &str, default: usize| {
+            env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        };
+
+        BodyLimits {
+            max_body_bytes: limit("MAX_BODY_BYTES", DEFAULT_MAX_BODY_BYTES),
+            max_vertices: limit("MAX_OVERLAY_VERTICES", DEFAULT_MAX_VERTICES),
+        }
+    }
+
+    // Checks a raw body against the limits, returning a description of what's wrong
+    pub fn check(&self, body: &[u8]) -> Result<(), String> {
+        if body.len() > self.max_body_bytes {
+            return Err(format!(
+                "Body of {} bytes exceeds the limit of {} bytes",
+                body.len(),
+                self.max_body_bytes
+            ));
+        }
+
+        let vertices = count_positions(body) + count_gpx_points(body);
+        if vertices > self.max_vertices {
+            return Err(format!(
+                "Geometry with {} vertices exceeds the limit of {}",
+                vertices, self.max_vertices
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// Counts GeoJSON positions in a raw JSON body without parsing it. A position is an array
+// that starts with a number, e.g. [8.1, 46.6], so we count every '[' whose next
+// non-whitespace character could start a number. Brackets inside strings can inflate the
+// count, which is fine for a limit.
+pub fn count_positions(body: &[u8]) -> usize {
+    let mut count = 0;
+    let mut bytes = body.iter().peekable();
+    while let Some(b) = bytes.next() {
+        if *b != b'[' {
+            continue;
+        }
+        while let Some(next) = bytes.peek() {
+            if next.is_ascii_whitespace() {
+                bytes.next();
+            } else {
+                if next.is_ascii_digit() || **next == b'-' {
+                    count += 1;
+                }
+                break;
+            }
+        }
+    }
+    count
+}
+
+// Counts GPX track and route points in a raw body, as the <trkpt and <rtept tags that
+// start them
+pub fn count_gpx_points(body: &[u8]) -> usize {
+    body.windows(6)
+        .filter(|window| *window == b"<trkpt" || *window == b"<rtept")
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_positions() {
+        assert_eq!(count_positions(b"{}"), 0);
+        assert_eq!(
+            count_positions(br#"{"type":"Point","coordinates":[8.1, 46.6]}"#),
+            1
+        );
+        assert_eq!(
+            count_positions(br#"{"coordinates":[[8.1,46.6],[ -8.2 ,46.7],[8.3,46.8]]}"#),
+            3
+        );
+        assert_eq!(count_positions(br#"{"coordinates":[[[1,2],[3,4]]]}"#), 2);
+    }
+
+    #[test]
+    fn test_count_gpx_points() {
+        assert_eq!(
+            count_gpx_points(br#"<trkseg><trkpt lat="1" lon="2"/><trkpt lat="3" lon="4"/>"#),
+            2
+        );
+        assert_eq!(
+            count_gpx_points(b"<rte><rtept lat='1' lon='2'></rtept></rte>"),
+            1
+        );
+        assert_eq!(count_gpx_points(b"<trkseg></trkseg>"), 0);
+    }
+
+    #[test]
+    fn test_check() {
+        let limits = BodyLimits {
+            max_body_bytes: 64,
+            max_vertices: 2,
+        };
+        assert!(limits.check(br#"{"coordinates":[[1,2],[3,4]]}"#).is_ok());
+        assert!(limits
+            .check(br#"{"coordinates":[[1,2],[3,4],[5,6]]}"#)
+            .is_err());
+        assert!(limits.check(&[b' '; 65]).is_err());
+    }
+}
@@ -0,0 +1,93 @@
+// ! # locale
+// ! Map labels in the requester's language, on top of tile_render::locale. A request's
+// ! ?lang= (e.g. lang=de, or lang=fr,de to fall back) or else its Accept-Language header
+// ! picks the languages its renders prefer, for tilesets that have localized variants.
+// ! Responses to requests that went by the header vary on it, so shared caches keep the
+// ! languages apart. Jobs a request submits render in the default language.
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderValue, ACCEPT_LANGUAGE, VARY};
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use std::collections::HashMap;
+use tile_render::locale;
+
+// Middleware running a request with the languages it asked for
+pub async fn scope_request(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let lang = web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.get("lang").cloned());
+    let header = req
+        .headers()
+        .get(ACCEPT_LANGUAGE)
+        .and_then(|val| val.to_str().ok())
+        .map(str::to_string);
+
+    let (languages, from_header) = match (lang, header) {
+        (Some(lang), _) => (locale::parse_accept_language(&lang), false),
+        (None, Some(header)) => (locale::parse_accept_language(&header), true),
+        (None, None) => return next.call(req).await,
+    };
+    let mut res = locale::scope(languages, next.call(req)).await?;
+    if from_header {
+        res.headers_mut()
+            .append(VARY, HeaderValue::from_static("Accept-Language"));
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, App, HttpResponse};
+    use tile_render::tiles::TileSet;
+
+    // Reports the language OSM tiles would be fetched in
+    #[actix_web::get("/lang")]
+    async fn osm_language() -> HttpResponse {
+        let lang = locale::variant(TileSet::Osm).map(|(lang, _)| lang);
+        HttpResponse::Ok().body(lang.unwrap_or("default"))
+    }
+
+    async fn language(uri: &str, header: Option<&str>) -> (String, bool) {
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(scope_request))
+                .service(osm_language),
+        )
+        .await;
+        let mut req = test::TestRequest::get().uri(uri);
+        if let Some(header) = header {
+            req = req.insert_header((ACCEPT_LANGUAGE, header));
+        }
+        let response = test::call_service(&app, req.to_request()).await;
+        let varies = response.headers().contains_key(VARY);
+        let body = test::read_body(response).await;
+        (String::from_utf8(body.to_vec()).unwrap(), varies)
+    }
+
+    #[actix_web::test]
+    async fn test_language_from_request() {
+        assert_eq!(
+            language("/lang", None).await,
+            ("default".to_string(), false)
+        );
+        assert_eq!(
+            language("/lang", Some("it-CH, fr;q=0.8")).await,
+            ("fr".to_string(), true)
+        );
+        assert_eq!(
+            language("/lang?lang=de", Some("fr")).await,
+            ("de".to_string(), false)
+        );
+        assert_eq!(
+            language("/lang?lang=it", None).await,
+            ("default".to_string(), false)
+        );
+    }
+}
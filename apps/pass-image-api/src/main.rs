@@ -1,13 +1,9 @@
-use std::collections::HashMap;
+use std::env;
 
-use crate::coordinates::LatLong;
-use crate::tiles::fetch_image_from_point;
-use actix_web::{get, http::header::ContentType, web, App, HttpResponse, HttpServer, Responder};
+use actix_web::{http::header::ContentType, web, App, HttpResponse, HttpServer, Responder};
 use actix_web_opentelemetry::RequestTracing;
 use log::{info, warn};
-use tiles::TileSet;
-mod coordinates;
-mod tiles;
+use pass_image_api::{grpc, image_api_scope, jobs, queue, seed, ImageApiConfig};
 
 mod telemetry_conf;
 use telemetry_conf::init_otel;
@@ -22,40 +18,6 @@ async fn health() -> impl Responder {
         .body("{\"status\": \"ok\"}")
 }
 
-#[get("/images/{long}/{lat}/{size_px}")]
-async fn get_image(
-    path: web::Path<(f64, f64, u32)>,
-    query: web::Query<HashMap<String, String>>,
-) -> impl Responder {
-    let (long, lat, size_px) = path.into_inner();
-
-    // Extract optional parameters from the query map
-    let radius = query
-        .get("radius")
-        .and_then(|r| r.parse().ok())
-        .unwrap_or(1.0);
-    let tileset = query
-        .get("tileset")
-        .map(|t| match t.as_str() {
-            "swisstopo" => TileSet::Swisstopo,
-            _ => TileSet::Osm,
-        })
-        .unwrap_or(TileSet::Osm);
-
-    info!(
-        latitude = lat,
-        longitude = long;
-        "Fetching image"
-    );
-
-    match fetch_image_from_point(LatLong(lat, long), radius, size_px, tileset).await {
-        Ok(image) => HttpResponse::Ok()
-            .content_type(ContentType::png())
-            .body(image),
-        Err(_) => HttpResponse::InternalServerError().into(),
-    }
-}
-
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     // Roll otel errors up to here and log them in aggregate
@@ -71,12 +33,51 @@ async fn main() -> std::io::Result<()> {
         }
     };
 
-    HttpServer::new(|| {
+    let config = ImageApiConfig::from_env()
+        .await
+        .map_err(std::io::Error::other)?;
+
+    // `pass-image-api consume` works through a render queue instead of serving HTTP
+    if env::args().nth(1).as_deref() == Some("consume") {
+        return queue::consume_from_env(config.tile_sources, config.usage_tracker)
+            .await
+            .map_err(std::io::Error::other);
+    }
+    // `pass-image-api seed` warms the tile cache and exits
+    if env::args().nth(1).as_deref() == Some("seed") {
+        return seed::seed_from_env(config.tile_sources.get_ref())
+            .await
+            .map_err(std::io::Error::other);
+    }
+
+    // Jobs the last process didn't get to finish are rendered again
+    jobs::recover(&config).map_err(std::io::Error::other)?;
+
+    if let Some(addr) = grpc::addr_from_env().map_err(std::io::Error::other)? {
+        let worker = grpc::RenderWorker::start(config.tile_sources.clone());
+        let service = grpc::PassImageService::new(
+            worker,
+            config.usage_tracker.clone(),
+            config.ip_rules.clone(),
+            config.body_limits,
+        );
+        grpc::serve(addr, service);
+    }
+
+    if let Some(refresher) = config.cache_refresher.clone() {
+        let sources = config.tile_sources.clone();
+        actix_web::rt::spawn(async move { refresher.run(&sources).await });
+    }
+    if let Some(guard) = config.memory_guard.clone() {
+        actix_web::rt::spawn(async move { guard.run().await });
+    }
+
+    HttpServer::new(move || {
         App::new()
             .wrap(RequestTracing::new())
             .route("/", web::get().to(index))
             .route("/ping", web::get().to(health))
-            .service(get_image)
+            .service(image_api_scope(config.clone()))
     })
     .bind(("0.0.0.0", 8080))?
     .run()
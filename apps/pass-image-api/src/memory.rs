@@ -0,0 +1,265 @@
+// ! # memory
+// ! A soft memory limit, so the service backs off before the kernel's OOM killer takes
+// ! every in-flight render down with it. With MEMORY_SOFT_LIMIT_BYTES set, the process's
+// ! resident set size is checked every MEMORY_CHECK_SECS. Once it reaches 90% of the
+// ! limit the service is under pressure: the in-memory caches are shed (finished jobs and
+// ! their results, cached images and the refresh module's tile counts), and renders
+// ! larger than MEMORY_LARGE_RENDER_PX are turned away with a 503 until it drops back
+// ! below.
+// !
+// ! Entering and leaving pressure, shedding and turning renders away are logged and
+// ! counted in the memory_events metric, by event. The RSS is read from /proc, so the
+// ! limit only works on Linux.
+
+use crate::jobs::JobStore;
+use crate::refresh::PopularTiles;
+use crate::render_cache::RenderCache;
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::{web, HttpRequest, HttpResponse};
+use anyhow::{anyhow, Context as _, Result};
+use log::{info, warn};
+use opentelemetry::{global, KeyValue};
+use std::env;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+const DEFAULT_CHECK_SECS: u64 = 5;
+const DEFAULT_LARGE_RENDER_PX: u32 = 1024;
+
+// The share of the soft limit at which the service comes under pressure
+const PRESSURE_PERCENT: u64 = 90;
+
+// How long clients turned away are asked to wait
+const RETRY_AFTER_SECS: u32 = 30;
+
+fn record_event(event: &'static str) {
+    let meter = global::meter("memory_meter");
+    let events = meter.u64_counter("memory_events").init();
+    events.add(1, &[KeyValue::new("event", event)]);
+}
+
+// The VmRSS line of a /proc/<pid>/status file, in bytes
+fn parse_vm_rss(status: &str) -> Option<u64> {
+    let line = status.lines().find_map(|l| l.strip_prefix("VmRSS:"))?;
+    let kb = line.trim().strip_suffix("kB")?.trim().parse::<u64>().ok()?;
+    Some(kb * 1024)
+}
+
+// The process's resident set size, or None where /proc isn't available
+fn read_rss() -> Option<u64> {
+    parse_vm_rss(&fs::read_to_string("/proc/self/status").ok()?)
+}
+
+pub struct MemoryGuard {
+    soft_limit: u64,
+    large_render_px: u32,
+    check_interval: Duration,
+    under_pressure: AtomicBool,
+    jobs: web::Data<JobStore>,
+    popular: Option<Arc<PopularTiles>>,
+    render_cache: Option<web::Data<RenderCache>>,
+}
+
+impl MemoryGuard {
+    pub fn new(soft_limit: u64, jobs: web::Data<JobStore>) -> MemoryGuard {
+        MemoryGuard {
+            soft_limit,
+            large_render_px: DEFAULT_LARGE_RENDER_PX,
+            check_interval: Duration::from_secs(DEFAULT_CHECK_SECS),
+            under_pressure: AtomicBool::new(false),
+            jobs,
+            popular: None,
+            render_cache: None,
+        }
+    }
+
+    // Renders larger than this are turned away under pressure
+    pub fn with_large_render_px(mut self, large_render_px: u32) -> MemoryGuard {
+        self.large_render_px = large_render_px;
+        self
+    }
+
+    // Sheds the refresh module's tile counts under pressure too
+    pub fn with_popular_tiles(mut self, popular: Arc<PopularTiles>) -> MemoryGuard {
+        self.popular = Some(popular);
+        self
+    }
+
+    // Sheds the rendered images cache under pressure too
+    pub fn with_render_cache(mut self, render_cache: web::Data<RenderCache>) -> MemoryGuard {
+        self.render_cache = Some(render_cache);
+        self
+    }
+
+    // The guard for MEMORY_SOFT_LIMIT_BYTES, if it's set
+    pub fn from_env(
+        jobs: web::Data<JobStore>,
+        popular: Option<Arc<PopularTiles>>,
+        render_cache: Option<web::Data<RenderCache>>,
+    ) -> Result<Option<MemoryGuard>> {
+        let Ok(limit) = env::var("MEMORY_SOFT_LIMIT_BYTES") else {
+            return Ok(None);
+        };
+        let limit = limit
+            .parse::<u64>()
+            .ok()
+            .filter(|&l| l > 0)
+            .ok_or_else(|| anyhow!("Invalid MEMORY_SOFT_LIMIT_BYTES {}", limit))?;
+        let mut guard = MemoryGuard::new(limit, jobs);
+        if let Ok(px) = env::var("MEMORY_LARGE_RENDER_PX") {
+            guard.large_render_px = px.parse().context("Invalid MEMORY_LARGE_RENDER_PX")?;
+        }
+        if let Ok(secs) = env::var("MEMORY_CHECK_SECS") {
+            let secs: u64 = secs.parse().context("Invalid MEMORY_CHECK_SECS")?;
+            guard.check_interval = Duration::from_secs(secs.max(1));
+        }
+        if let Some(popular) = popular {
+            guard = guard.with_popular_tiles(popular);
+        }
+        if let Some(render_cache) = render_cache {
+            guard = guard.with_render_cache(render_cache);
+        }
+        Ok(Some(guard))
+    }
+
+    pub fn under_pressure(&self) -> bool {
+        self.under_pressure.load(Ordering::Relaxed)
+    }
+
+    // Updates the pressure for the given RSS, shedding caches while under it
+    pub fn check(&self, rss: u64) {
+        let pressure = rss >= self.soft_limit * PRESSURE_PERCENT / 100;
+        let was = self.under_pressure.swap(pressure, Ordering::Relaxed);
+        match (was, pressure) {
+            (false, true) => {
+                record_event("pressure");
+                warn!(
+                    "Memory at {} of the {} byte soft limit; shedding caches and turning away renders over {}px",
+                    rss, self.soft_limit, self.large_render_px
+                );
+            }
+            (true, false) => {
+                record_event("recovered");
+                info!(
+                    "Memory back down to {} of the {} byte soft limit",
+                    rss, self.soft_limit
+                );
+            }
+            _ => {}
+        }
+        if pressure {
+            self.shed();
+        }
+    }
+
+    fn shed(&self) {
+        let job_bytes = self.jobs.shed();
+        let image_bytes = self.render_cache.as_ref().map_or(0, |cache| cache.clear());
+        let tiles = self.popular.as_ref().map_or(0, |popular| popular.clear());
+        if job_bytes > 0 || image_bytes > 0 || tiles > 0 {
+            record_event("shed");
+            warn!(
+                "Shed {} bytes of job results, {} bytes of cached images and counts for {} tiles",
+                job_bytes, image_bytes, tiles
+            );
+        }
+    }
+
+    // Checks the RSS every interval, for as long as the service runs
+    pub async fn run(&self) {
+        if read_rss().is_none() {
+            warn!("Can't read the process's memory use; MEMORY_SOFT_LIMIT_BYTES has no effect");
+            return;
+        }
+        loop {
+            if let Some(rss) = read_rss() {
+                self.check(rss);
+            }
+            actix_web::rt::time::sleep(self.check_interval).await;
+        }
+    }
+}
+
+// A 503 for a render of the given size if memory is under pressure and the render is a
+// large one, or None to go ahead. Without a guard configured, every render goes ahead.
+pub fn reject_render(req: &HttpRequest, size_px: u32) -> Option<HttpResponse> {
+    let guard = req.app_data::<web::Data<MemoryGuard>>()?;
+    if !guard.under_pressure() || size_px <= guard.large_render_px {
+        return None;
+    }
+    record_event("rejected");
+    warn!("Turning away a {}px render under memory pressure", size_px);
+    Some(
+        HttpResponse::ServiceUnavailable()
+            .insert_header((RETRY_AFTER, RETRY_AFTER_SECS))
+            .body(format!(
+                "The service is low on memory; renders over {}px can't be taken right now",
+                guard.large_render_px
+            )),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render_cache;
+    use actix_web::test;
+    use bytes::Bytes;
+    use tile_render::request::ImageRequest;
+
+    #[test]
+    fn test_parse_vm_rss() {
+        let status = "Name:\tpass-image-api\nVmPeak:\t  900000 kB\nVmRSS:\t  524288 kB\n";
+        assert_eq!(parse_vm_rss(status), Some(512 * 1024 * 1024));
+        assert_eq!(parse_vm_rss("Name:\tpass-image-api\n"), None);
+    }
+
+    #[actix_web::test]
+    async fn test_pressure() {
+        let popular = Arc::new(PopularTiles::default());
+        popular.record("osm/3/1/2.png");
+        let render_cache = web::Data::new(RenderCache::new(1000));
+        let mut request: ImageRequest =
+            serde_json::from_str(r#"{"long": 8.1, "lat": 46.6, "size_px": 64}"#).unwrap();
+        request.canonicalize();
+        render_cache::render(Some(&render_cache), &request, async {
+            Ok(Bytes::from_static(b"png"))
+        })
+        .await
+        .unwrap();
+        let guard = web::Data::new(
+            MemoryGuard::new(1000, web::Data::new(JobStore::from_env().unwrap()))
+                .with_large_render_px(512)
+                .with_popular_tiles(popular.clone())
+                .with_render_cache(render_cache.clone()),
+        );
+        let req = test::TestRequest::default()
+            .app_data(guard.clone())
+            .to_http_request();
+
+        guard.check(800);
+        assert!(!guard.under_pressure());
+        assert!(reject_render(&req, 1024).is_none());
+        assert!(!popular.take_top(1).is_empty());
+        assert!(!render_cache.is_empty());
+
+        guard.check(950);
+        assert!(guard.under_pressure());
+        assert!(reject_render(&req, 512).is_none());
+        let rejected = reject_render(&req, 1024).unwrap();
+        assert_eq!(rejected.status(), 503);
+        assert!(rejected.headers().contains_key(RETRY_AFTER));
+        assert!(render_cache.is_empty());
+        popular.record("osm/3/1/2.png");
+        guard.check(950);
+        assert!(popular.take_top(1).is_empty());
+
+        guard.check(100);
+        assert!(reject_render(&req, 1024).is_none());
+        // Without a guard nothing is turned away
+        let unguarded = test::TestRequest::default().to_http_request();
+        assert!(reject_render(&unguarded, 4096).is_none());
+    }
+}
@@ -0,0 +1,756 @@
+// ! # output
+// ! How rendered images are handed back. By default the PNG is the response body. With
+// ! ?output=s3 it's uploaded to the S3 bucket in OUTPUT_STORE_URL instead, and the
+// ! response is a small JSON body with a presigned URL to fetch it from, which keeps
+// ! multi-megabyte images out of the API gateway.
+// !
+// ! ?report=true sends a JSON breakdown of the render back instead of the image: tiles
+// ! fetched and cached, time per phase, bytes in and out, the zoom and the tilesets used.
+// ! ?report=header sends the image as usual with the same JSON in X-Render-Report, and
+// ! sends it with failed renders too, whose tiles_failed and failed_tiles say which tiles
+// ! timed out or errored.
+// !
+// ! Images are tagged with the SHA-256 of their bytes, as their ETag and as their name in
+// ! the bucket, so parameter variants that render byte-identical images share both. A
+// ! request whose If-None-Match has the image's ETag gets a 304 instead of the image.
+// ! Responses with an ETag and a known length, such as images served from the render
+// ! cache, also take a single byte range, so download managers can resume large images.
+// ! An If-Range that doesn't match the ETag gets the whole image, as do multiple ranges.
+// !
+// ! A render that had to give something up, e.g. a fallback tileset or a clamped zoom,
+// ! lists it in X-Render-Degradations, as comma separated tokens like
+// ! fallback;tileset=swisstopo;to=osm, with a 199 Warning header for each. X-Tile-Source
+// ! names the tilesets that served a rendered image's layers, whether or not any stood in
+// ! for the ones asked for.
+
+use crate::storage::{content_hash, ResultStore};
+use actix_web::body::{BodySize, MessageBody, SizedStream};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::{ErrorBadRequest, ErrorInternalServerError};
+use actix_web::http::header::{
+    ContentDisposition, DispositionParam, DispositionType, ETag, EntityTag, HeaderName,
+    HeaderValue, ACCEPT_RANGES, CONTENT_RANGE, ETAG, IF_NONE_MATCH, IF_RANGE, RANGE, WARNING,
+};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+use bytes::Bytes;
+use futures::{stream, Stream};
+use log::warn;
+use serde::Serialize;
+use std::future::{poll_fn, Future};
+use std::io::{Cursor, Write};
+use tile_render::avif;
+use tile_render::degradations::{self, Degradation};
+use tile_render::report::{self, RenderReport};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Output {
+    Png,
+    S3,
+}
+
+// The header ?report=header puts the render report in
+pub const REPORT_HEADER: &str = "X-Render-Report";
+pub const DEGRADATIONS_HEADER: &str = "X-Render-Degradations";
+pub const TILE_SOURCE_HEADER: &str = "X-Tile-Source";
+
+// Whether to send a render report back, and how
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Report {
+    Off,
+    Json,
+    Header,
+}
+
+#[derive(Serialize)]
+struct S3Response {
+    url: String,
+    location: String,
+    expires_in: u64,
+}
+
+#[derive(Serialize)]
+struct S3Variant {
+    size: u32,
+    url: String,
+    location: String,
+}
+
+#[derive(Serialize)]
+struct S3VariantsResponse {
+    variants: Vec<S3Variant>,
+    expires_in: u64,
+}
+
+// The ETag of a response body, from its content hash
+pub fn etag(body: &[u8]) -> ETag {
+    ETag(EntityTag::new_strong(content_hash(body)))
+}
+
+// Whether an If-None-Match header names the ETag. Tags are compared weakly, as RFC 9110
+// has it for If-None-Match.
+fn matches_etag(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if_none_match
+        .split(',')
+        .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+}
+
+// Middleware answering a request with a 304 if it already has the response's ETag. The
+// response is still rendered, as the ETag is only known once it has been, but its body
+// doesn't need to be sent.
+pub async fn not_modified(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let if_none_match = req
+        .headers()
+        .get(IF_NONE_MATCH)
+        .and_then(|val| val.to_str().ok())
+        .map(str::to_string);
+    let res = next.call(req).await?;
+    let etag = res.headers().get(ETAG).cloned();
+    let (Some(if_none_match), Some(etag)) = (if_none_match, etag) else {
+        return Ok(res.map_into_left_body());
+    };
+    let unchanged = res.status().is_success()
+        && etag
+            .to_str()
+            .is_ok_and(|etag| matches_etag(&if_none_match, etag));
+    if !unchanged {
+        return Ok(res.map_into_left_body());
+    }
+    let (req, _) = res.into_parts();
+    let not_modified = HttpResponse::NotModified()
+        .insert_header((ETAG, etag))
+        .finish();
+    Ok(ServiceResponse::new(req, not_modified).map_into_right_body())
+}
+
+// The part of a body a Range header asks for
+#[derive(Debug, PartialEq)]
+pub enum ByteRange {
+    // Headers that aren't a single byte range are ignored
+    Whole,
+    // From start up to, but not including, end
+    Part(u64, u64),
+    Unsatisfiable,
+}
+
+// Reads a Range header against a body of len bytes
+fn byte_range(range: &str, len: u64) -> ByteRange {
+    let Some(spec) = range.trim().strip_prefix("bytes=") else {
+        return ByteRange::Whole;
+    };
+    let Some((first, last)) = spec.trim().split_once('-').filter(|_| !spec.contains(',')) else {
+        return ByteRange::Whole;
+    };
+    let parse = |n: &str| n.parse::<u64>().ok();
+    let (start, end) = match (first.trim(), last.trim()) {
+        // The last n bytes
+        ("", suffix) => match parse(suffix) {
+            Some(n) => (len.saturating_sub(n), len),
+            None => return ByteRange::Whole,
+        },
+        (first, "") => match parse(first) {
+            Some(start) => (start, len),
+            None => return ByteRange::Whole,
+        },
+        (first, last) => match (parse(first), parse(last)) {
+            (Some(start), Some(last)) if start <= last => (start, (last + 1).min(len)),
+            _ => return ByteRange::Whole,
+        },
+    };
+    if start >= end {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Part(start, end)
+}
+
+// Reads a request's Range header against a response with the given ETag and length. An
+// If-Range that doesn't match the ETag asks for the whole body. It compares strongly, and
+// only dates would be weaker than our ETags.
+pub fn requested_range(
+    range: Option<&str>,
+    if_range: Option<&str>,
+    etag: &str,
+    len: u64,
+) -> ByteRange {
+    match range {
+        Some(range) if if_range.is_none_or(|tag| tag.trim() == etag) => byte_range(range, len),
+        _ => ByteRange::Whole,
+    }
+}
+
+// The Content-Range of the part of a body from start up to end
+pub fn content_range(start: u64, end: u64, len: u64) -> String {
+    format!("bytes {}-{}/{}", start, end - 1, len)
+}
+
+// The answer to a Range past the end of a body of len bytes
+pub fn range_not_satisfiable(len: u64) -> HttpResponse {
+    HttpResponse::RangeNotSatisfiable()
+        .insert_header((CONTENT_RANGE, format!("bytes */{}", len)))
+        .finish()
+}
+
+// The bytes of a body from start up to end, sliced out of its chunks as they stream
+fn slice_body<B: MessageBody + 'static>(
+    body: B,
+    start: u64,
+    end: u64,
+) -> impl Stream<Item = Result<Bytes, Error>> {
+    stream::unfold(
+        (Box::pin(body), 0),
+        move |(mut body, mut read)| async move {
+            while read < end {
+                let chunk = match poll_fn(|cx| body.as_mut().poll_next(cx)).await? {
+                    Ok(chunk) => chunk,
+                    Err(_) => {
+                        let error = ErrorInternalServerError("Couldn't read the response");
+                        return Some((Err(error), (body, end)));
+                    }
+                };
+                // Where the chunk is in the body
+                let from = read;
+                read += chunk.len() as u64;
+                if read > start {
+                    let (first, last) = (start.max(from) - from, end.min(read) - from);
+                    let part = chunk.slice(first as usize..last as usize);
+                    return Some((Ok(part), (body, read)));
+                }
+            }
+            None
+        },
+    )
+}
+
+// Middleware answering a Range request for a response with an ETag and a known length
+// with just the part asked for. The ETag, from the body's content hash, makes resuming
+// safe: a download picked up after the image was rendered again gets the same bytes. The
+// part is sliced out of the body as it streams, so large bodies aren't held in memory.
+pub async fn byte_ranges(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let header = |name: HeaderName| {
+        req.headers()
+            .get(name)
+            .and_then(|val| val.to_str().ok())
+            .map(str::to_string)
+    };
+    let (range, if_range) = (header(RANGE), header(IF_RANGE));
+    let mut res = next.call(req).await?;
+    let len = match res.response().body().size() {
+        BodySize::Sized(len) => len,
+        _ => return Ok(res.map_into_left_body()),
+    };
+    let Some(etag) = res.headers().get(ETAG).and_then(|etag| etag.to_str().ok()) else {
+        return Ok(res.map_into_left_body());
+    };
+    if res.status() != StatusCode::OK {
+        return Ok(res.map_into_left_body());
+    }
+    let range = requested_range(range.as_deref(), if_range.as_deref(), etag, len);
+    res.headers_mut()
+        .insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+
+    let (start, end) = match range {
+        ByteRange::Whole => return Ok(res.map_into_left_body()),
+        ByteRange::Part(start, end) => (start, end),
+        ByteRange::Unsatisfiable => {
+            let (req, _) = res.into_parts();
+            let unsatisfiable = range_not_satisfiable(len);
+            return Ok(ServiceResponse::new(req, unsatisfiable).map_into_right_body());
+        }
+    };
+    let (req, res) = res.into_parts();
+    let (head, whole) = res.into_parts();
+    let part = SizedStream::new(end - start, slice_body(whole, start, end));
+    let mut partial = head.set_body(part);
+    *partial.status_mut() = StatusCode::PARTIAL_CONTENT;
+    if let Ok(value) = HeaderValue::from_str(&content_range(start, end, len)) {
+        partial.headers_mut().insert(CONTENT_RANGE, value);
+    }
+    Ok(ServiceResponse::new(req, partial.map_into_boxed_body()).map_into_right_body())
+}
+
+// Lists the tilesets that served the request's renders in X-Tile-Source, and what the
+// renders gave up in X-Render-Degradations and Warning headers
+pub async fn report_degradations(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let (res, collected) = degradations::collect(next.call(req)).await;
+    let mut res = res?;
+    let headers = res.headers_mut();
+    if !collected.sources.is_empty() {
+        let name = HeaderName::try_from(TILE_SOURCE_HEADER).expect("a valid header name");
+        if let Ok(value) = HeaderValue::from_str(&collected.sources.join(", ")) {
+            headers.insert(name, value);
+        }
+    }
+    let degraded = collected.degradations;
+    if degraded.is_empty() {
+        return Ok(res);
+    }
+    let tokens: Vec<String> = degraded.iter().map(Degradation::token).collect();
+    let name = HeaderName::try_from(DEGRADATIONS_HEADER).expect("a valid header name");
+    if let Ok(value) = HeaderValue::from_str(&tokens.join(", ")) {
+        headers.insert(name, value);
+    }
+    for degradation in &degraded {
+        let warning = format!("199 pass-image-api \"{}\"", degradation);
+        if let Ok(value) = HeaderValue::from_str(&warning) {
+            headers.append(WARNING, value);
+        }
+    }
+    Ok(res)
+}
+
+// Packs named files into a ZIP. PNGs are already compressed, so they're stored as
+// they are.
+fn zip_files(files: Vec<(String, Bytes)>) -> anyhow::Result<Bytes> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    for (name, body) in files {
+        zip.start_file(name, options)?;
+        zip.write_all(&body)?;
+    }
+    Ok(Bytes::from(zip.finish()?.into_inner()))
+}
+
+// Sends named files as a ZIP download
+pub fn respond_zip(filename: &str, files: Vec<(String, Bytes)>) -> HttpResponse {
+    match zip_files(files) {
+        Ok(zip) => HttpResponse::Ok()
+            .content_type("application/zip")
+            .insert_header(etag(&zip))
+            .insert_header(ContentDisposition {
+                disposition: DispositionType::Attachment,
+                parameters: vec![DispositionParam::Filename(filename.to_string())],
+            })
+            .body(zip),
+        Err(e) => {
+            warn!("Couldn't zip rendered images: {:#}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+impl Output {
+    // Reads ?output=, checking up front that the store can take it so we don't render an
+    // image we can't deliver
+    pub fn from_query(
+        output: Option<&str>,
+        store: Option<&web::Data<ResultStore>>,
+    ) -> Result<Output, Error> {
+        match output {
+            None | Some("png") => Ok(Output::Png),
+            Some("s3") if store.is_some_and(|s| s.can_presign()) => Ok(Output::S3),
+            Some("s3") => Err(ErrorBadRequest(
+                "output=s3 needs an s3:// OUTPUT_STORE_URL to be configured",
+            )),
+            Some(other) => Err(ErrorBadRequest(format!(
+                "Unknown output {}: expected png or s3",
+                other
+            ))),
+        }
+    }
+
+    pub async fn respond(
+        self,
+        image: Bytes,
+        store: Option<&web::Data<ResultStore>>,
+    ) -> HttpResponse {
+        let store = match (self, store) {
+            (Output::S3, Some(store)) => store,
+            _ => {
+                return HttpResponse::Ok()
+                    .content_type(avif::image_type(&image).0)
+                    .insert_header(etag(&image))
+                    .body(image)
+            }
+        };
+
+        let uploaded = async {
+            let (name, location) = store.put_image(image).await?;
+            let url = store.presign(&name).await?;
+            anyhow::Ok(S3Response {
+                url,
+                location,
+                expires_in: store.url_ttl().as_secs(),
+            })
+        };
+        match uploaded.await {
+            Ok(response) => HttpResponse::Ok().json(response),
+            Err(e) => {
+                warn!("Couldn't upload rendered image: {:#}", e);
+                HttpResponse::BadGateway().body("Couldn't upload the image")
+            }
+        }
+    }
+
+    // As respond, for an image rendered at several sizes: a ZIP of them, or with S3 a
+    // JSON body with a presigned URL for each
+    pub async fn respond_variants(
+        self,
+        sizes: &[u32],
+        images: Vec<Bytes>,
+        store: Option<&web::Data<ResultStore>>,
+    ) -> HttpResponse {
+        let store = match (self, store) {
+            (Output::S3, Some(store)) => store,
+            _ => {
+                let files = sizes
+                    .iter()
+                    .zip(images)
+                    .map(|(size, image)| {
+                        (format!("{}.{}", size, avif::image_type(&image).1), image)
+                    })
+                    .collect();
+                return respond_zip("variants.zip", files);
+            }
+        };
+
+        let uploaded = async {
+            let mut variants = Vec::with_capacity(sizes.len());
+            for (&size, image) in sizes.iter().zip(images) {
+                let (name, location) = store.put_image(image).await?;
+                variants.push(S3Variant {
+                    size,
+                    url: store.presign(&name).await?,
+                    location,
+                });
+            }
+            anyhow::Ok(S3VariantsResponse {
+                variants,
+                expires_in: store.url_ttl().as_secs(),
+            })
+        };
+        match uploaded.await {
+            Ok(response) => HttpResponse::Ok().json(response),
+            Err(e) => {
+                warn!("Couldn't upload rendered images: {:#}", e);
+                HttpResponse::BadGateway().body("Couldn't upload the images")
+            }
+        }
+    }
+}
+
+impl Report {
+    // Reads ?report=. A JSON report replaces the image, so it can't also be uploaded.
+    pub fn from_query(report: Option<&str>, output: Output) -> Result<Report, Error> {
+        match report {
+            None | Some("false") => Ok(Report::Off),
+            Some("true") if output == Output::S3 => Err(ErrorBadRequest(
+                "report=true replaces the image; use report=header with output=s3",
+            )),
+            Some("true") => Ok(Report::Json),
+            Some("header") => Ok(Report::Header),
+            Some(other) => Err(ErrorBadRequest(format!(
+                "Unknown report {}: expected true, false or header",
+                other
+            ))),
+        }
+    }
+
+    // Runs a render, collecting its report if one was asked for
+    pub async fn collect<F: Future>(self, render: F) -> (F::Output, Option<RenderReport>) {
+        match self {
+            Report::Off => (render.await, None),
+            Report::Json | Report::Header => {
+                let (output, report) = report::collect(render).await;
+                (output, Some(report))
+            }
+        }
+    }
+
+    // Hands the report back with the response. A failed render keeps its error as the
+    // body, but still gets the report header, so the tiles that failed can be seen.
+    pub fn respond(self, mut response: HttpResponse, report: Option<RenderReport>) -> HttpResponse {
+        let Some(report) = report else {
+            return response;
+        };
+        match self {
+            Report::Off => response,
+            Report::Json if !response.status().is_success() => response,
+            Report::Json => HttpResponse::Ok().json(report),
+            Report::Header => {
+                let name = HeaderName::try_from(REPORT_HEADER).expect("a valid header name");
+                let value = serde_json::to_string(&report)
+                    .ok()
+                    .and_then(|json| HeaderValue::from_str(&json).ok());
+                if let Some(value) = value {
+                    response.headers_mut().insert(name, value);
+                }
+                response
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_query() {
+        let memory = web::Data::new(ResultStore::from_url("memory://").unwrap());
+        assert_eq!(Output::from_query(None, None).unwrap(), Output::Png);
+        assert_eq!(Output::from_query(Some("png"), None).unwrap(), Output::Png);
+        assert!(Output::from_query(Some("s3"), None).is_err());
+        assert!(Output::from_query(Some("s3"), Some(&memory)).is_err());
+        assert!(Output::from_query(Some("gif"), Some(&memory)).is_err());
+    }
+
+    #[test]
+    fn test_report_from_query() {
+        assert_eq!(Report::from_query(None, Output::Png).unwrap(), Report::Off);
+        assert_eq!(
+            Report::from_query(Some("true"), Output::Png).unwrap(),
+            Report::Json
+        );
+        assert_eq!(
+            Report::from_query(Some("header"), Output::S3).unwrap(),
+            Report::Header
+        );
+        assert!(Report::from_query(Some("true"), Output::S3).is_err());
+        assert!(Report::from_query(Some("yes"), Output::Png).is_err());
+    }
+
+    #[test]
+    fn test_report_respond() {
+        let report = RenderReport {
+            zoom: Some(14),
+            tiles: 4,
+            ..Default::default()
+        };
+        use actix_web::http::header::ContentType;
+
+        let png = || HttpResponse::Ok().content_type(ContentType::png()).finish();
+
+        let response = Report::Json.respond(png(), Some(report.clone()));
+        assert_eq!(
+            response.headers().get("content-type").unwrap(),
+            "application/json"
+        );
+        let response = Report::Header.respond(png(), Some(report.clone()));
+        let header = response.headers().get(REPORT_HEADER).unwrap();
+        assert!(header.to_str().unwrap().contains(r#""zoom":14"#));
+        let failed =
+            Report::Json.respond(HttpResponse::BadGateway().finish(), Some(report.clone()));
+        assert_eq!(failed.status(), 502);
+        assert!(failed.headers().get(REPORT_HEADER).is_none());
+        let failed = Report::Header.respond(HttpResponse::BadGateway().finish(), Some(report));
+        assert_eq!(failed.status(), 502);
+        assert!(failed.headers().get(REPORT_HEADER).is_some());
+    }
+
+    #[test]
+    fn test_zip_files() {
+        let zip = zip_files(vec![
+            ("512.png".to_string(), Bytes::from_static(b"big")),
+            ("256.png".to_string(), Bytes::from_static(b"small")),
+        ])
+        .unwrap();
+        let mut archive = zip::ZipArchive::new(Cursor::new(zip)).unwrap();
+        assert_eq!(archive.len(), 2);
+        let mut small = String::new();
+        std::io::Read::read_to_string(&mut archive.by_name("256.png").unwrap(), &mut small)
+            .unwrap();
+        assert_eq!(small, "small");
+    }
+
+    #[actix_web::get("/image")]
+    async fn image() -> HttpResponse {
+        Output::Png.respond(Bytes::from_static(b"png"), None).await
+    }
+
+    // A body streamed in several chunks, like a file
+    #[actix_web::get("/streamed")]
+    async fn streamed() -> HttpResponse {
+        let chunks = ["abc", "defg", "hi"].map(|chunk| Ok::<_, Error>(Bytes::from(chunk)));
+        HttpResponse::Ok()
+            .insert_header(etag(b"abcdefghi"))
+            .body(SizedStream::new(9, stream::iter(chunks)))
+    }
+
+    #[actix_web::get("/degraded")]
+    async fn degraded() -> HttpResponse {
+        degradations::record(Degradation::ZoomClamped { zoom: 21 });
+        degradations::record(Degradation::Fallback {
+            tileset: "swisstopo",
+            fallback: "osm",
+        });
+        degradations::record_source("osm");
+        HttpResponse::Ok().finish()
+    }
+
+    #[actix_web::get("/rendered")]
+    async fn rendered() -> HttpResponse {
+        degradations::record_source("esri");
+        degradations::record_source("esri_labels");
+        HttpResponse::Ok().finish()
+    }
+
+    #[actix_web::test]
+    async fn test_report_degradations() {
+        use actix_web::middleware::from_fn;
+        use actix_web::{test, App};
+
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(report_degradations))
+                .service(image)
+                .service(degraded)
+                .service(rendered),
+        )
+        .await;
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri("/degraded").to_request()).await;
+        assert_eq!(
+            response.headers().get(DEGRADATIONS_HEADER).unwrap(),
+            "zoom_clamped;zoom=21, fallback;tileset=swisstopo;to=osm"
+        );
+        let warnings: Vec<_> = response.headers().get_all(WARNING).collect();
+        assert_eq!(response.headers().get(TILE_SOURCE_HEADER).unwrap(), "osm");
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[1]
+            .to_str()
+            .unwrap()
+            .starts_with("199 pass-image-api \"The area"));
+
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri("/image").to_request()).await;
+        assert!(response.headers().get(DEGRADATIONS_HEADER).is_none());
+        assert!(response.headers().get(TILE_SOURCE_HEADER).is_none());
+
+        // A render that gave nothing up still names its tilesets
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri("/rendered").to_request()).await;
+        assert!(response.headers().get(DEGRADATIONS_HEADER).is_none());
+        assert_eq!(
+            response.headers().get(TILE_SOURCE_HEADER).unwrap(),
+            "esri, esri_labels"
+        );
+        assert!(response.headers().get(WARNING).is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_not_modified() {
+        use actix_web::middleware::from_fn;
+        use actix_web::{test, App};
+
+        assert!(matches_etag(r#"W/"abc", "def""#, r#""abc""#));
+        assert!(matches_etag("*", r#""abc""#));
+        assert!(!matches_etag(r#""ab""#, r#""abc""#));
+
+        let app = test::init_service(App::new().wrap(from_fn(not_modified)).service(image)).await;
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri("/image").to_request()).await;
+        let tag = response.headers().get(ETAG).unwrap().clone();
+        assert_eq!(
+            tag.to_str().unwrap(),
+            format!(r#""{}""#, content_hash(b"png"))
+        );
+        assert_eq!(test::read_body(response).await, "png");
+
+        let req = test::TestRequest::get()
+            .uri("/image")
+            .insert_header((IF_NONE_MATCH, tag.clone()))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), 304);
+        assert_eq!(response.headers().get(ETAG), Some(&tag));
+        assert!(test::read_body(response).await.is_empty());
+
+        let req = test::TestRequest::get()
+            .uri("/image")
+            .insert_header((IF_NONE_MATCH, r#""stale""#))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+    }
+
+    #[test]
+    fn test_byte_range() {
+        assert_eq!(byte_range("bytes=0-99", 1000), ByteRange::Part(0, 100));
+        assert_eq!(byte_range("bytes=900-", 1000), ByteRange::Part(900, 1000));
+        assert_eq!(byte_range("bytes=-100", 1000), ByteRange::Part(900, 1000));
+        assert_eq!(byte_range("bytes=-5000", 1000), ByteRange::Part(0, 1000));
+        assert_eq!(
+            byte_range("bytes=990-2000", 1000),
+            ByteRange::Part(990, 1000)
+        );
+        assert_eq!(byte_range("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(byte_range("bytes=-0", 1000), ByteRange::Unsatisfiable);
+        for ignored in ["bytes=0-1,5-9", "bytes=9-5", "items=0-9", "bytes=a-"] {
+            assert_eq!(byte_range(ignored, 1000), ByteRange::Whole, "{}", ignored);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_byte_ranges() {
+        use actix_web::middleware::from_fn;
+        use actix_web::{test, App};
+
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(byte_ranges))
+                .service(image)
+                .service(streamed),
+        )
+        .await;
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri("/image").to_request()).await;
+        assert_eq!(response.headers().get(ACCEPT_RANGES).unwrap(), "bytes");
+        let tag = response.headers().get(ETAG).unwrap().clone();
+
+        let range = |range: &str, if_range: Option<&str>| {
+            let mut req = test::TestRequest::get()
+                .uri("/image")
+                .insert_header((RANGE, range));
+            if let Some(if_range) = if_range {
+                req = req.insert_header((IF_RANGE, if_range));
+            }
+            req.to_request()
+        };
+        let response = test::call_service(&app, range("bytes=1-", None)).await;
+        assert_eq!(response.status(), 206);
+        assert_eq!(
+            response.headers().get(CONTENT_RANGE).unwrap(),
+            "bytes 1-2/3"
+        );
+        assert_eq!(response.headers().get(ETAG), Some(&tag));
+        assert_eq!(test::read_body(response).await, "ng");
+        let resumed = range("bytes=2-", Some(tag.to_str().unwrap()));
+        assert_eq!(test::call_service(&app, resumed).await.status(), 206);
+
+        // A different image is sent whole, and past its end is out of range
+        let changed = range("bytes=2-", Some(r#""stale""#));
+        let response = test::call_service(&app, changed).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(test::read_body(response).await, "png");
+        let response = test::call_service(&app, range("bytes=3-", None)).await;
+        assert_eq!(response.status(), 416);
+        assert_eq!(response.headers().get(CONTENT_RANGE).unwrap(), "bytes */3");
+
+        // Ranges across chunks are sliced out of the stream
+        for (range, part) in [
+            ("bytes=2-6", "cdefg"),
+            ("bytes=3-6", "defg"),
+            ("bytes=-1", "i"),
+        ] {
+            let req = test::TestRequest::get()
+                .uri("/streamed")
+                .insert_header((RANGE, range))
+                .to_request();
+            let response = test::call_service(&app, req).await;
+            assert_eq!(response.status(), 206);
+            assert_eq!(test::read_body(response).await, part, "{}", range);
+        }
+    }
+}
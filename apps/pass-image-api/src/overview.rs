@@ -0,0 +1,199 @@
+// ! # overview
+// ! GET /overview/{long}/{lat}/{size_px}?zooms=8,11,14 renders the same center at several
+// ! zooms in one call, for "context + detail" layouts. By default the levels come back as
+// ! a contact sheet PNG, a grid of size_px panels; ?layout=files returns them as separate
+// ! PNGs in a ZIP instead. See tile_render::overview for how they're rendered.
+
+use crate::memory;
+use crate::output::{respond_zip, Output};
+use crate::request::{bad_request, render_failed, RenderParams};
+use crate::storage::ResultStore;
+use crate::usage::{self, UsageTracker};
+use actix_web::error::ErrorBadRequest;
+use actix_web::{get, web, Error, HttpRequest, HttpResponse};
+use anyhow::Context as _;
+use log::info;
+use serde::Deserialize;
+use tile_render::coordinates::{radius_for_zoom, LatLong};
+use tile_render::fetcher::TileSources;
+use tile_render::overview::{contact_sheet, levels, render_levels};
+use tile_render::tiles::{encode_png, tile_count_for_point, TileSet};
+
+#[derive(Debug, Deserialize)]
+struct OverviewQuery {
+    zooms: String,
+    layout: Option<String>,
+    tileset: Option<String>,
+    output: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Layout {
+    Sheet,
+    Files,
+}
+
+fn parse_zooms(value: &str) -> anyhow::Result<Vec<u32>> {
+    let zooms = value
+        .split(',')
+        .map(|z| {
+            z.trim()
+                .parse()
+                .with_context(|| format!("Invalid zoom {}", z))
+        })
+        .collect::<anyhow::Result<Vec<u32>>>()?;
+    levels(&zooms)
+}
+
+#[get("/overview/{long}/{lat}/{size_px}")]
+async fn get_overview(
+    req: HttpRequest,
+    path: web::Path<(f64, f64, u32)>,
+    query: web::Query<OverviewQuery>,
+    params: web::Query<RenderParams>,
+    usage: web::Data<UsageTracker>,
+    sources: web::Data<TileSources>,
+    store: Option<web::Data<ResultStore>>,
+) -> Result<HttpResponse, Error> {
+    let (long, lat, size_px) = path.into_inner();
+    let center = LatLong(lat, long);
+
+    let api_key = usage::api_key(&req);
+    if let Err(e) = usage.check(&api_key).await {
+        return Ok(HttpResponse::TooManyRequests().body(e));
+    }
+    if let Some(rejected) = memory::reject_render(&req, size_px) {
+        return Ok(rejected);
+    }
+    let layout = match query.layout.as_deref() {
+        None | Some("sheet") => Layout::Sheet,
+        Some("files") => Layout::Files,
+        Some(other) => {
+            return Err(ErrorBadRequest(format!(
+                "Unknown layout {}: expected sheet or files",
+                other
+            )))
+        }
+    };
+    let output = Output::from_query(query.output.as_deref(), store.as_ref())?;
+    if layout == Layout::Files && output == Output::S3 {
+        return Err(ErrorBadRequest(
+            "layout=files can't be combined with output=s3",
+        ));
+    }
+    let tileset = match query.tileset.as_deref() {
+        None => TileSet::Osm,
+        Some(name) => TileSet::lookup(name)
+            .ok_or_else(|| ErrorBadRequest(format!("Unknown tileset {}", name)))?,
+    };
+    let zooms = parse_zooms(&query.zooms).map_err(bad_request)?;
+    let options = params
+        .render_options(Vec::new(), None, None)
+        .map_err(bad_request)?;
+
+    info!(
+        latitude = lat,
+        longitude = long,
+        levels = zooms.len();
+        "Rendering overview"
+    );
+
+    let images = match render_levels(
+        sources.get_ref(),
+        center,
+        size_px,
+        &zooms,
+        tileset,
+        &options,
+    )
+    .await
+    {
+        Ok(images) => images,
+        Err(e) => return Ok(render_failed(e)),
+    };
+    let tiles: u32 = zooms
+        .iter()
+        .map(|&z| {
+            let radius = radius_for_zoom(z, size_px);
+            tile_count_for_point(center, radius, size_px, tileset, &options)
+        })
+        .sum();
+    usage.record(&api_key, tiles as u64).await;
+
+    Ok(match layout {
+        Layout::Files => {
+            let files = zooms
+                .iter()
+                .map(|z| format!("z{}.png", z))
+                .zip(images)
+                .collect();
+            respond_zip("overview.zip", files)
+        }
+        Layout::Sheet => {
+            let panels = zooms
+                .iter()
+                .copied()
+                .zip(images)
+                .map(|(zoom, png)| Ok((zoom, image::load_from_memory(&png)?.to_rgba8())))
+                .collect::<anyhow::Result<Vec<_>>>();
+            match panels {
+                Ok(panels) => {
+                    let sheet = encode_png(contact_sheet(&panels, size_px));
+                    output.respond(sheet, store.as_ref()).await
+                }
+                Err(_) => HttpResponse::InternalServerError().into(),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::Connection;
+
+    #[test]
+    fn test_parse_zooms() {
+        assert_eq!(parse_zooms("14, 8,11").unwrap(), vec![8, 11, 14]);
+        assert!(parse_zooms("8,far").is_err());
+        assert!(parse_zooms("8,30").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_overview_layouts() {
+        use actix_web::{test, App};
+
+        let usage = UsageTracker::new(Connection::open_in_memory().unwrap(), None, None).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(usage))
+                .app_data(web::Data::new(TileSources::default()))
+                .service(get_overview),
+        )
+        .await;
+        let get = |query: &str| {
+            test::TestRequest::get()
+                .uri(&format!(
+                    "/overview/8.1021/46.6556/128?tileset=debug&{}",
+                    query
+                ))
+                .to_request()
+        };
+
+        let response = test::call_service(&app, get("zooms=10,13")).await;
+        assert!(response.status().is_success());
+        let sheet = image::load_from_memory(&test::read_body(response).await).unwrap();
+        assert_eq!((sheet.width(), sheet.height()), (2 * 128 + 24, 128 + 16));
+
+        let response = test::call_service(&app, get("zooms=10,13&layout=files")).await;
+        assert_eq!(
+            response.headers().get("content-type").unwrap(),
+            "application/zip"
+        );
+
+        let response = test::call_service(&app, get("zooms=10,13&layout=poster")).await;
+        assert_eq!(response.status(), 400);
+        let response = test::call_service(&app, get("zooms=10,99")).await;
+        assert_eq!(response.status(), 400);
+    }
+}
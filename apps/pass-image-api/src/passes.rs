@@ -0,0 +1,478 @@
+// ! # passes
+// ! Cards for the passes in the companion pass-api service. GET /image/pass/{pass_id} looks
+// ! the pass up in pass-api, at PASS_API_URL, and renders it the same way every time: the
+// ! country around it with the pass marked and labelled with its name and ascent, a scale
+// ! bar, and rounded, bordered corners. The trace context goes along on the call to
+// ! pass-api, so the lookup shows up in the same trace as the render.
+// !
+// ! GET /image/tour/{tour_id} does the same for a tour, a list of passes in the order
+// ! they're ridden: the map is fitted around all of them, with the route between them and
+// ! a numbered marker on each.
+
+use crate::output;
+use crate::usage::{self, UsageTracker};
+use actix_web::{get, http::header::ContentType, web, HttpRequest, HttpResponse, Responder};
+use anyhow::{anyhow, Context as _, Result};
+use log::{info, warn};
+use opentelemetry::Context;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use std::env;
+use tile_render::coordinates::{fit_points, LatLong};
+use tile_render::fetcher::TileSources;
+use tile_render::overlay;
+use tile_render::request::RenderParams;
+use tile_render::tiles::{fetch_image_from_point, tile_count_for_point, RenderOptions, TileSet};
+use tile_render::transport::{self, traced_get, Transport};
+
+const DEFAULT_PASS_API_URL: &str = "http://pass-api:8080";
+
+// Every card looks the same: this big, covering this radius in km around the pass
+const CARD_SIZE_PX: u32 = 512;
+const CARD_RADIUS_KM: f32 = 2.0;
+const CARD_MARKER_RADIUS: f32 = 8.0;
+const CARD_CORNER_RADIUS_PX: u32 = 16;
+const CARD_BORDER_PX: u32 = 3;
+const CARD_BORDER_COLOR: &str = "ffffff";
+
+// Tour overviews are bigger, and fitted around the passes with this much room to spare as
+// a fraction of their extent
+const TOUR_SIZE_PX: u32 = 1024;
+const TOUR_MARGIN: f64 = 0.1;
+const TOUR_MIN_RADIUS_KM: f32 = 2.0;
+const TOUR_MARKER_RADIUS: f32 = 10.0;
+const TOUR_ROUTE_WIDTH: f32 = 4.0;
+
+// A pass as pass-api has it. Ascent is in meters, and 0 where it isn't known.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Pass {
+    pub id: u32,
+    pub name: String,
+    pub country: String,
+    pub ascent: i32,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub climb_category: String,
+}
+
+impl Pass {
+    pub fn location(&self) -> LatLong {
+        LatLong(self.latitude, self.longitude)
+    }
+
+    fn label(&self) -> String {
+        if self.ascent > 0 {
+            format!("{} ({} m)", self.name, self.ascent)
+        } else {
+            self.name.clone()
+        }
+    }
+}
+
+// A tour as pass-api has it, with its passes in order
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Tour {
+    pub id: u32,
+    pub name: String,
+    pub passes: Vec<Pass>,
+}
+
+pub struct PassApi {
+    base_url: String,
+}
+
+impl PassApi {
+    pub fn from_env() -> PassApi {
+        PassApi::new(&env::var("PASS_API_URL").unwrap_or_else(|_| DEFAULT_PASS_API_URL.to_string()))
+    }
+
+    pub fn new(base_url: &str) -> PassApi {
+        PassApi {
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    // Looks a pass up, or None if pass-api doesn't know it
+    pub async fn pass(
+        &self,
+        transport: &dyn Transport,
+        id: u32,
+        cx: Context,
+    ) -> Result<Option<Pass>> {
+        self.get(transport, &format!("/passes/{}", id), cx).await
+    }
+
+    // Looks a tour up, or None if pass-api doesn't know it
+    pub async fn tour(
+        &self,
+        transport: &dyn Transport,
+        id: u32,
+        cx: Context,
+    ) -> Result<Option<Tour>> {
+        self.get(transport, &format!("/tours/{}", id), cx).await
+    }
+
+    // GETs a JSON resource, with a 404 as None. pass-api is ours and configured by the
+    // operator, so unlike upstreams it isn't put through the url_guard.
+    async fn get<T: DeserializeOwned>(
+        &self,
+        transport: &dyn Transport,
+        path: &str,
+        cx: Context,
+    ) -> Result<Option<T>> {
+        let url = format!("{}{}", self.base_url, path);
+        let response = traced_get(transport, &url, Vec::new(), "dd-sdlc-demo", cx).await?;
+        match response.status {
+            200 => {
+                let resource = serde_json::from_slice(&response.body)
+                    .with_context(|| format!("parsing {}", url))?;
+                Ok(Some(resource))
+            }
+            404 => Ok(None),
+            status => Err(anyhow!("{} answered {}", url, status)),
+        }
+    }
+}
+
+// The card's styling, with the pass as a labelled marker
+pub fn card_options(pass: &Pass) -> Result<RenderOptions> {
+    let marker = json!({
+        "type": "Feature",
+        "geometry": {"type": "Point", "coordinates": [pass.longitude, pass.latitude]},
+        "properties": {"title": pass.label(), "marker-radius": CARD_MARKER_RADIUS},
+    });
+    let params = RenderParams {
+        exact: Some(true),
+        corner_radius: Some(CARD_CORNER_RADIUS_PX),
+        border: Some(CARD_BORDER_PX),
+        border_color: Some(CARD_BORDER_COLOR.to_string()),
+        scale_bar: Some(true),
+        ..RenderParams::default()
+    };
+    params.render_options(overlay::from_geojson(&marker)?, None, None)
+}
+
+// The overview's styling: the route through the passes in order, and a marker on each
+// numbered from 1 and labelled with the pass's name
+pub fn tour_options(tour: &Tour) -> Result<RenderOptions> {
+    let coordinates: Vec<[f64; 2]> = tour
+        .passes
+        .iter()
+        .map(|pass| [pass.longitude, pass.latitude])
+        .collect();
+    let mut features = Vec::with_capacity(tour.passes.len() + 1);
+    if coordinates.len() > 1 {
+        features.push(json!({
+            "type": "Feature",
+            "geometry": {"type": "LineString", "coordinates": coordinates},
+            "properties": {"stroke-width": TOUR_ROUTE_WIDTH},
+        }));
+    }
+    for (i, pass) in tour.passes.iter().enumerate() {
+        features.push(json!({
+            "type": "Feature",
+            "geometry": {"type": "Point", "coordinates": [pass.longitude, pass.latitude]},
+            "properties": {
+                "title": pass.name,
+                "marker-symbol": (i + 1).to_string(),
+                "marker-radius": TOUR_MARKER_RADIUS,
+            },
+        }));
+    }
+    let overlay = json!({"type": "FeatureCollection", "features": features});
+    let params = RenderParams {
+        exact: Some(true),
+        scale_bar: Some(true),
+        // Every pass keeps its own numbered marker
+        cluster: Some(false),
+        ..RenderParams::default()
+    };
+    params.render_options(overlay::from_geojson(&overlay)?, None, None)
+}
+
+#[get("/image/pass/{pass_id}")]
+async fn get_pass_image(
+    req: HttpRequest,
+    path: web::Path<u32>,
+    pass_api: web::Data<PassApi>,
+    usage: web::Data<UsageTracker>,
+    sources: web::Data<TileSources>,
+) -> impl Responder {
+    let pass_id = path.into_inner();
+
+    let api_key = usage::api_key(&req);
+    if let Err(e) = usage.check(&api_key).await {
+        return HttpResponse::TooManyRequests().body(e);
+    }
+
+    let looked_up = async {
+        let transport = transport::plain()?;
+        pass_api
+            .pass(transport.as_ref(), pass_id, Context::current())
+            .await
+    };
+    let pass = match looked_up.await {
+        Ok(Some(pass)) => pass,
+        Ok(None) => return HttpResponse::NotFound().body(format!("Unknown pass {}", pass_id)),
+        Err(e) => {
+            warn!(pass_id = pass_id; "Couldn't look up pass: {:#}", e);
+            return HttpResponse::BadGateway().body("Couldn't look up the pass");
+        }
+    };
+
+    info!(
+        pass_id = pass_id,
+        latitude = pass.latitude,
+        longitude = pass.longitude;
+        "Fetching pass card"
+    );
+
+    let options = match card_options(&pass) {
+        Ok(options) => options,
+        Err(e) => {
+            warn!(pass_id = pass_id; "Couldn't style pass card: {:#}", e);
+            return HttpResponse::InternalServerError().into();
+        }
+    };
+    match fetch_image_from_point(
+        sources.get_ref(),
+        pass.location(),
+        CARD_RADIUS_KM,
+        CARD_SIZE_PX,
+        TileSet::Osm,
+        &options,
+    )
+    .await
+    {
+        Ok(image) => {
+            let tiles = tile_count_for_point(
+                pass.location(),
+                CARD_RADIUS_KM,
+                CARD_SIZE_PX,
+                TileSet::Osm,
+                &options,
+            );
+            usage.record(&api_key, tiles as u64).await;
+            HttpResponse::Ok()
+                .content_type(ContentType::png())
+                .insert_header(output::etag(&image))
+                .body(image)
+        }
+        Err(_) => HttpResponse::InternalServerError().into(),
+    }
+}
+
+#[get("/image/tour/{tour_id}")]
+async fn get_tour_image(
+    req: HttpRequest,
+    path: web::Path<u32>,
+    pass_api: web::Data<PassApi>,
+    usage: web::Data<UsageTracker>,
+    sources: web::Data<TileSources>,
+) -> impl Responder {
+    let tour_id = path.into_inner();
+
+    let api_key = usage::api_key(&req);
+    if let Err(e) = usage.check(&api_key).await {
+        return HttpResponse::TooManyRequests().body(e);
+    }
+
+    let looked_up = async {
+        let transport = transport::plain()?;
+        pass_api
+            .tour(transport.as_ref(), tour_id, Context::current())
+            .await
+    };
+    let tour = match looked_up.await {
+        Ok(Some(tour)) => tour,
+        Ok(None) => return HttpResponse::NotFound().body(format!("Unknown tour {}", tour_id)),
+        Err(e) => {
+            warn!(tour_id = tour_id; "Couldn't look up tour: {:#}", e);
+            return HttpResponse::BadGateway().body("Couldn't look up the tour");
+        }
+    };
+
+    let locations: Vec<LatLong> = tour.passes.iter().map(Pass::location).collect();
+    let Some((center, radius)) = fit_points(&locations, TOUR_MARGIN, TOUR_MIN_RADIUS_KM) else {
+        return HttpResponse::UnprocessableEntity().body(format!("Tour {} has no passes", tour_id));
+    };
+
+    info!(
+        tour_id = tour_id,
+        passes = tour.passes.len();
+        "Fetching tour overview"
+    );
+
+    let options = match tour_options(&tour) {
+        Ok(options) => options,
+        Err(e) => {
+            warn!(tour_id = tour_id; "Couldn't style tour overview: {:#}", e);
+            return HttpResponse::InternalServerError().into();
+        }
+    };
+    match fetch_image_from_point(
+        sources.get_ref(),
+        center,
+        radius,
+        TOUR_SIZE_PX,
+        TileSet::Osm,
+        &options,
+    )
+    .await
+    {
+        Ok(image) => {
+            let tiles = tile_count_for_point(center, radius, TOUR_SIZE_PX, TileSet::Osm, &options);
+            usage.record(&api_key, tiles as u64).await;
+            HttpResponse::Ok()
+                .content_type(ContentType::png())
+                .insert_header(output::etag(&image))
+                .body(image)
+        }
+        Err(_) => HttpResponse::InternalServerError().into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use futures::future::LocalBoxFuture;
+    use std::cell::RefCell;
+    use tile_render::overlay::Overlay;
+    use tile_render::transport::Response;
+
+    const ALBULA: &str = r#"{"id": 3, "name": "Albula Pass", "country": "Switzerland",
+        "ascent": 1005, "latitude": 46.5833, "longitude": 9.8833, "climb_category": "unknown"}"#;
+
+    const TOUR: &str = r#"{"id": 1, "name": "Engadin", "passes": [
+        {"id": 68, "name": "Julier Pass", "country": "Switzerland", "ascent": 1017,
+         "latitude": 46.472, "longitude": 9.729, "climb_category": "unknown"},
+        {"id": 3, "name": "Albula Pass", "country": "Switzerland", "ascent": 1005,
+         "latitude": 46.5833, "longitude": 9.8833, "climb_category": "unknown"},
+        {"id": 45, "name": "Flüela Pass", "country": "Switzerland", "ascent": 601,
+         "latitude": 46.7503, "longitude": 9.9475, "climb_category": "unknown"}]}"#;
+
+    // Answers every request the same way, remembering the URLs it was sent and the last
+    // request's headers
+    struct PassApiMock {
+        status: u16,
+        body: &'static str,
+        urls: RefCell<Vec<String>>,
+        headers: RefCell<Vec<(String, String)>>,
+    }
+
+    impl PassApiMock {
+        fn new(status: u16, body: &'static str) -> PassApiMock {
+            PassApiMock {
+                status,
+                body,
+                urls: RefCell::new(Vec::new()),
+                headers: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl Transport for PassApiMock {
+        fn send<'a>(
+            &'a self,
+            _method: http::Method,
+            url: &'a str,
+            headers: &'a [(String, String)],
+            _body: Bytes,
+        ) -> LocalBoxFuture<'a, Result<Response>> {
+            self.urls.borrow_mut().push(url.to_string());
+            *self.headers.borrow_mut() = headers.to_vec();
+            let response = Response {
+                status: self.status,
+                content_type: Some("application/json".to_string()),
+                location: None,
+                body: Bytes::from_static(self.body.as_bytes()),
+            };
+            Box::pin(async move { Ok(response) })
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_pass_lookup() {
+        let api = PassApi::new("http://pass-api:8080/");
+        let mock = PassApiMock::new(200, ALBULA);
+        let pass = api.pass(&mock, 3, Context::new()).await.unwrap().unwrap();
+        assert_eq!(pass.name, "Albula Pass");
+        assert_eq!(pass.ascent, 1005);
+        assert_eq!(pass.location(), LatLong(46.5833, 9.8833));
+        assert_eq!(mock.urls.take(), vec!["http://pass-api:8080/passes/3"]);
+        assert!(mock
+            .headers
+            .take()
+            .contains(&("User-Agent".to_string(), "dd-sdlc-demo".to_string())));
+
+        let mock = PassApiMock::new(404, "");
+        assert_eq!(api.pass(&mock, 999, Context::new()).await.unwrap(), None);
+
+        let mock = PassApiMock::new(500, "");
+        assert!(api.pass(&mock, 3, Context::new()).await.is_err());
+        let mock = PassApiMock::new(200, "{}");
+        assert!(api.pass(&mock, 3, Context::new()).await.is_err());
+    }
+
+    #[test]
+    fn test_card_options() {
+        let mut pass: Pass = serde_json::from_str(ALBULA).unwrap();
+        let options = card_options(&pass).unwrap();
+        assert!(options.scale_bar);
+        match &options.overlays[..] {
+            [Overlay::Point { point, label, .. }] => {
+                assert_eq!(*point, pass.location());
+                assert_eq!(label.as_deref(), Some("Albula Pass (1005 m)"));
+            }
+            other => panic!("expected a single marker, got {:?}", other),
+        }
+
+        // Passes without a known ascent are labelled with just their name
+        pass.ascent = 0;
+        assert_eq!(pass.label(), "Albula Pass");
+    }
+
+    #[actix_web::test]
+    async fn test_tour_lookup() {
+        let api = PassApi::new("http://pass-api:8080");
+        let mock = PassApiMock::new(200, TOUR);
+        let tour = api.tour(&mock, 1, Context::new()).await.unwrap().unwrap();
+        assert_eq!(tour.name, "Engadin");
+        assert_eq!(tour.passes.len(), 3);
+        assert_eq!(tour.passes[1].name, "Albula Pass");
+        assert_eq!(mock.urls.take(), vec!["http://pass-api:8080/tours/1"]);
+
+        let mock = PassApiMock::new(404, "");
+        assert_eq!(api.tour(&mock, 2, Context::new()).await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_tour_options() {
+        let tour: Tour = serde_json::from_str(TOUR).unwrap();
+        let options = tour_options(&tour).unwrap();
+        assert_eq!(options.cluster, Some(false));
+        match &options.overlays[..] {
+            [Overlay::Line { points, .. }, markers @ ..] => {
+                assert_eq!(points.len(), 3);
+                let symbols: Vec<Option<&str>> = markers
+                    .iter()
+                    .map(|marker| match marker {
+                        Overlay::Point { symbol, .. } => symbol.as_deref(),
+                        other => panic!("expected a marker, got {:?}", other),
+                    })
+                    .collect();
+                assert_eq!(symbols, vec![Some("1"), Some("2"), Some("3")]);
+            }
+            other => panic!("expected the route and markers, got {:?}", other),
+        }
+
+        // A tour of one pass has no route to draw
+        let single = Tour {
+            passes: tour.passes[..1].to_vec(),
+            ..tour
+        };
+        assert_eq!(tour_options(&single).unwrap().overlays.len(), 1);
+    }
+}
@@ -0,0 +1,204 @@
+// ! # pools
+// ! Keeps batch work from starving interactive requests. Single images, tiles, overviews
+// ! and pass images render on the HTTP server's workers, as they always have. Batch work,
+// ! render jobs and MBTiles exports, runs on a BatchPool instead: BATCH_WORKERS threads of
+// ! its own, each with its own actix runtime like grpc's RenderWorker, working through one
+// ! task at a time. However many exports are asked for, at most BATCH_WORKERS run at once,
+// ! and none of them on a thread an interactive request is waiting on.
+// !
+// ! At most BATCH_QUEUE tasks wait for a thread. Past that, batch endpoints answer 503
+// ! with a Retry-After rather than queueing without bound. Work that isn't a request, such
+// ! as jobs recovered at startup, waits for room in the queue instead. Tasks are counted
+// ! in the batch_tasks metric, by outcome.
+
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::HttpResponse;
+use anyhow::{anyhow, Context as _, Result};
+use futures::future::LocalBoxFuture;
+use log::info;
+use opentelemetry::{global, KeyValue};
+use std::env;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot, Mutex};
+
+const DEFAULT_WORKERS: usize = 2;
+const DEFAULT_QUEUE: usize = 64;
+
+// How long clients turned away are asked to wait
+const RETRY_AFTER_SECS: u32 = 10;
+
+// Tasks are built on the thread that runs them, as render futures aren't Send
+type Task = Box<dyn FnOnce() -> LocalBoxFuture<'static, ()> + Send>;
+
+fn record_task(outcome: &'static str) {
+    let meter = global::meter("pools_meter");
+    let tasks = meter.u64_counter("batch_tasks").init();
+    tasks.add(1, &[KeyValue::new("outcome", outcome)]);
+}
+
+// The batch pool's queue is full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PoolBusy;
+
+impl fmt::Display for PoolBusy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Too much batch work is queued; try again shortly")
+    }
+}
+
+impl std::error::Error for PoolBusy {}
+
+impl PoolBusy {
+    // The 503 batch endpoints answer with
+    pub fn response(&self) -> HttpResponse {
+        HttpResponse::ServiceUnavailable()
+            .insert_header((RETRY_AFTER, RETRY_AFTER_SECS))
+            .body(self.to_string())
+    }
+}
+
+pub struct BatchPool {
+    tasks: mpsc::Sender<Task>,
+}
+
+impl BatchPool {
+    // Starts the pool's worker threads, with room for queue tasks to wait for them
+    pub fn new(workers: usize, queue: usize) -> BatchPool {
+        let (tasks, receiver) = mpsc::channel::<Task>(queue.max(1));
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..workers.max(1) {
+            let receiver = receiver.clone();
+            std::thread::spawn(move || {
+                actix_web::rt::System::new().block_on(async move {
+                    loop {
+                        // The lock is only held while waiting, not while the task runs
+                        let task = receiver.lock().await.recv().await;
+                        let Some(task) = task else { break };
+                        task().await;
+                    }
+                })
+            });
+        }
+        BatchPool { tasks }
+    }
+
+    // The pool for BATCH_WORKERS and BATCH_QUEUE, falling back to the defaults
+    pub fn from_env() -> Result<BatchPool> {
+        let number = |name: &str, default: usize| -> Result<usize> {
+            match env::var(name) {
+                Ok(v) => v
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .with_context(|| format!("Invalid {} {}", name, v)),
+                Err(_) => Ok(default),
+            }
+        };
+        let workers = number("BATCH_WORKERS", DEFAULT_WORKERS)?;
+        let queue = number("BATCH_QUEUE", DEFAULT_QUEUE)?;
+        info!(
+            "Running batch work on {} threads, with up to {} tasks queued",
+            workers, queue
+        );
+        Ok(BatchPool::new(workers, queue))
+    }
+
+    // Queues a task to run on one of the pool's threads, or turns it away if the queue
+    // is full
+    pub fn spawn<F, Fut>(&self, task: F) -> Result<(), PoolBusy>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        let task: Task = Box::new(move || Box::pin(task()));
+        match self.tasks.try_send(task) {
+            Ok(()) => {
+                record_task("queued");
+                Ok(())
+            }
+            Err(_) => {
+                record_task("rejected");
+                Err(PoolBusy)
+            }
+        }
+    }
+
+    // Queues a task to run on one of the pool's threads, waiting for room in the queue if
+    // it's full
+    pub async fn spawn_waiting<F, Fut>(&self, task: F) -> Result<()>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        let task: Task = Box::new(move || Box::pin(task()));
+        self.tasks
+            .send(task)
+            .await
+            .map_err(|_| anyhow!("The batch pool has stopped"))?;
+        record_task("queued");
+        Ok(())
+    }
+
+    // Runs a task on one of the pool's threads and waits for its result
+    pub async fn run<F, Fut, T>(&self, task: F) -> Result<T>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = T> + 'static,
+        T: Send + 'static,
+    {
+        let (result, receiver) = oneshot::channel();
+        self.spawn(move || async move {
+            let _ = result.send(task().await);
+        })?;
+        receiver
+            .await
+            .map_err(|_| anyhow!("The batch pool has stopped"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::rt::time::timeout;
+    use std::time::Duration;
+
+    #[actix_web::test]
+    async fn test_batch_pool() {
+        let pool = BatchPool::new(1, 1);
+        let caller = std::thread::current().id();
+        let thread = pool.run(|| async { std::thread::current().id() }).await;
+        assert_ne!(thread.unwrap(), caller);
+
+        // Hold the only thread, then fill the queue
+        let (started, is_started) = oneshot::channel();
+        let (release, released) = oneshot::channel::<()>();
+        pool.spawn(move || async move {
+            let _ = started.send(());
+            let _ = released.await;
+        })
+        .unwrap();
+        is_started.await.unwrap();
+        let (result, queued) = oneshot::channel();
+        pool.spawn(move || async move {
+            let _ = result.send(42);
+        })
+        .unwrap();
+        let busy = pool.spawn(|| async {}).unwrap_err();
+        assert_eq!(busy.response().status(), 503);
+
+        // Waiting for room queues the task once the queue drains
+        let (waited, is_waited) = oneshot::channel();
+        let mut waiting = Box::pin(pool.spawn_waiting(move || async move {
+            let _ = waited.send(7);
+        }));
+        let pending = timeout(Duration::from_millis(50), &mut waiting).await;
+        assert!(pending.is_err());
+
+        release.send(()).unwrap();
+        waiting.await.unwrap();
+        assert_eq!(queued.await.unwrap(), 42);
+        assert_eq!(is_waited.await.unwrap(), 7);
+    }
+}
@@ -0,0 +1,176 @@
+// ! # prefetch
+// ! Warms the tile cache around the images it serves, so an interactive map that's panned
+// ! finds the tiles it asks for next already cached. With PREFETCH_TILES set, once GET or
+// ! POST /images has rendered a single image, up to that many tiles from the ring just
+// ! outside it are fetched in the background, PREFETCH_CONCURRENCY at a time, under the
+// ! request's archive date and languages. Which tiles, and how it backs off from failing
+// ! upstreams, is up to tile_render::prefetch.
+// !
+// ! At most PREFETCH_MAX_PENDING tiles wait to be prefetched across every request. A ring
+// ! that doesn't fit is dropped rather than queued, so prefetching never builds up a
+// ! backlog behind a busy service. Prefetched tiles don't count towards usage, and are
+// ! counted in the prefetch_tiles metric by outcome.
+
+use actix_web::{web, HttpRequest};
+use anyhow::{anyhow, Context as _, Result};
+use opentelemetry::{global, KeyValue};
+use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tile_render::coordinates::LatLong;
+use tile_render::fetcher::TileSources;
+use tile_render::prefetch::{self, Prefetched};
+use tile_render::tiles::{RenderOptions, TileSet};
+use tile_render::{archive, locale};
+
+const DEFAULT_CONCURRENCY: usize = 4;
+const DEFAULT_MAX_PENDING: usize = 1000;
+
+fn record_tiles(outcome: &'static str, tiles: usize) {
+    if tiles == 0 {
+        return;
+    }
+    let meter = global::meter("prefetch_meter");
+    let prefetched = meter.u64_counter("prefetch_tiles").init();
+    prefetched.add(tiles as u64, &[KeyValue::new("outcome", outcome)]);
+}
+
+pub struct Prefetcher {
+    // The most tiles prefetched around one image
+    budget: usize,
+    concurrency: usize,
+    max_pending: usize,
+    pending: AtomicUsize,
+}
+
+impl Prefetcher {
+    pub fn new(budget: usize, concurrency: usize, max_pending: usize) -> Prefetcher {
+        Prefetcher {
+            budget,
+            concurrency: concurrency.max(1),
+            max_pending,
+            pending: AtomicUsize::new(0),
+        }
+    }
+
+    // The prefetcher for PREFETCH_TILES, if it's set
+    pub fn from_env() -> Result<Option<Prefetcher>> {
+        let Ok(budget) = env::var("PREFETCH_TILES") else {
+            return Ok(None);
+        };
+        let budget = budget
+            .parse::<usize>()
+            .ok()
+            .filter(|&b| b > 0)
+            .ok_or_else(|| anyhow!("Invalid PREFETCH_TILES {}", budget))?;
+        if env::var("TILE_CACHE_URL").is_err() {
+            return Err(anyhow!(
+                "TILE_CACHE_URL must be set for there to be a cache to prefetch into"
+            ));
+        }
+        let number = |name: &str, default: usize| -> Result<usize> {
+            env::var(name)
+                .ok()
+                .map(|n| n.parse().with_context(|| format!("Invalid {}", name)))
+                .transpose()
+                .map(|n| n.unwrap_or(default))
+        };
+        Ok(Some(Prefetcher::new(
+            budget,
+            number("PREFETCH_CONCURRENCY", DEFAULT_CONCURRENCY)?,
+            number("PREFETCH_MAX_PENDING", DEFAULT_MAX_PENDING)?,
+        )))
+    }
+
+    // Claims room for the given number of tiles, if there is any
+    fn reserve(&self, tiles: usize) -> bool {
+        self.pending
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |pending| {
+                Some(pending + tiles).filter(|&total| total <= self.max_pending)
+            })
+            .is_ok()
+    }
+
+    // Prefetches tiles that have been reserved, giving their room back once it's done
+    async fn prefetch(
+        &self,
+        sources: &TileSources,
+        tiles: &[(TileSet, u32, u32, u32)],
+    ) -> Prefetched {
+        let prefetched = prefetch::prefetch(sources, tiles, self.concurrency).await;
+        self.pending.fetch_sub(tiles.len(), Ordering::Relaxed);
+        record_tiles("fetched", prefetched.fetched);
+        record_tiles("failed", prefetched.failed);
+        record_tiles("skipped", prefetched.skipped);
+        prefetched
+    }
+}
+
+// Prefetches the ring of tiles around an image that's just been rendered for the
+// request, in the background. Without a Prefetcher configured, this does nothing.
+pub fn after_render(
+    req: &HttpRequest,
+    center: LatLong,
+    radius_km: f32,
+    size_px: u32,
+    tileset: TileSet,
+    options: &RenderOptions,
+) {
+    let (Some(prefetcher), Some(sources)) = (
+        req.app_data::<web::Data<Prefetcher>>(),
+        req.app_data::<web::Data<TileSources>>(),
+    ) else {
+        return;
+    };
+    let tiles = prefetch::neighbors(
+        center,
+        radius_km,
+        size_px,
+        tileset,
+        options,
+        prefetcher.budget,
+    );
+    if !prefetcher.reserve(tiles.len()) {
+        record_tiles("dropped", tiles.len());
+        return;
+    }
+    let (prefetcher, sources) = (prefetcher.clone(), sources.clone());
+    let (date, languages) = (archive::date(), locale::languages());
+    actix_web::rt::spawn(async move {
+        let prefetch = locale::scope(languages, prefetcher.prefetch(&sources, &tiles));
+        match date {
+            Some(date) => archive::scope(date, prefetch).await,
+            None => prefetch.await,
+        };
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tile_render::debug_tiles::debug_tile;
+    use tile_render::fetcher::MemoryFetcher;
+    use tile_render::tiles::encode_png;
+
+    #[actix_web::test]
+    async fn test_prefetcher() {
+        let prefetcher = Prefetcher::new(8, 2, 10);
+        let upstream = MemoryFetcher::default().with_fallback(encode_png(debug_tile(1, 2, 3)));
+        let sources = TileSources::default().with_source(TileSet::Osm, Box::new(upstream));
+        let tiles = prefetch::neighbors(
+            LatLong(46.655559, 8.102121),
+            1.0,
+            256,
+            TileSet::Osm,
+            &RenderOptions::default(),
+            prefetcher.budget,
+        );
+        assert_eq!(tiles.len(), 8);
+
+        // A second ring doesn't fit until the first is done
+        assert!(prefetcher.reserve(tiles.len()));
+        assert!(!prefetcher.reserve(tiles.len()));
+        let prefetched = prefetcher.prefetch(&sources, &tiles).await;
+        assert_eq!((prefetched.fetched, prefetched.failed), (8, 0));
+        assert!(prefetcher.reserve(tiles.len()));
+    }
+}
@@ -0,0 +1,465 @@
+// ! # queue
+// ! Queue consumer mode, for asynchronous bulk renders such as pass thumbnails. Started as
+// ! `pass-image-api consume`, the binary takes render requests off a message queue instead
+// ! of serving HTTP, writes each image to object storage and publishes a completion event.
+// !
+// ! Brokers sit behind the MessageQueue trait. NATS JetStream is built in; Kafka or SQS
+// ! slot in by implementing it and adding their scheme to queue_from_env. A message is
+// ! acked once its completion event is out, so renders interrupted by a crash are
+// ! redelivered. Renders that fail are acked with a failed event rather than retried.
+
+use crate::jobs::JobStatus;
+use crate::limits::BodyLimits;
+use crate::request::{parse_body, read_gpx_overlay};
+use crate::storage::ResultStore;
+use crate::usage::{UsageTracker, ANONYMOUS_KEY};
+use actix_web::web;
+use anyhow::{anyhow, Context, Result};
+use async_nats::jetstream;
+use bytes::Bytes;
+use futures::future::LocalBoxFuture;
+use futures::stream::LocalBoxStream;
+use futures::StreamExt;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::time::Instant;
+use tile_render::avif;
+use tile_render::fetcher::TileSources;
+use tile_render::request::ImageRequest;
+use tile_render::tiles::{fetch_image_from_point, tile_count_for_point};
+use uuid::Uuid;
+
+const DEFAULT_SUBJECT: &str = "pass-image.render";
+const DEFAULT_EVENTS_SUBJECT: &str = "pass-image.done";
+const DEFAULT_STREAM: &str = "PASS_IMAGE";
+const DEFAULT_CONCURRENCY: usize = 4;
+
+// A message taken off the queue
+pub trait Delivery {
+    fn payload(&self) -> &[u8];
+    // Tells the broker the message has been dealt with, so it isn't redelivered
+    fn ack(self: Box<Self>) -> LocalBoxFuture<'static, Result<()>>;
+}
+
+// Messages as they arrive. The stream ends if the queue is closed.
+pub type Deliveries = LocalBoxStream<'static, Result<Box<dyn Delivery>>>;
+
+pub trait MessageQueue {
+    // Subscribes to the render requests waiting on the queue
+    fn deliveries(&self) -> LocalBoxFuture<'_, Result<Deliveries>>;
+    // Publishes a completion event
+    fn publish(&self, event: Bytes) -> LocalBoxFuture<'_, Result<()>>;
+}
+
+// A render request on the queue: a POST /images body, read within the same limits and
+// with GPX overlays converted as it is, with an optional ID to name the result by and an
+// API key to account it against
+#[derive(Debug, Deserialize)]
+struct QueuedRender {
+    #[serde(default)]
+    id: Option<String>,
+    #[serde(default)]
+    api_key: Option<String>,
+    #[serde(flatten)]
+    request: ImageRequest,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CompletionEvent {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    status: JobStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    tiles: u32,
+    duration_ms: u64,
+}
+
+// Renders requests off a queue into a ResultStore
+pub struct Consumer {
+    sources: web::Data<TileSources>,
+    store: ResultStore,
+    usage: web::Data<UsageTracker>,
+    limits: BodyLimits,
+    concurrency: usize,
+}
+
+impl Consumer {
+    pub fn new(
+        sources: web::Data<TileSources>,
+        store: ResultStore,
+        usage: web::Data<UsageTracker>,
+        limits: BodyLimits,
+        concurrency: usize,
+    ) -> Consumer {
+        Consumer {
+            sources,
+            store,
+            usage,
+            limits,
+            concurrency,
+        }
+    }
+
+    // Consumes the queue until it closes
+    pub async fn run(&self, queue: &dyn MessageQueue) -> Result<()> {
+        let deliveries = queue.deliveries().await?;
+        deliveries
+            .for_each_concurrent(self.concurrency, |delivery| async move {
+                let delivery = match delivery {
+                    Ok(delivery) => delivery,
+                    Err(e) => return warn!("Couldn't take a message off the queue: {}", e),
+                };
+                let event = self.process(delivery.payload()).await;
+                let published = match serde_json::to_vec(&event) {
+                    Ok(json) => queue.publish(json.into()).await,
+                    Err(e) => Err(e.into()),
+                };
+                match published {
+                    Ok(()) => {
+                        if let Err(e) = delivery.ack().await {
+                            warn!("Couldn't ack render request: {}", e);
+                        }
+                    }
+                    // Left unacked, so the broker redelivers it
+                    Err(e) => warn!("Couldn't publish completion event: {}", e),
+                }
+            })
+            .await;
+        Ok(())
+    }
+
+    async fn process(&self, payload: &[u8]) -> CompletionEvent {
+        let started = Instant::now();
+        let queued = parse_body::<QueuedRender>(payload, &self.limits).and_then(|mut queued| {
+            read_gpx_overlay(&mut queued.request, &self.limits)?;
+            Ok(queued)
+        });
+        let id = match &queued {
+            Ok(queued) => queued.id.clone(),
+            // Try to pick the ID out of an otherwise invalid request
+            Err(_) => serde_json::from_slice::<serde_json::Value>(payload)
+                .ok()
+                .and_then(|v| v.get("id")?.as_str().map(str::to_string)),
+        };
+
+        let mut tiles = 0;
+        let result = match queued {
+            Ok(queued) => self.render(queued, &mut tiles).await,
+            Err(e) => Err(anyhow!("Invalid render request: {}", e)),
+        };
+        let duration_ms = started.elapsed().as_millis() as u64;
+        match result {
+            Ok((id, location)) => {
+                info!(render_id = id.as_str(), duration_ms = duration_ms; "Rendered queued request");
+                CompletionEvent {
+                    id: Some(id),
+                    status: JobStatus::Done,
+                    location: Some(location),
+                    error: None,
+                    tiles,
+                    duration_ms,
+                }
+            }
+            Err(e) => {
+                warn!(
+                    "Queued render {} failed: {:#}",
+                    id.as_deref().unwrap_or("?"),
+                    e
+                );
+                CompletionEvent {
+                    id,
+                    status: JobStatus::Failed,
+                    location: None,
+                    error: Some(format!("{:#}", e)),
+                    tiles,
+                    duration_ms,
+                }
+            }
+        }
+    }
+
+    // Renders and stores the image, returning its ID and location
+    async fn render(&self, queued: QueuedRender, tiles: &mut u32) -> Result<(String, String)> {
+        let api_key = queued.api_key.as_deref().unwrap_or(ANONYMOUS_KEY);
+        self.usage.check(api_key).await.map_err(|e| anyhow!(e))?;
+
+        let id = queued.id.unwrap_or_else(|| Uuid::new_v4().to_string());
+        // IDs name objects, so keep them to characters that are safe in any store
+        let safe = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if id.is_empty() || !id.chars().all(safe) {
+            return Err(anyhow!("Invalid ID {:?}: use letters, digits, - and _", id));
+        }
+        let request = queued.request;
+        let options = request.render_options()?;
+        let center = request.center();
+        let image = fetch_image_from_point(
+            self.sources.get_ref(),
+            center,
+            request.radius,
+            request.size_px,
+            request.tileset(),
+            &options,
+        )
+        .await?;
+        *tiles = tile_count_for_point(
+            center,
+            request.radius,
+            request.size_px,
+            request.tileset(),
+            &options,
+        );
+        self.usage.record(api_key, *tiles as u64).await;
+
+        let extension = avif::image_type(&image).1;
+        let location = self
+            .store
+            .put(&format!("{}.{}", id, extension), image)
+            .await?;
+        Ok((id, location))
+    }
+}
+
+// A NATS JetStream work queue. Requests are published to a subject captured by the
+// stream, which is created if it doesn't exist, and read by a durable pull consumer so
+// replicas share the work. Events go out as plain NATS messages.
+pub struct NatsQueue {
+    client: async_nats::Client,
+    stream: String,
+    subject: String,
+    events_subject: String,
+}
+
+struct NatsDelivery(jetstream::Message);
+
+impl Delivery for NatsDelivery {
+    fn payload(&self) -> &[u8] {
+        &self.0.payload
+    }
+
+    fn ack(self: Box<Self>) -> LocalBoxFuture<'static, Result<()>> {
+        Box::pin(async move { self.0.ack().await.map_err(|e| anyhow!(e)) })
+    }
+}
+
+impl NatsQueue {
+    pub async fn connect(
+        url: &str,
+        stream: String,
+        subject: String,
+        events_subject: String,
+    ) -> Result<NatsQueue> {
+        let client = async_nats::connect(url)
+            .await
+            .with_context(|| format!("connecting to {}", url))?;
+        Ok(NatsQueue {
+            client,
+            stream,
+            subject,
+            events_subject,
+        })
+    }
+}
+
+impl MessageQueue for NatsQueue {
+    fn deliveries(&self) -> LocalBoxFuture<'_, Result<Deliveries>> {
+        Box::pin(async move {
+            let jetstream = jetstream::new(self.client.clone());
+            let stream = jetstream
+                .get_or_create_stream(jetstream::stream::Config {
+                    name: self.stream.clone(),
+                    subjects: vec![self.subject.clone()],
+                    retention: jetstream::stream::RetentionPolicy::WorkQueue,
+                    ..Default::default()
+                })
+                .await
+                .with_context(|| format!("opening stream {}", self.stream))?;
+            let consumer = stream
+                .get_or_create_consumer(
+                    "pass-image-api",
+                    jetstream::consumer::pull::Config {
+                        durable_name: Some("pass-image-api".to_string()),
+                        ..Default::default()
+                    },
+                )
+                .await
+                .context("creating consumer")?;
+            let messages = consumer.messages().await.context("reading messages")?;
+            let deliveries = messages.map(|message| match message {
+                Ok(message) => Ok(Box::new(NatsDelivery(message)) as Box<dyn Delivery>),
+                Err(e) => Err(anyhow!(e)),
+            });
+            Ok(deliveries.boxed_local())
+        })
+    }
+
+    fn publish(&self, event: Bytes) -> LocalBoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            self.client
+                .publish(self.events_subject.clone(), event)
+                .await
+                .with_context(|| format!("publishing to {}", self.events_subject))
+        })
+    }
+}
+
+// Connects to the broker in QUEUE_URL. Only nats:// is supported so far.
+async fn queue_from_env() -> Result<Box<dyn MessageQueue>> {
+    let url = env::var("QUEUE_URL").context("QUEUE_URL must be set to consume a queue")?;
+    let var = |name: &str, default: &str| env::var(name).unwrap_or_else(|_| default.to_string());
+    if url.starts_with("nats://") {
+        let queue = NatsQueue::connect(
+            &url,
+            var("QUEUE_STREAM", DEFAULT_STREAM),
+            var("QUEUE_SUBJECT", DEFAULT_SUBJECT),
+            var("QUEUE_EVENTS_SUBJECT", DEFAULT_EVENTS_SUBJECT),
+        )
+        .await?;
+        Ok(Box::new(queue))
+    } else {
+        Err(anyhow!("Unsupported queue {}: expected nats://", url))
+    }
+}
+
+// Runs the consumer configured by QUEUE_*, OUTPUT_STORE_URL and the usual tile sources
+// until the queue closes
+pub async fn consume_from_env(
+    sources: web::Data<TileSources>,
+    usage: web::Data<UsageTracker>,
+) -> Result<()> {
+    let store = ResultStore::from_env()?
+        .ok_or_else(|| anyhow!("OUTPUT_STORE_URL must be set to consume a queue"))?;
+    let concurrency = env::var("QUEUE_CONCURRENCY")
+        .ok()
+        .and_then(|c| c.parse().ok())
+        .unwrap_or(DEFAULT_CONCURRENCY);
+    let queue = queue_from_env().await?;
+
+    info!(
+        "Consuming render requests, writing results to {}",
+        store.location()
+    );
+    Consumer::new(sources, store, usage, BodyLimits::from_env(), concurrency)
+        .run(queue.as_ref())
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+    use image::{Rgba, RgbaImage};
+    use rusqlite::Connection;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use tile_render::fetcher::MemoryFetcher;
+    use tile_render::tiles::{encode_png, TileSet};
+
+    // A queue holding a fixed list of messages, recording what happens to them
+    #[derive(Default)]
+    struct MemoryQueue {
+        messages: Vec<&'static str>,
+        acked: Rc<RefCell<Vec<String>>>,
+        events: RefCell<Vec<serde_json::Value>>,
+    }
+
+    struct MemoryDelivery {
+        payload: &'static str,
+        acked: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Delivery for MemoryDelivery {
+        fn payload(&self) -> &[u8] {
+            self.payload.as_bytes()
+        }
+
+        fn ack(self: Box<Self>) -> LocalBoxFuture<'static, Result<()>> {
+            self.acked.borrow_mut().push(self.payload.to_string());
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    impl MessageQueue for MemoryQueue {
+        fn deliveries(&self) -> LocalBoxFuture<'_, Result<Deliveries>> {
+            let deliveries: Vec<Result<Box<dyn Delivery>>> = self
+                .messages
+                .iter()
+                .map(|&payload| {
+                    let acked = self.acked.clone();
+                    Ok(Box::new(MemoryDelivery { payload, acked }) as Box<dyn Delivery>)
+                })
+                .collect();
+            Box::pin(async move { Ok(stream::iter(deliveries).boxed_local()) })
+        }
+
+        fn publish(&self, event: Bytes) -> LocalBoxFuture<'_, Result<()>> {
+            self.events
+                .borrow_mut()
+                .push(serde_json::from_slice(&event).unwrap());
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    fn consumer() -> Consumer {
+        let tile = encode_png(RgbaImage::from_pixel(256, 256, Rgba([0, 128, 0, 255])));
+        let sources = TileSources::default().with_source(
+            TileSet::Osm,
+            Box::new(MemoryFetcher::default().with_fallback(tile)),
+        );
+        let usage = UsageTracker::new(Connection::open_in_memory().unwrap(), None, None).unwrap();
+        Consumer::new(
+            web::Data::new(sources),
+            ResultStore::from_url("memory://thumbs").unwrap(),
+            web::Data::new(usage),
+            BodyLimits::from_env(),
+            2,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_consume() {
+        let consumer = consumer();
+        let queue = MemoryQueue {
+            messages: vec![
+                r#"{"id": "scheidegg", "long": 8.1, "lat": 46.6, "size_px": 128}"#,
+                r#"{"id": "broken", "long": 8.1, "lat": 46.6}"#,
+                r#"{"long": 8.1, "lat": 46.6, "size_px": 128, "filter": "neon"}"#,
+                r#"{"id": "../etc", "long": 8.1, "lat": 46.6, "size_px": 128}"#,
+                concat!(
+                    r#"{"id": "track", "long": 8.1, "lat": 46.6, "size_px": 128, "overlay": ""#,
+                    "<gpx><trk><trkseg>",
+                    "<trkpt lat='46.6' lon='8.1'/><trkpt lat='46.61' lon='8.11'/>",
+                    r#"</trkseg></trk></gpx>"}"#,
+                ),
+            ],
+            ..Default::default()
+        };
+        consumer.run(&queue).await.unwrap();
+
+        assert_eq!(queue.acked.borrow().len(), 5);
+        let events = queue.events.take();
+        let event = |id: &str| {
+            events
+                .iter()
+                .find(|e| e["id"] == id)
+                .unwrap_or_else(|| panic!("no event for {}", id))
+        };
+
+        let done = event("scheidegg");
+        assert_eq!(done["status"], "done");
+        assert_eq!(done["location"], "memory:///thumbs/scheidegg.png");
+        assert!(done["tiles"].as_u64().unwrap() > 0);
+        let png = consumer.store.get("scheidegg.png").await.unwrap().unwrap();
+        assert!(image::load_from_memory(&png).is_ok());
+
+        assert_eq!(event("track")["status"], "done");
+        assert_eq!(event("broken")["status"], "failed");
+        assert_eq!(event("../etc")["status"], "failed");
+        let unnamed = events.iter().find(|e| e.get("id").is_none()).unwrap();
+        assert_eq!(unnamed["status"], "failed");
+        assert!(unnamed["error"].as_str().unwrap().contains("neon"));
+    }
+}
@@ -0,0 +1,382 @@
+// ! # refresh
+// ! Keeps the most popular tiles in the tile cache fresh. With REFRESH_SCHEDULE set, the
+// ! cache counts how often each tile is asked for, and on that schedule the REFRESH_TOP
+// ! most asked for are fetched from upstream again, REFRESH_CONCURRENCY at a time. That
+// ! keeps hot tiles warm and moves their upstream fetches to off-peak hours.
+// !
+// ! The schedule is one or more cron entries in UTC, separated by semicolons: minute,
+// ! hour, day of month, month and day of week, each *, a number, a range a-b, a step */n
+// ! or a-b/n, or a list of those. "0 3 * * *;30 13 * * 6,0" refreshes at 03:00 every day
+// ! and at 13:30 at weekends. Every refresh halves the counts, so tiles that stop being
+// ! asked for drop out of the refresh.
+
+use crate::usage::date_from_days;
+use anyhow::{anyhow, Context as _, Result};
+use futures::{stream, StreamExt};
+use log::{info, warn};
+use opentelemetry::Context;
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tile_render::cache::CachedTile;
+use tile_render::fetcher::TileSources;
+
+const DEFAULT_TOP: usize = 500;
+const DEFAULT_CONCURRENCY: usize = 4;
+
+// The most tiles counted at once. Past it, only tiles already counted are, until a
+// refresh halves the counts and drops the tiles asked for once.
+const MAX_TRACKED_TILES: usize = 100_000;
+
+// How far ahead to look for a schedule's next refresh, enough for Feb 29 entries
+const MAX_LOOKAHEAD_MINUTES: u64 = 4 * 366 * 24 * 60;
+
+// One cron entry. Each field is a bitmask of the values it matches.
+#[derive(Debug, Clone, PartialEq)]
+struct Entry {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // As in cron, if only one of the day fields is restricted only that one applies, and
+    // if both are either one matching will do
+    any_day: bool,
+    any_weekday: bool,
+}
+
+// Parses one cron field into a bitmask of the values from min to max it matches
+fn parse_field(spec: &str, min: u32, max: u32) -> Result<u64> {
+    let mut mask = 0u64;
+    for part in spec.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|&s| s > 0)),
+            None => (part, Some(1)),
+        };
+        let step = step.ok_or_else(|| anyhow!("Invalid step in {}", part))?;
+        let (from, to) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((from, to)) => (from.parse()?, to.parse()?),
+            None if part.contains('/') => (range.parse()?, max),
+            None => {
+                let value = range.parse()?;
+                (value, value)
+            }
+        };
+        if from < min || to > max || from > to {
+            return Err(anyhow!("{} is outside {}-{}", part, min, max));
+        }
+        for value in (from..=to).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+impl Entry {
+    fn parse(spec: &str) -> Result<Entry> {
+        let fields: Vec<&str> = spec.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(anyhow!(
+                "{} should have five fields: minute hour day month weekday",
+                spec
+            ));
+        };
+        let field = |spec: &str, min, max, name: &str| {
+            parse_field(spec, min, max).with_context(|| format!("Invalid {} in {}", name, spec))
+        };
+        let mut weekdays = field(weekday, 0, 7, "day of week")?;
+        // 7 is Sunday too
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Ok(Entry {
+            minutes: field(minute, 0, 59, "minute")?,
+            hours: field(hour, 0, 23, "hour")?,
+            days: field(day, 1, 31, "day of month")?,
+            months: field(month, 1, 12, "month")?,
+            weekdays,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        })
+    }
+
+    // Whether the entry fires in the given minute since the unix epoch
+    fn matches(&self, minute: u64) -> bool {
+        let bit = |mask: u64, value: u64| mask & (1 << value) != 0;
+        let days = minute / (24 * 60);
+        let (_, month, day) = date_from_days(days as i64);
+        // The epoch was a Thursday
+        let weekday = (days + 4) % 7;
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (true, _) => bit(self.weekdays, weekday),
+            (false, true) => bit(self.days, day as u64),
+            (false, false) => bit(self.days, day as u64) || bit(self.weekdays, weekday),
+        };
+        bit(self.minutes, minute % 60)
+            && bit(self.hours, minute / 60 % 24)
+            && bit(self.months, month as u64)
+            && day_matches
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    entries: Vec<Entry>,
+}
+
+impl Schedule {
+    pub fn parse(spec: &str) -> Result<Schedule> {
+        let entries = spec
+            .split(';')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(Entry::parse)
+            .collect::<Result<Vec<_>>>()?;
+        if entries.is_empty() {
+            return Err(anyhow!("The schedule has no entries"));
+        }
+        Ok(Schedule { entries })
+    }
+
+    // The start of the next minute after the given time, in seconds since the unix epoch,
+    // that any entry fires in. None if none ever does, e.g. for Feb 30.
+    pub fn next_after(&self, secs: u64) -> Option<u64> {
+        let first = secs / 60 + 1;
+        (first..first + MAX_LOOKAHEAD_MINUTES)
+            .find(|&minute| self.entries.iter().any(|entry| entry.matches(minute)))
+            .map(|minute| minute * 60)
+    }
+}
+
+// How often each cached tile has been asked for, by cache key
+#[derive(Default)]
+pub struct PopularTiles {
+    counts: Mutex<HashMap<String, u64>>,
+}
+
+impl PopularTiles {
+    pub fn record(&self, key: &str) {
+        let mut counts = self.counts.lock().unwrap();
+        match counts.get_mut(key) {
+            Some(count) => *count += 1,
+            None if counts.len() < MAX_TRACKED_TILES => {
+                counts.insert(key.to_string(), 1);
+            }
+            None => {}
+        }
+    }
+
+    // Forgets every count, to free memory, returning how many tiles were counted
+    pub fn clear(&self) -> usize {
+        let mut counts = self.counts.lock().unwrap();
+        let cleared = counts.len();
+        *counts = HashMap::new();
+        cleared
+    }
+
+    // The n most asked for tiles, most popular first, halving every count
+    pub fn take_top(&self, n: usize) -> Vec<String> {
+        let mut counts = self.counts.lock().unwrap();
+        let mut ranked: Vec<(&String, &u64)> = counts.iter().collect();
+        ranked.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        let top = ranked
+            .into_iter()
+            .take(n)
+            .map(|(key, _)| key.clone())
+            .collect();
+        counts.retain(|_, count| {
+            *count /= 2;
+            *count > 0
+        });
+        top
+    }
+}
+
+pub struct CacheRefresher {
+    schedule: Schedule,
+    popular: Arc<PopularTiles>,
+    top: usize,
+    concurrency: usize,
+}
+
+impl CacheRefresher {
+    pub fn new(schedule: Schedule, top: usize, concurrency: usize) -> CacheRefresher {
+        CacheRefresher {
+            schedule,
+            popular: Arc::new(PopularTiles::default()),
+            top,
+            concurrency: concurrency.max(1),
+        }
+    }
+
+    // The refresher for REFRESH_SCHEDULE, if one is set
+    pub fn from_env() -> Result<Option<CacheRefresher>> {
+        let Ok(spec) = env::var("REFRESH_SCHEDULE") else {
+            return Ok(None);
+        };
+        if env::var("TILE_CACHE_URL").is_err() {
+            return Err(anyhow!(
+                "TILE_CACHE_URL must be set for there to be a cache to refresh"
+            ));
+        }
+        let schedule =
+            Schedule::parse(&spec).with_context(|| format!("Invalid REFRESH_SCHEDULE {}", spec))?;
+        let number = |name: &str, default: usize| -> Result<usize> {
+            env::var(name)
+                .ok()
+                .map(|n| n.parse().with_context(|| format!("Invalid {}", name)))
+                .transpose()
+                .map(|n| n.unwrap_or(default))
+        };
+        Ok(Some(CacheRefresher::new(
+            schedule,
+            number("REFRESH_TOP", DEFAULT_TOP)?,
+            number("REFRESH_CONCURRENCY", DEFAULT_CONCURRENCY)?,
+        )))
+    }
+
+    // Where the tile cache counts the tiles asked for
+    pub fn popular(&self) -> Arc<PopularTiles> {
+        self.popular.clone()
+    }
+
+    // Refreshes the most popular tiles now, returning how many were refreshed and how
+    // many failed
+    pub async fn refresh(&self, sources: &TileSources) -> (usize, usize) {
+        let tiles: Vec<CachedTile> = self
+            .popular
+            .take_top(self.top)
+            .iter()
+            .filter_map(|key| CachedTile::parse(key))
+            .collect();
+        let cx = Context::current();
+        let results: Vec<Result<()>> = stream::iter(&tiles)
+            .map(|tile| sources.refresh(tile, cx.clone()))
+            .buffer_unordered(self.concurrency)
+            .collect()
+            .await;
+        let failures: Vec<anyhow::Error> = results.into_iter().filter_map(Result::err).collect();
+        if let Some(first) = failures.first() {
+            warn!(
+                "{} of {} tiles couldn't be refreshed, e.g. {:#}",
+                failures.len(),
+                tiles.len(),
+                first
+            );
+        }
+        (tiles.len() - failures.len(), failures.len())
+    }
+
+    // Refreshes on the schedule, for as long as the service runs
+    pub async fn run(&self, sources: &TileSources) {
+        loop {
+            let now = unix_secs();
+            let Some(next) = self.schedule.next_after(now) else {
+                warn!("REFRESH_SCHEDULE never fires; not refreshing the tile cache");
+                return;
+            };
+            actix_web::rt::time::sleep(Duration::from_secs(next - now)).await;
+
+            let started = Instant::now();
+            let (refreshed, failed) = self.refresh(sources).await;
+            info!(
+                "Refreshed {} popular tiles in {:.1}s, {} failed",
+                refreshed,
+                started.elapsed().as_secs_f64(),
+                failed
+            );
+        }
+    }
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::ResultStore;
+    use crate::tile_cache::StoreCache;
+    use tile_render::cache::fetch_through;
+    use tile_render::debug_tiles::debug_tile;
+    use tile_render::fetcher::MemoryFetcher;
+    use tile_render::tiles::{encode_png, TileSet};
+
+    // 2024-03-02, a Saturday, at 00:00 UTC
+    const SATURDAY: u64 = 1_709_337_600;
+
+    #[test]
+    fn test_schedule() {
+        let daily = Schedule::parse("0 3 * * *").unwrap();
+        assert_eq!(daily.next_after(SATURDAY), Some(SATURDAY + 3 * 3600));
+        assert_eq!(
+            daily.next_after(SATURDAY + 3 * 3600),
+            Some(SATURDAY + 27 * 3600)
+        );
+
+        // Weekdays only, so Saturday morning waits for Monday
+        let weekdays = Schedule::parse("*/30 2-4 * * 1-5; 15 22 1 * *").unwrap();
+        assert_eq!(
+            weekdays.next_after(SATURDAY),
+            Some(SATURDAY + (2 * 24 + 2) * 3600)
+        );
+        // Day of month and day of week both restricted: either will do
+        let either = Schedule::parse("0 0 1 * 0").unwrap();
+        assert_eq!(either.next_after(SATURDAY), Some(SATURDAY + 24 * 3600));
+
+        assert_eq!(
+            Schedule::parse("0 0 30 2 *").unwrap().next_after(SATURDAY),
+            None
+        );
+        for invalid in [
+            "",
+            "0 3 * *",
+            "60 * * * *",
+            "* * * 13 *",
+            "*/0 * * * *",
+            "a * * * *",
+        ] {
+            assert!(Schedule::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_popular_tiles() {
+        let popular = PopularTiles::default();
+        for key in ["a", "b", "b", "c", "c", "c"] {
+            popular.record(key);
+        }
+        assert_eq!(popular.take_top(2), vec!["c", "b"]);
+        // Halved, so "a" is gone and "c" and "b" are tied
+        assert_eq!(popular.take_top(5), vec!["b", "c"]);
+        assert!(popular.take_top(5).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cache_counts_popular_tiles() {
+        let refresher = CacheRefresher::new(Schedule::parse("0 3 * * *").unwrap(), 10, 2);
+        let cache = StoreCache::new(ResultStore::from_url("memory://tiles").unwrap())
+            .with_popularity(refresher.popular());
+        let upstream = MemoryFetcher::default().with_fallback(encode_png(debug_tile(1, 2, 3)));
+        for (x, y) in [(1, 2), (1, 2), (4, 5)] {
+            fetch_through(&cache, &upstream, TileSet::Osm, x, y, 3, Context::new())
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            refresher.popular().take_top(1),
+            vec!["osm/3/1/2.png".to_string()]
+        );
+
+        // Without a cache there's nothing to refresh, and keys that aren't tiles are skipped
+        refresher.popular().record("osm/3/1/2.png");
+        refresher.popular().record("quarantine/osm/3/1/2.png");
+        assert_eq!(refresher.refresh(&TileSources::default()).await, (0, 1));
+    }
+}
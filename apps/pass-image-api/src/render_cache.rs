@@ -0,0 +1,238 @@
+// ! # render_cache
+// ! Rendered images kept in memory, so map UIs asking for the same map again and again
+// ! don't have it rendered each time. With RENDER_CACHE_BYTES set, single images from
+// ! GET and POST /images are cached by a hash of their canonical request: presets
+// ! expanded, coordinates rounded, layer settings sorted (see
+// ! tile_render::request::ImageRequest::canonicalize), and the languages and archive date
+// ! they render in. Requests that differ only in ways that don't change the image share
+// ! it, and those that are canonicalized render from the rounded coordinates themselves,
+// ! so a hit is the image a render would have made.
+// !
+// ! The least recently used images are dropped to keep the cache under its size, and the
+// ! whole cache is shed under memory pressure. Failed renders aren't cached. Degraded ones
+// ! are, with their degradations, which are reported again on every hit, as are the
+// ! tilesets that served them. Requests for a
+// ! report or for several sizes are always rendered. Lookups are counted in the
+// ! render_cache_lookups metric, by result.
+
+use crate::storage::content_hash;
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use opentelemetry::{global, KeyValue};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::future::Future;
+use std::sync::Mutex;
+use tile_render::degradations::{self, Collected};
+use tile_render::request::ImageRequest;
+
+struct Entry {
+    image: Bytes,
+    collected: Collected,
+    used: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    images: HashMap<String, Entry>,
+    // Keys by when they were last used, oldest first
+    recency: BTreeMap<u64, String>,
+    clock: u64,
+    bytes: usize,
+}
+
+pub struct RenderCache {
+    max_bytes: usize,
+    entries: Mutex<Entries>,
+}
+
+fn record_lookup(result: &'static str) {
+    let meter = global::meter("render_cache_meter");
+    let lookups = meter.u64_counter("render_cache_lookups").init();
+    lookups.add(1, &[KeyValue::new("result", result)]);
+}
+
+impl RenderCache {
+    pub fn new(max_bytes: usize) -> RenderCache {
+        RenderCache {
+            max_bytes,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    // The cache for RENDER_CACHE_BYTES, if it's set
+    pub fn from_env() -> Result<Option<RenderCache>> {
+        let Ok(max_bytes) = env::var("RENDER_CACHE_BYTES") else {
+            return Ok(None);
+        };
+        let max_bytes = max_bytes
+            .parse::<usize>()
+            .ok()
+            .filter(|&b| b > 0)
+            .ok_or_else(|| anyhow!("Invalid RENDER_CACHE_BYTES {}", max_bytes))?;
+        Ok(Some(RenderCache::new(max_bytes)))
+    }
+
+    fn get(&self, key: &str) -> Option<(Bytes, Collected)> {
+        let mut entries = self.entries.lock().unwrap();
+        let Entries {
+            images,
+            recency,
+            clock,
+            ..
+        } = &mut *entries;
+        let entry = images.get_mut(key)?;
+        *clock += 1;
+        recency.remove(&entry.used);
+        recency.insert(*clock, key.to_string());
+        entry.used = *clock;
+        Some((entry.image.clone(), entry.collected.clone()))
+    }
+
+    fn insert(&self, key: String, image: Bytes, collected: Collected) {
+        if image.len() > self.max_bytes {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.clock += 1;
+        let used = entries.clock;
+        entries.bytes += image.len();
+        entries.recency.insert(used, key.clone());
+        let entry = Entry {
+            image,
+            collected,
+            used,
+        };
+        if let Some(old) = entries.images.insert(key, entry) {
+            entries.recency.remove(&old.used);
+            entries.bytes -= old.image.len();
+        }
+        while entries.bytes > self.max_bytes {
+            let Some((_, oldest)) = entries.recency.pop_first() else {
+                break;
+            };
+            if let Some(old) = entries.images.remove(&oldest) {
+                entries.bytes -= old.image.len();
+            }
+        }
+    }
+
+    // Drops every image, returning how many bytes they took
+    pub fn clear(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        std::mem::take(&mut *entries).bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().images.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Renders a canonicalized request, or takes its image from the cache if there is one and
+// it has it
+pub async fn render<F>(
+    cache: Option<&RenderCache>,
+    request: &ImageRequest,
+    render: F,
+) -> anyhow::Result<Bytes>
+where
+    F: Future<Output = anyhow::Result<Bytes>>,
+{
+    let Some(cache) = cache else {
+        return render.await;
+    };
+    let key = content_hash(request.cache_key().as_bytes());
+    if let Some((image, collected)) = cache.get(&key) {
+        record_lookup("hit");
+        collected.replay();
+        return Ok(image);
+    }
+    record_lookup("miss");
+    let (rendered, collected) = degradations::collect(render).await;
+    collected.clone().replay();
+    if let Ok(image) = &rendered {
+        cache.insert(key, image.clone(), collected);
+    }
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tile_render::degradations::Degradation;
+
+    fn request(long: f64) -> ImageRequest {
+        let mut request: ImageRequest =
+            serde_json::from_value(serde_json::json!({"long": long, "lat": 46.6, "size_px": 64}))
+                .unwrap();
+        request.canonicalize();
+        request
+    }
+
+    #[actix_web::test]
+    async fn test_render() {
+        let cache = RenderCache::new(10);
+        let image = |body: &'static [u8]| async move { Ok(Bytes::from_static(body)) };
+
+        // A near-identical request is a hit, with the first render's degradations and
+        // sources
+        let degraded = async {
+            degradations::record(Degradation::ZoomClamped { zoom: 21 });
+            degradations::record_source("osm");
+            Ok(Bytes::from_static(b"first"))
+        };
+        let rendered = render(Some(&cache), &request(8.1), degraded).await;
+        assert_eq!(rendered.unwrap(), "first");
+        let (rendered, collected) = degradations::collect(render(
+            Some(&cache),
+            &request(8.1000000001),
+            image(b"again"),
+        ))
+        .await;
+        assert_eq!(rendered.unwrap(), "first");
+        assert_eq!(
+            collected.degradations,
+            vec![Degradation::ZoomClamped { zoom: 21 }]
+        );
+        assert_eq!(collected.sources, vec!["osm"]);
+
+        // Failures aren't cached, and the least recently used image goes to make room
+        let failed = async { Err(anyhow!("upstream down")) };
+        assert!(render(Some(&cache), &request(8.2), failed).await.is_err());
+        assert_eq!(cache.len(), 1);
+        render(Some(&cache), &request(8.2), image(b"second"))
+            .await
+            .unwrap();
+        render(Some(&cache), &request(8.1), image(b"again"))
+            .await
+            .unwrap();
+        render(Some(&cache), &request(8.3), image(b"third"))
+            .await
+            .unwrap();
+        assert_eq!(
+            render(Some(&cache), &request(8.2), image(b"fourth"))
+                .await
+                .unwrap(),
+            "fourth"
+        );
+        assert_eq!(
+            render(Some(&cache), &request(8.3), image(b"fifth"))
+                .await
+                .unwrap(),
+            "third"
+        );
+
+        assert_eq!(cache.clear(), 10);
+        assert!(cache.is_empty());
+        assert_eq!(
+            render(None, &request(8.1), image(b"uncached"))
+                .await
+                .unwrap(),
+            "uncached"
+        );
+    }
+}
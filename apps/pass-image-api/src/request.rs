@@ -0,0 +1,291 @@
+// ! # request
+// ! The HTTP side of render requests. RenderParams and ImageRequest live in tile-render,
+// ! shared with pass-image-cli; here bodies are checked against the BodyLimits before
+// ! they're parsed, since they can carry GeoJSON, and invalid requests become 400s.
+// ! ?preset= fills in whatever a request leaves out from a named preset.
+// !
+// ! An overlay can also be a GPX document, given as a string, e.g. a track exported from a
+// ! GPS watch. Its track segments and routes are read into GeoJSON lines, and their points
+// ! count against the same vertex limit as GeoJSON positions.
+
+use crate::limits::BodyLimits;
+use actix_web::error::{ErrorBadRequest, ErrorPayloadTooLarge};
+use actix_web::{Error, HttpResponse};
+use anyhow::anyhow;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use tile_render::coverage::OutsideCoverage;
+use tile_render::presets::{self, Preset};
+pub use tile_render::request::{ImageRequest, RenderParams};
+use tile_render::tiles::MAX_VARIANTS;
+
+// Checks a raw body against the limits and parses it
+pub fn parse_image_request(body: &[u8], limits: &BodyLimits) -> Result<ImageRequest, Error> {
+    let mut request = parse_body(body, limits)?;
+    read_gpx_overlay(&mut request, limits)?;
+    Ok(request)
+}
+
+// As parse_image_request, for bodies that wrap an ImageRequest with more fields
+pub fn parse_body<T: DeserializeOwned>(body: &[u8], limits: &BodyLimits) -> Result<T, Error> {
+    limits.check(body).map_err(ErrorPayloadTooLarge)?;
+    serde_json::from_slice(body).map_err(|e| ErrorBadRequest(e.to_string()))
+}
+
+// As parse_image_request, with the fields the body leaves out filled in from a preset
+pub fn parse_preset_request(
+    body: &[u8],
+    limits: &BodyLimits,
+    preset: &Preset,
+) -> Result<ImageRequest, Error> {
+    let mut body: Map<String, Value> = parse_body(body, limits)?;
+    preset.apply_to_body(&mut body);
+    let mut request =
+        serde_json::from_value(Value::Object(body)).map_err(|e| ErrorBadRequest(e.to_string()))?;
+    read_gpx_overlay(&mut request, limits)?;
+    Ok(request)
+}
+
+// Replaces an overlay given as a GPX document with the GeoJSON it describes. The body's
+// scan only sees GPX points written out as tags, so they're counted again as they're read.
+pub fn read_gpx_overlay(request: &mut ImageRequest, limits: &BodyLimits) -> Result<(), Error> {
+    if let Some(Value::String(gpx)) = &request.overlay {
+        request.overlay = Some(gpx_to_geojson(gpx, limits.max_vertices)?);
+    }
+    Ok(())
+}
+
+// A MultiLineString with a line for each of a GPX document's track segments and routes.
+// Waypoints and points outside a segment or route aren't drawn.
+fn gpx_to_geojson(gpx: &str, max_vertices: usize) -> Result<Value, Error> {
+    let invalid = |e: &dyn std::fmt::Display| ErrorBadRequest(format!("Invalid GPX: {}", e));
+    let mut reader = Reader::from_str(gpx);
+    let mut lines: Vec<Vec<[f64; 2]>> = Vec::new();
+    let mut line: Option<Vec<[f64; 2]>> = None;
+    let mut vertices = 0;
+    loop {
+        match reader.read_event().map_err(|e| invalid(&e))? {
+            Event::Start(e) if matches!(e.local_name().as_ref(), b"trkseg" | b"rte") => {
+                line = Some(Vec::new());
+            }
+            Event::Start(e) | Event::Empty(e)
+                if matches!(e.local_name().as_ref(), b"trkpt" | b"rtept") =>
+            {
+                vertices += 1;
+                if vertices > max_vertices {
+                    return Err(ErrorPayloadTooLarge(format!(
+                        "GPX with more than {} points exceeds the vertex limit",
+                        max_vertices
+                    )));
+                }
+                let coordinate = |name: &str| -> Option<f64> {
+                    let value = e.try_get_attribute(name).ok()??;
+                    value.unescape_value().ok()?.trim().parse().ok()
+                };
+                let (Some(lat), Some(lon)) = (coordinate("lat"), coordinate("lon")) else {
+                    return Err(invalid(&"every point needs a lat and lon"));
+                };
+                if let Some(line) = &mut line {
+                    line.push([lon, lat]);
+                }
+            }
+            Event::End(e) if matches!(e.local_name().as_ref(), b"trkseg" | b"rte") => {
+                lines.extend(line.take().filter(|line| line.len() >= 2));
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    if lines.is_empty() {
+        return Err(invalid(&"no track or route with two or more points"));
+    }
+    Ok(json!({"type": "MultiLineString", "coordinates": lines}))
+}
+
+// Fills in the query parameters a request leaves out from its ?preset=, if it names one
+pub fn apply_preset(query: &mut HashMap<String, String>) -> Result<Option<&'static Preset>, Error> {
+    let Some(name) = query.get("preset") else {
+        return Ok(None);
+    };
+    let preset = presets::get(name).map_err(bad_request)?;
+    preset.apply_to_query(query);
+    Ok(Some(preset))
+}
+
+// The RenderParams in a query, once a preset has been applied to it
+pub fn query_params(query: &HashMap<String, String>) -> Result<RenderParams, Error> {
+    serde_urlencoded::to_string(query)
+        .map_err(|e| ErrorBadRequest(e.to_string()))
+        .and_then(|query| {
+            serde_urlencoded::from_str(&query).map_err(|e| ErrorBadRequest(e.to_string()))
+        })
+}
+
+// Invalid render parameters are a 400
+pub fn bad_request(e: anyhow::Error) -> Error {
+    ErrorBadRequest(e.to_string())
+}
+
+// A failed render is a 500, unless it asked for an area its tileset doesn't cover
+pub fn render_failed(e: anyhow::Error) -> HttpResponse {
+    match e.downcast_ref::<OutsideCoverage>() {
+        Some(outside) => HttpResponse::BadRequest().body(outside.to_string()),
+        None => HttpResponse::InternalServerError().into(),
+    }
+}
+
+// The sizes to render for ?sizes=, e.g. 512,256: the requested size first, then the rest
+// in the order given, without repeats
+pub fn parse_sizes(size_px: u32, sizes: &str) -> anyhow::Result<Vec<u32>> {
+    let mut all = vec![size_px];
+    for size in sizes.split(',') {
+        let size: u32 = size
+            .trim()
+            .parse()
+            .ok()
+            .filter(|&s| s > 0)
+            .ok_or_else(|| anyhow!("Invalid size {} in sizes", size))?;
+        if !all.contains(&size) {
+            all.push(size);
+        }
+    }
+    if all.len() > MAX_VARIANTS {
+        return Err(anyhow!(
+            "At most {} sizes can be rendered at once",
+            MAX_VARIANTS
+        ));
+    }
+    Ok(all)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: BodyLimits = BodyLimits {
+        max_body_bytes: 1024,
+        max_vertices: 2,
+    };
+
+    #[test]
+    fn test_parse() {
+        let request = parse_image_request(
+            br#"{"long": 8.1, "lat": 46.6, "size_px": 512,
+                 "overlay": {"type": "LineString", "coordinates": [[8.1, 46.6], [8.2, 46.7]]}}"#,
+            &LIMITS,
+        )
+        .unwrap();
+        assert_eq!(request.render_options().unwrap().overlays.len(), 1);
+
+        let request = parse_image_request(
+            br#"{"long": 8.1, "lat": 46.6, "size_px": 512, "filter": "neon"}"#,
+            &LIMITS,
+        )
+        .unwrap();
+        let err = request.render_options().map_err(bad_request).unwrap_err();
+        assert_eq!(
+            err.error_response().status(),
+            actix_web::http::StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn test_apply_preset() {
+        let mut query = HashMap::from([
+            ("preset".to_string(), "hero".to_string()),
+            ("radius".to_string(), "2.5".to_string()),
+        ]);
+        let preset = apply_preset(&mut query).unwrap().unwrap();
+        assert_eq!(preset.size_px(), Some(1600));
+        assert_eq!(query["radius"], "2.5");
+        assert_eq!(query_params(&query).unwrap().scale_bar, Some(true));
+
+        let request = parse_preset_request(
+            br#"{"long": 8.1, "lat": 46.6, "radius": 2.5}"#,
+            &LIMITS,
+            preset,
+        )
+        .unwrap();
+        assert_eq!((request.size_px, request.radius), (1600, 2.5));
+
+        assert!(apply_preset(&mut HashMap::new()).unwrap().is_none());
+        let mut unknown = HashMap::from([("preset".to_string(), "poster".to_string())]);
+        assert!(apply_preset(&mut unknown).is_err());
+    }
+
+    #[test]
+    fn test_parse_sizes() {
+        assert_eq!(parse_sizes(1024, "512, 256").unwrap(), vec![1024, 512, 256]);
+        assert_eq!(
+            parse_sizes(512, "1024,512,256").unwrap(),
+            vec![512, 1024, 256]
+        );
+        assert!(parse_sizes(512, "256,0").is_err());
+        assert!(parse_sizes(512, "256,big").is_err());
+        assert!(parse_sizes(512, "1,2,3,4,5,6,7,8").is_err());
+    }
+
+    #[test]
+    fn test_parse_gpx() {
+        let gpx = r#"<?xml version="1.0"?>
+<gpx version="1.1" xmlns="http://www.topografix.com/GPX/1/1">
+  <trk><name>Grosse Scheidegg</name><trkseg>
+    <trkpt lat="46.65" lon="8.09"><ele>1900</ele></trkpt>
+    <trkpt lat="46.66" lon="8.11"/>
+  </trkseg></trk>
+</gpx>"#;
+        let body = json!({"long": 8.1, "lat": 46.6, "size_px": 512, "overlay": gpx});
+        let request = parse_image_request(body.to_string().as_bytes(), &LIMITS).unwrap();
+        assert_eq!(
+            request.overlay.unwrap(),
+            json!({"type": "MultiLineString", "coordinates": [[[8.09, 46.65], [8.11, 46.66]]]})
+        );
+
+        // Routes are lines too, and their points count against the vertex limit
+        let route = r#"<gpx><rte><rtept lat="46.6" lon="8.0"/><rtept lat="46.7" lon="8.2"/>
+            <rtept lat="46.8" lon="8.3"/></rte></gpx>"#;
+        let error = gpx_to_geojson(route, 2).unwrap_err();
+        assert_eq!(
+            error.error_response().status(),
+            actix_web::http::StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert!(gpx_to_geojson(route, 3).is_ok());
+
+        // Malformed documents, points without coordinates and empty tracks are 400s
+        for gpx in [
+            "<gpx><trk></gpx>",
+            r#"<gpx><trk><trkseg><trkpt lat="x"/></trkseg></trk></gpx>"#,
+            "<gpx></gpx>",
+        ] {
+            let error = gpx_to_geojson(gpx, 10).unwrap_err();
+            assert_eq!(
+                error.error_response().status(),
+                actix_web::http::StatusCode::BAD_REQUEST
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_rejects_oversized_geometry() {
+        let result = parse_image_request(
+            br#"{"long": 8.1, "lat": 46.6, "size_px": 512,
+                 "overlay": {"type": "MultiPoint", "coordinates": [[1, 2], [3, 4], [5, 6]]}}"#,
+            &LIMITS,
+        );
+        assert!(result.is_err());
+
+        // GPX points are scanned for before the body is parsed, like positions
+        let gpx = "<gpx><trk><trkseg><trkpt lat='1' lon='2'/><trkpt lat='3' lon='4'/>\
+                   <trkpt lat='5' lon='6'/></trkseg></trk></gpx>";
+        let body = json!({"long": 8.1, "lat": 46.6, "size_px": 512, "overlay": gpx});
+        let error = parse_image_request(body.to_string().as_bytes(), &LIMITS).unwrap_err();
+        assert_eq!(
+            error.error_response().status(),
+            actix_web::http::StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert!(LIMITS.check(body.to_string().as_bytes()).is_err());
+    }
+}
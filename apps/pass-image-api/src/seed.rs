@@ -0,0 +1,243 @@
+// ! # seed
+// ! Warms the tile cache ahead of time, e.g. from a job in the GitOps pipeline so a new
+// ! environment doesn't start cold. `pass-image-api seed` walks the tiles covering
+// ! SEED_BBOX (west,south,east,north in degrees) at every zoom from SEED_MIN_ZOOM to
+// ! SEED_MAX_ZOOM, fetching each through the tile sources so it lands in the cache at
+// ! TILE_CACHE_URL. Tiles already in the cache aren't fetched again.
+// !
+// ! Tiles are seeded in batches. After each one the progress is logged and written to the
+// ! SEED_CHECKPOINT file, and if the seed is interrupted or a batch fails, running it again
+// ! with the same settings picks up after the last batch that finished.
+
+use crate::export::Pyramid;
+use anyhow::{anyhow, Context as _, Result};
+use futures::{stream, StreamExt};
+use log::{info, warn};
+use opentelemetry::Context;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tile_render::fetcher::TileFetcher;
+use tile_render::tiles::TileSet;
+
+const DEFAULT_CHECKPOINT: &str = "seed-checkpoint.json";
+const DEFAULT_CONCURRENCY: usize = 8;
+
+// How many tiles are seeded between checkpoints
+const BATCH_SIZE: usize = 256;
+
+// What to seed. A checkpoint only applies to the spec it was written for.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SeedSpec {
+    pub bbox: [f64; 4],
+    pub min_zoom: u32,
+    pub max_zoom: u32,
+    pub tileset: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Checkpoint {
+    spec: SeedSpec,
+    // How many tiles, in the pyramid's order, have been seeded
+    done: u64,
+    total: u64,
+    updated_at: u64,
+}
+
+// How many tiles a checkpoint says are done already, if it's for the same spec
+fn resume_from(path: &Path, spec: &SeedSpec) -> Result<u64> {
+    let json = match fs::read(path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+    };
+    let checkpoint: Checkpoint = serde_json::from_slice(&json)
+        .with_context(|| format!("parsing checkpoint {}", path.display()))?;
+    if checkpoint.spec == *spec {
+        Ok(checkpoint.done)
+    } else {
+        warn!(
+            "Checkpoint {} is for a different seed; starting over",
+            path.display()
+        );
+        Ok(0)
+    }
+}
+
+// Written to a temporary file and renamed into place, so a crash can't leave half a
+// checkpoint behind
+fn save_checkpoint(path: &Path, checkpoint: &Checkpoint) -> Result<()> {
+    let partial = path.with_extension("partial");
+    fs::write(&partial, serde_json::to_vec_pretty(checkpoint)?)
+        .with_context(|| format!("writing {}", partial.display()))?;
+    fs::rename(&partial, path).with_context(|| format!("writing {}", path.display()))?;
+    Ok(())
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Fetches every tile in the spec, resuming from and updating the checkpoint. Returns how
+// many tiles have been seeded, which is all of them unless there was an error.
+pub async fn seed(
+    fetcher: &dyn TileFetcher,
+    spec: &SeedSpec,
+    checkpoint: &Path,
+    concurrency: usize,
+) -> Result<u64> {
+    let tileset = TileSet::lookup(&spec.tileset)
+        .ok_or_else(|| anyhow!("Unknown tileset {}", spec.tileset))?;
+    let pyramid = Pyramid::new(spec.bbox, spec.min_zoom, spec.max_zoom)?;
+    let total = pyramid.tile_count();
+    let mut done = resume_from(checkpoint, spec)?.min(total);
+    if done > 0 {
+        info!("Resuming seed after {} of {} tiles", done, total);
+    }
+
+    let started = Instant::now();
+    let resumed_at = done;
+    let cx = Context::current();
+    let mut remaining = pyramid.tiles().skip(done as usize);
+    loop {
+        let batch: Vec<(u32, u32, u32)> = remaining.by_ref().take(BATCH_SIZE).collect();
+        if batch.is_empty() {
+            break;
+        }
+        let results: Vec<Result<_>> = stream::iter(&batch)
+            .map(|&(z, x, y)| fetcher.fetch(tileset, x, y, z, cx.clone()))
+            .buffer_unordered(concurrency.max(1))
+            .collect()
+            .await;
+        let failures: Vec<anyhow::Error> = results.into_iter().filter_map(Result::err).collect();
+        if let Some(first) = failures.first() {
+            return Err(anyhow!(
+                "{} of {} tiles after tile {} failed, e.g. {:#}. Run the seed again to resume",
+                failures.len(),
+                batch.len(),
+                done,
+                first
+            ));
+        }
+
+        done += batch.len() as u64;
+        save_checkpoint(
+            checkpoint,
+            &Checkpoint {
+                spec: spec.clone(),
+                done,
+                total,
+                updated_at: unix_secs(),
+            },
+        )?;
+        let rate = (done - resumed_at) as f64 / started.elapsed().as_secs_f64().max(0.001);
+        info!(
+            "Seeded {} of {} tiles ({:.1}%, {:.0} tiles/s)",
+            done,
+            total,
+            100.0 * done as f64 / total as f64,
+            rate
+        );
+    }
+    Ok(done)
+}
+
+// Reads the SEED_* settings and seeds the cache
+pub async fn seed_from_env(fetcher: &dyn TileFetcher) -> Result<()> {
+    if env::var("TILE_CACHE_URL").is_err() {
+        return Err(anyhow!(
+            "TILE_CACHE_URL must be set for there to be a cache to seed"
+        ));
+    }
+    let bbox = env::var("SEED_BBOX").context("SEED_BBOX must be set to west,south,east,north")?;
+    let bbox: Vec<f64> = bbox
+        .split(',')
+        .map(|v| v.trim().parse())
+        .collect::<Result<_, _>>()
+        .with_context(|| format!("Invalid SEED_BBOX {}", bbox))?;
+    let bbox: [f64; 4] = bbox
+        .try_into()
+        .map_err(|_| anyhow!("SEED_BBOX must have four values: west,south,east,north"))?;
+    let zoom = |name: &str| -> Result<Option<u32>> {
+        env::var(name)
+            .ok()
+            .map(|z| z.parse().with_context(|| format!("Invalid {}", name)))
+            .transpose()
+    };
+    let spec = SeedSpec {
+        bbox,
+        min_zoom: zoom("SEED_MIN_ZOOM")?.unwrap_or(0),
+        max_zoom: zoom("SEED_MAX_ZOOM")?.context("SEED_MAX_ZOOM must be set")?,
+        tileset: env::var("SEED_TILESET").unwrap_or_else(|_| "osm".to_string()),
+    };
+    let checkpoint = env::var("SEED_CHECKPOINT").unwrap_or_else(|_| DEFAULT_CHECKPOINT.to_string());
+    let concurrency = env::var("SEED_CONCURRENCY")
+        .ok()
+        .and_then(|c| c.parse().ok())
+        .unwrap_or(DEFAULT_CONCURRENCY);
+
+    let seeded = seed(fetcher, &spec, Path::new(&checkpoint), concurrency).await?;
+    info!("Seeding finished: {} tiles are in the cache", seeded);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use tile_render::fetcher::MemoryFetcher;
+    use uuid::Uuid;
+
+    fn spec() -> SeedSpec {
+        SeedSpec {
+            bbox: [8.3, 46.5, 8.5, 46.6],
+            min_zoom: 8,
+            max_zoom: 15,
+            tileset: "osm".to_string(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_seed_resumes() {
+        let checkpoint = std::env::temp_dir().join(format!("seed-{}.json", Uuid::new_v4()));
+        let spec = spec();
+        let total = Pyramid::new(spec.bbox, spec.min_zoom, spec.max_zoom)
+            .unwrap()
+            .tile_count();
+        assert!(total > BATCH_SIZE as u64);
+
+        // Everything's missing, so the first batch fails and nothing is checkpointed
+        let offline = MemoryFetcher::default();
+        assert!(seed(&offline, &spec, &checkpoint, 4).await.is_err());
+        assert_eq!(resume_from(&checkpoint, &spec).unwrap(), 0);
+
+        // Pretend an earlier run got through the first batch
+        let first_batch = Checkpoint {
+            spec: spec.clone(),
+            done: BATCH_SIZE as u64,
+            total,
+            updated_at: 0,
+        };
+        save_checkpoint(&checkpoint, &first_batch).unwrap();
+        assert_eq!(resume_from(&checkpoint, &spec).unwrap(), BATCH_SIZE as u64);
+        let other = SeedSpec {
+            max_zoom: 14,
+            ..spec.clone()
+        };
+        assert_eq!(resume_from(&checkpoint, &other).unwrap(), 0);
+
+        let online = MemoryFetcher::default().with_fallback(Bytes::from_static(b"png"));
+        assert_eq!(seed(&online, &spec, &checkpoint, 4).await.unwrap(), total);
+        let saved: Checkpoint = serde_json::from_slice(&fs::read(&checkpoint).unwrap()).unwrap();
+        assert_eq!((saved.done, saved.total), (total, total));
+
+        // Running it again has nothing left to do
+        assert_eq!(seed(&offline, &spec, &checkpoint, 4).await.unwrap(), total);
+        fs::remove_file(&checkpoint).unwrap();
+    }
+}
@@ -0,0 +1,237 @@
+// ! # sign
+// ! POST /sign turns a render request into a long-lived signed GET /images URL, for
+// ! <img> tags in emails and wikis, which can't send an X-Api-Key header. The body is
+// ! {"long", "lat", "size_px", "query", "ttl_secs"}, where query holds the same
+// ! parameters GET /images takes, e.g. {"radius": 2, "tileset": "swisstopo"}. The URL is
+// ! good for ttl_secs, 30 days unless asked otherwise and a year at most, and its
+// ! signature covers every parameter, so none of them can be changed.
+// !
+// ! The URL doesn't carry the API key it was signed with, only a fingerprint of it (see
+// ! UrlSigner::fingerprint). The usage database remembers which key that stands for, so
+// ! renders through the URL are accounted against the key that signed it.
+
+use crate::limits::BodyLimits;
+use crate::request::{bad_request, parse_body, parse_sizes, RenderParams};
+use crate::signing::UrlSigner;
+use crate::usage::{self, UsageTracker, ANONYMOUS_KEY};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::{post, web, Error, HttpRequest, HttpResponse};
+use anyhow::anyhow;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::time::Duration;
+
+const DEFAULT_TTL_SECS: u64 = 30 * 24 * 60 * 60;
+const MAX_TTL_SECS: u64 = 365 * 24 * 60 * 60;
+
+// Parameters the signature itself uses, which a request can't set
+const RESERVED_PARAMS: &[&str] = &["key", "expires", "signature"];
+
+#[derive(Debug, Deserialize)]
+struct SignRequest {
+    long: f64,
+    lat: f64,
+    size_px: u32,
+    #[serde(default)]
+    query: Map<String, Value>,
+    ttl_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct SignedUrl {
+    url: String,
+}
+
+// The query parameters for a request's query object. Only scalars fit in a URL; overlays
+// and the like need POST /images.
+fn query_params(query: &Map<String, Value>) -> anyhow::Result<Vec<(String, String)>> {
+    query
+        .iter()
+        .map(|(name, value)| {
+            if RESERVED_PARAMS.contains(&name.as_str()) {
+                return Err(anyhow!("{} can't be signed", name));
+            }
+            let value = match value {
+                Value::String(s) => s.clone(),
+                Value::Number(n) => n.to_string(),
+                Value::Bool(b) => b.to_string(),
+                _ => return Err(anyhow!("{} doesn't fit in a URL; use POST /images", name)),
+            };
+            Ok((name.clone(), value))
+        })
+        .collect()
+}
+
+// Where GET /images is served for a point, including the prefix the API is mounted under
+fn image_path(req: &HttpRequest, long: f64, lat: f64, size_px: u32) -> String {
+    let segments = [long.to_string(), lat.to_string(), size_px.to_string()];
+    req.url_for("image", &segments)
+        .map(|url| url.path().to_string())
+        .unwrap_or_else(|_| format!("/images/{}/{}/{}", long, lat, size_px))
+}
+
+#[post("/sign")]
+async fn sign_image(
+    req: HttpRequest,
+    body: web::Bytes,
+    limits: web::Data<BodyLimits>,
+    signer: web::Data<UrlSigner>,
+    usage: web::Data<UsageTracker>,
+) -> Result<HttpResponse, Error> {
+    let spec: SignRequest = parse_body(&body, &limits)?;
+    let ttl = spec.ttl_secs.unwrap_or(DEFAULT_TTL_SECS);
+    if ttl == 0 || ttl > MAX_TTL_SECS {
+        return Err(bad_request(anyhow!(
+            "ttl_secs has to be between 1 and {}",
+            MAX_TTL_SECS
+        )));
+    }
+    let mut params = query_params(&spec.query).map_err(bad_request)?;
+
+    // Check the parameters now, rather than when the URL is first used
+    let query = serde_urlencoded::to_string(&params).map_err(|e| bad_request(e.into()))?;
+    let render_params: RenderParams =
+        serde_urlencoded::from_str(&query).map_err(|e| bad_request(e.into()))?;
+    render_params
+        .render_options(Vec::new(), None, None)
+        .map_err(bad_request)?;
+    if let Some((_, sizes)) = params.iter().find(|(name, _)| name == "sizes") {
+        parse_sizes(spec.size_px, sizes).map_err(bad_request)?;
+    }
+
+    let api_key = usage::api_key(&req);
+    if api_key != ANONYMOUS_KEY {
+        let fingerprint = signer.fingerprint(&api_key);
+        usage
+            .remember_key(&fingerprint, &api_key)
+            .await
+            .map_err(|e| ErrorInternalServerError(e.to_string()))?;
+        params.push(("key".to_string(), fingerprint));
+    }
+    let query = serde_urlencoded::to_string(&params).map_err(|e| bad_request(e.into()))?;
+    let path = image_path(&req, spec.long, spec.lat, spec.size_px);
+    let connection = req.connection_info();
+    let url = format!(
+        "{}://{}{}",
+        connection.scheme(),
+        connection.host(),
+        signer.sign_query(&path, &query, Duration::from_secs(ttl))
+    );
+    Ok(HttpResponse::Ok().json(SignedUrl { url }))
+}
+
+// The key a GET /images request is accounted against. A signed request goes by the key
+// that signed it, and is a 403 if its signature doesn't check out; any other request
+// goes by its X-Api-Key header.
+pub async fn request_api_key(
+    req: &HttpRequest,
+    signer: &UrlSigner,
+    usage: &UsageTracker,
+) -> Result<String, HttpResponse> {
+    let query = req.query_string();
+    let param = |name: &str| {
+        query
+            .split('&')
+            .find_map(|p| p.strip_prefix(name)?.strip_prefix('='))
+    };
+    if param("signature").is_none() {
+        return Ok(usage::api_key(req));
+    }
+    if let Err(e) = signer.verify_query(req.path(), query) {
+        return Err(HttpResponse::Forbidden().body(e.to_string()));
+    }
+    let Some(fingerprint) = param("key") else {
+        return Ok(ANONYMOUS_KEY.to_string());
+    };
+    match usage.key_for(fingerprint).await {
+        Ok(Some(api_key)) => Ok(api_key),
+        Ok(None) => Ok(ANONYMOUS_KEY.to_string()),
+        Err(e) => {
+            warn!("Couldn't look up the key for a signed URL: {}", e);
+            Ok(ANONYMOUS_KEY.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{get, test, App};
+    use rusqlite::Connection;
+    use serde_json::json;
+
+    // Stands in for GET /images, reporting the key a request is accounted against
+    #[get("/images/{long}/{lat}/{size_px}", name = "image")]
+    async fn accounted_key(
+        req: HttpRequest,
+        signer: web::Data<UrlSigner>,
+        usage: web::Data<UsageTracker>,
+    ) -> HttpResponse {
+        match request_api_key(&req, &signer, &usage).await {
+            Ok(api_key) => HttpResponse::Ok().body(api_key),
+            Err(response) => response,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_sign_image() {
+        let tracker = UsageTracker::new(Connection::open_in_memory().unwrap(), None, None);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(BodyLimits::from_env()))
+                .app_data(web::Data::new(UrlSigner::new(
+                    b"key".to_vec(),
+                    Duration::from_secs(60),
+                )))
+                .app_data(web::Data::new(tracker.unwrap()))
+                .service(sign_image)
+                .service(accounted_key),
+        )
+        .await;
+        let sign = |body: Value| {
+            test::TestRequest::post()
+                .uri("/sign")
+                .insert_header((usage::API_KEY_HEADER, "wiki-team"))
+                .set_json(body)
+                .to_request()
+        };
+
+        let body = json!({
+            "long": 8.1, "lat": 46.6, "size_px": 512,
+            "query": {"radius": 2.5, "tileset": "swisstopo", "exact": true},
+        });
+        let signed: Value = test::call_and_read_body_json(&app, sign(body)).await;
+        let url = signed["url"].as_str().unwrap();
+        let path = url.split_once("://").unwrap().1;
+        let path = &path[path.find('/').unwrap()..];
+        assert!(path.starts_with("/images/8.1/46.6/512?"));
+        assert!(path.contains("radius=2.5") && path.contains("tileset=swisstopo"));
+        assert!(!path.contains("wiki-team"));
+
+        // The signed URL is accounted against the key that signed it
+        let req = test::TestRequest::get().uri(path).to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert_eq!(body, "wiki-team");
+        let tampered = path.replace("radius=2.5", "radius=25");
+        let req = test::TestRequest::get().uri(&tampered).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 403);
+        // Unsigned requests go by their header
+        let req = test::TestRequest::get()
+            .uri("/images/8.1/46.6/512")
+            .to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, ANONYMOUS_KEY);
+
+        for invalid in [
+            json!({"long": 8.1, "lat": 46.6, "size_px": 512, "query": {"filter": "neon"}}),
+            json!({"long": 8.1, "lat": 46.6, "size_px": 512, "query": {"key": "x"}}),
+            json!({"long": 8.1, "lat": 46.6, "size_px": 512, "query": {"overlay": {}}}),
+            json!({"long": 8.1, "lat": 46.6, "size_px": 512, "ttl_secs": 0}),
+        ] {
+            let status = test::call_service(&app, sign(invalid.clone()))
+                .await
+                .status();
+            assert_eq!(status, 400, "{}", invalid);
+        }
+    }
+}
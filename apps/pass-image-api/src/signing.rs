@@ -0,0 +1,302 @@
+// ! # signing
+// ! HMAC-signed, expiring URLs. These let us hand a browser a link to a result without
+// ! giving it access to the rest of the API: the link is only good for the path it was
+// ! minted for, and only until it expires.
+// !
+// ! Webhook bodies are signed too, with a separate key shared with the receivers, so they
+// ! can tell a callback really came from us.
+
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use log::warn;
+use rand::RngCore;
+use sha2::Sha256;
+use std::env;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+// How long a signed URL is valid for if SIGNED_URL_TTL_SECS isn't set
+const DEFAULT_TTL_SECS: u64 = 3600;
+
+pub struct UrlSigner {
+    key: Vec<u8>,
+    ttl: Duration,
+}
+
+impl UrlSigner {
+    pub fn new(key: Vec<u8>, ttl: Duration) -> UrlSigner {
+        UrlSigner { key, ttl }
+    }
+
+    // Reads the signing key from URL_SIGNING_KEY. If there isn't one we make up a random
+    // key, which works fine for a single replica but means URLs die with the process.
+    pub fn from_env() -> UrlSigner {
+        let key = match env::var("URL_SIGNING_KEY") {
+            Ok(key) if !key.is_empty() => key.into_bytes(),
+            _ => {
+                warn!("URL_SIGNING_KEY not set; signed URLs won't survive a restart");
+                let mut key = vec![0u8; 32];
+                rand::thread_rng().fill_bytes(&mut key);
+                key
+            }
+        };
+
+        let ttl = env::var("SIGNED_URL_TTL_SECS")
+            .ok()
+            .and_then(|t| t.parse().ok())
+            .unwrap_or(DEFAULT_TTL_SECS);
+
+        UrlSigner::new(key, Duration::from_secs(ttl))
+    }
+
+    // Produces a signed version of the given path, valid for the configured TTL
+    pub fn sign(&self, path: &str) -> String {
+        let expires = now_secs() + self.ttl.as_secs();
+        format!(
+            "{}?expires={}&signature={}",
+            path,
+            expires,
+            self.signature(path, expires)
+        )
+    }
+
+    // Checks a signature produced by sign() for the given path
+    pub fn verify(&self, path: &str, expires: u64, signature: &str) -> Result<()> {
+        if expires < now_secs() {
+            return Err(anyhow!("Signed URL has expired"));
+        }
+
+        let signature = hex::decode(signature).map_err(|_| anyhow!("Malformed signature"))?;
+        self.mac(path, expires)
+            .verify_slice(&signature)
+            .map_err(|_| anyhow!("Invalid signature"))
+    }
+
+    // Produces a signed version of a path and its query string, valid for the given TTL
+    // rather than the configured one. The signature covers the query as well, so none of
+    // the parameters can be changed.
+    pub fn sign_query(&self, path: &str, query: &str, ttl: Duration) -> String {
+        let expires = now_secs() + ttl.as_secs();
+        let signed = match query {
+            "" => path.to_string(),
+            query => format!("{}?{}", path, query),
+        };
+        let separator = if query.is_empty() { '?' } else { '&' };
+        format!(
+            "{}{}expires={}&signature={}",
+            signed,
+            separator,
+            expires,
+            self.signature(&signed, expires)
+        )
+    }
+
+    // Checks a URL produced by sign_query, given its path and whole query string
+    pub fn verify_query(&self, path: &str, query: &str) -> Result<()> {
+        let mut expires = None;
+        let mut signature = None;
+        let mut params = Vec::new();
+        for param in query.split('&').filter(|p| !p.is_empty()) {
+            match param.split_once('=') {
+                Some(("expires", v)) => expires = v.parse::<u64>().ok(),
+                Some(("signature", v)) => signature = Some(v),
+                _ => params.push(param),
+            }
+        }
+        let (expires, signature) = expires
+            .zip(signature)
+            .ok_or_else(|| anyhow!("Malformed signature"))?;
+        let signed = match params.join("&") {
+            query if query.is_empty() => path.to_string(),
+            query => format!("{}?{}", path, query),
+        };
+        self.verify(&signed, expires, signature)
+    }
+
+    // A stand-in for an API key that can go in a URL: it identifies the key to anyone
+    // holding the signing key, and to no one else
+    pub fn fingerprint(&self, api_key: &str) -> String {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC takes keys of any size");
+        mac.update(b"api-key\n");
+        mac.update(api_key.as_bytes());
+        hex::encode(&mac.finalize().into_bytes()[..16])
+    }
+
+    fn signature(&self, path: &str, expires: u64) -> String {
+        hex::encode(self.mac(path, expires).finalize().into_bytes())
+    }
+
+    fn mac(&self, path: &str, expires: u64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC takes keys of any size");
+        mac.update(path.as_bytes());
+        mac.update(b"\n");
+        mac.update(expires.to_string().as_bytes());
+        mac
+    }
+}
+
+// Signs webhook bodies. The signature goes in a header as t=<unix time>,v1=<hex HMAC>,
+// where the HMAC covers "<unix time>.<body>", so receivers can also reject stale replays.
+pub struct WebhookSigner {
+    key: Vec<u8>,
+}
+
+impl WebhookSigner {
+    pub fn new(key: Vec<u8>) -> WebhookSigner {
+        WebhookSigner { key }
+    }
+
+    // Reads the key from WEBHOOK_SIGNING_KEY. Without one there's nothing receivers could
+    // check signatures against, so webhooks are turned off.
+    pub fn from_env() -> Option<WebhookSigner> {
+        env::var("WEBHOOK_SIGNING_KEY")
+            .ok()
+            .filter(|key| !key.is_empty())
+            .map(|key| WebhookSigner::new(key.into_bytes()))
+    }
+
+    // The signature header value for a body sent now
+    pub fn sign(&self, body: &[u8]) -> String {
+        let timestamp = now_secs();
+        format!(
+            "t={},v1={}",
+            timestamp,
+            hex::encode(self.mac(timestamp, body).finalize().into_bytes())
+        )
+    }
+
+    fn mac(&self, timestamp: u64, body: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC takes keys of any size");
+        mac.update(timestamp.to_string().as_bytes());
+        mac.update(b".");
+        mac.update(body);
+        mac
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signer() -> UrlSigner {
+        UrlSigner::new(b"test-key".to_vec(), Duration::from_secs(60))
+    }
+
+    impl WebhookSigner {
+        // Checks a signature header against a body, as a receiver would
+        pub fn verify(&self, header: &str, body: &[u8]) -> Result<()> {
+            let mut timestamp = None;
+            let mut signature = None;
+            for part in header.split(',') {
+                match part.split_once('=') {
+                    Some(("t", t)) => timestamp = t.parse::<u64>().ok(),
+                    Some(("v1", v)) => signature = hex::decode(v).ok(),
+                    _ => {}
+                }
+            }
+            let (timestamp, signature) = timestamp
+                .zip(signature)
+                .ok_or_else(|| anyhow!("Malformed signature"))?;
+            self.mac(timestamp, body)
+                .verify_slice(&signature)
+                .map_err(|_| anyhow!("Invalid signature"))
+        }
+    }
+
+    // Pulls expires and signature back out of a signed URL
+    fn split(signed: &str) -> (u64, String) {
+        let query = signed.split_once('?').unwrap().1;
+        let mut expires = 0;
+        let mut signature = String::new();
+        for pair in query.split('&') {
+            match pair.split_once('=').unwrap() {
+                ("expires", v) => expires = v.parse().unwrap(),
+                ("signature", v) => signature = v.to_string(),
+                _ => {}
+            }
+        }
+        (expires, signature)
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signer = signer();
+        let (expires, signature) = split(&signer.sign("/jobs/abc/result"));
+        assert!(signer
+            .verify("/jobs/abc/result", expires, &signature)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_verify_rejects_other_paths() {
+        let signer = signer();
+        let (expires, signature) = split(&signer.sign("/jobs/abc/result"));
+        assert!(signer
+            .verify("/jobs/def/result", expires, &signature)
+            .is_err());
+    }
+
+    #[test]
+    fn test_sign_and_verify_query() {
+        let signer = signer();
+        let signed = signer.sign_query(
+            "/images/8.1/46.6/512",
+            "radius=2&tileset=swisstopo",
+            Duration::from_secs(60),
+        );
+        let query = signed.split_once('?').unwrap().1;
+        assert!(signer.verify_query("/images/8.1/46.6/512", query).is_ok());
+        let tampered = query.replace("radius=2", "radius=20");
+        assert!(signer
+            .verify_query("/images/8.1/46.6/512", &tampered)
+            .is_err());
+        assert!(signer
+            .verify_query("/images/8.1/46.6/512", "radius=2")
+            .is_err());
+
+        let bare = signer.sign_query("/images/8.1/46.6/512", "", Duration::from_secs(60));
+        let query = bare.split_once('?').unwrap().1;
+        assert!(signer.verify_query("/images/8.1/46.6/512", query).is_ok());
+        assert_ne!(signer.fingerprint("key-a"), signer.fingerprint("key-b"));
+    }
+
+    #[test]
+    fn test_verify_rejects_tampered_expiry() {
+        let signer = signer();
+        let (expires, signature) = split(&signer.sign("/jobs/abc/result"));
+        assert!(signer
+            .verify("/jobs/abc/result", expires + 3600, &signature)
+            .is_err());
+    }
+
+    #[test]
+    fn test_verify_rejects_expired() {
+        let signer = signer();
+        let expires = now_secs() - 1;
+        let signature = signer.signature("/jobs/abc/result", expires);
+        assert!(signer
+            .verify("/jobs/abc/result", expires, &signature)
+            .is_err());
+    }
+
+    #[test]
+    fn test_webhook_signature() {
+        let signer = WebhookSigner::new(b"hook-key".to_vec());
+        let header = signer.sign(b"{\"id\": \"abc\"}");
+        assert!(header.starts_with("t="));
+        assert!(signer.verify(&header, b"{\"id\": \"abc\"}").is_ok());
+        assert!(signer.verify(&header, b"{\"id\": \"def\"}").is_err());
+        assert!(WebhookSigner::new(b"other-key".to_vec())
+            .verify(&header, b"{\"id\": \"abc\"}")
+            .is_err());
+        assert!(signer.verify("v1=00", b"").is_err());
+    }
+}
@@ -0,0 +1,231 @@
+// ! # sprites
+// ! Marker icons as a MapLibre sprite sheet: a PNG with every icon side by side and a JSON
+// ! index of where each one is. The icons are drawn by the same overlay code as markers on
+// ! rendered images, so interactive front ends and static renders look the same. The icon
+// ! set is configured with MARKER_ICONS, a comma separated list of name:rrggbb[:radius]
+// ! entries, e.g. pass:2850dc,summit:dc2828:8. Without it there's a single "marker" icon.
+// !
+// ! Sheets are served at /sprites/sprite.png and /sprites/sprite.json, with @2x and @3x
+// ! variants for high density screens.
+
+use actix_web::{get, http::header::ContentType, web, HttpResponse, Responder};
+use anyhow::{anyhow, Result};
+use image::{imageops, Rgba, RgbaImage};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::env;
+use tile_render::coordinates::pixel_to_lat_long;
+use tile_render::overlay::{self, parse_color, Overlay, Viewport};
+use tile_render::tiles;
+
+pub const MAX_ICON_RADIUS: f32 = 64.0;
+const MAX_PIXEL_RATIO: u32 = 3;
+
+// Room around each icon for its anti-aliased edge
+const PADDING_PX: u32 = 1;
+
+// Any zoom works for placing a single point; a high one keeps it precise
+const ICON_ZOOM: u32 = 20;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Icon {
+    pub name: String,
+    pub color: Rgba<u8>,
+    pub radius: f32,
+}
+
+// Where an icon is on the sheet, as MapLibre expects it
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SpriteEntry {
+    pub width: u32,
+    pub height: u32,
+    pub x: u32,
+    pub y: u32,
+    pub pixel_ratio: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IconSet {
+    icons: Vec<Icon>,
+}
+
+impl IconSet {
+    pub fn from_env() -> Result<IconSet> {
+        match env::var("MARKER_ICONS") {
+            Ok(spec) => IconSet::parse(&spec),
+            Err(_) => Ok(IconSet {
+                icons: vec![Icon {
+                    name: "marker".to_string(),
+                    color: overlay::DEFAULT_MARKER_COLOR,
+                    radius: overlay::DEFAULT_MARKER_RADIUS,
+                }],
+            }),
+        }
+    }
+
+    // Parses a list of name:rrggbb[:radius] entries
+    pub fn parse(spec: &str) -> Result<IconSet> {
+        let mut icons: Vec<Icon> = Vec::new();
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let mut parts = entry.split(':');
+            let name = parts.next().unwrap_or_default().to_string();
+            let color = parts
+                .next()
+                .and_then(|c| parse_color(&format!("#{}", c.trim_start_matches('#'))))
+                .ok_or_else(|| anyhow!("Marker icon {} needs a color", entry))?;
+            let radius = match parts.next() {
+                Some(r) => r
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|r| *r > 0.0 && *r <= MAX_ICON_RADIUS)
+                    .ok_or_else(|| anyhow!("Invalid radius for marker icon {}", name))?,
+                None => overlay::DEFAULT_MARKER_RADIUS,
+            };
+            if name.is_empty() || icons.iter().any(|i| i.name == name) {
+                return Err(anyhow!("Marker icon names must be unique and non-empty"));
+            }
+            icons.push(Icon {
+                name,
+                color,
+                radius,
+            });
+        }
+        if icons.is_empty() {
+            return Err(anyhow!("MARKER_ICONS has no icons"));
+        }
+        Ok(IconSet { icons })
+    }
+
+    // Lays the icons out in a row at the given pixel ratio
+    pub fn sheet(&self, pixel_ratio: u32) -> (RgbaImage, BTreeMap<String, SpriteEntry>) {
+        let rendered: Vec<RgbaImage> = self
+            .icons
+            .iter()
+            .map(|icon| draw_icon(icon, pixel_ratio))
+            .collect();
+        let width = rendered.iter().map(RgbaImage::width).sum::<u32>().max(1);
+        let height = rendered.iter().map(RgbaImage::height).max().unwrap_or(1);
+
+        let mut sheet = RgbaImage::new(width, height);
+        let mut index = BTreeMap::new();
+        let mut x = 0;
+        for (icon, image) in self.icons.iter().zip(&rendered) {
+            imageops::replace(&mut sheet, image, x as i64, 0);
+            index.insert(
+                icon.name.clone(),
+                SpriteEntry {
+                    width: image.width(),
+                    height: image.height(),
+                    x,
+                    y: 0,
+                    pixel_ratio,
+                },
+            );
+            x += image.width();
+        }
+        (sheet, index)
+    }
+}
+
+// Draws an icon as a marker overlay in the middle of a canvas just big enough for it
+fn draw_icon(icon: &Icon, pixel_ratio: u32) -> RgbaImage {
+    let radius = icon.radius * pixel_ratio as f32;
+    let size = (radius * 2.0).ceil() as u32 + 2 * PADDING_PX * pixel_ratio;
+    let center = size as f64 / 2.0;
+
+    let mut canvas = RgbaImage::new(size, size);
+    let viewport = Viewport {
+        zoom: ICON_ZOOM,
+        origin: (0.0, 0.0),
+        scale: 1.0,
+    };
+    let marker = Overlay::Point {
+        point: pixel_to_lat_long(center, center, ICON_ZOOM),
+        color: icon.color,
+        radius,
+        label: None,
+        symbol: None,
+    };
+    overlay::draw_overlays_supersampled(
+        &mut canvas,
+        &viewport,
+        &[marker],
+        overlay::MAX_SUPERSAMPLE,
+    );
+    canvas
+}
+
+// Splits a sprite file name like sprite@2x.png into its pixel ratio and extension
+fn parse_file_name(name: &str) -> Option<(u32, &str)> {
+    let (stem, extension) = name.rsplit_once('.')?;
+    let ratio = match stem.strip_prefix("sprite")? {
+        "" => 1,
+        suffix => suffix
+            .strip_prefix('@')?
+            .strip_suffix('x')?
+            .parse()
+            .ok()
+            .filter(|r| (1..=MAX_PIXEL_RATIO).contains(r))?,
+    };
+    Some((ratio, extension))
+}
+
+#[get("/sprites/{file}")]
+async fn get_sprite(path: web::Path<String>, icons: web::Data<IconSet>) -> impl Responder {
+    let Some((ratio, extension)) = parse_file_name(&path) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let (sheet, index) = icons.sheet(ratio);
+    match extension {
+        "png" => HttpResponse::Ok()
+            .content_type(ContentType::png())
+            .body(tiles::encode_png(sheet)),
+        "json" => HttpResponse::Ok().json(index),
+        _ => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let icons = IconSet::parse("pass:2850dc, summit:#dc2828:8").unwrap();
+        assert_eq!(icons.icons.len(), 2);
+        assert_eq!(icons.icons[0].radius, overlay::DEFAULT_MARKER_RADIUS);
+        assert_eq!(icons.icons[1].color, Rgba([220, 40, 40, 255]));
+        assert_eq!(icons.icons[1].radius, 8.0);
+
+        assert!(IconSet::parse("pass").is_err());
+        assert!(IconSet::parse("pass:2850dc,pass:dc2828").is_err());
+        assert!(IconSet::parse("pass:2850dc:500").is_err());
+        assert!(IconSet::parse("").is_err());
+    }
+
+    #[test]
+    fn test_parse_file_name() {
+        assert_eq!(parse_file_name("sprite.png"), Some((1, "png")));
+        assert_eq!(parse_file_name("sprite@2x.json"), Some((2, "json")));
+        assert_eq!(parse_file_name("sprite@9x.png"), None);
+        assert_eq!(parse_file_name("icons.png"), None);
+    }
+
+    #[test]
+    fn test_sheet_layout() {
+        let icons = IconSet::parse("pass:2850dc,summit:dc2828:8").unwrap();
+        let (sheet, index) = icons.sheet(2);
+
+        let pass = &index["pass"];
+        let summit = &index["summit"];
+        assert_eq!((pass.x, pass.width, pass.pixel_ratio), (0, 28, 2));
+        assert_eq!((summit.x, summit.width), (28, 36));
+        assert_eq!(sheet.dimensions(), (64, 36));
+
+        // Each icon is a filled marker centered in its cell
+        assert_eq!(sheet.get_pixel(14, 14), &Rgba([40, 80, 220, 255]));
+        assert_eq!(sheet.get_pixel(46, 18), &Rgba([220, 40, 40, 255]));
+        assert_eq!(sheet.get_pixel(0, 0)[3], 0);
+    }
+}
@@ -0,0 +1,251 @@
+// ! # storage
+// ! Object storage for rendered images and cached tiles. A store is configured with a URL:
+// ! s3://bucket/prefix, with credentials and region from the usual AWS_* variables,
+// ! file:///path for a local directory, or memory:// for tests. S3 stores can also hand out
+// ! presigned URLs, so clients can fetch objects straight from the bucket.
+// !
+// ! Rendered images are stored under the SHA-256 of their bytes, so requests that render
+// ! byte-identical images, e.g. coordinates a hair apart that land on the same pixels,
+// ! share one object instead of each leaving a copy behind.
+
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use object_store::aws::{AmazonS3, AmazonS3Builder};
+use object_store::local::LocalFileSystem;
+use object_store::memory::InMemory;
+use object_store::path::Path;
+use object_store::signer::Signer;
+use object_store::ObjectStore;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::time::Duration;
+use tile_render::avif;
+
+// The hex SHA-256 of some bytes, which rendered images are stored and tagged by
+pub fn content_hash(body: &[u8]) -> String {
+    hex::encode(Sha256::digest(body))
+}
+
+// The content hash of whatever is left to read, e.g. a file too large to hold in memory
+pub fn read_hash(reader: &mut impl Read) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(reader, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+// How long presigned URLs work for if PRESIGNED_URL_TTL_SECS isn't set
+const DEFAULT_PRESIGNED_URL_TTL_SECS: u64 = 3600;
+
+pub struct ResultStore {
+    store: Box<dyn ObjectStore>,
+    // Set for S3 stores, which can presign URLs
+    signer: Option<AmazonS3>,
+    url_ttl: Duration,
+    // Where objects go within the store, and the URL of the store's root
+    prefix: Path,
+    root: String,
+}
+
+impl ResultStore {
+    // Opens the store in OUTPUT_STORE_URL, if one is configured. Presigned URLs last for
+    // PRESIGNED_URL_TTL_SECS.
+    pub fn from_env() -> Result<Option<ResultStore>> {
+        let url = match env::var("OUTPUT_STORE_URL") {
+            Ok(url) => url,
+            Err(_) => return Ok(None),
+        };
+        let ttl = env::var("PRESIGNED_URL_TTL_SECS")
+            .ok()
+            .and_then(|t| t.parse().ok())
+            .unwrap_or(DEFAULT_PRESIGNED_URL_TTL_SECS);
+        let store = ResultStore::from_url(&url)?;
+        Ok(Some(store.with_url_ttl(Duration::from_secs(ttl))))
+    }
+
+    pub fn from_url(url: &str) -> Result<ResultStore> {
+        let mut signer = None;
+        let (store, root, prefix): (Box<dyn ObjectStore>, String, &str) =
+            if let Some(location) = url.strip_prefix("s3://") {
+                let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+                let s3 = AmazonS3Builder::from_env()
+                    .with_url(url)
+                    .build()
+                    .with_context(|| format!("configuring {}", url))?;
+                signer = Some(s3.clone());
+                (Box::new(s3), format!("s3://{}/", bucket), prefix)
+            } else if let Some(dir) = url.strip_prefix("file://") {
+                fs::create_dir_all(dir).with_context(|| format!("creating {}", dir))?;
+                let local = LocalFileSystem::new();
+                (Box::new(local), "file:///".to_string(), dir)
+            } else if let Some(prefix) = url.strip_prefix("memory://") {
+                (Box::new(InMemory::new()), "memory:///".to_string(), prefix)
+            } else {
+                return Err(anyhow!(
+                    "Unsupported object store {}: expected s3://, file:// or memory://",
+                    url
+                ));
+            };
+
+        Ok(ResultStore {
+            store,
+            signer,
+            url_ttl: Duration::from_secs(DEFAULT_PRESIGNED_URL_TTL_SECS),
+            prefix: Path::from(prefix),
+            root,
+        })
+    }
+
+    pub fn with_url_ttl(mut self, url_ttl: Duration) -> ResultStore {
+        self.url_ttl = url_ttl;
+        self
+    }
+
+    pub fn url_ttl(&self) -> Duration {
+        self.url_ttl
+    }
+
+    // Where objects are written, as a URL
+    pub fn location(&self) -> String {
+        format!("{}{}", self.root, self.prefix)
+    }
+
+    pub fn can_presign(&self) -> bool {
+        self.signer.is_some()
+    }
+
+    // Where an object goes. Names can have directories in them, e.g. osm/14/8539/5778.png
+    fn path(&self, name: &str) -> Path {
+        name.split('/')
+            .fold(self.prefix.clone(), |path, part| path.child(part))
+    }
+
+    // Writes an object, returning the URL it can be found at
+    pub async fn put(&self, name: &str, body: Bytes) -> Result<String> {
+        let path = self.path(name);
+        self.store
+            .put(&path, body.into())
+            .await
+            .with_context(|| format!("writing {}{}", self.root, path))?;
+        Ok(format!("{}{}", self.root, path))
+    }
+
+    // Writes a rendered image under its content hash, as <hash>.png or <hash>.avif, unless
+    // an identical image is there already. Returns the name and the URL it can be found at.
+    pub async fn put_image(&self, image: Bytes) -> Result<(String, String)> {
+        let name = format!("{}.{}", content_hash(&image), avif::image_type(&image).1);
+        let path = self.path(&name);
+        match self.store.head(&path).await {
+            Ok(_) => Ok((name, format!("{}{}", self.root, path))),
+            Err(object_store::Error::NotFound { .. }) => {
+                let location = self.put(&name, image).await?;
+                Ok((name, location))
+            }
+            Err(e) => Err(e).with_context(|| format!("reading {}{}", self.root, path)),
+        }
+    }
+
+    // Reads an object, or None if there isn't one
+    pub async fn get(&self, name: &str) -> Result<Option<Bytes>> {
+        let path = self.path(name);
+        match self.store.get(&path).await {
+            Ok(object) => Ok(Some(object.bytes().await?)),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("reading {}{}", self.root, path)),
+        }
+    }
+
+    // A URL anyone can GET the object from until it expires
+    pub async fn presign(&self, name: &str) -> Result<String> {
+        let signer = self
+            .signer
+            .as_ref()
+            .ok_or_else(|| anyhow!("{} can't presign URLs; only S3 stores can", self.root))?;
+        let url = signer
+            .signed_url(http::Method::GET, &self.path(name), self.url_ttl)
+            .await?;
+        Ok(url.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_put_and_get() {
+        let store = ResultStore::from_url("memory://").unwrap();
+        let location = store
+            .put("a.png", Bytes::from_static(b"png"))
+            .await
+            .unwrap();
+        assert_eq!(location, "memory:///a.png");
+        assert_eq!(
+            store.get("a.png").await.unwrap(),
+            Some(Bytes::from_static(b"png"))
+        );
+        assert_eq!(store.get("b.png").await.unwrap(), None);
+        let location = store
+            .put("osm/14/8539/5778.png", Bytes::from_static(b"png"))
+            .await
+            .unwrap();
+        assert_eq!(location, "memory:///osm/14/8539/5778.png");
+
+        let dir = std::env::temp_dir().join(format!("result-store-{}", std::process::id()));
+        let url = format!("file://{}/", dir.display());
+        let store = ResultStore::from_url(&url).unwrap();
+        let location = store
+            .put("b.png", Bytes::from_static(b"png"))
+            .await
+            .unwrap();
+        assert_eq!(location, format!("file://{}/b.png", dir.display()));
+        assert_eq!(
+            store.get("b.png").await.unwrap(),
+            Some(Bytes::from_static(b"png"))
+        );
+        assert!(dir.join("b.png").exists());
+        fs::remove_dir_all(dir).unwrap();
+
+        assert!(ResultStore::from_url("ftp://example.com/thumbs").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_presign() {
+        let s3 = AmazonS3Builder::new()
+            .with_bucket_name("thumbs")
+            .with_region("eu-central-2")
+            .with_access_key_id("AKIDEXAMPLE")
+            .with_secret_access_key("secret")
+            .build()
+            .unwrap();
+        let store = ResultStore {
+            store: Box::new(s3.clone()),
+            signer: Some(s3),
+            url_ttl: Duration::from_secs(600),
+            prefix: Path::from("passes"),
+            root: "s3://thumbs/".to_string(),
+        };
+        let url = store.presign("a.png").await.unwrap();
+        assert!(url.contains("thumbs"));
+        assert!(url.contains("/passes/a.png?"));
+        assert!(url.contains("X-Amz-Expires=600"));
+        assert!(url.contains("X-Amz-Signature="));
+
+        let memory = ResultStore::from_url("memory://").unwrap();
+        assert!(!memory.can_presign());
+        assert!(memory.presign("a.png").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_put_image() {
+        let store = ResultStore::from_url("memory://").unwrap();
+        let (name, location) = store.put_image(Bytes::from_static(b"png")).await.unwrap();
+        assert_eq!(name, format!("{}.png", content_hash(b"png")));
+        assert_eq!(location, format!("memory:///{}", name));
+        let (again, _) = store.put_image(Bytes::from_static(b"png")).await.unwrap();
+        assert_eq!(again, name);
+        let (other, _) = store.put_image(Bytes::from_static(b"gif")).await.unwrap();
+        assert_ne!(other, name);
+    }
+}
@@ -0,0 +1,107 @@
+// ! # tile_cache
+// ! The upstream tile cache, kept in the object store at TILE_CACHE_URL: s3://bucket/prefix
+// ! to share it between replicas, or file:///path for a local directory. Without it every
+// ! render goes to the tile servers. Tiles are stored as <tileset>/<z>/<x>/<y>.png, so a
+// ! local cache directory can also be served directly with TILESET_<NAME>_SOURCE=dir:.
+// ! The cache can be warmed ahead of time with `pass-image-api seed`, and its most popular
+// ! tiles kept fresh on a schedule by the refresh module.
+
+use crate::faults;
+use crate::refresh::PopularTiles;
+use crate::storage::ResultStore;
+use anyhow::Result;
+use bytes::Bytes;
+use futures::future::LocalBoxFuture;
+use log::info;
+use std::env;
+use std::sync::Arc;
+use tile_render::cache::TileCache;
+use tile_render::fetcher::TileSources;
+
+pub struct StoreCache {
+    store: ResultStore,
+    popular: Option<Arc<PopularTiles>>,
+}
+
+impl StoreCache {
+    pub fn new(store: ResultStore) -> StoreCache {
+        StoreCache {
+            store,
+            popular: None,
+        }
+    }
+
+    // Counts the tiles asked for, for the refresh module to keep the popular ones fresh
+    pub fn with_popularity(mut self, popular: Arc<PopularTiles>) -> StoreCache {
+        self.popular = Some(popular);
+        self
+    }
+
+    // Opens the cache in TILE_CACHE_URL, if one is configured
+    pub fn from_env() -> Result<Option<StoreCache>> {
+        match env::var("TILE_CACHE_URL") {
+            Ok(url) => Ok(Some(StoreCache::new(ResultStore::from_url(&url)?))),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+impl TileCache for StoreCache {
+    fn get(&self, key: &str) -> LocalBoxFuture<'_, Result<Option<Bytes>>> {
+        if let Some(popular) = &self.popular {
+            popular.record(key);
+        }
+        let key = key.to_string();
+        Box::pin(async move { self.store.get(&key).await })
+    }
+
+    fn put(&self, key: &str, png: Bytes) -> LocalBoxFuture<'_, Result<()>> {
+        let key = key.to_string();
+        Box::pin(async move {
+            self.store.put(&key, png).await?;
+            Ok(())
+        })
+    }
+}
+
+// The tile sources from TILESET_<NAME>_SOURCE, cached if TILE_CACHE_URL is set, with
+// faults injected if FAULT_INJECTION is on. The cache counts the tiles asked for in
+// popular, if given.
+pub fn sources_from_env(popular: Option<Arc<PopularTiles>>) -> Result<TileSources> {
+    let mut sources = TileSources::from_env()?;
+    if faults::enabled_from_env() {
+        sources = sources.with_faults();
+    }
+    Ok(match StoreCache::from_env()? {
+        Some(mut cache) => {
+            info!("Caching tiles in {}", cache.store.location());
+            if let Some(popular) = popular {
+                cache = cache.with_popularity(popular);
+            }
+            sources.with_cache(Box::new(cache))
+        }
+        None => sources,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::Context;
+    use tile_render::cache::fetch_through;
+    use tile_render::debug_tiles::debug_tile;
+    use tile_render::fetcher::MemoryFetcher;
+    use tile_render::tiles::{encode_png, TileSet};
+
+    #[tokio::test]
+    async fn test_store_cache() {
+        let cache = StoreCache::new(ResultStore::from_url("memory://tiles").unwrap());
+        let png = encode_png(debug_tile(1, 2, 3));
+        let upstream = MemoryFetcher::default().with_tile(TileSet::Osm, 1, 2, 3, png.clone());
+        fetch_through(&cache, &upstream, TileSet::Osm, 1, 2, 3, Context::new())
+            .await
+            .unwrap();
+        assert_eq!(cache.store.get("osm/3/1/2.png").await.unwrap(), Some(png));
+        assert_eq!(cache.get("osm/3/1/3.png").await.unwrap(), None);
+    }
+}
@@ -0,0 +1,35 @@
+// ! # upstreams
+// ! GET /admin/upstreams, a dashboard of every tileset's upstream as the service sees it:
+// ! fetches and error rate over the last few minutes, p50 and p95 latency, and when a
+// ! fetch last succeeded. See tile_render::upstreams for how it's collected.
+
+use actix_web::{get, HttpResponse};
+use tile_render::upstreams;
+
+#[get("/admin/upstreams")]
+async fn get_upstreams() -> HttpResponse {
+    HttpResponse::Ok().json(upstreams::summaries())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+    use std::time::Duration;
+    use tile_render::tiles::TileSet;
+
+    #[actix_web::test]
+    async fn test_get_upstreams() {
+        upstreams::record(TileSet::Esri, Duration::from_millis(40), false);
+        let app = test::init_service(App::new().service(get_upstreams)).await;
+        let req = test::TestRequest::get()
+            .uri("/admin/upstreams")
+            .to_request();
+        let summaries: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let summaries = summaries.as_array().unwrap();
+        assert!(summaries.iter().all(|s| s["tileset"] != "debug"));
+        let esri = summaries.iter().find(|s| s["tileset"] == "esri").unwrap();
+        assert!(esri["fetches"].as_u64().unwrap() >= 1);
+        assert!(esri["p95_ms"].as_f64().is_some());
+    }
+}
@@ -0,0 +1,378 @@
+// ! # usage
+// ! Per-API-key usage accounting. We count images rendered and tiles fetched per key per
+// ! calendar month, persist the counts to SQLite so they survive restarts, and refuse
+// ! further renders once a key goes over its configured quota.
+// !
+// ! Only the keys listed in USAGE_API_KEYS are accounted on their own. Any other key
+// ! shares the anonymous quota, so making up a new X-Api-Key doesn't get a fresh one.
+// ! Queries run on the blocking thread pool, as SQLite can hold up a worker on a slow disk.
+
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+use anyhow::{Context, Result};
+use log::warn;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The header clients identify themselves with
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+
+// What we account requests without a key against
+pub const ANONYMOUS_KEY: &str = "anonymous";
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Usage {
+    pub api_key: String,
+    pub month: String,
+    pub images: u64,
+    pub tiles: u64,
+}
+
+pub struct UsageTracker {
+    conn: Arc<Mutex<Connection>>,
+    image_quota: Option<u64>,
+    tile_quota: Option<u64>,
+    // The keys accounted on their own
+    api_keys: HashSet<String>,
+}
+
+impl UsageTracker {
+    pub fn new(
+        conn: Connection,
+        image_quota: Option<u64>,
+        tile_quota: Option<u64>,
+    ) -> Result<Self> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS usage (
+                api_key TEXT NOT NULL,
+                month   TEXT NOT NULL,
+                images  INTEGER NOT NULL DEFAULT 0,
+                tiles   INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (api_key, month)
+            )",
+            [],
+        )
+        .with_context(|| "creating usage table")?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS signing_keys (
+                fingerprint TEXT PRIMARY KEY,
+                api_key     TEXT NOT NULL
+            )",
+            [],
+        )
+        .with_context(|| "creating signing keys table")?;
+
+        Ok(UsageTracker {
+            conn: Arc::new(Mutex::new(conn)),
+            image_quota,
+            tile_quota,
+            api_keys: HashSet::new(),
+        })
+    }
+
+    // Accounts the given keys on their own
+    pub fn with_api_keys<I, K>(mut self, api_keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        self.api_keys = api_keys.into_iter().map(Into::into).collect();
+        self
+    }
+
+    // Opens the database at USAGE_DB_PATH, reads the monthly quotas from
+    // USAGE_MONTHLY_IMAGE_QUOTA and USAGE_MONTHLY_TILE_QUOTA, and the keys to account on
+    // their own from the comma separated USAGE_API_KEYS. Missing quotas mean unlimited.
+    pub fn from_env() -> Result<Self> {
+        let path = env::var("USAGE_DB_PATH").unwrap_or_else(|_| "usage.db".to_string());
+        let conn =
+            Connection::open(&path).with_context(|| format!("opening usage db at {}", path))?;
+
+        let quota = |name: &str| env::var(name).ok().and_then(|q| q.parse().ok());
+        let api_keys = env::var("USAGE_API_KEYS").unwrap_or_default();
+        Ok(UsageTracker::new(
+            conn,
+            quota("USAGE_MONTHLY_IMAGE_QUOTA"),
+            quota("USAGE_MONTHLY_TILE_QUOTA"),
+        )?
+        .with_api_keys(
+            api_keys
+                .split(',')
+                .map(str::trim)
+                .filter(|key| !key.is_empty() && *key != ANONYMOUS_KEY),
+        ))
+    }
+
+    // The key a request is accounted against: its own if it's a known key, and otherwise
+    // the anonymous one
+    pub fn accounted_key<'a>(&self, api_key: &'a str) -> &'a str {
+        if self.api_keys.contains(api_key) {
+            api_key
+        } else {
+            ANONYMOUS_KEY
+        }
+    }
+
+    // Runs a query against the database on the blocking thread pool
+    async fn query<T, F>(&self, query: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        web::block(move || query(&conn.lock().unwrap())).await?
+    }
+
+    // Returns an error describing the exhausted quota if the key can't render any more
+    // this month.
+    pub async fn check(&self, api_key: &str) -> Result<(), String> {
+        let api_key = self.accounted_key(api_key).to_string();
+        let month = current_month();
+        let usage = match self
+            .query({
+                let api_key = api_key.clone();
+                move |conn| get(conn, &api_key, &month)
+            })
+            .await
+        {
+            Ok(usage) => usage,
+            Err(e) => {
+                // Don't take the service down because accounting is broken
+                warn!("Couldn't read usage for {}: {}", api_key, e);
+                return Ok(());
+            }
+        };
+
+        if let Some(quota) = self.image_quota {
+            if usage.images >= quota {
+                return Err(format!("Monthly image quota of {} exhausted", quota));
+            }
+        }
+        if let Some(quota) = self.tile_quota {
+            if usage.tiles >= quota {
+                return Err(format!("Monthly tile quota of {} exhausted", quota));
+            }
+        }
+        Ok(())
+    }
+
+    // Accounts one rendered image, made up of the given number of tiles, against a key
+    pub async fn record(&self, api_key: &str, tiles: u64) {
+        let api_key = self.accounted_key(api_key).to_string();
+        let month = current_month();
+        let result = self
+            .query({
+                let api_key = api_key.clone();
+                move |conn| {
+                    conn.execute(
+                        "INSERT INTO usage (api_key, month, images, tiles) VALUES (?1, ?2, 1, ?3)
+                         ON CONFLICT (api_key, month)
+                         DO UPDATE SET images = images + 1, tiles = tiles + excluded.tiles",
+                        params![api_key, month, tiles as i64],
+                    )?;
+                    Ok(())
+                }
+            })
+            .await;
+        if let Err(e) = result {
+            warn!("Couldn't record usage for {}: {}", api_key, e);
+        }
+    }
+
+    // Remembers which key a fingerprint in a signed URL stands for, so renders through
+    // the URL can be accounted against it
+    pub async fn remember_key(&self, fingerprint: &str, api_key: &str) -> Result<()> {
+        let (fingerprint, api_key) = (fingerprint.to_string(), api_key.to_string());
+        self.query(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO signing_keys (fingerprint, api_key) VALUES (?1, ?2)",
+                params![fingerprint, api_key],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    // The key a fingerprint stands for, if it was remembered
+    pub async fn key_for(&self, fingerprint: &str) -> Result<Option<String>> {
+        let fingerprint = fingerprint.to_string();
+        self.query(move |conn| {
+            Ok(conn
+                .query_row(
+                    "SELECT api_key FROM signing_keys WHERE fingerprint = ?1",
+                    params![fingerprint],
+                    |row| row.get(0),
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    // All keys' usage for a month
+    pub async fn list(&self, month: &str) -> Result<Vec<Usage>> {
+        let month = month.to_string();
+        self.query(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT api_key, images, tiles FROM usage WHERE month = ?1 ORDER BY api_key",
+            )?;
+            let rows = stmt.query_map(params![month], |row| {
+                Ok(Usage {
+                    api_key: row.get(0)?,
+                    month: month.clone(),
+                    images: row.get::<_, i64>(1)? as u64,
+                    tiles: row.get::<_, i64>(2)? as u64,
+                })
+            })?;
+            Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+        })
+        .await
+    }
+}
+
+fn get(conn: &Connection, api_key: &str, month: &str) -> Result<Usage> {
+    let mut stmt =
+        conn.prepare("SELECT images, tiles FROM usage WHERE api_key = ?1 AND month = ?2")?;
+    let mut rows = stmt.query(params![api_key, month])?;
+    let (images, tiles) = match rows.next()? {
+        Some(row) => (row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64),
+        None => (0, 0),
+    };
+    Ok(Usage {
+        api_key: api_key.to_string(),
+        month: month.to_string(),
+        images,
+        tiles,
+    })
+}
+
+// Works out which API key a request should be accounted against
+pub fn api_key(req: &HttpRequest) -> String {
+    req.headers()
+        .get(API_KEY_HEADER)
+        .and_then(|val| val.to_str().ok())
+        .filter(|key| !key.is_empty())
+        .unwrap_or(ANONYMOUS_KEY)
+        .to_string()
+}
+
+// The current month as YYYY-MM, in UTC
+pub(crate) fn current_month() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (year, month) = year_month_from_days((secs / 86_400) as i64);
+    format!("{:04}-{:02}", year, month)
+}
+
+fn year_month_from_days(days: i64) -> (i64, u32) {
+    let (year, month, _) = date_from_days(days);
+    (year, month)
+}
+
+// Converts days since the unix epoch to a (year, month, day) in the proleptic
+// Gregorian calendar. See http://howardhinnant.github.io/date_algorithms.html
+pub(crate) fn date_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[derive(Deserialize)]
+struct UsageQuery {
+    month: Option<String>,
+}
+
+#[get("/admin/usage")]
+async fn get_usage(
+    query: web::Query<UsageQuery>,
+    usage: web::Data<UsageTracker>,
+) -> impl Responder {
+    let month = query.month.clone().unwrap_or_else(current_month);
+    match usage.list(&month).await {
+        Ok(usage) => HttpResponse::Ok().json(usage),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker(image_quota: Option<u64>, tile_quota: Option<u64>) -> UsageTracker {
+        UsageTracker::new(
+            Connection::open_in_memory().unwrap(),
+            image_quota,
+            tile_quota,
+        )
+        .unwrap()
+        .with_api_keys(["key-a", "key-b"])
+    }
+
+    #[test]
+    fn test_year_month_from_days() {
+        assert_eq!(year_month_from_days(0), (1970, 1));
+        // 2000-02-29
+        assert_eq!(year_month_from_days(11_016), (2000, 2));
+        // 2024-12-31
+        assert_eq!(year_month_from_days(20_088), (2024, 12));
+        assert_eq!(date_from_days(11_016), (2000, 2, 29));
+        assert_eq!(date_from_days(20_088), (2024, 12, 31));
+    }
+
+    #[actix_web::test]
+    async fn test_record_accumulates() {
+        let usage = tracker(None, None);
+        usage.record("key-a", 12).await;
+        usage.record("key-a", 8).await;
+        usage.record("key-b", 4).await;
+
+        let listed = usage.list(&current_month()).await.unwrap();
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0].api_key, "key-a");
+        assert_eq!(listed[0].images, 2);
+        assert_eq!(listed[0].tiles, 20);
+        assert_eq!(listed[1].images, 1);
+    }
+
+    #[actix_web::test]
+    async fn test_quotas_enforced() {
+        let usage = tracker(Some(2), Some(100));
+        assert!(usage.check("key-a").await.is_ok());
+        usage.record("key-a", 10).await;
+        usage.record("key-a", 10).await;
+        assert!(usage.check("key-a").await.is_err());
+        assert!(usage.check("key-b").await.is_ok());
+
+        let usage = tracker(None, Some(15));
+        usage.record("key-a", 20).await;
+        assert!(usage.check("key-a").await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_unknown_keys_share_the_anonymous_quota() {
+        let usage = tracker(Some(2), None);
+        usage.record("made-up", 1).await;
+        usage.record("made-up-too", 1).await;
+        assert!(usage.check("another").await.is_err());
+        assert!(usage.check(ANONYMOUS_KEY).await.is_err());
+        assert!(usage.check("key-a").await.is_ok());
+
+        let listed = usage.list(&current_month()).await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(
+            (listed[0].api_key.as_str(), listed[0].images),
+            (ANONYMOUS_KEY, 2)
+        );
+    }
+}
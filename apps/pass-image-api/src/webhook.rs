@@ -0,0 +1,153 @@
+// ! # webhook
+// ! Callbacks to URLs clients hand us, e.g. to say a job has finished so they don't have
+// ! to poll. Bodies are JSON, signed in the X-Pass-Image-Signature header (see
+// ! signing::WebhookSigner). Callback URLs get the same checks as any other outbound URL
+// ! we don't control, and delivery is retried with backoff on errors and non-2xx answers.
+
+use crate::signing::WebhookSigner;
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use log::warn;
+use opentelemetry::Context;
+use std::time::Duration;
+use tile_render::transport::Transport;
+use tile_render::url_guard;
+
+pub const SIGNATURE_HEADER: &str = "X-Pass-Image-Signature";
+
+// How many times we try to deliver a callback, and how long we wait before the first
+// retry. The wait quadruples after each attempt.
+const ATTEMPTS: u32 = 3;
+pub const BACKOFF: Duration = Duration::from_secs(1);
+
+// POSTs a signed JSON body to a callback URL
+pub async fn deliver(
+    transport: &dyn Transport,
+    signer: &WebhookSigner,
+    url: &str,
+    body: Bytes,
+    backoff: Duration,
+) -> Result<()> {
+    let mut delay = backoff;
+    for attempt in 1..=ATTEMPTS {
+        let headers = vec![
+            ("Content-Type".to_string(), "application/json".to_string()),
+            (SIGNATURE_HEADER.to_string(), signer.sign(&body)),
+        ];
+        let sent = url_guard::guarded_post(
+            transport,
+            url,
+            headers,
+            body.clone(),
+            "dd-sdlc-demo",
+            Context::current(),
+        )
+        .await;
+        match sent {
+            Ok(response) if (200..300).contains(&response.status) => return Ok(()),
+            Ok(response) => warn!(
+                "Webhook {} answered {} (attempt {} of {})",
+                url, response.status, attempt, ATTEMPTS
+            ),
+            Err(e) => warn!(
+                "Webhook {} failed: {} (attempt {} of {})",
+                url, e, attempt, ATTEMPTS
+            ),
+        }
+        if attempt < ATTEMPTS {
+            actix_web::rt::time::sleep(delay).await;
+            delay *= 4;
+        }
+    }
+    Err(anyhow!(
+        "Gave up delivering webhook to {} after {} attempts",
+        url,
+        ATTEMPTS
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::LocalBoxFuture;
+    use std::cell::RefCell;
+    use tile_render::transport::Response;
+
+    // Answers with each status in turn, remembering the headers of every request
+    struct Receiver {
+        statuses: RefCell<Vec<u16>>,
+        received: RefCell<Vec<Vec<(String, String)>>>,
+    }
+
+    impl Receiver {
+        fn new(statuses: &[u16]) -> Receiver {
+            Receiver {
+                statuses: RefCell::new(statuses.iter().rev().copied().collect()),
+                received: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl Transport for Receiver {
+        fn send<'a>(
+            &'a self,
+            _method: http::Method,
+            _url: &'a str,
+            headers: &'a [(String, String)],
+            _body: Bytes,
+        ) -> LocalBoxFuture<'a, Result<Response>> {
+            self.received.borrow_mut().push(headers.to_vec());
+            let response = Response {
+                status: self.statuses.borrow_mut().pop().unwrap_or(500),
+                content_type: None,
+                location: None,
+                body: Bytes::new(),
+            };
+            Box::pin(async move { Ok(response) })
+        }
+    }
+
+    // Public IP literals keep validate_url from needing DNS
+    const URL: &str = "https://8.8.8.8/hooks/render";
+
+    #[actix_web::test]
+    async fn test_deliver_signs_and_retries() {
+        let signer = WebhookSigner::new(b"hook-key".to_vec());
+        let body = Bytes::from_static(br#"{"id": "abc"}"#);
+
+        let receiver = Receiver::new(&[503, 204]);
+        deliver(&receiver, &signer, URL, body.clone(), Duration::ZERO)
+            .await
+            .unwrap();
+        let received = receiver.received.take();
+        assert_eq!(received.len(), 2);
+        let signature = received[1]
+            .iter()
+            .find(|(name, _)| name == SIGNATURE_HEADER)
+            .map(|(_, value)| value.as_str())
+            .unwrap();
+        assert!(signer.verify(signature, &body).is_ok());
+
+        let receiver = Receiver::new(&[]);
+        assert!(deliver(&receiver, &signer, URL, body, Duration::ZERO)
+            .await
+            .is_err());
+        assert_eq!(receiver.received.borrow().len(), ATTEMPTS as usize);
+    }
+
+    #[actix_web::test]
+    async fn test_deliver_refuses_private_urls() {
+        let signer = WebhookSigner::new(b"hook-key".to_vec());
+        let receiver = Receiver::new(&[204]);
+        let result = deliver(
+            &receiver,
+            &signer,
+            "http://169.254.169.254/latest",
+            Bytes::new(),
+            Duration::ZERO,
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(receiver.received.borrow().is_empty());
+    }
+}
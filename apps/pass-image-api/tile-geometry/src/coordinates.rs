@@ -0,0 +1,503 @@
+// ! # coordinates
+// !
+// ! The coordinates module provides types and utilities for dealing with geospatial
+// ! coordinates. For our purposes this means converting between latitude/longitude WGS84
+// ! pairs and webmercator slippy-maps style tile coordinates.
+// !
+
+use crate::math;
+use core::f64::consts::PI;
+
+// The deepest zoom tiles are rendered at
+pub const MAX_ZOOM: u32 = 21;
+
+// A latitude/longitude pair
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatLong(pub f64, pub f64);
+
+// A tile coordinate. Note that a 'zoomLevel' value
+// must be carried along with this too
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TileCoordinate {
+    pub x: f32,
+    pub y: f32,
+    pub z: u32,
+}
+
+// Converts a lat/long pair to tile coordinates at a particular zoom
+pub fn lat_long_to_tile_coords(point: &LatLong, zoom: u32) -> TileCoordinate {
+    let lat_rad = point.0.to_radians();
+    let n = math::powi(2.0, zoom as i32);
+    let x_tile = (point.1 + 180.0) / 360.0 * n;
+    let y_tile = (1.0 - math::ln(math::tan(lat_rad) + 1.0 / math::cos(lat_rad)) / PI) / 2.0 * n;
+
+    TileCoordinate {
+        x: x_tile as f32,
+        y: y_tile as f32,
+        z: zoom,
+    }
+}
+
+// Converts a lat/long pair to global pixel coordinates at a particular zoom, where the
+// whole world is 256 * 2^zoom pixels wide. This is done in f64 throughout as f32 tile
+// coordinates aren't precise enough to place things at the pixel level at high zooms.
+pub fn lat_long_to_pixel(point: &LatLong, zoom: u32) -> (f64, f64) {
+    let lat_rad = point.0.to_radians();
+    let world_px = 256.0 * math::powi(2.0, zoom as i32);
+    let x = (point.1 + 180.0) / 360.0 * world_px;
+    let y = (1.0 - math::ln(math::tan(lat_rad) + 1.0 / math::cos(lat_rad)) / PI) / 2.0 * world_px;
+    (x, y)
+}
+
+// The inverse of lat_long_to_pixel
+pub fn pixel_to_lat_long(x: f64, y: f64, zoom: u32) -> LatLong {
+    let world_px = 256.0 * math::powi(2.0, zoom as i32);
+    let long = x / world_px * 360.0 - 180.0;
+    let lat = math::atan(math::sinh(PI * (1.0 - 2.0 * y / world_px))).to_degrees();
+    LatLong(lat, long)
+}
+
+// The quadkey Bing Maps addresses a tile by: one base 4 digit per zoom level, from the
+// top, each picking a quadrant of the tile above (0 top left, 1 top right, 2 bottom left,
+// 3 bottom right). Zoom 0 has no digits. The digits come one at a time, as the crate
+// doesn't allocate; collect them into a String.
+pub fn tile_to_quadkey(x: u32, y: u32, z: u32) -> impl Iterator<Item = char> {
+    (1..=z).rev().map(move |level| {
+        let mask = 1 << (level - 1);
+        let digit = u8::from(x & mask != 0) + 2 * u8::from(y & mask != 0);
+        char::from(b'0' + digit)
+    })
+}
+
+// Half the width of the Web Mercator (EPSG:3857) world, in meters
+pub const MERCATOR_HALF_WIDTH_M: f64 = 20_037_508.342789244;
+
+// The EPSG:3857 bounding box a tile covers, in meters, as [min_x, min_y, max_x, max_y].
+// Tile rows count down from the north, and mercator northings up from the equator.
+pub fn tile_to_mercator_bbox(x: u32, y: u32, z: u32) -> [f64; 4] {
+    let tile_m = 2.0 * MERCATOR_HALF_WIDTH_M / math::powi(2.0, z as i32);
+    let min_x = -MERCATOR_HALF_WIDTH_M + x as f64 * tile_m;
+    let max_y = MERCATOR_HALF_WIDTH_M - y as f64 * tile_m;
+    [min_x, max_y - tile_m, min_x + tile_m, max_y]
+}
+
+// The ground distance covered by one pixel at the given latitude and zoom, in meters
+pub fn meters_per_pixel(lat: f64, zoom: u32) -> f64 {
+    const EARTH_CIRCUMFERENCE_M: f64 = 40_075_016.686;
+    EARTH_CIRCUMFERENCE_M * math::cos(lat.to_radians()) / (256.0 * math::powi(2.0, zoom as i32))
+}
+
+// An extension of a TileBox that allows us to specify extra information to constrain it. The inner_size
+// is the number of pixels that are actually "used", and the center is the center the TileBox was taken around.
+// This is a bit of a funny type as it mixes coordinate systems; it would be better if we changed this so that
+// we have pixel offsets into the image here based on centering around the point we have created the ConstrainedTileBox
+// for.
+#[derive(Debug, Copy, Clone)]
+pub struct ConstrainedTileBox {
+    pub center: LatLong,
+    pub tile_box: TileBox,
+    pub inner_size_px: (u32, u32),
+}
+
+// A box of tiles
+#[derive(Debug, Copy, Clone)]
+pub struct TileBox {
+    pub top_left: TileCoordinate,
+    pub bottom_right: TileCoordinate,
+}
+
+impl TileBox {
+    pub fn _outer_size_px(&self) -> (u32, u32) {
+        (
+            (256.0 * (self.bottom_right.x - self.top_left.x)) as u32,
+            (256.0 * (self.bottom_right.y - self.top_left.y)) as u32,
+        )
+    }
+
+    // The number of whole tiles needed to cover the box
+    pub fn tile_count(&self) -> u32 {
+        let x_tiles =
+            math::ceil(self.bottom_right.x) as u32 - math::floor(self.top_left.x) as u32 + 1;
+        let y_tiles =
+            math::ceil(self.bottom_right.y) as u32 - math::floor(self.top_left.y) as u32 + 1;
+        x_tiles * y_tiles
+    }
+
+    pub fn outer_top_left(&self) -> (u32, u32) {
+        (
+            math::floor(self.top_left.x) as u32,
+            math::floor(self.top_left.y) as u32,
+        )
+    }
+}
+
+// Given a point on the earth, a radius, and a desired zoom level, this function produces a
+// ConstrainedTileBox that contains enough pixels to cover the given area.
+fn lat_long_and_radius_to_tile_box(
+    point: &LatLong,
+    radius_km: f32,
+    zoom: u32,
+) -> ConstrainedTileBox {
+    let earth_radius_km = 6371.0;
+
+    // Convert the center point to tile coordinates
+    let center_tile = lat_long_to_tile_coords(point, zoom);
+
+    // Calculate the approximate size of one tile in kilometers at the given zoom level
+    let tile_size_km = tile_size_kms(zoom, earth_radius_km);
+
+    // Calculate the number of tiles that fit into the radius (in both directions)
+    let radius_tiles = radius_km / tile_size_km;
+
+    // Create a square bounding box by using the same radius in both x and y directions
+    let top_left_tile = TileCoordinate {
+        x: center_tile.x - radius_tiles,
+        y: center_tile.y - radius_tiles,
+        z: zoom,
+    };
+    let bottom_right_tile = TileCoordinate {
+        x: center_tile.x + radius_tiles,
+        y: center_tile.y + radius_tiles,
+        z: zoom,
+    };
+
+    // What's the inner resolution for our given radius? E.g., if we get zoom level '0' and ask
+    // for a 10k radius, it's going to be very close to zero pixels
+    let inner_size_px = (256.0 * radius_tiles) as u32;
+
+    ConstrainedTileBox {
+        center: *point,
+        inner_size_px: (inner_size_px, inner_size_px),
+        tile_box: TileBox {
+            top_left: top_left_tile,
+            bottom_right: bottom_right_tile,
+        },
+    }
+}
+
+// tile_size_kms calculates the size of a tile at the given zoom level in kilometers.
+// in webmercator, the size of a tile is the same on both axes
+fn tile_size_kms(zoom: u32, earth_radius_km: f64) -> f32 {
+    let n = math::powi(2.0, zoom as i32);
+    ((earth_radius_km * 2.0 * PI) / n) as f32
+}
+
+// Given a center point, a desired image size, and a radius in kilometers, produces
+// a ConstrainedTileBox that provides enough pixels to cover the given area, ensuring
+// we have (image_size_px / 2) pixels available to the left/right/above/below of the
+// center point. This also means we have to pick an appropriate zoom level to get
+// the resolution we need. A radius too small to fill the image even at MAX_ZOOM gets
+// the MAX_ZOOM box, which is smaller than the image.
+pub fn lat_long_and_image_size_to_bounding_box(
+    center: LatLong,
+    radius_km: f32,
+    image_size_px: u32,
+) -> ConstrainedTileBox {
+    // The lowest zoom with enough pixels
+    (0..=MAX_ZOOM)
+        .map(|z| lat_long_and_radius_to_tile_box(&center, radius_km, z))
+        .find(|c| c.inner_size_px.0 > image_size_px)
+        .unwrap_or_else(|| lat_long_and_radius_to_tile_box(&center, radius_km, MAX_ZOOM))
+}
+
+// The radius to pass to lat_long_and_image_size_to_bounding_box for an image rendered at
+// exactly the given zoom. Two pixels over the image size keeps the float rounding in
+// lat_long_and_radius_to_tile_box from tipping it to the next zoom up.
+pub fn radius_for_zoom(zoom: u32, image_size_px: u32) -> f32 {
+    tile_size_kms(zoom, 6371.0) * (image_size_px + 2) as f32 / 256.0
+}
+
+// The center and radius to pass to lat_long_and_image_size_to_bounding_box for an image
+// showing all the points, with a margin around them as a fraction of their extent. The
+// radius is at least min_radius_km, so a single point or a tight group isn't shown at
+// street level. None if there are no points.
+pub fn fit_points(points: &[LatLong], margin: f64, min_radius_km: f32) -> Option<(LatLong, f32)> {
+    // Worked out in global pixels at zoom 0, where the world is 256 pixels across
+    let (first, rest) = points.split_first()?;
+    let (first_x, first_y) = lat_long_to_pixel(first, 0);
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (first_x, first_y, first_x, first_y);
+    for (x, y) in rest.iter().map(|p| lat_long_to_pixel(p, 0)) {
+        min_x = min_x.min(x);
+        min_y = min_y.min(y);
+        max_x = max_x.max(x);
+        max_y = max_y.max(y);
+    }
+    let center = pixel_to_lat_long((min_x + max_x) / 2.0, (min_y + max_y) / 2.0, 0);
+
+    // The image is square and spans the radius across, in kilometers at tile scale
+    let extent = (max_x - min_x).max(max_y - min_y) * (1.0 + 2.0 * margin);
+    let radius_km = (extent / 256.0) as f32 * tile_size_kms(0, 6371.0);
+    Some((center, radius_km.max(min_radius_km)))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+
+    use super::*;
+    use float_cmp::*;
+    use proptest::prelude::*;
+
+    const MARGIN: F32Margin = F32Margin {
+        ulps: 2,
+        epsilon: 0.0,
+    };
+
+    #[test]
+    fn test_zero() {
+        let TileCoordinate { x, y, .. } = lat_long_to_tile_coords(&LatLong(-31.0, 115.0), 0);
+
+        assert!(x.approx_eq(0.819_444_4, MARGIN));
+        assert!(y.approx_eq(0.590_648_7, MARGIN));
+    }
+
+    #[test]
+    fn test_lat_long_to_tile_coords_perth() {
+        let lat = -31.9514;
+        let lon = 115.8617;
+        let zoom = 12;
+        let TileCoordinate { x, y, z } = lat_long_to_tile_coords(&LatLong(lat, lon), zoom);
+
+        assert!(x.approx_eq(3_366.248_8, MARGIN));
+        assert!(y.approx_eq(2_431.989_7, MARGIN));
+        assert_eq!(z, zoom);
+    }
+
+    #[test]
+    fn test_tile_to_quadkey() {
+        let quadkey = |x, y, z| tile_to_quadkey(x, y, z).collect::<std::string::String>();
+        assert_eq!(quadkey(0, 0, 0), "");
+        assert_eq!(quadkey(1, 0, 1), "1");
+        // The example from Bing's tile system documentation
+        assert_eq!(quadkey(3, 5, 3), "213");
+        assert_eq!(quadkey(8539, 5778, 14), "12022121031031");
+    }
+
+    #[test]
+    fn test_tile_to_mercator_bbox() {
+        let half = MERCATOR_HALF_WIDTH_M;
+        assert_eq!(tile_to_mercator_bbox(0, 0, 0), [-half, -half, half, half]);
+        assert_eq!(tile_to_mercator_bbox(1, 0, 1), [0.0, 0.0, half, half]);
+        assert_eq!(tile_to_mercator_bbox(0, 1, 1), [-half, -half, 0.0, 0.0]);
+        // The tile Thun is in, a little east of Greenwich and north of the equator
+        let [min_x, min_y, max_x, max_y] = tile_to_mercator_bbox(8539, 5778, 14);
+        assert!((max_x - min_x - 2445.98).abs() < 0.01);
+        assert!((max_y - min_y - 2445.98).abs() < 0.01);
+        assert!((min_x - 848_756.76).abs() < 0.01);
+        assert!((max_y - 5_904_607.56).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_lat_long_to_tile_coords_thun() {
+        let lat = 46.7580;
+        let lon = 7.6280;
+        let zoom = 14;
+        let TileCoordinate { x, y, z } = lat_long_to_tile_coords(&LatLong(lat, lon), zoom);
+        assert!(x.approx_eq(8539.159, MARGIN));
+        assert!(y.approx_eq(5778.795, MARGIN));
+        assert_eq!(z, zoom);
+    }
+
+    #[test]
+    fn test_lat_long_and_radius_to_tile_box_perth() {
+        let lat = -31.9514;
+        let lon = 115.8617;
+        let zoom = 12;
+        let radius_km = 2.0;
+
+        // Convert the latitude and longitude of Perth to tile coordinates and calculate the bounding box
+        let ConstrainedTileBox {
+            tile_box:
+                TileBox {
+                    top_left,
+                    bottom_right,
+                },
+            ..
+        } = lat_long_and_radius_to_tile_box(&LatLong(lat, lon), radius_km, zoom);
+
+        // Assertions - rough values, need fixing with exact ones
+        let TileCoordinate {
+            x: top_left_x,
+            y: top_left_y,
+            z: _top_left_z,
+        } = top_left;
+        let TileCoordinate {
+            x: bottom_right_x,
+            y: bottom_right_y,
+            z: _bottom_right_z,
+        } = bottom_right;
+
+        assert!(top_left_x.approx_eq(3_366.044_2, MARGIN));
+        assert!(top_left_y.approx_eq(2_431.785_2, MARGIN));
+        assert!(bottom_right_x.approx_eq(3_366.453_4, MARGIN));
+        assert!(bottom_right_y.approx_eq(2_432.194_3, MARGIN));
+    }
+
+    #[test]
+    fn test_lat_long_to_pixel_matches_tile_coords() {
+        let point = LatLong(46.7580, 7.6280);
+        let (x, y) = lat_long_to_pixel(&point, 14);
+        let tile = lat_long_to_tile_coords(&point, 14);
+        assert!(((x / 256.0) as f32).approx_eq(tile.x, MARGIN));
+        assert!(((y / 256.0) as f32).approx_eq(tile.y, MARGIN));
+    }
+
+    #[test]
+    fn test_pixel_to_lat_long_round_trip() {
+        let point = LatLong(46.655559, 8.102121);
+        let (x, y) = lat_long_to_pixel(&point, 15);
+        let LatLong(lat, long) = pixel_to_lat_long(x, y, 15);
+        assert!((lat - point.0).abs() < 1e-9);
+        assert!((long - point.1).abs() < 1e-9);
+
+        // About 156km per pixel at the equator at zoom 0
+        assert!((meters_per_pixel(0.0, 0) - 156_543.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_fit_points() {
+        assert!(fit_points(&[], 0.1, 1.0).is_none());
+
+        // A single point is centered at the minimum radius
+        let (center, radius) = fit_points(&[LatLong(46.5, 8.5)], 0.1, 2.0).unwrap();
+        assert!((center.0 - 46.5).abs() < 1e-9 && (center.1 - 8.5).abs() < 1e-9);
+        assert_eq!(radius, 2.0);
+
+        // Every point ends up inside the image
+        let points = [
+            LatLong(46.5725, 8.415),
+            LatLong(46.562, 8.339),
+            LatLong(46.73, 8.449),
+            LatLong(46.55625, 8.567777),
+        ];
+        let (center, radius) = fit_points(&points, 0.1, 2.0).unwrap();
+        let tile_box = lat_long_and_image_size_to_bounding_box(center, radius, 1024);
+        let zoom = tile_box.tile_box.top_left.z;
+        let (cx, cy) = lat_long_to_pixel(&center, zoom);
+        let half = tile_box.inner_size_px.0 as f64 / 2.0;
+        for point in &points {
+            let (x, y) = lat_long_to_pixel(point, zoom);
+            assert!((x - cx).abs() < half && (y - cy).abs() < half);
+        }
+    }
+
+    #[test]
+    fn test_tile_count() {
+        let tile_box = TileBox {
+            top_left: TileCoordinate {
+                x: 10.5,
+                y: 20.2,
+                z: 6,
+            },
+            bottom_right: TileCoordinate {
+                x: 12.1,
+                y: 20.9,
+                z: 6,
+            },
+        };
+
+        // x covers 10..=13, y covers 20..=21
+        assert_eq!(tile_box.tile_count(), 8);
+    }
+
+    #[test]
+    fn test_lat_long_and_image_size_to_bounding_box_perth() {
+        let lat = -31.9514;
+        let lon = 115.8617;
+        let radius_km = 10.0;
+        let image_size_px = 1000;
+
+        // Call the function to get the best zoom level and bounding box
+        let ConstrainedTileBox {
+            tile_box:
+                TileBox {
+                    top_left,
+                    bottom_right,
+                },
+            ..
+        } = lat_long_and_image_size_to_bounding_box(LatLong(lat, lon), radius_km, image_size_px);
+
+        // Rough assertions for the zoom and tile coordinates
+        // assert_eq!(zoom, 14); // Adjust this value based on actual results
+
+        let TileCoordinate {
+            x: top_left_x,
+            y: top_left_y,
+            z: _top_left_z,
+        } = top_left;
+        let TileCoordinate {
+            x: bottom_right_x,
+            y: bottom_right_y,
+            z: _bottom_right_z,
+        } = bottom_right;
+
+        assert!(top_left_x.approx_eq(13_460.902, MARGIN));
+        assert!(top_left_y.approx_eq(9_723.866, MARGIN));
+        assert!(bottom_right_x.approx_eq(13_469.088, MARGIN));
+        assert!(bottom_right_y.approx_eq(9_732.052, MARGIN));
+    }
+
+    // Anywhere web mercator can show, with extra weight on the edges of the world where
+    // tile boxes overhang it
+    pub(crate) fn lat_long() -> impl Strategy<Value = LatLong> {
+        let lat = -85.051_128..85.051_128;
+        let long = -180.0..180.0;
+        let edge = |max: f64| prop_oneof![(max - 0.5)..max, -max..(0.5 - max)];
+        prop_oneof![
+            2 => (lat.clone(), long.clone()),
+            1 => (lat, edge(180.0)),
+            1 => (edge(85.051_128), long),
+        ]
+        .prop_map(|(lat, long)| LatLong(lat, long))
+    }
+
+    // Radiuses from 10m to 500km, spread evenly over the orders of magnitude
+    pub(crate) fn radius_km() -> impl Strategy<Value = f32> {
+        (-2.0f32..2.7).prop_map(|exponent| 10f32.powf(exponent))
+    }
+
+    proptest! {
+        #[test]
+        fn prop_pixel_round_trip(point in lat_long(), zoom in 0u32..=21) {
+            let (x, y) = lat_long_to_pixel(&point, zoom);
+            let LatLong(lat, long) = pixel_to_lat_long(x, y, zoom);
+            prop_assert!((lat - point.0).abs() < 1e-9 && (long - point.1).abs() < 1e-9);
+        }
+
+        // Tile coordinates are f32, so they're only good to about a part in 2^24 of the
+        // world, whatever the zoom: around a meter
+        #[test]
+        fn prop_tile_coords_round_trip(point in lat_long(), zoom in 0u32..=21) {
+            let tile = lat_long_to_tile_coords(&point, zoom);
+            prop_assert!(tile.x >= 0.0 && tile.y >= 0.0);
+            let LatLong(lat, long) =
+                pixel_to_lat_long(tile.x as f64 * 256.0, tile.y as f64 * 256.0, zoom);
+            prop_assert!((long - point.1).abs() < 2e-5, "{} vs {}", long, point.1);
+            prop_assert!((lat - point.0).abs() < 2e-5, "{} vs {}", lat, point.0);
+        }
+
+        #[test]
+        fn prop_bounding_box_contains_center(
+            center in lat_long(),
+            radius_km in radius_km(),
+            image_size_px in 16u32..4096,
+        ) {
+            let tile_box = lat_long_and_image_size_to_bounding_box(center, radius_km, image_size_px);
+            let TileBox { top_left, bottom_right } = tile_box.tile_box;
+            let tile = lat_long_to_tile_coords(&center, top_left.z);
+            prop_assert!(top_left.x <= tile.x && tile.x <= bottom_right.x);
+            prop_assert!(top_left.y <= tile.y && tile.y <= bottom_right.y);
+            prop_assert!(tile_box.inner_size_px.0 > 0 && tile_box.inner_size_px.1 > 0);
+        }
+
+        #[test]
+        fn prop_radius_for_zoom_picks_the_zoom(
+            center in lat_long(),
+            zoom in 0u32..=MAX_ZOOM,
+            image_size_px in 16u32..4096,
+        ) {
+            let radius_km = radius_for_zoom(zoom, image_size_px);
+            let tile_box = lat_long_and_image_size_to_bounding_box(center, radius_km, image_size_px);
+            prop_assert_eq!(tile_box.tile_box.top_left.z, zoom);
+        }
+    }
+}
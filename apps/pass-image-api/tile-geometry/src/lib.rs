@@ -0,0 +1,23 @@
+// ! # tile-geometry
+// ! The math that decides where things land on a rendered map: converting between
+// ! lat/longs, tiles and pixels, picking the zoom and tiles for an image, and projecting
+// ! points into the finished image. tile-render draws with it, and the front end runs the
+// ! same code compiled to WASM, so overlays on interactive maps line up with the static
+// ! images pixel for pixel.
+// !
+// ! The crate is no_std and doesn't allocate. The one thing it takes from std is float
+// ! math, which all goes through the math module; targets without std build with
+// ! --no-default-features --features libm instead. Build with the wasm feature for the
+// ! JavaScript bindings:
+// ! `wasm-pack build tile-geometry --target web -- --features wasm`.
+
+#![no_std]
+
+#[cfg(feature = "std")]
+extern crate std;
+
+pub mod coordinates;
+mod math;
+pub mod viewport;
+#[cfg(feature = "wasm")]
+pub mod wasm;
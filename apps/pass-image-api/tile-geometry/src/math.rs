@@ -0,0 +1,96 @@
+// ! # math
+// ! Float functions core doesn't have. These come from std, or from libm for no_std
+// ! targets: build those with --no-default-features --features libm.
+
+#[cfg(not(any(feature = "std", feature = "libm")))]
+compile_error!("tile-geometry needs the std or the libm feature for float math");
+
+#[cfg(feature = "std")]
+pub fn tan(x: f64) -> f64 {
+    x.tan()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn tan(x: f64) -> f64 {
+    libm::tan(x)
+}
+
+#[cfg(feature = "std")]
+pub fn cos(x: f64) -> f64 {
+    x.cos()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn cos(x: f64) -> f64 {
+    libm::cos(x)
+}
+
+#[cfg(feature = "std")]
+pub fn ln(x: f64) -> f64 {
+    x.ln()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn ln(x: f64) -> f64 {
+    libm::log(x)
+}
+
+#[cfg(feature = "std")]
+pub fn sinh(x: f64) -> f64 {
+    x.sinh()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn sinh(x: f64) -> f64 {
+    libm::sinh(x)
+}
+
+#[cfg(feature = "std")]
+pub fn atan(x: f64) -> f64 {
+    x.atan()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn atan(x: f64) -> f64 {
+    libm::atan(x)
+}
+
+#[cfg(feature = "std")]
+pub fn powi(x: f64, n: i32) -> f64 {
+    x.powi(n)
+}
+
+#[cfg(not(feature = "std"))]
+pub fn powi(x: f64, n: i32) -> f64 {
+    libm::pow(x, n as f64)
+}
+
+#[cfg(feature = "std")]
+pub fn floor(x: f32) -> f32 {
+    x.floor()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn floor(x: f32) -> f32 {
+    libm::floorf(x)
+}
+
+#[cfg(feature = "std")]
+pub fn ceil(x: f32) -> f32 {
+    x.ceil()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn ceil(x: f32) -> f32 {
+    libm::ceilf(x)
+}
+
+#[cfg(feature = "std")]
+pub fn round(x: f32) -> f32 {
+    x.round()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn round(x: f32) -> f32 {
+    libm::roundf(x)
+}
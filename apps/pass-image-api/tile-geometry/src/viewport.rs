@@ -0,0 +1,178 @@
+// ! # viewport
+// ! Where a rendered image sits in the world. The tiles for an image are stitched together
+// ! and cropped around its center, and possibly resized, and a Viewport follows those
+// ! steps so lat/longs can be put onto the finished image.
+
+use crate::coordinates::{
+    lat_long_and_image_size_to_bounding_box, lat_long_to_pixel, lat_long_to_tile_coords,
+    pixel_to_lat_long, ConstrainedTileBox, LatLong,
+};
+use crate::math;
+
+// Where the output image sits in the world, so we can put lat/longs onto it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Viewport {
+    pub zoom: u32,
+    // Global pixel coordinates of the image's top left corner at this zoom
+    pub origin: (f64, f64),
+    // Image pixels per global pixel, for images that have been resized
+    pub scale: f64,
+}
+
+impl Viewport {
+    // Projects a lat/long into pixel coordinates within the image
+    pub fn project(&self, point: &LatLong) -> (f64, f64) {
+        let (x, y) = lat_long_to_pixel(point, self.zoom);
+        (
+            (x - self.origin.0) * self.scale,
+            (y - self.origin.1) * self.scale,
+        )
+    }
+
+    // The inverse of project
+    pub fn unproject(&self, x: f64, y: f64) -> LatLong {
+        pixel_to_lat_long(
+            x / self.scale + self.origin.0,
+            y / self.scale + self.origin.1,
+            self.zoom,
+        )
+    }
+
+    // The same viewport after the image has been resized by the given factor
+    pub fn scaled(&self, factor: f64) -> Viewport {
+        Viewport {
+            scale: self.scale * factor,
+            ..*self
+        }
+    }
+}
+
+// Works out where the output image sits within the stitched tiles of a
+// ConstrainedTileBox, returning the crop offset and the output's Viewport.
+pub fn crop_window(tile_box: &ConstrainedTileBox) -> ((u32, u32), Viewport) {
+    // Each tile is 256x256 pixels
+    let tile_size = 256;
+
+    // Work out the offsets from the left and top of the image, so that we can
+    let center_pos_abs =
+        lat_long_to_tile_coords(&tile_box.center, tile_box.tile_box.bottom_right.z);
+    let center_x_tile_offset = center_pos_abs.x - tile_box.tile_box.outer_top_left().0 as f32;
+    let center_y_tile_offset = center_pos_abs.y - tile_box.tile_box.outer_top_left().1 as f32;
+    let center_x_px = (center_x_tile_offset * 256.0) as u32;
+    let center_y_px = (center_y_tile_offset * 256.0) as u32;
+
+    // Offset in by half the targeted radius, in pixels
+    // We can then use the full radius as the width and height, and we end up centered where we should
+    // be centered. Near the edge of the world the box overhangs it and there are no tiles
+    // to the left or above, so the crop stops at the edge and the center moves off-middle.
+    let offset_left = center_x_px.saturating_sub(tile_box.inner_size_px.0 / 2);
+    let offset_top = center_y_px.saturating_sub(tile_box.inner_size_px.1 / 2);
+
+    let (outer_left, outer_top) = tile_box.tile_box.outer_top_left();
+    let viewport = Viewport {
+        zoom: tile_box.tile_box.top_left.z,
+        origin: (
+            (outer_left * tile_size + offset_left) as f64,
+            (outer_top * tile_size + offset_top) as f64,
+        ),
+        scale: 1.0,
+    };
+
+    ((offset_left, offset_top), viewport)
+}
+
+// The size of the finished image, given the size of the cropped mosaic: exactly
+// image_size square with exact_size, and then resized by scale
+pub fn output_size(
+    mosaic_size: (u32, u32),
+    image_size: u32,
+    exact_size: bool,
+    scale: Option<f32>,
+) -> (u32, u32) {
+    let (width, height) = if exact_size {
+        (image_size, image_size)
+    } else {
+        mosaic_size
+    };
+    match scale {
+        Some(scale) => (
+            (math::round(width as f32 * scale) as u32).max(1),
+            (math::round(height as f32 * scale) as u32).max(1),
+        ),
+        None => (width, height),
+    }
+}
+
+// The size and Viewport of the image rendered for a center, radius and size, before any
+// frame is added around it. This follows the same steps as rendering, without the tiles.
+pub fn image_viewport(
+    center: LatLong,
+    radius_km: f32,
+    image_size: u32,
+    exact_size: bool,
+    scale: Option<f32>,
+) -> ((u32, u32), Viewport) {
+    let tile_box = lat_long_and_image_size_to_bounding_box(center, radius_km, image_size);
+    let (_, viewport) = crop_window(&tile_box);
+    let mosaic_size = tile_box.inner_size_px;
+    let (width, height) = output_size(mosaic_size, image_size, exact_size, scale);
+    if (width, height) == mosaic_size {
+        ((width, height), viewport)
+    } else {
+        let factor = width as f64 / mosaic_size.0 as f64;
+        ((width, height), viewport.scaled(factor))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coordinates::tests::{lat_long, radius_km};
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_image_viewport() {
+        let center = LatLong(46.655559, 8.102121);
+        let ((width, height), viewport) = image_viewport(center, 2.0, 512, false, None);
+        assert_eq!(width, height);
+        assert!(width > 512);
+
+        // The center lands in the middle of the image, give or take the crop's rounding
+        let (x, y) = viewport.project(&center);
+        assert!((x - width as f64 / 2.0).abs() <= 1.0);
+        assert!((y - height as f64 / 2.0).abs() <= 1.0);
+        let LatLong(lat, long) = viewport.unproject(x, y);
+        assert!((lat - center.0).abs() < 1e-9 && (long - center.1).abs() < 1e-9);
+
+        // Exact and scaled images shrink the viewport along with them
+        let (size, exact) = image_viewport(center, 2.0, 512, true, Some(0.5));
+        assert_eq!(size, (256, 256));
+        assert_eq!(exact.origin, viewport.origin);
+        assert!((exact.scale - 256.0 / width as f64).abs() < 1e-12);
+    }
+
+    proptest! {
+        // The crop lies within the stitched tiles, whose top left tile is the box's
+        // outer_top_left, and the center lands inside the image
+        #[test]
+        fn prop_crop_inside_tiles(
+            center in lat_long(),
+            radius_km in radius_km(),
+            image_size_px in 16u32..4096,
+        ) {
+            let tile_box = lat_long_and_image_size_to_bounding_box(center, radius_km, image_size_px);
+            let ((left, top), viewport) = crop_window(&tile_box);
+            let (width, height) = tile_box.inner_size_px;
+            let bottom_right = tile_box.tile_box.bottom_right;
+            let (outer_left, outer_top) = tile_box.tile_box.outer_top_left();
+            let mosaic_width = (math::ceil(bottom_right.x) as u32 - outer_left + 1) * 256;
+            let mosaic_height = (math::ceil(bottom_right.y) as u32 - outer_top + 1) * 256;
+            prop_assert!(left + width <= mosaic_width);
+            prop_assert!(top + height <= mosaic_height);
+
+            let (x, y) = viewport.project(&center);
+            prop_assert!((0.0..=width as f64).contains(&x), "x {} of {}", x, width);
+            prop_assert!((0.0..=height as f64).contains(&y), "y {} of {}", y, height);
+        }
+    }
+}
@@ -0,0 +1,85 @@
+// ! # wasm
+// ! JavaScript bindings, so an interactive map can place things exactly where the static
+// ! image puts them. A MapView is built from the same center, radius and size as the
+// ! image request:
+// !
+// ! ```js
+// ! const view = new MapView(46.6555, 8.1021, 2.0, 512, true, undefined);
+// ! const [x, y] = view.project(46.6555, 8.1021); // [256, 256]
+// ! ```
+
+use crate::coordinates::LatLong;
+use crate::viewport::{image_viewport, Viewport};
+use std::vec::Vec;
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen]
+pub struct MapView {
+    viewport: Viewport,
+    width: u32,
+    height: u32,
+}
+
+#[wasm_bindgen]
+impl MapView {
+    // The image for GET /images/{long}/{lat}/{size}?radius=..&exact=..&scale=..
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        lat: f64,
+        long: f64,
+        radius_km: f32,
+        size_px: u32,
+        exact: bool,
+        scale: Option<f32>,
+    ) -> MapView {
+        let ((width, height), viewport) =
+            image_viewport(LatLong(lat, long), radius_km, size_px, exact, scale);
+        MapView {
+            viewport,
+            width,
+            height,
+        }
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn zoom(&self) -> u32 {
+        self.viewport.zoom
+    }
+
+    // The pixel a lat/long lands on, as [x, y]
+    pub fn project(&self, lat: f64, long: f64) -> Vec<f64> {
+        let (x, y) = self.viewport.project(&LatLong(lat, long));
+        std::vec![x, y]
+    }
+
+    // The lat/long under a pixel, as [lat, long]
+    pub fn unproject(&self, x: f64, y: f64) -> Vec<f64> {
+        let LatLong(lat, long) = self.viewport.unproject(x, y);
+        std::vec![lat, long]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map_view() {
+        let view = MapView::new(46.6555, 8.1021, 2.0, 512, true, None);
+        assert_eq!((view.width(), view.height()), (512, 512));
+        let center = view.project(46.6555, 8.1021);
+        assert!((center[0] - 256.0).abs() <= 1.0 && (center[1] - 256.0).abs() <= 1.0);
+        let back = view.unproject(center[0], center[1]);
+        assert!((back[0] - 46.6555).abs() < 1e-9 && (back[1] - 8.1021).abs() < 1e-9);
+    }
+}
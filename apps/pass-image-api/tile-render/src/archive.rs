@@ -0,0 +1,139 @@
+// ! # archive
+// ! Maps as they looked at a given date, where a tileset's provider publishes an archive.
+// ! Swisstopo's Zeitreise ("journey through time") has its national maps going back to
+// ! 1844, one edition a year, so a pass area can be rendered as it was in a given year.
+// ! NASA GIBS has daily imagery, but only down to zoom 9, far too coarse for a pass, so it
+// ! isn't a tileset here.
+// !
+// ! A render asks for a date by running in scope with it, as locale::scope gives it its
+// ! languages. Tilesets with an archive are then fetched from the edition for that date,
+// ! and the rest as they are today. Archived tiles are cached apart from current ones,
+// ! under <tileset>@<time>/, and as they never change they're left out of refreshes.
+
+use crate::degradations::{self, Degradation};
+use crate::tiles::TileSet;
+use anyhow::{anyhow, Result};
+use std::future::Future;
+
+// A day, as asked for with date=YYYY or date=YYYY-MM-DD. A year alone is its last day.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Date {
+    pub year: u32,
+    pub month: u32,
+    pub day: u32,
+}
+
+impl Date {
+    pub fn parse(date: &str) -> Result<Date> {
+        let invalid = || anyhow!("Invalid date {}, expected YYYY or YYYY-MM-DD", date);
+        let mut parts = date.split('-');
+        let year = parts.next().ok_or_else(invalid)?;
+        let (month, day) = match (parts.next(), parts.next(), parts.next()) {
+            (None, None, None) => ("12", "31"),
+            (Some(month), Some(day), None) => (month, day),
+            _ => return Err(invalid()),
+        };
+        let number = |part: &str, len: usize| -> Result<u32> {
+            if part.len() != len || !part.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(invalid());
+            }
+            part.parse().map_err(|_| invalid())
+        };
+        let date = Date {
+            year: number(year, 4)?,
+            month: number(month, 2)?,
+            day: number(day, 2)?,
+        };
+        if !(1..=12).contains(&date.month) || !(1..=31).contains(&date.day) {
+            return Err(invalid());
+        }
+        Ok(date)
+    }
+}
+
+// A tileset's archive: the year its earliest edition is from, and a URL pattern with a
+// {time} placeholder for the edition
+struct Archive {
+    tileset: TileSet,
+    first_year: u32,
+    pattern: &'static str,
+}
+
+const ARCHIVES: &[Archive] = &[Archive {
+    tileset: TileSet::Swisstopo,
+    first_year: 1844,
+    pattern: "https://wmts.geo.admin.ch/1.0.0/ch.swisstopo.zeitreihen/default/{time}/3857/{z}/{x}/{y}.png",
+}];
+
+tokio::task_local! {
+    static DATE: Date;
+}
+
+// Runs a future showing the maps as they were at the given date. Only fetches made
+// within the future itself see it.
+pub async fn scope<F: Future>(date: Date, f: F) -> F::Output {
+    DATE.scope(date, f).await
+}
+
+// The date the current scope shows the maps at, if there is one
+pub fn date() -> Option<Date> {
+    DATE.try_with(|date| *date).ok()
+}
+
+// The edition and URL pattern the tileset should be fetched with under the current
+// scope, if it has an archive. Dates before the archive starts get its first edition.
+// Swisstopo's editions are named for the last day of their year, e.g. 18641231.
+pub fn variant(tileset: TileSet) -> Option<(String, String)> {
+    let date = date()?;
+    let archive = ARCHIVES.iter().find(|a| a.tileset == tileset)?;
+    if date.year < archive.first_year {
+        degradations::record(Degradation::DateClamped {
+            tileset: tileset.name(),
+            year: archive.first_year,
+        });
+    }
+    let time = format!("{}1231", date.year.max(archive.first_year));
+    let pattern = archive.pattern.replace("{time}", &time);
+    Some((time, pattern))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(
+            Date::parse("1950").unwrap(),
+            Date {
+                year: 1950,
+                month: 12,
+                day: 31
+            }
+        );
+        assert_eq!(Date::parse("2003-07-15").unwrap().month, 7);
+        for invalid in ["", "50", "1950-7-15", "1950-13-01", "1950-07", "yesterday"] {
+            assert!(Date::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_variant() {
+        assert_eq!(variant(TileSet::Swisstopo), None);
+        let date = Date::parse("1950-06-01").unwrap();
+        let (swisstopo, osm) = scope(date, async {
+            (variant(TileSet::Swisstopo), variant(TileSet::Osm))
+        })
+        .await;
+        let (time, pattern) = swisstopo.unwrap();
+        assert_eq!(time, "19501231");
+        assert!(pattern.contains("/ch.swisstopo.zeitreihen/default/19501231/"));
+        assert_eq!(osm, None);
+
+        let before = scope(Date::parse("1800").unwrap(), async {
+            variant(TileSet::Swisstopo)
+        })
+        .await;
+        assert_eq!(before.unwrap().0, "18441231");
+    }
+}
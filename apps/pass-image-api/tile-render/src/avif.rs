@@ -0,0 +1,103 @@
+// ! # avif
+// ! AVIF output, for format=avif. AVIF is far smaller than PNG for photographic imagery,
+// ! like aerial and satellite basemaps, but much slower to encode, so images are encoded
+// ! on tokio's blocking thread pool instead of the task that rendered them.
+// !
+// ! quality= trades size for fidelity per request, from 1 to 100, and defaults to
+// ! AVIF_QUALITY. AVIF_SPEED, from 1 (slowest, smallest) to 10 (fastest), sets how much
+// ! CPU the encoder spends on each image. AVIFs are encoded in sRGB and say so in their
+// ! color information, so OUTPUT_ICC_PROFILE only applies to PNGs.
+
+use anyhow::{anyhow, Context as _, Result};
+use bytes::Bytes;
+use image::codecs::avif::{AvifEncoder, ColorSpace};
+use image::{ExtendedColorType, ImageEncoder, RgbaImage};
+use std::env;
+use std::sync::OnceLock;
+
+pub const CONTENT_TYPE: &str = "image/avif";
+
+const DEFAULT_QUALITY: u8 = 70;
+const DEFAULT_SPEED: u8 = 6;
+
+// Reads a setting from 1 to max from the environment
+fn setting(name: &str, max: u8, default: u8) -> u8 {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|value| (1..=max).contains(value))
+        .unwrap_or(default)
+}
+
+// The quality images are encoded at when a request doesn't say, from AVIF_QUALITY
+pub fn default_quality() -> u8 {
+    static QUALITY: OnceLock<u8> = OnceLock::new();
+    *QUALITY.get_or_init(|| setting("AVIF_QUALITY", 100, DEFAULT_QUALITY))
+}
+
+// How hard the encoder tries, from AVIF_SPEED
+fn speed() -> u8 {
+    static SPEED: OnceLock<u8> = OnceLock::new();
+    *SPEED.get_or_init(|| setting("AVIF_SPEED", 10, DEFAULT_SPEED))
+}
+
+pub fn validate_quality(quality: u8) -> Result<u8> {
+    if !(1..=100).contains(&quality) {
+        return Err(anyhow!("quality must be between 1 and 100"));
+    }
+    Ok(quality)
+}
+
+// Encodes an image as an AVIF, on the blocking thread pool
+pub async fn encode(image: RgbaImage, quality: u8) -> Result<Bytes> {
+    let speed = speed();
+    tokio::task::spawn_blocking(move || encode_now(&image, quality, speed))
+        .await
+        .context("AVIF encoder panicked")?
+}
+
+fn encode_now(image: &RgbaImage, quality: u8, speed: u8) -> Result<Bytes> {
+    let mut avif = Vec::new();
+    AvifEncoder::new_with_speed_quality(&mut avif, speed, quality)
+        .with_colorspace(ColorSpace::Srgb)
+        .write_image(
+            image.as_raw(),
+            image.width(),
+            image.height(),
+            ExtendedColorType::Rgba8,
+        )
+        .context("encoding an AVIF")?;
+    Ok(Bytes::from(avif))
+}
+
+// Whether an encoded image is an AVIF, from the brand in its ftyp box
+pub fn is_avif(image: &[u8]) -> bool {
+    image.get(4..12) == Some(b"ftypavif")
+}
+
+// The content type and file extension of an encoded image: AVIF, or otherwise PNG, the
+// only other format images are rendered in
+pub fn image_type(image: &[u8]) -> (&'static str, &'static str) {
+    if is_avif(image) {
+        (CONTENT_TYPE, "avif")
+    } else {
+        ("image/png", "png")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    #[tokio::test]
+    async fn test_encode() {
+        let image = RgbaImage::from_pixel(64, 48, Rgba([40, 120, 200, 255]));
+        let avif = encode(image, 60).await.unwrap();
+        assert!(is_avif(&avif));
+        assert_eq!(image_type(&avif), (CONTENT_TYPE, "avif"));
+        assert_eq!(image_type(b"\x89PNG\r\n\x1a\n"), ("image/png", "png"));
+        assert!(validate_quality(0).is_err());
+        assert!(validate_quality(101).is_err());
+    }
+}
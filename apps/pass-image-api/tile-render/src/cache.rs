@@ -0,0 +1,288 @@
+// ! # cache
+// ! A cache in front of upstream tile servers, so each tile is only fetched from its
+// ! provider once. Tiles are kept by tileset and z/x/y, as <tileset>/<z>/<x>/<y>.png keys.
+// ! Where they're kept is up to the TileCache; the service keeps them in object storage.
+// ! Only tiles fetched over HTTP are cached, as MBTiles files and directories are local
+// ! already.
+// !
+// ! Tiles are checked by the integrity module on the way in and on the way out. A cached
+// ! tile that turns out to be corrupt is copied aside under quarantine/<key>, so it can
+// ! be looked at later, and fetched again over the top of it.
+
+use crate::fetcher::TileFetcher;
+use crate::integrity::{self, Found};
+use crate::tiles::TileSet;
+use crate::{archive, locale, report};
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use futures::future::LocalBoxFuture;
+use log::warn;
+use opentelemetry::Context;
+
+pub trait TileCache: Send + Sync {
+    // The cached tile, or None if it isn't cached
+    fn get(&self, key: &str) -> LocalBoxFuture<'_, Result<Option<Bytes>>>;
+    fn put(&self, key: &str, png: Bytes) -> LocalBoxFuture<'_, Result<()>>;
+}
+
+// Where a tile is kept in the cache. Tiles labelled in another language than the
+// tileset's default are kept apart, as <tileset>-<lang>/<z>/<x>/<y>.png, and archived
+// ones as <tileset>@<time>/<z>/<x>/<y>.png.
+pub fn tile_key(tileset: TileSet, x: u32, y: u32, z: u32) -> String {
+    if let Some((time, _)) = archive::variant(tileset) {
+        return format!("{}@{}/{}/{}/{}.png", tileset.name(), time, z, x, y);
+    }
+    match locale::variant(tileset) {
+        Some((lang, _)) => format!("{}-{}/{}/{}/{}.png", tileset.name(), lang, z, x, y),
+        None => format!("{}/{}/{}/{}.png", tileset.name(), z, x, y),
+    }
+}
+
+// A tile as kept in the cache, parsed back from its key
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedTile {
+    pub tileset: TileSet,
+    // The language it's labelled in, for tiles of a localized variant
+    pub lang: Option<String>,
+    pub x: u32,
+    pub y: u32,
+    pub z: u32,
+}
+
+impl CachedTile {
+    // Parses a key made by tile_key, or returns None for anything else
+    pub fn parse(key: &str) -> Option<CachedTile> {
+        let path = key.strip_suffix(".png")?;
+        let mut parts = path.split('/');
+        let first = parts.next()?;
+        let (name, lang) = match first.split_once('-') {
+            Some((name, lang)) => (name, Some(lang.to_string())),
+            None => (first, None),
+        };
+        let tileset = TileSet::all().into_iter().find(|t| t.name() == name)?;
+        let mut coord = || parts.next()?.parse::<u32>().ok();
+        let (z, x, y) = (coord()?, coord()?, coord()?);
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(CachedTile {
+            tileset,
+            lang,
+            x,
+            y,
+            z,
+        })
+    }
+}
+
+// Where a corrupt cached tile is moved aside to
+pub fn quarantine_key(key: &str) -> String {
+    format!("quarantine/{}", key)
+}
+
+// Serves a tile from the cache, or fetches it and caches it. The cache failing is logged
+// and otherwise ignored, so a broken cache only costs us the upstream fetch. Corrupt
+// cached tiles are quarantined and fetched again, and corrupt fetches are never cached.
+pub async fn fetch_through(
+    cache: &dyn TileCache,
+    fetcher: &dyn TileFetcher,
+    tileset: TileSet,
+    x: u32,
+    y: u32,
+    z: u32,
+    cx: Context,
+) -> Result<Bytes> {
+    let key = tile_key(tileset, x, y, z);
+    match cache.get(&key).await {
+        Ok(Some(tile)) => match integrity::check(&tile, tileset.tile_size()) {
+            Ok(()) => {
+                report::cache_hit();
+                return Ok(tile);
+            }
+            Err(e) => {
+                integrity::record_corrupt(tileset, Found::Cache);
+                warn!("Quarantining corrupt cached tile {}: {:#}", key, e);
+                if let Err(e) = cache.put(&quarantine_key(&key), tile).await {
+                    warn!("Couldn't quarantine tile {}: {:#}", key, e);
+                }
+            }
+        },
+        Ok(None) => {}
+        Err(e) => warn!("Couldn't read tile {} from the cache: {:#}", key, e),
+    }
+
+    let tile = integrity::fetch_checked(fetcher, tileset, x, y, z, cx).await?;
+    if let Err(e) = cache.put(&key, tile.clone()).await {
+        warn!("Couldn't cache tile {}: {:#}", key, e);
+    }
+    Ok(tile)
+}
+
+// Fetches a cached tile again and replaces it, e.g. to keep a popular tile fresh. Unlike
+// fetch_through, the cache failing is an error, as then nothing was refreshed.
+pub async fn refresh(
+    cache: &dyn TileCache,
+    fetcher: &dyn TileFetcher,
+    tile: &CachedTile,
+    cx: Context,
+) -> Result<()> {
+    let languages = tile.lang.iter().cloned().collect();
+    locale::scope(languages, async {
+        if tile.lang.as_deref() != locale::variant(tile.tileset).map(|(lang, _)| lang) {
+            return Err(anyhow!(
+                "{} has no tiles in {:?}",
+                tile.tileset.name(),
+                tile.lang
+            ));
+        }
+        let key = tile_key(tile.tileset, tile.x, tile.y, tile.z);
+        let png =
+            integrity::fetch_checked(fetcher, tile.tileset, tile.x, tile.y, tile.z, cx).await?;
+        cache.put(&key, png).await
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debug_tiles::debug_tile;
+    use crate::fetcher::MemoryFetcher;
+    use crate::tiles::encode_png;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct MemoryCache {
+        tiles: Mutex<HashMap<String, Bytes>>,
+    }
+
+    impl TileCache for MemoryCache {
+        fn get(&self, key: &str) -> LocalBoxFuture<'_, Result<Option<Bytes>>> {
+            let tile = self.tiles.lock().unwrap().get(key).cloned();
+            Box::pin(async move { Ok(tile) })
+        }
+
+        fn put(&self, key: &str, png: Bytes) -> LocalBoxFuture<'_, Result<()>> {
+            self.tiles.lock().unwrap().insert(key.to_string(), png);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    fn png() -> Bytes {
+        encode_png(debug_tile(1, 2, 3))
+    }
+
+    #[tokio::test]
+    async fn test_fetch_through() {
+        let cache = MemoryCache::default();
+        let upstream = MemoryFetcher::default().with_tile(TileSet::Osm, 1, 2, 3, png());
+
+        // A miss is fetched and cached, and then served from the cache alone
+        let tile = fetch_through(&cache, &upstream, TileSet::Osm, 1, 2, 3, Context::new())
+            .await
+            .unwrap();
+        assert_eq!(tile, png());
+        assert_eq!(
+            cache.tiles.lock().unwrap().get("osm/3/1/2.png"),
+            Some(&png())
+        );
+        let offline = MemoryFetcher::default();
+        let tile = fetch_through(&cache, &offline, TileSet::Osm, 1, 2, 3, Context::new())
+            .await
+            .unwrap();
+        assert_eq!(tile, png());
+
+        // Failed fetches aren't cached
+        assert!(
+            fetch_through(&cache, &offline, TileSet::Osm, 9, 9, 9, Context::new())
+                .await
+                .is_err()
+        );
+        assert_eq!(cache.tiles.lock().unwrap().len(), 1);
+
+        // Nor are corrupt ones
+        let corrupt = MemoryFetcher::default().with_fallback(Bytes::from_static(b"png"));
+        assert!(
+            fetch_through(&cache, &corrupt, TileSet::Osm, 9, 9, 9, Context::new())
+                .await
+                .is_err()
+        );
+        assert_eq!(cache.tiles.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_localized_tiles_are_kept_apart() {
+        assert_eq!(tile_key(TileSet::Osm, 1, 2, 3), "osm/3/1/2.png");
+        let key = locale::scope(vec!["de".to_string()], async {
+            tile_key(TileSet::Osm, 1, 2, 3)
+        })
+        .await;
+        assert_eq!(key, "osm-de/3/1/2.png");
+
+        let date = archive::Date::parse("1950").unwrap();
+        let key = archive::scope(date, async { tile_key(TileSet::Swisstopo, 1, 2, 3) }).await;
+        assert_eq!(key, "swisstopo@19501231/3/1/2.png");
+        // Archived tiles never change, so they aren't refreshed
+        assert_eq!(CachedTile::parse(&key), None);
+    }
+
+    #[test]
+    fn test_parse_cached_tile() {
+        let tile = CachedTile::parse("osm-de/3/1/2.png").unwrap();
+        assert_eq!(
+            (tile.tileset, tile.lang.as_deref(), tile.x, tile.y, tile.z),
+            (TileSet::Osm, Some("de"), 1, 2, 3)
+        );
+        assert_eq!(CachedTile::parse("terrain/3/1/2.png").unwrap().lang, None);
+        assert_eq!(CachedTile::parse("quarantine/osm/3/1/2.png"), None);
+        assert_eq!(CachedTile::parse("osm/3/1.png"), None);
+        assert_eq!(CachedTile::parse("mars/3/1/2.png"), None);
+    }
+
+    #[tokio::test]
+    async fn test_refresh() {
+        let cache = MemoryCache::default();
+        let stale = encode_png(debug_tile(0, 0, 0));
+        cache
+            .tiles
+            .lock()
+            .unwrap()
+            .insert("osm-fr/3/1/2.png".to_string(), stale);
+
+        let upstream = MemoryFetcher::default().with_tile(TileSet::Osm, 1, 2, 3, png());
+        let tile = CachedTile::parse("osm-fr/3/1/2.png").unwrap();
+        refresh(&cache, &upstream, &tile, Context::new())
+            .await
+            .unwrap();
+        assert_eq!(
+            cache.tiles.lock().unwrap().get("osm-fr/3/1/2.png"),
+            Some(&png())
+        );
+
+        let unknown = CachedTile::parse("osm-xx/3/1/2.png").unwrap();
+        assert!(refresh(&cache, &upstream, &unknown, Context::new())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_hits_are_quarantined() {
+        let cache = MemoryCache::default();
+        let truncated = png().slice(..100);
+        cache
+            .tiles
+            .lock()
+            .unwrap()
+            .insert("osm/3/1/2.png".to_string(), truncated.clone());
+
+        let upstream = MemoryFetcher::default().with_tile(TileSet::Osm, 1, 2, 3, png());
+        let tile = fetch_through(&cache, &upstream, TileSet::Osm, 1, 2, 3, Context::new())
+            .await
+            .unwrap();
+        assert_eq!(tile, png());
+        let tiles = cache.tiles.lock().unwrap();
+        assert_eq!(tiles.get("osm/3/1/2.png"), Some(&png()));
+        assert_eq!(tiles.get("quarantine/osm/3/1/2.png"), Some(&truncated));
+    }
+}
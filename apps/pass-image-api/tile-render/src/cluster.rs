@@ -0,0 +1,179 @@
+// ! # cluster
+// ! Marker clustering for dense point sets. Hundreds of markers drawn at a zoom where they
+// ! overlap just make a blob, so nearby markers are merged into a single badge showing how
+// ! many it stands for. Clustering is greedy: each marker joins the first cluster started
+// ! within CLUSTER_RADIUS_PX of it, found through a grid so only neighbouring cells are
+// ! searched, or starts a cluster of its own.
+
+use crate::coordinates::LatLong;
+use crate::overlay::{Overlay, Viewport};
+use image::Rgba;
+use std::collections::HashMap;
+
+// Overlays with at least this many markers are clustered unless the caller says otherwise
+pub const AUTO_CLUSTER_POINTS: usize = 100;
+
+// How close a marker has to be to a cluster to join it
+const CLUSTER_RADIUS_PX: f64 = 40.0;
+
+// Size of a badge for a handful of markers; it grows with the count
+const BADGE_RADIUS: f32 = 10.0;
+
+struct Cluster {
+    // Where the cluster was started, in image pixels
+    seed: (f64, f64),
+    // Indices of the overlays that are in it
+    members: Vec<usize>,
+}
+
+// Whether to cluster the overlays' markers. Without an explicit choice, only overlays
+// with a lot of markers are clustered.
+pub fn should_cluster(overlays: &[Overlay], requested: Option<bool>) -> bool {
+    requested.unwrap_or_else(|| {
+        overlays
+            .iter()
+            .filter(|o| matches!(o, Overlay::Point { .. }))
+            .count()
+            >= AUTO_CLUSTER_POINTS
+    })
+}
+
+// Replaces groups of nearby markers with cluster badges at the viewport's zoom. Lines and
+// markers left on their own are kept as they are.
+pub fn cluster_markers(overlays: &[Overlay], viewport: &Viewport) -> Vec<Overlay> {
+    let cell_of = |(x, y): (f64, f64)| {
+        (
+            (x / CLUSTER_RADIUS_PX).floor() as i64,
+            (y / CLUSTER_RADIUS_PX).floor() as i64,
+        )
+    };
+
+    let mut clusters: Vec<Cluster> = Vec::new();
+    let mut grid: HashMap<(i64, i64), Vec<usize>> = HashMap::new();
+    for (i, overlay) in overlays.iter().enumerate() {
+        let Overlay::Point { point, .. } = overlay else {
+            continue;
+        };
+        let position = viewport.project(point);
+        let (cx, cy) = cell_of(position);
+
+        let nearby = (cx - 1..=cx + 1)
+            .flat_map(|x| (cy - 1..=cy + 1).map(move |y| (x, y)))
+            .filter_map(|cell| grid.get(&cell))
+            .flatten()
+            .copied()
+            .filter(|c| {
+                let seed = clusters[*c].seed;
+                (seed.0 - position.0).hypot(seed.1 - position.1) <= CLUSTER_RADIUS_PX
+            })
+            .min();
+
+        match nearby {
+            Some(c) => clusters[c].members.push(i),
+            None => {
+                grid.entry((cx, cy)).or_default().push(clusters.len());
+                clusters.push(Cluster {
+                    seed: position,
+                    members: vec![i],
+                });
+            }
+        }
+    }
+
+    // A badge takes the place of its cluster's first marker, so overlapping overlays stay
+    // in order, and the other markers go
+    let mut replacements: HashMap<usize, Option<Overlay>> = HashMap::new();
+    for cluster in clusters.iter().filter(|c| c.members.len() > 1) {
+        replacements.insert(cluster.members[0], Some(badge(overlays, &cluster.members)));
+        for member in &cluster.members[1..] {
+            replacements.insert(*member, None);
+        }
+    }
+
+    overlays
+        .iter()
+        .enumerate()
+        .filter_map(|(i, overlay)| {
+            replacements
+                .remove(&i)
+                .unwrap_or_else(|| Some(overlay.clone()))
+        })
+        .collect()
+}
+
+// A badge at the middle of the cluster's markers, in the first marker's color
+fn badge(overlays: &[Overlay], members: &[usize]) -> Overlay {
+    let points: Vec<(LatLong, Rgba<u8>)> = members
+        .iter()
+        .filter_map(|i| match &overlays[*i] {
+            Overlay::Point { point, color, .. } => Some((*point, *color)),
+            _ => None,
+        })
+        .collect();
+    let count = points.len();
+    let lat = points.iter().map(|(p, _)| p.0).sum::<f64>() / count as f64;
+    let long = points.iter().map(|(p, _)| p.1).sum::<f64>() / count as f64;
+
+    Overlay::Cluster {
+        point: LatLong(lat, long),
+        count: count as u32,
+        color: points[0].1,
+        radius: BADGE_RADIUS + 4.0 * (count as f32).log10(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coordinates::pixel_to_lat_long;
+
+    const VIEWPORT: Viewport = Viewport {
+        zoom: 10,
+        origin: (0.0, 0.0),
+        scale: 1.0,
+    };
+
+    // A marker at the given image pixel
+    fn marker(x: f64, y: f64) -> Overlay {
+        let point = pixel_to_lat_long(x, y, VIEWPORT.zoom);
+        Overlay::Point {
+            point,
+            color: Rgba([0, 0, 255, 255]),
+            radius: 6.0,
+            label: None,
+            symbol: None,
+        }
+    }
+
+    #[test]
+    fn test_should_cluster() {
+        let few = vec![marker(0.0, 0.0); 3];
+        let many = vec![marker(0.0, 0.0); AUTO_CLUSTER_POINTS];
+        assert!(!should_cluster(&few, None));
+        assert!(should_cluster(&many, None));
+        assert!(should_cluster(&few, Some(true)));
+        assert!(!should_cluster(&many, Some(false)));
+    }
+
+    #[test]
+    fn test_cluster_markers() {
+        let overlays = vec![
+            marker(100.0, 100.0),
+            marker(110.0, 105.0),
+            marker(500.0, 500.0),
+            marker(95.0, 120.0),
+        ];
+        let clustered = cluster_markers(&overlays, &VIEWPORT);
+        assert_eq!(clustered.len(), 2);
+        assert!(matches!(clustered[0], Overlay::Cluster { count: 3, .. }));
+        assert_eq!(clustered[1], overlays[2]);
+    }
+
+    #[test]
+    fn test_clusters_across_grid_cells() {
+        // Either side of a cell boundary, but close together
+        let overlays = vec![marker(79.0, 10.0), marker(81.0, 10.0)];
+        let clustered = cluster_markers(&overlays, &VIEWPORT);
+        assert!(matches!(clustered[..], [Overlay::Cluster { count: 2, .. }]));
+    }
+}
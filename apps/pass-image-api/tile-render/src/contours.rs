@@ -0,0 +1,219 @@
+// ! # contours
+// ! Elevation contour lines traced from an ElevationGrid with marching squares. Every
+// ! fifth contour is an index contour: drawn heavier and labelled with its height.
+
+use crate::dem::ElevationGrid;
+use crate::overlay::{draw_segments, Segment};
+use crate::text::{draw_text, fill_rect, text_height, text_width};
+use anyhow::{anyhow, Result};
+use image::{Rgba, RgbaImage};
+
+// The range of contour intervals accepted, in meters
+pub const MIN_INTERVAL_M: f32 = 5.0;
+pub const MAX_INTERVAL_M: f32 = 1000.0;
+
+// Every nth contour is an index contour
+const INDEX_EVERY: i64 = 5;
+
+const CONTOUR_COLOR: Rgba<u8> = Rgba([140, 90, 40, 150]);
+const INDEX_COLOR: Rgba<u8> = Rgba([140, 90, 40, 220]);
+const LABEL_BACKGROUND: Rgba<u8> = Rgba([255, 255, 255, 170]);
+
+// Labels are kept at least this far apart, and from the edges of the image
+const LABEL_SPACING_PX: f64 = 200.0;
+const LABEL_MARGIN_PX: f64 = 16.0;
+
+pub fn validate_interval(interval: f32) -> Result<f32> {
+    if interval.is_finite() && (MIN_INTERVAL_M..=MAX_INTERVAL_M).contains(&interval) {
+        Ok(interval)
+    } else {
+        Err(anyhow!(
+            "contours must be between {} and {} meters",
+            MIN_INTERVAL_M,
+            MAX_INTERVAL_M
+        ))
+    }
+}
+
+// Contour segments for every multiple of the interval, in pixel coordinates, along with
+// the contour number (height / interval) each belongs to
+fn trace(grid: &ElevationGrid, interval: f32) -> Vec<(i64, Segment)> {
+    let mut segments = Vec::new();
+    if grid.width < 2 || grid.height < 2 {
+        return segments;
+    }
+
+    for y in 0..grid.height - 1 {
+        for x in 0..grid.width - 1 {
+            // Corners clockwise from the top left, at pixel centers
+            let corners = [
+                ((x as f64 + 0.5, y as f64 + 0.5), grid.get(x, y)),
+                ((x as f64 + 1.5, y as f64 + 0.5), grid.get(x + 1, y)),
+                ((x as f64 + 1.5, y as f64 + 1.5), grid.get(x + 1, y + 1)),
+                ((x as f64 + 0.5, y as f64 + 1.5), grid.get(x, y + 1)),
+            ];
+            if corners.iter().any(|(_, h)| h.is_nan()) {
+                continue;
+            }
+
+            let min = corners.iter().map(|c| c.1).fold(f32::INFINITY, f32::min);
+            let max = corners
+                .iter()
+                .map(|c| c.1)
+                .fold(f32::NEG_INFINITY, f32::max);
+            let first = (min / interval).floor() as i64 + 1;
+            let last = (max / interval).floor() as i64;
+            for n in first..=last {
+                let level = n as f32 * interval;
+                for segment in cell_segments(&corners, level) {
+                    segments.push((n, segment));
+                }
+            }
+        }
+    }
+    segments
+}
+
+// Marching squares for a single cell: the segments where the level crosses it
+fn cell_segments(corners: &[((f64, f64), f32); 4], level: f32) -> Vec<Segment> {
+    let above = corners.map(|(_, h)| h >= level);
+
+    // Where the level crosses each edge (top, right, bottom, left), if it does
+    let crossing = |i: usize| {
+        let (a, b) = (corners[i], corners[(i + 1) % 4]);
+        if above[i] == above[(i + 1) % 4] {
+            return None;
+        }
+        let t = ((level - a.1) / (b.1 - a.1)) as f64;
+        Some((
+            a.0 .0 + t * (b.0 .0 - a.0 .0),
+            a.0 .1 + t * (b.0 .1 - a.0 .1),
+        ))
+    };
+    let edges: Vec<(usize, (f64, f64))> =
+        (0..4).filter_map(|i| crossing(i).map(|p| (i, p))).collect();
+
+    match edges.as_slice() {
+        [(_, a), (_, b)] => vec![(*a, *b)],
+        [top, right, bottom, left] => {
+            // A saddle. Use the average of the corners to decide which pair of opposite
+            // corners is joined through the middle, and cut off the other two.
+            let center = corners.iter().map(|c| c.1).sum::<f32>() / 4.0;
+            if above[0] != (center >= level) {
+                vec![(left.1, top.1), (right.1, bottom.1)]
+            } else {
+                vec![(top.1, right.1), (bottom.1, left.1)]
+            }
+        }
+        _ => Vec::new(),
+    }
+}
+
+// Draws contours every `interval` meters, labelling the index contours
+pub fn draw_contours(img: &mut RgbaImage, grid: &ElevationGrid, interval: f32) {
+    let traced = trace(grid, interval);
+    let is_index = |n: i64| n % INDEX_EVERY == 0;
+
+    let (index, regular): (Vec<_>, Vec<_>) = traced.iter().partition(|(n, _)| is_index(*n));
+    let segments = |traced: &[&(i64, Segment)]| traced.iter().map(|(_, s)| *s).collect::<Vec<_>>();
+    draw_segments(img, &segments(&regular), 1.0, CONTOUR_COLOR);
+    draw_segments(img, &segments(&index), 2.0, INDEX_COLOR);
+
+    // Label index contours, skipping spots too close to an existing label or the edge
+    let scale = if img.width() >= 1024 { 2 } else { 1 };
+    let mut placed: Vec<(f64, f64)> = Vec::new();
+    for (n, (a, b)) in index {
+        let (x, y) = ((a.0 + b.0) / 2.0, (a.1 + b.1) / 2.0);
+        let inside = x > LABEL_MARGIN_PX
+            && y > LABEL_MARGIN_PX
+            && x < img.width() as f64 - LABEL_MARGIN_PX
+            && y < img.height() as f64 - LABEL_MARGIN_PX;
+        let clear = placed
+            .iter()
+            .all(|(px, py)| (px - x).hypot(py - y) >= LABEL_SPACING_PX);
+        if inside && clear {
+            let label = format!("{}", (*n as f32 * interval).round() as i64);
+            draw_label(img, x, y, &label, scale);
+            placed.push((x, y));
+        }
+    }
+}
+
+// Draws a label centered on (x, y), on a translucent background so it reads over the map
+fn draw_label(img: &mut RgbaImage, x: f64, y: f64, label: &str, scale: u32) {
+    let padding = scale;
+    let width = text_width(label, scale) + 2 * padding;
+    let height = text_height(scale) + 2 * padding;
+    let left = x as i64 - width as i64 / 2;
+    let top = y as i64 - height as i64 / 2;
+    fill_rect(img, left, top, width, height, LABEL_BACKGROUND);
+    draw_text(
+        img,
+        left + padding as i64,
+        top + padding as i64,
+        label,
+        scale,
+        INDEX_COLOR,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A slope rising 10m per pixel to the east
+    fn ramp(width: u32, height: u32) -> ElevationGrid {
+        let data = (0..height)
+            .flat_map(|_| (0..width).map(|x| x as f32 * 10.0))
+            .collect();
+        ElevationGrid::new(width, height, data, 10.0)
+    }
+
+    #[test]
+    fn test_trace_ramp() {
+        let grid = ramp(11, 4);
+        let segments = trace(&grid, 25.0);
+
+        // Heights run 0..100, so there are contours at 25, 50, 75 and 100, each crossing
+        // the three rows of cells once
+        assert_eq!(segments.len(), 4 * 3);
+
+        // The 50m contour runs north-south through x = 5.5
+        for (_, (a, b)) in segments.iter().filter(|(n, _)| *n == 2) {
+            assert!((a.0 - 5.5).abs() < 1e-6 && (b.0 - 5.5).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_saddle() {
+        let corners = [
+            ((0.0, 0.0), 10.0),
+            ((1.0, 0.0), 0.0),
+            ((1.0, 1.0), 10.0),
+            ((0.0, 1.0), 0.0),
+        ];
+        assert_eq!(cell_segments(&corners, 5.0).len(), 2);
+        assert!(cell_segments(&corners, 20.0).is_empty());
+    }
+
+    #[test]
+    fn test_nan_cells_are_skipped() {
+        let grid = ElevationGrid::new(2, 2, vec![0.0, 100.0, f32::NAN, 100.0], 10.0);
+        assert!(trace(&grid, 10.0).is_empty());
+    }
+
+    #[test]
+    fn test_draw_contours() {
+        let grid = ramp(64, 64);
+        let mut img = RgbaImage::new(64, 64);
+        draw_contours(&mut img, &grid, 50.0);
+        assert!(img.get_pixel(5, 32)[3] > 0);
+        assert_eq!(img.get_pixel(2, 32)[3], 0);
+    }
+
+    #[test]
+    fn test_validate_interval() {
+        assert!(validate_interval(50.0).is_ok());
+        assert!(validate_interval(0.5).is_err());
+    }
+}
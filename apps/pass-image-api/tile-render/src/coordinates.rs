@@ -0,0 +1,8 @@
+// ! # coordinates
+// !
+// ! Converting between latitude/longitude WGS84 pairs and webmercator slippy-maps style
+// ! tile coordinates. The math lives in the tile-geometry crate, which the front end also
+// ! builds to WASM, and is re-exported here.
+// !
+
+pub use tile_geometry::coordinates::*;
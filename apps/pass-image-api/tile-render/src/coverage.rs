@@ -0,0 +1,295 @@
+// ! # coverage
+// ! Where each tileset has tiles. Swisstopo only covers Switzerland, and a render outside
+// ! it used to fetch a storm of 404s before failing. A tileset can declare its coverage,
+// ! and a render that isn't entirely inside it fails before anything is fetched, with an
+// ! OutsideCoverage error naming the tileset. With fallbacks configured for the tileset,
+// ! the render uses the first of them that covers the area instead. The rest of the chain
+// ! stands in for it in turn if its tiles can't be fetched (see tiles::fetch_layers).
+// !
+// ! TILESET_<NAME>_COVERAGE sets a tileset's coverage, as west,south,east,north in
+// ! degrees or geojson:<path> to a file with a (Multi)Polygon, and TILESET_<NAME>_FALLBACK
+// ! the tilesets to fall back to, comma separated in order of preference, e.g. esri,osm.
+// ! Swisstopo defaults to the extent of its tiles; every other tileset covers the world
+// ! unless it's configured otherwise.
+
+use crate::coordinates::{pixel_to_lat_long, LatLong, TileBox};
+use crate::crop::rings_from_geojson;
+use crate::degradations::{self, Degradation};
+use crate::tiles::{Layer, TileSet};
+use anyhow::{anyhow, Context as _, Result};
+use log::info;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::sync::OnceLock;
+
+// The extent swisstopo serves Web Mercator tiles for, a little beyond the border
+const SWISSTOPO_COVERAGE: [f64; 4] = [5.14, 45.39, 11.48, 48.23];
+
+static COVERAGE: OnceLock<HashMap<&'static str, Coverage>> = OnceLock::new();
+
+// The area a tileset has tiles for
+#[derive(Debug, Clone, PartialEq)]
+pub enum Area {
+    // west, south, east, north in degrees
+    Bbox([f64; 4]),
+    // Polygon rings, filled even-odd
+    Polygon(Vec<Vec<LatLong>>),
+}
+
+impl Area {
+    // Parses west,south,east,north, or geojson:<path> to read a polygon from
+    pub fn parse(spec: &str) -> Result<Area> {
+        if let Some(path) = spec.strip_prefix("geojson:") {
+            let json = fs::read(path).with_context(|| format!("reading {}", path))?;
+            let geojson =
+                serde_json::from_slice(&json).with_context(|| format!("parsing {}", path))?;
+            let rings = rings_from_geojson(&geojson)?;
+            if rings.is_empty() {
+                return Err(anyhow!("{} has no polygons", path));
+            }
+            return Ok(Area::Polygon(rings));
+        }
+        let bbox: Vec<f64> = spec
+            .split(',')
+            .map(|v| v.trim().parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| anyhow!("Coverage must be west,south,east,north or geojson:<path>"))?;
+        let bbox: [f64; 4] = bbox
+            .try_into()
+            .map_err(|_| anyhow!("Coverage must be west,south,east,north or geojson:<path>"))?;
+        if bbox[0] >= bbox[2] || bbox[1] >= bbox[3] {
+            return Err(anyhow!("Coverage {} is empty", spec));
+        }
+        Ok(Area::Bbox(bbox))
+    }
+
+    pub fn contains(&self, point: LatLong) -> bool {
+        let LatLong(lat, long) = point;
+        match self {
+            Area::Bbox([west, south, east, north]) => {
+                (*west..=*east).contains(&long) && (*south..=*north).contains(&lat)
+            }
+            Area::Polygon(rings) => {
+                let mut inside = false;
+                for ring in rings {
+                    for (a, b) in ring.iter().zip(ring.iter().cycle().skip(1)) {
+                        if (a.0 > lat) != (b.0 > lat)
+                            && long < a.1 + (lat - a.0) / (b.0 - a.0) * (b.1 - a.1)
+                        {
+                            inside = !inside;
+                        }
+                    }
+                }
+                inside
+            }
+        }
+    }
+
+    // Whether the area holds all of a box of tiles, judged by its corners
+    pub fn covers(&self, tile_box: &TileBox) -> bool {
+        let (left, top) = (tile_box.top_left.x as f64, tile_box.top_left.y as f64);
+        let (right, bottom) = (
+            tile_box.bottom_right.x as f64,
+            tile_box.bottom_right.y as f64,
+        );
+        let z = tile_box.top_left.z;
+        [(left, top), (right, top), (left, bottom), (right, bottom)]
+            .into_iter()
+            .all(|(x, y)| self.contains(pixel_to_lat_long(x * 256.0, y * 256.0, z)))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    // None for the whole world
+    pub area: Option<Area>,
+    // Tilesets to render from instead, in order of preference
+    pub fallbacks: Vec<TileSet>,
+}
+
+// A render asked for tiles a tileset doesn't have
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutsideCoverage {
+    pub tileset: TileSet,
+}
+
+impl fmt::Display for OutsideCoverage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The area is outside the {} tileset's coverage; ",
+            self.tileset.name()
+        )?;
+        match self.tileset {
+            TileSet::Swisstopo => write!(f, "it only covers Switzerland. Try tileset=osm"),
+            _ => write!(f, "try another tileset"),
+        }
+    }
+}
+
+impl std::error::Error for OutsideCoverage {}
+
+fn defaults() -> HashMap<&'static str, Coverage> {
+    HashMap::from([(
+        TileSet::Swisstopo.name(),
+        Coverage {
+            area: Some(Area::Bbox(SWISSTOPO_COVERAGE)),
+            fallbacks: Vec::new(),
+        },
+    )])
+}
+
+// Reads TILESET_<NAME>_COVERAGE and TILESET_<NAME>_FALLBACK. This should be called once
+// at startup; until it is, the defaults apply.
+pub fn init_from_env() -> Result<()> {
+    let mut coverage = defaults();
+    for tileset in TileSet::all() {
+        let prefix = format!("TILESET_{}", tileset.name().to_uppercase());
+        let entry = coverage.entry(tileset.name()).or_default();
+        if let Ok(spec) = env::var(format!("{}_COVERAGE", prefix)) {
+            entry.area =
+                Some(Area::parse(&spec).with_context(|| format!("Invalid {}_COVERAGE", prefix))?);
+        }
+        if let Ok(names) = env::var(format!("{}_FALLBACK", prefix)) {
+            entry.fallbacks = names
+                .split(',')
+                .map(|name| {
+                    TileSet::lookup(name.trim()).ok_or_else(|| {
+                        anyhow!("Invalid {}_FALLBACK: unknown tileset {}", prefix, name)
+                    })
+                })
+                .collect::<Result<_>>()?;
+            if entry.fallbacks.contains(&tileset) {
+                return Err(anyhow!(
+                    "Invalid {}_FALLBACK: a tileset can't fall back to itself",
+                    prefix
+                ));
+            }
+            info!(
+                "Falling back to {} from {}",
+                names.replace(',', ", then "),
+                tileset.name()
+            );
+        }
+    }
+    let _ = COVERAGE.set(coverage);
+    Ok(())
+}
+
+pub fn coverage(tileset: TileSet) -> Coverage {
+    COVERAGE
+        .get_or_init(defaults)
+        .get(tileset.name())
+        .cloned()
+        .unwrap_or_default()
+}
+
+fn covers(coverage: &Coverage, tile_box: &TileBox) -> bool {
+    coverage
+        .area
+        .as_ref()
+        .is_none_or(|area| area.covers(tile_box))
+}
+
+// The tilesets a layer can be rendered from over a box of tiles, most preferred first:
+// its own if it covers the box, then its fallbacks that do. A layer whose tileset doesn't
+// cover the box is rendered from the first fallback that does, or fails the render if
+// there isn't one.
+pub fn sources(layer: Layer, tile_box: &TileBox) -> Result<Vec<TileSet>> {
+    sources_for(layer, &coverage(layer.tileset), tile_box)
+}
+
+fn sources_for(layer: Layer, coverage: &Coverage, tile_box: &TileBox) -> Result<Vec<TileSet>> {
+    let sources: Vec<TileSet> = covers(coverage, tile_box)
+        .then_some(layer.tileset)
+        .into_iter()
+        .chain(
+            coverage
+                .fallbacks
+                .iter()
+                .copied()
+                .filter(|&fallback| covers(&self::coverage(fallback), tile_box)),
+        )
+        .collect();
+    let outside = OutsideCoverage {
+        tileset: layer.tileset,
+    };
+    match sources.first() {
+        None => Err(outside.into()),
+        Some(&first) if first != layer.tileset => {
+            info!("Rendering from {} instead: {}", first.name(), outside);
+            degradations::record(Degradation::Fallback {
+                tileset: layer.tileset.name(),
+                fallback: first.name(),
+            });
+            Ok(sources)
+        }
+        Some(_) => Ok(sources),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coordinates::lat_long_and_image_size_to_bounding_box;
+
+    fn tile_box(lat: f64, long: f64) -> TileBox {
+        lat_long_and_image_size_to_bounding_box(LatLong(lat, long), 1.0, 256).tile_box
+    }
+
+    #[test]
+    fn test_area() {
+        let bbox = Area::parse("5.9, 45.8, 10.5, 47.8").unwrap();
+        assert!(bbox.contains(LatLong(46.6, 8.1)));
+        assert!(!bbox.contains(LatLong(-31.9, 115.8)));
+        assert!(Area::parse("10.5,45.8,5.9,47.8").is_err());
+        assert!(Area::parse("5.9,45.8,10.5").is_err());
+
+        // A triangle over the west of Switzerland
+        let triangle = Area::Polygon(vec![vec![
+            LatLong(46.0, 6.0),
+            LatLong(47.5, 7.0),
+            LatLong(46.0, 8.0),
+        ]]);
+        assert!(triangle.contains(LatLong(46.5, 7.0)));
+        assert!(!triangle.contains(LatLong(47.4, 6.1)));
+        assert!(triangle.covers(&tile_box(46.5, 7.0)));
+        assert!(!triangle.covers(&tile_box(46.0, 6.0)));
+    }
+
+    #[test]
+    fn test_sources() {
+        let grosse_scheidegg = tile_box(46.655559, 8.102121);
+        let perth = tile_box(-31.952718, 115.8587);
+        let swisstopo = Layer {
+            tileset: TileSet::Swisstopo,
+            opacity: 0.5,
+        };
+        assert_eq!(
+            sources(swisstopo, &grosse_scheidegg).unwrap(),
+            vec![TileSet::Swisstopo]
+        );
+        let e = sources(swisstopo, &perth).unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<OutsideCoverage>(),
+            Some(&OutsideCoverage {
+                tileset: TileSet::Swisstopo
+            })
+        );
+
+        let with_fallbacks = Coverage {
+            area: Some(Area::Bbox(SWISSTOPO_COVERAGE)),
+            fallbacks: vec![TileSet::Esri, TileSet::Osm],
+        };
+        assert_eq!(
+            sources_for(swisstopo, &with_fallbacks, &perth).unwrap(),
+            vec![TileSet::Esri, TileSet::Osm]
+        );
+        assert_eq!(
+            sources_for(swisstopo, &with_fallbacks, &grosse_scheidegg).unwrap(),
+            vec![TileSet::Swisstopo, TileSet::Esri, TileSet::Osm]
+        );
+    }
+}
@@ -0,0 +1,210 @@
+// ! # crop
+// ! Crops the output to a GeoJSON polygon, e.g. a canton or park boundary: everything
+// ! outside it is made transparent, and the boundary can be outlined. Every polygon ring
+// ! is used and filled even-odd, so multipolygons and polygons with holes crop as expected.
+
+use crate::coordinates::LatLong;
+use crate::overlay::{self, parse_color, Overlay, Viewport};
+use crate::route::{self, LineStyle};
+use anyhow::{anyhow, Result};
+use image::{GrayImage, Luma, Rgba, RgbaImage};
+use serde_json::Value;
+
+const DEFAULT_OUTLINE_WIDTH: f32 = 2.0;
+pub const MAX_OUTLINE_WIDTH: f32 = 20.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Crop {
+    pub rings: Vec<Vec<LatLong>>,
+    pub outline: Option<(Rgba<u8>, f32)>,
+}
+
+impl Crop {
+    // Builds a crop from the polygon's rings and the crop_outline= and
+    // crop_outline_width= parameters. Returns None if there's no polygon.
+    pub fn from_params(
+        rings: Option<Vec<Vec<LatLong>>>,
+        outline: Option<&str>,
+        outline_width: Option<f32>,
+    ) -> Result<Option<Crop>> {
+        let Some(rings) = rings else {
+            return Ok(None);
+        };
+        let width = match outline_width {
+            Some(w) if !(0.0..=MAX_OUTLINE_WIDTH).contains(&w) => {
+                return Err(anyhow!(
+                    "crop_outline_width must be between 0 and {}",
+                    MAX_OUTLINE_WIDTH
+                ))
+            }
+            w => w.unwrap_or(DEFAULT_OUTLINE_WIDTH),
+        };
+        let outline = match outline {
+            Some(value) => {
+                let color = parse_color(&format!("#{}", value.trim_start_matches('#')))
+                    .ok_or_else(|| anyhow!("Invalid crop_outline {}", value))?;
+                Some((color, width))
+            }
+            None => None,
+        };
+        Ok(Some(Crop { rings, outline }))
+    }
+}
+
+// The rings of every polygon in a GeoJSON Polygon, MultiPolygon, Feature or
+// FeatureCollection
+pub fn rings_from_geojson(geojson: &Value) -> Result<Vec<Vec<LatLong>>> {
+    let rings: Vec<Vec<LatLong>> = overlay::from_geojson(geojson)?
+        .into_iter()
+        .filter_map(|overlay| match overlay {
+            Overlay::Line {
+                points, area: true, ..
+            } if points.len() >= 3 => Some(points),
+            _ => None,
+        })
+        .collect();
+    if rings.is_empty() {
+        return Err(anyhow!("crop must contain a polygon"));
+    }
+    Ok(rings)
+}
+
+// Makes the image transparent outside the polygon and draws its outline
+pub fn apply_crop(img: &mut RgbaImage, viewport: &Viewport, crop: &Crop) {
+    let mask = fill_rings(img.width(), img.height(), viewport, &crop.rings);
+    for (pixel, inside) in img.pixels_mut().zip(mask.pixels()) {
+        if inside[0] == 0 {
+            pixel[3] = 0;
+        }
+    }
+
+    if let Some((color, width)) = crop.outline {
+        for ring in &crop.rings {
+            let mut pixels: Vec<(f64, f64)> = ring.iter().map(|p| viewport.project(p)).collect();
+            // GeoJSON rings are closed already, but don't count on it
+            if pixels.first() != pixels.last() {
+                pixels.push(pixels[0]);
+            }
+            route::draw_route(img, &pixels, width, color, &LineStyle::default());
+        }
+    }
+}
+
+// A width x height mask that's white inside the rings and black outside, filled even-odd
+pub fn fill_rings(
+    width: u32,
+    height: u32,
+    viewport: &Viewport,
+    rings: &[Vec<LatLong>],
+) -> GrayImage {
+    let mut mask = GrayImage::new(width, height);
+    let rings: Vec<Vec<(f64, f64)>> = rings
+        .iter()
+        .map(|ring| ring.iter().map(|p| viewport.project(p)).collect())
+        .collect();
+    for y in 0..height {
+        // Fill between pairs of crossings along each row
+        let py = y as f64 + 0.5;
+        let mut crossings: Vec<f64> = rings
+            .iter()
+            .flat_map(|vertices| {
+                (0..vertices.len()).filter_map(move |i| {
+                    let (a, b) = (vertices[i], vertices[(i + 1) % vertices.len()]);
+                    if (a.1 <= py) != (b.1 <= py) {
+                        Some(a.0 + (py - a.1) / (b.1 - a.1) * (b.0 - a.0))
+                    } else {
+                        None
+                    }
+                })
+            })
+            .collect();
+        crossings.sort_by(f64::total_cmp);
+        for pair in crossings.chunks_exact(2) {
+            let start = (pair[0] - 0.5).ceil().max(0.0) as u32;
+            let end = (pair[1] - 0.5).floor().min(width as f64 - 1.0);
+            if end >= 0.0 {
+                for x in start..=end as u32 {
+                    mask.put_pixel(x, y, Luma([255]));
+                }
+            }
+        }
+    }
+    mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coordinates::pixel_to_lat_long;
+    use serde_json::json;
+
+    const VIEWPORT: Viewport = Viewport {
+        zoom: 12,
+        origin: (0.0, 0.0),
+        scale: 1.0,
+    };
+
+    fn ring(corners: [(f64, f64); 4]) -> Vec<LatLong> {
+        corners
+            .map(|(x, y)| pixel_to_lat_long(x, y, VIEWPORT.zoom))
+            .to_vec()
+    }
+
+    #[test]
+    fn test_from_params() {
+        assert_eq!(Crop::from_params(None, Some("ff0000"), None).unwrap(), None);
+
+        let crop = Crop::from_params(Some(vec![]), Some("ff0000"), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            crop.outline,
+            Some((Rgba([255, 0, 0, 255]), DEFAULT_OUTLINE_WIDTH))
+        );
+
+        assert!(Crop::from_params(Some(vec![]), Some("red"), None).is_err());
+        assert!(Crop::from_params(Some(vec![]), None, Some(50.0)).is_err());
+    }
+
+    #[test]
+    fn test_rings_from_geojson() {
+        let polygon = json!({
+            "type": "MultiPolygon",
+            "coordinates": [
+                [[[8.0, 46.0], [8.1, 46.0], [8.1, 46.1], [8.0, 46.0]]],
+                [[[9.0, 46.0], [9.1, 46.0], [9.1, 46.1], [9.0, 46.0]]]
+            ]
+        });
+        assert_eq!(rings_from_geojson(&polygon).unwrap().len(), 2);
+
+        let line = json!({ "type": "LineString", "coordinates": [[8.0, 46.0], [8.1, 46.1]] });
+        assert!(rings_from_geojson(&line).is_err());
+    }
+
+    #[test]
+    fn test_hole_is_cropped() {
+        let outer = ring([(8.0, 8.0), (56.0, 8.0), (56.0, 56.0), (8.0, 56.0)]);
+        let hole = ring([(24.0, 24.0), (40.0, 24.0), (40.0, 40.0), (24.0, 40.0)]);
+        let crop = Crop {
+            rings: vec![outer, hole],
+            outline: None,
+        };
+        let mut img = RgbaImage::from_pixel(64, 64, Rgba([200, 200, 200, 255]));
+        apply_crop(&mut img, &VIEWPORT, &crop);
+
+        assert_eq!(img.get_pixel(16, 16)[3], 255);
+        assert_eq!(img.get_pixel(32, 32)[3], 0);
+        assert_eq!(img.get_pixel(2, 2)[3], 0);
+    }
+
+    #[test]
+    fn test_outline_is_drawn() {
+        let crop = Crop {
+            rings: vec![ring([(8.0, 8.0), (56.0, 8.0), (56.0, 56.0), (8.0, 56.0)])],
+            outline: Some((Rgba([255, 0, 0, 255]), 2.0)),
+        };
+        let mut img = RgbaImage::from_pixel(64, 64, Rgba([200, 200, 200, 255]));
+        apply_crop(&mut img, &VIEWPORT, &crop);
+        assert_eq!(img.get_pixel(32, 8), &Rgba([255, 0, 0, 255]));
+    }
+}
@@ -0,0 +1,70 @@
+// ! # debug_tiles
+// ! The debug tileset, generated locally instead of fetched, for tests and demos that
+// ! shouldn't need the network. Every tile is the same for the same z/x/y: a checkerboard
+// ! of 32px cells, grid lines every 64px, a red line along the tile's top and left edges
+// ! and its z/x/y in the top left corner. The edge lines land exactly on tile boundaries,
+// ! so cropping and overlay placement can be checked pixel for pixel.
+
+use crate::text::{draw_text_with_halo, fill_rect};
+use image::{Rgba, RgbaImage};
+
+pub const TILE_SIZE: u32 = 256;
+const CELL_SIZE: u32 = 32;
+const GRID_SPACING: u32 = 64;
+
+const LIGHT: Rgba<u8> = Rgba([235, 235, 230, 255]);
+const DARK: Rgba<u8> = Rgba([205, 205, 200, 255]);
+pub const GRID_COLOR: Rgba<u8> = Rgba([120, 160, 220, 255]);
+pub const EDGE_COLOR: Rgba<u8> = Rgba([220, 40, 40, 255]);
+const LABEL_COLOR: Rgba<u8> = Rgba([40, 40, 40, 255]);
+const LABEL_HALO: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+// Draws the tile at z/x/y. Neighbouring tiles start their checkerboard on opposite
+// colors, so tile boundaries stand out even where the edge lines are cropped away.
+pub fn debug_tile(x: u32, y: u32, z: u32) -> RgbaImage {
+    let parity = (x + y) % 2;
+    let mut img = RgbaImage::from_fn(TILE_SIZE, TILE_SIZE, |px, py| {
+        if (px / CELL_SIZE + py / CELL_SIZE + parity).is_multiple_of(2) {
+            LIGHT
+        } else {
+            DARK
+        }
+    });
+
+    for offset in (GRID_SPACING..TILE_SIZE).step_by(GRID_SPACING as usize) {
+        fill_rect(&mut img, offset as i64, 0, 1, TILE_SIZE, GRID_COLOR);
+        fill_rect(&mut img, 0, offset as i64, TILE_SIZE, 1, GRID_COLOR);
+    }
+    fill_rect(&mut img, 0, 0, TILE_SIZE, 1, EDGE_COLOR);
+    fill_rect(&mut img, 0, 0, 1, TILE_SIZE, EDGE_COLOR);
+
+    let label = format!("{}/{}/{}", z, x, y);
+    draw_text_with_halo(&mut img, 6, 6, &label, 2, LABEL_COLOR, LABEL_HALO);
+    img
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug_tile() {
+        let tile = debug_tile(3, 5, 4);
+        assert_eq!(tile, debug_tile(3, 5, 4));
+        assert_eq!(tile.dimensions(), (TILE_SIZE, TILE_SIZE));
+
+        // Edges, grid lines and the checkerboard are where they should be
+        assert_eq!(tile.get_pixel(0, 200), &EDGE_COLOR);
+        assert_eq!(tile.get_pixel(200, 0), &EDGE_COLOR);
+        assert_eq!(tile.get_pixel(128, 200), &GRID_COLOR);
+        assert_eq!(tile.get_pixel(200, 192), &GRID_COLOR);
+        assert_eq!(tile.get_pixel(100, 100), &LIGHT);
+        assert_eq!(tile.get_pixel(100, 140), &DARK);
+
+        // The next tile over starts on the other color
+        assert_eq!(debug_tile(4, 5, 4).get_pixel(100, 100), &DARK);
+
+        // And the label differs from tile to tile
+        assert_ne!(tile, debug_tile(3, 6, 4));
+    }
+}
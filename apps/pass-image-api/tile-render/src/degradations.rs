@@ -0,0 +1,183 @@
+// ! # degradations
+// ! What a render gave up to get an image out: tiles from a fallback tileset because the
+// ! area was outside the requested one's coverage or its tiles couldn't be fetched, a zoom
+// ! clamped because the radius was too small to fill the image, or an archive edition
+// ! other than the one asked for.
+// ! Clients can use them to decide whether to retry with other parameters or show the
+// ! image as it is.
+// !
+// ! Degradations are collected like reports are: running a render in collect gives it a
+// ! list to record into, and outside one recording does nothing. Each degradation is
+// ! recorded once, however many tiles ran into it. The tilesets that served the render's
+// ! layers are collected alongside them, so a response can always name its sources.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::future::Future;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Degradation {
+    // The area was outside the tileset's coverage, so its fallback's tiles were used
+    Fallback {
+        tileset: &'static str,
+        fallback: &'static str,
+    },
+    // The tileset's tiles couldn't be fetched, so the next tileset in its fallback chain
+    // was tried
+    Failover {
+        tileset: &'static str,
+        fallback: &'static str,
+    },
+    // The radius was too small to fill the image even at the highest zoom, so the
+    // image is smaller, or scaled up
+    ZoomClamped {
+        zoom: u32,
+    },
+    // The date was before the tileset's archive starts, so its first edition was used
+    DateClamped {
+        tileset: &'static str,
+        year: u32,
+    },
+}
+
+impl Degradation {
+    // The degradation's entry in X-Render-Degradations, e.g.
+    // fallback;tileset=swisstopo;to=osm
+    pub fn token(&self) -> String {
+        match self {
+            Degradation::Fallback { tileset, fallback } => {
+                format!("fallback;tileset={};to={}", tileset, fallback)
+            }
+            Degradation::Failover { tileset, fallback } => {
+                format!("failover;tileset={};to={}", tileset, fallback)
+            }
+            Degradation::ZoomClamped { zoom } => format!("zoom_clamped;zoom={}", zoom),
+            Degradation::DateClamped { tileset, year } => {
+                format!("date_clamped;tileset={};year={}", tileset, year)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Degradation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Degradation::Fallback { tileset, fallback } => write!(
+                f,
+                "The area is outside {}'s coverage; rendered from {} instead",
+                tileset, fallback
+            ),
+            Degradation::Failover { tileset, fallback } => write!(
+                f,
+                "{}'s tiles couldn't be fetched; tried {} instead",
+                tileset, fallback
+            ),
+            Degradation::ZoomClamped { zoom } => write!(
+                f,
+                "The radius is too small to fill the image at zoom {}, the highest there is",
+                zoom
+            ),
+            Degradation::DateClamped { tileset, year } => write!(
+                f,
+                "{}'s archive starts in {}; rendered from that edition",
+                tileset, year
+            ),
+        }
+    }
+}
+
+// What a render recorded: what it gave up, in the order it happened, and the tilesets
+// that served its layers
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Collected {
+    pub degradations: Vec<Degradation>,
+    pub sources: Vec<&'static str>,
+}
+
+impl Collected {
+    // Records everything again under the current collect, e.g. for an image from a cache
+    pub fn replay(self) {
+        self.degradations.into_iter().for_each(record);
+        self.sources.into_iter().for_each(record_source);
+    }
+}
+
+tokio::task_local! {
+    static COLLECTED: RefCell<Collected>;
+}
+
+// Runs a render, returning its output along with what it recorded
+pub async fn collect<F: Future>(f: F) -> (F::Output, Collected) {
+    COLLECTED
+        .scope(RefCell::new(Collected::default()), async {
+            let output = f.await;
+            (output, COLLECTED.with(|collected| collected.take()))
+        })
+        .await
+}
+
+// Records a degradation under the current collect, if there is one
+pub fn record(degradation: Degradation) {
+    let _ = COLLECTED.try_with(|collected| {
+        let degradations = &mut collected.borrow_mut().degradations;
+        if !degradations.contains(&degradation) {
+            degradations.push(degradation);
+        }
+    });
+}
+
+// Records a tileset that served one of the render's layers, after any fallback, under
+// the current collect, if there is one
+pub fn record_source(tileset: &'static str) {
+    let _ = COLLECTED.try_with(|collected| {
+        let sources = &mut collected.borrow_mut().sources;
+        if !sources.contains(&tileset) {
+            sources.push(tileset);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_collect() {
+        let fallback = Degradation::Fallback {
+            tileset: "swisstopo",
+            fallback: "osm",
+        };
+        let ((), collected) = collect(async {
+            record(fallback.clone());
+            record(Degradation::ZoomClamped { zoom: 21 });
+            record(fallback.clone());
+            record_source("osm");
+            record_source("esri");
+            record_source("osm");
+        })
+        .await;
+        assert_eq!(
+            collected.degradations,
+            vec![fallback.clone(), Degradation::ZoomClamped { zoom: 21 }]
+        );
+        assert_eq!(
+            collected.degradations[0].token(),
+            "fallback;tileset=swisstopo;to=osm"
+        );
+        assert_eq!(collected.sources, vec!["osm", "esri"]);
+
+        // Outside a collect, recording does nothing
+        record(fallback);
+        record_source("osm");
+
+        // Replaying records the same again, e.g. for a cached image
+        let ((), replayed) = collect(async { collected.clone().replay() }).await;
+        assert_eq!(replayed, collected);
+
+        let failover = Degradation::Failover {
+            tileset: "esri",
+            fallback: "osm",
+        };
+        assert_eq!(failover.token(), "failover;tileset=esri;to=osm");
+    }
+}
@@ -0,0 +1,381 @@
+// ! # dem
+// ! Elevation data from DEM tiles, where each pixel's color encodes a height. By default we
+// ! use the public AWS terrain tiles in Terrarium encoding; TERRAIN_TILE_URL and
+// ! TERRAIN_ENCODING point this at any other source, e.g. a Mapbox Terrain-RGB compatible
+// ! server. Elevations are resampled onto the pixels of the image being rendered so that
+// ! terrain overlays can work in image space, or sampled at single points with
+// ! elevations.
+
+use crate::coordinates::{lat_long_to_pixel, meters_per_pixel, pixel_to_lat_long, LatLong};
+use crate::fetcher::TileFetcher;
+use crate::overlay::Viewport;
+use crate::tiles::TileSet;
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use image::RgbaImage;
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+// The highest zoom DEM tiles are available at. Renders at higher zooms interpolate.
+pub const MAX_DEM_ZOOM: u32 = 15;
+
+// The most points one call to elevations may sample
+pub const MAX_ELEVATION_POINTS: usize = 1000;
+
+// Web Mercator stops short of the poles
+const MAX_LATITUDE: f64 = 85.051_128;
+
+const DEFAULT_TERRAIN_URL: &str =
+    "https://s3.amazonaws.com/elevation-tiles-prod/terrarium/{z}/{x}/{y}.png";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    // height = (R * 256 + G + B / 256) - 32768
+    Terrarium,
+    // height = -10000 + (R * 65536 + G * 256 + B) * 0.1
+    TerrainRgb,
+}
+
+impl FromStr for Encoding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "terrarium" => Ok(Encoding::Terrarium),
+            "terrain-rgb" => Ok(Encoding::TerrainRgb),
+            other => Err(anyhow!("Unknown DEM encoding {}", other)),
+        }
+    }
+}
+
+impl Encoding {
+    // Decodes a pixel's height in meters
+    pub fn decode(&self, rgb: [u8; 3]) -> f32 {
+        let [r, g, b] = rgb.map(|c| c as f32);
+        match self {
+            Encoding::Terrarium => r * 256.0 + g + b / 256.0 - 32768.0,
+            Encoding::TerrainRgb => -10000.0 + (r * 65536.0 + g * 256.0 + b) * 0.1,
+        }
+    }
+}
+
+struct TerrainSource {
+    url: String,
+    encoding: Encoding,
+}
+
+fn source() -> &'static TerrainSource {
+    static SOURCE: OnceLock<TerrainSource> = OnceLock::new();
+    SOURCE.get_or_init(|| TerrainSource {
+        url: env::var("TERRAIN_TILE_URL").unwrap_or_else(|_| DEFAULT_TERRAIN_URL.to_string()),
+        encoding: env::var("TERRAIN_ENCODING")
+            .ok()
+            .and_then(|e| e.parse().ok())
+            .unwrap_or(Encoding::Terrarium),
+    })
+}
+
+// The URL pattern DEM tiles are fetched from
+pub fn terrain_url() -> &'static str {
+    &source().url
+}
+
+// The zoom DEM tiles should be fetched at for a render at the given zoom
+pub fn dem_zoom(zoom: u32) -> u32 {
+    zoom.min(MAX_DEM_ZOOM)
+}
+
+// The range of DEM tiles, inclusive, covering an image of the given size at the viewport
+pub fn tile_range(viewport: &Viewport, size: (u32, u32)) -> ((u32, u32), (u32, u32)) {
+    let factor = 2.0_f64.powi((viewport.zoom - dem_zoom(viewport.zoom)) as i32);
+    let to_tile = |px: f64| (px / factor / 256.0).floor().max(0.0) as u32;
+    let right = viewport.origin.0 + size.0 as f64 / viewport.scale;
+    let bottom = viewport.origin.1 + size.1 as f64 / viewport.scale;
+    (
+        (to_tile(viewport.origin.0), to_tile(viewport.origin.1)),
+        (to_tile(right), to_tile(bottom)),
+    )
+}
+
+// Heights in meters for every pixel of a rendered image
+#[derive(Debug, Clone)]
+pub struct ElevationGrid {
+    pub width: u32,
+    pub height: u32,
+    data: Vec<f32>,
+    // Ground distance between neighbouring pixels, taken at the middle of the image
+    pub meters_per_pixel: f64,
+}
+
+impl ElevationGrid {
+    pub fn new(width: u32, height: u32, data: Vec<f32>, meters_per_pixel: f64) -> ElevationGrid {
+        assert_eq!(data.len(), (width * height) as usize);
+        ElevationGrid {
+            width,
+            height,
+            data,
+            meters_per_pixel,
+        }
+    }
+
+    // The height at a pixel, or NaN where there's no data
+    pub fn get(&self, x: u32, y: u32) -> f32 {
+        self.data[(y * self.width + x) as usize]
+    }
+
+    // Resamples decoded DEM tiles, keyed by (x, y, z), onto the pixels of an image of the
+    // given size at the viewport
+    pub fn from_tiles(
+        tiles: &HashMap<(u32, u32, u32), Bytes>,
+        viewport: &Viewport,
+        size: (u32, u32),
+    ) -> Result<ElevationGrid> {
+        let dem = DemTiles::decode(tiles)?;
+        let factor = 2.0_f64.powi((viewport.zoom - dem_zoom(viewport.zoom)) as i32);
+        let mut data = Vec::with_capacity((size.0 * size.1) as usize);
+        for y in 0..size.1 {
+            for x in 0..size.0 {
+                // The pixel's center in DEM pixel space
+                let gx = (viewport.origin.0 + (x as f64 + 0.5) / viewport.scale) / factor - 0.5;
+                let gy = (viewport.origin.1 + (y as f64 + 0.5) / viewport.scale) / factor - 0.5;
+                data.push(dem.interpolate(gx, gy));
+            }
+        }
+
+        let middle = pixel_to_lat_long(
+            viewport.origin.0 + size.0 as f64 / viewport.scale / 2.0,
+            viewport.origin.1 + size.1 as f64 / viewport.scale / 2.0,
+            viewport.zoom,
+        );
+        let resolution = meters_per_pixel(middle.0, viewport.zoom) / viewport.scale;
+
+        Ok(ElevationGrid::new(size.0, size.1, data, resolution))
+    }
+}
+
+// Decoded DEM tiles at one zoom, keyed by (x, y)
+struct DemTiles {
+    encoding: Encoding,
+    tiles: HashMap<(u32, u32), RgbaImage>,
+}
+
+impl DemTiles {
+    fn decode(tiles: &HashMap<(u32, u32, u32), Bytes>) -> Result<DemTiles> {
+        let tiles = tiles
+            .iter()
+            .map(|(&(x, y, _), bytes)| {
+                let image = image::load_from_memory(bytes)
+                    .with_context(|| format!("decoding DEM tile {}/{}", x, y))?
+                    .to_rgba8();
+                Ok(((x, y), image))
+            })
+            .collect::<Result<_>>()?;
+        Ok(DemTiles {
+            encoding: source().encoding,
+            tiles,
+        })
+    }
+
+    // The height of a DEM pixel, or NaN where there's no data
+    fn height_at(&self, px: i64, py: i64) -> f32 {
+        let tile = (px.div_euclid(256) as u32, py.div_euclid(256) as u32);
+        match self.tiles.get(&tile) {
+            Some(image) => {
+                let (x, y) = (px.rem_euclid(256) as u32, py.rem_euclid(256) as u32);
+                if x < image.width() && y < image.height() {
+                    let [r, g, b, _] = image.get_pixel(x, y).0;
+                    self.encoding.decode([r, g, b])
+                } else {
+                    f32::NAN
+                }
+            }
+            None => f32::NAN,
+        }
+    }
+
+    // The height at a point in DEM pixel space, by bilinear interpolation between the
+    // four DEM pixel centers around it
+    fn interpolate(&self, gx: f64, gy: f64) -> f32 {
+        let (x0, y0) = (gx.floor(), gy.floor());
+        let (fx, fy) = ((gx - x0) as f32, (gy - y0) as f32);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        let top = lerp(self.height_at(x0, y0), self.height_at(x0 + 1, y0), fx);
+        let bottom = lerp(
+            self.height_at(x0, y0 + 1),
+            self.height_at(x0 + 1, y0 + 1),
+            fx,
+        );
+        lerp(top, bottom, fy)
+    }
+}
+
+// Checks that points can be sampled: not too many of them, and all on the map
+pub fn validate_points(points: &[LatLong]) -> Result<()> {
+    if points.len() > MAX_ELEVATION_POINTS {
+        return Err(anyhow!(
+            "At most {} points can be sampled at once",
+            MAX_ELEVATION_POINTS
+        ));
+    }
+    for point in points {
+        if !(-MAX_LATITUDE..=MAX_LATITUDE).contains(&point.0)
+            || !(-180.0..=180.0).contains(&point.1)
+        {
+            return Err(anyhow!(
+                "{},{} is out of range: latitudes go to {} and longitudes to 180",
+                point.0,
+                point.1,
+                MAX_LATITUDE
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Heights in meters at points, or NaN where there's no data. The DEM tiles are fetched
+// at MAX_DEM_ZOOM through the fetcher, so they're cached like any other tiles, and each
+// only once however many of the points fall in it.
+pub async fn elevations(fetcher: &dyn TileFetcher, points: &[LatLong]) -> Result<Vec<f32>> {
+    validate_points(points)?;
+
+    // Each point's position in DEM pixel space, and the tiles around it
+    let z = MAX_DEM_ZOOM;
+    let positions: Vec<(f64, f64)> = points
+        .iter()
+        .map(|point| {
+            let (x, y) = lat_long_to_pixel(point, z);
+            (x - 0.5, y - 0.5)
+        })
+        .collect();
+    let tiles_across = 1u32 << z;
+    let wanted: BTreeSet<(u32, u32)> = positions
+        .iter()
+        .flat_map(|&(gx, gy)| {
+            let (x0, y0) = (gx.floor() as i64, gy.floor() as i64);
+            [(x0, y0), (x0 + 1, y0), (x0, y0 + 1), (x0 + 1, y0 + 1)]
+        })
+        .map(|(px, py)| (px.div_euclid(256), py.div_euclid(256)))
+        .filter(|&(x, y)| {
+            (0..tiles_across as i64).contains(&x) && (0..tiles_across as i64).contains(&y)
+        })
+        .map(|(x, y)| (x as u32, y as u32))
+        .collect();
+
+    let cx = opentelemetry::Context::current();
+    let tiles: HashMap<(u32, u32, u32), Bytes> = stream::iter(wanted)
+        .map(|(x, y)| {
+            let cx = cx.clone();
+            async move {
+                let tile = fetcher.fetch(TileSet::Terrain, x, y, z, cx).await?;
+                anyhow::Ok(((x, y, z), tile))
+            }
+        })
+        .buffer_unordered(10)
+        .try_collect()
+        .await?;
+    let dem = DemTiles::decode(&tiles)?;
+    Ok(positions
+        .iter()
+        .map(|&(gx, gy)| dem.interpolate(gx, gy))
+        .collect())
+}
+
+// Linear interpolation that doesn't look at b when t is zero, so a missing neighbour
+// doesn't turn a pixel we do have data for into NaN
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    if t == 0.0 {
+        a
+    } else {
+        a * (1.0 - t) + b * t
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetcher::MemoryFetcher;
+    use image::{ImageFormat, Rgba};
+    use std::io::Cursor;
+
+    // A Terrarium tile at a constant height
+    fn flat_tile(height: f32) -> Bytes {
+        let value = height + 32768.0;
+        let (r, g) = ((value / 256.0).floor() as u8, (value % 256.0) as u8);
+        let image = RgbaImage::from_pixel(256, 256, Rgba([r, g, 0, 255]));
+        let mut png = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        Bytes::from(png)
+    }
+
+    #[tokio::test]
+    async fn test_elevations() {
+        let fetcher = MemoryFetcher::default().with_fallback(flat_tile(2100.0));
+        let heights = elevations(
+            &fetcher,
+            &[LatLong(46.655559, 8.102121), LatLong(-33.9, 151.2)],
+        )
+        .await
+        .unwrap();
+        assert_eq!(heights, vec![2100.0, 2100.0]);
+
+        // A tile that can't be fetched fails the lookup, as do points off the map
+        assert!(elevations(&MemoryFetcher::default(), &[LatLong(0.0, 0.0)])
+            .await
+            .is_err());
+        assert!(elevations(&fetcher, &[LatLong(89.0, 8.1)]).await.is_err());
+        assert!(
+            elevations(&fetcher, &[LatLong(46.6, 8.1); MAX_ELEVATION_POINTS + 1])
+                .await
+                .is_err()
+        );
+        assert!(elevations(&fetcher, &[]).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(Encoding::Terrarium.decode([128, 0, 0]), 0.0);
+        assert_eq!(Encoding::Terrarium.decode([136, 52, 0]), 2100.0);
+        assert_eq!(Encoding::TerrainRgb.decode([1, 134, 160]), 0.0);
+    }
+
+    #[test]
+    fn test_tile_range_clamps_to_dem_zoom() {
+        // A 512px image at zoom 17 starting at tile 4, 8 is within DEM tile 1, 2 at zoom 15
+        let viewport = Viewport {
+            zoom: 17,
+            origin: (4.0 * 256.0, 8.0 * 256.0),
+            scale: 1.0,
+        };
+        assert_eq!(tile_range(&viewport, (512, 512)), ((1, 2), (1, 2)));
+    }
+
+    #[test]
+    fn test_from_tiles() {
+        let mut tiles = HashMap::new();
+        tiles.insert((0, 0, 15), flat_tile(2100.0));
+        let viewport = Viewport {
+            zoom: 15,
+            origin: (10.0, 10.0),
+            scale: 1.0,
+        };
+
+        let grid = ElevationGrid::from_tiles(&tiles, &viewport, (16, 16)).unwrap();
+        assert_eq!(grid.get(0, 0), 2100.0);
+        assert_eq!(grid.get(15, 15), 2100.0);
+        assert!(grid.meters_per_pixel > 0.0);
+
+        // Outside the fetched tiles there's no data
+        let viewport = Viewport {
+            origin: (300.0, 300.0),
+            ..viewport
+        };
+        let grid = ElevationGrid::from_tiles(&tiles, &viewport, (4, 4)).unwrap();
+        assert!(grid.get(0, 0).is_nan());
+    }
+}
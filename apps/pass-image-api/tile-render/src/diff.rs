@@ -0,0 +1,169 @@
+// ! # diff
+// ! Where two renders of the same area differ, e.g. two tilesets, or a tileset with an
+// ! archive before and after its provider updated it. Both sides are rendered with the same
+// ! extent and options, and compared pixel by pixel. The difference image is the second
+// ! render washed out to a light gray, with the pixels that changed in magenta, so changes
+// ! stand out however small they are.
+// !
+// ! A pixel has changed when any of its channels moved by more than the threshold, which
+// ! keeps JPEG noise and antialiasing from counting as changes.
+
+use crate::archive::{self, Date};
+use crate::coordinates::LatLong;
+use crate::fetcher::TileFetcher;
+use crate::tiles::{encode_png, render_image_from_point, RenderOptions, TileSet};
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use futures::future;
+use image::{Rgba, RgbaImage};
+
+pub const DEFAULT_THRESHOLD: u8 = 32;
+
+const CHANGED: Rgba<u8> = Rgba([255, 0, 255, 255]);
+
+// One side of a comparison: a tileset, as of a date if it has an archive
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Side {
+    pub tileset: TileSet,
+    pub date: Option<Date>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Difference {
+    pub image: RgbaImage,
+    // The share of the pixels that changed, from 0 to 100
+    pub changed_percent: f64,
+}
+
+// Compares two images of the same size
+pub fn difference(before: &RgbaImage, after: &RgbaImage, threshold: u8) -> Result<Difference> {
+    if before.dimensions() != after.dimensions() {
+        return Err(anyhow!(
+            "Can't compare a {:?} image with a {:?} one",
+            before.dimensions(),
+            after.dimensions()
+        ));
+    }
+    let mut changed = 0u64;
+    let image = RgbaImage::from_fn(after.width(), after.height(), |x, y| {
+        let (a, b) = (before.get_pixel(x, y), after.get_pixel(x, y));
+        if a.0.iter().zip(b.0).any(|(&a, b)| a.abs_diff(b) > threshold) {
+            changed += 1;
+            return CHANGED;
+        }
+        // Rec. 601 luma, faded two thirds of the way to white
+        let [r, g, b, _] = b.0.map(u32::from);
+        let luma = (299 * r + 587 * g + 114 * b) / 1000;
+        let faded = (255 - (255 - luma) / 3) as u8;
+        Rgba([faded, faded, faded, 255])
+    });
+    let pixels = (after.width() as u64 * after.height() as u64).max(1);
+    Ok(Difference {
+        image,
+        changed_percent: changed as f64 * 100.0 / pixels as f64,
+    })
+}
+
+async fn render_side(
+    fetcher: &dyn TileFetcher,
+    center: LatLong,
+    radius_km: f32,
+    image_size: u32,
+    side: Side,
+    options: &RenderOptions,
+) -> Result<RgbaImage> {
+    let render = render_image_from_point(
+        fetcher,
+        center,
+        radius_km,
+        image_size,
+        side.tileset,
+        options,
+    );
+    match side.date {
+        Some(date) => archive::scope(date, render).await,
+        None => render.await,
+    }
+}
+
+// Renders both sides of a comparison, at the same time, and returns the difference image
+// as a PNG along with the share of pixels that changed
+#[allow(clippy::too_many_arguments)]
+pub async fn render_difference(
+    fetcher: &dyn TileFetcher,
+    center: LatLong,
+    radius_km: f32,
+    image_size: u32,
+    before: Side,
+    after: Side,
+    options: &RenderOptions,
+    threshold: u8,
+) -> Result<(Bytes, f64)> {
+    let (before, after) = future::try_join(
+        render_side(fetcher, center, radius_km, image_size, before, options),
+        render_side(fetcher, center, radius_km, image_size, after, options),
+    )
+    .await?;
+    let difference = difference(&before, &after, threshold)?;
+    Ok((encode_png(difference.image), difference.changed_percent))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetcher::{MemoryFetcher, TileSources};
+
+    #[test]
+    fn test_difference() {
+        let before = RgbaImage::from_pixel(4, 4, Rgba([0, 0, 0, 255]));
+        let mut after = before.clone();
+        after.put_pixel(1, 1, Rgba([200, 0, 0, 255]));
+        // Too small a change to count
+        after.put_pixel(2, 2, Rgba([20, 20, 20, 255]));
+
+        let diff = difference(&before, &after, DEFAULT_THRESHOLD).unwrap();
+        assert_eq!(diff.changed_percent, 100.0 / 16.0);
+        assert_eq!(*diff.image.get_pixel(1, 1), CHANGED);
+        assert_eq!(*diff.image.get_pixel(0, 0), Rgba([170, 170, 170, 255]));
+
+        assert_eq!(
+            difference(&before, &before, 0).unwrap().changed_percent,
+            0.0
+        );
+        assert!(difference(&before, &RgbaImage::new(2, 2), 0).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_render_difference() {
+        let red =
+            crate::tiles::encode_tile(RgbaImage::from_pixel(256, 256, Rgba([255, 0, 0, 255])));
+        let sources = TileSources::default().with_source(
+            TileSet::Osm,
+            Box::new(MemoryFetcher::default().with_fallback(red)),
+        );
+        let side = |tileset| Side {
+            tileset,
+            date: None,
+        };
+        let options = RenderOptions::default();
+        let render = |after| {
+            render_difference(
+                &sources,
+                LatLong(46.655559, 8.102121),
+                1.0,
+                128,
+                side(TileSet::Osm),
+                side(after),
+                &options,
+                DEFAULT_THRESHOLD,
+            )
+        };
+
+        let (png, changed) = render(TileSet::Osm).await.unwrap();
+        assert_eq!(changed, 0.0);
+        assert!(image::load_from_memory(&png).is_ok());
+        // The debug tiles are nothing like a plain red map
+        let (_, changed) = render(TileSet::Debug).await.unwrap();
+        assert!(changed > 50.0);
+    }
+}
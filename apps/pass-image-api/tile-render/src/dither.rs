@@ -0,0 +1,161 @@
+// ! # dither
+// ! Palette-limited output for e-paper displays, such as the ones at trailheads. The
+// ! finished image is flattened onto white, since e-paper has no transparency, and every
+// ! pixel is mapped to the nearest palette color with Floyd-Steinberg error diffusion so
+// ! gradients and shading survive as dot patterns.
+
+use anyhow::{anyhow, Result};
+use image::{Rgba, RgbaImage};
+use std::str::FromStr;
+
+const MONO: [[u8; 3]; 2] = [[0, 0, 0], [255, 255, 255]];
+
+const GRAY4: [[u8; 3]; 4] = [[0, 0, 0], [85, 85, 85], [170, 170, 170], [255, 255, 255]];
+
+// The seven inks of common color e-paper panels
+const EINK7: [[u8; 3]; 7] = [
+    [0, 0, 0],
+    [255, 255, 255],
+    [0, 255, 0],
+    [0, 0, 255],
+    [255, 0, 0],
+    [255, 255, 0],
+    [255, 128, 0],
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Palette {
+    // Black and white
+    Mono,
+    // Four levels of gray
+    Gray4,
+    // Black, white, green, blue, red, yellow and orange
+    Eink7,
+}
+
+impl FromStr for Palette {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "1bit" | "mono" => Ok(Palette::Mono),
+            "gray4" | "grey4" => Ok(Palette::Gray4),
+            "eink7" => Ok(Palette::Eink7),
+            other => Err(anyhow!("Unknown palette {}", other)),
+        }
+    }
+}
+
+impl Palette {
+    fn colors(&self) -> &'static [[u8; 3]] {
+        match self {
+            Palette::Mono => &MONO,
+            Palette::Gray4 => &GRAY4,
+            Palette::Eink7 => &EINK7,
+        }
+    }
+
+    // The gray palettes only look at lightness, so colors map to the gray they'd print as
+    fn is_gray(&self) -> bool {
+        matches!(self, Palette::Mono | Palette::Gray4)
+    }
+
+    fn nearest(&self, color: [f32; 3]) -> [u8; 3] {
+        let distance = |c: &[u8; 3]| {
+            (0..3)
+                .map(|i| (c[i] as f32 - color[i]).powi(2))
+                .sum::<f32>()
+        };
+        *self
+            .colors()
+            .iter()
+            .min_by(|a, b| distance(a).total_cmp(&distance(b)))
+            .expect("palettes aren't empty")
+    }
+}
+
+// Flattens the image onto white and reduces it to the palette's colors
+pub fn dither(img: &mut RgbaImage, palette: Palette) {
+    let (width, height) = (img.width() as usize, img.height() as usize);
+
+    let mut values: Vec<[f32; 3]> = img
+        .pixels()
+        .map(|p| {
+            let alpha = p[3] as f32 / 255.0;
+            let mut rgb = [0.0; 3];
+            for (i, channel) in rgb.iter_mut().enumerate() {
+                *channel = p[i] as f32 * alpha + 255.0 * (1.0 - alpha);
+            }
+            if palette.is_gray() {
+                let luma = 0.299 * rgb[0] + 0.587 * rgb[1] + 0.114 * rgb[2];
+                rgb = [luma; 3];
+            }
+            rgb
+        })
+        .collect();
+
+    for y in 0..height {
+        for x in 0..width {
+            let old = values[y * width + x];
+            let new = palette.nearest(old);
+            img.put_pixel(x as u32, y as u32, Rgba([new[0], new[1], new[2], 255]));
+
+            let error: [f32; 3] = std::array::from_fn(|i| old[i] - new[i] as f32);
+            let mut spread = |dx: isize, dy: usize, weight: f32| {
+                let nx = x as isize + dx;
+                if nx >= 0 && (nx as usize) < width && y + dy < height {
+                    let value = &mut values[(y + dy) * width + nx as usize];
+                    for (v, e) in value.iter_mut().zip(error) {
+                        *v += e * weight;
+                    }
+                }
+            };
+            spread(1, 0, 7.0 / 16.0);
+            spread(-1, 1, 3.0 / 16.0);
+            spread(0, 1, 5.0 / 16.0);
+            spread(1, 1, 1.0 / 16.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn only_palette_colors(img: &RgbaImage, palette: Palette) -> bool {
+        img.pixels()
+            .all(|p| palette.colors().contains(&[p[0], p[1], p[2]]) && p[3] == 255)
+    }
+
+    #[test]
+    fn test_parse_palette() {
+        assert_eq!("1bit".parse::<Palette>().unwrap(), Palette::Mono);
+        assert_eq!("eink7".parse::<Palette>().unwrap(), Palette::Eink7);
+        assert!("cga".parse::<Palette>().is_err());
+    }
+
+    #[test]
+    fn test_mid_gray_dithers_to_half_black() {
+        let mut img = RgbaImage::from_pixel(32, 32, Rgba([128, 128, 128, 255]));
+        dither(&mut img, Palette::Mono);
+        assert!(only_palette_colors(&img, Palette::Mono));
+
+        let black = img.pixels().filter(|p| p[0] == 0).count();
+        assert!((450..=574).contains(&black), "{} black pixels", black);
+    }
+
+    #[test]
+    fn test_transparent_is_white() {
+        let mut img = RgbaImage::new(4, 4);
+        dither(&mut img, Palette::Gray4);
+        assert!(img.pixels().all(|p| p == &Rgba([255, 255, 255, 255])));
+    }
+
+    #[test]
+    fn test_eink7_keeps_exact_colors() {
+        let mut img = RgbaImage::from_pixel(8, 8, Rgba([255, 0, 0, 255]));
+        dither(&mut img, Palette::Eink7);
+        assert!(only_palette_colors(&img, Palette::Eink7));
+        assert!(img.pixels().all(|p| p == &Rgba([255, 0, 0, 255])));
+    }
+}
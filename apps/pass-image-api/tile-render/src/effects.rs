@@ -0,0 +1,706 @@
+// ! # effects
+// ! Color effects and resizing applied to the basemap mosaic before overlays are drawn, so that the
+// ! map can be themed to match a UI without needing a separately styled tile provider.
+// ! Filters are a fixed set of looks; adjustments are continuous tweaks on top (e.g.
+// ! washing the basemap out so routes drawn over it stand out).
+
+use anyhow::{anyhow, Result};
+use image::imageops::{self, FilterType};
+use image::RgbaImage;
+use std::str::FromStr;
+
+// A 3x3 matrix applied to each pixel's RGB values
+type ColorMatrix = [[f32; 3]; 3];
+
+const GRAYSCALE: ColorMatrix = [
+    [0.299, 0.587, 0.114],
+    [0.299, 0.587, 0.114],
+    [0.299, 0.587, 0.114],
+];
+
+const SEPIA: ColorMatrix = [
+    [0.393, 0.769, 0.189],
+    [0.349, 0.686, 0.168],
+    [0.272, 0.534, 0.131],
+];
+
+// A 180 degree hue rotation. After inverting, this swings hues back to roughly where
+// they started so that water stays blue and forests stay green.
+const HUE_ROTATE_180: ColorMatrix = [
+    [-0.574, 1.430, 0.144],
+    [0.426, 0.430, 0.144],
+    [0.426, 1.430, -0.856],
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    Grayscale,
+    Sepia,
+    // Inverted lightness, for dark UI themes
+    Dark,
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "grayscale" | "greyscale" => Ok(Filter::Grayscale),
+            "sepia" => Ok(Filter::Sepia),
+            "dark" => Ok(Filter::Dark),
+            other => Err(anyhow!("Unknown filter {}", other)),
+        }
+    }
+}
+
+pub fn apply_filter(img: &mut RgbaImage, filter: Filter) {
+    match filter {
+        Filter::Grayscale => apply_matrix(img, &GRAYSCALE),
+        Filter::Sepia => apply_matrix(img, &SEPIA),
+        Filter::Dark => {
+            invert(img);
+            apply_matrix(img, &HUE_ROTATE_180);
+        }
+    }
+}
+
+// The range of factors the output can be scaled by
+pub const MIN_SCALE: f32 = 0.1;
+pub const MAX_SCALE: f32 = 4.0;
+
+// The algorithm used when resizing. Nearest keeps hard pixel edges, the others trade
+// speed for smoothness; text in the basemap tends to look best with Lanczos3.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Resample {
+    Nearest,
+    Bilinear,
+    #[default]
+    CatmullRom,
+    Lanczos3,
+}
+
+impl FromStr for Resample {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "nearest" => Ok(Resample::Nearest),
+            "bilinear" => Ok(Resample::Bilinear),
+            "catmullrom" | "bicubic" => Ok(Resample::CatmullRom),
+            "lanczos3" | "lanczos" => Ok(Resample::Lanczos3),
+            other => Err(anyhow!("Unknown resample algorithm {}", other)),
+        }
+    }
+}
+
+impl Resample {
+    fn filter_type(&self) -> FilterType {
+        match self {
+            Resample::Nearest => FilterType::Nearest,
+            Resample::Bilinear => FilterType::Triangle,
+            Resample::CatmullRom => FilterType::CatmullRom,
+            Resample::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+pub fn resize(img: &RgbaImage, width: u32, height: u32, resample: Resample) -> RgbaImage {
+    imageops::resize(img, width, height, resample.filter_type())
+}
+
+// Checks a scale factor is something we're willing to apply
+pub fn validate_scale(scale: f32) -> Result<f32> {
+    check_range("scale", scale, MIN_SCALE, MAX_SCALE)
+}
+
+// Checks a parameter is a finite number within [min, max]
+fn check_range(name: &str, value: f32, min: f32, max: f32) -> Result<f32> {
+    if value.is_finite() && (min..=max).contains(&value) {
+        Ok(value)
+    } else {
+        Err(anyhow!(
+            "{} must be between {} and {}, got {}",
+            name,
+            min,
+            max,
+            value
+        ))
+    }
+}
+
+// The largest multiplier accepted for any adjustment
+pub const MAX_ADJUSTMENT: f32 = 4.0;
+
+// Brightness, contrast and saturation multipliers. 1.0 leaves the image unchanged, 0.0
+// gives black, flat gray and grayscale respectively.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Adjustments {
+    pub brightness: f32,
+    pub contrast: f32,
+    pub saturation: f32,
+}
+
+impl Default for Adjustments {
+    fn default() -> Self {
+        Adjustments {
+            brightness: 1.0,
+            contrast: 1.0,
+            saturation: 1.0,
+        }
+    }
+}
+
+impl Adjustments {
+    pub fn is_identity(&self) -> bool {
+        *self == Adjustments::default()
+    }
+}
+
+// Checks an adjustment multiplier is something we're willing to apply
+pub fn validate_adjustment(name: &str, value: f32) -> Result<f32> {
+    check_range(name, value, 0.0, MAX_ADJUSTMENT)
+}
+
+pub fn apply_adjustments(img: &mut RgbaImage, adjustments: &Adjustments) {
+    if adjustments.is_identity() {
+        return;
+    }
+
+    let s = adjustments.saturation;
+    let saturate: ColorMatrix = [
+        [0.213 + 0.787 * s, 0.715 - 0.715 * s, 0.072 - 0.072 * s],
+        [0.213 - 0.213 * s, 0.715 + 0.285 * s, 0.072 - 0.072 * s],
+        [0.213 - 0.213 * s, 0.715 - 0.715 * s, 0.072 + 0.928 * s],
+    ];
+
+    for pixel in img.pixels_mut() {
+        let [r, g, b, a] = pixel.0;
+        let rgb = [r as f32, g as f32, b as f32];
+        let mut out = [0u8; 3];
+        for (i, row) in saturate.iter().enumerate() {
+            let value = row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2];
+            let value = value * adjustments.brightness;
+            let value = (value - 127.5) * adjustments.contrast + 127.5;
+            out[i] = value.round().clamp(0.0, 255.0) as u8;
+        }
+        pixel.0 = [out[0], out[1], out[2], a];
+    }
+}
+
+// The range of gamma values accepted. Values above 1 brighten the shadows.
+pub const MIN_GAMMA: f32 = 0.1;
+pub const MAX_GAMMA: f32 = 5.0;
+
+// Checks a gamma value is something we're willing to apply
+pub fn validate_gamma(gamma: f32) -> Result<f32> {
+    check_range("gamma", gamma, MIN_GAMMA, MAX_GAMMA)
+}
+
+// The largest blur radii (Gaussian sigma, in pixels) accepted for sharpening and blurring
+pub const MAX_SHARPEN_SIGMA: f32 = 10.0;
+pub const MAX_BLUR_SIGMA: f32 = 50.0;
+
+// Differences smaller than this aren't sharpened, so flat areas don't get noisy
+const SHARPEN_THRESHOLD: i32 = 2;
+
+pub fn validate_sharpen(sigma: f32) -> Result<f32> {
+    check_range("sharpen", sigma, 0.1, MAX_SHARPEN_SIGMA)
+}
+
+pub fn validate_blur(sigma: f32) -> Result<f32> {
+    check_range("blur", sigma, 0.1, MAX_BLUR_SIGMA)
+}
+
+// Unsharp-mask sharpening, mostly useful after downsampling imagery
+pub fn sharpen(img: &RgbaImage, sigma: f32) -> RgbaImage {
+    imageops::unsharpen(img, sigma, SHARPEN_THRESHOLD)
+}
+
+// Gaussian blur, e.g. to obscure a map used as a background
+pub fn blur(img: &RgbaImage, sigma: f32) -> RgbaImage {
+    imageops::blur(img, sigma)
+}
+
+// A tone curve through a handful of (input, output) control points, interpolated
+// linearly. Inputs before the first point or after the last are held flat.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToneCurve {
+    points: Vec<(u8, u8)>,
+}
+
+impl FromStr for ToneCurve {
+    type Err = anyhow::Error;
+
+    // Parses "in:out,in:out,...", e.g. "0:0,64:110,255:255" to lift the shadows
+    fn from_str(s: &str) -> Result<Self> {
+        let points = s
+            .split(',')
+            .map(|point| {
+                let (input, output) = point
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("Curve points look like in:out, got {}", point))?;
+                let value = |v: &str| {
+                    v.trim()
+                        .parse::<u8>()
+                        .map_err(|_| anyhow!("Curve values must be 0-255, got {}", v))
+                };
+                Ok((value(input)?, value(output)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        if points.len() < 2 {
+            return Err(anyhow!("A curve needs at least two points"));
+        }
+        if points.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+            return Err(anyhow!("Curve inputs must be increasing"));
+        }
+        Ok(ToneCurve { points })
+    }
+}
+
+impl ToneCurve {
+    fn map(&self, input: f32) -> f32 {
+        let first = self.points[0];
+        let last = self.points[self.points.len() - 1];
+        if input <= first.0 as f32 {
+            return first.1 as f32;
+        }
+        if input >= last.0 as f32 {
+            return last.1 as f32;
+        }
+        for pair in self.points.windows(2) {
+            let ((x0, y0), (x1, y1)) = (pair[0], pair[1]);
+            let (x0, y0, x1, y1) = (x0 as f32, y0 as f32, x1 as f32, y1 as f32);
+            if input <= x1 {
+                return y0 + (input - x0) * (y1 - y0) / (x1 - x0);
+            }
+        }
+        last.1 as f32
+    }
+}
+
+// Applies gamma and then the tone curve to each color channel, through a lookup table
+pub fn apply_tone(img: &mut RgbaImage, gamma: Option<f32>, curve: Option<&ToneCurve>) {
+    if gamma.is_none() && curve.is_none() {
+        return;
+    }
+
+    let mut table = [0u8; 256];
+    for (input, entry) in table.iter_mut().enumerate() {
+        let mut value = input as f32;
+        if let Some(gamma) = gamma {
+            value = 255.0 * (value / 255.0).powf(1.0 / gamma);
+        }
+        if let Some(curve) = curve {
+            value = curve.map(value);
+        }
+        *entry = value.round().clamp(0.0, 255.0) as u8;
+    }
+
+    for pixel in img.pixels_mut() {
+        for channel in pixel.0.iter_mut().take(3) {
+            *channel = table[*channel as usize];
+        }
+    }
+}
+
+// Contrast enhancement computed from the image's own luminance histogram, for dark or
+// hazy satellite imagery
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Equalize {
+    // Stretches the luminance range linearly, ignoring a sliver of outliers at each end
+    Stretch,
+    // Contrast-limited histogram equalization, spreading common luminances apart
+    Histogram,
+}
+
+impl FromStr for Equalize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "stretch" => Ok(Equalize::Stretch),
+            "histogram" => Ok(Equalize::Histogram),
+            other => Err(anyhow!("Unknown equalization {}", other)),
+        }
+    }
+}
+
+// The fraction of pixels at each end of the range a stretch ignores
+const STRETCH_CLIP: f32 = 0.005;
+
+// Histogram bins are capped at this multiple of the average, so large uniform areas like
+// water don't get all the contrast
+const HISTOGRAM_CLIP_LIMIT: f32 = 4.0;
+
+// Pixels are brightened by at most this factor, so noise in the shadows isn't blown up
+const MAX_LUMA_GAIN: f32 = 3.0;
+
+// Remaps luminance and scales each pixel's channels by the same factor, so hue and
+// saturation stay as they were. A channel that would clip caps the factor instead.
+pub fn apply_equalize(img: &mut RgbaImage, equalize: Equalize) {
+    let mut histogram = [0u32; 256];
+    for pixel in img.pixels().filter(|p| p[3] > 0) {
+        histogram[luma(pixel.0).round() as usize] += 1;
+    }
+    let total: u32 = histogram.iter().sum();
+    if total == 0 {
+        return;
+    }
+    let table = match equalize {
+        Equalize::Stretch => stretch_table(&histogram, total),
+        Equalize::Histogram => equalize_table(&histogram, total),
+    };
+
+    for pixel in img.pixels_mut() {
+        let [r, g, b, a] = pixel.0;
+        let luma = luma(pixel.0);
+        if luma < 1.0 {
+            continue;
+        }
+        let brightest = r.max(g).max(b) as f32;
+        let gain = (table[luma.round() as usize] / luma)
+            .min(MAX_LUMA_GAIN)
+            .min(255.0 / brightest);
+        let [r, g, b] = [r, g, b].map(|c| (c as f32 * gain).round().clamp(0.0, 255.0) as u8);
+        pixel.0 = [r, g, b, a];
+    }
+}
+
+fn luma(pixel: [u8; 4]) -> f32 {
+    0.299 * pixel[0] as f32 + 0.587 * pixel[1] as f32 + 0.114 * pixel[2] as f32
+}
+
+// Maps the luminances between the clipped ends of the histogram onto the full range
+fn stretch_table(histogram: &[u32; 256], total: u32) -> [f32; 256] {
+    let clip = (total as f32 * STRETCH_CLIP) as u32;
+    let mut seen = 0;
+    let low = histogram
+        .iter()
+        .position(|&count| {
+            seen += count;
+            seen > clip
+        })
+        .unwrap_or(0);
+    seen = 0;
+    let high = 255
+        - histogram
+            .iter()
+            .rev()
+            .position(|&count| {
+                seen += count;
+                seen > clip
+            })
+            .unwrap_or(0);
+
+    std::array::from_fn(|v| {
+        if high <= low {
+            v as f32
+        } else {
+            ((v as f32 - low as f32) / (high - low) as f32 * 255.0).clamp(0.0, 255.0)
+        }
+    })
+}
+
+// Maps each luminance to its place in the clipped cumulative histogram
+fn equalize_table(histogram: &[u32; 256], total: u32) -> [f32; 256] {
+    let limit = total as f32 / 256.0 * HISTOGRAM_CLIP_LIMIT;
+    let excess: f32 = histogram
+        .iter()
+        .map(|&count| (count as f32 - limit).max(0.0))
+        .sum();
+    let mut cumulative = 0.0;
+    std::array::from_fn(|v| {
+        cumulative += (histogram[v] as f32).min(limit) + excess / 256.0;
+        cumulative / total as f32 * 255.0
+    })
+}
+
+// Where hues are moved to by the color-blind-safe recoloring, as (from, to) in degrees.
+// Warm hues go to oranges and yellows and cool ones to blues, so that red and green,
+// e.g. trail difficulty markings, differ along the blue-yellow axis that red-green
+// color blind viewers still see. Hues in between are interpolated.
+const COLORBLIND_HUES: [(f32, f32); 7] = [
+    (0.0, 30.0),
+    (60.0, 55.0),
+    (120.0, 200.0),
+    (180.0, 215.0),
+    (240.0, 235.0),
+    (300.0, 320.0),
+    (360.0, 390.0),
+];
+
+// Remaps hues to a color-blind-safe palette, keeping saturation and lightness
+pub fn apply_colorblind_safe(img: &mut RgbaImage) {
+    for pixel in img.pixels_mut() {
+        let [r, g, b, a] = pixel.0;
+        let (hue, saturation, value) = rgb_to_hsv([r, g, b]);
+        if saturation == 0.0 {
+            continue;
+        }
+        let [r, g, b] = hsv_to_rgb(remap_hue(hue), saturation, value);
+        pixel.0 = [r, g, b, a];
+    }
+}
+
+fn remap_hue(hue: f32) -> f32 {
+    let i = COLORBLIND_HUES
+        .windows(2)
+        .position(|w| hue < w[1].0)
+        .unwrap_or(COLORBLIND_HUES.len() - 2);
+    let ((x0, y0), (x1, y1)) = (COLORBLIND_HUES[i], COLORBLIND_HUES[i + 1]);
+    (y0 + (hue - x0) / (x1 - x0) * (y1 - y0)) % 360.0
+}
+
+// Hue in degrees, saturation and value from 0 to 1
+fn rgb_to_hsv(rgb: [u8; 3]) -> (f32, f32, f32) {
+    let [r, g, b] = rgb.map(|c| c as f32 / 255.0);
+    let max = r.max(g).max(b);
+    let delta = max - r.min(g).min(b);
+    let hue = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    let saturation = if max == 0.0 { 0.0 } else { delta / max };
+    (hue, saturation, max)
+}
+
+fn hsv_to_rgb(hue: f32, saturation: f32, value: f32) -> [u8; 3] {
+    let chroma = value * saturation;
+    let h = hue / 60.0;
+    let x = chroma * (1.0 - (h.rem_euclid(2.0) - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = value - chroma;
+    [r, g, b].map(|c| ((c + m) * 255.0).round().clamp(0.0, 255.0) as u8)
+}
+
+fn invert(img: &mut RgbaImage) {
+    for pixel in img.pixels_mut() {
+        for channel in pixel.0.iter_mut().take(3) {
+            *channel = 255 - *channel;
+        }
+    }
+}
+
+fn apply_matrix(img: &mut RgbaImage, matrix: &ColorMatrix) {
+    for pixel in img.pixels_mut() {
+        let [r, g, b, a] = pixel.0;
+        let rgb = [r as f32, g as f32, b as f32];
+        let mut out = [0u8; 3];
+        for (i, row) in matrix.iter().enumerate() {
+            let value = row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2];
+            out[i] = value.round().clamp(0.0, 255.0) as u8;
+        }
+        pixel.0 = [out[0], out[1], out[2], a];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn single(pixel: [u8; 4], filter: Filter) -> [u8; 4] {
+        let mut img = RgbaImage::from_pixel(1, 1, Rgba(pixel));
+        apply_filter(&mut img, filter);
+        img.get_pixel(0, 0).0
+    }
+
+    #[test]
+    fn test_parse_filter() {
+        assert_eq!("sepia".parse::<Filter>().unwrap(), Filter::Sepia);
+        assert!("vaporwave".parse::<Filter>().is_err());
+    }
+
+    #[test]
+    fn test_grayscale() {
+        let [r, g, b, a] = single([255, 0, 0, 200], Filter::Grayscale);
+        assert_eq!((r, g, b, a), (76, 76, 76, 200));
+    }
+
+    fn adjusted(pixel: [u8; 4], adjustments: Adjustments) -> [u8; 4] {
+        let mut img = RgbaImage::from_pixel(1, 1, Rgba(pixel));
+        apply_adjustments(&mut img, &adjustments);
+        img.get_pixel(0, 0).0
+    }
+
+    #[test]
+    fn test_adjustments() {
+        let pixel = [40, 120, 200, 255];
+        assert_eq!(adjusted(pixel, Adjustments::default()), pixel);
+
+        let dim = Adjustments {
+            brightness: 0.5,
+            ..Default::default()
+        };
+        assert_eq!(adjusted(pixel, dim), [20, 60, 100, 255]);
+
+        let flat = Adjustments {
+            contrast: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(adjusted(pixel, flat), [128, 128, 128, 255]);
+
+        let desaturated = Adjustments {
+            saturation: 0.0,
+            ..Default::default()
+        };
+        let [r, g, b, _] = adjusted(pixel, desaturated);
+        assert!(r == g && g == b);
+    }
+
+    #[test]
+    fn test_validate_adjustment() {
+        assert_eq!(validate_adjustment("contrast", 1.5).unwrap(), 1.5);
+        assert!(validate_adjustment("contrast", -0.1).is_err());
+        assert!(validate_adjustment("contrast", f32::NAN).is_err());
+        assert!(validate_adjustment("contrast", 10.0).is_err());
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut img = RgbaImage::new(4, 4);
+        img.put_pixel(0, 0, Rgba([255, 255, 255, 255]));
+
+        let resized = resize(&img, 2, 2, Resample::Nearest);
+        assert_eq!(resized.dimensions(), (2, 2));
+        assert_eq!("lanczos3".parse::<Resample>().unwrap(), Resample::Lanczos3);
+        assert!("sinc".parse::<Resample>().is_err());
+        assert!(validate_scale(0.5).is_ok());
+        assert!(validate_scale(0.0).is_err());
+    }
+
+    #[test]
+    fn test_parse_tone_curve() {
+        assert!("0:0,64:110,255:255".parse::<ToneCurve>().is_ok());
+        assert!("0:0".parse::<ToneCurve>().is_err());
+        assert!("0:0,300:255".parse::<ToneCurve>().is_err());
+        assert!("128:0,64:255".parse::<ToneCurve>().is_err());
+    }
+
+    #[test]
+    fn test_apply_tone() {
+        let toned = |gamma: Option<f32>, curve: Option<&ToneCurve>| {
+            let mut img = RgbaImage::from_pixel(1, 1, Rgba([0, 64, 255, 90]));
+            apply_tone(&mut img, gamma, curve);
+            img.get_pixel(0, 0).0
+        };
+
+        assert_eq!(toned(None, None), [0, 64, 255, 90]);
+
+        // Gamma keeps black and white where they are but lifts everything between
+        let [r, g, b, a] = toned(Some(2.0), None);
+        assert_eq!((r, b, a), (0, 255, 90));
+        assert_eq!(g, 128);
+
+        let curve: ToneCurve = "0:0,64:128,255:255".parse().unwrap();
+        assert_eq!(toned(None, Some(&curve)), [0, 128, 255, 90]);
+        let flat: ToneCurve = "32:100,200:100".parse().unwrap();
+        assert_eq!(toned(None, Some(&flat)), [100, 100, 100, 90]);
+    }
+
+    #[test]
+    fn test_sharpen_and_blur() {
+        let mut img = RgbaImage::from_pixel(9, 9, Rgba([100, 100, 100, 255]));
+        img.put_pixel(4, 4, Rgba([200, 200, 200, 255]));
+
+        let blurred = blur(&img, 1.0);
+        assert!(blurred.get_pixel(4, 4)[0] < 200);
+        assert!(blurred.get_pixel(4, 5)[0] > 100);
+
+        let sharpened = sharpen(&img, 1.0);
+        assert!(sharpened.get_pixel(4, 4)[0] > 200);
+
+        assert!(validate_blur(0.0).is_err());
+        assert!(validate_sharpen(100.0).is_err());
+    }
+
+    #[test]
+    fn test_stretch_expands_hazy_range() {
+        // A hazy gradient between luminance 100 and 150
+        let mut img = RgbaImage::from_fn(51, 1, |x, _| {
+            let v = 100 + x as u8;
+            Rgba([v, v, v, 255])
+        });
+        apply_equalize(&mut img, Equalize::Stretch);
+        assert!(img.get_pixel(0, 0)[0] <= 5);
+        assert_eq!(img.get_pixel(50, 0)[0], 255);
+    }
+
+    #[test]
+    fn test_equalize_keeps_hue() {
+        let mut img = RgbaImage::from_fn(64, 1, |x, _| {
+            let v = 20 + x as u8;
+            Rgba([v * 2, v, v / 2, 255])
+        });
+        let before = rgb_to_hsv([
+            img.get_pixel(10, 0)[0],
+            img.get_pixel(10, 0)[1],
+            img.get_pixel(10, 0)[2],
+        ]);
+        apply_equalize(&mut img, Equalize::Histogram);
+        let p = img.get_pixel(10, 0);
+        let after = rgb_to_hsv([p[0], p[1], p[2]]);
+        assert!((before.0 - after.0).abs() < 2.0);
+        assert!((before.1 - after.1).abs() < 0.05);
+        assert!(after.2 > before.2);
+    }
+
+    #[test]
+    fn test_equalize_gain_is_capped() {
+        // A dark image between 20 and 40, where stretching would lift 30 to mid gray
+        let mut img = RgbaImage::from_fn(10, 10, |x, _| {
+            let v = if x < 5 { 20 } else { 40 };
+            Rgba([v, v, v, 255])
+        });
+        img.put_pixel(0, 0, Rgba([30, 30, 30, 255]));
+        apply_equalize(&mut img, Equalize::Stretch);
+        assert_eq!(img.get_pixel(0, 0), &Rgba([90, 90, 90, 255]));
+    }
+
+    #[test]
+    fn test_hsv_round_trip() {
+        for rgb in [[255, 0, 0], [12, 200, 90], [30, 30, 200], [128, 128, 128]] {
+            let (h, s, v) = rgb_to_hsv(rgb);
+            assert_eq!(hsv_to_rgb(h, s, v), rgb);
+        }
+    }
+
+    #[test]
+    fn test_colorblind_safe_separates_red_and_green() {
+        let mut img = RgbaImage::from_fn(3, 1, |x, _| match x {
+            0 => Rgba([220, 0, 0, 255]),
+            1 => Rgba([0, 180, 0, 255]),
+            _ => Rgba([90, 90, 90, 255]),
+        });
+        apply_colorblind_safe(&mut img);
+
+        // Red becomes orange and green becomes blue; grays are left alone
+        let [r, g, b, _] = img.get_pixel(0, 0).0;
+        assert!(r > g && g > b);
+        let [r, g, b, _] = img.get_pixel(1, 0).0;
+        assert!(b > g && g > r);
+        assert_eq!(img.get_pixel(2, 0), &Rgba([90, 90, 90, 255]));
+    }
+
+    #[test]
+    fn test_dark_swaps_lightness() {
+        assert_eq!(single([255, 255, 255, 255], Filter::Dark), [0, 0, 0, 255]);
+        assert_eq!(single([0, 0, 0, 255], Filter::Dark), [255, 255, 255, 255]);
+        // Pure gray stays gray after the hue rotation
+        let [r, g, b, _] = single([200, 200, 200, 255], Filter::Dark);
+        assert!(r.abs_diff(55) <= 1 && g.abs_diff(55) <= 1 && b.abs_diff(55) <= 1);
+    }
+}
@@ -0,0 +1,123 @@
+// ! # extent
+// ! Where a rendered image lies in the world, for clients that draw it as an overlay on
+// ! an interactive map. The extent follows the same steps a render does, without fetching
+// ! anything: the tile box for the center, radius and size, the crop around the center
+// ! (which stops at the edge of the world), exact= and scale= resizing, and the frame
+// ! around the image, whose border and background lie in the world like the map does.
+// !
+// ! Images are drawn north up in Web Mercator, so the extent is a rectangle in lat/long
+// ! and an overlay stretched over it lines up with the map. A crop= polygon only makes
+// ! pixels transparent, so it doesn't change the extent.
+
+use crate::coordinates::LatLong;
+use crate::tiles::RenderOptions;
+use serde_json::{json, Value};
+use tile_geometry::viewport;
+
+// The rendered image's size and where its corners are
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Extent {
+    pub width: u32,
+    pub height: u32,
+    pub north_west: LatLong,
+    pub south_east: LatLong,
+}
+
+// The extent of the image fetch_image_from_point renders for the same arguments
+pub fn image_extent(
+    center: LatLong,
+    radius_km: f32,
+    image_size: u32,
+    options: &RenderOptions,
+) -> Extent {
+    let ((width, height), viewport) = viewport::image_viewport(
+        center,
+        radius_km,
+        image_size,
+        options.exact_size,
+        options.scale,
+    );
+    let padding = options.frame.as_ref().map_or(0, |frame| frame.padding());
+    let pad = padding as f64;
+    Extent {
+        width: width + 2 * padding,
+        height: height + 2 * padding,
+        north_west: viewport.unproject(-pad, -pad),
+        south_east: viewport.unproject(width as f64 + pad, height as f64 + pad),
+    }
+}
+
+impl Extent {
+    // The extent as a GeoJSON Feature: a Polygon with its exterior ring counterclockwise,
+    // starting from the south west corner, with the image's size in its properties
+    pub fn to_geojson(&self) -> Value {
+        let LatLong(north, west) = self.north_west;
+        let LatLong(south, east) = self.south_east;
+        json!({
+            "type": "Feature",
+            "bbox": [west, south, east, north],
+            "geometry": {
+                "type": "Polygon",
+                "coordinates": [[
+                    [west, south],
+                    [east, south],
+                    [east, north],
+                    [west, north],
+                    [west, south],
+                ]],
+            },
+            "properties": {
+                "width": self.width,
+                "height": self.height,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::Frame;
+    use image::Rgba;
+
+    #[test]
+    fn test_image_extent() {
+        let center = LatLong(46.655559, 8.102121);
+        let extent = image_extent(center, 1.0, 512, &RenderOptions::default());
+        assert!(extent.width >= 512 && extent.height >= 512);
+        let (LatLong(north, west), LatLong(south, east)) = (extent.north_west, extent.south_east);
+        assert!(south < center.0 && center.0 < north);
+        assert!(west < center.1 && center.1 < east);
+
+        // Centered on the point, give or take the crop's rounding
+        let degrees_per_px = (east - west) / extent.width as f64;
+        assert!(((west + east) / 2.0 - center.1).abs() <= degrees_per_px);
+
+        // A frame adds to the image all round, and a scale doesn't change the area
+        let options = RenderOptions {
+            frame: Some(Frame {
+                border_width: 10,
+                border_color: Rgba([0, 0, 0, 255]),
+                shadow: 0,
+                background: Rgba([0, 0, 0, 0]),
+            }),
+            scale: Some(0.5),
+            ..Default::default()
+        };
+        let framed = image_extent(center, 1.0, 512, &options);
+        assert_eq!(
+            framed.width,
+            (extent.width as f32 * 0.5).round() as u32 + 20
+        );
+        assert!(framed.north_west.0 > north && framed.north_west.1 < west);
+        assert!(framed.south_east.0 < south && framed.south_east.1 > east);
+
+        let geojson = extent.to_geojson();
+        let ring = geojson["geometry"]["coordinates"][0].as_array().unwrap();
+        assert_eq!(ring.len(), 5);
+        assert_eq!(ring[0], ring[4]);
+        assert_eq!(ring[0], json!([west, south]));
+        assert_eq!(ring[2], json!([east, north]));
+        assert_eq!(geojson["properties"]["width"], extent.width);
+    }
+}
@@ -0,0 +1,244 @@
+// ! # faults
+// ! Fault injection for chaos testing and demos: upstream tile fetches can be slowed
+// ! down, failed or handed back corrupt bytes, per tileset, so what the service does
+// ! when an upstream misbehaves can be seen in traces and logs on demand.
+// !
+// ! Faults are planned per tileset name, or for every tileset with "*". The plan comes
+// ! from one of two places: a process-wide plan set with set_plan, e.g. from an admin
+// ! endpoint, or a plan scoped to one request with scope, which wins for that request.
+// ! Plans are written as, e.g.
+// !
+// !   osm=latency:500,error:0.2;swisstopo=corrupt:1
+// !
+// ! which delays every OSM tile by 500ms and fails a fifth of them, and corrupts every
+// ! swisstopo tile. Nothing is injected unless TileSources has been built with faults
+// ! enabled, so a production build can't be made to misbehave by a request.
+
+use crate::tiles::TileSet;
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use log::warn;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{OnceLock, RwLock};
+use std::time::Duration;
+
+// The plan key for faults on every tileset
+pub const ALL_TILESETS: &str = "*";
+
+// The longest delay that can be injected, so a typo can't hang requests indefinitely
+const MAX_LATENCY_MS: u64 = 60_000;
+
+// What to do to a tileset's fetches
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Fault {
+    // Added before every fetch
+    pub latency_ms: u64,
+    // The fraction of fetches that fail
+    pub error_rate: f64,
+    // The fraction of fetched tiles whose bytes are mangled
+    pub corrupt_rate: f64,
+}
+
+impl Fault {
+    fn validate(&self) -> Result<()> {
+        if self.latency_ms > MAX_LATENCY_MS {
+            return Err(anyhow!("Latency can be at most {}ms", MAX_LATENCY_MS));
+        }
+        for rate in [self.error_rate, self.corrupt_rate] {
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(anyhow!("Rates must be between 0 and 1, not {}", rate));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Faults by tileset name, or ALL_TILESETS
+pub type FaultPlan = HashMap<String, Fault>;
+
+// Checks that a plan only names real tilesets and sensible faults
+pub fn validate(plan: &FaultPlan) -> Result<()> {
+    for (name, fault) in plan {
+        if name != ALL_TILESETS && TileSet::lookup(name).is_none() {
+            return Err(anyhow!("Unknown tileset {}", name));
+        }
+        fault
+            .validate()
+            .with_context(|| format!("Invalid fault for {}", name))?;
+    }
+    Ok(())
+}
+
+// Parses a plan written as tileset=kind:value,...;tileset=...
+pub fn parse(value: &str) -> Result<FaultPlan> {
+    let mut plan = FaultPlan::new();
+    for entry in value.split(';').filter(|e| !e.trim().is_empty()) {
+        let (name, faults) = entry
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expected tileset=faults, got {}", entry))?;
+        let mut fault = Fault::default();
+        for part in faults.split(',') {
+            let (kind, amount) = part
+                .split_once(':')
+                .ok_or_else(|| anyhow!("Expected kind:value, got {}", part))?;
+            let invalid = || format!("Invalid {} in {}", kind, entry);
+            match kind.trim() {
+                "latency" => fault.latency_ms = amount.trim().parse().with_context(invalid)?,
+                "error" => fault.error_rate = amount.trim().parse().with_context(invalid)?,
+                "corrupt" => fault.corrupt_rate = amount.trim().parse().with_context(invalid)?,
+                _ => return Err(anyhow!("Unknown fault {} in {}", kind, entry)),
+            }
+        }
+        plan.insert(name.trim().to_string(), fault);
+    }
+    validate(&plan)?;
+    Ok(plan)
+}
+
+static PLAN: OnceLock<RwLock<FaultPlan>> = OnceLock::new();
+
+tokio::task_local! {
+    static REQUEST_PLAN: FaultPlan;
+}
+
+fn global() -> &'static RwLock<FaultPlan> {
+    PLAN.get_or_init(|| RwLock::new(FaultPlan::new()))
+}
+
+// Replaces the process-wide plan. An empty plan stops injecting faults.
+pub fn set_plan(plan: FaultPlan) -> Result<()> {
+    validate(&plan)?;
+    *global().write().unwrap_or_else(|e| e.into_inner()) = plan;
+    Ok(())
+}
+
+pub fn plan() -> FaultPlan {
+    global().read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+// Runs a future with its own plan, e.g. one from a request header, in place of the
+// process-wide one. Only fetches made within the future itself see it.
+pub async fn scope<F: Future>(plan: FaultPlan, f: F) -> F::Output {
+    REQUEST_PLAN.scope(plan, f).await
+}
+
+// The fault for a tileset under the current plan, if there is one
+fn fault_for(tileset: TileSet) -> Option<Fault> {
+    let pick = |plan: &FaultPlan| {
+        plan.get(tileset.name())
+            .or_else(|| plan.get(ALL_TILESETS))
+            .copied()
+    };
+    REQUEST_PLAN
+        .try_with(pick)
+        .unwrap_or_else(|_| pick(&global().read().unwrap_or_else(|e| e.into_inner())))
+}
+
+// Mangles a tile so it no longer decodes: the PNG signature is scrambled and the rest cut
+// short
+fn corrupt(tile: &[u8]) -> Bytes {
+    let mut bytes: Vec<u8> = tile[..tile.len() / 2].to_vec();
+    for b in bytes.iter_mut().take(8) {
+        *b ^= 0xa5;
+    }
+    Bytes::from(bytes)
+}
+
+// Applies the current plan's fault for the tileset to a fetch
+pub async fn inject<F>(tileset: TileSet, fetch: F) -> Result<Bytes>
+where
+    F: Future<Output = Result<Bytes>>,
+{
+    let Some(fault) = fault_for(tileset) else {
+        return fetch.await;
+    };
+    if fault.latency_ms > 0 {
+        tokio::time::sleep(Duration::from_millis(fault.latency_ms)).await;
+    }
+    // Decided up front, as the thread's rng can't be held across an await
+    let (fail, mangle) = {
+        let mut rng = rand::thread_rng();
+        (
+            rng.gen_bool(fault.error_rate),
+            rng.gen_bool(fault.corrupt_rate),
+        )
+    };
+    if fail {
+        warn!(tileset = tileset.name(); "Injecting an upstream error");
+        return Err(anyhow!(
+            "Injected fault: upstream {} failed",
+            tileset.name()
+        ));
+    }
+    let tile = fetch.await?;
+    if mangle {
+        warn!(tileset = tileset.name(); "Injecting a corrupt tile");
+        return Ok(corrupt(&tile));
+    }
+    Ok(tile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile() -> Bytes {
+        Bytes::from_static(b"\x89PNG\r\n\x1a\n and the rest of the tile")
+    }
+
+    #[test]
+    fn test_parse() {
+        let plan = parse("osm=latency:500,error:0.2; *=corrupt:1").unwrap();
+        assert_eq!(
+            plan["osm"],
+            Fault {
+                latency_ms: 500,
+                error_rate: 0.2,
+                corrupt_rate: 0.0,
+            }
+        );
+        assert_eq!(plan[ALL_TILESETS].corrupt_rate, 1.0);
+        assert!(parse("").unwrap().is_empty());
+
+        assert!(parse("osm").is_err());
+        assert!(parse("osm=slow:1").is_err());
+        assert!(parse("osm=error:2").is_err());
+        assert!(parse("osm=latency:600000").is_err());
+        assert!(parse("mars=error:1").is_err());
+    }
+
+    // The process-wide plan is shared by every test, so it's only touched here
+    #[tokio::test]
+    async fn test_inject() {
+        let ok = || async { Ok(tile()) };
+
+        set_plan(parse("osm=error:1;swisstopo=corrupt:1").unwrap()).unwrap();
+        assert!(inject(TileSet::Osm, ok()).await.is_err());
+        let corrupted = inject(TileSet::Swisstopo, ok()).await.unwrap();
+        assert_ne!(corrupted, tile());
+        assert!(image::load_from_memory(&corrupted).is_err());
+        assert_eq!(inject(TileSet::Terrain, ok()).await.unwrap(), tile());
+
+        // A request's own plan replaces the process-wide one
+        let healthy = scope(FaultPlan::new(), inject(TileSet::Osm, ok())).await;
+        assert_eq!(healthy.unwrap(), tile());
+        let all = scope(parse("*=error:1").unwrap(), inject(TileSet::Terrain, ok())).await;
+        assert!(all.is_err());
+
+        set_plan(FaultPlan::new()).unwrap();
+        assert_eq!(inject(TileSet::Osm, ok()).await.unwrap(), tile());
+        assert!(set_plan(
+            parse("osm=error:1")
+                .map(|mut p| {
+                    p.insert("mars".to_string(), Fault::default());
+                    p
+                })
+                .unwrap()
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,984 @@
+// ! # fetcher
+// ! Where tiles come from. The render pipeline asks a TileFetcher for each tile rather than
+// ! going to the network itself, so a tileset can be served from its upstream tile server,
+// ! an MBTiles file or a directory of tiles, and tests can use tiles held in memory.
+// ! TileSources picks the fetcher for each tileset from TILESET_<NAME>_SOURCE:
+// !
+// !   http (default)   - the tileset's upstream URL
+// !   mbtiles:<path>   - an MBTiles (SQLite) file, opened read only
+// !   pmtiles:<path or url> - a PMTiles archive, on disk or read with HTTP range requests
+// !                      (see the pmtiles module)
+// !   dir:<path>       - a directory of <z>/<x>/<y>.png files
+// !   wms:<url>        - a WMS 1.3.0 server, asked for each tile with GetMap (see the wms
+// !                      module)
+// !   wmts:<url>#<layer> - a layer of a WMTS server, from its GetCapabilities URL (see the
+// !                      wmts module)
+// !
+// ! TILESET_<NAME>_URL points a tileset's HTTP fetches somewhere other than its upstream,
+// ! e.g. a mirror or a mock server, as a {z}/{x}/{y} URL pattern, or a {quadkey} one for
+// ! servers that address tiles the way Bing Maps does. TILESET_<NAME>_SCHEME=tms flips
+// ! {y} for TMS servers, which number rows from the bottom of the world, overriding the
+// ! scheme a registry tileset is configured with. TILESET_<NAME>_MIRRORS lists mirrors
+// ! to fail over to when the tileset's upstream is down (see the mirrors module). Without
+// ! a URL, a tileset with an archive is fetched from its edition for the render's date
+// ! (see the archive module), and one with labels in the render's language from that
+// ! variant's server (see the locale module).
+// !
+// ! Upstreams that need an API key, like Mapbox, take it from TILESET_<NAME>_TOKEN, filled
+// ! in for a {token} placeholder. Built-in tilesets that need one are disabled until it's
+// ! set (see the registry module), and a tileset configured with
+// ! TILESET_<NAME>_SOURCE=http or TILESET_<NAME>_URL but no token it needs is an error at
+// ! startup. The token is kept out of span attributes, logs and errors.
+// ! MAX_TILE_BYTES caps how much of each tile response is read, so a misbehaving
+// ! upstream can't run us out of memory. Tiles fetched over HTTP are checked by the
+// ! integrity module, and go through the TileCache, if one is set.
+// !
+// ! Tests use a FixtureFetcher, which replays tiles recorded to disk instead of fetching
+// ! them. With TILE_FIXTURES=record, tiles that haven't been recorded yet are fetched and
+// ! saved, so recording them is a matter of running the tests once.
+
+use crate::cache::{self, CachedTile, TileCache};
+use crate::coordinates::tile_to_quadkey;
+use crate::debug_tiles::debug_tile;
+use crate::mirrors::Mirrors;
+use crate::pmtiles::PmTilesFetcher;
+use crate::registry::Scheme;
+use crate::tiles::{encode_tile, TileSet};
+use crate::{archive, faults, integrity, locale, mvt, transport, upstreams, url_guard, wms, wmts};
+use anyhow::{anyhow, Context as _, Result};
+use bytes::Bytes;
+use futures::future::LocalBoxFuture;
+use opentelemetry::Context;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use std::collections::HashMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+
+pub trait TileFetcher: Send + Sync {
+    // Fetches the PNG for a tile. The futures aren't Send, as awc's aren't.
+    fn fetch(
+        &self,
+        tileset: TileSet,
+        x: u32,
+        y: u32,
+        z: u32,
+        cx: Context,
+    ) -> LocalBoxFuture<'_, Result<Bytes>>;
+}
+
+// The most of a tile response that's read unless MAX_TILE_BYTES says otherwise. Real
+// tiles are tens of kilobytes.
+pub const DEFAULT_MAX_TILE_BYTES: usize = 4 * 1024 * 1024;
+
+// Fetches tiles from the tileset's upstream tile server, or from the URL it's been
+// pointed at instead
+pub struct HttpFetcher {
+    urls: HashMap<&'static str, String>,
+    tokens: HashMap<&'static str, String>,
+    schemes: HashMap<&'static str, Scheme>,
+    mirrors: HashMap<&'static str, Mirrors>,
+    max_tile_bytes: usize,
+}
+
+impl Default for HttpFetcher {
+    fn default() -> HttpFetcher {
+        HttpFetcher {
+            urls: HashMap::new(),
+            tokens: HashMap::new(),
+            schemes: HashMap::new(),
+            mirrors: HashMap::new(),
+            max_tile_bytes: DEFAULT_MAX_TILE_BYTES,
+        }
+    }
+}
+
+impl HttpFetcher {
+    // Fetches the tileset from a {z}/{x}/{y} or {quadkey} URL pattern instead of its
+    // upstream
+    pub fn with_url(mut self, tileset: TileSet, pattern: &str) -> Result<HttpFetcher> {
+        check_pattern(pattern)?;
+        self.urls.insert(tileset.name(), pattern.to_string());
+        Ok(self)
+    }
+
+    // Fails over to the mirrors' URL patterns, in order, when the tileset's own URL is
+    // down or fails
+    pub fn with_mirrors(mut self, tileset: TileSet, mirrors: Mirrors) -> Result<HttpFetcher> {
+        for pattern in mirrors.patterns() {
+            check_pattern(pattern)?;
+        }
+        self.mirrors.insert(tileset.name(), mirrors);
+        Ok(self)
+    }
+
+    // Fetches the tileset with GetMap requests to a WMS server instead of its upstream
+    pub fn with_wms(mut self, tileset: TileSet, url: &str) -> Result<HttpFetcher> {
+        self.urls.insert(tileset.name(), wms::getmap_pattern(url)?);
+        Ok(self)
+    }
+
+    // Fills in the tileset's {token} placeholder with an API key
+    pub fn with_token(mut self, tileset: TileSet, token: &str) -> Result<HttpFetcher> {
+        if token.is_empty() {
+            return Err(anyhow!("The {} token is empty", tileset.name()));
+        }
+        self.tokens.insert(tileset.name(), token.to_string());
+        Ok(self)
+    }
+
+    // Numbers the tileset's rows by the given scheme, whatever it's configured with
+    pub fn with_scheme(mut self, tileset: TileSet, scheme: Scheme) -> HttpFetcher {
+        self.schemes.insert(tileset.name(), scheme);
+        self
+    }
+
+    fn scheme(&self, tileset: TileSet) -> Scheme {
+        self.schemes
+            .get(tileset.name())
+            .copied()
+            .unwrap_or_else(|| tileset.scheme())
+    }
+
+    // The URL pattern the tileset is fetched with under the current scope. An explicit
+    // URL wins over the tileset's archive, and that over its localized variants.
+    fn pattern(&self, tileset: TileSet) -> String {
+        self.urls
+            .get(tileset.name())
+            .cloned()
+            .or_else(|| archive::variant(tileset).map(|(_, pattern)| pattern))
+            .or_else(|| locale::variant(tileset).map(|(_, pattern)| pattern.to_string()))
+            .unwrap_or_else(|| tileset.url_pattern().to_string())
+    }
+
+    // Fails if the tileset's URL needs a token and it hasn't been given one
+    pub fn check_token(&self, tileset: TileSet) -> Result<()> {
+        let mirrors = self
+            .mirrors
+            .get(tileset.name())
+            .map_or(&[][..], Mirrors::patterns);
+        let needs_token = self.pattern(tileset).contains("{token}")
+            || mirrors.iter().any(|pattern| pattern.contains("{token}"));
+        if needs_token && !self.tokens.contains_key(tileset.name()) {
+            return Err(anyhow!(
+                "{} needs an API token: set TILESET_{}_TOKEN",
+                tileset.name(),
+                tileset.name().to_uppercase()
+            ));
+        }
+        Ok(())
+    }
+
+    // Reads at most this much of each tile response
+    pub fn with_max_tile_bytes(mut self, max_tile_bytes: usize) -> Result<HttpFetcher> {
+        if max_tile_bytes == 0 {
+            return Err(anyhow!("The tile size limit has to be above 0"));
+        }
+        self.max_tile_bytes = max_tile_bytes;
+        Ok(self)
+    }
+
+    // Reads the TILESET_<NAME>_URL overrides, TILESET_<NAME>_TOKEN keys,
+    // TILESET_<NAME>_SCHEME schemes, TILESET_<NAME>_MIRRORS failovers and MAX_TILE_BYTES
+    pub fn from_env() -> Result<HttpFetcher> {
+        let mut fetcher = HttpFetcher::default();
+        if let Ok(max) = env::var("MAX_TILE_BYTES") {
+            fetcher = fetcher
+                .with_max_tile_bytes(max.parse().context("Invalid MAX_TILE_BYTES")?)
+                .context("Invalid MAX_TILE_BYTES")?;
+        }
+        for tileset in TileSet::all() {
+            let var = format!("TILESET_{}_URL", tileset.name().to_uppercase());
+            if let Ok(pattern) = env::var(&var) {
+                fetcher = fetcher
+                    .with_url(tileset, &pattern)
+                    .with_context(|| format!("Invalid {}", var))?;
+            }
+            let token_var = format!("TILESET_{}_TOKEN", tileset.name().to_uppercase());
+            if let Ok(token) = env::var(&token_var) {
+                fetcher = fetcher
+                    .with_token(tileset, &token)
+                    .with_context(|| format!("Invalid {}", token_var))?;
+                // A tileset's retina tiles come from the same account
+                if let Some(retina) = tileset.retina() {
+                    fetcher = fetcher.with_token(retina, &token)?;
+                }
+            }
+            let scheme_var = format!("TILESET_{}_SCHEME", tileset.name().to_uppercase());
+            if let Ok(scheme) = env::var(&scheme_var) {
+                let scheme = Scheme::from_name(&scheme)
+                    .with_context(|| format!("Invalid {}", scheme_var))?;
+                fetcher = fetcher.with_scheme(tileset, scheme);
+            }
+            let mirrors_var = format!("TILESET_{}_MIRRORS", tileset.name().to_uppercase());
+            if let Ok(mirrors) = env::var(&mirrors_var) {
+                fetcher = Mirrors::parse(&mirrors)
+                    .and_then(|mirrors| fetcher.with_mirrors(tileset, mirrors))
+                    .with_context(|| format!("Invalid {}", mirrors_var))?;
+            }
+            if fetcher.urls.contains_key(tileset.name())
+                || fetcher.mirrors.contains_key(tileset.name())
+            {
+                fetcher.check_token(tileset)?;
+            }
+        }
+        Ok(fetcher)
+    }
+}
+
+impl TileFetcher for HttpFetcher {
+    fn fetch(
+        &self,
+        tileset: TileSet,
+        x: u32,
+        y: u32,
+        z: u32,
+        cx: Context,
+    ) -> LocalBoxFuture<'_, Result<Bytes>> {
+        let pattern = self.pattern(tileset);
+        if pattern.is_empty() {
+            return Box::pin(
+                async move { Err(anyhow!("{} tiles have no upstream", tileset.name())) },
+            );
+        }
+        if let Err(e) = self.check_token(tileset) {
+            return Box::pin(async move { Err(e) });
+        }
+        Box::pin(async move {
+            let started = Instant::now();
+            let fetched = self.fetch_mirrored(tileset, &pattern, x, y, z, cx).await;
+            upstreams::record(tileset, started.elapsed(), fetched.is_ok());
+            fetched
+        })
+    }
+}
+
+impl HttpFetcher {
+    // Fetches a tile from the tileset's own URL or, if that's down or fails, its mirrors
+    async fn fetch_mirrored(
+        &self,
+        tileset: TileSet,
+        own: &str,
+        x: u32,
+        y: u32,
+        z: u32,
+        cx: Context,
+    ) -> Result<Bytes> {
+        let Some(mirrors) = self.mirrors.get(tileset.name()) else {
+            return self.fetch_from(tileset, own, x, y, z, cx).await;
+        };
+        let mut failures = Vec::new();
+        for upstream in mirrors.order(Instant::now()) {
+            let pattern = mirrors.pattern(upstream).unwrap_or(own);
+            let fetched = self.fetch_from(tileset, pattern, x, y, z, cx.clone()).await;
+            mirrors.record(upstream, fetched.is_ok(), Instant::now());
+            match fetched {
+                Ok(tile) => return Ok(tile),
+                Err(e) => failures.push(format!("{:#}", e)),
+            }
+        }
+        Err(anyhow!(
+            "Every {} upstream failed: {}",
+            tileset.name(),
+            failures.join("; ")
+        ))
+    }
+
+    // Fetches a tile from one URL pattern, with its rows numbered by the tileset's scheme
+    // and its token filled in
+    async fn fetch_from(
+        &self,
+        tileset: TileSet,
+        pattern: &str,
+        x: u32,
+        y: u32,
+        z: u32,
+        cx: Context,
+    ) -> Result<Bytes> {
+        let pattern = number_rows(pattern.to_string(), self.scheme(tileset), y, z);
+        let max_bytes = self.max_tile_bytes;
+        match self.tokens.get(tileset.name()) {
+            None => fetch_http(tileset, &pattern, max_bytes, x, y, z, cx).await,
+            // Errors can quote the URL, so the token is scrubbed from them
+            Some(token) => fetch_http(
+                tileset,
+                &pattern.replace("{token}", token),
+                max_bytes,
+                x,
+                y,
+                z,
+                cx,
+            )
+            .await
+            .map_err(|e| anyhow!(format!("{:#}", e).replace(token.as_str(), "REDACTED"))),
+        }
+    }
+}
+
+// Fills in a URL pattern for the requested tile, from its zoom, x and y, its quadkey or,
+// for WMS, its bounding box
+fn tile_url(pattern: &str, x: u32, y: u32, z: u32) -> String {
+    let url = pattern
+        .replace("{z}", &z.to_string())
+        .replace("{x}", &x.to_string())
+        .replace("{y}", &y.to_string());
+    if url.contains("{quadkey}") {
+        url.replace("{quadkey}", &tile_to_quadkey(x, y, z).collect::<String>())
+    } else if url.contains(wms::BBOX_PLACEHOLDER) {
+        url.replace(wms::BBOX_PLACEHOLDER, &wms::bbox(x, y, z))
+    } else {
+        url
+    }
+}
+
+// Fails unless a URL pattern has the placeholders to fill in for a tile
+fn check_pattern(pattern: &str) -> Result<()> {
+    if !pattern.contains("{quadkey}") && !["{z}", "{x}", "{y}"].iter().all(|p| pattern.contains(p))
+    {
+        return Err(anyhow!(
+            "Tile URL {} needs {{z}}, {{x}} and {{y}} placeholders, or {{quadkey}}",
+            pattern
+        ));
+    }
+    Ok(())
+}
+
+// Fills in {y} with the row the scheme has the tile in. Quadkeys and WMS bounding boxes
+// are worked out from the tile itself, so they're left to tile_url.
+fn number_rows(pattern: String, scheme: Scheme, y: u32, z: u32) -> String {
+    match scheme {
+        Scheme::Xyz => pattern,
+        Scheme::Tms => pattern.replace("{y}", &scheme.row(y, z).to_string()),
+    }
+}
+
+// Fetches a single tile from a given TileSet
+async fn fetch_http(
+    t: TileSet,
+    pattern: &str,
+    max_bytes: usize,
+    x: u32,
+    y: u32,
+    z: u32,
+    cx: Context,
+) -> Result<Bytes> {
+    let url = tile_url(pattern, x, y, z);
+
+    // The transport presents the tileset's client certificate if it has one, and gives
+    // up on bodies over max_bytes. Redirects are followed by the url_guard so that each
+    // hop is validated.
+    let transport = transport::for_tileset(t, max_bytes)?;
+
+    // Make an HTTP GET request to fetch the tile
+    let headers = t.headers();
+    let response =
+        url_guard::guarded_get(transport.as_ref(), &url, &headers, "dd-sdlc-demo", cx).await?;
+
+    // Check if the response status is a success
+    if response.status != 200 {
+        return Err(anyhow::anyhow!(
+            "Request to {} failed with status: {}",
+            url,
+            response.status
+        ));
+    }
+
+    // Check the content type
+    let content_type = response.content_type.unwrap_or_default();
+
+    // Aerial imagery, like Bing's and Esri's, comes as JPEG. Configured tilesets can
+    // insist on one or the other. Vector tiles come under a few names.
+    let expected = match t.content_type() {
+        Some(mvt::CONTENT_TYPE) => mvt::is_vector(&content_type),
+        Some(expected) => content_type == expected,
+        None => content_type == "image/png" || content_type == "image/jpeg",
+    };
+    if !expected {
+        return Err(anyhow::anyhow!(
+            "Unexpected content type from {}: {}",
+            url,
+            content_type
+        ));
+    }
+
+    if t.content_type() == Some(mvt::CONTENT_TYPE) {
+        return mvt::rasterize(&response.body)
+            .with_context(|| format!("rasterizing vector tile from {}", url));
+    }
+    Ok(response.body)
+}
+
+// Reads tiles from an MBTiles file. MBTiles number rows from the bottom (TMS), so y is
+// flipped.
+pub struct MbTilesFetcher {
+    conn: Mutex<Connection>,
+}
+
+impl MbTilesFetcher {
+    pub fn open(path: &str) -> Result<MbTilesFetcher> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .with_context(|| format!("opening MBTiles file {}", path))?;
+        Ok(MbTilesFetcher::new(conn))
+    }
+
+    pub fn new(conn: Connection) -> MbTilesFetcher {
+        MbTilesFetcher {
+            conn: Mutex::new(conn),
+        }
+    }
+
+    fn tile(&self, x: u32, y: u32, z: u32) -> Result<Bytes> {
+        let rows = 1u64 << z;
+        if y as u64 >= rows {
+            return Err(anyhow!("Tile {}/{}/{} is out of range", z, x, y));
+        }
+        let row = rows - 1 - y as u64;
+        let conn = self.conn.lock().unwrap();
+        let data: Option<Vec<u8>> = conn
+            .query_row(
+                "SELECT tile_data FROM tiles
+                 WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+                params![z, x, row],
+                |row| row.get(0),
+            )
+            .optional()?;
+        data.map(Bytes::from)
+            .ok_or_else(|| anyhow!("Tile {}/{}/{} isn't in the MBTiles file", z, x, y))
+    }
+}
+
+impl TileFetcher for MbTilesFetcher {
+    fn fetch(
+        &self,
+        _tileset: TileSet,
+        x: u32,
+        y: u32,
+        z: u32,
+        _cx: Context,
+    ) -> LocalBoxFuture<'_, Result<Bytes>> {
+        Box::pin(async move { self.tile(x, y, z) })
+    }
+}
+
+// Reads tiles from a directory laid out as <z>/<x>/<y>.png
+pub struct DirectoryFetcher {
+    root: PathBuf,
+}
+
+impl DirectoryFetcher {
+    pub fn new(root: impl Into<PathBuf>) -> DirectoryFetcher {
+        DirectoryFetcher { root: root.into() }
+    }
+}
+
+impl TileFetcher for DirectoryFetcher {
+    fn fetch(
+        &self,
+        _tileset: TileSet,
+        x: u32,
+        y: u32,
+        z: u32,
+        _cx: Context,
+    ) -> LocalBoxFuture<'_, Result<Bytes>> {
+        let path = self
+            .root
+            .join(z.to_string())
+            .join(x.to_string())
+            .join(format!("{}.png", y));
+        Box::pin(async move {
+            std::fs::read(&path)
+                .map(Bytes::from)
+                .with_context(|| format!("reading tile {}", path.display()))
+        })
+    }
+}
+
+// Generates debug tiles locally, whatever tileset they're asked for as
+#[derive(Default)]
+pub struct DebugFetcher;
+
+impl TileFetcher for DebugFetcher {
+    fn fetch(
+        &self,
+        _tileset: TileSet,
+        x: u32,
+        y: u32,
+        z: u32,
+        _cx: Context,
+    ) -> LocalBoxFuture<'_, Result<Bytes>> {
+        Box::pin(async move { Ok(encode_tile(debug_tile(x, y, z))) })
+    }
+}
+
+// The fetcher for each tileset, falling back to HTTP for those without their own source.
+// The debug tileset is generated by a DebugFetcher unless it's been given a source.
+#[derive(Default)]
+pub struct TileSources {
+    sources: HashMap<&'static str, Box<dyn TileFetcher>>,
+    http: HttpFetcher,
+    cache: Option<Box<dyn TileCache>>,
+    faults: bool,
+}
+
+impl TileSources {
+    // Serves the tileset from the given fetcher instead of over HTTP
+    pub fn with_source(mut self, tileset: TileSet, fetcher: Box<dyn TileFetcher>) -> TileSources {
+        self.sources.insert(tileset.name(), fetcher);
+        self
+    }
+
+    // Caches the tiles fetched over HTTP
+    pub fn with_cache(mut self, cache: Box<dyn TileCache>) -> TileSources {
+        self.cache = Some(cache);
+        self
+    }
+
+    // Lets the faults module's plans slow down, fail or corrupt fetches. Cache hits are
+    // affected too, so a corrupt tile is never cached.
+    pub fn with_faults(mut self) -> TileSources {
+        self.faults = true;
+        self
+    }
+
+    pub fn faults_enabled(&self) -> bool {
+        self.faults
+    }
+
+    pub fn from_env() -> Result<TileSources> {
+        let mut sources = TileSources {
+            http: HttpFetcher::from_env()?,
+            ..Default::default()
+        };
+        for tileset in TileSet::all() {
+            let var = format!("TILESET_{}_SOURCE", tileset.name().to_uppercase());
+            let Ok(source) = env::var(&var) else {
+                continue;
+            };
+            let fetcher: Box<dyn TileFetcher> = match source.split_once(':') {
+                None if source == "http" => {
+                    sources.http.check_token(tileset)?;
+                    continue;
+                }
+                Some(("mbtiles", path)) => Box::new(MbTilesFetcher::open(path)?),
+                Some(("pmtiles", location)) => Box::new(PmTilesFetcher::open(location)?),
+                Some(("dir", path)) => Box::new(DirectoryFetcher::new(path)),
+                // WMS servers are fetched over HTTP like upstreams, so they're cached
+                Some(("wms", url)) => {
+                    sources.http = std::mem::take(&mut sources.http)
+                        .with_wms(tileset, url)
+                        .with_context(|| format!("Invalid {}", var))?;
+                    sources.http.check_token(tileset)?;
+                    continue;
+                }
+                Some(("wmts", _)) => {
+                    let pattern = wmts::pattern(tileset).ok_or_else(|| {
+                        anyhow!("{}'s WMTS capabilities haven't been loaded", tileset.name())
+                    })?;
+                    sources.http = std::mem::take(&mut sources.http)
+                        .with_url(tileset, pattern)
+                        .with_context(|| format!("Invalid {}", var))?;
+                    sources.http.check_token(tileset)?;
+                    continue;
+                }
+                _ => return Err(anyhow!("Invalid {}: {}", var, source)),
+            };
+            sources = sources.with_source(tileset, fetcher);
+        }
+        Ok(sources)
+    }
+}
+
+impl TileFetcher for TileSources {
+    fn fetch(
+        &self,
+        tileset: TileSet,
+        x: u32,
+        y: u32,
+        z: u32,
+        cx: Context,
+    ) -> LocalBoxFuture<'_, Result<Bytes>> {
+        let fetch = self.fetch_unfaulted(tileset, x, y, z, cx);
+        if self.faults {
+            Box::pin(faults::inject(tileset, fetch))
+        } else {
+            fetch
+        }
+    }
+}
+
+impl TileSources {
+    // Fetches a cached tile from upstream again and replaces it in the cache. Only tiles
+    // fetched over HTTP are cached, so only those can be refreshed.
+    pub async fn refresh(&self, tile: &CachedTile, cx: Context) -> Result<()> {
+        let Some(cache) = &self.cache else {
+            return Err(anyhow!("There's no tile cache to refresh"));
+        };
+        if tile.tileset == TileSet::Debug || self.sources.contains_key(tile.tileset.name()) {
+            return Err(anyhow!("{} tiles aren't cached", tile.tileset.name()));
+        }
+        cache::refresh(cache.as_ref(), &self.http, tile, cx).await
+    }
+
+    fn fetch_unfaulted(
+        &self,
+        tileset: TileSet,
+        x: u32,
+        y: u32,
+        z: u32,
+        cx: Context,
+    ) -> LocalBoxFuture<'_, Result<Bytes>> {
+        match (self.sources.get(tileset.name()), &self.cache) {
+            (Some(fetcher), _) => fetcher.fetch(tileset, x, y, z, cx),
+            (None, _) if tileset == TileSet::Debug => DebugFetcher.fetch(tileset, x, y, z, cx),
+            (None, _) if tileset == TileSet::Hybrid => Box::pin(async {
+                Err(anyhow!(
+                    "hybrid has no tiles of its own; it's rendered from esri, esri_roads and esri_labels"
+                ))
+            }),
+            (None, Some(cache)) => Box::pin(cache::fetch_through(
+                cache.as_ref(),
+                &self.http,
+                tileset,
+                x,
+                y,
+                z,
+                cx,
+            )),
+            (None, None) => Box::pin(integrity::fetch_checked(&self.http, tileset, x, y, z, cx)),
+        }
+    }
+}
+
+// Replays tiles recorded under a directory as <tileset>/<z>/<x>/<y>.png. When recording,
+// tiles that haven't been recorded yet are fetched from upstream and saved; otherwise
+// they're an error, so a replay never touches the network.
+pub struct FixtureFetcher {
+    root: PathBuf,
+    upstream: Box<dyn TileFetcher>,
+    record: bool,
+}
+
+impl FixtureFetcher {
+    pub fn new(root: impl Into<PathBuf>, upstream: Box<dyn TileFetcher>, record: bool) -> Self {
+        FixtureFetcher {
+            root: root.into(),
+            upstream,
+            record,
+        }
+    }
+
+    // Records over HTTP if TILE_FIXTURES=record, and only replays otherwise
+    pub fn from_env(root: impl Into<PathBuf>) -> Result<FixtureFetcher> {
+        let record = env::var("TILE_FIXTURES").is_ok_and(|mode| mode == "record");
+        Ok(FixtureFetcher::new(
+            root,
+            Box::new(HttpFetcher::from_env()?),
+            record,
+        ))
+    }
+
+    async fn tile(&self, tileset: TileSet, x: u32, y: u32, z: u32, cx: Context) -> Result<Bytes> {
+        let key = cache::tile_key(tileset, x, y, z);
+        let path = self.root.join(&key);
+        if path.exists() {
+            return std::fs::read(&path)
+                .map(Bytes::from)
+                .with_context(|| format!("reading fixture {}", path.display()));
+        }
+        if !self.record {
+            return Err(anyhow!(
+                "No fixture for tile {}; run with TILE_FIXTURES=record to record it",
+                key
+            ));
+        }
+        let tile = self.upstream.fetch(tileset, x, y, z, cx).await?;
+        save(&path, &tile).with_context(|| format!("recording fixture {}", path.display()))?;
+        Ok(tile)
+    }
+}
+
+fn save(path: &Path, tile: &[u8]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, tile)?;
+    Ok(())
+}
+
+impl TileFetcher for FixtureFetcher {
+    fn fetch(
+        &self,
+        tileset: TileSet,
+        x: u32,
+        y: u32,
+        z: u32,
+        cx: Context,
+    ) -> LocalBoxFuture<'_, Result<Bytes>> {
+        Box::pin(self.tile(tileset, x, y, z, cx))
+    }
+}
+
+// Tiles held in memory, e.g. for tests that shouldn't touch the network. Tiles that
+// weren't added come back as the fallback tile if there is one.
+#[derive(Default)]
+pub struct MemoryFetcher {
+    tiles: HashMap<(&'static str, u32, u32, u32), Bytes>,
+    fallback: Option<Bytes>,
+}
+
+impl MemoryFetcher {
+    pub fn with_tile(mut self, tileset: TileSet, x: u32, y: u32, z: u32, png: Bytes) -> Self {
+        self.tiles.insert((tileset.name(), x, y, z), png);
+        self
+    }
+
+    pub fn with_fallback(mut self, png: Bytes) -> Self {
+        self.fallback = Some(png);
+        self
+    }
+}
+
+impl TileFetcher for MemoryFetcher {
+    fn fetch(
+        &self,
+        tileset: TileSet,
+        x: u32,
+        y: u32,
+        z: u32,
+        _cx: Context,
+    ) -> LocalBoxFuture<'_, Result<Bytes>> {
+        let tile = self
+            .tiles
+            .get(&(tileset.name(), x, y, z))
+            .or(self.fallback.as_ref())
+            .cloned()
+            .ok_or_else(|| anyhow!("No tile {}/{}/{} for {}", z, x, y, tileset.name()));
+        Box::pin(async move { tile })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile(n: u8) -> Bytes {
+        Bytes::from(vec![n; 4])
+    }
+
+    #[tokio::test]
+    async fn test_mbtiles_flips_rows() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER,
+             tile_data BLOB)",
+            [],
+        )
+        .unwrap();
+        // Tile 2/1/0 counted from the top is row 3 counted from the bottom
+        conn.execute(
+            "INSERT INTO tiles VALUES (2, 1, 3, ?1)",
+            params![vec![7u8; 4]],
+        )
+        .unwrap();
+
+        let fetcher = MbTilesFetcher::new(conn);
+        let cx = Context::current();
+        let found = fetcher.fetch(TileSet::Osm, 1, 0, 2, cx.clone()).await;
+        assert_eq!(found.unwrap(), tile(7));
+        assert!(fetcher
+            .fetch(TileSet::Osm, 1, 3, 2, cx.clone())
+            .await
+            .is_err());
+        assert!(fetcher.fetch(TileSet::Osm, 1, 9, 2, cx).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_directory_fetcher() {
+        let root = env::temp_dir().join(format!("tiles-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("3/4")).unwrap();
+        std::fs::write(root.join("3/4/5.png"), tile(1)).unwrap();
+
+        let fetcher = DirectoryFetcher::new(&root);
+        let cx = Context::current();
+        assert_eq!(
+            fetcher
+                .fetch(TileSet::Osm, 4, 5, 3, cx.clone())
+                .await
+                .unwrap(),
+            tile(1)
+        );
+        assert!(fetcher.fetch(TileSet::Osm, 4, 6, 3, cx).await.is_err());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sources_route_by_tileset() {
+        let sources = TileSources::default().with_source(
+            TileSet::Swisstopo,
+            Box::new(MemoryFetcher::default().with_fallback(tile(2))),
+        );
+        let found = sources
+            .fetch(TileSet::Swisstopo, 0, 0, 0, Context::current())
+            .await;
+        assert_eq!(found.unwrap(), tile(2));
+    }
+
+    #[test]
+    fn test_url_override_needs_placeholders() {
+        let fetcher = HttpFetcher::default()
+            .with_url(TileSet::Osm, "http://mirror.test/{z}/{x}/{y}.png")
+            .unwrap();
+        assert_eq!(
+            fetcher.urls.get("osm").map(String::as_str),
+            Some("http://mirror.test/{z}/{x}/{y}.png")
+        );
+        assert!(HttpFetcher::default()
+            .with_url(TileSet::Osm, "http://mirror.test/{z}/{x}.png")
+            .is_err());
+    }
+
+    #[test]
+    fn test_tile_url() {
+        assert_eq!(
+            tile_url(TileSet::Osm.url_pattern(), 4, 5, 3),
+            "https://tile.openstreetmap.org/3/4/5.png"
+        );
+        assert_eq!(
+            tile_url(TileSet::Bing.url_pattern(), 3, 5, 3),
+            "https://ecn.t0.tiles.virtualearth.net/tiles/a213.jpeg?g=1"
+        );
+        assert_eq!(
+            tile_url(TileSet::Esri.url_pattern(), 4, 5, 3),
+            "https://server.arcgisonline.com/ArcGIS/rest/services/World_Imagery/MapServer/tile/3/5/4"
+        );
+        assert!(HttpFetcher::default()
+            .with_url(TileSet::Bing, "http://mirror.test/{quadkey}.jpeg")
+            .is_ok());
+
+        // TMS servers have the same tile 7 - 5 rows up from the bottom
+        let tms = HttpFetcher::default().with_scheme(TileSet::Osm, Scheme::Tms);
+        assert_eq!(tms.scheme(TileSet::Osm), Scheme::Tms);
+        assert_eq!(
+            tile_url(
+                &number_rows(tms.pattern(TileSet::Osm), Scheme::Tms, 5, 3),
+                4,
+                5,
+                3
+            ),
+            "https://tile.openstreetmap.org/3/4/2.png"
+        );
+        assert_eq!(
+            tile_url(
+                &number_rows(TileSet::Bing.url_pattern().to_string(), Scheme::Tms, 5, 3),
+                3,
+                5,
+                3
+            ),
+            "https://ecn.t0.tiles.virtualearth.net/tiles/a213.jpeg?g=1"
+        );
+
+        let wms = HttpFetcher::default()
+            .with_wms(TileSet::Osm, "https://geo.test/wms?LAYERS=topo")
+            .unwrap();
+        assert_eq!(
+            tile_url(&wms.pattern(TileSet::Osm), 0, 1, 1),
+            "https://geo.test/wms?SERVICE=WMS&VERSION=1.3.0&REQUEST=GetMap&CRS=EPSG:3857\
+             &BBOX=-20037508.34,-20037508.34,0.00,0.00&WIDTH=256&HEIGHT=256\
+             &FORMAT=image/png&STYLES=&LAYERS=topo"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tokens() {
+        // Mapbox can't be fetched without its token, and fails before going to the network
+        let fetcher = HttpFetcher::default();
+        assert!(fetcher.check_token(TileSet::Mapbox).is_err());
+        assert!(fetcher.check_token(TileSet::Osm).is_ok());
+        let err = fetcher
+            .fetch(TileSet::Mapbox, 1, 2, 3, Context::current())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("TILESET_MAPBOX_TOKEN"));
+
+        // With one, errors quoting the URL don't give the token away
+        let fetcher = HttpFetcher::default()
+            .with_url(
+                TileSet::Mapbox,
+                "ftp://mirror.test/{z}/{x}/{y}?access_token={token}",
+            )
+            .unwrap()
+            .with_token(TileSet::Mapbox, "pk.secret")
+            .unwrap();
+        assert!(fetcher.check_token(TileSet::Mapbox).is_ok());
+        let err = fetcher
+            .fetch(TileSet::Mapbox, 1, 2, 3, Context::current())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("access_token=REDACTED"));
+        assert!(!err.to_string().contains("pk.secret"));
+        assert!(HttpFetcher::default()
+            .with_token(TileSet::Mapbox, "")
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_mirrors() {
+        // Every upstream is tried before the fetch fails, and mirrors need the token too
+        let mirrors = Mirrors::parse("ftp://eu.test/{z}/{x}/{y}?key={token}").unwrap();
+        let fetcher = HttpFetcher::default()
+            .with_url(TileSet::Osm, "ftp://mirror.test/{z}/{x}/{y}.png")
+            .unwrap()
+            .with_mirrors(TileSet::Osm, mirrors)
+            .unwrap();
+        assert!(fetcher.check_token(TileSet::Osm).is_err());
+        let fetcher = fetcher.with_token(TileSet::Osm, "secret").unwrap();
+        let err = fetcher
+            .fetch(TileSet::Osm, 1, 2, 3, Context::current())
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("Every osm upstream failed"));
+        assert!(err.contains("ftp://mirror.test/3/1/2.png"));
+        assert!(err.contains("ftp://eu.test/3/1/2?key=REDACTED"));
+
+        let bad = Mirrors::parse("ftp://eu.test/{z}/{x}.png").unwrap();
+        assert!(HttpFetcher::default()
+            .with_mirrors(TileSet::Osm, bad)
+            .is_err());
+    }
+
+    #[test]
+    fn test_max_tile_bytes() {
+        assert_eq!(
+            HttpFetcher::default().max_tile_bytes,
+            DEFAULT_MAX_TILE_BYTES
+        );
+        let fetcher = HttpFetcher::default().with_max_tile_bytes(1024).unwrap();
+        assert_eq!(fetcher.max_tile_bytes, 1024);
+        assert!(HttpFetcher::default().with_max_tile_bytes(0).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fixtures_record_then_replay() {
+        let root = env::temp_dir().join(format!("fixtures-{}", std::process::id()));
+        let cx = Context::current();
+
+        let recorder = FixtureFetcher::new(
+            &root,
+            Box::new(MemoryFetcher::default().with_fallback(tile(3))),
+            true,
+        );
+        let recorded = recorder.fetch(TileSet::Osm, 4, 5, 3, cx.clone()).await;
+        assert_eq!(recorded.unwrap(), tile(3));
+        assert!(root.join("osm/3/4/5.png").exists());
+
+        // Replaying never goes upstream, so only the recorded tile is there
+        let replay = FixtureFetcher::new(&root, Box::new(MemoryFetcher::default()), false);
+        let replayed = replay.fetch(TileSet::Osm, 4, 5, 3, cx.clone()).await;
+        assert_eq!(replayed.unwrap(), tile(3));
+        let missing = replay.fetch(TileSet::Osm, 4, 6, 3, cx).await;
+        assert!(format!("{:#}", missing.unwrap_err()).contains("TILE_FIXTURES=record"));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,240 @@
+// ! # focus
+// ! A focus highlight: everything outside an area around the pass is dimmed or
+// ! desaturated so the eye goes to the middle of the map. The area is a circle around the
+// ! requested point, or on POST bodies any GeoJSON polygon. Its edge is feathered by
+// ! blurring a mask, which is built at a reduced resolution as it only needs to be soft.
+
+use crate::coordinates::{meters_per_pixel, LatLong};
+use crate::crop;
+use crate::overlay::{self, Overlay, Viewport};
+use anyhow::{anyhow, Result};
+use image::imageops::{self, FilterType};
+use image::{GrayImage, Luma, RgbaImage};
+use serde_json::Value;
+use std::str::FromStr;
+
+// The range of focus radii accepted, in km
+pub const MIN_RADIUS_KM: f32 = 0.01;
+pub const MAX_RADIUS_KM: f32 = 100.0;
+
+const DEFAULT_STRENGTH: f32 = 0.6;
+
+// The width of the feathered edge, as a fraction of the image's smaller side
+const FEATHER_FRACTION: f32 = 0.04;
+
+// The mask is built at this fraction of the image's size in each direction
+const MASK_DOWNSAMPLE: u32 = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FocusArea {
+    // A circle around the center of the image
+    Circle { radius_km: f32 },
+    Polygon(Vec<LatLong>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FocusEffect {
+    #[default]
+    Dim,
+    Desaturate,
+}
+
+impl FromStr for FocusEffect {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "dim" => Ok(FocusEffect::Dim),
+            "desaturate" => Ok(FocusEffect::Desaturate),
+            other => Err(anyhow!("Unknown focus effect {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Focus {
+    pub area: FocusArea,
+    pub effect: FocusEffect,
+    // How strongly the outside is affected, from 0 (not at all) to 1
+    pub strength: f32,
+}
+
+impl Focus {
+    // Builds a focus from the focus=, focus_effect= and focus_strength= parameters, with a
+    // polygon taking the place of the radius when one's given. Returns None if there's no
+    // area to focus on.
+    pub fn from_params(
+        radius_km: Option<f32>,
+        polygon: Option<Vec<LatLong>>,
+        effect: Option<&str>,
+        strength: Option<f32>,
+    ) -> Result<Option<Focus>> {
+        let valid_radius = |r: f32| (MIN_RADIUS_KM..=MAX_RADIUS_KM).contains(&r);
+        let area = match (polygon, radius_km) {
+            (Some(points), _) => FocusArea::Polygon(points),
+            (None, Some(radius_km)) if valid_radius(radius_km) => FocusArea::Circle { radius_km },
+            (None, Some(_)) => {
+                return Err(anyhow!(
+                    "focus must be between {} and {} km",
+                    MIN_RADIUS_KM,
+                    MAX_RADIUS_KM
+                ))
+            }
+            (None, None) => return Ok(None),
+        };
+
+        let strength = match strength {
+            Some(s) if !(0.0..=1.0).contains(&s) => {
+                return Err(anyhow!("focus_strength must be between 0 and 1"))
+            }
+            s => s.unwrap_or(DEFAULT_STRENGTH),
+        };
+
+        Ok(Some(Focus {
+            area,
+            effect: effect.map(str::parse).transpose()?.unwrap_or_default(),
+            strength,
+        }))
+    }
+}
+
+// The outer ring of the first polygon in a GeoJSON Polygon, Feature or FeatureCollection
+pub fn polygon_from_geojson(geojson: &Value) -> Result<Vec<LatLong>> {
+    overlay::from_geojson(geojson)?
+        .into_iter()
+        .find_map(|overlay| match overlay {
+            Overlay::Line { points, .. } if points.len() >= 3 => Some(points),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("focus_area must contain a polygon"))
+}
+
+// Dims or desaturates the image outside the focus area. The center is the point the
+// image was requested around.
+pub fn apply_focus(img: &mut RgbaImage, viewport: &Viewport, center: LatLong, focus: &Focus) {
+    let (width, height) = img.dimensions();
+    let factor = 1.0 / MASK_DOWNSAMPLE as f64;
+    let mask = area_mask(
+        (width / MASK_DOWNSAMPLE).max(1),
+        (height / MASK_DOWNSAMPLE).max(1),
+        &viewport.scaled(factor),
+        center,
+        &focus.area,
+    );
+    let feather = width.min(height) as f32 * FEATHER_FRACTION / MASK_DOWNSAMPLE as f32;
+    let mask = imageops::blur(&mask, feather.max(0.5));
+    let mask = imageops::resize(&mask, width, height, FilterType::Triangle);
+
+    for (pixel, inside) in img.pixels_mut().zip(mask.pixels()) {
+        let amount = focus.strength * (1.0 - inside[0] as f32 / 255.0);
+        if amount <= 0.0 {
+            continue;
+        }
+        let [r, g, b, a] = pixel.0;
+        let rgb = [r, g, b].map(|c| c as f32);
+        let out = match focus.effect {
+            FocusEffect::Dim => rgb.map(|c| c * (1.0 - amount)),
+            FocusEffect::Desaturate => {
+                let luma = 0.299 * rgb[0] + 0.587 * rgb[1] + 0.114 * rgb[2];
+                rgb.map(|c| c + (luma - c) * amount)
+            }
+        };
+        let [r, g, b] = out.map(|c| c.round().clamp(0.0, 255.0) as u8);
+        pixel.0 = [r, g, b, a];
+    }
+}
+
+// A width x height mask that's white inside the area and black outside
+fn area_mask(
+    width: u32,
+    height: u32,
+    viewport: &Viewport,
+    center: LatLong,
+    area: &FocusArea,
+) -> GrayImage {
+    match area {
+        FocusArea::Circle { radius_km } => {
+            let mut mask = GrayImage::new(width, height);
+            let (cx, cy) = viewport.project(&center);
+            let radius = *radius_km as f64 * 1000.0 / meters_per_pixel(center.0, viewport.zoom)
+                * viewport.scale;
+            for (x, y, pixel) in mask.enumerate_pixels_mut() {
+                let (px, py) = (x as f64 + 0.5, y as f64 + 0.5);
+                if (px - cx).hypot(py - cy) <= radius {
+                    *pixel = Luma([255]);
+                }
+            }
+            mask
+        }
+        FocusArea::Polygon(points) => {
+            crop::fill_rings(width, height, viewport, std::slice::from_ref(points))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coordinates::pixel_to_lat_long;
+    use image::Rgba;
+
+    const VIEWPORT: Viewport = Viewport {
+        zoom: 12,
+        origin: (0.0, 0.0),
+        scale: 1.0,
+    };
+
+    #[test]
+    fn test_from_params() {
+        assert_eq!(
+            Focus::from_params(None, None, Some("dim"), None).unwrap(),
+            None
+        );
+
+        let focus = Focus::from_params(Some(1.0), None, Some("desaturate"), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(focus.area, FocusArea::Circle { radius_km: 1.0 });
+        assert_eq!(focus.effect, FocusEffect::Desaturate);
+        assert_eq!(focus.strength, DEFAULT_STRENGTH);
+
+        assert!(Focus::from_params(Some(500.0), None, None, None).is_err());
+        assert!(Focus::from_params(Some(1.0), None, Some("blur"), None).is_err());
+        assert!(Focus::from_params(Some(1.0), None, None, Some(2.0)).is_err());
+    }
+
+    #[test]
+    fn test_polygon_mask() {
+        let square = [(16.0, 16.0), (48.0, 16.0), (48.0, 48.0), (16.0, 48.0)]
+            .map(|(x, y)| pixel_to_lat_long(x, y, VIEWPORT.zoom))
+            .to_vec();
+        let center = pixel_to_lat_long(32.0, 32.0, VIEWPORT.zoom);
+        let mask = area_mask(64, 64, &VIEWPORT, center, &FocusArea::Polygon(square));
+        assert_eq!(mask.get_pixel(32, 32)[0], 255);
+        assert_eq!(mask.get_pixel(16, 16)[0], 255);
+        assert_eq!(mask.get_pixel(47, 47)[0], 255);
+        assert_eq!(mask.get_pixel(48, 32)[0], 0);
+        assert_eq!(mask.get_pixel(8, 8)[0], 0);
+    }
+
+    #[test]
+    fn test_apply_focus_dims_outside() {
+        // Centered on 0, 0, where zoom 12 is about 38m per pixel, so 2km is about 52px
+        let middle = 256.0 * 2.0_f64.powi(11);
+        let viewport = Viewport {
+            origin: (middle - 128.0, middle - 128.0),
+            ..VIEWPORT
+        };
+        let center = pixel_to_lat_long(middle, middle, viewport.zoom);
+        let focus = Focus {
+            area: FocusArea::Circle { radius_km: 2.0 },
+            effect: FocusEffect::Dim,
+            strength: 1.0,
+        };
+        let mut img = RgbaImage::from_pixel(256, 256, Rgba([200, 200, 200, 255]));
+        apply_focus(&mut img, &viewport, center, &focus);
+
+        assert!(img.get_pixel(128, 128)[0] > 190);
+        assert_eq!(img.get_pixel(2, 2), &Rgba([0, 0, 0, 255]));
+    }
+}
@@ -0,0 +1,300 @@
+// ! # frame
+// ! Shapes the finished map for display, e.g. as an avatar-style thumbnail in the front
+// ! end. Masks fade the image out to transparent, with a one pixel soft edge so the
+// ! curves don't look jagged. Frames then put the result on a larger canvas with an
+// ! optional border and drop shadow that follow the mask's shape.
+
+use crate::overlay::parse_color;
+use crate::text;
+use anyhow::{anyhow, Result};
+use image::{imageops, Rgba, RgbaImage};
+
+// The widest border or shadow we'll draw, in pixels
+pub const MAX_FRAME_PX: u32 = 64;
+
+const DEFAULT_BORDER_COLOR: Rgba<u8> = Rgba([255, 255, 255, 255]);
+const SHADOW_COLOR: Rgba<u8> = Rgba([0, 0, 0, 128]);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mask {
+    // The largest circle that fits in the image
+    Circle,
+    // Rounded corners with the given radius in pixels
+    RoundedCorners(u32),
+}
+
+impl Mask {
+    // Builds a mask from the mask= and corner_radius= parameters. A circle wins if both
+    // are given.
+    pub fn from_params(mask: Option<&str>, corner_radius: Option<u32>) -> Result<Option<Mask>> {
+        match (mask, corner_radius) {
+            (Some("circle"), _) => Ok(Some(Mask::Circle)),
+            (Some(other), _) => Err(anyhow!("Unknown mask {}", other)),
+            (None, Some(radius)) if radius > 0 => Ok(Some(Mask::RoundedCorners(radius))),
+            (None, _) => Ok(None),
+        }
+    }
+}
+
+// How much of a pixel whose center is `distance` from a shape's center lies inside an
+// edge at `radius`
+fn edge_coverage(distance: f64, radius: f64) -> f64 {
+    (radius - distance + 0.5).clamp(0.0, 1.0)
+}
+
+// How much of the pixel centered at (px, py) lies inside a width x height shape cut by
+// the mask. No mask means a plain rectangle.
+fn shape_coverage(mask: Option<Mask>, width: f64, height: f64, px: f64, py: f64) -> f64 {
+    let max_radius = width.min(height) / 2.0;
+    match mask {
+        Some(Mask::Circle) => {
+            let distance = (px - width / 2.0).hypot(py - height / 2.0);
+            edge_coverage(distance, max_radius)
+        }
+        Some(Mask::RoundedCorners(radius)) => {
+            // Measure from the nearest point of the rectangle inset by the radius;
+            // that's zero everywhere except in the corners
+            let radius = (radius as f64).min(max_radius);
+            let nearest_x = px.clamp(radius, width - radius);
+            let nearest_y = py.clamp(radius, height - radius);
+            edge_coverage((px - nearest_x).hypot(py - nearest_y), radius)
+        }
+        None => {
+            if (0.0..width).contains(&px) && (0.0..height).contains(&py) {
+                1.0
+            } else {
+                0.0
+            }
+        }
+    }
+}
+
+pub fn apply_mask(img: &mut RgbaImage, mask: Mask) {
+    let (width, height) = (img.width() as f64, img.height() as f64);
+    for (x, y, pixel) in img.enumerate_pixels_mut() {
+        let coverage = shape_coverage(Some(mask), width, height, x as f64 + 0.5, y as f64 + 0.5);
+        if coverage < 1.0 {
+            pixel[3] = (pixel[3] as f64 * coverage).round() as u8;
+        }
+    }
+}
+
+// A border and/or drop shadow around the image, on a canvas of the background color
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frame {
+    pub border_width: u32,
+    pub border_color: Rgba<u8>,
+    // Size of the drop shadow in pixels; it's offset by half this and blurred
+    pub shadow: u32,
+    pub background: Rgba<u8>,
+}
+
+impl Frame {
+    // How far the canvas extends past the image on each side: room for the border, plus
+    // the offset shadow and its blur
+    pub fn padding(&self) -> u32 {
+        self.border_width + self.shadow / 2 + self.shadow
+    }
+
+    // Builds a frame from the border=, border_color=, shadow= and background= parameters.
+    // Colors are hex, with or without the leading #. Returns None if there's nothing to do.
+    pub fn from_params(
+        border: Option<u32>,
+        border_color: Option<&str>,
+        shadow: Option<u32>,
+        background: Option<&str>,
+    ) -> Result<Option<Frame>> {
+        let color = |name: &str, value: &str| {
+            parse_color(&format!("#{}", value.trim_start_matches('#')))
+                .ok_or_else(|| anyhow!("Invalid {} {}", name, value))
+        };
+        let size = |name: &str, value: Option<u32>| match value {
+            Some(value) if value > MAX_FRAME_PX => {
+                Err(anyhow!("{} must be at most {}", name, MAX_FRAME_PX))
+            }
+            value => Ok(value.unwrap_or(0)),
+        };
+
+        let frame = Frame {
+            border_width: size("border", border)?,
+            border_color: match border_color {
+                Some(value) => color("border_color", value)?,
+                None => DEFAULT_BORDER_COLOR,
+            },
+            shadow: size("shadow", shadow)?,
+            background: match background {
+                Some(value) => color("background", value)?,
+                None => Rgba([0, 0, 0, 0]),
+            },
+        };
+
+        if frame.border_width == 0 && frame.shadow == 0 && frame.background[3] == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(frame))
+        }
+    }
+}
+
+// Puts the image on a larger canvas with the frame's border and shadow. The border
+// follows the mask the image was cut with, if any.
+pub fn apply_frame(img: &RgbaImage, frame: &Frame, mask: Option<Mask>) -> RgbaImage {
+    let border = frame.border_width;
+    let offset = frame.shadow / 2;
+    let padding = frame.padding();
+    let mut canvas = RgbaImage::from_pixel(
+        img.width() + 2 * padding,
+        img.height() + 2 * padding,
+        frame.background,
+    );
+
+    // The border is the image's shape grown by the border width
+    let outer_mask = match mask {
+        Some(Mask::RoundedCorners(radius)) => Some(Mask::RoundedCorners(radius + border)),
+        other => other,
+    };
+    let (outer_width, outer_height) = (img.width() + 2 * border, img.height() + 2 * border);
+
+    if frame.shadow > 0 {
+        let mut shadow = RgbaImage::new(canvas.width(), canvas.height());
+        fill_shape(
+            &mut shadow,
+            (padding - border + offset, padding - border + offset),
+            (outer_width, outer_height),
+            outer_mask,
+            SHADOW_COLOR,
+        );
+        let shadow = imageops::blur(&shadow, frame.shadow as f32 / 2.0);
+        text::overlay_image(&mut canvas, &shadow, 0, 0);
+    }
+
+    if border > 0 {
+        fill_shape(
+            &mut canvas,
+            (padding - border, padding - border),
+            (outer_width, outer_height),
+            outer_mask,
+            frame.border_color,
+        );
+    }
+
+    text::overlay_image(&mut canvas, img, padding as i64, padding as i64);
+    canvas
+}
+
+// Blends a width x height shape, cut by the mask, into the canvas at the given position
+fn fill_shape(
+    canvas: &mut RgbaImage,
+    position: (u32, u32),
+    size: (u32, u32),
+    mask: Option<Mask>,
+    color: Rgba<u8>,
+) {
+    let (width, height) = (size.0 as f64, size.1 as f64);
+    for y in 0..size.1 {
+        for x in 0..size.0 {
+            let coverage = shape_coverage(mask, width, height, x as f64 + 0.5, y as f64 + 0.5);
+            if coverage > 0.0 {
+                let mut color = color;
+                color[3] = (color[3] as f64 * coverage).round() as u8;
+                text::blend(canvas.get_pixel_mut(position.0 + x, position.1 + y), &color);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn opaque(size: u32) -> RgbaImage {
+        RgbaImage::from_pixel(size, size, Rgba([10, 20, 30, 255]))
+    }
+
+    #[test]
+    fn test_from_params() {
+        assert_eq!(
+            Mask::from_params(Some("circle"), Some(8)).unwrap(),
+            Some(Mask::Circle)
+        );
+        assert_eq!(
+            Mask::from_params(None, Some(8)).unwrap(),
+            Some(Mask::RoundedCorners(8))
+        );
+        assert_eq!(Mask::from_params(None, Some(0)).unwrap(), None);
+        assert!(Mask::from_params(Some("hexagon"), None).is_err());
+    }
+
+    #[test]
+    fn test_circle_mask() {
+        let mut img = opaque(64);
+        apply_mask(&mut img, Mask::Circle);
+        assert_eq!(img.get_pixel(32, 32)[3], 255);
+        assert_eq!(img.get_pixel(32, 1)[3], 255);
+        assert_eq!(img.get_pixel(0, 0)[3], 0);
+        assert_eq!(img.get_pixel(63, 63)[3], 0);
+        // The very top edge is partly covered
+        assert!(img.get_pixel(32, 0)[3] > 128);
+    }
+
+    #[test]
+    fn test_frame_from_params() {
+        assert_eq!(Frame::from_params(None, None, None, None).unwrap(), None);
+
+        let frame = Frame::from_params(Some(4), Some("ff0000"), None, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(frame.border_color, Rgba([255, 0, 0, 255]));
+
+        assert!(Frame::from_params(Some(500), None, None, None).is_err());
+        assert!(Frame::from_params(Some(4), Some("red"), None, None).is_err());
+    }
+
+    #[test]
+    fn test_border() {
+        let frame = Frame {
+            border_width: 4,
+            border_color: Rgba([255, 0, 0, 255]),
+            shadow: 0,
+            background: Rgba([0, 0, 0, 0]),
+        };
+        let framed = apply_frame(&opaque(32), &frame, None);
+        assert_eq!(framed.dimensions(), (40, 40));
+        assert_eq!(framed.get_pixel(0, 0), &Rgba([255, 0, 0, 255]));
+        assert_eq!(framed.get_pixel(20, 20), &Rgba([10, 20, 30, 255]));
+
+        // A circular image gets a circular border
+        let mut img = opaque(32);
+        apply_mask(&mut img, Mask::Circle);
+        let framed = apply_frame(&img, &frame, Some(Mask::Circle));
+        assert_eq!(framed.get_pixel(0, 0)[3], 0);
+        assert_eq!(framed.get_pixel(20, 1), &Rgba([255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn test_shadow() {
+        let frame = Frame {
+            border_width: 0,
+            border_color: DEFAULT_BORDER_COLOR,
+            shadow: 8,
+            background: Rgba([0, 0, 0, 0]),
+        };
+        let framed = apply_frame(&opaque(32), &frame, None);
+        assert_eq!(framed.dimensions(), (56, 56));
+        // The shadow falls below and to the right
+        let below_right = framed.get_pixel(12 + 32 + 2, 12 + 32 + 2)[3];
+        let above_left = framed.get_pixel(12 - 2, 12 - 2)[3];
+        assert!(below_right > above_left);
+    }
+
+    #[test]
+    fn test_rounded_corners() {
+        let mut img = opaque(64);
+        apply_mask(&mut img, Mask::RoundedCorners(10));
+        assert_eq!(img.get_pixel(0, 0)[3], 0);
+        assert_eq!(img.get_pixel(32, 0)[3], 255);
+        assert_eq!(img.get_pixel(0, 32)[3], 255);
+        assert_eq!(img.get_pixel(10, 10)[3], 255);
+    }
+}
@@ -0,0 +1,103 @@
+// ! # golden
+// ! Golden-image tests of the whole render pipeline: tiles are fetched from the debug
+// ! tileset, mosaicked, cropped, styled, overlaid and encoded exactly as for a request,
+// ! and the decoded PNG is compared with a snapshot. The debug tiles' red edges and z/x/y
+// ! labels make an off-by-one crop or a misplaced overlay show up as a diff.
+// !
+// ! Snapshots live next to the overlay ones under snapshots/, prefixed pipeline_.
+
+use crate::coordinates::LatLong;
+use crate::effects::Filter;
+use crate::fetcher::TileSources;
+use crate::frame::{Frame, Mask};
+use crate::overlay::Overlay;
+use crate::route::LineStyle;
+use crate::snapshot::{assert_snapshot_within, DEFAULT_TOLERANCE};
+use crate::tiles::{fetch_image_from_point, RenderOptions, TileSet};
+use image::{Rgba, RgbaImage};
+
+// The Grosse Scheidegg pass. Its center sits off the tile grid, so the crop offsets are
+// neither zero nor a whole number of tiles.
+const CENTER: LatLong = LatLong(46.655559, 8.102121);
+
+// Resampling and blending can round differently between platforms, so resized images are
+// allowed a little more slack than the default
+const RESAMPLED_TOLERANCE: f32 = 0.005;
+
+async fn render(radius_km: f32, size: u32, options: &RenderOptions) -> RgbaImage {
+    let png = fetch_image_from_point(
+        &TileSources::default(),
+        CENTER,
+        radius_km,
+        size,
+        TileSet::Debug,
+        options,
+    )
+    .await
+    .expect("debug tiles always render");
+    image::load_from_memory(&png)
+        .expect("the pipeline encodes a PNG")
+        .to_rgba8()
+}
+
+#[tokio::test]
+async fn test_pipeline_crop() {
+    let image = render(0.5, 256, &RenderOptions::default()).await;
+    assert_snapshot_within("pipeline_crop", &image, DEFAULT_TOLERANCE);
+}
+
+#[tokio::test]
+async fn test_pipeline_exact_size_and_scale() {
+    let options = RenderOptions {
+        exact_size: true,
+        scale: Some(0.5),
+        ..Default::default()
+    };
+    let image = render(0.5, 256, &options).await;
+    assert_eq!(image.dimensions(), (128, 128));
+    assert_snapshot_within("pipeline_exact_scaled", &image, RESAMPLED_TOLERANCE);
+}
+
+#[tokio::test]
+async fn test_pipeline_overlays() {
+    let offset = |lat: f64, long: f64| LatLong(CENTER.0 + lat, CENTER.1 + long);
+    let options = RenderOptions {
+        overlays: vec![
+            Overlay::Line {
+                points: vec![offset(-0.002, -0.003), CENTER, offset(0.0015, 0.003)],
+                color: Rgba([220, 40, 40, 255]),
+                width: 4.0,
+                style: LineStyle::default(),
+                area: false,
+            },
+            Overlay::Point {
+                point: CENTER,
+                color: Rgba([40, 80, 220, 255]),
+                radius: 6.0,
+                label: Some("Grosse Scheidegg".to_string()),
+                symbol: None,
+            },
+        ],
+        antialias: 2,
+        ..Default::default()
+    };
+    let image = render(0.5, 256, &options).await;
+    assert_snapshot_within("pipeline_overlays", &image, DEFAULT_TOLERANCE);
+}
+
+#[tokio::test]
+async fn test_pipeline_filter_and_frame() {
+    let options = RenderOptions {
+        filter: Some(Filter::Sepia),
+        mask: Some(Mask::RoundedCorners(24)),
+        frame: Some(Frame {
+            border_width: 4,
+            border_color: Rgba([40, 40, 40, 255]),
+            shadow: 8,
+            background: Rgba([255, 255, 255, 0]),
+        }),
+        ..Default::default()
+    };
+    let image = render(0.5, 256, &options).await;
+    assert_snapshot_within("pipeline_filter_frame", &image, RESAMPLED_TOLERANCE);
+}
@@ -0,0 +1,615 @@
+// ! # icc
+// ! ICC color profiles. Rendered PNGs carry one in an iCCP chunk, so color-managed viewers
+// ! and print pipelines show the map's colors as they were meant to look. By default it's
+// ! a compact sRGB profile built here, sRGB being what tiles are mosaicked and styled in.
+// ! OUTPUT_ICC_PROFILE=<path> embeds another RGB profile instead, e.g. Display P3 for wide
+// ! gamut screens, and converts the image into it first. OUTPUT_ICC_PROFILE=none embeds
+// ! nothing.
+// !
+// ! Tiles can carry profiles of their own, which are ignored unless CONVERT_TILE_PROFILES
+// ! is set. Then tiles are converted from their profiles into sRGB as they're decoded, so
+// ! blended layers from tilesets in different color spaces match. Only matrix/TRC RGB
+// ! profiles, the kind displays and most image editors use, are supported. Tiles with
+// ! other profiles are left as they are, and other output profiles fail at startup.
+
+use anyhow::{anyhow, Context as _, Result};
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+use image::{DynamicImage, ImageDecoder, ImageReader, RgbaImage};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{Cursor, Write};
+use std::sync::{Arc, Mutex, OnceLock};
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+// Profiles seen on tiles, and how to convert them into sRGB if we can
+type TileTransforms = HashMap<Vec<u8>, Option<Arc<Transform>>>;
+static TILE_TRANSFORMS: OnceLock<Mutex<TileTransforms>> = OnceLock::new();
+// Tile profiles are usually the same few, so this only guards against a source that
+// sends a different one with every tile
+const MAX_TILE_TRANSFORMS: usize = 32;
+
+// The sRGB primaries, adapted to the D50 white of the profile connection space, as the
+// columns of the matrix from linear RGB to XYZ
+const SRGB_MATRIX: [[f32; 3]; 3] = [
+    [0.436_074_7, 0.385_064_9, 0.143_080_4],
+    [0.222_504_5, 0.716_878_6, 0.060_616_9],
+    [0.013_932_2, 0.097_104_5, 0.714_173_3],
+];
+const D50: [f32; 3] = [0.9642, 1.0, 0.8249];
+// Entries in the built-in profile's tone curve, one per 8-bit level
+const SRGB_CURVE_POINTS: usize = 256;
+// Samples of a destination tone curve the conversion searches to encode linear light, and
+// the steps of linear light it encodes, fine enough that sRGB to sRGB changes nothing
+const CURVE_SAMPLES: usize = 4096;
+const ENCODE_STEPS: usize = 65536;
+
+struct Config {
+    output: Option<OutputProfile>,
+    convert_tiles: bool,
+}
+
+struct OutputProfile {
+    icc: Vec<u8>,
+    // None for the built-in sRGB profile, which rendered images are already in
+    transform: Option<Transform>,
+}
+
+fn defaults() -> Config {
+    Config {
+        output: Some(OutputProfile {
+            icc: srgb_icc().to_vec(),
+            transform: None,
+        }),
+        convert_tiles: false,
+    }
+}
+
+fn config() -> &'static Config {
+    CONFIG.get_or_init(defaults)
+}
+
+// Reads OUTPUT_ICC_PROFILE and CONVERT_TILE_PROFILES. This should be called once at
+// startup; until it is, images get the built-in sRGB profile and tiles aren't converted.
+pub fn init_from_env() -> Result<()> {
+    let mut config = defaults();
+    match env::var("OUTPUT_ICC_PROFILE").ok().as_deref() {
+        None | Some("srgb") => {}
+        Some("none") => config.output = None,
+        Some(path) => {
+            let icc = fs::read(path).with_context(|| format!("reading {}", path))?;
+            let profile = Profile::parse(&icc).with_context(|| format!("parsing {}", path))?;
+            let transform = Transform::new(srgb(), &profile)?;
+            info!("Embedding the ICC profile {} in images", path);
+            config.output = Some(OutputProfile {
+                icc,
+                transform: Some(transform),
+            });
+        }
+    }
+    config.convert_tiles = env::var("CONVERT_TILE_PROFILES").is_ok_and(|v| v == "true");
+    let _ = CONFIG.set(config);
+    Ok(())
+}
+
+// A tone curve, from encoded values to linear light, both from 0 to 1
+#[derive(Debug, Clone, PartialEq)]
+enum Curve {
+    Gamma(f32),
+    // Evenly spaced samples, interpolated between
+    Table(Vec<f32>),
+    // One of the ICC parametric curves, with its g, a, b, c, d, e and f
+    Parametric(u16, [f32; 7]),
+}
+
+impl Curve {
+    fn eval(&self, x: f32) -> f32 {
+        let x = x.clamp(0.0, 1.0);
+        let y = match self {
+            Curve::Gamma(g) => x.powf(*g),
+            Curve::Table(table) => match table.len() {
+                0 => x,
+                1 => table[0],
+                n => {
+                    let position = x * (n - 1) as f32;
+                    let i = (position.floor() as usize).min(n - 2);
+                    let t = position - i as f32;
+                    table[i] * (1.0 - t) + table[i + 1] * t
+                }
+            },
+            Curve::Parametric(kind, [g, a, b, c, d, e, f]) => {
+                let power = |x: f32| (a * x + b).max(0.0).powf(*g);
+                match kind {
+                    0 => x.powf(*g),
+                    1 if x >= -b / a => power(x),
+                    1 => 0.0,
+                    2 if x >= -b / a => power(x) + c,
+                    2 => *c,
+                    3 if x >= *d => power(x),
+                    3 => c * x,
+                    _ if x >= *d => power(x) + e,
+                    _ => c * x + f,
+                }
+            }
+        };
+        y.clamp(0.0, 1.0)
+    }
+}
+
+// The parts of a matrix/TRC RGB profile that say how its colors map to XYZ
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    matrix: [[f32; 3]; 3],
+    curves: [Curve; 3],
+}
+
+fn u16_at(icc: &[u8], offset: usize) -> Result<u16> {
+    icc.get(offset..offset + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(|| anyhow!("The profile is truncated"))
+}
+
+fn u32_at(icc: &[u8], offset: usize) -> Result<u32> {
+    icc.get(offset..offset + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| anyhow!("The profile is truncated"))
+}
+
+fn s15_fixed16_at(icc: &[u8], offset: usize) -> Result<f32> {
+    Ok(u32_at(icc, offset)? as i32 as f32 / 65536.0)
+}
+
+impl Profile {
+    pub fn parse(icc: &[u8]) -> Result<Profile> {
+        if icc.len() < 132 || &icc[36..40] != b"acsp" {
+            return Err(anyhow!("Not an ICC profile"));
+        }
+        if &icc[16..20] != b"RGB " || &icc[20..24] != b"XYZ " {
+            return Err(anyhow!(
+                "Only RGB profiles with an XYZ connection space are supported"
+            ));
+        }
+        let count = u32_at(icc, 128)? as usize;
+        let tag = |signature: &[u8; 4]| -> Result<&[u8]> {
+            let entry = (0..count.min(icc.len() / 12))
+                .map(|i| 132 + i * 12)
+                .find(|&entry| icc.get(entry..entry + 4) == Some(&signature[..]))
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Only matrix/TRC profiles are supported: there's no {} tag",
+                        String::from_utf8_lossy(signature)
+                    )
+                })?;
+            let offset = u32_at(icc, entry + 4)? as usize;
+            let size = u32_at(icc, entry + 8)? as usize;
+            icc.get(offset..offset.saturating_add(size))
+                .filter(|data| data.len() >= 12)
+                .ok_or_else(|| anyhow!("The profile is truncated"))
+        };
+
+        let mut matrix = [[0.0; 3]; 3];
+        for (column, signature) in [b"rXYZ", b"gXYZ", b"bXYZ"].into_iter().enumerate() {
+            let data = tag(signature)?;
+            if &data[..4] != b"XYZ " {
+                return Err(anyhow!("Unexpected colorant type"));
+            }
+            for (row, value) in matrix.iter_mut().enumerate() {
+                value[column] = s15_fixed16_at(data, 8 + row * 4)?;
+            }
+        }
+        let curves = [
+            parse_curve(tag(b"rTRC")?)?,
+            parse_curve(tag(b"gTRC")?)?,
+            parse_curve(tag(b"bTRC")?)?,
+        ];
+        Ok(Profile { matrix, curves })
+    }
+}
+
+fn parse_curve(data: &[u8]) -> Result<Curve> {
+    match &data[..4] {
+        b"curv" => {
+            let count = u32_at(data, 8)? as usize;
+            match count {
+                1 => Ok(Curve::Gamma(u16_at(data, 12)? as f32 / 256.0)),
+                _ => (0..count)
+                    .map(|i| Ok(u16_at(data, 12 + i * 2)? as f32 / 65535.0))
+                    .collect::<Result<_>>()
+                    .map(Curve::Table),
+            }
+        }
+        b"para" => {
+            let kind = u16_at(data, 8)?;
+            let used = match kind {
+                0 => 1,
+                1 => 3,
+                2 => 4,
+                3 => 5,
+                4 => 7,
+                _ => return Err(anyhow!("Unknown parametric curve type {}", kind)),
+            };
+            let mut params = [0.0; 7];
+            for (i, param) in params.iter_mut().take(used).enumerate() {
+                *param = s15_fixed16_at(data, 12 + i * 4)?;
+            }
+            Ok(Curve::Parametric(kind, params))
+        }
+        other => Err(anyhow!(
+            "Unknown tone curve type {}",
+            String::from_utf8_lossy(other)
+        )),
+    }
+}
+
+fn invert(m: &[[f32; 3]; 3]) -> Option<[[f32; 3]; 3]> {
+    let cofactor =
+        |r0: usize, r1: usize, c0: usize, c1: usize| m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0];
+    let det = m[0][0] * cofactor(1, 2, 1, 2) - m[0][1] * cofactor(1, 2, 0, 2)
+        + m[0][2] * cofactor(1, 2, 0, 1);
+    if det.abs() < 1e-6 {
+        return None;
+    }
+    Some([
+        [
+            cofactor(1, 2, 1, 2) / det,
+            -cofactor(0, 2, 1, 2) / det,
+            cofactor(0, 1, 1, 2) / det,
+        ],
+        [
+            -cofactor(1, 2, 0, 2) / det,
+            cofactor(0, 2, 0, 2) / det,
+            -cofactor(0, 1, 0, 2) / det,
+        ],
+        [
+            cofactor(1, 2, 0, 1) / det,
+            -cofactor(0, 2, 0, 1) / det,
+            cofactor(0, 1, 0, 1) / det,
+        ],
+    ])
+}
+
+fn multiply(a: &[[f32; 3]; 3], b: &[[f32; 3]; 3]) -> [[f32; 3]; 3] {
+    let mut product = [[0.0; 3]; 3];
+    for (r, row) in product.iter_mut().enumerate() {
+        for (c, value) in row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| a[r][k] * b[k][c]).sum();
+        }
+    }
+    product
+}
+
+// Converts 8-bit pixels from one profile to another through linear light
+struct Transform {
+    linearize: [[f32; 256]; 3],
+    // From the source's linear RGB to the destination's
+    matrix: [[f32; 3]; 3],
+    encode: [Vec<u8>; 3],
+}
+
+impl Transform {
+    fn new(from: &Profile, to: &Profile) -> Result<Transform> {
+        let to_rgb =
+            invert(&to.matrix).ok_or_else(|| anyhow!("The profile's matrix is singular"))?;
+        let linearize = [0, 1, 2].map(|channel| {
+            let mut table = [0.0; 256];
+            for (v, linear) in table.iter_mut().enumerate() {
+                *linear = from.curves[channel].eval(v as f32 / 255.0);
+            }
+            table
+        });
+        // The destination's curves only go one way, so they're sampled finely and each
+        // linear step takes the encoded value whose sample is closest to it
+        let encode = [0, 1, 2].map(|channel| {
+            let curve = &to.curves[channel];
+            let samples: Vec<f32> = (0..CURVE_SAMPLES)
+                .map(|i| curve.eval(i as f32 / (CURVE_SAMPLES - 1) as f32))
+                .collect();
+            (0..ENCODE_STEPS)
+                .map(|step| {
+                    let linear = step as f32 / (ENCODE_STEPS - 1) as f32;
+                    let above = samples
+                        .partition_point(|&s| s < linear)
+                        .min(CURVE_SAMPLES - 1);
+                    let i = match above.checked_sub(1) {
+                        Some(below) if linear - samples[below] < samples[above] - linear => below,
+                        _ => above,
+                    };
+                    (i as f32 / (CURVE_SAMPLES - 1) as f32 * 255.0).round() as u8
+                })
+                .collect()
+        });
+        Ok(Transform {
+            linearize,
+            matrix: multiply(&to_rgb, &from.matrix),
+            encode,
+        })
+    }
+
+    fn apply(&self, image: &mut RgbaImage) {
+        for pixel in image.pixels_mut() {
+            let linear = [0, 1, 2].map(|c| self.linearize[c][pixel[c] as usize]);
+            for (c, row) in self.matrix.iter().enumerate() {
+                let value: f32 = row.iter().zip(linear).map(|(m, v)| m * v).sum();
+                let step = (value.clamp(0.0, 1.0) * (ENCODE_STEPS - 1) as f32).round();
+                pixel[c] = self.encode[c][step as usize];
+            }
+        }
+    }
+}
+
+fn push_s15_fixed16(out: &mut Vec<u8>, value: f32) {
+    out.extend_from_slice(&((value * 65536.0).round() as i32).to_be_bytes());
+}
+
+fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// Builds a version 2 sRGB display profile: its primaries, white point and tone curve
+fn build_srgb_icc() -> Vec<u8> {
+    let mut desc = b"desc\0\0\0\0".to_vec();
+    let name = b"sRGB\0";
+    desc.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    desc.extend_from_slice(name);
+    // No Unicode or ScriptCode descriptions
+    desc.extend_from_slice(&[0; 8 + 3 + 67]);
+    let cprt = b"text\0\0\0\0No copyright, use freely\0".to_vec();
+    let xyz = |values: [f32; 3]| {
+        let mut tag = b"XYZ \0\0\0\0".to_vec();
+        for v in values {
+            push_s15_fixed16(&mut tag, v);
+        }
+        tag
+    };
+    let column = |c: usize| [0, 1, 2].map(|r| SRGB_MATRIX[r][c]);
+    let mut curve = b"curv\0\0\0\0".to_vec();
+    curve.extend_from_slice(&(SRGB_CURVE_POINTS as u32).to_be_bytes());
+    for i in 0..SRGB_CURVE_POINTS {
+        let linear = srgb_to_linear(i as f32 / (SRGB_CURVE_POINTS - 1) as f32);
+        curve.extend_from_slice(&((linear * 65535.0).round() as u16).to_be_bytes());
+    }
+
+    // The three tone curves are the same, so their tags share one copy
+    let tags: [(&[u8; 4], usize); 9] = [
+        (b"desc", 0),
+        (b"cprt", 1),
+        (b"wtpt", 2),
+        (b"rXYZ", 3),
+        (b"gXYZ", 4),
+        (b"bXYZ", 5),
+        (b"rTRC", 6),
+        (b"gTRC", 6),
+        (b"bTRC", 6),
+    ];
+    let data = [
+        desc,
+        cprt,
+        xyz(D50),
+        xyz(column(0)),
+        xyz(column(1)),
+        xyz(column(2)),
+        curve,
+    ];
+    let mut offsets = Vec::new();
+    let mut body = Vec::new();
+    let start = 128 + 4 + tags.len() * 12;
+    for tag in &data {
+        offsets.push((start + body.len(), tag.len()));
+        body.extend_from_slice(tag);
+        body.resize(body.len().next_multiple_of(4), 0);
+    }
+    let size = start + body.len();
+
+    let mut icc = Vec::with_capacity(size);
+    icc.extend_from_slice(&(size as u32).to_be_bytes());
+    icc.extend_from_slice(&[0; 4]);
+    // Version 2.1, a display profile from RGB to XYZ
+    icc.extend_from_slice(&[2, 0x10, 0, 0]);
+    icc.extend_from_slice(b"mntrRGB XYZ ");
+    icc.extend_from_slice(&[0; 12]);
+    icc.extend_from_slice(b"acsp");
+    icc.extend_from_slice(&[0; 24]);
+    // Perceptual rendering intent, then the connection space's illuminant
+    icc.extend_from_slice(&[0; 4]);
+    for v in D50 {
+        push_s15_fixed16(&mut icc, v);
+    }
+    icc.resize(128, 0);
+    icc.extend_from_slice(&(tags.len() as u32).to_be_bytes());
+    for (signature, index) in tags {
+        let (offset, len) = offsets[index];
+        icc.extend_from_slice(signature);
+        icc.extend_from_slice(&(offset as u32).to_be_bytes());
+        icc.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+    icc.extend_from_slice(&body);
+    icc
+}
+
+// The built-in sRGB profile
+pub fn srgb_icc() -> &'static [u8] {
+    static ICC: OnceLock<Vec<u8>> = OnceLock::new();
+    ICC.get_or_init(build_srgb_icc)
+}
+
+fn srgb() -> &'static Profile {
+    static PROFILE: OnceLock<Profile> = OnceLock::new();
+    PROFILE.get_or_init(|| Profile::parse(srgb_icc()).expect("the built-in profile parses"))
+}
+
+// Converts a rendered image into the output profile, if it isn't sRGB
+pub fn convert_output(image: &mut RgbaImage) {
+    if let Some(transform) = config().output.as_ref().and_then(|o| o.transform.as_ref()) {
+        transform.apply(image);
+    }
+}
+
+// Adds the output profile to a PNG, in an iCCP chunk after its header
+pub fn embed_in_png(png: Vec<u8>) -> Vec<u8> {
+    match &config().output {
+        Some(output) => embed(png, &output.icc),
+        None => png,
+    }
+}
+
+fn embed(png: Vec<u8>, icc: &[u8]) -> Vec<u8> {
+    // The 8 byte signature, then IHDR's length, type, 13 bytes of data and CRC
+    const IHDR_END: usize = 8 + 4 + 4 + 13 + 4;
+    if png.len() < IHDR_END || &png[12..16] != b"IHDR" {
+        return png;
+    }
+    let mut compressed = ZlibEncoder::new(Vec::new(), Compression::default());
+    compressed.write_all(icc).expect("writing to memory");
+    let compressed = compressed.finish().expect("writing to memory");
+
+    // The profile's name, then compression method 0 for zlib
+    let mut chunk = b"iCCP".to_vec();
+    chunk.extend_from_slice(b"ICC Profile\0\0");
+    chunk.extend_from_slice(&compressed);
+    let mut crc = Crc::new();
+    crc.update(&chunk);
+
+    let mut out = Vec::with_capacity(png.len() + chunk.len() + 8);
+    out.extend_from_slice(&png[..IHDR_END]);
+    out.extend_from_slice(&((chunk.len() - 4) as u32).to_be_bytes());
+    out.extend_from_slice(&chunk);
+    out.extend_from_slice(&crc.sum().to_be_bytes());
+    out.extend_from_slice(&png[IHDR_END..]);
+    out
+}
+
+// How to convert a tile with the given profile into sRGB, or None if it can't be
+fn tile_transform(icc: &[u8]) -> Option<Arc<Transform>> {
+    let transforms = TILE_TRANSFORMS.get_or_init(Default::default);
+    let mut transforms = transforms.lock().unwrap();
+    if let Some(transform) = transforms.get(icc) {
+        return transform.clone();
+    }
+    let transform = Profile::parse(icc)
+        .and_then(|profile| Transform::new(&profile, srgb()))
+        .map_err(|e| {
+            warn!(
+                "Leaving tiles with an unsupported ICC profile as they are: {:#}",
+                e
+            )
+        })
+        .ok()
+        .map(Arc::new);
+    if transforms.len() >= MAX_TILE_TRANSFORMS {
+        transforms.clear();
+    }
+    transforms.insert(icc.to_vec(), transform.clone());
+    transform
+}
+
+// Decodes a tile, converting it into sRGB from its own profile with CONVERT_TILE_PROFILES
+pub fn decode_tile(tile: &[u8]) -> Result<RgbaImage> {
+    if !config().convert_tiles {
+        return Ok(image::load_from_memory(tile)?.to_rgba8());
+    }
+    let mut decoder = ImageReader::new(Cursor::new(tile))
+        .with_guessed_format()?
+        .into_decoder()?;
+    let icc = decoder.icc_profile()?;
+    let mut image = DynamicImage::from_decoder(decoder)?.to_rgba8();
+    if let Some(transform) = icc
+        .filter(|icc| icc != srgb_icc())
+        .and_then(|icc| tile_transform(&icc))
+    {
+        transform.apply(&mut image);
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, Rgba};
+
+    // A profile with the sRGB primaries and a plain 2.2 gamma
+    fn gamma_profile() -> Profile {
+        Profile {
+            matrix: SRGB_MATRIX,
+            curves: [0, 1, 2].map(|_| Curve::Gamma(2.2)),
+        }
+    }
+
+    #[test]
+    fn test_srgb_profile() {
+        let icc = srgb_icc();
+        assert_eq!(u32_at(icc, 0).unwrap() as usize, icc.len());
+        assert_eq!(icc.len() % 4, 0);
+        let profile = srgb();
+        assert_eq!(
+            profile.matrix.map(|row| row.map(|v| (v * 1e4).round())),
+            SRGB_MATRIX.map(|row| row.map(|v| (v * 1e4).round()))
+        );
+        assert!((profile.curves[0].eval(0.5) - srgb_to_linear(0.5)).abs() < 1e-4);
+        assert!(Profile::parse(b"not a profile").is_err());
+
+        // sRGB to itself changes nothing
+        let transform = Transform::new(srgb(), srgb()).unwrap();
+        let mut image =
+            RgbaImage::from_fn(16, 16, |x, y| Rgba([x as u8 * 16, y as u8 * 16, 200, 255]));
+        let original = image.clone();
+        transform.apply(&mut image);
+        assert_eq!(image, original);
+
+        // Gamma 2.2 and sRGB differ most in the shadows
+        let transform = Transform::new(&gamma_profile(), srgb()).unwrap();
+        let mut image = RgbaImage::from_pixel(1, 1, Rgba([40, 128, 255, 255]));
+        transform.apply(&mut image);
+        let [r, g, b, a] = image.get_pixel(0, 0).0;
+        assert!(r < 40 && (126..=130).contains(&g) && b == 255 && a == 255);
+    }
+
+    #[test]
+    fn test_parametric_curve() {
+        // sRGB's own curve, as v4 profiles write it
+        let srgb = Curve::Parametric(
+            3,
+            [
+                2.4,
+                1.0 / 1.055,
+                0.055 / 1.055,
+                1.0 / 12.92,
+                0.04045,
+                0.0,
+                0.0,
+            ],
+        );
+        for v in [0.0, 0.02, 0.3, 0.8, 1.0] {
+            assert!((srgb.eval(v) - srgb_to_linear(v)).abs() < 1e-5);
+        }
+        assert_eq!(Curve::Table(vec![]).eval(0.3), 0.3);
+    }
+
+    #[test]
+    fn test_embed_in_png() {
+        let image = RgbaImage::from_pixel(4, 4, Rgba([10, 20, 30, 255]));
+        let mut png = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+
+        let tagged = embed(png, srgb_icc());
+        let mut decoder = ImageReader::new(Cursor::new(&tagged))
+            .with_guessed_format()
+            .unwrap()
+            .into_decoder()
+            .unwrap();
+        assert_eq!(decoder.icc_profile().unwrap().as_deref(), Some(srgb_icc()));
+        assert_eq!(
+            DynamicImage::from_decoder(decoder).unwrap().to_rgba8(),
+            image
+        );
+        assert_eq!(embed(b"not a png".to_vec(), srgb_icc()), b"not a png");
+    }
+}
@@ -0,0 +1,145 @@
+// ! # integrity
+// ! Checks that a fetched tile is really a tile before anything keeps it. An upstream can
+// ! hand back a truncated body or an error page with a 200, and a tile like that used to
+// ! be cached and then fail every render that needed it. Tiles are checked by decoding
+// ! them, which catches truncation as well as a bad signature, and their size is checked
+// ! against the tile size their tileset serves.
+// !
+// ! A corrupt tile is fetched again once before giving up, and counted in the
+// ! corrupt_tiles metric by tileset and where it was found.
+
+use crate::fetcher::TileFetcher;
+use crate::tiles::TileSet;
+use anyhow::{anyhow, Context as _, Result};
+use bytes::Bytes;
+use log::warn;
+use opentelemetry::{global, Context, KeyValue};
+
+// Where a corrupt tile was found, for the metric
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Found {
+    // Fetched from the tileset's source
+    Upstream,
+    // Read back from the tile cache
+    Cache,
+}
+
+impl Found {
+    fn name(&self) -> &'static str {
+        match self {
+            Found::Upstream => "upstream",
+            Found::Cache => "cache",
+        }
+    }
+}
+
+// Checks that the bytes decode to a whole tile of the given size
+pub fn check(png: &[u8], tile_size: u32) -> Result<()> {
+    let image = image::load_from_memory(png).context("Tile doesn't decode")?;
+    if (image.width(), image.height()) != (tile_size, tile_size) {
+        return Err(anyhow!(
+            "Tile is {}x{}, not {}x{}",
+            image.width(),
+            image.height(),
+            tile_size,
+            tile_size
+        ));
+    }
+    Ok(())
+}
+
+// Counts a corrupt tile
+pub fn record_corrupt(tileset: TileSet, found: Found) {
+    let meter = global::meter("tile_integrity_meter");
+    let corrupt_tiles = meter.u64_counter("corrupt_tiles").init();
+    corrupt_tiles.add(
+        1,
+        &[
+            KeyValue::new("tileset", tileset.name()),
+            KeyValue::new("found", found.name()),
+        ],
+    );
+}
+
+// Fetches a tile and checks it, fetching it once more if it's corrupt. A tile that's
+// still corrupt the second time is an error rather than something to render.
+pub async fn fetch_checked(
+    fetcher: &dyn TileFetcher,
+    tileset: TileSet,
+    x: u32,
+    y: u32,
+    z: u32,
+    cx: Context,
+) -> Result<Bytes> {
+    let tile = fetcher.fetch(tileset, x, y, z, cx.clone()).await?;
+    let Err(e) = check(&tile, tileset.tile_size()) else {
+        return Ok(tile);
+    };
+    record_corrupt(tileset, Found::Upstream);
+    warn!(tileset = tileset.name(); "Tile {}/{}/{} is corrupt, fetching it again: {:#}", z, x, y, e);
+
+    let tile = fetcher.fetch(tileset, x, y, z, cx).await?;
+    if let Err(e) = check(&tile, tileset.tile_size()) {
+        record_corrupt(tileset, Found::Upstream);
+        return Err(e.context(format!(
+            "Tile {}/{}/{} for {} is corrupt",
+            z,
+            x,
+            y,
+            tileset.name()
+        )));
+    }
+    Ok(tile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debug_tiles::debug_tile;
+    use crate::tiles::encode_png;
+    use futures::future::LocalBoxFuture;
+    use std::sync::Mutex;
+
+    // Hands out the given responses in turn
+    struct Sequence(Mutex<Vec<Bytes>>);
+
+    impl TileFetcher for Sequence {
+        fn fetch(
+            &self,
+            _tileset: TileSet,
+            _x: u32,
+            _y: u32,
+            _z: u32,
+            _cx: Context,
+        ) -> LocalBoxFuture<'_, Result<Bytes>> {
+            let tile = self.0.lock().unwrap().remove(0);
+            Box::pin(async move { Ok(tile) })
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let tile = encode_png(debug_tile(1, 2, 3));
+        assert!(check(&tile, 256).is_ok());
+        assert!(check(&tile[..tile.len() / 2], 256).is_err());
+        assert!(check(b"<html>rate limited</html>", 256).is_err());
+        let small = encode_png(image::RgbaImage::new(128, 128));
+        assert!(format!("{:#}", check(&small, 256).unwrap_err()).contains("128x128"));
+        // A 256px tile from a tileset that serves 512px ones is as wrong
+        assert!(format!("{:#}", check(&tile, 512).unwrap_err()).contains("not 512x512"));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_checked_retries_once() {
+        let tile = encode_png(debug_tile(1, 2, 3));
+        let truncated = tile.slice(..tile.len() / 2);
+
+        let flaky = Sequence(Mutex::new(vec![truncated.clone(), tile.clone()]));
+        let fetched = fetch_checked(&flaky, TileSet::Osm, 1, 2, 3, Context::new()).await;
+        assert_eq!(fetched.unwrap(), tile);
+
+        let broken = Sequence(Mutex::new(vec![truncated.clone(), truncated]));
+        let fetched = fetch_checked(&broken, TileSet::Osm, 1, 2, 3, Context::new()).await;
+        assert!(format!("{:#}", fetched.unwrap_err()).contains("3/1/2 for osm is corrupt"));
+    }
+}
@@ -0,0 +1,152 @@
+// ! # labels
+// ! Text labels next to markers, e.g. pass names. Labels are drawn with a halo so they read
+// ! over any basemap, and each goes in the first of a few spots around its marker that
+// ! doesn't overlap a label already placed. Labels are placed in the order their markers
+// ! were given, so which ones win is deterministic; those with nowhere to go are dropped
+// ! rather than drawn on top of one another.
+
+use crate::text::{draw_text_with_halo, text_height, text_width};
+use image::{Rgba, RgbaImage};
+
+const LABEL_COLOR: Rgba<u8> = Rgba([30, 30, 30, 255]);
+const HALO_COLOR: Rgba<u8> = Rgba([255, 255, 255, 220]);
+
+// Gap between a marker and its label, in unscaled pixels
+const GAP_PX: i64 = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    pub text: String,
+    // The marker's center in image pixels, and how far the label should keep from it
+    pub anchor: (f64, f64),
+    pub clearance: f64,
+    pub scale: u32,
+}
+
+// A label's bounds in image pixels, halo included
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Rect {
+    left: i64,
+    top: i64,
+    right: i64,
+    bottom: i64,
+}
+
+impl Rect {
+    fn overlaps(&self, other: &Rect) -> bool {
+        self.left < other.right
+            && other.left < self.right
+            && self.top < other.bottom
+            && other.top < self.bottom
+    }
+
+    fn within(&self, width: u32, height: u32) -> bool {
+        self.left >= 0
+            && self.top >= 0
+            && self.right <= width as i64
+            && self.bottom <= height as i64
+    }
+}
+
+impl Label {
+    // Where the text's top left corner could go, in order of preference: right of the
+    // marker, left of it, above and below
+    fn candidates(&self) -> [(i64, i64); 4] {
+        let (width, height) = (
+            text_width(&self.text, self.scale) as i64,
+            text_height(self.scale) as i64,
+        );
+        let (x, y) = (self.anchor.0 as i64, self.anchor.1 as i64);
+        let away = self.clearance.ceil() as i64 + GAP_PX * self.scale as i64;
+        [
+            (x + away, y - height / 2),
+            (x - away - width, y - height / 2),
+            (x - width / 2, y - away - height),
+            (x - width / 2, y + away),
+        ]
+    }
+
+    // The bounds of the label with its text at the given position
+    fn bounds(&self, (x, y): (i64, i64)) -> Rect {
+        let halo = self.scale as i64;
+        Rect {
+            left: x - halo,
+            top: y - halo,
+            right: x + text_width(&self.text, self.scale) as i64 + halo,
+            bottom: y + text_height(self.scale) as i64 + halo,
+        }
+    }
+}
+
+// Picks a position for each label's text, or None where it would overlap a label placed
+// before it or fall off the image
+fn place_labels(labels: &[Label], width: u32, height: u32) -> Vec<Option<(i64, i64)>> {
+    let mut placed: Vec<Rect> = Vec::new();
+    labels
+        .iter()
+        .map(|label| {
+            let position = label.candidates().into_iter().find(|candidate| {
+                let bounds = label.bounds(*candidate);
+                bounds.within(width, height) && !placed.iter().any(|p| p.overlaps(&bounds))
+            })?;
+            placed.push(label.bounds(position));
+            Some(position)
+        })
+        .collect()
+}
+
+pub fn draw_labels(img: &mut RgbaImage, labels: &[Label]) {
+    let positions = place_labels(labels, img.width(), img.height());
+    for (label, position) in labels.iter().zip(positions) {
+        if let Some((x, y)) = position {
+            draw_text_with_halo(img, x, y, &label.text, label.scale, LABEL_COLOR, HALO_COLOR);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn label(text: &str, x: f64, y: f64) -> Label {
+        Label {
+            text: text.to_string(),
+            anchor: (x, y),
+            clearance: 6.0,
+            scale: 1,
+        }
+    }
+
+    #[test]
+    fn test_labels_move_out_of_the_way() {
+        let labels = vec![
+            label("Grimsel", 100.0, 100.0),
+            label("Furka", 100.0, 100.0),
+            label("Susten", 100.0, 100.0),
+            label("Nufenen", 100.0, 100.0),
+            label("Oberalp", 100.0, 100.0),
+        ];
+        let positions = place_labels(&labels, 256, 256);
+
+        // Right, left, above, below, and then there's no room left
+        for (i, (position, label)) in positions.iter().zip(&labels).take(4).enumerate() {
+            assert_eq!(*position, Some(label.candidates()[i]));
+        }
+        assert_eq!(positions[4], None);
+    }
+
+    #[test]
+    fn test_labels_stay_on_the_image() {
+        // Too close to the right edge to go on the right
+        let labels = vec![label("Gotthard", 250.0, 100.0)];
+        let positions = place_labels(&labels, 256, 256);
+        assert_eq!(positions[0], Some(labels[0].candidates()[1]));
+    }
+
+    #[test]
+    fn test_labels_far_apart_dont_move() {
+        let labels = vec![label("Klausen", 50.0, 50.0), label("Pragel", 50.0, 200.0)];
+        let positions = place_labels(&labels, 256, 256);
+        assert_eq!(positions[1], Some(labels[1].candidates()[0]));
+    }
+}
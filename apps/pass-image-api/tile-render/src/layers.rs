@@ -0,0 +1,198 @@
+// ! # layers
+// ! The layers a rendered image is built from. From the bottom up: the basemap, raster
+// ! overlays computed from terrain, polygons, lines, markers, marker labels and finally
+// ! the attribution. Each layer is drawn on a canvas of its own and the canvases are
+// ! composited in z order, so callers can change a layer's opacity or move it up or down,
+// ! e.g. to put routes under a translucent slope shading. The attribution always stays on
+// ! top and opaque, as licensed tilesets require it to be legible.
+
+use crate::text;
+use anyhow::{anyhow, Result};
+use image::RgbaImage;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+// The range z-index overrides can take. Everything stays under the attribution.
+pub const MIN_Z: i32 = 0;
+pub const MAX_Z: i32 = 99;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LayerKind {
+    Basemap,
+    // Slope shading and contours
+    Raster,
+    Polygons,
+    Lines,
+    Markers,
+    Labels,
+    Attribution,
+}
+
+impl FromStr for LayerKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "basemap" => Ok(LayerKind::Basemap),
+            "raster" => Ok(LayerKind::Raster),
+            "polygons" => Ok(LayerKind::Polygons),
+            "lines" => Ok(LayerKind::Lines),
+            "markers" => Ok(LayerKind::Markers),
+            "labels" => Ok(LayerKind::Labels),
+            "attribution" => Ok(LayerKind::Attribution),
+            other => Err(anyhow!("Unknown layer {}", other)),
+        }
+    }
+}
+
+impl LayerKind {
+    // Where the layer goes without an override
+    pub fn default_z(&self) -> i32 {
+        match self {
+            LayerKind::Basemap => 0,
+            LayerKind::Raster => 10,
+            LayerKind::Polygons => 20,
+            LayerKind::Lines => 30,
+            LayerKind::Markers => 40,
+            LayerKind::Labels => 50,
+            LayerKind::Attribution => 100,
+        }
+    }
+}
+
+// Per-layer opacity and z-index overrides
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LayerSettings {
+    opacity: HashMap<LayerKind, f32>,
+    z: HashMap<LayerKind, i32>,
+}
+
+impl LayerSettings {
+    // Parses the layer_opacity= and layer_z= parameters, each a list of layer:value
+    // pairs, e.g. lines:0.6,markers:0.8 and lines:45
+    pub fn from_params(opacity: Option<&str>, z: Option<&str>) -> Result<LayerSettings> {
+        let mut settings = LayerSettings::default();
+        for (kind, value) in parse_spec::<f32>("layer_opacity", opacity)? {
+            if !(0.0..=1.0).contains(&value) {
+                return Err(anyhow!("layer_opacity values must be between 0 and 1"));
+            }
+            settings.opacity.insert(kind, value);
+        }
+        for (kind, value) in parse_spec::<i32>("layer_z", z)? {
+            if !(MIN_Z..=MAX_Z).contains(&value) {
+                return Err(anyhow!(
+                    "layer_z values must be between {} and {}",
+                    MIN_Z,
+                    MAX_Z
+                ));
+            }
+            settings.z.insert(kind, value);
+        }
+        Ok(settings)
+    }
+
+    pub fn opacity(&self, kind: LayerKind) -> f32 {
+        self.opacity.get(&kind).copied().unwrap_or(1.0)
+    }
+
+    pub fn z(&self, kind: LayerKind) -> i32 {
+        self.z.get(&kind).copied().unwrap_or(kind.default_z())
+    }
+}
+
+fn parse_spec<T: FromStr>(name: &str, spec: Option<&str>) -> Result<Vec<(LayerKind, T)>> {
+    let Some(spec) = spec else {
+        return Ok(Vec::new());
+    };
+    spec.split(',')
+        .map(|entry| {
+            let (kind, value) = entry
+                .split_once(':')
+                .ok_or_else(|| anyhow!("{} entries look like layer:value, got {}", name, entry))?;
+            let kind: LayerKind = kind.trim().parse()?;
+            if kind == LayerKind::Attribution {
+                return Err(anyhow!("The attribution layer can't be restyled"));
+            }
+            let value = value
+                .trim()
+                .parse::<T>()
+                .map_err(|_| anyhow!("Invalid {} value {}", name, value))?;
+            Ok((kind, value))
+        })
+        .collect()
+}
+
+// Composites layers, all the same size, in z order at their opacities. Layers with the
+// same z keep the order they're given in.
+pub fn composite(mut layers: Vec<(LayerKind, RgbaImage)>, settings: &LayerSettings) -> RgbaImage {
+    layers.sort_by_key(|(kind, _)| settings.z(*kind));
+    let (width, height) = layers.first().map_or((0, 0), |(_, l)| l.dimensions());
+
+    let mut image = RgbaImage::new(width, height);
+    for (kind, mut layer) in layers {
+        let opacity = settings.opacity(kind);
+        if opacity <= 0.0 {
+            continue;
+        }
+        if opacity < 1.0 {
+            for pixel in layer.pixels_mut() {
+                pixel[3] = (pixel[3] as f32 * opacity).round() as u8;
+            }
+        }
+        text::overlay_image(&mut image, &layer, 0, 0);
+    }
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn solid(color: [u8; 4]) -> RgbaImage {
+        RgbaImage::from_pixel(2, 2, Rgba(color))
+    }
+
+    #[test]
+    fn test_from_params() {
+        let settings =
+            LayerSettings::from_params(Some("lines:0.5, markers:0"), Some("lines:45")).unwrap();
+        assert_eq!(settings.opacity(LayerKind::Lines), 0.5);
+        assert_eq!(settings.opacity(LayerKind::Basemap), 1.0);
+        assert_eq!(settings.z(LayerKind::Lines), 45);
+        assert_eq!(settings.z(LayerKind::Markers), 40);
+
+        assert!(LayerSettings::from_params(Some("lines:2"), None).is_err());
+        assert!(LayerSettings::from_params(Some("roads:0.5"), None).is_err());
+        assert!(LayerSettings::from_params(None, Some("attribution:0")).is_err());
+        assert!(LayerSettings::from_params(None, Some("lines:200")).is_err());
+    }
+
+    #[test]
+    fn test_composite_in_z_order() {
+        let layers = vec![
+            (LayerKind::Markers, solid([0, 0, 255, 255])),
+            (LayerKind::Basemap, solid([255, 255, 255, 255])),
+            (LayerKind::Lines, solid([255, 0, 0, 255])),
+        ];
+        let image = composite(layers.clone(), &LayerSettings::default());
+        assert_eq!(image.get_pixel(0, 0), &Rgba([0, 0, 255, 255]));
+
+        // Lines moved above the markers
+        let settings = LayerSettings::from_params(None, Some("lines:60")).unwrap();
+        let image = composite(layers, &settings);
+        assert_eq!(image.get_pixel(0, 0), &Rgba([255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn test_composite_opacity() {
+        let layers = vec![
+            (LayerKind::Basemap, solid([255, 255, 255, 255])),
+            (LayerKind::Lines, solid([0, 0, 0, 255])),
+        ];
+        let settings = LayerSettings::from_params(Some("lines:0.5"), None).unwrap();
+        let [r, _, _, a] = composite(layers, &settings).get_pixel(0, 0).0;
+        assert!(r.abs_diff(128) <= 1);
+        assert_eq!(a, 255);
+    }
+}
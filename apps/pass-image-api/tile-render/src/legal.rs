@@ -0,0 +1,109 @@
+// ! # legal
+// ! The legal metadata behind each tileset's attribution notice: the full attribution
+// ! text its provider asks for, the license the data or tiles are under, and the logo
+// ! the provider publishes for notices. The short notice drawn into images is
+// ! TileSet::attribution; this is what clients embedding those images show alongside.
+
+use crate::tiles::TileSet;
+use serde::Serialize;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Legal {
+    pub tileset: &'static str,
+    // The short notice drawn into images
+    pub notice: &'static str,
+    // The full attribution text the provider asks for
+    pub text: &'static str,
+    pub license: Option<&'static str>,
+    pub license_url: Option<&'static str>,
+    pub logo_url: Option<&'static str>,
+    // Whether the notice must appear on every image; see TileSet::is_licensed
+    pub required: bool,
+}
+
+pub fn legal(tileset: TileSet) -> Legal {
+    // Retina tilesets, like mapbox_2x, are under the terms of the tileset they sharpen
+    let provider = TileSet::all()
+        .into_iter()
+        .find(|t| t.retina() == Some(tileset))
+        .unwrap_or(tileset);
+    let (text, license, license_url, logo_url) = match provider {
+        TileSet::Osm => (
+            "Map data (c) OpenStreetMap contributors, available under the Open Database \
+             License. Map tiles by the OpenStreetMap Foundation and the OpenStreetMap \
+             communities running localized tile servers.",
+            Some("ODbL-1.0"),
+            Some("https://www.openstreetmap.org/copyright"),
+            Some("https://upload.wikimedia.org/wikipedia/commons/b/b0/Openstreetmap_logo.svg"),
+        ),
+        TileSet::Swisstopo => (
+            "(c) swisstopo. Geodata of the Swiss Federal Office of Topography, used under \
+             the terms of use of the Federal Spatial Data Infrastructure.",
+            Some("FSDI terms of use"),
+            Some("https://www.geo.admin.ch/en/general-terms-of-use-fsdi"),
+            None,
+        ),
+        TileSet::Terrain => (
+            "Terrain tiles (c) Mapzen and others, derived from SRTM, GMTED2010, ETOPO1 and \
+             other elevation sources, each under its own terms.",
+            Some("Various"),
+            Some("https://github.com/tilezen/joerd/blob/master/docs/attribution.md"),
+            None,
+        ),
+        TileSet::Bing => (
+            "Imagery (c) Microsoft Corporation and its data suppliers, used under the Bing \
+             Maps terms of use.",
+            Some("Bing Maps terms of use"),
+            Some("https://www.microsoft.com/en-us/maps/product/terms"),
+            None,
+        ),
+        TileSet::Mapbox => (
+            "(c) Mapbox (c) OpenStreetMap contributors. Map design and tiles by Mapbox, \
+             map data available under the Open Database License.",
+            Some("Mapbox terms of service"),
+            Some("https://www.mapbox.com/legal/tos"),
+            None,
+        ),
+        TileSet::Esri => (
+            "Source: Esri, Maxar, Earthstar Geographics, and the GIS User Community. World \
+             Imagery is used under the Esri terms of use.",
+            Some("Esri terms of use"),
+            Some("https://www.esri.com/en-us/legal/terms/full-master-agreement"),
+            None,
+        ),
+        TileSet::EsriRoads | TileSet::EsriLabels | TileSet::Hybrid => (
+            "Source: Esri, Maxar, Earthstar Geographics, HERE, Garmin, (c) OpenStreetMap \
+             contributors, and the GIS User Community. World Imagery and the World \
+             Transportation and World Boundaries and Places reference layers are used under \
+             the Esri terms of use.",
+            Some("Esri terms of use"),
+            Some("https://www.esri.com/en-us/legal/terms/full-master-agreement"),
+            None,
+        ),
+        TileSet::StamenTerrain | TileSet::StamenWatercolor => (
+            "(c) Stadia Maps (c) Stamen Design (c) OpenMapTiles (c) OpenStreetMap \
+             contributors. Map tiles by Stamen Design, hosted by Stadia Maps, used under the \
+             Stadia Maps terms of service. Map data available under the Open Database License.",
+            Some("Stadia Maps terms of service"),
+            Some("https://stadiamaps.com/terms-of-service/"),
+            None,
+        ),
+        TileSet::Debug => (
+            "Debug tiles are generated by this service and need no attribution.",
+            None,
+            None,
+            None,
+        ),
+        // Configured tilesets only have their notice to go on
+        TileSet::Custom(_) => (tileset.attribution(), None, None, None),
+    };
+    Legal {
+        tileset: tileset.name(),
+        notice: tileset.attribution(),
+        text,
+        license,
+        license_url,
+        logo_url,
+        required: tileset.is_licensed(),
+    }
+}
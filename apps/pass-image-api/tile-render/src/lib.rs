@@ -0,0 +1,61 @@
+// ! # tile-render
+// ! The map rendering core behind pass-image-api: fetching tiles, mosaicking them,
+// ! styling, drawing overlays, cropping and encoding to PNG or AVIF. It has no web
+// ! framework in it, so batch jobs and command line tools can render the same images as
+// ! the service.
+// !
+// ! Upstream tiles are fetched with reqwest by default. The service builds this crate
+// ! with the awc-transport feature instead, to stay on actix's own HTTP client.
+
+pub mod archive;
+pub mod avif;
+pub mod cache;
+pub mod cluster;
+pub mod contours;
+pub mod coordinates;
+pub mod coverage;
+pub mod crop;
+pub mod debug_tiles;
+pub mod degradations;
+pub mod dem;
+pub mod diff;
+pub mod dither;
+pub mod effects;
+pub mod extent;
+pub mod faults;
+pub mod fetcher;
+pub mod focus;
+pub mod frame;
+#[cfg(test)]
+mod golden;
+pub mod icc;
+pub mod integrity;
+pub mod labels;
+pub mod layers;
+pub mod legal;
+pub mod locale;
+pub mod mirrors;
+pub mod mvt;
+pub mod overlay;
+pub mod overview;
+pub mod plugin;
+pub mod pmtiles;
+pub mod prefetch;
+pub mod presets;
+pub mod registry;
+pub mod report;
+pub mod request;
+pub mod route;
+pub mod scale_bar;
+pub mod slope;
+#[cfg(test)]
+mod snapshot;
+pub mod text;
+pub mod tiles;
+pub mod tls;
+pub mod transport;
+pub mod upstreams;
+pub mod url_guard;
+pub mod watermark;
+pub mod wms;
+pub mod wmts;
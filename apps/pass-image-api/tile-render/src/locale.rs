@@ -0,0 +1,115 @@
+// ! # locale
+// ! Tiles with labels in the reader's language, where a tileset's provider publishes
+// ! language-specific variants. Standard OSM tiles label places in their local language;
+// ! the German and French OSM communities run tile servers labelled in theirs.
+// !
+// ! A render asks for languages by running in scope with the languages it prefers, best
+// ! first, as faults::scope gives a request its own plan. Each tileset then uses the first
+// ! of those languages it has a variant for, and its default tiles otherwise. Localized
+// ! tiles are cached apart from the default ones, under <tileset>-<lang>/.
+
+use crate::tiles::TileSet;
+use std::future::Future;
+
+// Language-specific tile URLs, by tileset and ISO 639-1 language
+const VARIANTS: &[(TileSet, &str, &str)] = &[
+    (
+        TileSet::Osm,
+        "de",
+        "https://tile.openstreetmap.de/{z}/{x}/{y}.png",
+    ),
+    (
+        TileSet::Osm,
+        "fr",
+        "https://a.tile.openstreetmap.fr/osmfr/{z}/{x}/{y}.png",
+    ),
+];
+
+tokio::task_local! {
+    static LANGUAGES: Vec<String>;
+}
+
+// Runs a future preferring the given languages, best first. Only fetches made within the
+// future itself see them.
+pub async fn scope<F: Future>(languages: Vec<String>, f: F) -> F::Output {
+    LANGUAGES.scope(languages, f).await
+}
+
+// The languages the current scope prefers, best first, or none outside one
+pub fn languages() -> Vec<String> {
+    LANGUAGES.try_with(Vec::clone).unwrap_or_default()
+}
+
+// The language and URL pattern the tileset should be fetched with under the current
+// scope, if it has a variant in one of its languages
+pub fn variant(tileset: TileSet) -> Option<(&'static str, &'static str)> {
+    LANGUAGES
+        .try_with(|languages| {
+            languages.iter().find_map(|language| {
+                VARIANTS
+                    .iter()
+                    .find(|(t, lang, _)| *t == tileset && lang == language)
+                    .map(|&(_, lang, pattern)| (lang, pattern))
+            })
+        })
+        .ok()
+        .flatten()
+}
+
+// The languages in an Accept-Language header, best first, as primary subtags: "de-CH,
+// en;q=0.8" prefers de and then en. Wildcards and anything weighted 0 are left out.
+pub fn parse_accept_language(header: &str) -> Vec<String> {
+    let mut weighted: Vec<(String, f32)> = header
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';');
+            let tag = parts.next()?.trim();
+            let weight = parts
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse().ok())?;
+            let primary = tag.split('-').next()?.to_ascii_lowercase();
+            if primary.is_empty() || primary == "*" || weight <= 0.0 {
+                return None;
+            }
+            Some((primary, weight))
+        })
+        .collect();
+    // Stable, so languages of equal weight keep the order they were listed in
+    weighted.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let mut languages: Vec<String> = Vec::new();
+    for (language, _) in weighted {
+        if !languages.contains(&language) {
+            languages.push(language);
+        }
+    }
+    languages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_accept_language() {
+        assert_eq!(
+            parse_accept_language("fr-CH, fr;q=0.9, en;q=0.8, de;q=0.95, *;q=0.5"),
+            vec!["fr", "de", "en"]
+        );
+        assert_eq!(parse_accept_language("en;q=0, it"), vec!["it"]);
+        assert_eq!(parse_accept_language("de;q=high"), Vec::<String>::new());
+        assert!(parse_accept_language("").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_variant() {
+        assert_eq!(variant(TileSet::Osm), None);
+        let preferred = vec!["it".to_string(), "fr".to_string(), "de".to_string()];
+        let (osm, swisstopo) = scope(preferred, async {
+            (variant(TileSet::Osm), variant(TileSet::Swisstopo))
+        })
+        .await;
+        assert_eq!(osm.map(|(lang, _)| lang), Some("fr"));
+        assert_eq!(swisstopo, None);
+    }
+}
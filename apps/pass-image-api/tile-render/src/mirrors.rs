@@ -0,0 +1,142 @@
+// ! # mirrors
+// ! Failover between copies of a tileset's upstream, so one regional CDN outage at a
+// ! provider doesn't take the image API down with it. TILESET_<NAME>_MIRRORS lists URL
+// ! patterns serving the same tiles, comma separated, in order of preference:
+// !
+// !     TILESET_OSM_MIRRORS=https://eu.tiles.example.com/{z}/{x}/{y}.png,https://us.tiles.example.com/{z}/{x}/{y}.png
+// !
+// ! The tileset's own URL comes first. Each tile is fetched from the first upstream that's
+// ! up and, if that fails, from the next, so a failing upstream costs a retry rather than
+// ! the tile. Upstreams are health checked on the fetches themselves: after
+// ! FAILURE_THRESHOLD failures in a row one is down for COOLDOWN, and only tried once the
+// ! ones that are up have failed too. After the cooldown it's tried again, and a single
+// ! success brings it back up, while a single failure takes it down for another.
+// !
+// ! Mirrors take the same placeholders, token and scheme as the tileset's own URL.
+
+use anyhow::{anyhow, Result};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const FAILURE_THRESHOLD: u32 = 3;
+pub const COOLDOWN: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Health {
+    // Failures since the last success
+    failures: u32,
+    down_until: Option<Instant>,
+}
+
+// A tileset's mirrors, and the health of each of its upstreams. Upstreams are numbered
+// by preference, with the tileset's own URL as 0.
+#[derive(Debug)]
+pub struct Mirrors {
+    patterns: Vec<String>,
+    health: Mutex<Vec<Health>>,
+}
+
+impl Mirrors {
+    pub fn new(patterns: Vec<String>) -> Result<Mirrors> {
+        if patterns.is_empty() {
+            return Err(anyhow!("No mirrors are listed"));
+        }
+        Ok(Mirrors {
+            health: Mutex::new(vec![Health::default(); patterns.len() + 1]),
+            patterns,
+        })
+    }
+
+    // Reads a comma separated TILESET_<NAME>_MIRRORS list
+    pub fn parse(list: &str) -> Result<Mirrors> {
+        Mirrors::new(
+            list.split(',')
+                .map(str::trim)
+                .filter(|pattern| !pattern.is_empty())
+                .map(str::to_string)
+                .collect(),
+        )
+    }
+
+    pub fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+
+    // The mirror's URL pattern, or None for the tileset's own URL
+    pub fn pattern(&self, upstream: usize) -> Option<&str> {
+        upstream
+            .checked_sub(1)
+            .and_then(|i| self.patterns.get(i))
+            .map(String::as_str)
+    }
+
+    // The order to try the upstreams in: those that are up by preference, then those that
+    // are down, the soonest back first
+    pub fn order(&self, now: Instant) -> Vec<usize> {
+        let health = self.health.lock().unwrap();
+        let mut order: Vec<usize> = (0..health.len()).collect();
+        order.sort_by_key(|&upstream| health[upstream].down_until.filter(|until| *until > now));
+        order
+    }
+
+    // Records how a fetch from an upstream went
+    pub fn record(&self, upstream: usize, ok: bool, now: Instant) {
+        let mut health = self.health.lock().unwrap();
+        let health = &mut health[upstream];
+        if ok {
+            *health = Health::default();
+            return;
+        }
+        health.failures += 1;
+        if health.failures >= FAILURE_THRESHOLD {
+            health.down_until = Some(now + COOLDOWN);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_order() {
+        let mirrors =
+            Mirrors::parse("https://eu.test/{z}/{x}/{y}.png, https://us.test/{z}/{x}/{y}.png")
+                .unwrap();
+        assert_eq!(mirrors.pattern(0), None);
+        assert_eq!(mirrors.pattern(2), Some("https://us.test/{z}/{x}/{y}.png"));
+        let start = Instant::now();
+        assert_eq!(mirrors.order(start), vec![0, 1, 2]);
+
+        // An upstream goes down after a few failures in a row, not one
+        mirrors.record(0, false, start);
+        mirrors.record(0, false, start);
+        assert_eq!(mirrors.order(start), vec![0, 1, 2]);
+        mirrors.record(0, false, start);
+        assert_eq!(mirrors.order(start), vec![1, 2, 0]);
+        mirrors.record(1, false, start);
+        mirrors.record(1, true, start);
+        mirrors.record(1, false, start);
+        assert_eq!(mirrors.order(start), vec![1, 2, 0]);
+
+        // Once the cooldown is over it's tried first again, and one more failure takes it
+        // back down
+        let later = start + COOLDOWN;
+        assert_eq!(mirrors.order(later), vec![0, 1, 2]);
+        mirrors.record(0, false, later + Duration::from_secs(5));
+        for upstream in [1, 2] {
+            for _ in 0..FAILURE_THRESHOLD {
+                mirrors.record(
+                    upstream,
+                    false,
+                    later + Duration::from_secs(upstream as u64),
+                );
+            }
+        }
+        assert_eq!(mirrors.order(later), vec![1, 2, 0]);
+        mirrors.record(0, true, later);
+        assert_eq!(mirrors.order(later), vec![0, 1, 2]);
+
+        assert!(Mirrors::parse(" , ").is_err());
+    }
+}
@@ -0,0 +1,455 @@
+// ! # mvt
+// ! Tilesets whose providers only publish Mapbox Vector Tiles. A registry tileset with
+// ! content_type = "application/vnd.mapbox-vector-tile" is fetched as .pbf tiles, which
+// ! are decoded and drawn into 256px raster tiles with a simple built-in style before
+// ! they're cached and mosaicked like any other:
+// !
+// !     [[tileset]]
+// !     name = "vector"
+// !     url = "https://tiles.example.com/v3/{z}/{x}/{y}.pbf"
+// !     content_type = "application/vnd.mapbox-vector-tile"
+// !     attribution = "(c) Example Mapping"
+// !
+// ! The style knows the layers of the OpenMapTiles and Mapbox Streets schemas: land use
+// ! and land cover, water and waterways, buildings, and roads, wider and brighter the
+// ! more major their class. Everything else, points and labels included, is left out.
+// ! Gzipped tiles are unpacked first.
+
+use crate::overlay::{draw_segments, Segment};
+use crate::tiles::encode_tile;
+use anyhow::{anyhow, Context as _, Result};
+use bytes::Bytes;
+use flate2::read::GzDecoder;
+use image::{Rgba, RgbaImage};
+use std::io::Read;
+
+pub const CONTENT_TYPE: &str = "application/vnd.mapbox-vector-tile";
+
+// What servers send vector tiles as besides CONTENT_TYPE
+const OTHER_CONTENT_TYPES: [&str; 2] = ["application/x-protobuf", "application/octet-stream"];
+
+pub const TILE_PX: u32 = 256;
+const DEFAULT_EXTENT: u32 = 4096;
+
+// The most a gzipped tile unpacks to
+const MAX_TILE_BYTES: u64 = 16 * 1024 * 1024;
+
+const BACKGROUND: Rgba<u8> = Rgba([242, 239, 233, 255]);
+const LAND_USE: Rgba<u8> = Rgba([228, 224, 216, 255]);
+const GREEN: Rgba<u8> = Rgba([205, 228, 190, 255]);
+const WATER: Rgba<u8> = Rgba([170, 211, 223, 255]);
+const BUILDING: Rgba<u8> = Rgba([217, 208, 201, 255]);
+const MAJOR_ROAD: Rgba<u8> = Rgba([247, 200, 120, 255]);
+const ROAD: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+// Whether a response's content type is one vector tiles come as
+pub fn is_vector(content_type: &str) -> bool {
+    content_type == CONTENT_TYPE || OTHER_CONTENT_TYPES.contains(&content_type)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Point,
+    Line,
+    Polygon,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Feature {
+    kind: Kind,
+    class: Option<String>,
+    // Lines, or polygon rings, in tile pixels
+    parts: Vec<Vec<(f64, f64)>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Layer {
+    name: String,
+    features: Vec<Feature>,
+}
+
+// A protobuf message's fields, read one at a time
+struct Fields<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    // Fixed width numbers, which nothing drawn needs
+    Fixed,
+}
+
+impl<'a> Fields<'a> {
+    fn new(bytes: &'a [u8]) -> Fields<'a> {
+        Fields { bytes, pos: 0 }
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self
+                .bytes
+                .get(self.pos)
+                .ok_or_else(|| anyhow!("Vector tile ends early"))?;
+            self.pos += 1;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(anyhow!("Vector tile has an invalid varint"))
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| anyhow!("Vector tile ends early"))?;
+        let taken = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(taken)
+    }
+
+    // The next field's number and value, or None at the end of the message
+    fn next(&mut self) -> Result<Option<(u64, Field<'a>)>> {
+        if self.pos == self.bytes.len() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let field = match key & 7 {
+            0 => Field::Varint(self.varint()?),
+            1 => self.take(8).map(|_| Field::Fixed)?,
+            2 => {
+                let len = self.varint()? as usize;
+                Field::Bytes(self.take(len)?)
+            }
+            5 => self.take(4).map(|_| Field::Fixed)?,
+            wire => return Err(anyhow!("Vector tile has unknown wire type {}", wire)),
+        };
+        Ok(Some((key >> 3, field)))
+    }
+}
+
+fn packed(bytes: &[u8]) -> Result<Vec<u32>> {
+    let mut fields = Fields::new(bytes);
+    let mut values = Vec::new();
+    while fields.pos < bytes.len() {
+        values.push(fields.varint()? as u32);
+    }
+    Ok(values)
+}
+
+fn zigzag(value: u32) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+// Decodes a feature's geometry commands into its parts, scaled from the layer's extent
+// to tile pixels. Polygon rings are closed.
+fn geometry(commands: &[u32], scale: f64) -> Result<Vec<Vec<(f64, f64)>>> {
+    let mut parts: Vec<Vec<(f64, f64)>> = Vec::new();
+    let (mut x, mut y) = (0i64, 0i64);
+    let mut commands = commands.iter().copied();
+    while let Some(command) = commands.next() {
+        let (id, count) = (command & 7, command >> 3);
+        match id {
+            // MoveTo and LineTo
+            1 | 2 => {
+                for i in 0..count {
+                    let (dx, dy) = commands
+                        .next()
+                        .zip(commands.next())
+                        .ok_or_else(|| anyhow!("Vector tile geometry ends early"))?;
+                    x += zigzag(dx);
+                    y += zigzag(dy);
+                    let point = (x as f64 * scale, y as f64 * scale);
+                    match parts.last_mut() {
+                        Some(part) if id == 2 || i > 0 => part.push(point),
+                        _ => parts.push(vec![point]),
+                    }
+                }
+            }
+            // ClosePath
+            7 => {
+                if let Some(part) = parts.last_mut() {
+                    part.push(part[0]);
+                }
+            }
+            _ => return Err(anyhow!("Vector tile has unknown command {}", id)),
+        }
+    }
+    Ok(parts)
+}
+
+fn decode_feature(
+    bytes: &[u8],
+    keys: &[String],
+    values: &[Option<String>],
+    scale: f64,
+) -> Result<Feature> {
+    let (mut kind, mut tags, mut commands) = (Kind::Point, Vec::new(), Vec::new());
+    let mut fields = Fields::new(bytes);
+    while let Some((number, field)) = fields.next()? {
+        match (number, field) {
+            (2, Field::Bytes(bytes)) => tags = packed(bytes)?,
+            (3, Field::Varint(2)) => kind = Kind::Line,
+            (3, Field::Varint(3)) => kind = Kind::Polygon,
+            (4, Field::Bytes(bytes)) => commands = packed(bytes)?,
+            _ => {}
+        }
+    }
+    let class = tags.chunks_exact(2).find_map(|tag| {
+        let key = keys.get(tag[0] as usize)?;
+        (key == "class").then(|| values.get(tag[1] as usize)?.clone())?
+    });
+    Ok(Feature {
+        kind,
+        class,
+        parts: geometry(&commands, scale)?,
+    })
+}
+
+fn decode_layer(bytes: &[u8]) -> Result<Layer> {
+    let (mut name, mut extent) = (String::new(), DEFAULT_EXTENT);
+    let (mut keys, mut values, mut features) = (Vec::new(), Vec::new(), Vec::new());
+    let mut fields = Fields::new(bytes);
+    while let Some((number, field)) = fields.next()? {
+        match (number, field) {
+            (1, Field::Bytes(bytes)) => name = String::from_utf8_lossy(bytes).into_owned(),
+            (2, Field::Bytes(bytes)) => features.push(bytes),
+            (3, Field::Bytes(bytes)) => keys.push(String::from_utf8_lossy(bytes).into_owned()),
+            (4, Field::Bytes(bytes)) => {
+                // Only string values are styled on
+                let mut value = Fields::new(bytes);
+                let mut string = None;
+                while let Some((number, field)) = value.next()? {
+                    if let (1, Field::Bytes(bytes)) = (number, field) {
+                        string = Some(String::from_utf8_lossy(bytes).into_owned());
+                    }
+                }
+                values.push(string);
+            }
+            (5, Field::Varint(value)) => extent = value as u32,
+            _ => {}
+        }
+    }
+    if extent == 0 {
+        return Err(anyhow!("Vector tile layer {} has no extent", name));
+    }
+    let scale = TILE_PX as f64 / extent as f64;
+    let features = features
+        .into_iter()
+        .map(|bytes| decode_feature(bytes, &keys, &values, scale))
+        .collect::<Result<Vec<_>>>()
+        .with_context(|| format!("decoding layer {}", name))?;
+    Ok(Layer { name, features })
+}
+
+fn decode(bytes: &[u8]) -> Result<Vec<Layer>> {
+    let mut layers = Vec::new();
+    let mut fields = Fields::new(bytes);
+    while let Some((number, field)) = fields.next()? {
+        if let (3, Field::Bytes(bytes)) = (number, field) {
+            layers.push(decode_layer(bytes)?);
+        }
+    }
+    Ok(layers)
+}
+
+enum Paint {
+    Fill(Rgba<u8>),
+    Line(f32, Rgba<u8>),
+}
+
+// How the built-in style draws a feature, if it does, and the order it's drawn in
+fn style(layer: &str, feature: &Feature) -> Option<(u32, Paint)> {
+    let class = feature.class.as_deref().unwrap_or_default();
+    match (layer, feature.kind) {
+        ("landuse" | "landcover" | "park", Kind::Polygon) => match class {
+            "wood" | "forest" | "grass" | "park" | "meadow" | "scrub" | "farmland" => {
+                Some((1, Paint::Fill(GREEN)))
+            }
+            _ if layer == "park" => Some((1, Paint::Fill(GREEN))),
+            _ => Some((0, Paint::Fill(LAND_USE))),
+        },
+        ("water", Kind::Polygon) => Some((2, Paint::Fill(WATER))),
+        ("waterway", Kind::Line) => Some((3, Paint::Line(1.5, WATER))),
+        ("building", Kind::Polygon) => Some((4, Paint::Fill(BUILDING))),
+        ("transportation" | "road", Kind::Line) => match class {
+            "motorway" | "trunk" | "primary" => Some((7, Paint::Line(3.0, MAJOR_ROAD))),
+            "secondary" | "tertiary" => Some((6, Paint::Line(2.0, ROAD))),
+            "rail" | "transit" | "ferry" | "aerialway" => None,
+            _ => Some((5, Paint::Line(1.0, ROAD))),
+        },
+        _ => None,
+    }
+}
+
+// Fills polygon rings, even-odd, so holes are left out
+fn fill_polygon(img: &mut RgbaImage, rings: &[Vec<(f64, f64)>], color: Rgba<u8>) {
+    for y in 0..img.height() {
+        let py = y as f64 + 0.5;
+        let mut crossings: Vec<f64> = rings
+            .iter()
+            .flat_map(|ring| ring.windows(2))
+            .filter(|edge| (edge[0].1 <= py) != (edge[1].1 <= py))
+            .map(|edge| {
+                let (a, b) = (edge[0], edge[1]);
+                a.0 + (py - a.1) / (b.1 - a.1) * (b.0 - a.0)
+            })
+            .collect();
+        crossings.sort_by(f64::total_cmp);
+        for pair in crossings.chunks_exact(2) {
+            let start = (pair[0] - 0.5).ceil().max(0.0);
+            let end = (pair[1] - 0.5).floor().min(img.width() as f64 - 1.0);
+            if start <= end {
+                for x in start as u32..=end as u32 {
+                    img.put_pixel(x, y, color);
+                }
+            }
+        }
+    }
+}
+
+// Draws a vector tile into a 256px PNG with the built-in style
+pub fn rasterize(tile: &[u8]) -> Result<Bytes> {
+    let mut unpacked = Vec::new();
+    let tile = if tile.starts_with(&[0x1f, 0x8b]) {
+        GzDecoder::new(tile)
+            .take(MAX_TILE_BYTES)
+            .read_to_end(&mut unpacked)
+            .context("unpacking vector tile")?;
+        &unpacked[..]
+    } else {
+        tile
+    };
+    let layers = decode(tile)?;
+    let mut painted: Vec<(u32, Paint, &Feature)> = layers
+        .iter()
+        .flat_map(|layer| {
+            layer.features.iter().filter_map(|feature| {
+                style(&layer.name, feature).map(|(order, paint)| (order, paint, feature))
+            })
+        })
+        .collect();
+    painted.sort_by_key(|(order, _, _)| *order);
+
+    let mut img = RgbaImage::from_pixel(TILE_PX, TILE_PX, BACKGROUND);
+    for (_, paint, feature) in painted {
+        match paint {
+            Paint::Fill(color) => fill_polygon(&mut img, &feature.parts, color),
+            Paint::Line(width, color) => {
+                let segments: Vec<Segment> = feature
+                    .parts
+                    .iter()
+                    .flat_map(|line| line.windows(2).map(|pair| (pair[0], pair[1])))
+                    .collect();
+                draw_segments(&mut img, &segments, width, color);
+            }
+        }
+    }
+    Ok(encode_tile(img))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(mut value: u64, out: &mut Vec<u8>) {
+        while value >= 0x80 {
+            out.push((value as u8 & 0x7f) | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    fn bytes_field(number: u64, bytes: &[u8], out: &mut Vec<u8>) {
+        varint(number << 3 | 2, out);
+        varint(bytes.len() as u64, out);
+        out.extend_from_slice(bytes);
+    }
+
+    fn packed_field(number: u64, values: &[u32], out: &mut Vec<u8>) {
+        let mut bytes = Vec::new();
+        for &value in values {
+            varint(value as u64, &mut bytes);
+        }
+        bytes_field(number, &bytes, out);
+    }
+
+    fn feature(kind: u64, tags: &[u32], geometry: &[u32]) -> Vec<u8> {
+        let mut out = Vec::new();
+        packed_field(2, tags, &mut out);
+        varint(3 << 3, &mut out);
+        varint(kind, &mut out);
+        packed_field(4, geometry, &mut out);
+        out
+    }
+
+    fn layer(name: &str, features: &[Vec<u8>], keys: &[&str], values: &[&str]) -> Vec<u8> {
+        let mut out = Vec::new();
+        bytes_field(1, name.as_bytes(), &mut out);
+        for feature in features {
+            bytes_field(2, feature, &mut out);
+        }
+        for key in keys {
+            bytes_field(3, key.as_bytes(), &mut out);
+        }
+        for value in values {
+            let mut string = Vec::new();
+            bytes_field(1, value.as_bytes(), &mut string);
+            bytes_field(4, &string, &mut out);
+        }
+        // Field 5, the extent
+        out.extend([5 << 3, 0x80, 0x20]);
+        out
+    }
+
+    fn zz(value: i32) -> u32 {
+        ((value << 1) ^ (value >> 31)) as u32
+    }
+
+    #[test]
+    fn test_rasterize() {
+        // Water over the left half of the tile, and a primary road across the middle
+        let water = feature(
+            3,
+            &[],
+            &[9, 0, 0, 26, zz(2048), 0, 0, zz(4096), zz(-2048), 0, 15],
+        );
+        let road = feature(2, &[0, 0], &[9, 0, zz(2048), 10, zz(4096), 0]);
+        let mut tile = Vec::new();
+        bytes_field(3, &layer("water", &[water], &[], &[]), &mut tile);
+        bytes_field(
+            3,
+            &layer("transportation", &[road], &["class"], &["primary"]),
+            &mut tile,
+        );
+
+        let layers = decode(&tile).unwrap();
+        assert_eq!(layers.len(), 2);
+        assert_eq!(layers[1].features[0].class.as_deref(), Some("primary"));
+        assert_eq!(
+            layers[0].features[0].parts,
+            vec![vec![
+                (0.0, 0.0),
+                (128.0, 0.0),
+                (128.0, 256.0),
+                (0.0, 256.0),
+                (0.0, 0.0)
+            ]]
+        );
+
+        let png = rasterize(&tile).unwrap();
+        let img = image::load_from_memory(&png).unwrap().to_rgba8();
+        assert_eq!(img.dimensions(), (256, 256));
+        assert_eq!(*img.get_pixel(20, 20), WATER);
+        assert_eq!(*img.get_pixel(200, 20), BACKGROUND);
+        assert_eq!(*img.get_pixel(200, 128), MAJOR_ROAD);
+        assert_eq!(*img.get_pixel(20, 128), MAJOR_ROAD);
+
+        assert!(rasterize(&[0x1a, 0x05, 0x0a]).is_err());
+    }
+}
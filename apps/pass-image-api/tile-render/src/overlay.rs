@@ -0,0 +1,830 @@
+// ! # overlay
+// ! Vector overlays drawn over the rendered basemap: tracks, areas and markers. Overlays
+// ! are given to us as GeoJSON and drawn in the same web mercator projection as the tiles
+// ! underneath, using a Viewport that maps lat/long to pixels in the output image.
+// ! Features with a "renderer" property are drawn by a custom OverlayRenderer instead, see
+// ! the plugin module.
+
+use crate::coordinates::LatLong;
+use crate::labels::{self, Label};
+use crate::layers::LayerKind;
+use crate::plugin;
+use crate::route::{self, LineStyle};
+use crate::text::{self, blend_pixel, draw_text, text_height, text_width};
+use anyhow::{anyhow, Result};
+use image::{Rgba, RgbaImage};
+use serde_json::Value;
+use std::collections::HashSet;
+
+const DEFAULT_LINE_COLOR: Rgba<u8> = Rgba([220, 40, 40, 255]);
+pub const DEFAULT_MARKER_COLOR: Rgba<u8> = Rgba([40, 80, 220, 255]);
+const DEFAULT_LINE_WIDTH: f32 = 3.0;
+pub const DEFAULT_MARKER_RADIUS: f32 = 6.0;
+// Longer marker titles are cut short
+const MAX_LABEL_CHARS: usize = 48;
+// Marker symbols are a number or a letter or two, drawn inside the marker
+const MAX_SYMBOL_CHARS: usize = 3;
+
+const CLUSTER_TEXT_COLOR: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+// The largest supersampling factor we'll render overlays at
+pub const MAX_SUPERSAMPLE: u32 = 4;
+
+// Caps the size of the supersampled canvas; bigger images get a smaller factor
+const MAX_SUPERSAMPLE_PIXELS: u64 = 16 * 1024 * 1024;
+
+// Where the output image sits in the world, shared with the front end through
+// tile-geometry
+pub use tile_geometry::viewport::Viewport;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Overlay {
+    // A track or area outline
+    Line {
+        points: Vec<LatLong>,
+        color: Rgba<u8>,
+        width: f32,
+        style: LineStyle,
+        // Whether this is a polygon's ring rather than a track
+        area: bool,
+    },
+    // A single marker, optionally labelled, and optionally with a symbol such as a number
+    // inside it
+    Point {
+        point: LatLong,
+        color: Rgba<u8>,
+        radius: f32,
+        label: Option<String>,
+        symbol: Option<String>,
+    },
+    // A badge standing in for a cluster of markers, showing how many there are
+    Cluster {
+        point: LatLong,
+        count: u32,
+        color: Rgba<u8>,
+        radius: f32,
+    },
+    // A feature drawn by the OverlayRenderer registered under the renderer name, with
+    // pixel sizes multiplied by scale
+    Custom {
+        renderer: String,
+        layer: LayerKind,
+        points: Vec<LatLong>,
+        properties: Value,
+        scale: f32,
+    },
+}
+
+impl Overlay {
+    // The layer the overlay is drawn on
+    pub fn layer(&self) -> LayerKind {
+        match self {
+            Overlay::Line { area: true, .. } => LayerKind::Polygons,
+            Overlay::Line { .. } => LayerKind::Lines,
+            Overlay::Point { .. } | Overlay::Cluster { .. } => LayerKind::Markers,
+            Overlay::Custom { layer, .. } => *layer,
+        }
+    }
+
+    pub fn has_label(&self) -> bool {
+        matches!(self, Overlay::Point { label: Some(_), .. })
+    }
+
+    // The same overlay with its pixel sizes multiplied by factor. Widths in meters scale
+    // with the viewport already.
+    fn scaled(&self, factor: f32) -> Overlay {
+        match self {
+            Overlay::Line {
+                points,
+                color,
+                width,
+                style,
+                area,
+            } => Overlay::Line {
+                points: points.clone(),
+                color: *color,
+                width: if style.width_in_meters {
+                    *width
+                } else {
+                    width * factor
+                },
+                style: style.scaled(factor),
+                area: *area,
+            },
+            Overlay::Point {
+                point,
+                color,
+                radius,
+                label,
+                symbol,
+            } => Overlay::Point {
+                point: *point,
+                color: *color,
+                radius: radius * factor,
+                label: label.clone(),
+                symbol: symbol.clone(),
+            },
+            Overlay::Cluster {
+                point,
+                count,
+                color,
+                radius,
+            } => Overlay::Cluster {
+                point: *point,
+                count: *count,
+                color: *color,
+                radius: radius * factor,
+            },
+            Overlay::Custom {
+                renderer,
+                layer,
+                points,
+                properties,
+                scale,
+            } => Overlay::Custom {
+                renderer: renderer.clone(),
+                layer: *layer,
+                points: points.clone(),
+                properties: properties.clone(),
+                scale: scale * factor,
+            },
+        }
+    }
+}
+
+// Draws overlays onto the image with anti-aliased edges. They're rendered onto a
+// transparent canvas `factor` times larger in each direction, which is then averaged
+// back down and blended over the image. A factor of 1 draws directly.
+pub fn draw_overlays_supersampled(
+    img: &mut RgbaImage,
+    viewport: &Viewport,
+    overlays: &[Overlay],
+    factor: u32,
+) {
+    supersampled(img, viewport, overlays, factor, draw_overlays);
+}
+
+// Draws the overlays' marker labels, supersampled like the markers themselves
+pub fn draw_labels_supersampled(
+    img: &mut RgbaImage,
+    viewport: &Viewport,
+    overlays: &[Overlay],
+    factor: u32,
+) {
+    supersampled(img, viewport, overlays, factor, draw_labels);
+}
+
+fn supersampled(
+    img: &mut RgbaImage,
+    viewport: &Viewport,
+    overlays: &[Overlay],
+    factor: u32,
+    draw: fn(&mut RgbaImage, &Viewport, &[Overlay]),
+) {
+    let mut factor = factor.min(MAX_SUPERSAMPLE);
+    let pixels = img.width() as u64 * img.height() as u64;
+    while factor > 1 && pixels * (factor as u64).pow(2) > MAX_SUPERSAMPLE_PIXELS {
+        factor -= 1;
+    }
+    if overlays.is_empty() || factor <= 1 {
+        draw(img, viewport, overlays);
+        return;
+    }
+
+    let mut canvas = RgbaImage::new(img.width() * factor, img.height() * factor);
+    let scaled: Vec<Overlay> = overlays.iter().map(|o| o.scaled(factor as f32)).collect();
+    draw(&mut canvas, &viewport.scaled(factor as f64), &scaled);
+
+    let layer = downsample(&canvas, factor);
+    for (x, y, pixel) in layer.enumerate_pixels() {
+        if pixel[3] > 0 {
+            text::blend(img.get_pixel_mut(x, y), pixel);
+        }
+    }
+}
+
+// Averages each factor x factor block into one pixel. Colors are weighted by alpha so
+// that transparent pixels around the edges don't darken the result.
+fn downsample(canvas: &RgbaImage, factor: u32) -> RgbaImage {
+    let samples = (factor * factor) as u64;
+    RgbaImage::from_fn(canvas.width() / factor, canvas.height() / factor, |x, y| {
+        let mut sums = [0u64; 4];
+        for sy in 0..factor {
+            for sx in 0..factor {
+                let [r, g, b, a] = canvas.get_pixel(x * factor + sx, y * factor + sy).0;
+                let a = a as u64;
+                sums[0] += r as u64 * a;
+                sums[1] += g as u64 * a;
+                sums[2] += b as u64 * a;
+                sums[3] += a;
+            }
+        }
+        if sums[3] == 0 {
+            return Rgba([0, 0, 0, 0]);
+        }
+        Rgba([
+            (sums[0] / sums[3]) as u8,
+            (sums[1] / sums[3]) as u8,
+            (sums[2] / sums[3]) as u8,
+            ((sums[3] + samples / 2) / samples) as u8,
+        ])
+    })
+}
+
+// Draws overlays onto the image in the order given. Marker labels are drawn separately
+// by draw_labels, as they go on a layer of their own.
+pub fn draw_overlays(img: &mut RgbaImage, viewport: &Viewport, overlays: &[Overlay]) {
+    for overlay in overlays {
+        match overlay {
+            Overlay::Line {
+                points,
+                color,
+                width,
+                style,
+                ..
+            } => {
+                let pixels: Vec<(f64, f64)> = points.iter().map(|p| viewport.project(p)).collect();
+                let width = match points.first() {
+                    Some(first) if style.width_in_meters => {
+                        route::width_in_pixels(*width, first.0, viewport)
+                    }
+                    _ => *width,
+                };
+                route::draw_route(img, &pixels, width, *color, style);
+            }
+            Overlay::Point {
+                point,
+                color,
+                radius,
+                symbol: Some(symbol),
+                ..
+            } => {
+                let (x, y) = viewport.project(point);
+                draw_badge(img, x, y, symbol, *radius, *color);
+            }
+            Overlay::Point {
+                point,
+                color,
+                radius,
+                ..
+            } => {
+                let (x, y) = viewport.project(point);
+                fill_circle(img, x, y, *radius, *color);
+            }
+            Overlay::Cluster {
+                point,
+                count,
+                color,
+                radius,
+            } => {
+                let (x, y) = viewport.project(point);
+                draw_cluster(img, x, y, *count, *radius, *color);
+            }
+            Overlay::Custom {
+                renderer,
+                points,
+                properties,
+                scale,
+                ..
+            } => {
+                if let Some(renderer) = plugin::lookup(renderer) {
+                    renderer.draw(img, viewport, points, properties, *scale);
+                }
+            }
+        }
+    }
+}
+
+// Draws the labels of the markers among the overlays, keeping them clear of each other
+pub fn draw_labels(img: &mut RgbaImage, viewport: &Viewport, overlays: &[Overlay]) {
+    let labels: Vec<Label> = overlays
+        .iter()
+        .filter_map(|overlay| match overlay {
+            Overlay::Point {
+                point,
+                radius,
+                label: Some(text),
+                ..
+            } => Some(Label {
+                text: text.clone(),
+                anchor: viewport.project(point),
+                clearance: *radius as f64,
+                // Labels grow with their markers, e.g. when supersampling
+                scale: ((radius / DEFAULT_MARKER_RADIUS).round() as u32).max(1),
+            }),
+            _ => None,
+        })
+        .collect();
+    labels::draw_labels(img, &labels);
+}
+
+// Draws a cluster badge, showing how many markers it stands for
+fn draw_cluster(img: &mut RgbaImage, x: f64, y: f64, count: u32, radius: f32, color: Rgba<u8>) {
+    let label = if count > 999 {
+        "999+".to_string()
+    } else {
+        count.to_string()
+    };
+    draw_badge(img, x, y, &label, radius, color);
+}
+
+// Draws a disc with a white ring and the text in the middle, for cluster badges and
+// markers with a symbol
+fn draw_badge(img: &mut RgbaImage, x: f64, y: f64, text: &str, radius: f32, color: Rgba<u8>) {
+    fill_circle(img, x, y, radius, CLUSTER_TEXT_COLOR);
+    fill_circle(img, x, y, radius * 0.85, color);
+
+    let scale = ((radius / 10.0) as u32).max(1);
+    let left = x as i64 - text_width(text, scale) as i64 / 2;
+    let top = y as i64 - text_height(scale) as i64 / 2;
+    draw_text(img, left, top, text, scale, CLUSTER_TEXT_COLOR);
+}
+
+// A line segment between two points in pixel coordinates
+pub type Segment = ((f64, f64), (f64, f64));
+
+// Draws a set of thick line segments in pixel coordinates. Pixels are collected first
+// and blended once each, so that translucent lines don't get darker where segments meet.
+pub fn draw_segments(img: &mut RgbaImage, segments: &[Segment], width: f32, color: Rgba<u8>) {
+    let mut covered = HashSet::new();
+    for &(from, to) in segments {
+        covered.extend(segment_pixels(img, from, to, width));
+    }
+
+    for (px, py) in covered {
+        blend_pixel(img, px, py, color);
+    }
+}
+
+// The pixels within the image covered by a thick line segment
+pub fn segment_pixels(
+    img: &RgbaImage,
+    from: (f64, f64),
+    to: (f64, f64),
+    width: f32,
+) -> Vec<(i64, i64)> {
+    let half_width = (width / 2.0).max(0.5) as f64;
+    let (min_x, max_x) = clamp_span(from.0.min(to.0), from.0.max(to.0), half_width, img.width());
+    let (min_y, max_y) = clamp_span(from.1.min(to.1), from.1.max(to.1), half_width, img.height());
+
+    let mut pixels = Vec::new();
+    for py in min_y..=max_y {
+        for px in min_x..=max_x {
+            let center = (px as f64 + 0.5, py as f64 + 0.5);
+            if distance_to_segment(center, from, to) <= half_width {
+                pixels.push((px, py));
+            }
+        }
+    }
+    pixels
+}
+
+// Fills every pixel whose center lies within the circle
+pub fn fill_circle(img: &mut RgbaImage, cx: f64, cy: f64, radius: f32, color: Rgba<u8>) {
+    let r = radius.max(0.5) as f64;
+    let (min_x, max_x) = clamp_span(cx, cx, r, img.width());
+    let (min_y, max_y) = clamp_span(cy, cy, r, img.height());
+    for py in min_y..=max_y {
+        for px in min_x..=max_x {
+            let dx = px as f64 + 0.5 - cx;
+            let dy = py as f64 + 0.5 - cy;
+            if dx * dx + dy * dy <= r * r {
+                blend_pixel(img, px, py, color);
+            }
+        }
+    }
+}
+
+// The range of pixel indices within [min - margin, max + margin], clamped to the image.
+// An empty range comes back as (0, -1).
+pub fn clamp_span(min: f64, max: f64, margin: f64, size: u32) -> (i64, i64) {
+    let lo = ((min - margin).floor() as i64).max(0);
+    let hi = ((max + margin).ceil() as i64).min(size as i64 - 1);
+    if lo > hi {
+        (0, -1)
+    } else {
+        (lo, hi)
+    }
+}
+
+// Shortest distance from p to the segment a-b
+fn distance_to_segment(p: (f64, f64), a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let length_sq = dx * dx + dy * dy;
+    let t = if length_sq == 0.0 {
+        0.0
+    } else {
+        (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / length_sq).clamp(0.0, 1.0)
+    };
+    let (nx, ny) = (a.0 + t * dx, a.1 + t * dy);
+    ((p.0 - nx).powi(2) + (p.1 - ny).powi(2)).sqrt()
+}
+
+// Parses a "#rrggbb" or "#rrggbbaa" color
+pub fn parse_color(s: &str) -> Option<Rgba<u8>> {
+    let hex = s.strip_prefix('#')?;
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    match hex.len() {
+        6 => Some(Rgba([channel(0)?, channel(2)?, channel(4)?, 255])),
+        8 => Some(Rgba([channel(0)?, channel(2)?, channel(4)?, channel(6)?])),
+        _ => None,
+    }
+}
+
+// Builds overlays from a GeoJSON FeatureCollection, Feature or bare Geometry. Styling is
+// taken from simplestyle-spec properties (stroke, stroke-width, marker-color,
+// marker-symbol) where present.
+pub fn from_geojson(geojson: &Value) -> Result<Vec<Overlay>> {
+    let mut overlays = Vec::new();
+    add_geojson(geojson, &Value::Null, &mut overlays)?;
+    Ok(overlays)
+}
+
+fn add_geojson(value: &Value, properties: &Value, overlays: &mut Vec<Overlay>) -> Result<()> {
+    let kind = value
+        .get("type")
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("GeoJSON object without a type"))?;
+
+    match kind {
+        "FeatureCollection" => {
+            for feature in array(value, "features")? {
+                add_geojson(feature, &Value::Null, overlays)?;
+            }
+        }
+        "Feature" => {
+            let properties = value.get("properties").unwrap_or(&Value::Null);
+            let geometry = value.get("geometry").filter(|g| !g.is_null());
+            match (geometry, properties.get("renderer")) {
+                (Some(geometry), Some(renderer)) => {
+                    overlays.push(custom(geometry, renderer, properties)?)
+                }
+                (Some(geometry), None) => add_geojson(geometry, properties, overlays)?,
+                (None, _) => {}
+            }
+        }
+        "GeometryCollection" => {
+            for geometry in array(value, "geometries")? {
+                add_geojson(geometry, properties, overlays)?;
+            }
+        }
+        "Point" => overlays.push(point(position(coordinates(value)?)?, properties)),
+        "MultiPoint" => {
+            for p in positions(coordinates(value)?)? {
+                overlays.push(point(p, properties));
+            }
+        }
+        "LineString" => overlays.push(line(coordinates(value)?, properties, false)?),
+        "MultiLineString" => {
+            for part in as_array(coordinates(value)?)? {
+                overlays.push(line(part, properties, false)?);
+            }
+        }
+        "Polygon" => {
+            for ring in as_array(coordinates(value)?)? {
+                overlays.push(line(ring, properties, true)?);
+            }
+        }
+        "MultiPolygon" => {
+            for polygon in as_array(coordinates(value)?)? {
+                for ring in as_array(polygon)? {
+                    overlays.push(line(ring, properties, true)?);
+                }
+            }
+        }
+        other => return Err(anyhow!("Unsupported GeoJSON type {}", other)),
+    }
+    Ok(())
+}
+
+fn point(point: LatLong, properties: &Value) -> Overlay {
+    Overlay::Point {
+        point,
+        color: style_color(properties, "marker-color").unwrap_or(DEFAULT_MARKER_COLOR),
+        radius: style_number(properties, "marker-radius").unwrap_or(DEFAULT_MARKER_RADIUS),
+        label: properties
+            .get("title")
+            .and_then(Value::as_str)
+            .map(|title| title.chars().take(MAX_LABEL_CHARS).collect()),
+        symbol: marker_symbol(properties),
+    }
+}
+
+// A feature for a custom renderer, which is given every position in its geometry
+fn custom(geometry: &Value, renderer: &Value, properties: &Value) -> Result<Overlay> {
+    let name = renderer
+        .as_str()
+        .ok_or_else(|| anyhow!("renderer must be a string"))?;
+    let layer = plugin::lookup(name)
+        .ok_or_else(|| anyhow!("Unknown overlay renderer {}", name))?
+        .layer();
+
+    let mut parts = Vec::new();
+    add_geojson(geometry, &Value::Null, &mut parts)?;
+    let points = parts
+        .into_iter()
+        .flat_map(|part| match part {
+            Overlay::Line { points, .. } => points,
+            Overlay::Point { point, .. } => vec![point],
+            _ => Vec::new(),
+        })
+        .collect();
+    Ok(Overlay::Custom {
+        renderer: name.to_string(),
+        layer,
+        points,
+        properties: properties.clone(),
+        scale: 1.0,
+    })
+}
+
+// simplestyle allows icon names as symbols too, but we've only got text to draw them with
+fn marker_symbol(properties: &Value) -> Option<String> {
+    let symbol = match properties.get("marker-symbol")? {
+        Value::String(s) => s.clone(),
+        Value::Number(n) => n.to_string(),
+        _ => return None,
+    };
+    (!symbol.is_empty() && symbol.chars().count() <= MAX_SYMBOL_CHARS).then_some(symbol)
+}
+
+// A line through the given GeoJSON positions, or a polygon's ring if area is set
+fn line(positions_value: &Value, properties: &Value, area: bool) -> Result<Overlay> {
+    Ok(Overlay::Line {
+        points: positions(positions_value)?,
+        color: style_color(properties, "stroke").unwrap_or(DEFAULT_LINE_COLOR),
+        width: style_number(properties, "stroke-width").unwrap_or(DEFAULT_LINE_WIDTH),
+        style: LineStyle::from_properties(properties, elevations(positions_value))?,
+        area,
+    })
+}
+
+fn style_color(properties: &Value, key: &str) -> Option<Rgba<u8>> {
+    properties
+        .get(key)
+        .and_then(Value::as_str)
+        .and_then(parse_color)
+}
+
+fn style_number(properties: &Value, key: &str) -> Option<f32> {
+    properties
+        .get(key)
+        .and_then(Value::as_f64)
+        .map(|n| n as f32)
+}
+
+fn coordinates(value: &Value) -> Result<&Value> {
+    value
+        .get("coordinates")
+        .ok_or_else(|| anyhow!("Geometry without coordinates"))
+}
+
+fn array<'a>(value: &'a Value, key: &str) -> Result<&'a Vec<Value>> {
+    value
+        .get(key)
+        .ok_or_else(|| anyhow!("Missing {}", key))
+        .and_then(as_array)
+}
+
+fn as_array(value: &Value) -> Result<&Vec<Value>> {
+    value
+        .as_array()
+        .ok_or_else(|| anyhow!("Expected an array in GeoJSON coordinates"))
+}
+
+// GeoJSON positions are [longitude, latitude]
+fn position(value: &Value) -> Result<LatLong> {
+    let coords = as_array(value)?;
+    match (
+        coords.first().and_then(Value::as_f64),
+        coords.get(1).and_then(Value::as_f64),
+    ) {
+        (Some(long), Some(lat)) => Ok(LatLong(lat, long)),
+        _ => Err(anyhow!("Invalid GeoJSON position")),
+    }
+}
+
+fn positions(value: &Value) -> Result<Vec<LatLong>> {
+    as_array(value)?.iter().map(position).collect()
+}
+
+// The elevation, the optional third value, of every position, if they all have one
+fn elevations(value: &Value) -> Option<Vec<f32>> {
+    value
+        .as_array()?
+        .iter()
+        .map(|p| p.get(2).and_then(Value::as_f64).map(|e| e as f32))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_color() {
+        assert_eq!(parse_color("#ff0080"), Some(Rgba([255, 0, 128, 255])));
+        assert_eq!(parse_color("#ff008040"), Some(Rgba([255, 0, 128, 64])));
+        assert_eq!(parse_color("ff0080"), None);
+        assert_eq!(parse_color("#zz0080"), None);
+    }
+
+    #[test]
+    fn test_from_geojson() {
+        let geojson = json!({
+            "type": "FeatureCollection",
+            "features": [
+                {
+                    "type": "Feature",
+                    "properties": { "stroke": "#00ff00", "stroke-width": 5 },
+                    "geometry": {
+                        "type": "LineString",
+                        "coordinates": [[8.0, 46.0], [8.1, 46.1]]
+                    }
+                },
+                {
+                    "type": "Feature",
+                    "properties": {},
+                    "geometry": { "type": "Point", "coordinates": [8.05, 46.05] }
+                }
+            ]
+        });
+
+        let overlays = from_geojson(&geojson).unwrap();
+        assert_eq!(overlays.len(), 2);
+        assert_eq!(
+            overlays[0],
+            Overlay::Line {
+                points: vec![LatLong(46.0, 8.0), LatLong(46.1, 8.1)],
+                color: Rgba([0, 255, 0, 255]),
+                width: 5.0,
+                style: LineStyle::default(),
+                area: false,
+            }
+        );
+        assert!(matches!(overlays[1], Overlay::Point { .. }));
+    }
+
+    #[test]
+    fn test_overlay_layers() {
+        let geojson = json!({
+            "type": "GeometryCollection",
+            "geometries": [
+                { "type": "Polygon", "coordinates": [[[8.0, 46.0], [8.1, 46.0], [8.1, 46.1]]] },
+                { "type": "LineString", "coordinates": [[8.0, 46.0], [8.1, 46.1]] },
+                { "type": "Point", "coordinates": [8.05, 46.05] }
+            ]
+        });
+        let layers: Vec<LayerKind> = from_geojson(&geojson)
+            .unwrap()
+            .iter()
+            .map(Overlay::layer)
+            .collect();
+        assert_eq!(
+            layers,
+            vec![LayerKind::Polygons, LayerKind::Lines, LayerKind::Markers]
+        );
+    }
+
+    #[test]
+    fn test_marker_titles_become_labels() {
+        let geojson = json!({
+            "type": "Feature",
+            "properties": { "title": "Grosse Scheidegg" },
+            "geometry": { "type": "Point", "coordinates": [8.1, 46.65] }
+        });
+        let overlays = from_geojson(&geojson).unwrap();
+        assert!(matches!(
+            &overlays[0],
+            Overlay::Point { label: Some(label), .. } if label == "Grosse Scheidegg"
+        ));
+    }
+
+    #[test]
+    fn test_marker_symbols() {
+        let symbol = |value: Value| {
+            let geojson = json!({
+                "type": "Feature",
+                "properties": { "marker-symbol": value },
+                "geometry": { "type": "Point", "coordinates": [8.1, 46.65] }
+            });
+            match from_geojson(&geojson).unwrap().remove(0) {
+                Overlay::Point { symbol, .. } => symbol,
+                other => panic!("expected a marker, got {:?}", other),
+            }
+        };
+        assert_eq!(symbol(json!(3)), Some("3".to_string()));
+        assert_eq!(symbol(json!("A")), Some("A".to_string()));
+        // Icon names can't be drawn, so they're left out
+        assert_eq!(symbol(json!("mountain")), None);
+        assert_eq!(symbol(json!("")), None);
+    }
+
+    #[test]
+    fn test_from_geojson_rejects_garbage() {
+        assert!(from_geojson(&json!({ "type": "Point", "coordinates": "nope" })).is_err());
+        assert!(from_geojson(&json!({ "type": "Blob" })).is_err());
+    }
+
+    #[test]
+    fn test_distance_to_segment() {
+        assert_eq!(
+            distance_to_segment((5.0, 3.0), (0.0, 0.0), (10.0, 0.0)),
+            3.0
+        );
+        assert_eq!(
+            distance_to_segment((-4.0, 3.0), (0.0, 0.0), (10.0, 0.0)),
+            5.0
+        );
+        assert_eq!(distance_to_segment((3.0, 4.0), (0.0, 0.0), (0.0, 0.0)), 5.0);
+    }
+
+    #[test]
+    fn test_draw_line_offscreen() {
+        // Lines far outside the image mustn't panic or take forever
+        let mut img = RgbaImage::new(16, 16);
+        route::draw_route(
+            &mut img,
+            &[(-1.0e7, -1.0e7), (-1.0e7 + 1.0, -1.0e7)],
+            3.0,
+            Rgba([0, 0, 0, 255]),
+            &LineStyle::default(),
+        );
+        assert_eq!(img.get_pixel(0, 0), &Rgba([0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn test_draw_point() {
+        let viewport = Viewport {
+            zoom: 0,
+            origin: (0.0, 0.0),
+            scale: 1.0,
+        };
+        let mut img = RgbaImage::new(256, 256);
+        let overlays = vec![Overlay::Point {
+            // The middle of the world at zoom 0
+            point: LatLong(0.0, 0.0),
+            color: Rgba([255, 0, 0, 255]),
+            radius: 4.0,
+            label: None,
+            symbol: None,
+        }];
+        draw_overlays(&mut img, &viewport, &overlays);
+
+        assert_eq!(img.get_pixel(128, 128), &Rgba([255, 0, 0, 255]));
+        assert_eq!(img.get_pixel(100, 100), &Rgba([0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn test_supersampled_edges_are_soft() {
+        let viewport = Viewport {
+            zoom: 0,
+            origin: (0.0, 0.0),
+            scale: 1.0,
+        };
+        let overlays = vec![Overlay::Point {
+            point: LatLong(0.0, 0.0),
+            color: Rgba([255, 0, 0, 255]),
+            radius: 10.0,
+            label: None,
+            symbol: None,
+        }];
+
+        let mut aliased = RgbaImage::new(256, 256);
+        draw_overlays(&mut aliased, &viewport, &overlays);
+        let mut smooth = RgbaImage::new(256, 256);
+        draw_overlays_supersampled(&mut smooth, &viewport, &overlays, 4);
+
+        // The middle is solid either way
+        assert_eq!(smooth.get_pixel(128, 128), &Rgba([255, 0, 0, 255]));
+
+        // Without supersampling every pixel is either fully in or out
+        assert!(aliased.pixels().all(|p| p[3] == 0 || p[3] == 255));
+        // With it, pixels along the edge are partially covered
+        assert!(smooth.pixels().any(|p| p[3] > 0 && p[3] < 255));
+    }
+
+    #[test]
+    fn test_downsample() {
+        let mut canvas = RgbaImage::new(2, 2);
+        canvas.put_pixel(0, 0, Rgba([0, 0, 255, 255]));
+        canvas.put_pixel(1, 0, Rgba([0, 0, 255, 255]));
+        let pixel = downsample(&canvas, 2).get_pixel(0, 0).0;
+        assert_eq!(pixel, [0, 0, 255, 128]);
+    }
+
+    #[test]
+    fn test_scaled_viewport() {
+        let viewport = Viewport {
+            zoom: 0,
+            origin: (64.0, 64.0),
+            scale: 1.0,
+        };
+        assert_eq!(viewport.project(&LatLong(0.0, 0.0)), (64.0, 64.0));
+        assert_eq!(
+            viewport.scaled(0.5).project(&LatLong(0.0, 0.0)),
+            (32.0, 32.0)
+        );
+    }
+}
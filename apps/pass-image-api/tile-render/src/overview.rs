@@ -0,0 +1,265 @@
+// ! # overview
+// ! The same center rendered at several zooms in one go, for "context + detail" layouts
+// ! in reports: a regional map, a valley and the pass itself. Levels come back as
+// ! separate images or as a contact sheet, a grid of panels from the widest view to the
+// ! closest, each labelled with its zoom and outlining the area the next panel shows.
+// !
+// ! Levels at different zooms need different tiles, but any tile more than one level
+// ! needs, such as the DEM tiles behind contours, is fetched only once per overview.
+
+use crate::coordinates::{radius_for_zoom, LatLong, MAX_ZOOM};
+use crate::fetcher::TileFetcher;
+use crate::text::{draw_text_with_halo, fill_rect};
+use crate::tiles::{fetch_image_from_point, RenderOptions, TileSet};
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use futures::future::LocalBoxFuture;
+use image::{imageops, Rgba, RgbaImage};
+use opentelemetry::Context;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// The most zooms one overview can show
+pub const MAX_LEVELS: usize = 6;
+
+// Space between and around the panels of a contact sheet
+const GUTTER: u32 = 8;
+const BACKGROUND: Rgba<u8> = Rgba([255, 255, 255, 255]);
+const LABEL_COLOR: Rgba<u8> = Rgba([40, 40, 40, 255]);
+const LABEL_HALO: Rgba<u8> = Rgba([255, 255, 255, 220]);
+const OUTLINE_COLOR: Rgba<u8> = Rgba([200, 30, 30, 255]);
+const OUTLINE_WIDTH: u32 = 2;
+
+// A tile's tileset name, x, y and z
+type TileKey = (&'static str, u32, u32, u32);
+
+// Remembers every tile fetched through it, so a tile needed again comes from memory
+pub struct SharedTiles<'a> {
+    inner: &'a dyn TileFetcher,
+    tiles: Mutex<HashMap<TileKey, Bytes>>,
+}
+
+impl<'a> SharedTiles<'a> {
+    pub fn new(inner: &'a dyn TileFetcher) -> SharedTiles<'a> {
+        SharedTiles {
+            inner,
+            tiles: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl TileFetcher for SharedTiles<'_> {
+    fn fetch(
+        &self,
+        tileset: TileSet,
+        x: u32,
+        y: u32,
+        z: u32,
+        cx: Context,
+    ) -> LocalBoxFuture<'_, Result<Bytes>> {
+        let key = (tileset.name(), x, y, z);
+        if let Some(tile) = self.tiles.lock().unwrap().get(&key) {
+            let tile = tile.clone();
+            return Box::pin(async move { Ok(tile) });
+        }
+        Box::pin(async move {
+            let tile = self.inner.fetch(tileset, x, y, z, cx).await?;
+            self.tiles.lock().unwrap().insert(key, tile.clone());
+            Ok(tile)
+        })
+    }
+}
+
+// Sorts the zooms from widest to closest, dropping repeats
+pub fn levels(zooms: &[u32]) -> Result<Vec<u32>> {
+    let mut levels = zooms.to_vec();
+    levels.sort_unstable();
+    levels.dedup();
+    if levels.is_empty() || levels.len() > MAX_LEVELS {
+        return Err(anyhow!("Between 1 and {} zooms can be shown", MAX_LEVELS));
+    }
+    if let Some(zoom) = levels.iter().find(|&&z| z > MAX_ZOOM) {
+        return Err(anyhow!("Zoom {} is past {}", zoom, MAX_ZOOM));
+    }
+    Ok(levels)
+}
+
+// Renders the center at each zoom, in the order given, as PNGs about image_size square
+pub async fn render_levels(
+    fetcher: &dyn TileFetcher,
+    center: LatLong,
+    image_size: u32,
+    zooms: &[u32],
+    tileset: TileSet,
+    options: &RenderOptions,
+) -> Result<Vec<Bytes>> {
+    let shared = SharedTiles::new(fetcher);
+    let mut images = Vec::with_capacity(zooms.len());
+    for &zoom in zooms {
+        let radius_km = radius_for_zoom(zoom, image_size);
+        images.push(
+            fetch_image_from_point(&shared, center, radius_km, image_size, tileset, options)
+                .await?,
+        );
+    }
+    Ok(images)
+}
+
+// Crops an image to at most size square around its middle
+fn center_crop(image: &RgbaImage, size: u32) -> RgbaImage {
+    let (width, height) = (image.width().min(size), image.height().min(size));
+    let x = (image.width() - width) / 2;
+    let y = (image.height() - height) / 2;
+    imageops::crop_imm(image, x, y, width, height).to_image()
+}
+
+// Outlines a square of the given side, centered in the panel
+fn outline(panel: &mut RgbaImage, side: u32) {
+    let side = side.max(2 * OUTLINE_WIDTH + 1);
+    let x = (panel.width() as i64 - side as i64) / 2;
+    let y = (panel.height() as i64 - side as i64) / 2;
+    let far = side - OUTLINE_WIDTH;
+    fill_rect(panel, x, y, side, OUTLINE_WIDTH, OUTLINE_COLOR);
+    fill_rect(panel, x, y + far as i64, side, OUTLINE_WIDTH, OUTLINE_COLOR);
+    fill_rect(panel, x, y, OUTLINE_WIDTH, side, OUTLINE_COLOR);
+    fill_rect(panel, x + far as i64, y, OUTLINE_WIDTH, side, OUTLINE_COLOR);
+}
+
+// Lays the levels out in a grid, widest first, reading left to right. Panels are cropped
+// to panel_size square, and each but the last outlines the area of the next.
+pub fn contact_sheet(levels: &[(u32, RgbaImage)], panel_size: u32) -> RgbaImage {
+    let columns = (levels.len() as f64).sqrt().ceil().max(1.0) as u32;
+    let rows = (levels.len() as u32).div_ceil(columns);
+    let span = |n: u32| n * panel_size + (n + 1) * GUTTER;
+    let mut sheet = RgbaImage::from_pixel(span(columns), span(rows), BACKGROUND);
+
+    for (i, (zoom, image)) in levels.iter().enumerate() {
+        let mut panel = center_crop(image, panel_size);
+        if let Some((next, _)) = levels.get(i + 1) {
+            // The next level shows panel_size pixels at its zoom, which is this many here
+            let side = panel_size >> (next - zoom).min(31);
+            outline(&mut panel, side);
+        }
+        draw_text_with_halo(
+            &mut panel,
+            6,
+            6,
+            &format!("z{}", zoom),
+            2,
+            LABEL_COLOR,
+            LABEL_HALO,
+        );
+        let (column, row) = (i as u32 % columns, i as u32 / columns);
+        let x = GUTTER + column * (panel_size + GUTTER);
+        let y = GUTTER + row * (panel_size + GUTTER);
+        imageops::replace(&mut sheet, &panel, x as i64, y as i64);
+    }
+    sheet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetcher::{MemoryFetcher, TileSources};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const CENTER: LatLong = LatLong(46.655559, 8.102121);
+
+    struct CountingFetcher {
+        inner: MemoryFetcher,
+        fetched: AtomicUsize,
+    }
+
+    impl TileFetcher for CountingFetcher {
+        fn fetch(
+            &self,
+            tileset: TileSet,
+            x: u32,
+            y: u32,
+            z: u32,
+            cx: Context,
+        ) -> LocalBoxFuture<'_, Result<Bytes>> {
+            self.fetched.fetch_add(1, Ordering::Relaxed);
+            self.inner.fetch(tileset, x, y, z, cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shared_tiles_fetch_once() {
+        let fetcher = CountingFetcher {
+            inner: MemoryFetcher::default().with_fallback(Bytes::from_static(b"png")),
+            fetched: AtomicUsize::new(0),
+        };
+        let shared = SharedTiles::new(&fetcher);
+        for _ in 0..3 {
+            let tile = shared
+                .fetch(TileSet::Osm, 1, 2, 3, Context::new())
+                .await
+                .unwrap();
+            assert_eq!(tile, Bytes::from_static(b"png"));
+        }
+        shared
+            .fetch(TileSet::Osm, 2, 2, 3, Context::new())
+            .await
+            .unwrap();
+        assert_eq!(fetcher.fetched.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_levels() {
+        assert_eq!(levels(&[16, 10, 13, 10]).unwrap(), vec![10, 13, 16]);
+        assert!(levels(&[]).is_err());
+        assert!(levels(&[1, 2, 3, 4, 5, 6, 7]).is_err());
+        assert!(levels(&[10, 22]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_render_levels_at_their_zooms() {
+        let images = render_levels(
+            &TileSources::default(),
+            CENTER,
+            256,
+            &[11, 14],
+            TileSet::Debug,
+            &RenderOptions::default(),
+        )
+        .await
+        .unwrap();
+        let images: Vec<RgbaImage> = images
+            .iter()
+            .map(|png| image::load_from_memory(png).unwrap().to_rgba8())
+            .collect();
+        // A couple of pixels over the size, as radius_for_zoom asks for
+        assert!(images.iter().all(|i| i.width() >= 256 && i.width() < 260));
+
+        let levels: Vec<(u32, RgbaImage)> = [11, 14].into_iter().zip(images).collect();
+        let sheet = contact_sheet(&levels, 256);
+        assert_eq!(sheet.dimensions(), (2 * 256 + 3 * GUTTER, 256 + 2 * GUTTER));
+        assert_eq!(sheet.get_pixel(0, 0), &BACKGROUND);
+
+        // The widest panel outlines the closest one, 8 times smaller, around its middle
+        let middle = GUTTER + 128;
+        let corner = middle - 16;
+        assert_eq!(sheet.get_pixel(corner, middle), &OUTLINE_COLOR);
+        assert_eq!(sheet.get_pixel(middle, corner), &OUTLINE_COLOR);
+        assert_ne!(sheet.get_pixel(middle, middle), &OUTLINE_COLOR);
+        // The closest panel outlines nothing
+        let second = GUTTER * 2 + 256 + 128;
+        assert_ne!(sheet.get_pixel(second - 16, middle), &OUTLINE_COLOR);
+    }
+
+    #[test]
+    fn test_contact_sheet_grid() {
+        let panel = RgbaImage::from_pixel(64, 64, Rgba([0, 0, 255, 255]));
+        let levels: Vec<(u32, RgbaImage)> = (10..15).map(|z| (z, panel.clone())).collect();
+        // Five panels take a 3 by 2 grid
+        let sheet = contact_sheet(&levels, 64);
+        assert_eq!(
+            sheet.dimensions(),
+            (3 * 64 + 4 * GUTTER, 2 * 64 + 3 * GUTTER)
+        );
+        // The last row's empty slot is left blank
+        let empty = 2 * GUTTER + 64 + 32;
+        assert_eq!(sheet.get_pixel(2 * (64 + GUTTER) + 32, empty), &BACKGROUND);
+    }
+}
@@ -0,0 +1,145 @@
+// ! # plugin
+// ! Custom overlay renderers, for bespoke overlays such as lift lines or webcam icons that
+// ! don't belong in the overlay module itself. A renderer is registered once at startup
+// ! under a name, and GeoJSON features pick it with a "renderer" property:
+// !
+// !   { "type": "Feature",
+// !     "properties": { "renderer": "lift-line", "lift": "chair" },
+// !     "geometry": { "type": "LineString", "coordinates": [[8.0, 46.0], [8.1, 46.1]] } }
+// !
+// ! The feature's positions are handed to the renderer along with all its properties, so
+// ! it can style them however it likes. Features naming a renderer that isn't registered
+// ! are rejected, rather than silently drawn as plain lines or markers.
+
+use crate::coordinates::LatLong;
+use crate::layers::LayerKind;
+use crate::overlay::Viewport;
+use image::RgbaImage;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock, RwLock};
+
+// Renderer names are kept short and simple, as they're written into GeoJSON
+const MAX_NAME_CHARS: usize = 32;
+
+static RENDERERS: OnceLock<RwLock<HashMap<String, Arc<dyn OverlayRenderer>>>> = OnceLock::new();
+
+pub trait OverlayRenderer: Send + Sync {
+    // The layer the renderer's overlays are drawn on
+    fn layer(&self) -> LayerKind {
+        LayerKind::Markers
+    }
+
+    // Draws one feature. Its points are projected onto the image with the viewport, and
+    // pixel sizes such as line widths should be multiplied by scale, which is above 1 when
+    // overlays are supersampled.
+    fn draw(
+        &self,
+        img: &mut RgbaImage,
+        viewport: &Viewport,
+        points: &[LatLong],
+        properties: &Value,
+        scale: f32,
+    );
+}
+
+fn renderers() -> &'static RwLock<HashMap<String, Arc<dyn OverlayRenderer>>> {
+    RENDERERS.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+// Registers a renderer under a name, replacing any renderer already registered under it.
+// Names are letters, digits and dashes, e.g. lift-line.
+pub fn register(name: &str, renderer: Arc<dyn OverlayRenderer>) -> anyhow::Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_CHARS
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    if !valid {
+        return Err(anyhow::anyhow!("Invalid overlay renderer name {:?}", name));
+    }
+    renderers()
+        .write()
+        .unwrap()
+        .insert(name.to_string(), renderer);
+    Ok(())
+}
+
+// The renderer registered under a name
+pub fn lookup(name: &str) -> Option<Arc<dyn OverlayRenderer>> {
+    renderers().read().unwrap().get(name).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::overlay::{self, fill_circle, parse_color, Overlay};
+    use image::Rgba;
+    use serde_json::json;
+
+    // Draws a pylon at every point, in the feature's "pylon-color"
+    struct Pylons;
+
+    impl OverlayRenderer for Pylons {
+        fn layer(&self) -> LayerKind {
+            LayerKind::Lines
+        }
+
+        fn draw(
+            &self,
+            img: &mut RgbaImage,
+            viewport: &Viewport,
+            points: &[LatLong],
+            properties: &Value,
+            scale: f32,
+        ) {
+            let color = properties
+                .get("pylon-color")
+                .and_then(Value::as_str)
+                .and_then(parse_color)
+                .unwrap_or(Rgba([0, 0, 0, 255]));
+            for point in points {
+                let (x, y) = viewport.project(point);
+                fill_circle(img, x, y, 3.0 * scale, color);
+            }
+        }
+    }
+
+    #[test]
+    fn test_custom_renderer() {
+        register("test-pylons", Arc::new(Pylons)).unwrap();
+        let geojson = json!({
+            "type": "Feature",
+            "properties": { "renderer": "test-pylons", "pylon-color": "#ff0000" },
+            "geometry": { "type": "LineString", "coordinates": [[0.0, 0.0], [45.0, 0.0]] }
+        });
+        let overlays = overlay::from_geojson(&geojson).unwrap();
+        assert!(matches!(
+            &overlays[..],
+            [Overlay::Custom { renderer, points, .. }] if renderer == "test-pylons" && points.len() == 2
+        ));
+        assert_eq!(overlays[0].layer(), LayerKind::Lines);
+
+        // At zoom 0 the points land in the middle and five eighths of the way across
+        let viewport = Viewport {
+            zoom: 0,
+            origin: (0.0, 0.0),
+            scale: 1.0,
+        };
+        let mut img = RgbaImage::new(256, 256);
+        overlay::draw_overlays_supersampled(&mut img, &viewport, &overlays, 2);
+        assert_eq!(img.get_pixel(128, 128), &Rgba([255, 0, 0, 255]));
+        assert_eq!(img.get_pixel(160, 128), &Rgba([255, 0, 0, 255]));
+        assert_eq!(img.get_pixel(144, 128), &Rgba([0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn test_unknown_renderer() {
+        let geojson = json!({
+            "type": "Feature",
+            "properties": { "renderer": "no-such-renderer" },
+            "geometry": { "type": "Point", "coordinates": [8.0, 46.0] }
+        });
+        assert!(overlay::from_geojson(&geojson).is_err());
+        assert!(register("", Arc::new(Pylons)).is_err());
+        assert!(register("lift line", Arc::new(Pylons)).is_err());
+    }
+}
@@ -0,0 +1,523 @@
+// ! # pmtiles
+// ! Tilesets served straight from a PMTiles v3 archive: one file of tiles that can sit on
+// ! local disk, or on any static file host or object store that answers HTTP range
+// ! requests, so a self-hosted setup doesn't need a tile server at all.
+// !
+// !     TILESET_OSM_SOURCE=pmtiles:/srv/tiles/switzerland.pmtiles
+// !     TILESET_OSM_SOURCE=pmtiles:https://tiles.example.com/switzerland.pmtiles
+// !
+// ! A tile is looked up by its Hilbert tile ID in the root directory, which comes with
+// ! the header in the archive's first 16KiB, and then in the leaf directories that points
+// ! to. Directories may be gzipped, and the last MAX_CACHED_LEAVES leaves read are kept in
+// ! memory. The archive has to hold PNG or JPEG tiles. Local archives are checked when the
+// ! source is set up; remote ones on their first fetch, and each range is fetched through
+// ! the url_guard, with the tileset's headers and client certificate, like an upstream.
+
+use crate::fetcher::TileFetcher;
+use crate::tiles::TileSet;
+use crate::{transport, url_guard};
+use anyhow::{anyhow, Context as _, Result};
+use bytes::Bytes;
+use flate2::read::GzDecoder;
+use futures::future::LocalBoxFuture;
+use opentelemetry::Context;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
+
+const MAGIC: &[u8] = b"PMTiles";
+const HEADER_BYTES: usize = 127;
+
+// The header and root directory are always within an archive's first 16KiB
+const ROOT_BYTES: u64 = 16 * 1024;
+
+// The most of a directory or tile that's read at once
+const MAX_READ_BYTES: usize = 16 * 1024 * 1024;
+
+// The most leaf directories kept in memory for each archive
+const MAX_CACHED_LEAVES: usize = 64;
+
+// Archives nest leaf directories at most this deep below the root
+const MAX_DEPTH: usize = 4;
+
+// The deepest zoom whose tile IDs fit in 64 bits
+const MAX_TILE_ZOOM: u32 = 31;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Compression {
+    None,
+    Gzip,
+}
+
+impl Compression {
+    fn from_byte(byte: u8) -> Result<Compression> {
+        match byte {
+            1 => Ok(Compression::None),
+            2 => Ok(Compression::Gzip),
+            3 => Err(anyhow!("Brotli compressed archives aren't supported")),
+            4 => Err(anyhow!("Zstandard compressed archives aren't supported")),
+            _ => Err(anyhow!("Unknown compression {}", byte)),
+        }
+    }
+
+    fn decompress(&self, data: Bytes) -> Result<Bytes> {
+        match self {
+            Compression::None => Ok(data),
+            Compression::Gzip => {
+                let mut out = Vec::new();
+                GzDecoder::new(&data[..])
+                    .take(MAX_READ_BYTES as u64 + 1)
+                    .read_to_end(&mut out)
+                    .context("decompressing")?;
+                if out.len() > MAX_READ_BYTES {
+                    return Err(anyhow!("Decompresses to over {} bytes", MAX_READ_BYTES));
+                }
+                Ok(Bytes::from(out))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Header {
+    root_offset: u64,
+    root_length: u64,
+    leaf_offset: u64,
+    data_offset: u64,
+    internal: Compression,
+    tiles: Compression,
+    min_zoom: u8,
+    max_zoom: u8,
+}
+
+fn parse_header(bytes: &[u8]) -> Result<Header> {
+    if bytes.len() < HEADER_BYTES || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(anyhow!("Not a PMTiles archive"));
+    }
+    if bytes[7] != 3 {
+        return Err(anyhow!("PMTiles version {} isn't supported", bytes[7]));
+    }
+    let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+    // 2 and 3 are PNG and JPEG; vector tiles, WebP and AVIF can't be mosaicked
+    if !matches!(bytes[99], 2 | 3) {
+        return Err(anyhow!("Only PNG and JPEG tiles are supported"));
+    }
+    Ok(Header {
+        root_offset: u64_at(8),
+        root_length: u64_at(16),
+        leaf_offset: u64_at(40),
+        data_offset: u64_at(56),
+        internal: Compression::from_byte(bytes[97])?,
+        tiles: Compression::from_byte(bytes[98])?,
+        min_zoom: bytes[100],
+        max_zoom: bytes[101],
+    })
+}
+
+// A directory entry: a run of tiles with the same data, or a leaf directory for the tile
+// IDs from its own up to the next entry's if its run length is 0
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Entry {
+    tile_id: u64,
+    offset: u64,
+    length: u32,
+    run_length: u32,
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *bytes
+            .get(*pos)
+            .ok_or_else(|| anyhow!("Directory ends early"))?;
+        *pos += 1;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(anyhow!("Directory has an invalid varint"))
+}
+
+// Parses a decompressed directory: its entry count, then their tile IDs as deltas, run
+// lengths, lengths and offsets, each as varints. An offset of 0 means the entry's data
+// follows on from the one before; any other is one past where the data starts.
+fn parse_directory(bytes: &[u8]) -> Result<Vec<Entry>> {
+    let pos = &mut 0;
+    let count = read_varint(bytes, pos)? as usize;
+    if count > bytes.len() {
+        return Err(anyhow!("Directory is too short for {} entries", count));
+    }
+    let mut entries = vec![Entry::default(); count];
+    let mut tile_id = 0u64;
+    for entry in entries.iter_mut() {
+        tile_id = tile_id
+            .checked_add(read_varint(bytes, pos)?)
+            .ok_or_else(|| anyhow!("Directory has an invalid tile ID"))?;
+        entry.tile_id = tile_id;
+    }
+    for entry in entries.iter_mut() {
+        entry.run_length = u32::try_from(read_varint(bytes, pos)?)?;
+    }
+    for entry in entries.iter_mut() {
+        entry.length = u32::try_from(read_varint(bytes, pos)?)?;
+    }
+    for i in 0..count {
+        entries[i].offset = match (read_varint(bytes, pos)?, i.checked_sub(1)) {
+            (0, Some(previous)) => entries[previous].offset + entries[previous].length as u64,
+            (0, None) => return Err(anyhow!("Directory's first offset is missing")),
+            (offset, _) => offset - 1,
+        };
+    }
+    Ok(entries)
+}
+
+// A tile's ID in the archive: the count of tiles at lower zooms, plus its position along
+// the Hilbert curve over its zoom. IDs fit in 64 bits up to zoom 31.
+pub fn tile_id(x: u32, y: u32, z: u32) -> Result<u64> {
+    if z > MAX_TILE_ZOOM || x as u64 >= 1 << z || y as u64 >= 1 << z {
+        return Err(anyhow!("No tile {}/{}/{} in a PMTiles archive", z, x, y));
+    }
+    let lower_zooms = ((1u64 << (2 * z)) - 1) / 3;
+    let n = 1u64 << z;
+    let (mut x, mut y) = (x as u64, y as u64);
+    let mut d = 0;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = u64::from(x & s > 0);
+        let ry = u64::from(y & s > 0);
+        d += s * s * ((3 * rx) ^ ry);
+        if ry == 0 {
+            if rx == 1 {
+                x = n - 1 - x;
+                y = n - 1 - y;
+            }
+            (x, y) = (y, x);
+        }
+        s /= 2;
+    }
+    Ok(lower_zooms + d)
+}
+
+// The entry for a tile ID: a run of tiles that includes it, or a leaf directory it may be
+// in
+fn find(entries: &[Entry], tile_id: u64) -> Option<Entry> {
+    let entry = entries[..entries.partition_point(|e| e.tile_id <= tile_id)].last()?;
+    (entry.run_length == 0 || tile_id < entry.tile_id + entry.run_length as u64).then_some(*entry)
+}
+
+// The header and root directory
+#[derive(Debug)]
+struct Root {
+    header: Header,
+    entries: Arc<Vec<Entry>>,
+}
+
+fn parse_root(bytes: &[u8]) -> Result<Root> {
+    let header = parse_header(bytes)?;
+    let start = header.root_offset as usize;
+    let root = bytes
+        .get(start..start.saturating_add(header.root_length as usize))
+        .ok_or_else(|| anyhow!("The root directory isn't in the first {} bytes", ROOT_BYTES))?;
+    let root = header
+        .internal
+        .decompress(Bytes::copy_from_slice(root))
+        .context("reading the root directory")?;
+    Ok(Root {
+        header,
+        entries: Arc::new(parse_directory(&root).context("reading the root directory")?),
+    })
+}
+
+// Reads up to length bytes from a local archive
+fn read_file(file: &Mutex<File>, offset: u64, length: u64) -> Result<Bytes> {
+    let mut file = file.lock().unwrap();
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = Vec::new();
+    (&mut *file).take(length).read_to_end(&mut data)?;
+    Ok(Bytes::from(data))
+}
+
+enum Archive {
+    File(Mutex<File>),
+    Http(String),
+}
+
+impl Archive {
+    // Reads up to length bytes, fewer at the end of the archive
+    async fn read(&self, tileset: TileSet, offset: u64, length: u64, cx: Context) -> Result<Bytes> {
+        let url = match self {
+            Archive::File(file) => return read_file(file, offset, length),
+            Archive::Http(url) => url,
+        };
+        if length as usize > MAX_READ_BYTES {
+            return Err(anyhow!("{} bytes is too much to read at once", length));
+        }
+        let transport = transport::for_tileset(tileset, MAX_READ_BYTES)?;
+        let mut headers = tileset.headers();
+        headers.push((
+            "Range".to_string(),
+            format!("bytes={}-{}", offset, offset + length.max(1) - 1),
+        ));
+        let response =
+            url_guard::guarded_get(transport.as_ref(), url, &headers, "dd-sdlc-demo", cx).await?;
+        match response.status {
+            206 => Ok(response.body),
+            // Servers that ignore the range send the archive from the start
+            200 => {
+                let start = (offset as usize).min(response.body.len());
+                let end = (offset + length).min(response.body.len() as u64) as usize;
+                Ok(response.body.slice(start..end))
+            }
+            status => Err(anyhow!(
+                "Range request to {} failed with status: {}",
+                url,
+                status
+            )),
+        }
+    }
+
+    // Reads exactly length bytes
+    async fn read_exact(
+        &self,
+        tileset: TileSet,
+        offset: u64,
+        length: u64,
+        cx: Context,
+    ) -> Result<Bytes> {
+        let data = self.read(tileset, offset, length, cx).await?;
+        if data.len() as u64 != length {
+            return Err(anyhow!(
+                "The archive ends {} bytes into a {} byte read",
+                data.len(),
+                length
+            ));
+        }
+        Ok(data)
+    }
+}
+
+// Leaf directories read, by their offset and length
+type Leaves = HashMap<(u64, u64), Arc<Vec<Entry>>>;
+
+// Reads tiles from a local or remote PMTiles archive
+pub struct PmTilesFetcher {
+    archive: Archive,
+    root: Mutex<Option<Arc<Root>>>,
+    leaves: Mutex<Leaves>,
+}
+
+impl PmTilesFetcher {
+    // Opens an archive from a path, or an http(s) URL. Local archives are read and checked
+    // straight away.
+    pub fn open(location: &str) -> Result<PmTilesFetcher> {
+        if location.starts_with("https://") || location.starts_with("http://") {
+            return Ok(PmTilesFetcher::new(
+                Archive::Http(location.to_string()),
+                None,
+            ));
+        }
+        let file = Mutex::new(
+            File::open(location)
+                .with_context(|| format!("opening PMTiles archive {}", location))?,
+        );
+        let root = read_file(&file, 0, ROOT_BYTES)
+            .and_then(|bytes| parse_root(&bytes))
+            .with_context(|| format!("reading PMTiles archive {}", location))?;
+        Ok(PmTilesFetcher::new(Archive::File(file), Some(root)))
+    }
+
+    fn new(archive: Archive, root: Option<Root>) -> PmTilesFetcher {
+        PmTilesFetcher {
+            archive,
+            root: Mutex::new(root.map(Arc::new)),
+            leaves: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn root(&self, tileset: TileSet, cx: Context) -> Result<Arc<Root>> {
+        let cached = self.root.lock().unwrap().clone();
+        if let Some(root) = cached {
+            return Ok(root);
+        }
+        let bytes = self.archive.read(tileset, 0, ROOT_BYTES, cx).await?;
+        let root = Arc::new(parse_root(&bytes).context("reading PMTiles archive")?);
+        *self.root.lock().unwrap() = Some(root.clone());
+        Ok(root)
+    }
+
+    async fn leaf(
+        &self,
+        tileset: TileSet,
+        header: &Header,
+        entry: Entry,
+        cx: Context,
+    ) -> Result<Arc<Vec<Entry>>> {
+        let key = (header.leaf_offset + entry.offset, entry.length as u64);
+        let cached = self.leaves.lock().unwrap().get(&key).cloned();
+        if let Some(leaf) = cached {
+            return Ok(leaf);
+        }
+        let data = self.archive.read_exact(tileset, key.0, key.1, cx).await?;
+        let leaf = Arc::new(
+            parse_directory(&header.internal.decompress(data)?)
+                .context("reading a leaf directory")?,
+        );
+        let mut leaves = self.leaves.lock().unwrap();
+        if leaves.len() >= MAX_CACHED_LEAVES {
+            leaves.clear();
+        }
+        leaves.insert(key, leaf.clone());
+        Ok(leaf)
+    }
+
+    async fn tile(&self, tileset: TileSet, x: u32, y: u32, z: u32, cx: Context) -> Result<Bytes> {
+        let root = self.root(tileset, cx.clone()).await?;
+        let header = root.header;
+        let missing = || anyhow!("Tile {}/{}/{} isn't in the PMTiles archive", z, x, y);
+        if z < header.min_zoom as u32 || z > header.max_zoom as u32 {
+            return Err(missing());
+        }
+        let id = tile_id(x, y, z)?;
+        let mut entries = root.entries.clone();
+        for _ in 0..=MAX_DEPTH {
+            let entry = find(&entries, id).ok_or_else(missing)?;
+            if entry.run_length > 0 {
+                let data = self
+                    .archive
+                    .read_exact(
+                        tileset,
+                        header.data_offset + entry.offset,
+                        entry.length as u64,
+                        cx,
+                    )
+                    .await?;
+                return header.tiles.decompress(data);
+            }
+            entries = self.leaf(tileset, &header, entry, cx.clone()).await?;
+        }
+        Err(anyhow!("PMTiles leaf directories nest too deep"))
+    }
+}
+
+impl TileFetcher for PmTilesFetcher {
+    fn fetch(
+        &self,
+        tileset: TileSet,
+        x: u32,
+        y: u32,
+        z: u32,
+        cx: Context,
+    ) -> LocalBoxFuture<'_, Result<Bytes>> {
+        Box::pin(self.tile(tileset, x, y, z, cx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use std::env;
+    use std::io::Write;
+
+    fn varints(values: &[u64]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for &value in values {
+            let mut value = value;
+            while value >= 0x80 {
+                bytes.push((value as u8 & 0x7f) | 0x80);
+                value >>= 7;
+            }
+            bytes.push(value as u8);
+        }
+        bytes
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_tile_id() {
+        assert_eq!(tile_id(0, 0, 0).unwrap(), 0);
+        assert_eq!(
+            [(0, 0), (0, 1), (1, 1), (1, 0)].map(|(x, y)| tile_id(x, y, 1).unwrap()),
+            [1, 2, 3, 4]
+        );
+        assert_eq!(tile_id(0, 0, 2).unwrap(), 5);
+        assert_eq!(tile_id(3, 0, 2).unwrap(), 20);
+        assert_eq!(tile_id(0, 0, 12).unwrap(), 5592405);
+        let last = (1 << 31) - 1;
+        assert!(tile_id(last, last, 31).is_ok());
+
+        // Tiles off the edge of their zoom, or past the deepest one, have no ID
+        assert!(tile_id(2, 0, 1).is_err());
+        assert!(tile_id(0, 4, 2).is_err());
+        assert!(tile_id(0, 0, 32).is_err());
+        assert!(tile_id(0, 0, u32::MAX).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_archive() {
+        // Tiles 1 and 2 share their data, 3 is in a leaf directory, and 4 is missing
+        let tiles = b"aaaabbbbbb";
+        let leaf = gzip(&varints(&[1, 3, 1, 6, 1]));
+        let root = gzip(&varints(&[
+            3,
+            0,
+            1,
+            2,
+            1,
+            2,
+            0,
+            4,
+            6,
+            leaf.len() as u64,
+            1,
+            0,
+            1,
+        ]));
+
+        let mut archive = vec![0u8; HEADER_BYTES];
+        archive[..7].copy_from_slice(MAGIC);
+        archive[7] = 3;
+        let mut set = |at: usize, value: u64| {
+            archive[at..at + 8].copy_from_slice(&value.to_le_bytes());
+        };
+        let leaf_offset = (HEADER_BYTES + root.len()) as u64;
+        set(8, HEADER_BYTES as u64);
+        set(16, root.len() as u64);
+        set(40, leaf_offset);
+        set(56, leaf_offset + leaf.len() as u64);
+        archive[97] = 2;
+        archive[98] = 1;
+        archive[99] = 2;
+        archive[101] = 1;
+        archive.extend(root);
+        archive.extend(leaf);
+        archive.extend(tiles);
+
+        let path = env::temp_dir().join(format!("archive-{}.pmtiles", std::process::id()));
+        std::fs::write(&path, &archive).unwrap();
+        let fetcher = PmTilesFetcher::open(path.to_str().unwrap()).unwrap();
+        let tile = |x, y, z| fetcher.fetch(TileSet::Osm, x, y, z, Context::current());
+        assert_eq!(tile(0, 0, 0).await.unwrap(), &b"aaaa"[..]);
+        assert_eq!(tile(0, 0, 1).await.unwrap(), &b"bbbbbb"[..]);
+        assert_eq!(tile(0, 1, 1).await.unwrap(), &b"bbbbbb"[..]);
+        assert_eq!(tile(1, 1, 1).await.unwrap(), &b"aaaabb"[..]);
+        assert!(tile(1, 0, 1).await.is_err());
+        assert!(tile(0, 0, 2).await.is_err());
+        assert!(tile(2, 0, 1).await.is_err());
+        assert_eq!(fetcher.leaves.lock().unwrap().len(), 1);
+
+        // WebP tiles can't be mosaicked
+        archive[99] = 4;
+        std::fs::write(&path, &archive).unwrap();
+        assert!(PmTilesFetcher::open(path.to_str().unwrap()).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert!(PmTilesFetcher::open("https://tiles.example.com/a.pmtiles").is_ok());
+    }
+}
@@ -0,0 +1,202 @@
+// ! # prefetch
+// ! The tiles just outside a render, for warming the cache with the ones an interactive
+// ! map is likely to ask for next as it's panned. The ring is one tile wide around the
+// ! tiles the render fetched, at the same zoom, and nearest the center first, so a budget
+// ! that can't cover all of it keeps the tiles a pan needs soonest.
+// !
+// ! Prefetching only ever adds load, so it backs off rather than retrying: tilesets whose
+// ! upstream failed more than MAX_ERROR_RATE of its recent fetches aren't prefetched,
+// ! a tile that fails isn't fetched again, and after MAX_FAILURES failures the rest of the
+// ! ring is given up on.
+
+use crate::coordinates::{
+    lat_long_and_image_size_to_bounding_box, lat_long_to_tile_coords, LatLong,
+};
+use crate::fetcher::TileFetcher;
+use crate::tiles::{RenderOptions, TileSet};
+use crate::upstreams;
+use futures::{stream, StreamExt};
+use opentelemetry::Context;
+
+pub const MAX_ERROR_RATE: f64 = 0.1;
+const MAX_FAILURES: usize = 3;
+
+// How a prefetch went. Tiles that were never asked for, because their upstream was
+// failing or the prefetch gave up, are skipped.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Prefetched {
+    pub fetched: usize,
+    pub failed: usize,
+    pub skipped: usize,
+}
+
+// The ring of tiles around a render, as (tileset, x, y, z), for each of its layers and
+// nearest the center first, up to budget tiles
+pub fn neighbors(
+    center: LatLong,
+    radius_km: f32,
+    image_size: u32,
+    tileset: TileSet,
+    options: &RenderOptions,
+    budget: usize,
+) -> Vec<(TileSet, u32, u32, u32)> {
+    let tile_box = lat_long_and_image_size_to_bounding_box(center, radius_km, image_size).tile_box;
+    let z = tile_box.top_left.z;
+    let last = (1u64 << z) - 1;
+    let (left, top) = tile_box.outer_top_left();
+    let (right, bottom) = (
+        tile_box.bottom_right.x.ceil() as u32,
+        tile_box.bottom_right.y.ceil() as u32,
+    );
+
+    // The ring stops at the edges of the world rather than wrapping
+    let mut ring = Vec::new();
+    for x in left.saturating_sub(1)..=(right as u64 + 1).min(last) as u32 {
+        for y in top.saturating_sub(1)..=(bottom as u64 + 1).min(last) as u32 {
+            let inside = (left..=right).contains(&x) && (top..=bottom).contains(&y);
+            if !inside {
+                ring.push((x, y));
+            }
+        }
+    }
+    let middle = lat_long_to_tile_coords(&center, z);
+    let distance = |&(x, y): &(u32, u32)| {
+        let (dx, dy) = (x as f32 + 0.5 - middle.x, y as f32 + 0.5 - middle.y);
+        dx * dx + dy * dy
+    };
+    ring.sort_by(|a, b| distance(a).total_cmp(&distance(b)));
+
+    // Each layer's tiles are taken together, so a tight budget covers every layer of the
+    // nearest tiles rather than one layer of all of them
+    let layers = options.layers(tileset);
+    ring.into_iter()
+        .flat_map(|(x, y)| layers.iter().map(move |layer| (layer.tileset, x, y, z)))
+        .take(budget)
+        .collect()
+}
+
+// Fetches the tiles, concurrency at a time, so they land in the fetcher's cache. Their
+// contents are thrown away.
+pub async fn prefetch(
+    fetcher: &dyn TileFetcher,
+    tiles: &[(TileSet, u32, u32, u32)],
+    concurrency: usize,
+) -> Prefetched {
+    let wanted: Vec<_> = tiles
+        .iter()
+        .filter(|(tileset, ..)| upstreams::error_rate(*tileset) <= MAX_ERROR_RATE)
+        .collect();
+    let mut prefetched = Prefetched::default();
+    let cx = Context::new();
+    let mut fetches = stream::iter(&wanted)
+        .map(|&&(tileset, x, y, z)| fetcher.fetch(tileset, x, y, z, cx.clone()))
+        .buffer_unordered(concurrency.max(1));
+    while let Some(fetched) = fetches.next().await {
+        match fetched {
+            Ok(_) => prefetched.fetched += 1,
+            Err(_) => prefetched.failed += 1,
+        }
+        if prefetched.failed >= MAX_FAILURES {
+            break;
+        }
+    }
+    prefetched.skipped = tiles.len() - prefetched.fetched - prefetched.failed;
+    prefetched
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetcher::MemoryFetcher;
+    use crate::tiles::{encode_tile, parse_blend};
+    use anyhow::{anyhow, Result};
+    use bytes::Bytes;
+    use futures::future::LocalBoxFuture;
+    use image::RgbaImage;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_neighbors() {
+        let center = LatLong(46.655559, 8.102121);
+        let options = RenderOptions::default();
+        let tile_box = lat_long_and_image_size_to_bounding_box(center, 1.0, 256).tile_box;
+        let (left, top) = tile_box.outer_top_left();
+        let (right, bottom) = (
+            tile_box.bottom_right.x.ceil() as u32,
+            tile_box.bottom_right.y.ceil() as u32,
+        );
+        let ring = neighbors(center, 1.0, 256, TileSet::Osm, &options, usize::MAX);
+        let (width, height) = (right - left + 1, bottom - top + 1);
+        assert_eq!(ring.len() as u32, 2 * (width + height) + 4);
+        assert!(ring.iter().all(|&(tileset, x, y, z)| {
+            let inside = (left..=right).contains(&x) && (top..=bottom).contains(&y);
+            tileset == TileSet::Osm && z == tile_box.top_left.z && !inside
+        }));
+
+        // The budget keeps the nearest tiles, with every layer of each
+        let options = RenderOptions {
+            blend: parse_blend("osm,esri:0.5").unwrap(),
+            ..Default::default()
+        };
+        let nearest = neighbors(center, 1.0, 256, TileSet::Osm, &options, 4);
+        assert_eq!(nearest.len(), 4);
+        assert_eq!((nearest[0].1, nearest[0].2), (nearest[1].1, nearest[1].2));
+        assert_eq!((nearest[0].0, nearest[1].0), (TileSet::Osm, TileSet::Esri));
+    }
+
+    #[test]
+    fn test_neighbors_stop_at_the_edge_of_the_world() {
+        let ring = neighbors(
+            LatLong(85.0, -179.9),
+            50.0,
+            256,
+            TileSet::Osm,
+            &RenderOptions::default(),
+            usize::MAX,
+        );
+        assert!(!ring.is_empty());
+        let last = (1 << ring[0].3) - 1;
+        assert!(ring.iter().all(|&(_, x, y, _)| x <= last && y <= last));
+    }
+
+    // Fails every fetch, counting them
+    #[derive(Default)]
+    struct Failing(AtomicUsize);
+
+    impl TileFetcher for Failing {
+        fn fetch(
+            &self,
+            _tileset: TileSet,
+            _x: u32,
+            _y: u32,
+            _z: u32,
+            _cx: Context,
+        ) -> LocalBoxFuture<'_, Result<Bytes>> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Box::pin(async { Err(anyhow!("upstream is down")) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prefetch() {
+        let tiles: Vec<_> = (0..10).map(|x| (TileSet::Debug, x, 0, 4)).collect();
+        let fetcher = MemoryFetcher::default().with_fallback(encode_tile(RgbaImage::new(1, 1)));
+        assert_eq!(
+            prefetch(&fetcher, &tiles, 4).await,
+            Prefetched {
+                fetched: 10,
+                failed: 0,
+                skipped: 0
+            }
+        );
+
+        // A failing upstream isn't asked for the whole ring
+        let failing = Failing::default();
+        let prefetched = prefetch(&failing, &tiles, 1).await;
+        assert_eq!(
+            (prefetched.failed, prefetched.skipped),
+            (MAX_FAILURES, 10 - MAX_FAILURES)
+        );
+        assert_eq!(failing.0.load(Ordering::Relaxed), MAX_FAILURES);
+    }
+}
@@ -0,0 +1,214 @@
+// ! # presets
+// ! Named bundles of render settings, so the stack's UIs can ask for preset=card rather
+// ! than repeating the same size, radius and styling everywhere, and all get the same look.
+// ! A preset holds ImageRequest fields: size_px, radius, tileset and any RenderParams,
+// ! such as format, scale_bar or attribution. Requests fill in whatever they leave out
+// ! from their preset, so anything they do set wins.
+// !
+// ! card, hero and print are built in. PRESETS_CONFIG points at a TOML file that
+// ! overrides them or adds more, one table each:
+// !
+// !     [preset.card]
+// !     size_px = 320
+// !     radius = 1.5
+// !     tileset = "swisstopo"
+// !
+// ! Presets are loaded and checked once at startup.
+
+use crate::request::ImageRequest;
+use anyhow::{anyhow, Context as _, Result};
+use log::info;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::sync::OnceLock;
+
+static PRESETS: OnceLock<BTreeMap<String, Preset>> = OnceLock::new();
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preset {
+    values: Map<String, Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default)]
+    preset: BTreeMap<String, Map<String, Value>>,
+}
+
+fn builtins() -> BTreeMap<String, Preset> {
+    let preset = |values: Value| Preset {
+        values: match values {
+            Value::Object(values) => values,
+            _ => unreachable!("presets are objects"),
+        },
+    };
+    BTreeMap::from([
+        // Thumbnails in lists and cards
+        (
+            "card".to_string(),
+            preset(json!({"size_px": 320, "radius": 1.0})),
+        ),
+        // Full width banners
+        (
+            "hero".to_string(),
+            preset(json!({"size_px": 1600, "radius": 4.0, "scale_bar": true})),
+        ),
+        // Printed route sheets, credited in full
+        (
+            "print".to_string(),
+            preset(json!({
+                "size_px": 2400,
+                "radius": 3.0,
+                "scale_bar": true,
+                "attribution": true,
+            })),
+        ),
+    ])
+}
+
+impl Preset {
+    pub fn size_px(&self) -> Option<u32> {
+        self.values
+            .get("size_px")
+            .and_then(Value::as_u64)
+            .and_then(|size| u32::try_from(size).ok())
+    }
+
+    // Fills in the fields a JSON request body leaves out
+    pub fn apply_to_body(&self, body: &mut Map<String, Value>) {
+        for (name, value) in &self.values {
+            if !body.contains_key(name) {
+                body.insert(name.clone(), value.clone());
+            }
+        }
+    }
+
+    // Fills in the query parameters a request leaves out. The size is left to the caller,
+    // as GET requests have theirs in the path.
+    pub fn apply_to_query(&self, query: &mut HashMap<String, String>) {
+        for (name, value) in self.values.iter().filter(|(name, _)| *name != "size_px") {
+            let value = match value {
+                Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+            query.entry(name.clone()).or_insert(value);
+        }
+    }
+
+    // Checks that a request using just the preset would be valid
+    fn validate(&self) -> Result<()> {
+        let mut body = Map::from_iter([
+            ("long".to_string(), json!(0.0)),
+            ("lat".to_string(), json!(0.0)),
+            ("size_px".to_string(), json!(256)),
+        ]);
+        body.extend(self.values.clone());
+        let request: ImageRequest = serde_json::from_value(Value::Object(body))?;
+        request.render_options()?;
+        if self.values.contains_key("size_px") && self.size_px().is_none_or(|size| size == 0) {
+            return Err(anyhow!("size_px must be a positive number of pixels"));
+        }
+        Ok(())
+    }
+}
+
+// Parses a presets config and adds its presets to the built-in ones, replacing any of
+// the same name
+pub fn parse(config: &str) -> Result<BTreeMap<String, Preset>> {
+    let config: Config = toml::from_str(config)?;
+    let mut presets = builtins();
+    for (name, values) in config.preset {
+        let preset = Preset { values };
+        preset
+            .validate()
+            .with_context(|| format!("Invalid preset {}", name))?;
+        presets.insert(name, preset);
+    }
+    Ok(presets)
+}
+
+// Loads PRESETS_CONFIG, if it's set. This should be called once at startup.
+pub fn init_from_env() -> Result<()> {
+    let presets = match env::var("PRESETS_CONFIG") {
+        Ok(path) => {
+            let config = fs::read_to_string(&path).with_context(|| format!("reading {}", path))?;
+            let presets = parse(&config).with_context(|| format!("Invalid {}", path))?;
+            info!(
+                "Loaded presets {} from {}",
+                presets.keys().cloned().collect::<Vec<_>>().join(", "),
+                path
+            );
+            presets
+        }
+        Err(_) => builtins(),
+    };
+    let _ = PRESETS.set(presets);
+    Ok(())
+}
+
+// The preset with the given name, or an error listing the ones there are
+pub fn get(name: &str) -> Result<&'static Preset> {
+    let presets = PRESETS.get_or_init(builtins);
+    presets.get(name).ok_or_else(|| {
+        anyhow!(
+            "Unknown preset {}: expected one of {}",
+            name,
+            presets.keys().cloned().collect::<Vec<_>>().join(", ")
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        for preset in builtins().values() {
+            preset.validate().unwrap();
+        }
+
+        let presets = parse(
+            r#"
+            [preset.card]
+            size_px = 400
+            tileset = "swisstopo"
+
+            [preset.poster]
+            radius = 10.0
+            filter = "sepia"
+            format = "png"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(presets["card"].size_px(), Some(400));
+        assert_eq!(presets["hero"].size_px(), Some(1600));
+        assert_eq!(presets["poster"].size_px(), None);
+
+        let mut body = Map::from_iter([("radius".to_string(), json!(2.0))]);
+        presets["poster"].apply_to_body(&mut body);
+        assert_eq!(body["radius"], 2.0);
+        assert_eq!(body["filter"], "sepia");
+        let mut query = HashMap::from([("filter".to_string(), "dark".to_string())]);
+        presets["card"].apply_to_query(&mut query);
+        presets["poster"].apply_to_query(&mut query);
+        assert_eq!(query["filter"], "dark");
+        assert_eq!(query["radius"], "10.0");
+        assert_eq!(query["tileset"], "swisstopo");
+        assert!(!query.contains_key("size_px"));
+
+        for invalid in [
+            "[preset.card]\nsize_px = 0",
+            "[preset.card]\nsize_px = \"big\"",
+            "[preset.card]\nfilter = \"neon\"",
+            "[preset.card]\nformat = \"gif\"",
+            "[presets.card]\nradius = 1.0",
+        ] {
+            assert!(parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
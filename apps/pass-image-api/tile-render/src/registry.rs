@@ -0,0 +1,496 @@
+// ! # registry
+// ! Every tileset the service knows about, loaded once at startup. The built-in tilesets
+// ! are seeded into it first, and operators add more without recompiling: TILESETS_CONFIG
+// ! points at a TOML file with a [[tileset]] table for each source:
+// !
+// !     [[tileset]]
+// !     name = "topo"
+// !     url = "https://tiles.example.com/topo/{z}/{x}/{y}.png"
+// !     tile_size = 512
+// !     scheme = "xyz"
+// !     content_type = "image/png"
+// !     attribution = "(c) Example Mapping"
+// !     licensed = true
+// !     headers = { Referer = "https://maps.example.com/" }
+// !
+// ! Each source becomes a TileSet::Custom, asked for by name with tileset= like the
+// ! built-in tilesets and fetched over HTTP from its URL template, which takes the same
+// ! placeholders as a TILESET_<NAME>_URL override. Its headers go out with every fetch.
+// ! tile_size is the size its tiles decode to, 256, 512 or 1024, and renders are mosaicked
+// ! at the largest among their layers; tiles that aren't that size are corrupt. It
+// ! defaults to 256, and without a content_type PNG and JPEG are both accepted. A
+// ! content_type of "application/vnd.mapbox-vector-tile" fetches vector tiles, which are
+// ! drawn with a built-in style (see mvt). scheme is "tms" for servers that number rows
+// ! from the bottom of the world rather than the top, whose {y} is flipped when it's
+// ! filled in; it defaults to "xyz". TILESET_<NAME>_* settings, such as coverage and
+// ! tokens, apply to them as well. Upstreams that want mutual TLS take client_cert,
+// ! client_key and ca_cert, paths to PEM files (see tls).
+// !
+// ! retina names another tileset in the config that serves the same map with at least
+// ! twice the tile size, such as a provider's @2x endpoint. Renders with scale=2 or more
+// ! fetch that one instead, so they're sharp rather than upsampled, e.g. for streets:
+// !
+// !     [[tileset]]
+// !     name = "streets"
+// !     url = "https://tiles.example.com/streets/{z}/{x}/{y}.png"
+// !     attribution = "(c) Example Mapping"
+// !     retina = "streets_2x"
+// !
+// !     [[tileset]]
+// !     name = "streets_2x"
+// !     url = "https://tiles.example.com/streets/{z}/{x}/{y}@2x.png"
+// !     tile_size = 512
+// !     attribution = "(c) Example Mapping"
+// !
+// ! Mapbox and Stadia's Stamen styles come with retina tilesets of their own, mapbox_2x
+// ! and stamen_*_2x, which share their tokens. Tilesets without one are upsampled.
+// !
+// ! A TileSet is a handle on a registry entry, its index. The built-in tilesets come first,
+// ! in the order of TileSet::ALL, so the renderer can treat the ones it has to, like
+// ! debug and hybrid, specially by name. Until the registry is loaded, or without
+// ! TILESETS_CONFIG, only the built-in tilesets exist.
+
+use crate::{dem, mvt};
+use anyhow::{anyhow, Context as _, Result};
+use log::info;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::sync::OnceLock;
+
+const TILE_SIZES: [u32; 3] = [256, 512, 1024];
+const CONTENT_TYPES: [&str; 3] = ["image/png", "image/jpeg", mvt::CONTENT_TYPE];
+
+static REGISTRY: OnceLock<Vec<Source>> = OnceLock::new();
+
+// A tileset, built in or from the config
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Source {
+    pub name: String,
+    pub url: String,
+    #[serde(default = "default_tile_size")]
+    pub tile_size: u32,
+    #[serde(default)]
+    pub scheme: Scheme,
+    pub content_type: Option<String>,
+    pub attribution: String,
+    #[serde(default)]
+    pub licensed: bool,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    pub retina: Option<String>,
+    // PEM files for mTLS with the upstream, see tls
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    pub ca_cert: Option<String>,
+    // Built-in tilesets that need an API token nobody gave us can't be asked for
+    #[serde(skip)]
+    pub disabled: bool,
+}
+
+fn default_tile_size() -> u32 {
+    256
+}
+
+// How an upstream numbers its rows: from the top, as OSM and most tile servers do, or
+// from the bottom, as TMS servers do
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+    #[default]
+    Xyz,
+    Tms,
+}
+
+impl Scheme {
+    pub fn from_name(name: &str) -> Result<Scheme> {
+        match name {
+            "xyz" => Ok(Scheme::Xyz),
+            "tms" => Ok(Scheme::Tms),
+            _ => Err(anyhow!("Unknown tile scheme {}: expected xyz or tms", name)),
+        }
+    }
+
+    // The row the upstream has a tile in, for its row from the top at zoom z
+    pub fn row(&self, y: u32, z: u32) -> u32 {
+        match self {
+            Scheme::Xyz => y,
+            Scheme::Tms => ((1u64 << z) - 1).saturating_sub(y as u64) as u32,
+        }
+    }
+}
+
+// The built-in tilesets, in the order of TileSet::ALL, and then the retina tilesets of
+// the providers with @2x endpoints. Debug and hybrid tiles aren't fetched, so they have
+// no URL.
+fn builtins() -> Vec<Source> {
+    let source = |name: &str, url: &str, attribution: &str, licensed: bool| Source {
+        name: name.to_string(),
+        url: url.to_string(),
+        tile_size: default_tile_size(),
+        scheme: Scheme::Xyz,
+        content_type: None,
+        attribution: attribution.to_string(),
+        licensed,
+        headers: BTreeMap::new(),
+        retina: None,
+        client_cert: None,
+        client_key: None,
+        ca_cert: None,
+        disabled: false,
+    };
+    let retina = |mut source: Source, name: &str| {
+        source.retina = Some(name.to_string());
+        source
+    };
+    let sharper = |name: &str, url: &str, tile_size: u32, attribution: &str| Source {
+        tile_size,
+        ..source(name, url, attribution, true)
+    };
+    let mapbox = "(c) Mapbox (c) OpenStreetMap contributors";
+    let stamen =
+        "(c) Stadia Maps (c) Stamen Design (c) OpenMapTiles (c) OpenStreetMap contributors";
+    let mut sources = vec![
+        source(
+            "osm",
+            "https://tile.openstreetmap.org/{z}/{x}/{y}.png",
+            "(c) OpenStreetMap contributors",
+            false,
+        ),
+        source(
+            "swisstopo",
+            "https://wmts.geo.admin.ch/1.0.0/ch.swisstopo.landeskarte-farbe-10/default/current/3857/{z}/{x}/{y}.png",
+            "(c) swisstopo",
+            true,
+        ),
+        source(
+            "terrain",
+            dem::terrain_url(),
+            "Terrain tiles (c) Mapzen and others",
+            false,
+        ),
+        source("debug", "", "Debug tiles", false),
+        // Bing's takes the tile's quadkey rather than its z/x/y; see fetcher::tile_url
+        source(
+            "bing",
+            "https://ecn.t0.tiles.virtualearth.net/tiles/a{quadkey}.jpeg?g=1",
+            "(c) Microsoft",
+            true,
+        ),
+        // Mapbox's and Stadia's have a {token} placeholder for their API tokens
+        retina(
+            source(
+                "mapbox",
+                "https://api.mapbox.com/styles/v1/mapbox/outdoors-v12/tiles/256/{z}/{x}/{y}?access_token={token}",
+                mapbox,
+                true,
+            ),
+            "mapbox_2x",
+        ),
+        source(
+            "esri",
+            "https://server.arcgisonline.com/ArcGIS/rest/services/World_Imagery/MapServer/tile/{z}/{y}/{x}",
+            "Source: Esri, Maxar, Earthstar Geographics",
+            true,
+        ),
+        source(
+            "esri_roads",
+            "https://server.arcgisonline.com/ArcGIS/rest/services/Reference/World_Transportation/MapServer/tile/{z}/{y}/{x}",
+            "Source: Esri, HERE, Garmin (c) OpenStreetMap contributors",
+            true,
+        ),
+        source(
+            "esri_labels",
+            "https://server.arcgisonline.com/ArcGIS/rest/services/Reference/World_Boundaries_and_Places/MapServer/tile/{z}/{y}/{x}",
+            "Source: Esri, HERE, Garmin (c) OpenStreetMap contributors",
+            true,
+        ),
+        source(
+            "hybrid",
+            "",
+            "Source: Esri, Maxar, Earthstar Geographics, HERE, Garmin (c) OpenStreetMap contributors",
+            true,
+        ),
+        retina(
+            source(
+                "stamen_terrain",
+                "https://tiles.stadiamaps.com/tiles/stamen_terrain/{z}/{x}/{y}.png?api_key={token}",
+                stamen,
+                true,
+            ),
+            "stamen_terrain_2x",
+        ),
+        retina(
+            source(
+                "stamen_watercolor",
+                "https://tiles.stadiamaps.com/tiles/stamen_watercolor/{z}/{x}/{y}.jpg?api_key={token}",
+                stamen,
+                true,
+            ),
+            "stamen_watercolor_2x",
+        ),
+        // Mapbox's 512px style tiles at @2x are 1024px, for the same z/x/y
+        sharper(
+            "mapbox_2x",
+            "https://api.mapbox.com/styles/v1/mapbox/outdoors-v12/tiles/512/{z}/{x}/{y}@2x?access_token={token}",
+            1024,
+            mapbox,
+        ),
+        sharper(
+            "stamen_terrain_2x",
+            "https://tiles.stadiamaps.com/tiles/stamen_terrain/{z}/{x}/{y}@2x.png?api_key={token}",
+            512,
+            stamen,
+        ),
+        sharper(
+            "stamen_watercolor_2x",
+            "https://tiles.stadiamaps.com/tiles/stamen_watercolor/{z}/{x}/{y}@2x.jpg?api_key={token}",
+            512,
+            stamen,
+        ),
+    ];
+
+    // Tilesets that need an API token are opt-in, so a deployment without one doesn't
+    // offer them only to fail every render. They're enabled by their TILESET_<NAME>_TOKEN,
+    // or a TILESET_<NAME>_URL or _SOURCE, which are checked for the token when they're
+    // read. Retina tilesets are enabled by the token of the tileset they sharpen.
+    let set = |name: &str, setting: &str| {
+        env::var(format!("TILESET_{}_{}", name.to_uppercase(), setting)).is_ok()
+    };
+    let enabled: Vec<bool> = sources
+        .iter()
+        .map(|source| {
+            let sharpens = sources
+                .iter()
+                .find(|s| s.retina.as_ref() == Some(&source.name));
+            !source.url.contains("{token}")
+                || ["TOKEN", "URL", "SOURCE"]
+                    .iter()
+                    .any(|setting| set(&source.name, setting))
+                || sharpens.is_some_and(|s| set(&s.name, "TOKEN"))
+        })
+        .collect();
+    for (source, enabled) in sources.iter_mut().zip(enabled) {
+        source.disabled = !enabled;
+    }
+    sources
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default)]
+    tileset: Vec<Source>,
+}
+
+impl Source {
+    fn validate(&self) -> Result<()> {
+        let valid_name = !self.name.is_empty()
+            && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if !valid_name {
+            return Err(anyhow!(
+                "Tileset names may only contain lowercase letters, digits and '_'"
+            ));
+        }
+        let has_xyz = ["{z}", "{x}", "{y}"].iter().all(|p| self.url.contains(p));
+        if !has_xyz && !self.url.contains("{quadkey}") {
+            return Err(anyhow!(
+                "{}'s url needs {{z}}, {{x}} and {{y}} placeholders, or {{quadkey}}",
+                self.name
+            ));
+        }
+        if self.scheme == Scheme::Tms && !has_xyz {
+            return Err(anyhow!("{}'s tms scheme needs a {{y}} to flip", self.name));
+        }
+        if !TILE_SIZES.contains(&self.tile_size) {
+            return Err(anyhow!(
+                "{}'s tile_size must be 256, 512 or 1024",
+                self.name
+            ));
+        }
+        if let Some(content_type) = &self.content_type {
+            if !CONTENT_TYPES.contains(&content_type.as_str()) {
+                return Err(anyhow!(
+                    "{}'s content_type must be one of {}",
+                    self.name,
+                    CONTENT_TYPES.join(", ")
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Parses and checks a registry config, returning the sources it adds to the built-in ones
+pub fn parse(config: &str) -> Result<Vec<Source>> {
+    let config: Config = toml::from_str(config)?;
+    let builtins = builtins();
+    for (i, source) in config.tileset.iter().enumerate() {
+        source.validate()?;
+        if builtins.iter().any(|s| s.name == source.name)
+            || config.tileset[..i].iter().any(|s| s.name == source.name)
+        {
+            return Err(anyhow!("There's already a tileset named {}", source.name));
+        }
+    }
+    for source in &config.tileset {
+        let Some(retina) = &source.retina else {
+            continue;
+        };
+        let sharper = config
+            .tileset
+            .iter()
+            .find(|s| &s.name == retina)
+            .ok_or_else(|| {
+                anyhow!(
+                    "{}'s retina tileset {} isn't in the config",
+                    source.name,
+                    retina
+                )
+            })?;
+        if sharper.tile_size < 2 * source.tile_size {
+            return Err(anyhow!(
+                "{}'s retina tileset {} needs tiles at least twice its {}px",
+                source.name,
+                retina,
+                source.tile_size
+            ));
+        }
+    }
+    if builtins.len() + config.tileset.len() > u16::MAX as usize {
+        return Err(anyhow!("Too many tilesets"));
+    }
+    Ok(config.tileset)
+}
+
+// Seeds the built-in tilesets and adds the sources in TILESETS_CONFIG. This should be
+// called once at startup, before anything else reads tileset settings from the
+// environment; it's an error if the registry has been read already.
+pub fn init_from_env() -> Result<()> {
+    let Ok(path) = env::var("TILESETS_CONFIG") else {
+        sources();
+        return Ok(());
+    };
+    let config = fs::read_to_string(&path).with_context(|| format!("reading {}", path))?;
+    let sources = parse(&config).with_context(|| format!("Invalid {}", path))?;
+    for source in &sources {
+        info!("Registered tileset {} from {}", source.name, path);
+    }
+    REGISTRY
+        .set(builtins().into_iter().chain(sources).collect())
+        .map_err(|_| anyhow!("The tileset registry was read before it was configured"))
+}
+
+// Every tileset, the built-in ones first
+pub fn sources() -> &'static [Source] {
+    REGISTRY.get_or_init(builtins)
+}
+
+pub fn source(index: u16) -> Option<&'static Source> {
+    sources().get(index as usize)
+}
+
+// The index of the source with the given name, which is its TileSet's
+pub fn lookup(name: &str) -> Option<u16> {
+    sources()
+        .iter()
+        .position(|source| source.name == name)
+        .map(|index| index as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let sources = parse(
+            r#"
+            [[tileset]]
+            name = "topo"
+            url = "https://tiles.example.com/topo/{z}/{x}/{y}.png"
+            tile_size = 512
+            scheme = "tms"
+            attribution = "(c) Example Mapping"
+            headers = { Referer = "https://maps.example.com/" }
+
+            [[tileset]]
+            name = "aerial_2020"
+            url = "https://tiles.example.com/aerial/{quadkey}.jpeg"
+            content_type = "image/jpeg"
+            attribution = "(c) Example Aerials"
+            licensed = true
+            retina = "aerial_2020_2x"
+
+            [[tileset]]
+            name = "aerial_2020_2x"
+            url = "https://tiles.example.com/aerial/{quadkey}@2x.jpeg"
+            tile_size = 512
+            attribution = "(c) Example Aerials"
+            licensed = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(sources.len(), 3);
+        assert_eq!(sources[1].retina.as_deref(), Some("aerial_2020_2x"));
+        assert_eq!(sources[0].tile_size, 512);
+        assert_eq!(
+            (sources[0].scheme, sources[1].scheme),
+            (Scheme::Tms, Scheme::Xyz)
+        );
+        assert_eq!(sources[0].headers["Referer"], "https://maps.example.com/");
+        assert!(!sources[0].licensed);
+        assert_eq!((sources[1].tile_size, sources[1].licensed), (256, true));
+        assert!(parse("").unwrap().is_empty());
+        assert!(parse(&format!(
+            "[[tileset]]\nname = \"vector\"\nattribution = \"x\"\nurl = \"https://t.test/{{z}}/{{x}}/{{y}}.pbf\"\ncontent_type = \"{}\"",
+            mvt::CONTENT_TYPE
+        ))
+        .is_ok());
+
+        let source = |fields: &str| {
+            format!(
+                "[[tileset]]\nattribution = \"x\"\nurl = \"https://t.test/{{z}}/{{x}}/{{y}}.png\"\n{}",
+                fields
+            )
+        };
+        for invalid in [
+            source("name = \"Topo-Map\""),
+            source("name = \"osm\""),
+            source("name = \"topo\"\ntile_size = 300"),
+            source("name = \"topo\"\ncontent_type = \"image/webp\""),
+            source("name = \"topo\"\nscheme = \"wmts\""),
+            source("name = \"topo\"\nzoom = 3"),
+            source("name = \"topo\"\nretina = \"osm\""),
+            format!(
+                "{}\n{}",
+                source("name = \"topo\"\nretina = \"topo_2x\"\ntile_size = 512"),
+                source("name = \"topo_2x\"\ntile_size = 512")
+            ),
+            format!(
+                "{}\n{}",
+                source("name = \"topo\""),
+                source("name = \"topo\"")
+            ),
+            "[[tileset]]\nname = \"topo\"\nattribution = \"x\"\nurl = \"https://t.test/\""
+                .to_string(),
+            "[[tileset]]\nname = \"topo\"\nattribution = \"x\"\nscheme = \"tms\"\n\
+             url = \"https://t.test/{quadkey}\""
+                .to_string(),
+        ] {
+            assert!(parse(&invalid).is_err(), "{}", invalid);
+        }
+
+        // TMS rows count up from the bottom
+        assert_eq!(Scheme::Tms.row(0, 3), 7);
+        assert_eq!(Scheme::Tms.row(5, 3), 2);
+        assert_eq!(Scheme::Tms.row(0, 0), 0);
+        assert_eq!(Scheme::Xyz.row(5, 3), 5);
+        assert!(Scheme::from_name("TMS").is_err());
+    }
+}
@@ -0,0 +1,173 @@
+// ! # report
+// ! A breakdown of what went into a render: how many tiles it needed and how many of
+// ! those came from the cache, how long each phase took, how many bytes went in and out,
+// ! the zoom the tiles were fetched at and which tilesets they came from. Tiles that
+// ! failed or timed out are listed by key, up to MAX_FAILED_TILES of them, so one bad tile
+// ! can be told apart from a provider that's down.
+// !
+// ! Reports are collected by running a render in collect, which gives the render its own
+// ! report to fill in, in the way faults::scope gives a request its own plan. The pipeline
+// ! records into whichever report it's running under, and outside one recording does
+// ! nothing, so renders that nobody asked a report for don't pay for one.
+
+use serde::Serialize;
+use std::cell::RefCell;
+use std::future::Future;
+use std::time::Duration;
+
+// The most failed tiles a report lists. The rest are only counted.
+pub const MAX_FAILED_TILES: usize = 32;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RenderReport {
+    // The zoom the basemap's tiles were fetched at
+    pub zoom: Option<u32>,
+    // The tilesets tiles were fetched from, in the order they were first used
+    pub providers: Vec<String>,
+    pub tiles: u32,
+    // Tiles served from the tile cache, and tiles fetched from their source
+    pub tiles_cached: u32,
+    pub tiles_fetched: u32,
+    // Tiles that failed or timed out, including ones a failover then fetched from
+    // another tileset
+    pub tiles_failed: u32,
+    pub failed_tiles: Vec<FailedTile>,
+    // Bytes of tiles read, and of images encoded
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    // Time spent in each phase, in the order they ran
+    pub phases: Vec<Phase>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Phase {
+    pub name: &'static str,
+    pub ms: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FailedTile {
+    pub tileset: String,
+    // As z/x/y
+    pub tile: String,
+    // "timeout" or "error"
+    pub reason: &'static str,
+    pub error: String,
+}
+
+tokio::task_local! {
+    static REPORT: RefCell<RenderReport>;
+}
+
+// Runs a render, returning its output along with its report
+pub async fn collect<F: Future>(f: F) -> (F::Output, RenderReport) {
+    REPORT
+        .scope(RefCell::new(RenderReport::default()), async {
+            let output = f.await;
+            let mut report = REPORT.with(|report| report.take());
+            report.tiles_fetched = report.tiles.saturating_sub(report.tiles_cached);
+            (output, report)
+        })
+        .await
+}
+
+// Updates the current report, if there is one
+fn record(update: impl FnOnce(&mut RenderReport)) {
+    let _ = REPORT.try_with(|report| update(&mut report.borrow_mut()));
+}
+
+// Records a tile fetched for a tileset. Tiles that were fetched before are counted again,
+// as renders don't share tiles.
+pub fn tile(tileset: &str, bytes: usize) {
+    record(|report| {
+        report.tiles += 1;
+        report.bytes_in += bytes as u64;
+        if !report.providers.iter().any(|p| p == tileset) {
+            report.providers.push(tileset.to_string());
+        }
+    });
+}
+
+// Records a tile that couldn't be fetched
+pub fn tile_failed(tileset: &str, tile: String, reason: &'static str, error: String) {
+    record(|report| {
+        report.tiles_failed += 1;
+        if report.failed_tiles.len() < MAX_FAILED_TILES {
+            report.failed_tiles.push(FailedTile {
+                tileset: tileset.to_string(),
+                tile,
+                reason,
+                error,
+            });
+        }
+    });
+}
+
+// Records that a tile came from the cache
+pub fn cache_hit() {
+    record(|report| report.tiles_cached += 1);
+}
+
+pub fn zoom(zoom: u32) {
+    record(|report| {
+        report.zoom.get_or_insert(zoom);
+    });
+}
+
+// Records time spent in a phase. A phase that runs more than once, e.g. for each variant
+// of an image, adds up.
+pub fn phase(name: &'static str, elapsed: Duration) {
+    let ms = elapsed.as_secs_f64() * 1000.0;
+    record(
+        |report| match report.phases.iter_mut().find(|p| p.name == name) {
+            Some(phase) => phase.ms += ms,
+            None => report.phases.push(Phase { name, ms }),
+        },
+    );
+}
+
+pub fn bytes_out(bytes: usize) {
+    record(|report| report.bytes_out += bytes as u64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_collect() {
+        let ((), report) = collect(async {
+            zoom(14);
+            zoom(12);
+            tile("osm", 100);
+            tile("osm", 50);
+            tile("terrain", 10);
+            cache_hit();
+            phase("fetch", Duration::from_millis(5));
+            phase("encode", Duration::from_millis(2));
+            phase("fetch", Duration::from_millis(5));
+            bytes_out(42);
+            for x in 0..MAX_FAILED_TILES + 1 {
+                tile_failed("osm", format!("12/{}/5", x), "timeout", "timed out".into());
+            }
+        })
+        .await;
+
+        assert_eq!(report.zoom, Some(14));
+        assert_eq!(report.providers, vec!["osm", "terrain"]);
+        assert_eq!(
+            (report.tiles, report.tiles_cached, report.tiles_fetched),
+            (3, 1, 2)
+        );
+        assert_eq!((report.bytes_in, report.bytes_out), (160, 42));
+        assert_eq!(report.phases.len(), 2);
+        assert_eq!(report.phases[0].name, "fetch");
+        assert!((report.phases[0].ms - 10.0).abs() < 1e-9);
+        assert_eq!(report.tiles_failed as usize, MAX_FAILED_TILES + 1);
+        assert_eq!(report.failed_tiles.len(), MAX_FAILED_TILES);
+        assert_eq!(report.failed_tiles[1].tile, "12/1/5");
+
+        // Outside a report, recording does nothing
+        tile("osm", 100);
+    }
+}
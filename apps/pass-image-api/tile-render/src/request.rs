@@ -0,0 +1,453 @@
+// ! # request
+// ! Render requests. RenderParams are the styling options shared by every way of
+// ! asking for an image, whether they come from a query string or a JSON body.
+// ! ImageRequest is a whole render as JSON: the body of POST /images and POST /jobs, and
+// ! the spec files read by pass-image-cli. Unlike a query string it can carry GeoJSON
+// ! overlays, focus areas and crop polygons.
+// !
+// ! Requests that would render the same image can differ: map UIs send coordinates to a
+// ! dozen decimals and layer settings in whatever order they hold them. canonicalize
+// ! rounds and sorts those away, so such requests share a cache_key.
+
+use crate::coordinates::LatLong;
+use crate::crop::{self, Crop};
+use crate::dither::Palette;
+use crate::effects::{self, Adjustments, Equalize, Filter, Resample, ToneCurve};
+use crate::focus::{self, Focus};
+use crate::frame::{Frame, Mask};
+use crate::layers::LayerSettings;
+use crate::overlay::{self, Overlay};
+use crate::slope;
+use crate::tiles::{self, Format, RenderOptions, TileSet};
+use crate::{archive, avif, contours, locale};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+// Coordinates are rounded to this many decimals, about 10cm, far below a pixel at the
+// highest zoom
+pub const COORD_DECIMALS: i32 = 6;
+
+// Styling options for a render
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct RenderParams {
+    // grayscale, sepia or dark
+    pub filter: Option<String>,
+    // Multipliers, 1.0 leaves the map unchanged
+    pub brightness: Option<f32>,
+    pub contrast: Option<f32>,
+    pub saturation: Option<f32>,
+    // Gamma correction, above 1 brightens shadows
+    pub gamma: Option<f32>,
+    // Tone curve control points, e.g. 0:0,64:110,255:255
+    pub curve: Option<String>,
+    // stretch or histogram, for dark or hazy imagery
+    pub equalize: Option<String>,
+    // Unsharp mask and Gaussian blur sigmas, in pixels
+    pub sharpen: Option<f32>,
+    pub blur: Option<f32>,
+    // Basemaps to composite, e.g. osm:1.0,swisstopo:0.5
+    pub blend: Option<String>,
+    // Factor to resize the output by, e.g. 0.5 for thumbnails
+    pub scale: Option<f32>,
+    // nearest, bilinear, catmullrom or lanczos3
+    pub resample: Option<String>,
+    // Supersampling factor for overlays, 1 (off) to 4
+    pub aa: Option<u32>,
+    // Resize the output to exactly the requested size
+    pub exact: Option<bool>,
+    // circle
+    pub mask: Option<String>,
+    // Rounds the image's corners, in pixels
+    pub corner_radius: Option<u32>,
+    // Border width in pixels, and its hex color
+    pub border: Option<u32>,
+    pub border_color: Option<String>,
+    // Drop shadow size in pixels
+    pub shadow: Option<u32>,
+    // Hex color of the canvas behind the image, transparent by default
+    pub background: Option<String>,
+    // Render the overlays alone, without a basemap
+    pub overlays_only: Option<bool>,
+    // Contour interval in meters
+    pub contours: Option<f32>,
+    // Shade slopes of 30 degrees and steeper, at the given opacity
+    pub slope: Option<bool>,
+    pub slope_opacity: Option<f32>,
+    // Merge nearby markers into count badges. By default only dense overlays are clustered.
+    pub cluster: Option<bool>,
+    // 1bit, gray4 or eink7, dithered for e-paper displays
+    pub palette: Option<String>,
+    // Remap the basemap's reds and greens to a color-blind-safe palette
+    pub colorblind: Option<bool>,
+    // Highlight this radius in km around the point, dimming or desaturating the rest
+    pub focus: Option<f32>,
+    // dim or desaturate
+    pub focus_effect: Option<String>,
+    // From 0 to 1
+    pub focus_strength: Option<f32>,
+    // Per-layer overrides as layer:value lists, e.g. lines:0.6,markers:0.8 and lines:45
+    pub layer_opacity: Option<String>,
+    pub layer_z: Option<String>,
+    // Outline color and width for the crop polygon on POST bodies
+    pub crop_outline: Option<String>,
+    pub crop_outline_width: Option<f32>,
+    // Draw a scale bar in the bottom left corner
+    pub scale_bar: Option<bool>,
+    // Credit every tileset in the image, not just the licensed ones that require it
+    pub attribution: Option<bool>,
+    // png, avif, or geojson-extent for where the image would lie rather than the image
+    pub format: Option<String>,
+    // AVIF quality, from 1 to 100
+    pub quality: Option<u8>,
+}
+
+impl RenderParams {
+    // Sorts layer:value lists by layer and leaves out switches that are off, which is
+    // what they default to
+    fn canonicalize(&mut self) {
+        for list in [&mut self.layer_opacity, &mut self.layer_z]
+            .into_iter()
+            .flatten()
+        {
+            let mut entries: Vec<&str> = list.split(',').map(str::trim).collect();
+            entries.sort_by_key(|entry| entry.split(':').next());
+            *list = entries.join(",");
+        }
+        for switch in [
+            &mut self.exact,
+            &mut self.overlays_only,
+            &mut self.slope,
+            &mut self.colorblind,
+            &mut self.scale_bar,
+            &mut self.attribution,
+        ] {
+            if *switch == Some(false) {
+                *switch = None;
+            }
+        }
+        if self.format.as_deref() == Some("png") {
+            self.format = None;
+        }
+    }
+
+    // Validates the parameters and turns them into RenderOptions for the given overlays,
+    // focus polygon and crop polygon rings
+    pub fn render_options(
+        &self,
+        overlays: Vec<Overlay>,
+        focus_area: Option<Vec<LatLong>>,
+        crop_rings: Option<Vec<Vec<LatLong>>>,
+    ) -> Result<RenderOptions> {
+        let filter = self
+            .filter
+            .as_deref()
+            .map(str::parse::<Filter>)
+            .transpose()?;
+
+        let adjustment = |name: &str, value: Option<f32>| match value {
+            Some(value) => effects::validate_adjustment(name, value),
+            None => Ok(1.0),
+        };
+        let adjustments = Adjustments {
+            brightness: adjustment("brightness", self.brightness)?,
+            contrast: adjustment("contrast", self.contrast)?,
+            saturation: adjustment("saturation", self.saturation)?,
+        };
+
+        let gamma = self.gamma.map(effects::validate_gamma).transpose()?;
+        let equalize = self
+            .equalize
+            .as_deref()
+            .map(str::parse::<Equalize>)
+            .transpose()?;
+        let curve = self
+            .curve
+            .as_deref()
+            .map(str::parse::<ToneCurve>)
+            .transpose()?;
+
+        let sharpen = self.sharpen.map(effects::validate_sharpen).transpose()?;
+        let blur = self.blur.map(effects::validate_blur).transpose()?;
+
+        let blend = match &self.blend {
+            Some(spec) => tiles::parse_blend(spec)?,
+            None => Vec::new(),
+        };
+
+        let scale = self.scale.map(effects::validate_scale).transpose()?;
+        let resample = self
+            .resample
+            .as_deref()
+            .map(str::parse::<Resample>)
+            .transpose()?
+            .unwrap_or_default();
+
+        let antialias = match self.aa {
+            Some(aa) if !(1..=overlay::MAX_SUPERSAMPLE).contains(&aa) => {
+                return Err(anyhow!(
+                    "aa must be between 1 and {}",
+                    overlay::MAX_SUPERSAMPLE
+                ))
+            }
+            Some(aa) => aa,
+            None => 1,
+        };
+
+        let contour_interval = self.contours.map(contours::validate_interval).transpose()?;
+        let slope_opacity = match (self.slope, self.slope_opacity) {
+            (Some(true), opacity) => Some(slope::validate_opacity(
+                opacity.unwrap_or(slope::DEFAULT_OPACITY),
+            )?),
+            _ => None,
+        };
+
+        let palette = self
+            .palette
+            .as_deref()
+            .map(str::parse::<Palette>)
+            .transpose()?;
+
+        let focus = Focus::from_params(
+            self.focus,
+            focus_area,
+            self.focus_effect.as_deref(),
+            self.focus_strength,
+        )?;
+
+        let format = match self.format.as_deref() {
+            Some("avif") => Format::Avif {
+                quality: match self.quality {
+                    Some(quality) => avif::validate_quality(quality)?,
+                    None => avif::default_quality(),
+                },
+            },
+            Some(other) if other != "png" && other != "geojson-extent" => {
+                return Err(anyhow!(
+                    "Unknown format {}: expected png, avif or geojson-extent",
+                    other
+                ))
+            }
+            _ if self.quality.is_some() => {
+                return Err(anyhow!("quality only applies to format=avif"))
+            }
+            _ => Format::Png,
+        };
+
+        let layer_settings =
+            LayerSettings::from_params(self.layer_opacity.as_deref(), self.layer_z.as_deref())?;
+
+        let crop = Crop::from_params(
+            crop_rings,
+            self.crop_outline.as_deref(),
+            self.crop_outline_width,
+        )?;
+
+        let mask = Mask::from_params(self.mask.as_deref(), self.corner_radius)?;
+        let frame = Frame::from_params(
+            self.border,
+            self.border_color.as_deref(),
+            self.shadow,
+            self.background.as_deref(),
+        )?;
+
+        Ok(RenderOptions {
+            overlays,
+            filter,
+            adjustments,
+            gamma,
+            equalize,
+            curve,
+            sharpen,
+            blur,
+            blend,
+            scale,
+            resample,
+            antialias,
+            exact_size: self.exact.unwrap_or(false),
+            mask,
+            frame,
+            overlays_only: self.overlays_only.unwrap_or(false),
+            contour_interval,
+            slope_opacity,
+            cluster: self.cluster,
+            palette,
+            colorblind_safe: self.colorblind.unwrap_or(false),
+            focus,
+            layer_settings,
+            crop,
+            scale_bar: self.scale_bar.unwrap_or(false),
+            attribute_all: self.attribution.unwrap_or(false),
+            format,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ImageRequest {
+    pub long: f64,
+    pub lat: f64,
+    pub size_px: u32,
+    #[serde(default = "default_radius")]
+    pub radius: f32,
+    #[serde(default)]
+    pub tileset: Option<String>,
+    // A GeoJSON FeatureCollection, Feature or Geometry to draw over the map
+    #[serde(default)]
+    pub overlay: Option<Value>,
+    // A GeoJSON polygon to highlight, in place of the focus radius
+    #[serde(default)]
+    pub focus_area: Option<Value>,
+    // A GeoJSON polygon to crop the output to
+    #[serde(default)]
+    pub crop: Option<Value>,
+    #[serde(flatten)]
+    pub params: RenderParams,
+}
+
+fn default_radius() -> f32 {
+    1.0
+}
+
+impl ImageRequest {
+    pub fn center(&self) -> LatLong {
+        LatLong(self.lat, self.long)
+    }
+
+    pub fn tileset(&self) -> TileSet {
+        self.tileset
+            .as_deref()
+            .map(TileSet::from_name)
+            .unwrap_or(TileSet::Osm)
+    }
+
+    pub fn render_options(&self) -> Result<RenderOptions> {
+        let overlays = match &self.overlay {
+            Some(geojson) => overlay::from_geojson(geojson)?,
+            None => Vec::new(),
+        };
+        let focus_area = self
+            .focus_area
+            .as_ref()
+            .map(focus::polygon_from_geojson)
+            .transpose()?;
+        let crop_rings = self
+            .crop
+            .as_ref()
+            .map(crop::rings_from_geojson)
+            .transpose()?;
+        self.params.render_options(overlays, focus_area, crop_rings)
+    }
+
+    // Rounds the coordinates, names the tileset and puts the parameters in order, leaving
+    // the image the request renders as it was
+    pub fn canonicalize(&mut self) {
+        let factor = 10f64.powi(COORD_DECIMALS);
+        self.long = (self.long * factor).round() / factor;
+        self.lat = (self.lat * factor).round() / factor;
+        self.tileset = Some(self.tileset().name().to_string());
+        self.params.canonicalize();
+    }
+
+    // Identifies the image the request renders under the current locale and archive
+    // scopes. Requests should be canonicalized first, so near-identical ones share it.
+    pub fn cache_key(&self) -> String {
+        let date = archive::date()
+            .map(|date| format!("{:04}-{:02}-{:02}", date.year, date.month, date.day));
+        json!({
+            "request": self,
+            "languages": locale::languages(),
+            "date": date,
+        })
+        .to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> ImageRequest {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let request = parse(
+            r#"{"long": 8.1, "lat": 46.6, "size_px": 512,
+                "overlay": {"type": "LineString", "coordinates": [[8.1, 46.6], [8.2, 46.7]]}}"#,
+        );
+        assert_eq!(request.radius, 1.0);
+        assert_eq!(request.tileset(), TileSet::Osm);
+        assert_eq!(request.render_options().unwrap().overlays.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_render_params() {
+        let request = parse(r#"{"long": 8.1, "lat": 46.6, "size_px": 512, "filter": "sepia"}"#);
+        assert_eq!(
+            request.render_options().unwrap().filter,
+            Some(Filter::Sepia)
+        );
+
+        let request = parse(r#"{"long": 8.1, "lat": 46.6, "size_px": 512, "filter": "neon"}"#);
+        assert!(request.render_options().is_err());
+
+        let format = |params: &str| {
+            let json = format!(
+                r#"{{"long": 8.1, "lat": 46.6, "size_px": 512, {}}}"#,
+                params
+            );
+            parse(&json).render_options().map(|options| options.format)
+        };
+        assert_eq!(
+            format(r#""format": "avif", "quality": 40"#).unwrap(),
+            Format::Avif { quality: 40 }
+        );
+        assert_eq!(
+            format(r#""format": "geojson-extent""#).unwrap(),
+            Format::Png
+        );
+        for invalid in [
+            r#""format": "gif""#,
+            r#""format": "avif", "quality": 0"#,
+            r#""quality": 40"#,
+        ] {
+            assert!(format(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cache_key() {
+        let key = |json: &str| {
+            let mut request = parse(json);
+            request.canonicalize();
+            request.cache_key()
+        };
+        let canonical = key(
+            r#"{"long": 8.1, "lat": 46.6, "size_px": 512, "tileset": "osm",
+                "layer_opacity": "markers:0.8,lines:0.6"}"#,
+        );
+        assert_eq!(
+            key(r#"{"long": 8.10000004, "lat": 46.59999999, "size_px": 512,
+                    "layer_opacity": "lines:0.6, markers:0.8", "scale_bar": false,
+                    "format": "png"}"#),
+            canonical
+        );
+        assert_ne!(
+            key(r#"{"long": 8.1001, "lat": 46.6, "size_px": 512,
+                    "layer_opacity": "markers:0.8,lines:0.6"}"#),
+            canonical
+        );
+
+        // The languages and date the request renders in are part of it
+        let json = r#"{"long": 8.1, "lat": 46.6, "size_px": 512}"#;
+        let default = key(json);
+        let german = locale::scope(vec!["de".to_string()], async { key(json) }).await;
+        let dated =
+            archive::scope(archive::Date::parse("1900").unwrap(), async { key(json) }).await;
+        assert_ne!(german, default);
+        assert_ne!(dated, default);
+        assert!(dated.contains("1900-12-31"));
+    }
+}
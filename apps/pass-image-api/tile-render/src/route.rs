@@ -0,0 +1,585 @@
+// ! # route
+// ! Styling for line overlays beyond a plain stroke: dash patterns, widths given in meters
+// ! on the ground, color gradients along the path and arrowheads showing its direction.
+// ! Styles come from extra simplestyle-like properties on each GeoJSON feature:
+// !
+// !   stroke-dasharray   - [on, off, ...] lengths in pixels, e.g. [8, 4]
+// !   stroke-width-unit  - px (default) or m
+// !   stroke-gradient    - ["#rrggbb", ...] colors from the start of the path to its end
+// !   stroke-gradient-by - distance (default) or elevation, the third value of each position
+// !   stroke-arrows      - true to draw arrowheads along the path
+// !
+
+use crate::coordinates::meters_per_pixel;
+use crate::overlay::{clamp_span, parse_color, segment_pixels, Viewport};
+use crate::text::blend_pixel;
+use anyhow::{anyhow, Result};
+use image::{Rgba, RgbaImage};
+use serde_json::Value;
+use std::collections::HashMap;
+
+// Limits on dash patterns and gradients, to keep rendering cheap
+const MAX_DASHES: usize = 8;
+const MIN_DASH_PX: f64 = 1.0;
+const MAX_DASH_PX: f64 = 1000.0;
+const MAX_GRADIENT_COLORS: usize = 8;
+
+// Gradients are drawn in pieces of at most this length, each a single color
+const GRADIENT_STEP_PX: f64 = 4.0;
+
+// Distance between arrowheads along a path. There's always one at the end.
+const ARROW_SPACING_PX: f64 = 150.0;
+const MIN_ARROW_PX: f64 = 8.0;
+
+type Point = (f64, f64);
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LineStyle {
+    // Alternating on and off lengths in pixels; empty for a solid line
+    pub dashes: Vec<f32>,
+    // Whether the line's width is in meters on the ground rather than pixels
+    pub width_in_meters: bool,
+    pub gradient: Option<Gradient>,
+    pub arrows: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gradient {
+    // Evenly spaced colors, from the start of the path to its end
+    pub colors: Vec<Rgba<u8>>,
+    pub by: GradientBy,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GradientBy {
+    // How far along the path each point is
+    Distance,
+    // The elevation of each point, lowest to highest
+    Elevation(Vec<f32>),
+}
+
+impl LineStyle {
+    // Reads the style from a feature's properties. Elevations are the third value of each
+    // of the line's positions, if they all have one.
+    pub fn from_properties(properties: &Value, elevations: Option<Vec<f32>>) -> Result<LineStyle> {
+        let dashes = match properties.get("stroke-dasharray") {
+            Some(value) => {
+                let dashes = value
+                    .as_array()
+                    .ok_or_else(|| anyhow!("stroke-dasharray must be an array"))?
+                    .iter()
+                    .map(|d| {
+                        d.as_f64()
+                            .filter(|d| (MIN_DASH_PX..=MAX_DASH_PX).contains(d))
+                            .map(|d| d as f32)
+                            .ok_or_else(|| {
+                                anyhow!(
+                                    "stroke-dasharray lengths must be between {} and {} pixels",
+                                    MIN_DASH_PX,
+                                    MAX_DASH_PX
+                                )
+                            })
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                if dashes.len() > MAX_DASHES {
+                    return Err(anyhow!(
+                        "stroke-dasharray can have at most {} lengths",
+                        MAX_DASHES
+                    ));
+                }
+                dashes
+            }
+            None => Vec::new(),
+        };
+
+        let width_in_meters = match properties.get("stroke-width-unit").and_then(Value::as_str) {
+            None | Some("px") => false,
+            Some("m") => true,
+            Some(other) => return Err(anyhow!("Unknown stroke-width-unit {}", other)),
+        };
+
+        let gradient = match properties.get("stroke-gradient") {
+            Some(value) => {
+                let colors = value
+                    .as_array()
+                    .ok_or_else(|| anyhow!("stroke-gradient must be an array of colors"))?
+                    .iter()
+                    .map(|c| {
+                        c.as_str()
+                            .and_then(parse_color)
+                            .ok_or_else(|| anyhow!("Invalid stroke-gradient color {}", c))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                if !(2..=MAX_GRADIENT_COLORS).contains(&colors.len()) {
+                    return Err(anyhow!(
+                        "stroke-gradient needs between 2 and {} colors",
+                        MAX_GRADIENT_COLORS
+                    ));
+                }
+                let by = match properties.get("stroke-gradient-by").and_then(Value::as_str) {
+                    None | Some("distance") => GradientBy::Distance,
+                    Some("elevation") => GradientBy::Elevation(elevations.ok_or_else(|| {
+                        anyhow!("stroke-gradient-by elevation needs an elevation in every position")
+                    })?),
+                    Some(other) => return Err(anyhow!("Unknown stroke-gradient-by {}", other)),
+                };
+                Some(Gradient { colors, by })
+            }
+            None => None,
+        };
+
+        Ok(LineStyle {
+            dashes,
+            width_in_meters,
+            gradient,
+            arrows: properties
+                .get("stroke-arrows")
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
+        })
+    }
+
+    // The same style with its pixel lengths multiplied by factor
+    pub fn scaled(&self, factor: f32) -> LineStyle {
+        LineStyle {
+            dashes: self.dashes.iter().map(|d| d * factor).collect(),
+            ..self.clone()
+        }
+    }
+}
+
+impl Gradient {
+    // The color at t, from 0 at the start of the gradient to 1 at the end
+    fn color_at(&self, t: f32) -> Rgba<u8> {
+        let position = t.clamp(0.0, 1.0) * (self.colors.len() - 1) as f32;
+        let i = (position.floor() as usize).min(self.colors.len() - 2);
+        let f = position - i as f32;
+        let (a, b) = (self.colors[i], self.colors[i + 1]);
+        Rgba(std::array::from_fn(|c| {
+            (a[c] as f32 + (b[c] as f32 - a[c] as f32) * f).round() as u8
+        }))
+    }
+}
+
+// The width in image pixels of a line `meters` wide at the given latitude
+pub fn width_in_pixels(meters: f32, lat: f64, viewport: &Viewport) -> f32 {
+    (meters as f64 / meters_per_pixel(lat, viewport.zoom) * viewport.scale) as f32
+}
+
+// Draws a polyline in pixel coordinates with the given style. As with plain lines,
+// pixels are collected first and blended once each.
+pub fn draw_route(
+    img: &mut RgbaImage,
+    pixels: &[Point],
+    width: f32,
+    color: Rgba<u8>,
+    style: &LineStyle,
+) {
+    if pixels.len() < 2 {
+        return;
+    }
+    let lengths = cumulative_lengths(pixels);
+    let stops = gradient_stops(&lengths, style);
+    let color_at = |t: f32| match &style.gradient {
+        Some(gradient) => gradient.color_at(t),
+        None => color,
+    };
+
+    // Only the part of the path near the image is split up, so long lines running far
+    // off the edge stay cheap
+    let margin = width as f64 + MIN_ARROW_PX;
+    let bounds = (
+        (-margin, -margin),
+        (img.width() as f64 + margin, img.height() as f64 + margin),
+    );
+
+    let mut covered: HashMap<(i64, i64), Rgba<u8>> = HashMap::new();
+    for (from, to, t0, t1) in pieces(pixels, &stops, &style.dashes, bounds) {
+        let steps = match style.gradient {
+            Some(_) => ((distance(from, to) / GRADIENT_STEP_PX).ceil() as usize).max(1),
+            None => 1,
+        };
+        for i in 0..steps {
+            let (a, b) = (i as f64 / steps as f64, (i + 1) as f64 / steps as f64);
+            let color = color_at(lerp(t0, t1, (a + b) / 2.0));
+            for pixel in segment_pixels(img, along(from, to, a), along(from, to, b), width) {
+                covered.insert(pixel, color);
+            }
+        }
+    }
+
+    if style.arrows {
+        let size = (width as f64 * 3.0).max(MIN_ARROW_PX);
+        for (tip, direction, t) in arrowheads(pixels, &lengths, &stops) {
+            for pixel in triangle_pixels(img, tip, direction, size) {
+                covered.insert(pixel, color_at(t));
+            }
+        }
+    }
+
+    for ((x, y), color) in covered {
+        blend_pixel(img, x, y, color);
+    }
+}
+
+// How far along the path each point is, in pixels
+fn cumulative_lengths(pixels: &[Point]) -> Vec<f64> {
+    let mut total = 0.0;
+    let mut lengths = vec![0.0];
+    for pair in pixels.windows(2) {
+        total += distance(pair[0], pair[1]);
+        lengths.push(total);
+    }
+    lengths
+}
+
+// Each point's position in the gradient, from 0 to 1
+fn gradient_stops(lengths: &[f64], style: &LineStyle) -> Vec<f32> {
+    let normalize = |values: &[f32]| {
+        let min = values.iter().copied().fold(f32::INFINITY, f32::min);
+        let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let range = max - min;
+        values
+            .iter()
+            .map(|v| if range > 0.0 { (v - min) / range } else { 0.0 })
+            .collect()
+    };
+    match style.gradient.as_ref().map(|g| &g.by) {
+        Some(GradientBy::Elevation(elevations)) if elevations.len() == lengths.len() => {
+            normalize(elevations)
+        }
+        _ => normalize(&lengths.iter().map(|l| *l as f32).collect::<Vec<_>>()),
+    }
+}
+
+// Splits the path into the pieces to draw, skipping the gaps in any dash pattern and
+// anything outside the bounds, along with the gradient position at each end
+fn pieces(
+    pixels: &[Point],
+    stops: &[f32],
+    dashes: &[f32],
+    bounds: (Point, Point),
+) -> Vec<(Point, Point, f32, f32)> {
+    // An odd number of lengths is repeated, as in SVG, so on and off alternate
+    let pattern: Vec<f64> = match dashes.len() % 2 {
+        0 => dashes.iter().map(|d| *d as f64).collect(),
+        _ => dashes.iter().chain(dashes).map(|d| *d as f64).collect(),
+    };
+    let period: f64 = pattern.iter().sum();
+
+    let mut pieces = Vec::new();
+    // How far into the dash pattern the start of the current segment is
+    let mut phase = 0.0;
+    for (pair, t) in pixels.windows(2).zip(stops.windows(2)) {
+        let (from, to) = (pair[0], pair[1]);
+        let length = distance(from, to);
+        let piece = |a: f64, b: f64| {
+            (
+                along(from, to, a),
+                along(from, to, b),
+                lerp(t[0], t[1], a),
+                lerp(t[0], t[1], b),
+            )
+        };
+
+        if let Some((start, end)) = clip(from, to, bounds) {
+            if pattern.is_empty() {
+                pieces.push(piece(start, end));
+            } else {
+                // Walk the visible part a dash at a time
+                let offset = start * length;
+                let visible = (end - start) * length;
+                let mut dash_phase = (phase + offset) % period;
+                let mut walked = 0.0;
+                while walked < visible {
+                    let (index, into) = dash_at(&pattern, dash_phase);
+                    let step = (pattern[index] - into).min(visible - walked);
+                    if index % 2 == 0 {
+                        pieces.push(piece(
+                            (offset + walked) / length,
+                            (offset + walked + step) / length,
+                        ));
+                    }
+                    walked += step;
+                    dash_phase = (dash_phase + step) % period;
+                }
+            }
+        }
+        if period > 0.0 {
+            phase = (phase + length) % period;
+        }
+    }
+    pieces
+}
+
+// Which length of the pattern a phase falls in, and how far into it
+fn dash_at(pattern: &[f64], phase: f64) -> (usize, f64) {
+    let mut rest = phase;
+    for (i, length) in pattern.iter().enumerate() {
+        if rest < *length {
+            return (i, rest);
+        }
+        rest -= length;
+    }
+    (0, 0.0)
+}
+
+// Where arrowheads go along the path: the tip, the direction it points in, and its
+// position in the gradient
+fn arrowheads(pixels: &[Point], lengths: &[f64], stops: &[f32]) -> Vec<(Point, Point, f32)> {
+    let total = *lengths.last().unwrap_or(&0.0);
+    if total == 0.0 {
+        return Vec::new();
+    }
+
+    let mut distances: Vec<f64> = (1..)
+        .map(|i| i as f64 * ARROW_SPACING_PX)
+        .take_while(|d| *d < total - ARROW_SPACING_PX / 2.0)
+        .collect();
+    distances.push(total);
+
+    distances
+        .into_iter()
+        .filter_map(|d| {
+            // The last segment that starts before d and has some length to point along
+            let i = lengths
+                .partition_point(|l| *l < d)
+                .clamp(1, pixels.len() - 1)
+                - 1;
+            let (from, to) = (pixels[i], pixels[i + 1]);
+            let length = distance(from, to);
+            if length == 0.0 {
+                return None;
+            }
+            let f = (d - lengths[i]) / length;
+            let direction = ((to.0 - from.0) / length, (to.1 - from.1) / length);
+            Some((
+                along(from, to, f),
+                direction,
+                lerp(stops[i], stops[i + 1], f),
+            ))
+        })
+        .collect()
+}
+
+// The pixels inside an arrowhead with its tip at `tip`, pointing along the unit vector
+// `direction`
+fn triangle_pixels(img: &RgbaImage, tip: Point, direction: Point, size: f64) -> Vec<(i64, i64)> {
+    let base = (tip.0 - direction.0 * size, tip.1 - direction.1 * size);
+    let half = size * 0.6;
+    let normal = (-direction.1 * half, direction.0 * half);
+    let corners = [
+        tip,
+        (base.0 + normal.0, base.1 + normal.1),
+        (base.0 - normal.0, base.1 - normal.1),
+    ];
+
+    let xs = corners.map(|c| c.0);
+    let ys = corners.map(|c| c.1);
+    let (min_x, max_x) = clamp_span(
+        xs.iter().copied().fold(f64::INFINITY, f64::min),
+        xs.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+        0.0,
+        img.width(),
+    );
+    let (min_y, max_y) = clamp_span(
+        ys.iter().copied().fold(f64::INFINITY, f64::min),
+        ys.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+        0.0,
+        img.height(),
+    );
+
+    let side = |a: Point, b: Point, p: Point| (b.0 - a.0) * (p.1 - a.1) - (b.1 - a.1) * (p.0 - a.0);
+    let mut pixels = Vec::new();
+    for py in min_y..=max_y {
+        for px in min_x..=max_x {
+            let p = (px as f64 + 0.5, py as f64 + 0.5);
+            let sides = [
+                side(corners[0], corners[1], p),
+                side(corners[1], corners[2], p),
+                side(corners[2], corners[0], p),
+            ];
+            if sides.iter().all(|s| *s >= 0.0) || sides.iter().all(|s| *s <= 0.0) {
+                pixels.push((px, py));
+            }
+        }
+    }
+    pixels
+}
+
+// The part of the segment inside the bounds, as fractions along it
+fn clip(from: Point, to: Point, bounds: (Point, Point)) -> Option<(f64, f64)> {
+    let ((min_x, min_y), (max_x, max_y)) = bounds;
+    let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+    let (mut start, mut end) = (0.0_f64, 1.0_f64);
+    for (p, q) in [
+        (-dx, from.0 - min_x),
+        (dx, max_x - from.0),
+        (-dy, from.1 - min_y),
+        (dy, max_y - from.1),
+    ] {
+        if p == 0.0 {
+            if q < 0.0 {
+                return None;
+            }
+        } else if p < 0.0 {
+            start = start.max(q / p);
+        } else {
+            end = end.min(q / p);
+        }
+    }
+    (start < end).then_some((start, end))
+}
+
+fn distance(a: Point, b: Point) -> f64 {
+    (b.0 - a.0).hypot(b.1 - a.1)
+}
+
+fn along(from: Point, to: Point, f: f64) -> Point {
+    (from.0 + (to.0 - from.0) * f, from.1 + (to.1 - from.1) * f)
+}
+
+fn lerp(a: f32, b: f32, f: f64) -> f32 {
+    a + (b - a) * f as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+    const BLUE: Rgba<u8> = Rgba([0, 0, 255, 255]);
+
+    #[test]
+    fn test_from_properties() {
+        let style = LineStyle::from_properties(
+            &json!({
+                "stroke-dasharray": [8, 4],
+                "stroke-width-unit": "m",
+                "stroke-gradient": ["#ff0000", "#0000ff"],
+                "stroke-arrows": true
+            }),
+            None,
+        )
+        .unwrap();
+        assert_eq!(style.dashes, vec![8.0, 4.0]);
+        assert!(style.width_in_meters);
+        assert_eq!(style.gradient.unwrap().by, GradientBy::Distance);
+        assert!(style.arrows);
+
+        assert_eq!(
+            LineStyle::from_properties(&Value::Null, None).unwrap(),
+            LineStyle::default()
+        );
+        assert!(LineStyle::from_properties(&json!({ "stroke-dasharray": [0, 4] }), None).is_err());
+        assert!(LineStyle::from_properties(&json!({ "stroke-width-unit": "ft" }), None).is_err());
+        // Elevation gradients need elevations
+        let by_elevation = json!({
+            "stroke-gradient": ["#ff0000", "#0000ff"],
+            "stroke-gradient-by": "elevation"
+        });
+        assert!(LineStyle::from_properties(&by_elevation, None).is_err());
+        assert!(LineStyle::from_properties(&by_elevation, Some(vec![1.0, 2.0])).is_ok());
+    }
+
+    #[test]
+    fn test_gradient_color_at() {
+        let gradient = Gradient {
+            colors: vec![RED, BLUE],
+            by: GradientBy::Distance,
+        };
+        assert_eq!(gradient.color_at(0.0), RED);
+        assert_eq!(gradient.color_at(1.0), BLUE);
+        assert_eq!(gradient.color_at(0.5), Rgba([128, 0, 128, 255]));
+    }
+
+    #[test]
+    fn test_dashes() {
+        let mut img = RgbaImage::new(64, 8);
+        let style = LineStyle {
+            dashes: vec![8.0, 8.0],
+            ..LineStyle::default()
+        };
+        draw_route(&mut img, &[(0.0, 4.0), (64.0, 4.0)], 2.0, RED, &style);
+        assert_eq!(img.get_pixel(4, 4), &RED);
+        assert_eq!(img.get_pixel(12, 4)[3], 0);
+        assert_eq!(img.get_pixel(20, 4), &RED);
+    }
+
+    #[test]
+    fn test_dashes_carry_across_points() {
+        // The pattern continues from one segment to the next rather than restarting
+        let pieces = pieces(
+            &[(0.0, 0.0), (6.0, 0.0), (16.0, 0.0)],
+            &[0.0, 0.4, 1.0],
+            &[4.0, 4.0],
+            ((-10.0, -10.0), (100.0, 100.0)),
+        );
+        assert_eq!(pieces.len(), 2);
+        assert!((pieces[0].0 .0 - 0.0).abs() < 1e-9);
+        assert!((pieces[1].0 .0 - 8.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_gradient_by_distance() {
+        let mut img = RgbaImage::new(64, 8);
+        let style = LineStyle {
+            gradient: Some(Gradient {
+                colors: vec![RED, BLUE],
+                by: GradientBy::Distance,
+            }),
+            ..LineStyle::default()
+        };
+        draw_route(&mut img, &[(0.0, 4.0), (64.0, 4.0)], 2.0, RED, &style);
+        assert!(img.get_pixel(1, 4)[0] > 200);
+        assert!(img.get_pixel(62, 4)[2] > 200);
+    }
+
+    #[test]
+    fn test_gradient_by_elevation() {
+        let style = LineStyle {
+            gradient: Some(Gradient {
+                colors: vec![RED, BLUE],
+                by: GradientBy::Elevation(vec![100.0, 300.0, 200.0]),
+            }),
+            ..LineStyle::default()
+        };
+        assert_eq!(
+            gradient_stops(&[0.0, 1.0, 2.0], &style),
+            vec![0.0, 1.0, 0.5]
+        );
+    }
+
+    #[test]
+    fn test_arrowheads() {
+        let pixels = [(0.0, 10.0), (400.0, 10.0)];
+        let lengths = cumulative_lengths(&pixels);
+        let arrows = arrowheads(&pixels, &lengths, &[0.0, 1.0]);
+        // At 150 and 300, and the end
+        assert_eq!(arrows.len(), 3);
+        assert_eq!(arrows[2].0, (400.0, 10.0));
+        assert_eq!(arrows[2].1, (1.0, 0.0));
+
+        // The arrowhead is wider than the line behind its tip
+        let mut img = RgbaImage::new(64, 32);
+        let style = LineStyle {
+            arrows: true,
+            ..LineStyle::default()
+        };
+        draw_route(&mut img, &[(0.0, 16.0), (60.0, 16.0)], 2.0, RED, &style);
+        assert_eq!(img.get_pixel(53, 18), &RED);
+        assert_eq!(img.get_pixel(30, 18)[3], 0);
+    }
+
+    #[test]
+    fn test_offscreen_dashes_are_cheap() {
+        let mut img = RgbaImage::new(16, 16);
+        let style = LineStyle {
+            dashes: vec![1.0, 1.0],
+            ..LineStyle::default()
+        };
+        draw_route(&mut img, &[(-1.0e9, 8.0), (1.0e9, 8.0)], 2.0, RED, &style);
+        assert!(img.pixels().any(|p| p == &RED));
+    }
+}
@@ -0,0 +1,144 @@
+// ! # scale_bar
+// ! A scale bar in the bottom left corner, so distances can be read off the map. The bar
+// ! is the longest round distance (1, 2 or 5 times a power of ten) that fits in a quarter
+// ! of the image's width, measured at the latitude of the image's center, as Web Mercator
+// ! stretches distances away from the equator.
+
+use crate::coordinates::{meters_per_pixel, LatLong};
+use crate::overlay::Viewport;
+use crate::text::{draw_text, fill_rect, text_height, text_width};
+use image::{Rgba, RgbaImage};
+
+// How much of the image's width the bar may take
+const MAX_WIDTH_FRACTION: f64 = 0.25;
+
+// Ground meters covered by one pixel of the image at the given point
+fn image_meters_per_pixel(viewport: &Viewport, at: LatLong) -> f64 {
+    meters_per_pixel(at.0, viewport.zoom) / viewport.scale
+}
+
+// The longest round distance no longer than max_m
+fn round_distance(max_m: f64) -> f64 {
+    let magnitude = 10f64.powf(max_m.log10().floor());
+    [5.0, 2.0, 1.0]
+        .into_iter()
+        .map(|step| step * magnitude)
+        .find(|&d| d <= max_m)
+        .unwrap_or(magnitude)
+}
+
+fn format_distance(meters: f64) -> String {
+    if meters >= 1000.0 {
+        format!("{} km", meters / 1000.0)
+    } else {
+        format!("{} m", meters)
+    }
+}
+
+pub fn draw_scale_bar(img: &mut RgbaImage, viewport: &Viewport, center: LatLong) {
+    let meters_per_px = image_meters_per_pixel(viewport, center);
+    if !meters_per_px.is_finite() || meters_per_px <= 0.0 {
+        return;
+    }
+    let distance = round_distance(img.width() as f64 * MAX_WIDTH_FRACTION * meters_per_px);
+    let length = (distance / meters_per_px).round() as u32;
+    let label = format_distance(distance);
+
+    // Sized to match the attribution in the opposite corner
+    let scale = if img.width() >= 1024 { 2 } else { 1 };
+    let padding = 2 * scale;
+    let thickness = 2 * scale;
+    let tick = 5 * scale;
+    let box_width = length.max(text_width(&label, scale)) + 2 * padding;
+    let box_height = text_height(scale) + tick + 3 * padding;
+    let box_x = 0;
+    let box_y = img.height() as i64 - box_height as i64;
+    fill_rect(
+        img,
+        box_x,
+        box_y,
+        box_width,
+        box_height,
+        Rgba([255, 255, 255, 192]),
+    );
+
+    let black = Rgba([0, 0, 0, 255]);
+    draw_text(
+        img,
+        box_x + padding as i64,
+        box_y + padding as i64,
+        &label,
+        scale,
+        black,
+    );
+    // The bar, with ticks rising from both ends
+    let bar_x = box_x + padding as i64;
+    let bar_y = box_y + (box_height - padding - thickness) as i64;
+    fill_rect(img, bar_x, bar_y, length, thickness, black);
+    let tick_y = bar_y + thickness as i64 - tick as i64;
+    fill_rect(img, bar_x, tick_y, thickness, tick, black);
+    fill_rect(
+        img,
+        bar_x + length as i64 - thickness as i64,
+        tick_y,
+        thickness,
+        tick,
+        black,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use float_cmp::approx_eq;
+    use image::GenericImageView;
+
+    #[test]
+    fn test_round_distance() {
+        assert_eq!(round_distance(740.0), 500.0);
+        assert_eq!(round_distance(1999.0), 1000.0);
+        assert_eq!(round_distance(2000.0), 2000.0);
+        assert_eq!(round_distance(37.0), 20.0);
+        assert_eq!(format_distance(500.0), "500 m");
+        assert_eq!(format_distance(2000.0), "2 km");
+    }
+
+    #[test]
+    fn test_meters_per_pixel() {
+        let viewport = Viewport {
+            zoom: 14,
+            origin: (0.0, 0.0),
+            scale: 1.0,
+        };
+        let equator = image_meters_per_pixel(&viewport, LatLong(0.0, 0.0));
+        assert!(approx_eq!(f64, equator, 9.554, epsilon = 0.001));
+        // A resize to half the size doubles it, and it shrinks towards the poles
+        assert!(approx_eq!(
+            f64,
+            image_meters_per_pixel(&viewport.scaled(0.5), LatLong(0.0, 0.0)),
+            equator * 2.0,
+            epsilon = 1e-9
+        ));
+        assert!(image_meters_per_pixel(&viewport, LatLong(46.6, 8.1)) < equator);
+    }
+
+    #[test]
+    fn test_draw_scale_bar() {
+        let mut img = RgbaImage::from_pixel(512, 512, Rgba([0, 128, 0, 255]));
+        let viewport = Viewport {
+            zoom: 14,
+            origin: (0.0, 0.0),
+            scale: 1.0,
+        };
+        draw_scale_bar(&mut img, &viewport, LatLong(46.6, 8.1));
+        // The bar is drawn in the bottom left, and the rest of the image is untouched
+        let black = img
+            .view(0, 480, 140, 32)
+            .pixels()
+            .filter(|(_, _, p)| p.0 == [0, 0, 0, 255])
+            .count();
+        assert!(black > 100);
+        assert_eq!(*img.get_pixel(511, 511), Rgba([0, 128, 0, 255]));
+        assert_eq!(*img.get_pixel(256, 256), Rgba([0, 128, 0, 255]));
+    }
+}
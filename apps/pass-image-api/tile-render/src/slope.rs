@@ -0,0 +1,124 @@
+// ! # slope
+// ! Slope-angle shading for avalanche awareness maps. The steepness of the terrain under
+// ! each pixel is worked out from an ElevationGrid, and slopes of 30° and more are tinted
+// ! by class, the way avalanche bulletins and backcountry maps classify them.
+
+use crate::dem::ElevationGrid;
+use crate::text;
+use anyhow::{anyhow, Result};
+use image::{Rgba, RgbaImage};
+
+// The default opacity of the shading, so the map underneath still reads
+pub const DEFAULT_OPACITY: f32 = 0.5;
+
+// Slope classes, steepest last: the lowest angle in degrees for each, and its color
+const CLASSES: [(f32, Rgba<u8>); 4] = [
+    (30.0, Rgba([255, 230, 0, 255])),
+    (35.0, Rgba([255, 140, 0, 255])),
+    (40.0, Rgba([230, 0, 0, 255])),
+    (45.0, Rgba([140, 0, 160, 255])),
+];
+
+pub fn validate_opacity(opacity: f32) -> Result<f32> {
+    if (0.0..=1.0).contains(&opacity) {
+        Ok(opacity)
+    } else {
+        Err(anyhow!("slope_opacity must be between 0 and 1"))
+    }
+}
+
+// The slope angle in degrees at a pixel, from the heights of its neighbours. Pixels at
+// the edges use the one neighbour they have; NaN where there's no data.
+pub fn slope_at(grid: &ElevationGrid, x: u32, y: u32) -> f32 {
+    let gradient = |before: f32, here: f32, after: f32, span: u32| -> f32 {
+        let (a, b, steps) = match (before.is_nan(), after.is_nan()) {
+            (false, false) => (before, after, span),
+            (true, false) => (here, after, 1),
+            (false, true) => (before, here, 1),
+            (true, true) => return f32::NAN,
+        };
+        (b - a) / (steps as f64 * grid.meters_per_pixel) as f32
+    };
+    let here = grid.get(x, y);
+    let at = |x: Option<u32>, y: Option<u32>| match (x, y) {
+        (Some(x), Some(y)) if x < grid.width && y < grid.height => grid.get(x, y),
+        _ => f32::NAN,
+    };
+
+    let dx = gradient(
+        at(x.checked_sub(1), Some(y)),
+        here,
+        at(Some(x + 1), Some(y)),
+        2,
+    );
+    let dy = gradient(
+        at(Some(x), y.checked_sub(1)),
+        here,
+        at(Some(x), Some(y + 1)),
+        2,
+    );
+    dx.hypot(dy).atan().to_degrees()
+}
+
+// The color for a slope angle, if it's steep enough to be shaded
+fn classify(angle: f32) -> Option<Rgba<u8>> {
+    CLASSES
+        .iter()
+        .rev()
+        .find(|(min, _)| angle >= *min)
+        .map(|(_, color)| *color)
+}
+
+// Tints the image by the slope class of the terrain under each pixel
+pub fn shade_slopes(img: &mut RgbaImage, grid: &ElevationGrid, opacity: f32) {
+    let alpha = (opacity * 255.0).round() as u8;
+    for (x, y, pixel) in img.enumerate_pixels_mut() {
+        if let Some(mut color) = classify(slope_at(grid, x, y)) {
+            color[3] = alpha;
+            text::blend(pixel, &color);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A plane rising `rise` meters per pixel to the east, at 10m per pixel
+    fn plane(rise: f32) -> ElevationGrid {
+        let data = (0..8)
+            .flat_map(|_| (0..8).map(move |x| x as f32 * rise))
+            .collect();
+        ElevationGrid::new(8, 8, data, 10.0)
+    }
+
+    #[test]
+    fn test_slope_at() {
+        // 10m up for every 10m across is 45°, including at the edges
+        let grid = plane(10.0);
+        assert!((slope_at(&grid, 4, 4) - 45.0).abs() < 0.01);
+        assert!((slope_at(&grid, 0, 0) - 45.0).abs() < 0.01);
+        assert!((slope_at(&grid, 7, 7) - 45.0).abs() < 0.01);
+        assert_eq!(slope_at(&plane(0.0), 4, 4), 0.0);
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify(20.0), None);
+        assert_eq!(classify(32.0), Some(CLASSES[0].1));
+        assert_eq!(classify(42.0), Some(CLASSES[2].1));
+        assert_eq!(classify(f32::NAN), None);
+    }
+
+    #[test]
+    fn test_shade_slopes() {
+        let mut img = RgbaImage::from_pixel(8, 8, Rgba([255, 255, 255, 255]));
+        // About 38°, in the orange class
+        shade_slopes(&mut img, &plane(7.8), 1.0);
+        assert_eq!(img.get_pixel(4, 4), &CLASSES[1].1);
+
+        let mut img = RgbaImage::from_pixel(8, 8, Rgba([255, 255, 255, 255]));
+        shade_slopes(&mut img, &plane(1.0), 1.0);
+        assert_eq!(img.get_pixel(4, 4), &Rgba([255, 255, 255, 255]));
+    }
+}
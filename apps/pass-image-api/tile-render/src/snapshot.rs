@@ -0,0 +1,302 @@
+// ! # snapshot
+// ! Helpers for pixel-level regression tests of overlay rendering, without the network.
+// ! synthetic_basemap stands in for real tiles with a checkerboard labelled with each
+// ! tile's z/x/y, and render draws the overlays over it with the real layer pipeline.
+// ! assert_snapshot compares an image with a PNG under snapshots/ using a perceptual
+// ! tolerance, so anti-aliasing noise doesn't fail a test but a real change does.
+// !
+// ! A missing snapshot is written on the first run, except on CI where it's an error.
+// ! Run with UPDATE_SNAPSHOTS=1 to rewrite snapshots after an intended change. On a
+// ! mismatch the expected and actual images and a diff are written to
+// ! SNAPSHOT_ARTIFACTS_DIR, or the temp directory if it isn't set, for CI to upload.
+
+use crate::overlay::Viewport;
+use crate::text::draw_text;
+use crate::tiles::{compose_layers, RenderOptions};
+use image::{Rgba, RgbaImage};
+use std::env;
+use std::path::PathBuf;
+
+const TILE_SIZE: f64 = 256.0;
+const LIGHT: Rgba<u8> = Rgba([225, 225, 220, 255]);
+const DARK: Rgba<u8> = Rgba([190, 190, 185, 255]);
+const LABEL_COLOR: Rgba<u8> = Rgba([90, 90, 90, 255]);
+
+// How far apart two pixels can be, from 0 to 1, before they count as different
+const PIXEL_THRESHOLD: f32 = 0.04;
+
+// The fraction of pixels that may differ before a snapshot fails
+pub const DEFAULT_TOLERANCE: f32 = 0.001;
+
+// A deterministic stand-in for the basemap under the viewport: tiles alternate between
+// two grays and carry their z/x/y in the top left corner
+pub fn synthetic_basemap(viewport: &Viewport, width: u32, height: u32) -> RgbaImage {
+    let tile_at = |x: f64, y: f64| {
+        (
+            ((viewport.origin.0 + x / viewport.scale) / TILE_SIZE).floor() as i64,
+            ((viewport.origin.1 + y / viewport.scale) / TILE_SIZE).floor() as i64,
+        )
+    };
+
+    let mut img = RgbaImage::from_fn(width, height, |x, y| {
+        let (tx, ty) = tile_at(x as f64 + 0.5, y as f64 + 0.5);
+        if (tx + ty).rem_euclid(2) == 0 {
+            LIGHT
+        } else {
+            DARK
+        }
+    });
+
+    let (first, last) = (tile_at(0.0, 0.0), tile_at(width as f64, height as f64));
+    for ty in first.1..=last.1 {
+        for tx in first.0..=last.0 {
+            let x = ((tx as f64 * TILE_SIZE - viewport.origin.0) * viewport.scale).round();
+            let y = ((ty as f64 * TILE_SIZE - viewport.origin.1) * viewport.scale).round();
+            let label = format!("{}/{}/{}", viewport.zoom, tx, ty);
+            draw_text(&mut img, x as i64 + 4, y as i64 + 4, &label, 1, LABEL_COLOR);
+        }
+    }
+    img
+}
+
+// Renders the options' overlays over the synthetic basemap. Slope shading and contours
+// need elevation data and are left out.
+pub fn render(viewport: &Viewport, width: u32, height: u32, options: &RenderOptions) -> RgbaImage {
+    compose_layers(
+        synthetic_basemap(viewport, width, height),
+        viewport,
+        options,
+        None,
+    )
+}
+
+#[derive(Debug)]
+pub struct Diff {
+    pub differing: usize,
+    pub total: usize,
+    // The largest difference between two pixels, from 0 to 1
+    pub max_delta: f32,
+    // Differing pixels in red over a faded copy of the expected image
+    pub image: RgbaImage,
+}
+
+impl Diff {
+    pub fn fraction(&self) -> f32 {
+        self.differing as f32 / self.total.max(1) as f32
+    }
+}
+
+// How different two pixels look, from 0 to 1. Both are flattened onto white, and the
+// channels are weighted by how sensitive the eye is to them (the "redmean" approximation).
+pub fn pixel_delta(a: &Rgba<u8>, b: &Rgba<u8>) -> f32 {
+    let flatten = |p: &Rgba<u8>| {
+        let alpha = p[3] as f32 / 255.0;
+        [0, 1, 2].map(|i| p[i] as f32 * alpha + 255.0 * (1.0 - alpha))
+    };
+    let (a, b) = (flatten(a), flatten(b));
+    let red_mean = (a[0] + b[0]) / 2.0;
+    let (dr, dg, db) = (a[0] - b[0], a[1] - b[1], a[2] - b[2]);
+    let distance = ((2.0 + red_mean / 256.0) * dr * dr
+        + 4.0 * dg * dg
+        + (2.0 + (255.0 - red_mean) / 256.0) * db * db)
+        .sqrt();
+    // The distance between black and white
+    distance / (9.0_f32 * 255.0 * 255.0).sqrt()
+}
+
+// Compares two images of the same size pixel by pixel
+pub fn perceptual_diff(actual: &RgbaImage, expected: &RgbaImage) -> Diff {
+    assert_eq!(
+        actual.dimensions(),
+        expected.dimensions(),
+        "images differ in size"
+    );
+    let mut image = RgbaImage::new(expected.width(), expected.height());
+    let mut differing = 0;
+    let mut max_delta: f32 = 0.0;
+    for ((a, e), out) in actual
+        .pixels()
+        .zip(expected.pixels())
+        .zip(image.pixels_mut())
+    {
+        let delta = pixel_delta(a, e);
+        max_delta = max_delta.max(delta);
+        *out = if delta > PIXEL_THRESHOLD {
+            differing += 1;
+            Rgba([255, 0, 0, 255])
+        } else {
+            Rgba([e[0], e[1], e[2], e[3] / 4])
+        };
+    }
+    Diff {
+        differing,
+        total: (expected.width() * expected.height()) as usize,
+        max_delta,
+        image,
+    }
+}
+
+// Fails if more than the tolerated fraction of pixels differ
+pub fn assert_similar(actual: &RgbaImage, expected: &RgbaImage, tolerance: f32) {
+    let diff = perceptual_diff(actual, expected);
+    assert!(
+        diff.fraction() <= tolerance,
+        "{} of {} pixels differ (max delta {:.3})",
+        diff.differing,
+        diff.total,
+        diff.max_delta
+    );
+}
+
+// Where mismatches are written
+fn artifacts_dir() -> PathBuf {
+    env::var("SNAPSHOT_ARTIFACTS_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| env::temp_dir())
+}
+
+fn snapshot_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("snapshots")
+        .join(format!("{}.png", name))
+}
+
+// Compares the image with the named snapshot, writing it if it's missing or
+// UPDATE_SNAPSHOTS is set
+pub fn assert_snapshot(name: &str, actual: &RgbaImage) {
+    assert_snapshot_within(name, actual, DEFAULT_TOLERANCE);
+}
+
+// As assert_snapshot, failing if more than the given fraction of pixels differ
+pub fn assert_snapshot_within(name: &str, actual: &RgbaImage, tolerance: f32) {
+    let path = snapshot_path(name);
+    let update = env::var("UPDATE_SNAPSHOTS").is_ok_and(|v| v == "1");
+    if update || !path.exists() {
+        assert!(
+            update || env::var("CI").is_err(),
+            "Snapshot {} is missing; run the tests with UPDATE_SNAPSHOTS=1 and commit it",
+            path.display()
+        );
+        std::fs::create_dir_all(path.parent().expect("snapshots have a directory"))
+            .expect("I can create the snapshot directory");
+        actual.save(&path).expect("I can write the snapshot");
+        return;
+    }
+
+    let expected = image::open(&path)
+        .unwrap_or_else(|e| panic!("Couldn't read snapshot {}: {}", path.display(), e))
+        .to_rgba8();
+    let out = artifacts_dir();
+    let _ = std::fs::create_dir_all(&out);
+    let actual_path = out.join(format!("{}.actual.png", name));
+    if actual.dimensions() != expected.dimensions() {
+        let _ = actual.save(&actual_path);
+        panic!(
+            "Snapshot {} is {:?} but the image is {:?}. See {}",
+            name,
+            expected.dimensions(),
+            actual.dimensions(),
+            actual_path.display()
+        );
+    }
+
+    let diff = perceptual_diff(actual, &expected);
+    if diff.fraction() > tolerance {
+        let expected_path = out.join(format!("{}.expected.png", name));
+        let diff_path = out.join(format!("{}.diff.png", name));
+        let _ = expected.save(&expected_path);
+        let _ = actual.save(&actual_path);
+        let _ = diff.image.save(&diff_path);
+        panic!(
+            "Snapshot {} doesn't match: {} of {} pixels differ, more than {:.2}% (max delta {:.3}). See {}",
+            name,
+            diff.differing,
+            diff.total,
+            tolerance * 100.0,
+            diff.max_delta,
+            diff_path.display()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coordinates::pixel_to_lat_long;
+    use crate::overlay::Overlay;
+    use crate::route::LineStyle;
+
+    // Straddles the corner of four tiles
+    const VIEWPORT: Viewport = Viewport {
+        zoom: 14,
+        origin: (2_187_392.0, 1_476_480.0),
+        scale: 1.0,
+    };
+
+    #[test]
+    fn test_synthetic_basemap() {
+        let a = synthetic_basemap(&VIEWPORT, 256, 256);
+        assert_eq!(a, synthetic_basemap(&VIEWPORT, 256, 256));
+
+        // Neighbouring tiles alternate
+        let (left, right) = (a.get_pixel(100, 200), a.get_pixel(200, 200));
+        assert_ne!(left, right);
+        assert!([LIGHT, DARK].contains(left) && [LIGHT, DARK].contains(right));
+    }
+
+    #[test]
+    fn test_perceptual_diff() {
+        let base = synthetic_basemap(&VIEWPORT, 64, 64);
+
+        let mut noisy = base.clone();
+        for pixel in noisy.pixels_mut().step_by(3) {
+            pixel[0] = pixel[0].saturating_add(2);
+        }
+        assert_eq!(perceptual_diff(&noisy, &base).differing, 0);
+        assert_similar(&noisy, &base, 0.0);
+
+        let mut changed = base.clone();
+        changed.put_pixel(10, 10, Rgba([255, 0, 0, 255]));
+        let diff = perceptual_diff(&changed, &base);
+        assert_eq!(diff.differing, 1);
+        assert_eq!(diff.image.get_pixel(10, 10), &Rgba([255, 0, 0, 255]));
+
+        // Fully transparent reads as white
+        assert_eq!(
+            pixel_delta(&Rgba([0, 0, 0, 0]), &Rgba([255, 255, 255, 255])),
+            0.0
+        );
+        assert!(
+            (pixel_delta(&Rgba([0, 0, 0, 255]), &Rgba([255, 255, 255, 255])) - 1.0).abs() < 1e-3
+        );
+    }
+
+    #[test]
+    fn test_route_with_marker_snapshot() {
+        let at = |x: f64, y: f64| {
+            pixel_to_lat_long(VIEWPORT.origin.0 + x, VIEWPORT.origin.1 + y, VIEWPORT.zoom)
+        };
+        let options = RenderOptions {
+            overlays: vec![
+                Overlay::Line {
+                    points: vec![at(20.0, 200.0), at(120.0, 90.0), at(230.0, 60.0)],
+                    color: Rgba([220, 40, 40, 255]),
+                    width: 4.0,
+                    style: LineStyle::default(),
+                    area: false,
+                },
+                Overlay::Point {
+                    point: at(120.0, 90.0),
+                    color: Rgba([40, 80, 220, 255]),
+                    radius: 6.0,
+                    label: Some("Grosse Scheidegg".to_string()),
+                    symbol: None,
+                },
+            ],
+            antialias: 2,
+            ..Default::default()
+        };
+        let image = render(&VIEWPORT, 256, 256, &options);
+        assert_snapshot("route_with_marker", &image);
+    }
+}
@@ -0,0 +1,312 @@
+// ! # text
+// ! Minimal text rendering using a built-in 5x7 bitmap font. It's not pretty, but it's
+// ! dependency free, deterministic, and plenty for attribution notices and labels.
+// ! Only printable ASCII is supported; anything else is drawn as '?'.
+
+use image::{Rgba, RgbaImage};
+
+pub const GLYPH_WIDTH: u32 = 5;
+pub const GLYPH_HEIGHT: u32 = 7;
+
+// Gap between glyphs, in unscaled pixels
+const GLYPH_SPACING: u32 = 1;
+
+// Column-major glyphs for ASCII 0x20..=0x7e. Each byte is one column, least significant
+// bit at the top.
+const FONT: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x00, 0x00, 0x5f, 0x00, 0x00], // !
+    [0x00, 0x07, 0x00, 0x07, 0x00], // "
+    [0x14, 0x7f, 0x14, 0x7f, 0x14], // #
+    [0x24, 0x2a, 0x7f, 0x2a, 0x12], // $
+    [0x23, 0x13, 0x08, 0x64, 0x62], // %
+    [0x36, 0x49, 0x55, 0x22, 0x50], // &
+    [0x00, 0x05, 0x03, 0x00, 0x00], // '
+    [0x00, 0x1c, 0x22, 0x41, 0x00], // (
+    [0x00, 0x41, 0x22, 0x1c, 0x00], // )
+    [0x08, 0x2a, 0x1c, 0x2a, 0x08], // *
+    [0x08, 0x08, 0x3e, 0x08, 0x08], // +
+    [0x00, 0x50, 0x30, 0x00, 0x00], // ,
+    [0x08, 0x08, 0x08, 0x08, 0x08], // -
+    [0x00, 0x60, 0x60, 0x00, 0x00], // .
+    [0x20, 0x10, 0x08, 0x04, 0x02], // /
+    [0x3e, 0x51, 0x49, 0x45, 0x3e], // 0
+    [0x00, 0x42, 0x7f, 0x40, 0x00], // 1
+    [0x42, 0x61, 0x51, 0x49, 0x46], // 2
+    [0x21, 0x41, 0x45, 0x4b, 0x31], // 3
+    [0x18, 0x14, 0x12, 0x7f, 0x10], // 4
+    [0x27, 0x45, 0x45, 0x45, 0x39], // 5
+    [0x3c, 0x4a, 0x49, 0x49, 0x30], // 6
+    [0x01, 0x71, 0x09, 0x05, 0x03], // 7
+    [0x36, 0x49, 0x49, 0x49, 0x36], // 8
+    [0x06, 0x49, 0x49, 0x29, 0x1e], // 9
+    [0x00, 0x36, 0x36, 0x00, 0x00], // :
+    [0x00, 0x56, 0x36, 0x00, 0x00], // ;
+    [0x08, 0x14, 0x22, 0x41, 0x00], // <
+    [0x14, 0x14, 0x14, 0x14, 0x14], // =
+    [0x00, 0x41, 0x22, 0x14, 0x08], // >
+    [0x02, 0x01, 0x51, 0x09, 0x06], // ?
+    [0x32, 0x49, 0x79, 0x41, 0x3e], // @
+    [0x7e, 0x11, 0x11, 0x11, 0x7e], // A
+    [0x7f, 0x49, 0x49, 0x49, 0x36], // B
+    [0x3e, 0x41, 0x41, 0x41, 0x22], // C
+    [0x7f, 0x41, 0x41, 0x22, 0x1c], // D
+    [0x7f, 0x49, 0x49, 0x49, 0x41], // E
+    [0x7f, 0x09, 0x09, 0x09, 0x01], // F
+    [0x3e, 0x41, 0x49, 0x49, 0x7a], // G
+    [0x7f, 0x08, 0x08, 0x08, 0x7f], // H
+    [0x00, 0x41, 0x7f, 0x41, 0x00], // I
+    [0x20, 0x40, 0x41, 0x3f, 0x01], // J
+    [0x7f, 0x08, 0x14, 0x22, 0x41], // K
+    [0x7f, 0x40, 0x40, 0x40, 0x40], // L
+    [0x7f, 0x02, 0x0c, 0x02, 0x7f], // M
+    [0x7f, 0x04, 0x08, 0x10, 0x7f], // N
+    [0x3e, 0x41, 0x41, 0x41, 0x3e], // O
+    [0x7f, 0x09, 0x09, 0x09, 0x06], // P
+    [0x3e, 0x41, 0x51, 0x21, 0x5e], // Q
+    [0x7f, 0x09, 0x19, 0x29, 0x46], // R
+    [0x46, 0x49, 0x49, 0x49, 0x31], // S
+    [0x01, 0x01, 0x7f, 0x01, 0x01], // T
+    [0x3f, 0x40, 0x40, 0x40, 0x3f], // U
+    [0x1f, 0x20, 0x40, 0x20, 0x1f], // V
+    [0x3f, 0x40, 0x38, 0x40, 0x3f], // W
+    [0x63, 0x14, 0x08, 0x14, 0x63], // X
+    [0x07, 0x08, 0x70, 0x08, 0x07], // Y
+    [0x61, 0x51, 0x49, 0x45, 0x43], // Z
+    [0x00, 0x7f, 0x41, 0x41, 0x00], // [
+    [0x02, 0x04, 0x08, 0x10, 0x20], // \
+    [0x00, 0x41, 0x41, 0x7f, 0x00], // ]
+    [0x04, 0x02, 0x01, 0x02, 0x04], // ^
+    [0x40, 0x40, 0x40, 0x40, 0x40], // _
+    [0x00, 0x01, 0x02, 0x04, 0x00], // `
+    [0x20, 0x54, 0x54, 0x54, 0x78], // a
+    [0x7f, 0x48, 0x44, 0x44, 0x38], // b
+    [0x38, 0x44, 0x44, 0x44, 0x20], // c
+    [0x38, 0x44, 0x44, 0x48, 0x7f], // d
+    [0x38, 0x54, 0x54, 0x54, 0x18], // e
+    [0x08, 0x7e, 0x09, 0x01, 0x02], // f
+    [0x0c, 0x52, 0x52, 0x52, 0x3e], // g
+    [0x7f, 0x08, 0x04, 0x04, 0x78], // h
+    [0x00, 0x44, 0x7d, 0x40, 0x00], // i
+    [0x20, 0x40, 0x44, 0x3d, 0x00], // j
+    [0x7f, 0x10, 0x28, 0x44, 0x00], // k
+    [0x00, 0x41, 0x7f, 0x40, 0x00], // l
+    [0x7c, 0x04, 0x18, 0x04, 0x78], // m
+    [0x7c, 0x08, 0x04, 0x04, 0x78], // n
+    [0x38, 0x44, 0x44, 0x44, 0x38], // o
+    [0x7c, 0x14, 0x14, 0x14, 0x08], // p
+    [0x08, 0x14, 0x14, 0x18, 0x7c], // q
+    [0x7c, 0x08, 0x04, 0x04, 0x08], // r
+    [0x48, 0x54, 0x54, 0x54, 0x20], // s
+    [0x04, 0x3f, 0x44, 0x40, 0x20], // t
+    [0x3c, 0x40, 0x40, 0x20, 0x7c], // u
+    [0x1c, 0x20, 0x40, 0x20, 0x1c], // v
+    [0x3c, 0x40, 0x30, 0x40, 0x3c], // w
+    [0x44, 0x28, 0x10, 0x28, 0x44], // x
+    [0x0c, 0x50, 0x50, 0x50, 0x3c], // y
+    [0x44, 0x64, 0x54, 0x4c, 0x44], // z
+    [0x00, 0x08, 0x36, 0x41, 0x00], // {
+    [0x00, 0x00, 0x7f, 0x00, 0x00], // |
+    [0x00, 0x41, 0x36, 0x08, 0x00], // }
+    [0x08, 0x04, 0x08, 0x10, 0x08], // ~
+];
+
+fn glyph(c: char) -> &'static [u8; 5] {
+    match c {
+        ' '..='~' => &FONT[c as usize - 0x20],
+        _ => &FONT['?' as usize - 0x20],
+    }
+}
+
+// The width in pixels of the given text at the given scale
+pub fn text_width(text: &str, scale: u32) -> u32 {
+    let chars = text.chars().count() as u32;
+    if chars == 0 {
+        return 0;
+    }
+    (chars * (GLYPH_WIDTH + GLYPH_SPACING) - GLYPH_SPACING) * scale
+}
+
+// The height in pixels of a line of text at the given scale
+pub fn text_height(scale: u32) -> u32 {
+    GLYPH_HEIGHT * scale
+}
+
+// Blends a color over a pixel. Pixel::blend truncates, which leaves opaque pixels at 254
+// alpha after something translucent is blended over them; this rounds instead.
+pub fn blend(pixel: &mut Rgba<u8>, color: &Rgba<u8>) {
+    match color[3] {
+        0 => return,
+        255 => {
+            *pixel = *color;
+            return;
+        }
+        _ => {}
+    }
+    let top = color[3] as f32 / 255.0;
+    let bottom = pixel[3] as f32 / 255.0 * (1.0 - top);
+    let alpha = top + bottom;
+    for i in 0..3 {
+        pixel[i] = ((color[i] as f32 * top + pixel[i] as f32 * bottom) / alpha).round() as u8;
+    }
+    pixel[3] = (alpha * 255.0).round() as u8;
+}
+
+// Blends one image over another with its top left corner at (x, y), like
+// imageops::overlay but rounding like blend
+pub fn overlay_image(bottom: &mut RgbaImage, top: &RgbaImage, x: i64, y: i64) {
+    for (tx, ty, color) in top.enumerate_pixels() {
+        blend_pixel(bottom, x + tx as i64, y + ty as i64, *color);
+    }
+}
+
+// Blends a single pixel into the image, ignoring anything that falls outside it
+pub fn blend_pixel(img: &mut RgbaImage, x: i64, y: i64, color: Rgba<u8>) {
+    if x >= 0 && y >= 0 && (x as u32) < img.width() && (y as u32) < img.height() {
+        blend(img.get_pixel_mut(x as u32, y as u32), &color);
+    }
+}
+
+// Blends a filled rectangle into the image
+pub fn fill_rect(img: &mut RgbaImage, x: i64, y: i64, width: u32, height: u32, color: Rgba<u8>) {
+    for py in y..y + height as i64 {
+        for px in x..x + width as i64 {
+            blend_pixel(img, px, py, color);
+        }
+    }
+}
+
+// Draws text with its top left corner at (x, y). Each font pixel becomes a
+// scale x scale block.
+pub fn draw_text(img: &mut RgbaImage, x: i64, y: i64, text: &str, scale: u32, color: Rgba<u8>) {
+    let advance = ((GLYPH_WIDTH + GLYPH_SPACING) * scale) as i64;
+    for (i, c) in text.chars().enumerate() {
+        let origin_x = x + i as i64 * advance;
+        for (col, bits) in glyph(c).iter().enumerate() {
+            for row in 0..GLYPH_HEIGHT {
+                if bits & (1 << row) != 0 {
+                    fill_rect(
+                        img,
+                        origin_x + (col as u32 * scale) as i64,
+                        y + (row * scale) as i64,
+                        scale,
+                        scale,
+                        color,
+                    );
+                }
+            }
+        }
+    }
+}
+
+// Draws text with a halo around it, so it reads over anything underneath. The halo is
+// one scaled pixel wide and blended once per pixel, so a translucent halo stays even.
+pub fn draw_text_with_halo(
+    img: &mut RgbaImage,
+    x: i64,
+    y: i64,
+    text: &str,
+    scale: u32,
+    color: Rgba<u8>,
+    halo: Rgba<u8>,
+) {
+    let pad = scale as i64;
+    let mut glyphs = RgbaImage::new(
+        text_width(text, scale) + 2 * scale,
+        text_height(scale) + 2 * scale,
+    );
+    draw_text(&mut glyphs, pad, pad, text, scale, Rgba([0, 0, 0, 255]));
+
+    let inked = |gx: i64, gy: i64| {
+        gx >= 0
+            && gy >= 0
+            && glyphs
+                .get_pixel_checked(gx as u32, gy as u32)
+                .is_some_and(|p| p[3] > 0)
+    };
+    for gy in 0..glyphs.height() as i64 {
+        for gx in 0..glyphs.width() as i64 {
+            let (px, py) = (x - pad + gx, y - pad + gy);
+            if inked(gx, gy) {
+                blend_pixel(img, px, py, color);
+            } else if (-pad..=pad).any(|dy| (-pad..=pad).any(|dx| inked(gx + dx, gy + dy))) {
+                blend_pixel(img, px, py, halo);
+            }
+        }
+    }
+}
+
+// Stamps an attribution notice into the bottom right corner of the image, on a
+// translucent white background so it's legible over any imagery.
+pub fn draw_attribution(img: &mut RgbaImage, attribution: &str) {
+    let scale = if img.width() >= 1024 { 2 } else { 1 };
+    let padding = 2 * scale;
+    let box_width = text_width(attribution, scale) + 2 * padding;
+    let box_height = text_height(scale) + 2 * padding;
+    let box_x = img.width() as i64 - box_width as i64;
+    let box_y = img.height() as i64 - box_height as i64;
+
+    fill_rect(
+        img,
+        box_x,
+        box_y,
+        box_width,
+        box_height,
+        Rgba([255, 255, 255, 192]),
+    );
+    draw_text(
+        img,
+        box_x + padding as i64,
+        box_y + padding as i64,
+        attribution,
+        scale,
+        Rgba([0, 0, 0, 255]),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_width() {
+        assert_eq!(text_width("", 1), 0);
+        assert_eq!(text_width("A", 1), 5);
+        assert_eq!(text_width("AB", 1), 11);
+        assert_eq!(text_width("AB", 2), 22);
+    }
+
+    #[test]
+    fn test_draw_text_pixels() {
+        let mut img = RgbaImage::from_pixel(10, 10, Rgba([255, 255, 255, 255]));
+        draw_text(&mut img, 0, 0, "I", 1, Rgba([0, 0, 0, 255]));
+
+        // 'I' has a full-height stroke in its middle column, and a serif at the top
+        for row in 0..GLYPH_HEIGHT {
+            assert_eq!(img.get_pixel(2, row), &Rgba([0, 0, 0, 255]));
+        }
+        assert_eq!(img.get_pixel(1, 0), &Rgba([0, 0, 0, 255]));
+        assert_eq!(img.get_pixel(0, 3), &Rgba([255, 255, 255, 255]));
+    }
+
+    #[test]
+    fn test_draw_text_clips() {
+        // Mustn't panic when text runs off the edge
+        let mut img = RgbaImage::new(4, 4);
+        draw_text(&mut img, -3, 2, "Hello", 2, Rgba([0, 0, 0, 255]));
+        draw_attribution(&mut img, "(c) somebody");
+    }
+
+    #[test]
+    fn test_draw_text_with_halo() {
+        let mut img = RgbaImage::new(10, 10);
+        let (black, white) = (Rgba([0, 0, 0, 255]), Rgba([255, 255, 255, 255]));
+        draw_text_with_halo(&mut img, 1, 1, "I", 1, black, white);
+
+        assert_eq!(img.get_pixel(3, 1), &black);
+        // Around the stroke, but not inside the glyph's empty columns far from it
+        assert_eq!(img.get_pixel(3, 0), &white);
+        assert_eq!(img.get_pixel(2, 4), &white);
+        assert_eq!(img.get_pixel(0, 4)[3], 0);
+    }
+}
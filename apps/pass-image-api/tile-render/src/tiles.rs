@@ -0,0 +1,1764 @@
+// ! # Tiles
+// ! Provides functions for retrieving and mosaicing
+// tile imagery from public tile imagery sources.
+
+use crate::coordinates::{
+    lat_long_and_image_size_to_bounding_box, ConstrainedTileBox, LatLong, TileCoordinate,
+};
+use crate::crop::{self, Crop};
+use crate::degradations::{self, Degradation};
+use crate::dem::{self, ElevationGrid};
+use crate::dither::{self, Palette};
+use crate::effects::{self, Adjustments, Equalize, Filter, Resample, ToneCurve};
+use crate::fetcher::TileFetcher;
+use crate::focus::{self, Focus};
+use crate::frame::{self, Frame, Mask};
+use crate::layers::{self, LayerKind, LayerSettings};
+use crate::overlay::{self, Overlay, Viewport};
+use crate::{avif, cluster, contours, coverage, icc, mvt, registry, report};
+use crate::{scale_bar, slope, text, watermark};
+use tile_geometry::viewport::{self, crop_window};
+
+use anyhow::{anyhow, Context as _, Result};
+use bytes::Bytes;
+use futures::future;
+use futures::stream::{self, StreamExt};
+use image::{imageops, DynamicImage, Rgba, RgbaImage};
+use log::warn;
+use opentelemetry::trace::{SpanKind, Status, TraceContextExt, Tracer};
+use opentelemetry::{global, Context, KeyValue};
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::env;
+use std::io::Cursor;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TileSet {
+    Osm,
+    Swisstopo,
+    // Elevation data rather than imagery; see the dem module
+    Terrain,
+    // Generated locally rather than fetched; see the debug_tiles module
+    Debug,
+    // Bing Maps aerial imagery, addressed by quadkey rather than z/x/y
+    Bing,
+    // Mapbox Outdoors, which needs an API token; see the fetcher module
+    Mapbox,
+    // Esri's ArcGIS World Imagery, whose URLs take the row before the column
+    Esri,
+    // Esri's transparent reference layers of roads, and of place names and boundaries, for
+    // drawing over imagery
+    EsriRoads,
+    EsriLabels,
+    // Esri's imagery with its roads and labels over it. It has no tiles of its own and is
+    // rendered from its parts; see TileSet::parts
+    Hybrid,
+    // Stamen's Terrain and Watercolor styles, hosted by Stadia Maps, which need its API key
+    StamenTerrain,
+    StamenWatercolor,
+    // A source from TILESETS_CONFIG, by its index in the registry; see the registry module
+    Custom(u16),
+}
+
+impl TileSet {
+    pub const ALL: [TileSet; 12] = [
+        TileSet::Osm,
+        TileSet::Swisstopo,
+        TileSet::Terrain,
+        TileSet::Debug,
+        TileSet::Bing,
+        TileSet::Mapbox,
+        TileSet::Esri,
+        TileSet::EsriRoads,
+        TileSet::EsriLabels,
+        TileSet::Hybrid,
+        TileSet::StamenTerrain,
+        TileSet::StamenWatercolor,
+    ];
+
+    // Every tileset in the registry that can be asked for, the built-in ones first
+    pub fn all() -> Vec<TileSet> {
+        (0..registry::sources().len())
+            .map(|i| TileSet::from_index(i as u16))
+            .filter(TileSet::is_enabled)
+            .collect()
+    }
+
+    // Whether the tileset can be asked for. Tilesets that need an API token are disabled
+    // until it's configured; see the registry module.
+    pub fn is_enabled(&self) -> bool {
+        self.source().is_some_and(|s| !s.disabled)
+    }
+
+    // The handle for the registry entry at the given index
+    fn from_index(index: u16) -> TileSet {
+        TileSet::ALL
+            .get(index as usize)
+            .copied()
+            .unwrap_or(TileSet::Custom(index))
+    }
+
+    // The tileset's index in the registry. Built-in tilesets are seeded in ALL's order.
+    fn index(&self) -> u16 {
+        match self {
+            TileSet::Custom(index) => *index,
+            builtin => TileSet::ALL
+                .iter()
+                .position(|t| t == builtin)
+                .expect("ALL has every built-in tileset") as u16,
+        }
+    }
+
+    fn source(&self) -> Option<&'static registry::Source> {
+        registry::source(self.index())
+    }
+
+    // Looks up a TileSet by the name used in query strings, falling back to OSM
+    pub fn from_name(name: &str) -> TileSet {
+        TileSet::lookup(name).unwrap_or(TileSet::Osm)
+    }
+
+    // Looks up a TileSet by name, returning None if there isn't one or it's disabled.
+    // Terrain is elevation data for contours and hillshading, not a basemap, so it can't
+    // be asked for.
+    pub fn lookup(name: &str) -> Option<TileSet> {
+        registry::lookup(name)
+            .map(TileSet::from_index)
+            .filter(|tileset| tileset.is_enabled() && *tileset != TileSet::Terrain)
+    }
+
+    // The name used for this TileSet in query strings and configuration
+    pub fn name(&self) -> &'static str {
+        self.source().map_or("unknown", |s| s.name.as_str())
+    }
+
+    // Licensed tilesets come from commercial providers whose terms require their notice
+    // on every image. We watermark those, and don't let their raw tiles be proxied.
+    pub fn is_licensed(&self) -> bool {
+        self.source().is_some_and(|s| s.licensed)
+    }
+
+    // The attribution notice required by the provider
+    pub fn attribution(&self) -> &'static str {
+        self.source().map_or("", |s| s.attribution.as_str())
+    }
+
+    // The upstream URL pattern. Debug and hybrid tiles have no upstream, so theirs is
+    // empty. Bing's takes the tile's quadkey rather than its z/x/y; see fetcher::tile_url.
+    // Mapbox's and Stadia's have a {token} placeholder for their API tokens.
+    pub fn url_pattern(&self) -> &str {
+        self.source().map_or("", |s| s.url.as_str())
+    }
+
+    // The size of the tileset's tiles, which the mosaic is built at. Vector tiles are
+    // rasterized at 256px whatever their source says.
+    pub fn tile_size(&self) -> u32 {
+        match self.content_type() {
+            Some(mvt::CONTENT_TYPE) => mvt::TILE_PX,
+            _ => self.source().map_or(256, |s| s.tile_size),
+        }
+    }
+
+    // The tilesets this one is rendered from, bottom first. Hybrid is composited from
+    // Esri's imagery and reference layers; every other tileset is its own tiles.
+    pub fn parts(&self) -> Vec<TileSet> {
+        match self {
+            TileSet::Hybrid => vec![TileSet::Esri, TileSet::EsriRoads, TileSet::EsriLabels],
+            tileset => vec![*tileset],
+        }
+    }
+
+    // How the upstream numbers its rows
+    pub fn scheme(&self) -> registry::Scheme {
+        self.source().map_or(registry::Scheme::Xyz, |s| s.scheme)
+    }
+
+    // The content type the upstream's tiles must come as, or None for PNG or JPEG
+    pub fn content_type(&self) -> Option<&'static str> {
+        self.source().and_then(|s| s.content_type.as_deref())
+    }
+
+    // The content type one of the tileset's tiles is served with: the one the tileset's
+    // tiles must come as, or otherwise JPEG or PNG, going by the tile's signature
+    pub fn tile_content_type(&self, tile: &[u8]) -> &'static str {
+        match self.content_type() {
+            Some(content_type) => content_type,
+            None if tile.starts_with(&[0xff, 0xd8, 0xff]) => "image/jpeg",
+            None => "image/png",
+        }
+    }
+
+    // The tileset serving the same map at a higher resolution, e.g. from an @2x endpoint,
+    // if the registry names one
+    pub fn retina(&self) -> Option<TileSet> {
+        self.source()
+            .and_then(|s| s.retina.as_deref())
+            .and_then(registry::lookup)
+            .map(TileSet::from_index)
+            .filter(TileSet::is_enabled)
+    }
+
+    // Extra headers sent with every fetch from the upstream
+    pub fn headers(&self) -> Vec<(String, String)> {
+        self.source().map_or_else(Vec::new, |s| {
+            s.headers
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect()
+        })
+    }
+}
+
+// The most basemaps that can be blended into one render. Tilesets with parts, like
+// hybrid, count a layer for each, since each is fetched.
+pub const MAX_BLEND_LAYERS: usize = 4;
+
+// One basemap in a blended render, drawn over the layers below it with the given opacity
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Layer {
+    pub tileset: TileSet,
+    pub opacity: f32,
+}
+
+// Parses a blend specification such as "osm:1.0,swisstopo:0.5". Layers are listed bottom
+// first, and the opacity defaults to 1.0 if it's left off.
+pub fn parse_blend(spec: &str) -> Result<Vec<Layer>> {
+    let layers = spec
+        .split(',')
+        .map(|layer| {
+            let (name, opacity) = match layer.split_once(':') {
+                Some((name, opacity)) => (name.trim(), Some(opacity.trim())),
+                None => (layer.trim(), None),
+            };
+            let tileset =
+                TileSet::lookup(name).ok_or_else(|| anyhow!("Unknown tileset {}", name))?;
+            let opacity = match opacity {
+                Some(opacity) => opacity
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|o| (0.0..=1.0).contains(o))
+                    .ok_or_else(|| anyhow!("Invalid opacity for {}: {}", name, opacity))?,
+                None => 1.0,
+            };
+            Ok(Layer { tileset, opacity })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let count: usize = layers.iter().map(|layer| layer.tileset.parts().len()).sum();
+    if count > MAX_BLEND_LAYERS {
+        return Err(anyhow!(
+            "At most {} layers can be blended",
+            MAX_BLEND_LAYERS
+        ));
+    }
+    Ok(layers)
+}
+
+const DEFAULT_TILE_TIMEOUT_MS: u64 = 10_000;
+
+// The most tile keys a span lists for each kind of failure. The rest are only counted.
+const MAX_SPAN_TILES: usize = 20;
+
+// Fetches all of the tiles within a TileBox. Every tile is given TILE_TIMEOUT_MS, and
+// the ones that fail or time out are recorded on the span and in the render report.
+// Note - we assume that a TileBox is only 2D - e.g., all tiles
+// are within the same zoom level.
+async fn fetch_tile_box(
+    fetcher: &dyn TileFetcher,
+    tileset: TileSet,
+    top_left: &TileCoordinate,
+    bottom_right: &TileCoordinate,
+) -> Result<HashMap<(u32, u32, u32), Bytes>> {
+    // Create a manual span for this function
+    // This span will be the parent of all outgoing calls
+    let tracer = global::tracer("fetch_image_tracer");
+    let span = tracer
+        .span_builder("fetch_image")
+        .with_kind(SpanKind::Internal)
+        .with_attributes(vec![KeyValue::new("tileset", tileset.name())])
+        .start(&tracer);
+
+    let cx = Context::current_with_span(span);
+    let ctx = cx.borrow();
+
+    // Collect all tile coordinates in the bounding box
+    let mut tile_coords = Vec::new();
+
+    for x in top_left.x.floor() as u32..=bottom_right.x.ceil() as u32 {
+        for y in top_left.y.floor() as u32..=bottom_right.y.ceil() as u32 {
+            tile_coords.push((x, y, top_left.z));
+        }
+    }
+
+    // Fetch all tiles in parallel, each within the tile timeout
+    let timeout = tile_timeout();
+    let tile_fetches = stream::iter(tile_coords.into_iter().map(|tile| {
+        // For each tile, fetch the corresponding tile asynchronously
+        async move {
+            let fetch = fetcher.fetch(tileset, tile.0, tile.1, tile.2, ctx.clone());
+            let fetched = match tokio::time::timeout(timeout, fetch).await {
+                Ok(fetched) => fetched.map_err(|e| ("error", e)),
+                Err(_) => Err((
+                    "timeout",
+                    anyhow!("Timed out after {}ms", timeout.as_millis()),
+                )),
+            };
+            (tile, fetched)
+        }
+    }))
+    .buffer_unordered(10) // Limit to 10 concurrent requests
+    .collect::<Vec<_>>() // Collect all results (errors or successes)
+    .await;
+
+    // Every tile is tried, so the failures say whether one tile is bad or all of them are
+    let mut tile_map = HashMap::new();
+    let mut failed = Vec::new();
+    for (tile, fetched) in tile_fetches {
+        match fetched {
+            Ok(bytes) => {
+                report::tile(tileset.name(), bytes.len());
+                tile_map.insert(tile, bytes);
+            }
+            Err((reason, e)) => failed.push((tile, reason, e)),
+        }
+    }
+    if !failed.is_empty() {
+        failed.sort_by_key(|&((x, y, z), ..)| (z, x, y));
+        let span = cx.span();
+        for (reason, name) in [("error", "tiles.failed"), ("timeout", "tiles.timed_out")] {
+            let keys: Vec<String> = failed
+                .iter()
+                .filter(|(_, r, _)| *r == reason)
+                .map(|((x, y, z), ..)| format!("{}/{}/{}", z, x, y))
+                .collect();
+            span.set_attribute(KeyValue::new(format!("{}_count", name), keys.len() as i64));
+            if !keys.is_empty() {
+                let listed = &keys[..keys.len().min(MAX_SPAN_TILES)];
+                span.set_attribute(KeyValue::new(name, listed.join(",")));
+            }
+        }
+        for ((x, y, z), reason, e) in &failed {
+            let key = format!("{}/{}/{}", z, x, y);
+            report::tile_failed(tileset.name(), key, reason, format!("{:#}", e));
+        }
+        let (count, total) = (failed.len(), failed.len() + tile_map.len());
+        let ((x, y, z), _, e) = failed.remove(0);
+        let e = e.context(format!(
+            "{} of {} tiles from {} failed, first {}/{}/{}",
+            count,
+            total,
+            tileset.name(),
+            z,
+            x,
+            y
+        ));
+        // If any tile fetch fails, set the span status to Error and return the error
+        span.set_status(Status::Error {
+            description: format!("{:#}", e).into(),
+        });
+        return Err(e);
+    }
+
+    // Set the span status to OK and end the span
+    cx.span().set_status(Status::Ok);
+    cx.span().end();
+
+    Ok(tile_map)
+}
+
+// How long a single tile may take, from TILE_TIMEOUT_MS
+fn tile_timeout() -> Duration {
+    static TIMEOUT: OnceLock<Duration> = OnceLock::new();
+    *TIMEOUT.get_or_init(|| {
+        let ms = env::var("TILE_TIMEOUT_MS")
+            .ok()
+            .and_then(|ms| ms.parse().ok())
+            .filter(|&ms| ms > 0)
+            .unwrap_or(DEFAULT_TILE_TIMEOUT_MS);
+        Duration::from_millis(ms)
+    })
+}
+
+// Everything about a render beyond where it is and how big it is
+#[derive(Debug, Clone, Default)]
+pub struct RenderOptions {
+    // Vector overlays to draw over the basemap
+    pub overlays: Vec<Overlay>,
+    // Color filter applied to the basemap before overlays are drawn
+    pub filter: Option<Filter>,
+    // Brightness, contrast and saturation, applied after the filter
+    pub adjustments: Adjustments,
+    // Gamma correction, then a tone curve, applied after the adjustments
+    pub gamma: Option<f32>,
+    pub curve: Option<ToneCurve>,
+    // Contrast enhancement from the basemap's own histogram, applied before anything else
+    pub equalize: Option<Equalize>,
+    // Unsharp mask and Gaussian blur sigmas, applied to the map after it's been resized
+    pub sharpen: Option<f32>,
+    pub blur: Option<f32>,
+    // Basemaps to composite instead of the requested tileset, bottom first
+    pub blend: Vec<Layer>,
+    // Factor to resize the map by before overlays are drawn
+    pub scale: Option<f32>,
+    // How to resample the map when it's resized
+    pub resample: Resample,
+    // Supersampling factor for anti-aliased overlays; 0 or 1 draws them directly
+    pub antialias: u32,
+    // Resize the cropped map to exactly the requested size. Otherwise it's whatever the
+    // tile geometry gives us, which is at least the requested size.
+    pub exact_size: bool,
+    // Alpha mask shaping the finished image
+    pub mask: Option<Mask>,
+    // Border, shadow and background around the finished image
+    pub frame: Option<Frame>,
+    // Skip the basemap and draw the overlays alone on a transparent canvas
+    pub overlays_only: bool,
+    // Draw elevation contours at this interval, in meters
+    pub contour_interval: Option<f32>,
+    // Shade steep slopes at this opacity
+    pub slope_opacity: Option<f32>,
+    // Whether to cluster markers, or None to cluster when there are a lot of them
+    pub cluster: Option<bool>,
+    // Reduce the output to a small palette for e-paper displays
+    pub palette: Option<Palette>,
+    // Recolor the basemap so red/green distinctions survive color blindness
+    pub colorblind_safe: bool,
+    // Dim or desaturate the map outside an area, to highlight the pass
+    pub focus: Option<Focus>,
+    // Opacity and z-index overrides for the layers the image is composited from
+    pub layer_settings: LayerSettings,
+    // Crop the output to a polygon
+    pub crop: Option<Crop>,
+    // Draw a scale bar in the bottom left corner
+    pub scale_bar: bool,
+    // Credit every tileset, where otherwise only licensed ones are
+    pub attribute_all: bool,
+    // What the finished image is encoded as
+    pub format: Format,
+}
+
+// The formats images are encoded in
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Format {
+    #[default]
+    Png,
+    // From 1 to 100
+    Avif {
+        quality: u8,
+    },
+}
+
+impl RenderOptions {
+    // The basemap layers to render, given the tileset that was asked for. Tilesets with
+    // parts, like hybrid, are expanded into a layer for each, at the tileset's opacity.
+    // Scaled up 2x or more, tilesets with retina tiles are fetched from those instead.
+    pub fn layers(&self, tileset: TileSet) -> Vec<Layer> {
+        let layers = if self.overlays_only {
+            Vec::new()
+        } else if self.blend.is_empty() {
+            vec![Layer {
+                tileset,
+                opacity: 1.0,
+            }]
+        } else {
+            self.blend.clone()
+        };
+        let retina = self.scale.is_some_and(|scale| scale >= 2.0);
+        layers
+            .into_iter()
+            .flat_map(|layer| {
+                layer.tileset.parts().into_iter().map(move |tileset| Layer {
+                    tileset: tileset.retina().filter(|_| retina).unwrap_or(tileset),
+                    opacity: layer.opacity,
+                })
+            })
+            .collect()
+    }
+
+    // How many pixels the basemap keeps for each pixel of a 256px tile: as many as the
+    // output is scaled up by, so larger tiles aren't shrunk only to be enlarged again
+    fn detail(&self) -> u32 {
+        self.scale.map_or(1, |scale| scale.ceil() as u32).max(1)
+    }
+}
+
+// Fetches an image centered at the given point, using the provided TileSet. Tiles come
+// from the fetcher.
+pub async fn fetch_image_from_point(
+    fetcher: &dyn TileFetcher,
+    center: LatLong,
+    radius_km: f32,
+    image_size: u32,
+    tileset: TileSet,
+    options: &RenderOptions,
+) -> Result<Bytes> {
+    // Find the center
+    let tile_box = lat_long_and_image_size_to_bounding_box(center, radius_km, image_size);
+
+    // Fetch the image
+    fetch_image(fetcher, tileset, &tile_box, image_size, options).await
+}
+
+// The most sizes one request can be rendered at
+pub const MAX_VARIANTS: usize = 8;
+
+// Renders the image centered at the given point at several sizes, e.g. for responsive
+// image sets. The tiles are fetched and mosaicked once, for the largest size, and scaled
+// down for the rest, so the variants show the same area. Images come back in the order
+// of the sizes.
+pub async fn fetch_image_variants_from_point(
+    fetcher: &dyn TileFetcher,
+    center: LatLong,
+    radius_km: f32,
+    sizes: &[u32],
+    tileset: TileSet,
+    options: &RenderOptions,
+) -> Result<Vec<Bytes>> {
+    if sizes.is_empty() || sizes.len() > MAX_VARIANTS {
+        return Err(anyhow!(
+            "Between 1 and {} sizes can be rendered",
+            MAX_VARIANTS
+        ));
+    }
+    let largest = *sizes.iter().max().expect("there's at least one size");
+    let tile_box = lat_long_and_image_size_to_bounding_box(center, radius_km, largest);
+    record_zoom(&tile_box, largest);
+    let started = Instant::now();
+    let (layers, layer_tiles) = fetch_layers(fetcher, &options.layers(tileset), &tile_box).await?;
+    report::phase("fetch", started.elapsed());
+    let started = Instant::now();
+    let (basemap, viewport) = prepare_basemap(layer_tiles, &tile_box, options)?;
+    report::phase("mosaic", started.elapsed());
+    let full_size = output_size(tile_box.inner_size_px, largest, options);
+
+    let mut images = Vec::with_capacity(sizes.len());
+    for &size in sizes {
+        let factor = size as f64 / largest as f64;
+        let scaled = |v: u32| ((v as f64 * factor).round() as u32).max(1);
+        let target = (scaled(full_size.0), scaled(full_size.1));
+        let started = Instant::now();
+        let image = finish_image(
+            fetcher,
+            basemap.clone(),
+            viewport,
+            target,
+            tile_box.center,
+            &layers,
+            options,
+        )
+        .await?;
+        report::phase("draw", started.elapsed());
+        images.push(encode_reported(image, options.format).await?);
+    }
+    Ok(images)
+}
+
+// Records the zoom tiles are fetched at, and whether it fell short of filling the image
+fn record_zoom(tile_box: &ConstrainedTileBox, image_size: u32) {
+    let zoom = tile_box.tile_box.top_left.z;
+    report::zoom(zoom);
+    if tile_box.inner_size_px.0 <= image_size {
+        degradations::record(Degradation::ZoomClamped { zoom });
+    }
+}
+
+// The number of tiles fetch_image_from_point will need for the given image
+pub fn tile_count_for_point(
+    center: LatLong,
+    radius_km: f32,
+    image_size: u32,
+    tileset: TileSet,
+    options: &RenderOptions,
+) -> u32 {
+    let per_layer = lat_long_and_image_size_to_bounding_box(center, radius_km, image_size)
+        .tile_box
+        .tile_count();
+    per_layer * options.layers(tileset).len() as u32
+}
+
+// Fetches an image at the given point using the provided TileSet and ConstrainedTileBox
+// This function will fetch enough tiles around the given point to allow it to crop the resulting
+// image down to ensure we have enough pixels to cover the requested resolution.
+async fn fetch_image(
+    fetcher: &dyn TileFetcher,
+    tileset: TileSet,
+    tile_box: &ConstrainedTileBox,
+    image_size: u32,
+    options: &RenderOptions,
+) -> Result<Bytes> {
+    let image = render_image(fetcher, tileset, tile_box, image_size, options).await?;
+    encode_reported(image, options.format).await
+}
+
+// Renders the image centered at the given point as fetch_image_from_point does, but
+// leaves it unencoded, for callers that go on to work with its pixels
+pub async fn render_image_from_point(
+    fetcher: &dyn TileFetcher,
+    center: LatLong,
+    radius_km: f32,
+    image_size: u32,
+    tileset: TileSet,
+    options: &RenderOptions,
+) -> Result<RgbaImage> {
+    let tile_box = lat_long_and_image_size_to_bounding_box(center, radius_km, image_size);
+    render_image(fetcher, tileset, &tile_box, image_size, options).await
+}
+
+async fn render_image(
+    fetcher: &dyn TileFetcher,
+    tileset: TileSet,
+    tile_box: &ConstrainedTileBox,
+    image_size: u32,
+    options: &RenderOptions,
+) -> Result<RgbaImage> {
+    let meter = global::meter("processing_time_meter");
+    let processing_time = meter.f64_histogram("processing_time").init();
+
+    record_zoom(tile_box, image_size);
+    let fetch_started = Instant::now();
+    let (layers, layer_tiles) = fetch_layers(fetcher, &options.layers(tileset), tile_box).await?;
+    report::phase("fetch", fetch_started.elapsed());
+    let start = Instant::now();
+    let (basemap, viewport) = prepare_basemap(layer_tiles, tile_box, options)?;
+    report::phase("mosaic", start.elapsed());
+    let target = output_size(tile_box.inner_size_px, image_size, options);
+    let draw_started = Instant::now();
+    let image = finish_image(
+        fetcher,
+        basemap,
+        viewport,
+        target,
+        tile_box.center,
+        &layers,
+        options,
+    )
+    .await?;
+    report::phase("draw", draw_started.elapsed());
+
+    processing_time.record(start.elapsed().as_secs_f64(), &[]);
+    Ok(image)
+}
+
+// Fetches the tiles in the bounding box for every layer, returning the layers as they were
+// rendered. A layer is fetched from the first tileset in its fallback chain that covers
+// the box, and if any of its tiles can't be fetched the whole box is fetched again from
+// the next one, so an image never mixes tiles from different tilesets in one layer.
+async fn fetch_layers(
+    fetcher: &dyn TileFetcher,
+    layers: &[Layer],
+    tile_box: &ConstrainedTileBox,
+) -> Result<(Vec<Layer>, Vec<LayerTiles>)> {
+    // Every layer's coverage is checked before anything is fetched
+    let sources = layers
+        .iter()
+        .map(|&layer| coverage::sources(layer, &tile_box.tile_box))
+        .collect::<Result<Vec<_>>>()?;
+
+    // The layers' tile boxes are fetched at the same time, so a blend takes as long as its
+    // slowest tileset rather than all of them one after another
+    let fetched = future::try_join_all(
+        layers
+            .iter()
+            .zip(sources)
+            .map(|(&layer, sources)| fetch_layer(fetcher, layer, sources, tile_box)),
+    )
+    .await?;
+    let (layers, layer_tiles): (Vec<Layer>, Vec<LayerTiles>) = fetched.into_iter().unzip();
+
+    // Every render names the tilesets that served it, whether or not any stood in for the
+    // ones asked for
+    let served: Vec<&'static str> = layers.iter().map(|layer| layer.tileset.name()).collect();
+    served.iter().copied().for_each(degradations::record_source);
+    Context::current()
+        .span()
+        .set_attribute(KeyValue::new("tile_source", served.join(",")));
+    Ok((layers, layer_tiles))
+}
+
+// Fetches a layer's tiles from the first of its sources that has them all
+async fn fetch_layer(
+    fetcher: &dyn TileFetcher,
+    layer: Layer,
+    sources: Vec<TileSet>,
+    tile_box: &ConstrainedTileBox,
+) -> Result<(Layer, LayerTiles)> {
+    let mut sources = sources.into_iter().peekable();
+    loop {
+        let tileset = sources.next().expect("a layer has at least one source");
+        let fetched = fetch_tile_box(
+            fetcher,
+            tileset,
+            &tile_box.tile_box.top_left,
+            &tile_box.tile_box.bottom_right,
+        )
+        .await;
+        match (fetched, sources.peek()) {
+            (Ok(tiles), _) => {
+                let layer_tiles = LayerTiles {
+                    tiles,
+                    opacity: layer.opacity,
+                    tileset,
+                    tile_size: tileset.tile_size(),
+                };
+                return Ok((Layer { tileset, ..layer }, layer_tiles));
+            }
+            (Err(e), Some(next)) => {
+                warn!(
+                    "Falling back from {} to {}: {:#}",
+                    tileset.name(),
+                    next.name(),
+                    e
+                );
+                degradations::record(Degradation::Failover {
+                    tileset: tileset.name(),
+                    fallback: next.name(),
+                });
+            }
+            (Err(e), None) => return Err(e),
+        }
+    }
+}
+
+// Mosaics the layers' tiles and applies the color effects, which don't depend on the
+// size the image ends up at. Returns the basemap and where it sits in the world. The
+// basemap is larger than the tile box when the output is scaled up enough to keep detail.
+fn prepare_basemap(
+    layer_tiles: Vec<LayerTiles>,
+    tile_box: &ConstrainedTileBox,
+    options: &RenderOptions,
+) -> Result<(RgbaImage, Viewport)> {
+    let (mut image, viewport) = if layer_tiles.is_empty() {
+        let (_, viewport) = crop_window(tile_box);
+        let (width, height) = tile_box.inner_size_px;
+        (RgbaImage::new(width, height), viewport)
+    } else {
+        mosaic(layer_tiles, tile_box, options.detail())?
+    };
+
+    if let Some(equalize) = options.equalize {
+        effects::apply_equalize(&mut image, equalize);
+    }
+    if options.colorblind_safe {
+        effects::apply_colorblind_safe(&mut image);
+    }
+    if let Some(filter) = options.filter {
+        effects::apply_filter(&mut image, filter);
+    }
+    effects::apply_adjustments(&mut image, &options.adjustments);
+    effects::apply_tone(&mut image, options.gamma, options.curve.as_ref());
+    Ok((image, viewport))
+}
+
+// Resizes a basemap to the target size and draws everything over it
+async fn finish_image(
+    fetcher: &dyn TileFetcher,
+    mut image: RgbaImage,
+    mut viewport: Viewport,
+    (width, height): (u32, u32),
+    center: LatLong,
+    layers: &[Layer],
+    options: &RenderOptions,
+) -> Result<RgbaImage> {
+    // Resize before drawing overlays and attribution, so those stay crisp
+    if (width, height) != image.dimensions() {
+        let factor = width as f64 / image.width() as f64;
+        image = effects::resize(&image, width, height, options.resample);
+        viewport = viewport.scaled(factor);
+    }
+
+    if let Some(sigma) = options.sharpen {
+        image = effects::sharpen(&image, sigma);
+    }
+    if let Some(sigma) = options.blur {
+        image = effects::blur(&image, sigma);
+    }
+
+    if let Some(focus) = &options.focus {
+        focus::apply_focus(&mut image, &viewport, center, focus);
+    }
+
+    let elevation = if options.contour_interval.is_some() || options.slope_opacity.is_some() {
+        Some(fetch_elevation(fetcher, &viewport, image.dimensions()).await?)
+    } else {
+        None
+    };
+    let mut image = compose_layers(image, &viewport, options, elevation.as_ref());
+    if options.scale_bar {
+        scale_bar::draw_scale_bar(&mut image, &viewport, center);
+    }
+
+    // Licensed tilesets always carry their attribution, whatever the caller asked for
+    let mut attributions: Vec<&str> = Vec::new();
+    let credited = |layer: &&Layer| layer.tileset.is_licensed() || options.attribute_all;
+    for layer in layers.iter().filter(credited) {
+        if !attributions.contains(&layer.tileset.attribution()) {
+            attributions.push(layer.tileset.attribution());
+        }
+    }
+    if !attributions.is_empty() {
+        text::draw_attribution(&mut image, &attributions.join(", "));
+    }
+
+    if let Some(watermark) = watermark::get() {
+        watermark.apply(&mut image);
+    }
+
+    if let Some(frame) = &options.frame {
+        image = frame::apply_frame(&image, frame, options.mask);
+    }
+
+    // Last of all, so the frame, attribution and watermark are dithered too
+    if let Some(palette) = options.palette {
+        dither::dither(&mut image, palette);
+    }
+    Ok(image)
+}
+
+// Draws everything that goes over the basemap onto layers, composites them and applies
+// the crop and mask. The elevation grid is needed for slope shading and contours. This
+// is the part of the pipeline that doesn't touch the network.
+pub fn compose_layers(
+    basemap: RgbaImage,
+    viewport: &Viewport,
+    options: &RenderOptions,
+    elevation: Option<&ElevationGrid>,
+) -> RgbaImage {
+    // Everything over the basemap is drawn on a layer of its own, and the layers are
+    // composited in z order at their opacities
+    let (width, height) = basemap.dimensions();
+    let mut stack = vec![(LayerKind::Basemap, basemap)];
+
+    if let Some(grid) = elevation {
+        let mut raster = RgbaImage::new(width, height);
+        if let Some(opacity) = options.slope_opacity {
+            slope::shade_slopes(&mut raster, grid, opacity);
+        }
+        if let Some(interval) = options.contour_interval {
+            contours::draw_contours(&mut raster, grid, interval);
+        }
+        stack.push((LayerKind::Raster, raster));
+    }
+
+    // Cluster markers at the zoom we're drawing at, so the badges stay legible
+    let clustered;
+    let overlays = if cluster::should_cluster(&options.overlays, options.cluster) {
+        clustered = cluster::cluster_markers(&options.overlays, viewport);
+        &clustered
+    } else {
+        &options.overlays
+    };
+    for kind in [LayerKind::Polygons, LayerKind::Lines, LayerKind::Markers] {
+        let members: Vec<Overlay> = overlays
+            .iter()
+            .filter(|o| o.layer() == kind)
+            .cloned()
+            .collect();
+        if !members.is_empty() {
+            let mut canvas = RgbaImage::new(width, height);
+            overlay::draw_overlays_supersampled(&mut canvas, viewport, &members, options.antialias);
+            stack.push((kind, canvas));
+        }
+    }
+    if overlays.iter().any(Overlay::has_label) {
+        let mut canvas = RgbaImage::new(width, height);
+        overlay::draw_labels_supersampled(&mut canvas, viewport, overlays, options.antialias);
+        stack.push((LayerKind::Labels, canvas));
+    }
+
+    let mut image = layers::composite(stack, &options.layer_settings);
+
+    // Crop and mask before the attribution, so that it's never cut off
+    if let Some(crop) = &options.crop {
+        crop::apply_crop(&mut image, viewport, crop);
+    }
+    if let Some(mask) = options.mask {
+        frame::apply_mask(&mut image, mask);
+    }
+    image
+}
+
+// The size of the final image, given the size of the cropped mosaic and the size
+// that was asked for
+fn output_size(mosaic_size: (u32, u32), image_size: u32, options: &RenderOptions) -> (u32, u32) {
+    viewport::output_size(mosaic_size, image_size, options.exact_size, options.scale)
+}
+
+// Fetches the elevation for every pixel of an image of the given size at the viewport
+async fn fetch_elevation(
+    fetcher: &dyn TileFetcher,
+    viewport: &Viewport,
+    size: (u32, u32),
+) -> Result<ElevationGrid> {
+    let ((left, top), (right, bottom)) = dem::tile_range(viewport, size);
+    let z = dem::dem_zoom(viewport.zoom);
+    let tiles = fetch_tile_box(
+        fetcher,
+        TileSet::Terrain,
+        &TileCoordinate {
+            x: left as f32,
+            y: top as f32,
+            z,
+        },
+        &TileCoordinate {
+            x: right as f32,
+            y: bottom as f32,
+            z,
+        },
+    )
+    .await?;
+    ElevationGrid::from_tiles(&tiles, viewport, size)
+}
+
+// Fetched tiles for one layer, along with the layer's opacity and the tileset that served
+// them and the size its tiles are
+struct LayerTiles {
+    tiles: HashMap<(u32, u32, u32), Bytes>,
+    opacity: f32,
+    tileset: TileSet,
+    tile_size: u32,
+}
+
+// Stitches fetched tiles together and crops the result down to the ConstrainedTileBox,
+// returning the image along with the Viewport describing where it sits in the world.
+// Up to detail pixels are kept for each pixel of a 256px tile, so retina tiles keep
+// their detail and other tiles are upsampled to it.
+// When there are several layers they're composited one tile at a time, and each tile is
+// only drawn where it falls inside the crop, so we never hold more than one image.
+fn mosaic(
+    layers: Vec<LayerTiles>,
+    tile_box: &ConstrainedTileBox,
+    detail: u32,
+) -> Result<(RgbaImage, Viewport)> {
+    // The mosaic is built at the largest tile size among the layers, so none loses detail,
+    // or larger when the output needs more. The tile box is measured in 256px tiles, so
+    // its pixels are scaled to match.
+    let tile_size = layers
+        .iter()
+        .map(|layer| layer.tile_size)
+        .max()
+        .expect("There's at least one layer")
+        .max(256 * detail);
+    let scale = tile_size / 256;
+
+    let ((offset_left, offset_top), viewport) = crop_window(tile_box);
+    let (width, height) = tile_box.inner_size_px;
+    let mut image = RgbaImage::new(width * scale, height * scale);
+
+    // Every layer covers the same tiles, so the first one tells us the layout. Drawing
+    // each tile relative to the crop window crops the mosaic as it's built, so we end
+    // up centered where we want to be.
+    for tile_coord in layers[0].tiles.keys() {
+        let tile_img = composite_tile(&layers, tile_coord, tile_size)?;
+
+        let x_offset = (tile_coord.0 - tile_box.tile_box.top_left.x.floor() as u32) * tile_size;
+        let y_offset = (tile_coord.1 - tile_box.tile_box.top_left.y.floor() as u32) * tile_size;
+
+        imageops::replace(
+            &mut image,
+            &tile_img,
+            x_offset as i64 - (offset_left * scale) as i64,
+            y_offset as i64 - (offset_top * scale) as i64,
+        );
+    }
+
+    // Larger tiles than the output needs are scaled down once here rather than one at a
+    // time, and the viewport is scaled to whatever's kept
+    let kept = scale.min(detail.max(1));
+    if kept < scale {
+        image = effects::resize(&image, width * kept, height * kept, Resample::CatmullRom);
+    }
+    Ok((image, viewport.scaled(kept as f64)))
+}
+
+// Composites one tile from each layer, bottom first, at the mosaic's tile size. Tiles
+// from local sources aren't checked when they're fetched, so one that doesn't decode, or
+// isn't the size its tileset serves, is an error here.
+fn composite_tile(
+    layers: &[LayerTiles],
+    tile_coord: &(u32, u32, u32),
+    tile_size: u32,
+) -> Result<RgbaImage> {
+    let decode = |layer: &LayerTiles| -> Result<RgbaImage> {
+        let (x, y, z) = tile_coord;
+        let tile = icc::decode_tile(&layer.tiles[tile_coord])
+            .with_context(|| format!("decoding tile {}/{}/{}", z, x, y))?;
+        let expected = layer.tile_size;
+        if tile.dimensions() != (expected, expected) {
+            return Err(anyhow!(
+                "Tile {}/{}/{} from {} is {}x{}, not the {}px its tileset serves",
+                z,
+                x,
+                y,
+                layer.tileset.name(),
+                tile.width(),
+                tile.height(),
+                expected
+            ));
+        }
+        // A layer with smaller tiles than the others is scaled up to theirs
+        if expected < tile_size {
+            return Ok(effects::resize(
+                &tile,
+                tile_size,
+                tile_size,
+                Resample::CatmullRom,
+            ));
+        }
+        Ok(tile)
+    };
+
+    // The common case - a single opaque basemap
+    if let [layer] = layers {
+        if layer.opacity >= 1.0 {
+            return decode(layer);
+        }
+    }
+
+    let mut composite: Option<RgbaImage> = None;
+    for layer in layers {
+        let tile = decode(layer)?;
+        let canvas = composite.get_or_insert_with(|| RgbaImage::new(tile.width(), tile.height()));
+        blend_layer(canvas, &tile, layer.opacity);
+    }
+    Ok(composite.expect("There's at least one layer"))
+}
+
+// Draws a layer over the canvas with the given opacity
+fn blend_layer(canvas: &mut RgbaImage, layer: &RgbaImage, opacity: f32) {
+    for (x, y, pixel) in layer.enumerate_pixels() {
+        if x < canvas.width() && y < canvas.height() {
+            let [r, g, b, a] = pixel.0;
+            let a = (a as f32 * opacity).round() as u8;
+            text::blend(canvas.get_pixel_mut(x, y), &Rgba([r, g, b, a]));
+        }
+    }
+}
+
+// Encodes a rendered image in the format asked for, recording it in the report
+async fn encode_reported(image: RgbaImage, format: Format) -> Result<Bytes> {
+    let started = Instant::now();
+    let encoded = match format {
+        Format::Png => encode_png(image),
+        Format::Avif { quality } => avif::encode(image, quality).await?,
+    };
+    report::phase("encode", started.elapsed());
+    report::bytes_out(encoded.len());
+    Ok(encoded)
+}
+
+// Encodes an image as a PNG, in and tagged with the output color profile
+pub fn encode_png(mut image: RgbaImage) -> Bytes {
+    icc::convert_output(&mut image);
+    Bytes::from(icc::embed_in_png(write_png(image)))
+}
+
+// Encodes a tile as a PNG as it is, in sRGB with no profile, for mosaicking later
+pub(crate) fn encode_tile(image: RgbaImage) -> Bytes {
+    Bytes::from(write_png(image))
+}
+
+fn write_png(image: RgbaImage) -> Vec<u8> {
+    let mut png_buffer = Vec::new();
+    DynamicImage::ImageRgba8(image)
+        .write_to(&mut Cursor::new(&mut png_buffer), image::ImageFormat::Png)
+        .expect("I can write a PNG");
+    png_buffer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coordinates::{lat_long_and_image_size_to_bounding_box, LatLong};
+    use crate::debug_tiles;
+    use crate::fetcher::{FixtureFetcher, MemoryFetcher, TileSources};
+    use image::GenericImageView;
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Tiles recorded from the live servers, so these tests don't depend on them
+    fn fixtures() -> FixtureFetcher {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tiles");
+        FixtureFetcher::from_env(root).unwrap()
+    }
+
+    #[test]
+    fn test_parse_blend() {
+        let layers = parse_blend("osm:1.0, swisstopo:0.5").unwrap();
+        assert_eq!(
+            layers,
+            vec![
+                Layer {
+                    tileset: TileSet::Osm,
+                    opacity: 1.0
+                },
+                Layer {
+                    tileset: TileSet::Swisstopo,
+                    opacity: 0.5
+                },
+            ]
+        );
+        assert_eq!(parse_blend("swisstopo").unwrap()[0].opacity, 1.0);
+
+        assert!(parse_blend("osm:1.5").is_err());
+        assert!(parse_blend("nowhere:0.5").is_err());
+        assert!(parse_blend("osm,osm,osm,osm,osm").is_err());
+        assert!(parse_blend("hybrid,osm:0.5").is_ok());
+        assert!(parse_blend("hybrid,hybrid,hybrid,hybrid").is_err());
+    }
+
+    #[test]
+    fn test_builtin_tilesets() {
+        // The built-in tilesets are the registry's first entries, in ALL's order
+        let names: Vec<&str> = TileSet::ALL.iter().map(TileSet::name).collect();
+        assert_eq!(
+            names,
+            [
+                "osm",
+                "swisstopo",
+                "terrain",
+                "debug",
+                "bing",
+                "mapbox",
+                "esri",
+                "esri_roads",
+                "esri_labels",
+                "hybrid",
+                "stamen_terrain",
+                "stamen_watercolor"
+            ]
+        );
+        // Without their tokens, Mapbox and Stadia's tilesets are disabled
+        let token_free = |t: &TileSet| !t.url_pattern().contains("{token}");
+        let enabled: Vec<TileSet> = TileSet::ALL.into_iter().filter(token_free).collect();
+        assert_eq!(&TileSet::all()[..enabled.len()], &enabled[..]);
+        for tileset in enabled.into_iter().filter(|t| *t != TileSet::Terrain) {
+            assert_eq!(TileSet::lookup(tileset.name()), Some(tileset));
+        }
+        assert!(!TileSet::Mapbox.is_enabled() && TileSet::Osm.is_enabled());
+        assert_eq!(TileSet::lookup("mapbox"), None);
+        assert_eq!(TileSet::lookup("terrain"), None);
+        assert_eq!(TileSet::from_name("nope"), TileSet::Osm);
+        // Tiles are served as PNG or JPEG by their signature unless the tileset says
+        let jpeg = [0xff, 0xd8, 0xff, 0xe0];
+        assert_eq!(TileSet::Bing.tile_content_type(&jpeg), "image/jpeg");
+        assert_eq!(TileSet::Osm.tile_content_type(b"\x89PNG"), "image/png");
+        assert!(TileSet::Bing.is_licensed() && !TileSet::Osm.is_licensed());
+        assert_eq!(TileSet::Hybrid.url_pattern(), "");
+        assert_eq!(
+            TileSet::Esri.attribution(),
+            "Source: Esri, Maxar, Earthstar Geographics"
+        );
+
+        // Providers with @2x endpoints have retina tilesets, fetched at scale=2 and up once
+        // they're enabled along with the tileset they sharpen
+        let retina = TileSet::from_index(registry::lookup("mapbox_2x").unwrap());
+        assert_eq!(
+            TileSet::Mapbox.source().unwrap().retina.as_deref(),
+            Some("mapbox_2x")
+        );
+        assert_eq!(retina.tile_size(), 1024);
+        assert!(retina.url_pattern().contains("/tiles/512/{z}/{x}/{y}@2x?"));
+        assert_eq!(TileSet::Mapbox.retina(), None);
+        assert_eq!(TileSet::Osm.retina(), None);
+        let options = RenderOptions {
+            scale: Some(2.0),
+            ..Default::default()
+        };
+        assert_eq!(options.layers(TileSet::Mapbox)[0].tileset, TileSet::Mapbox);
+    }
+
+    #[test]
+    fn test_output_size() {
+        let mut options = RenderOptions::default();
+        assert_eq!(output_size((1100, 1100), 1024, &options), (1100, 1100));
+
+        options.exact_size = true;
+        assert_eq!(output_size((1100, 1100), 1024, &options), (1024, 1024));
+
+        options.scale = Some(0.5);
+        assert_eq!(output_size((1100, 1100), 1024, &options), (512, 512));
+    }
+
+    #[test]
+    fn test_overlays_only_fetches_nothing() {
+        let options = RenderOptions {
+            overlays_only: true,
+            ..Default::default()
+        };
+        assert!(options.layers(TileSet::Osm).is_empty());
+        assert_eq!(
+            tile_count_for_point(LatLong(46.6, 8.1), 1.0, 512, TileSet::Osm, &options),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_overlays_only_is_transparent() {
+        let options = RenderOptions {
+            overlays_only: true,
+            ..Default::default()
+        };
+        let image = fetch_image_from_point(
+            &MemoryFetcher::default(),
+            LatLong(46.6, 8.1),
+            1.0,
+            256,
+            TileSet::Osm,
+            &options,
+        )
+        .await
+        .unwrap();
+        let image = image::load_from_memory(&image).unwrap().to_rgba8();
+        assert!(image.pixels().all(|p| p[3] == 0));
+    }
+
+    #[tokio::test]
+    async fn test_debug_tiles_line_up_with_viewport() {
+        let center = LatLong(46.655559, 8.102121);
+        let image = fetch_image_from_point(
+            &TileSources::default(),
+            center,
+            1.0,
+            512,
+            TileSet::Debug,
+            &RenderOptions::default(),
+        )
+        .await
+        .unwrap();
+        let image = image::load_from_memory(&image).unwrap().to_rgba8();
+        let ((width, height), viewport) = viewport::image_viewport(center, 1.0, 512, false, None);
+        assert_eq!(image.dimensions(), (width, height));
+
+        // Every tile's left edge is exactly where the viewport puts the tile boundary
+        let tile = debug_tiles::TILE_SIZE as f64;
+        let first = (viewport.origin.0 / tile).ceil() as u32;
+        let last = ((viewport.origin.0 + width as f64) / tile).floor() as u32;
+        assert!(last > first);
+        // A row clear of the tiles' top edges and grid lines
+        let y = ((viewport.origin.1 / tile).ceil() * tile + 100.0 - viewport.origin.1) as u32;
+        assert!(y < height);
+        for tx in first..=last {
+            let x = (tx as f64 * tile - viewport.origin.0) as u32;
+            if x < width {
+                assert_eq!(image.get_pixel(x, y), &debug_tiles::EDGE_COLOR);
+                assert_ne!(image.get_pixel(x + 1, y), &debug_tiles::EDGE_COLOR);
+            }
+        }
+    }
+
+    // Counts the tiles fetched through it
+    struct CountingFetcher {
+        inner: TileSources,
+        fetched: AtomicUsize,
+    }
+
+    impl TileFetcher for CountingFetcher {
+        fn fetch(
+            &self,
+            tileset: TileSet,
+            x: u32,
+            y: u32,
+            z: u32,
+            cx: Context,
+        ) -> futures::future::LocalBoxFuture<'_, Result<Bytes>> {
+            self.fetched.fetch_add(1, Ordering::Relaxed);
+            self.inner.fetch(tileset, x, y, z, cx)
+        }
+    }
+
+    // Holds every fetch back until both tilesets have been asked for a tile, and serves
+    // debug tiles for both
+    #[derive(Default)]
+    struct RendezvousFetcher {
+        inner: TileSources,
+        started: std::sync::Mutex<std::collections::HashSet<&'static str>>,
+    }
+
+    impl TileFetcher for RendezvousFetcher {
+        fn fetch(
+            &self,
+            tileset: TileSet,
+            x: u32,
+            y: u32,
+            z: u32,
+            cx: Context,
+        ) -> futures::future::LocalBoxFuture<'_, Result<Bytes>> {
+            Box::pin(async move {
+                self.started.lock().unwrap().insert(tileset.name());
+                while self.started.lock().unwrap().len() < 2 {
+                    tokio::task::yield_now().await;
+                }
+                self.inner.fetch(TileSet::Debug, x, y, z, cx).await
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_blend_fetches_layers_together() {
+        let fetcher = RendezvousFetcher::default();
+        let options = RenderOptions {
+            blend: parse_blend("osm,debug:0.5").unwrap(),
+            ..Default::default()
+        };
+        let render = fetch_image_from_point(
+            &fetcher,
+            LatLong(46.655559, 8.102121),
+            1.0,
+            256,
+            TileSet::Osm,
+            &options,
+        );
+        // Fetched one after the other, the first layer would wait for the second forever
+        let image = tokio::time::timeout(std::time::Duration::from_secs(10), render)
+            .await
+            .expect("the layers are fetched together")
+            .unwrap();
+        assert!(image::load_from_memory(&image).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_layer_fails_over() {
+        // Osm has every tile but one, esri none, and debug all of them
+        let tile_box =
+            lat_long_and_image_size_to_bounding_box(LatLong(46.655559, 8.102121), 1.0, 256);
+        let top_left = &tile_box.tile_box.top_left;
+        let debug = TileSources::default();
+        let fetcher = MemoryFetcher::default().with_tile(
+            TileSet::Osm,
+            top_left.x as u32,
+            top_left.y as u32,
+            top_left.z,
+            debug
+                .fetch(
+                    TileSet::Debug,
+                    top_left.x as u32,
+                    top_left.y as u32,
+                    top_left.z,
+                    Context::current(),
+                )
+                .await
+                .unwrap(),
+        );
+        let fetcher = TileSources::default()
+            .with_source(TileSet::Osm, Box::new(fetcher))
+            .with_source(TileSet::Esri, Box::new(MemoryFetcher::default()));
+        let layer = Layer {
+            tileset: TileSet::Osm,
+            opacity: 0.5,
+        };
+
+        let sources = vec![TileSet::Osm, TileSet::Esri, TileSet::Debug];
+        let (fetched, collected) =
+            degradations::collect(fetch_layer(&fetcher, layer, sources, &tile_box)).await;
+        let (layer, layer_tiles) = fetched.unwrap();
+        assert_eq!(
+            (layer.tileset, layer_tiles.tileset, layer_tiles.opacity),
+            (TileSet::Debug, TileSet::Debug, 0.5)
+        );
+        assert_eq!(
+            layer_tiles.tiles.len() as u32,
+            tile_box.tile_box.tile_count()
+        );
+        assert_eq!(
+            collected
+                .degradations
+                .iter()
+                .map(Degradation::token)
+                .collect::<Vec<_>>(),
+            vec![
+                "failover;tileset=osm;to=esri",
+                "failover;tileset=esri;to=debug"
+            ]
+        );
+
+        // The last source's error fails the layer
+        let sources = vec![TileSet::Osm, TileSet::Esri];
+        assert!(fetch_layer(&fetcher, layer, sources, &tile_box)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_mosaic_larger_tiles() {
+        let tile_box =
+            lat_long_and_image_size_to_bounding_box(LatLong(46.655559, 8.102121), 1.0, 64);
+        let top_left = &tile_box.tile_box.top_left;
+        let bottom_right = &tile_box.tile_box.bottom_right;
+        // Solid tiles, each colored by where it sits, so any misplaced tile shows. Only
+        // the tiles the output overlaps are needed.
+        let layer = |tile_size: u32| {
+            let colors: Vec<Bytes> = (0..4u8)
+                .map(|i| {
+                    let color = Rgba([40 + 160 * (i % 2), 40 + 160 * (i / 2), 90, 255]);
+                    encode_tile(RgbaImage::from_pixel(tile_size, tile_size, color))
+                })
+                .collect();
+            let mut tiles = HashMap::new();
+            for x in top_left.x.floor() as u32..=bottom_right.x.floor() as u32 {
+                for y in top_left.y.floor() as u32..=bottom_right.y.floor() as u32 {
+                    let color = colors[(x % 2 + 2 * (y % 2)) as usize].clone();
+                    tiles.insert((x, y, top_left.z), color);
+                }
+            }
+            LayerTiles {
+                tiles,
+                opacity: 1.0,
+                tileset: TileSet::Debug,
+                tile_size,
+            }
+        };
+
+        // The same tiles at 512px and 1024px come out the same as at 256px, away from the
+        // seams between tiles, which scaling down softens
+        let (expected, _) = mosaic(vec![layer(256)], &tile_box, 1).unwrap();
+        let (width, height) = expected.dimensions();
+        let inside = |x: u32, y: u32| {
+            let pixel = expected.get_pixel(x, y);
+            [(x - 3, y), (x + 3, y), (x, y - 3), (x, y + 3)]
+                .iter()
+                .all(|&(x, y)| expected.get_pixel(x, y) == pixel)
+        };
+        let points: Vec<(u32, u32)> = (4..width - 4)
+            .step_by(10)
+            .flat_map(|x| (4..height - 4).step_by(10).map(move |y| (x, y)))
+            .filter(|&(x, y)| inside(x, y))
+            .collect();
+        assert!(points.len() > 10);
+        for tile_size in [512, 1024] {
+            let (image, _) = mosaic(vec![layer(tile_size)], &tile_box, 1).unwrap();
+            assert_eq!(image.dimensions(), expected.dimensions());
+            for &(x, y) in &points {
+                let (a, b) = (image.get_pixel(x, y), expected.get_pixel(x, y));
+                assert!((0..3).all(|c| a[c].abs_diff(b[c]) < 4), "at {},{}", x, y);
+            }
+        }
+        // Layers with different tile sizes blend
+        let mut overlay = layer(256);
+        overlay.opacity = 0.5;
+        assert!(mosaic(vec![layer(512), overlay], &tile_box, 1).is_ok());
+
+        // Tiles that aren't the size their tileset serves are an error
+        let mut wrong = layer(256);
+        wrong.tile_size = 512;
+        let error = mosaic(vec![wrong], &tile_box, 1).unwrap_err();
+        assert!(format!("{:#}", error).contains("is 256x256, not the 512px"));
+    }
+
+    #[tokio::test]
+    async fn test_mosaic_retina_detail() {
+        let tile_box =
+            lat_long_and_image_size_to_bounding_box(LatLong(46.655559, 8.102121), 1.0, 256);
+        let tiles = fetch_tile_box(
+            &TileSources::default(),
+            TileSet::Debug,
+            &tile_box.tile_box.top_left,
+            &tile_box.tile_box.bottom_right,
+        )
+        .await
+        .unwrap();
+        let solid = |tile_size: u32| {
+            let tile = RgbaImage::from_pixel(tile_size, tile_size, Rgba([200, 40, 40, 255]));
+            let tile = encode_tile(tile);
+            LayerTiles {
+                tiles: tiles.keys().map(|coord| (*coord, tile.clone())).collect(),
+                opacity: 1.0,
+                tileset: TileSet::Debug,
+                tile_size,
+            }
+        };
+
+        let (image, viewport) = mosaic(vec![solid(256)], &tile_box, 1).unwrap();
+        assert_eq!(image.dimensions(), tile_box.inner_size_px);
+
+        // Scaled up output keeps retina tiles' detail, with the viewport to match, and
+        // tiles without it are upsampled. Tiles with more detail than that are scaled down.
+        let (width, height) = tile_box.inner_size_px;
+        for tile_size in [512, 256, 1024] {
+            let (retina, retina_viewport) = mosaic(vec![solid(tile_size)], &tile_box, 2).unwrap();
+            assert_eq!(retina.dimensions(), (width * 2, height * 2));
+            assert_eq!(retina_viewport.scale, viewport.scale * 2.0);
+            assert_eq!(retina.get_pixel(width, height), &Rgba([200, 40, 40, 255]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hybrid() {
+        let center = LatLong(46.655559, 8.102121);
+        let options = RenderOptions::default();
+        let parts: Vec<TileSet> = options
+            .layers(TileSet::Hybrid)
+            .iter()
+            .map(|layer| layer.tileset)
+            .collect();
+        assert_eq!(
+            parts,
+            vec![TileSet::Esri, TileSet::EsriRoads, TileSet::EsriLabels]
+        );
+        assert_eq!(
+            tile_count_for_point(center, 1.0, 256, TileSet::Hybrid, &options),
+            3 * tile_count_for_point(center, 1.0, 256, TileSet::Esri, &options)
+        );
+
+        // Blue imagery with a red road across the middle of every tile, and no labels
+        let source = |image| Box::new(MemoryFetcher::default().with_fallback(encode_tile(image)));
+        let road = RgbaImage::from_fn(256, 256, |_, y| {
+            if (120..136).contains(&y) {
+                Rgba([255, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 0, 0])
+            }
+        });
+        let sources = TileSources::default()
+            .with_source(
+                TileSet::Esri,
+                source(RgbaImage::from_pixel(256, 256, Rgba([0, 0, 255, 255]))),
+            )
+            .with_source(TileSet::EsriRoads, source(road))
+            .with_source(TileSet::EsriLabels, source(RgbaImage::new(256, 256)));
+        let (png, collected) = degradations::collect(fetch_image_from_point(
+            &sources,
+            center,
+            1.0,
+            256,
+            TileSet::Hybrid,
+            &options,
+        ))
+        .await;
+        let image = image::load_from_memory(&png.unwrap()).unwrap().to_rgba8();
+        let [red, blue] =
+            [[255, 0, 0], [0, 0, 255]].map(|color| image.pixels().any(|p| p.0[..3] == color));
+        assert!(red && blue);
+        // The render names the parts that served it
+        assert_eq!(collected.sources, vec!["esri", "esri_roads", "esri_labels"]);
+
+        // Hybrid has no tiles of its own to fetch
+        assert!(sources
+            .fetch(TileSet::Hybrid, 0, 0, 0, Context::new())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_variants_share_one_mosaic() {
+        let center = LatLong(46.655559, 8.102121);
+        let fetcher = CountingFetcher {
+            inner: TileSources::default(),
+            fetched: Default::default(),
+        };
+        let options = RenderOptions::default();
+        let variants = fetch_image_variants_from_point(
+            &fetcher,
+            center,
+            1.0,
+            &[512, 256, 128],
+            TileSet::Debug,
+            &options,
+        )
+        .await
+        .unwrap();
+        let tiles = tile_count_for_point(center, 1.0, 512, TileSet::Debug, &options) as usize;
+        assert_eq!(fetcher.fetched.load(Ordering::Relaxed), tiles);
+
+        // The largest is the image that size would have been on its own
+        let full = fetch_image_from_point(&fetcher, center, 1.0, 512, TileSet::Debug, &options)
+            .await
+            .unwrap();
+        assert_eq!(variants[0], full);
+        let dimensions: Vec<(u32, u32)> = variants
+            .iter()
+            .map(|png| image::load_from_memory(png).unwrap().dimensions())
+            .collect();
+        let (width, height) = dimensions[0];
+        let scaled = |v: u32, by: f64| (v as f64 / by).round() as u32;
+        assert_eq!(dimensions[1], (scaled(width, 2.0), scaled(height, 2.0)));
+        assert_eq!(dimensions[2], (scaled(width, 4.0), scaled(height, 4.0)));
+
+        assert!(fetch_image_variants_from_point(
+            &fetcher,
+            center,
+            1.0,
+            &[],
+            TileSet::Debug,
+            &options
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_render_report() {
+        let center = LatLong(46.655559, 8.102121);
+        let options = RenderOptions::default();
+        let (image, report) = report::collect(fetch_image_from_point(
+            &TileSources::default(),
+            center,
+            1.0,
+            256,
+            TileSet::Debug,
+            &options,
+        ))
+        .await;
+        let image = image.unwrap();
+
+        let tiles = tile_count_for_point(center, 1.0, 256, TileSet::Debug, &options);
+        assert_eq!((report.tiles, report.tiles_fetched), (tiles, tiles));
+        assert_eq!(report.providers, vec!["debug"]);
+        assert!(report.zoom.is_some());
+        assert_eq!(report.bytes_out, image.len() as u64);
+        let phases: Vec<&str> = report.phases.iter().map(|p| p.name).collect();
+        assert_eq!(phases, vec!["fetch", "mosaic", "draw", "encode"]);
+    }
+
+    // Serves debug tiles, except for one tile that always fails
+    struct BadTileFetcher((u32, u32, u32));
+
+    impl TileFetcher for BadTileFetcher {
+        fn fetch(
+            &self,
+            tileset: TileSet,
+            x: u32,
+            y: u32,
+            z: u32,
+            cx: Context,
+        ) -> futures::future::LocalBoxFuture<'_, Result<Bytes>> {
+            if (x, y, z) == self.0 {
+                return Box::pin(async { Err(anyhow!("upstream said 500")) });
+            }
+            Box::pin(async move { TileSources::default().fetch(tileset, x, y, z, cx).await })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_tiles_are_reported() {
+        let center = LatLong(46.655559, 8.102121);
+        let tile_box = lat_long_and_image_size_to_bounding_box(center, 1.0, 256);
+        let (x, y) = tile_box.tile_box.outer_top_left();
+        let z = tile_box.tile_box.top_left.z;
+        let fetcher = BadTileFetcher((x + 1, y, z));
+
+        let options = RenderOptions::default();
+        let (image, report) = report::collect(fetch_image_from_point(
+            &fetcher,
+            center,
+            1.0,
+            256,
+            TileSet::Debug,
+            &options,
+        ))
+        .await;
+        let error = format!("{:#}", image.unwrap_err());
+        let key = format!("{}/{}/{}", z, x + 1, y);
+        let tiles = tile_count_for_point(center, 1.0, 256, TileSet::Debug, &options);
+        assert!(error.contains(&format!(
+            "1 of {} tiles from debug failed, first {}",
+            tiles, key
+        )));
+        assert!(error.contains("upstream said 500"));
+        assert_eq!(report.tiles_failed, 1);
+        assert_eq!(
+            report.failed_tiles,
+            vec![report::FailedTile {
+                tileset: "debug".to_string(),
+                tile: key,
+                reason: "error",
+                error: "upstream said 500".to_string(),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_image_from_memory_tiles() {
+        let red = encode_png(RgbaImage::from_pixel(256, 256, Rgba([255, 0, 0, 255])));
+        let fetcher = MemoryFetcher::default().with_fallback(red);
+        let image = fetch_image_from_point(
+            &fetcher,
+            LatLong(46.6, 8.1),
+            1.0,
+            256,
+            TileSet::Osm,
+            &RenderOptions::default(),
+        )
+        .await
+        .unwrap();
+        let image = image::load_from_memory(&image).unwrap().to_rgba8();
+        assert!(image.width() >= 256);
+        assert!(image.pixels().all(|p| p == &Rgba([255, 0, 0, 255])));
+    }
+
+    #[test]
+    fn test_blend_layer() {
+        let mut canvas = RgbaImage::from_pixel(2, 2, Rgba([0, 0, 0, 255]));
+        let layer = RgbaImage::from_pixel(2, 2, Rgba([255, 255, 255, 255]));
+        blend_layer(&mut canvas, &layer, 0.5);
+        let [r, g, b, a] = canvas.get_pixel(1, 1).0;
+        assert!(r.abs_diff(128) <= 1 && r == g && g == b);
+        assert_eq!(a, 255);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_tile() {
+        let tile = (3366, 2431);
+        let zoom = 12;
+        let cx = Context::current();
+
+        let result = fixtures()
+            .fetch(TileSet::Osm, tile.0, tile.1, zoom, cx)
+            .await;
+
+        // Assert the result is Ok and contains the correct number of bytes
+        assert!(result.is_ok());
+        let bytes = result.unwrap();
+        assert!(bytes.len() > 1000);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_image_from_lat_lon_box() {
+        // Set up the lat/long and radius
+        let point = LatLong(-31.9514, 115.8617);
+        let radius_km = 1.0;
+
+        // Use lat_lon_and_radius_to_tile_box to calculate the bounding box for tiles
+        let tile_box = lat_long_and_image_size_to_bounding_box(point, radius_km, 1024);
+
+        // Generate the image using fetch_image
+        let result = fetch_image(
+            &fixtures(),
+            TileSet::Osm,
+            &tile_box,
+            1024,
+            &RenderOptions::default(),
+        )
+        .await;
+        assert!(result.is_ok(), "Fetching image failed");
+
+        let image_bytes = result.unwrap();
+
+        // Load the image from the bytes to check its dimensions
+        let img = image::load_from_memory(&image_bytes).expect("Failed to load image from bytes");
+
+        // Check that the image is at least 1000x1000 pixels
+        let (width, height) = img.dimensions();
+        assert!(
+            width >= 1000 && height >= 1000,
+            "Generated image is smaller than expected: {}x{}",
+            width,
+            height
+        );
+
+        // Create a temporary directory and file to store the image
+        let dir = env::current_dir().expect("I can get my cwd");
+        let file_path = dir.join("test_image.png");
+
+        // Write the image to the temporary file
+        let mut file = File::create(file_path.clone()).expect("Failed to create temp file");
+        file.write_all(&image_bytes)
+            .expect("Failed to write image to temp file");
+
+        log::debug!("Image saved to: {:?}", file_path);
+    }
+}
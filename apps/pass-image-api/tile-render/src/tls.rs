@@ -0,0 +1,223 @@
+// ! # tls
+// ! Client TLS configuration for upstream tile sources. Public sources only need the
+// ! standard web PKI roots, but internal WMS/WMTS services often insist on mutual TLS.
+// ! For those a client certificate and key (and optionally a private CA) can be set on the
+// ! tileset's entry in TILESETS_CONFIG:
+// !
+// !   client_cert  - PEM certificate chain to present
+// !   client_key   - PEM private key (PKCS#8, RSA or EC)
+// !   ca_cert      - optional PEM bundle of extra roots to trust
+// !
+// ! TILESET_<NAME>_CLIENT_CERT, _CLIENT_KEY and _CA_CERT override them, and are the only
+// ! way to give a built-in tileset an identity.
+// !
+// ! The rustls config built here is for the awc transport, and only compiled with it;
+// ! the reqwest transport reads the same identity and builds its own.
+
+use crate::registry::{self, Source};
+use std::env;
+#[cfg(not(feature = "reqwest-transport"))]
+use {
+    anyhow::{anyhow, Context, Result},
+    log::info,
+    rustls::{Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore},
+    std::collections::HashMap,
+    std::fs::File,
+    std::io::BufReader,
+    std::sync::{Arc, Mutex, OnceLock},
+};
+
+// Where to find the client identity for a tileset
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientIdentity {
+    pub cert_path: String,
+    pub key_path: String,
+    pub ca_path: Option<String>,
+}
+
+impl ClientIdentity {
+    // The identity for a tileset, from its registry entry with the environment
+    // overriding it. Both the certificate and the key have to be set for mTLS to be used.
+    pub fn for_tileset(tileset_name: &str) -> Option<ClientIdentity> {
+        let source = registry::lookup(tileset_name).and_then(registry::source);
+        ClientIdentity::from_vars(source, |suffix| {
+            env::var(format!(
+                "TILESET_{}_{}",
+                tileset_name.to_uppercase(),
+                suffix
+            ))
+            .ok()
+        })
+    }
+
+    fn from_vars(
+        source: Option<&Source>,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Option<ClientIdentity> {
+        let setting = |suffix: &str, field: fn(&Source) -> &Option<String>| {
+            var(suffix).or_else(|| source.and_then(|source| field(source).clone()))
+        };
+
+        Some(ClientIdentity {
+            cert_path: setting("CLIENT_CERT", |s| &s.client_cert)?,
+            key_path: setting("CLIENT_KEY", |s| &s.client_key)?,
+            ca_path: setting("CA_CERT", |s| &s.ca_cert),
+        })
+    }
+}
+
+// Returns the mTLS client config for a tileset, or None if it doesn't use one.
+// Configs are built once and then shared between requests.
+#[cfg(not(feature = "reqwest-transport"))]
+pub fn client_config_for(tileset_name: &str) -> Result<Option<Arc<ClientConfig>>> {
+    static CONFIGS: OnceLock<Mutex<HashMap<String, Arc<ClientConfig>>>> = OnceLock::new();
+
+    let identity = match ClientIdentity::for_tileset(tileset_name) {
+        Some(identity) => identity,
+        None => return Ok(None),
+    };
+
+    let mut configs = CONFIGS.get_or_init(Default::default).lock().unwrap();
+    if let Some(config) = configs.get(tileset_name) {
+        return Ok(Some(config.clone()));
+    }
+
+    let config = client_config(&identity)
+        .with_context(|| format!("building mTLS config for tileset {}", tileset_name))?;
+    info!(
+        "Using client certificate {} for tileset {}",
+        identity.cert_path, tileset_name
+    );
+    configs.insert(tileset_name.to_string(), config.clone());
+    Ok(Some(config))
+}
+
+// Builds a rustls client config that trusts the web PKI (plus any extra CA) and presents
+// the given client certificate.
+#[cfg(not(feature = "reqwest-transport"))]
+pub fn client_config(identity: &ClientIdentity) -> Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+
+    if let Some(ca_path) = &identity.ca_path {
+        let (added, _) = roots.add_parsable_certificates(&read_certs(ca_path)?);
+        if added == 0 {
+            return Err(anyhow!("No usable CA certificates in {}", ca_path));
+        }
+    }
+
+    let certs = read_certs(&identity.cert_path)?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        return Err(anyhow!("No certificates in {}", identity.cert_path));
+    }
+    let key = read_key(&identity.key_path)?;
+
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_single_cert(certs, key)
+        .with_context(|| "configuring client certificate")?;
+
+    Ok(Arc::new(config))
+}
+
+#[cfg(not(feature = "reqwest-transport"))]
+fn open(path: &str) -> Result<BufReader<File>> {
+    let file = File::open(path).with_context(|| format!("opening {}", path))?;
+    Ok(BufReader::new(file))
+}
+
+#[cfg(not(feature = "reqwest-transport"))]
+fn read_certs(path: &str) -> Result<Vec<Vec<u8>>> {
+    rustls_pemfile::certs(&mut open(path)?).with_context(|| format!("reading {}", path))
+}
+
+// Reads the first private key in a PEM file, whatever flavour it is
+#[cfg(not(feature = "reqwest-transport"))]
+fn read_key(path: &str) -> Result<PrivateKey> {
+    let mut reader = open(path)?;
+    while let Some(item) =
+        rustls_pemfile::read_one(&mut reader).with_context(|| format!("reading {}", path))?
+    {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => continue,
+        }
+    }
+    Err(anyhow!("No private key in {}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(config: &str) -> Source {
+        registry::parse(&format!(
+            "[[tileset]]\nname = \"internal\"\nattribution = \"x\"\nurl = \"https://t.test/{{z}}/{{x}}/{{y}}.png\"\n{}",
+            config
+        ))
+        .unwrap()
+        .remove(0)
+    }
+
+    #[test]
+    fn test_no_identity_without_settings() {
+        assert_eq!(ClientIdentity::for_tileset("not-a-tileset"), None);
+        assert_eq!(ClientIdentity::from_vars(Some(&source("")), |_| None), None);
+        // The key has to come from somewhere too
+        let cert_only = source("client_cert = \"/etc/mtls/client.pem\"");
+        assert_eq!(ClientIdentity::from_vars(Some(&cert_only), |_| None), None);
+    }
+
+    #[test]
+    fn test_identity_from_registry_and_env() {
+        let source =
+            source("client_cert = \"/etc/mtls/client.pem\"\nclient_key = \"/etc/mtls/client.key\"");
+        assert_eq!(
+            ClientIdentity::from_vars(Some(&source), |_| None),
+            Some(ClientIdentity {
+                cert_path: "/etc/mtls/client.pem".to_string(),
+                key_path: "/etc/mtls/client.key".to_string(),
+                ca_path: None,
+            })
+        );
+
+        let env = |suffix: &str| match suffix {
+            "CLIENT_KEY" => Some("/run/secrets/client.key".to_string()),
+            "CA_CERT" => Some("/run/secrets/ca.pem".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            ClientIdentity::from_vars(Some(&source), env),
+            Some(ClientIdentity {
+                cert_path: "/etc/mtls/client.pem".to_string(),
+                key_path: "/run/secrets/client.key".to_string(),
+                ca_path: Some("/run/secrets/ca.pem".to_string()),
+            })
+        );
+    }
+
+    #[cfg(not(feature = "reqwest-transport"))]
+    #[test]
+    fn test_missing_files_are_reported() {
+        assert!(client_config_for("not-a-tileset").unwrap().is_none());
+        let identity = ClientIdentity {
+            cert_path: "/nonexistent/client.pem".to_string(),
+            key_path: "/nonexistent/client.key".to_string(),
+            ca_path: None,
+        };
+        let err = client_config(&identity).unwrap_err();
+        assert!(format!("{:#}", err).contains("/nonexistent/client.pem"));
+    }
+}
@@ -0,0 +1,565 @@
+// ! # transport
+// ! The HTTP client used for upstream requests, behind a small trait so it can be swapped
+// ! out. The client is picked by feature: reqwest-transport (the default) works in any
+// ! tokio runtime, and awc-transport uses actix's client, as the service does. If both
+// ! are enabled reqwest wins. Transports only send a single request and read the body;
+// ! redirects, URL checks and tracing are done here and in the url_guard, the same
+// ! whichever client is underneath. Bodies are read up to a limit, which is
+// ! MAX_RESPONSE_BYTES unless the transport is given its own, and a response that
+// ! announces or turns out to be larger is abandoned rather than read into memory.
+// !
+// ! Transports for URLs we don't control, tilesets' and webhooks', are guarded: they
+// ! resolve hosts with url_guard::resolve_public as they connect, so a host that passed
+// ! the url_guard's checks can't be rebound to a private address before the request goes
+// ! out. Plain transports are for services the operator configured, like pass-api.
+
+use crate::tiles::TileSet;
+use crate::tls;
+use crate::url_guard::{self, MAX_RESPONSE_BYTES};
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use futures::future::LocalBoxFuture;
+use http::Method;
+use opentelemetry::trace::{SpanKind, Status, TraceContextExt, Tracer};
+use opentelemetry::{global, Context, KeyValue};
+use std::collections::HashMap;
+
+#[cfg(not(any(feature = "awc-transport", feature = "reqwest-transport")))]
+compile_error!("Enable the awc-transport or reqwest-transport feature");
+
+// A response with its body read, up to the transport's limit
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub location: Option<String>,
+    pub body: Bytes,
+}
+
+impl Response {
+    pub fn is_redirection(&self) -> bool {
+        (300..400).contains(&self.status)
+    }
+}
+
+pub trait Transport {
+    // Sends a request with the given headers and body, without following redirects
+    fn send<'a>(
+        &'a self,
+        method: Method,
+        url: &'a str,
+        headers: &'a [(String, String)],
+        body: Bytes,
+    ) -> LocalBoxFuture<'a, Result<Response>>;
+}
+
+// The guarded transport for a tileset, presenting its client certificate if it has one
+// and reading at most max_body_bytes of each response
+pub fn for_tileset(tileset: TileSet, max_body_bytes: usize) -> Result<Box<dyn Transport>> {
+    #[cfg(feature = "reqwest-transport")]
+    let transport = ReqwestTransport::new(
+        tls::ClientIdentity::for_tileset(tileset.name()).as_ref(),
+        true,
+    )?;
+    #[cfg(not(feature = "reqwest-transport"))]
+    let transport = AwcTransport::new(tls::client_config_for(tileset.name())?, true);
+    Ok(Box::new(transport.with_max_body_bytes(max_body_bytes)))
+}
+
+// Query parameters that carry credentials, such as Mapbox's access_token
+const SECRET_PARAMS: &[&str] = &["access_token", "token", "key", "api_key", "apikey"];
+
+// The URL with the values of any credential query parameters replaced, for span
+// attributes and log lines
+pub fn redact_url(url: &str) -> String {
+    let Some((base, query)) = url.split_once('?') else {
+        return url.to_string();
+    };
+    let params: Vec<String> = query
+        .split('&')
+        .map(|param| match param.split_once('=') {
+            Some((name, _)) if SECRET_PARAMS.contains(&name.to_ascii_lowercase().as_str()) => {
+                format!("{}=REDACTED", name)
+            }
+            _ => param.to_string(),
+        })
+        .collect();
+    format!("{}?{}", base, params.join("&"))
+}
+
+// Fails if a response announces a body larger than the limit, so it can be abandoned
+// before any of it is read
+fn check_content_length(url: &str, content_length: Option<u64>, max: usize) -> Result<()> {
+    match content_length {
+        Some(len) if len > max as u64 => Err(anyhow!(
+            "Response from {} is too large: {} bytes, the limit is {}",
+            url,
+            len,
+            max
+        )),
+        _ => Ok(()),
+    }
+}
+
+// A transport without client certificates
+pub fn plain() -> Result<Box<dyn Transport>> {
+    #[cfg(feature = "reqwest-transport")]
+    let transport = ReqwestTransport::new(None, false)?;
+    #[cfg(not(feature = "reqwest-transport"))]
+    let transport = AwcTransport::new(None, false);
+    Ok(Box::new(transport))
+}
+
+// A guarded transport without client certificates, e.g. for webhooks
+pub fn guarded() -> Result<Box<dyn Transport>> {
+    #[cfg(feature = "reqwest-transport")]
+    let transport = ReqwestTransport::new(None, true)?;
+    #[cfg(not(feature = "reqwest-transport"))]
+    let transport = AwcTransport::new(None, true);
+    Ok(Box::new(transport))
+}
+
+// Sends a GET in a client span, propagating the trace context in the request headers
+pub async fn traced_get(
+    transport: &dyn Transport,
+    url: &str,
+    headers: Vec<(String, String)>,
+    user_agent: &str,
+    cx: Context,
+) -> Result<Response> {
+    traced_send(
+        transport,
+        Method::GET,
+        url,
+        headers,
+        Bytes::new(),
+        user_agent,
+        cx,
+    )
+    .await
+}
+
+// Sends a POST in a client span, as traced_get does
+pub async fn traced_post(
+    transport: &dyn Transport,
+    url: &str,
+    headers: Vec<(String, String)>,
+    body: Bytes,
+    user_agent: &str,
+    cx: Context,
+) -> Result<Response> {
+    traced_send(transport, Method::POST, url, headers, body, user_agent, cx).await
+}
+
+async fn traced_send(
+    transport: &dyn Transport,
+    method: Method,
+    url: &str,
+    mut headers: Vec<(String, String)>,
+    body: Bytes,
+    user_agent: &str,
+    cx: Context,
+) -> Result<Response> {
+    let tracer = global::tracer("http_client");
+    let span = tracer
+        .span_builder(method.to_string())
+        .with_kind(SpanKind::Client)
+        .with_attributes(vec![
+            KeyValue::new("http.request.method", method.to_string()),
+            KeyValue::new("url.full", redact_url(url)),
+        ])
+        .start_with_context(&tracer, &cx);
+    let cx = cx.with_span(span);
+
+    let mut trace_headers: HashMap<String, String> = HashMap::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&cx, &mut trace_headers)
+    });
+    headers.extend(trace_headers);
+    headers.push(("User-Agent".to_string(), user_agent.to_string()));
+
+    let result = transport.send(method, url, &headers, body).await;
+    let span = cx.span();
+    match &result {
+        Ok(response) => {
+            span.set_attribute(KeyValue::new(
+                "http.response.status_code",
+                response.status as i64,
+            ));
+            if response.status >= 400 {
+                span.set_status(Status::Error {
+                    description: format!("status {}", response.status).into(),
+                });
+            }
+        }
+        Err(e) => span.set_status(Status::Error {
+            description: e.to_string().into(),
+        }),
+    }
+    span.end();
+    result
+}
+
+#[cfg(not(feature = "reqwest-transport"))]
+pub struct AwcTransport {
+    client: awc::Client,
+    max_body_bytes: usize,
+}
+
+// Resolves hosts for awc's connector with url_guard::resolve_public
+#[cfg(not(feature = "reqwest-transport"))]
+struct GuardedResolver;
+
+#[cfg(not(feature = "reqwest-transport"))]
+impl actix_tls::connect::Resolve for GuardedResolver {
+    fn lookup<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> LocalBoxFuture<'a, Result<Vec<std::net::SocketAddr>, Box<dyn std::error::Error>>> {
+        Box::pin(async move { Ok(url_guard::resolve_public(host, port).await?) })
+    }
+}
+
+#[cfg(not(feature = "reqwest-transport"))]
+impl AwcTransport {
+    pub fn new(tls: Option<std::sync::Arc<rustls::ClientConfig>>, guarded: bool) -> AwcTransport {
+        use actix_tls::connect::{Connector, Resolver};
+
+        let resolver = if guarded {
+            Resolver::custom(GuardedResolver)
+        } else {
+            Resolver::default()
+        };
+        let connector = awc::Connector::new().connector(Connector::new(resolver).service());
+        let connector = match tls {
+            Some(config) => connector.rustls(config),
+            None => connector,
+        };
+        let client = awc::Client::builder()
+            .connector(connector)
+            .disable_redirects()
+            .finish();
+        AwcTransport {
+            client,
+            max_body_bytes: MAX_RESPONSE_BYTES,
+        }
+    }
+
+    pub fn with_max_body_bytes(mut self, max_body_bytes: usize) -> AwcTransport {
+        self.max_body_bytes = max_body_bytes;
+        self
+    }
+}
+
+#[cfg(not(feature = "reqwest-transport"))]
+impl Transport for AwcTransport {
+    fn send<'a>(
+        &'a self,
+        method: Method,
+        url: &'a str,
+        headers: &'a [(String, String)],
+        body: Bytes,
+    ) -> LocalBoxFuture<'a, Result<Response>> {
+        use awc::http::header::{CONTENT_LENGTH, CONTENT_TYPE, LOCATION};
+
+        Box::pin(async move {
+            // awc is still on http 0.2, so its Method is a different type
+            let method = awc::http::Method::from_bytes(method.as_str().as_bytes())?;
+            let mut request = self.client.request(method, url);
+            for (name, value) in headers {
+                request = request.insert_header((name.as_str(), value.as_str()));
+            }
+            let mut response = request
+                .send_body(body)
+                .await
+                .map_err(|e| anyhow!("Failed to send request to {}: {}", url, e))?;
+            let header = |name| {
+                response
+                    .headers()
+                    .get(name)
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_string)
+            };
+            let (content_type, location) = (header(CONTENT_TYPE), header(LOCATION));
+            let content_length = header(CONTENT_LENGTH).and_then(|len| len.parse().ok());
+            check_content_length(url, content_length, self.max_body_bytes)?;
+            let body = response
+                .body()
+                .limit(self.max_body_bytes)
+                .await
+                .map_err(|e| anyhow!("Failed to read response body from {}: {}", url, e))?;
+            Ok(Response {
+                status: response.status().as_u16(),
+                content_type,
+                location,
+                body,
+            })
+        })
+    }
+}
+
+#[cfg(feature = "reqwest-transport")]
+pub struct ReqwestTransport {
+    client: reqwest::Client,
+    max_body_bytes: usize,
+}
+
+// Resolves hosts for reqwest with url_guard::resolve_public
+#[cfg(feature = "reqwest-transport")]
+struct GuardedResolver;
+
+#[cfg(feature = "reqwest-transport")]
+impl reqwest::dns::Resolve for GuardedResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            // reqwest fills in the port from the URL
+            let addrs = url_guard::resolve_public(name.as_str(), 0).await?;
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+#[cfg(feature = "reqwest-transport")]
+impl ReqwestTransport {
+    pub fn new(identity: Option<&tls::ClientIdentity>, guarded: bool) -> Result<ReqwestTransport> {
+        use anyhow::Context as _;
+
+        let mut builder = reqwest::Client::builder()
+            .use_rustls_tls()
+            .redirect(reqwest::redirect::Policy::none());
+        if guarded {
+            builder = builder.dns_resolver(std::sync::Arc::new(GuardedResolver));
+        }
+        if let Some(identity) = identity {
+            let read =
+                |path: &str| std::fs::read(path).with_context(|| format!("reading {}", path));
+            let mut pem = read(&identity.cert_path)?;
+            pem.extend(read(&identity.key_path)?);
+            builder = builder.identity(reqwest::Identity::from_pem(&pem)?);
+            if let Some(ca_path) = &identity.ca_path {
+                for cert in reqwest::Certificate::from_pem_bundle(&read(ca_path)?)? {
+                    builder = builder.add_root_certificate(cert);
+                }
+            }
+        }
+        Ok(ReqwestTransport {
+            client: builder.build()?,
+            max_body_bytes: MAX_RESPONSE_BYTES,
+        })
+    }
+
+    pub fn with_max_body_bytes(mut self, max_body_bytes: usize) -> ReqwestTransport {
+        self.max_body_bytes = max_body_bytes;
+        self
+    }
+}
+
+#[cfg(feature = "reqwest-transport")]
+impl Transport for ReqwestTransport {
+    fn send<'a>(
+        &'a self,
+        method: Method,
+        url: &'a str,
+        headers: &'a [(String, String)],
+        body: Bytes,
+    ) -> LocalBoxFuture<'a, Result<Response>> {
+        use reqwest::header::{CONTENT_TYPE, LOCATION};
+
+        Box::pin(async move {
+            let mut request = self.client.request(method, url).body(body);
+            for (name, value) in headers {
+                request = request.header(name, value);
+            }
+            let mut response = request
+                .send()
+                .await
+                .map_err(|e| anyhow!("Failed to send request to {}: {}", url, e))?;
+            let header = |name| {
+                response
+                    .headers()
+                    .get(name)
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_string)
+            };
+            let (content_type, location) = (header(CONTENT_TYPE), header(LOCATION));
+            let status = response.status().as_u16();
+            check_content_length(url, response.content_length(), self.max_body_bytes)?;
+
+            let mut body = Vec::new();
+            while let Some(chunk) = response
+                .chunk()
+                .await
+                .map_err(|e| anyhow!("Failed to read response body from {}: {}", url, e))?
+            {
+                if body.len() + chunk.len() > self.max_body_bytes {
+                    return Err(anyhow!(
+                        "Response from {} is too large: the limit is {} bytes",
+                        url,
+                        self.max_body_bytes
+                    ));
+                }
+                body.extend_from_slice(&chunk);
+            }
+            Ok(Response {
+                status,
+                content_type,
+                location,
+                body: Bytes::from(body),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    // Answers every request with a canned response, remembering what it was sent
+    struct Canned {
+        response: Response,
+        sent: RefCell<Vec<(String, String)>>,
+        bodies: RefCell<Vec<(Method, Bytes)>>,
+    }
+
+    impl Canned {
+        fn new(response: Response) -> Canned {
+            Canned {
+                response,
+                sent: RefCell::new(Vec::new()),
+                bodies: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl Transport for Canned {
+        fn send<'a>(
+            &'a self,
+            method: Method,
+            _url: &'a str,
+            headers: &'a [(String, String)],
+            body: Bytes,
+        ) -> LocalBoxFuture<'a, Result<Response>> {
+            self.sent.borrow_mut().extend(headers.iter().cloned());
+            self.bodies.borrow_mut().push((method, body));
+            let response = self.response.clone();
+            Box::pin(async move { Ok(response) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_traced_get_sends_user_agent() {
+        let canned = Canned::new(Response {
+            status: 200,
+            content_type: Some("image/png".to_string()),
+            location: None,
+            body: Bytes::from_static(b"png"),
+        });
+        let response = traced_get(
+            &canned,
+            "https://example.com/1/2/3.png",
+            Vec::new(),
+            "test",
+            Context::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.body, Bytes::from_static(b"png"));
+        assert!(canned
+            .sent
+            .borrow()
+            .contains(&("User-Agent".to_string(), "test".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_traced_post_sends_body_and_headers() {
+        let canned = Canned::new(Response {
+            status: 204,
+            content_type: None,
+            location: None,
+            body: Bytes::new(),
+        });
+        let headers = vec![("Content-Type".to_string(), "application/json".to_string())];
+        let response = traced_post(
+            &canned,
+            "https://example.com/hook",
+            headers,
+            Bytes::from_static(b"{}"),
+            "test",
+            Context::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status, 204);
+        assert_eq!(
+            canned.bodies.borrow()[0],
+            (Method::POST, Bytes::from_static(b"{}"))
+        );
+        let sent = canned.sent.borrow();
+        assert!(sent.contains(&("Content-Type".to_string(), "application/json".to_string())));
+        assert!(sent.contains(&("User-Agent".to_string(), "test".to_string())));
+    }
+
+    #[test]
+    fn test_check_content_length() {
+        let url = "https://example.com/1/2/3.png";
+        assert!(check_content_length(url, None, 10).is_ok());
+        assert!(check_content_length(url, Some(10), 10).is_ok());
+        let err = check_content_length(url, Some(11), 10).unwrap_err();
+        assert!(err.to_string().contains("11 bytes, the limit is 10"));
+    }
+
+    #[cfg(feature = "reqwest-transport")]
+    #[tokio::test]
+    async fn test_guarded_transport_refuses_private_hosts() {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://localhost:{}/",
+            listener.local_addr().unwrap().port()
+        );
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let _ = stream.read(&mut [0; 1024]);
+                let _ = stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n");
+            }
+        });
+
+        // The host is checked as it's connected to, whatever it resolved to before
+        let guarded = ReqwestTransport::new(None, true).unwrap();
+        assert!(guarded
+            .send(Method::GET, &url, &[], Bytes::new())
+            .await
+            .is_err());
+        let plain = ReqwestTransport::new(None, false).unwrap();
+        let response = plain.send(Method::GET, &url, &[], Bytes::new()).await;
+        assert_eq!(response.unwrap().status, 204);
+    }
+
+    #[test]
+    fn test_redact_url() {
+        assert_eq!(
+            redact_url("https://api.mapbox.com/1/2/3?access_token=pk.secret&fresh=true"),
+            "https://api.mapbox.com/1/2/3?access_token=REDACTED&fresh=true"
+        );
+        assert_eq!(
+            redact_url("https://tile.openstreetmap.org/3/4/5.png"),
+            "https://tile.openstreetmap.org/3/4/5.png"
+        );
+    }
+
+    #[test]
+    fn test_is_redirection() {
+        let response = |status| Response {
+            status,
+            content_type: None,
+            location: None,
+            body: Bytes::new(),
+        };
+        assert!(response(302).is_redirection());
+        assert!(!response(200).is_redirection());
+        assert!(!response(404).is_redirection());
+    }
+}
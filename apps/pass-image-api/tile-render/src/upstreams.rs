@@ -0,0 +1,168 @@
+// ! # upstreams
+// ! The live state of each tileset's upstream, for an admin dashboard: how many tiles
+// ! were fetched from it over the last WINDOW, what share of those failed, their p50 and
+// ! p95 latency, and when a fetch from it last succeeded. HttpFetcher records every fetch
+// ! it sends; tiles from the cache, MBTiles files or memory never reach an upstream and
+// ! aren't counted.
+// !
+// ! At most MAX_SAMPLES fetches are kept per tileset, so a busy upstream's figures cover
+// ! its most recent fetches. There are no circuit breakers or rate limits in front of the
+// ! upstreams, so there's no breaker state or budget to report.
+
+use crate::tiles::TileSet;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const WINDOW: Duration = Duration::from_secs(5 * 60);
+const MAX_SAMPLES: usize = 1024;
+
+static UPSTREAMS: OnceLock<Mutex<Upstreams>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    at: Instant,
+    ms: f64,
+    ok: bool,
+}
+
+#[derive(Debug, Default)]
+struct Upstream {
+    samples: VecDeque<Sample>,
+    last_success: Option<SystemTime>,
+}
+
+// Every upstream's recent fetches
+#[derive(Debug, Default)]
+struct Upstreams {
+    upstreams: HashMap<&'static str, Upstream>,
+}
+
+// One upstream's state, as the dashboard shows it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UpstreamSummary {
+    pub tileset: &'static str,
+    // Fetches over the window, and the share of them that failed
+    pub fetches: usize,
+    pub error_rate: f64,
+    // Latency over the window, failures included. None without any fetches
+    pub p50_ms: Option<f64>,
+    pub p95_ms: Option<f64>,
+    // When a fetch last succeeded, in seconds since the epoch
+    pub last_success: Option<u64>,
+}
+
+// The value below which the given share of sorted values fall
+fn percentile(sorted: &[f64], share: f64) -> Option<f64> {
+    let last = sorted.len().checked_sub(1)?;
+    Some(sorted[(last as f64 * share).round() as usize])
+}
+
+impl Upstreams {
+    fn record(&mut self, tileset: TileSet, at: Instant, elapsed: Duration, ok: bool) {
+        let upstream = self.upstreams.entry(tileset.name()).or_default();
+        if upstream.samples.len() == MAX_SAMPLES {
+            upstream.samples.pop_front();
+        }
+        upstream.samples.push_back(Sample {
+            at,
+            ms: elapsed.as_secs_f64() * 1000.0,
+            ok,
+        });
+        if ok {
+            upstream.last_success = Some(SystemTime::now());
+        }
+    }
+
+    fn summary(&mut self, tileset: TileSet, now: Instant) -> UpstreamSummary {
+        let upstream = self.upstreams.entry(tileset.name()).or_default();
+        upstream
+            .samples
+            .retain(|sample| now.saturating_duration_since(sample.at) < WINDOW);
+        let fetches = upstream.samples.len();
+        let failed = upstream.samples.iter().filter(|s| !s.ok).count();
+        let mut latencies: Vec<f64> = upstream.samples.iter().map(|s| s.ms).collect();
+        latencies.sort_by(f64::total_cmp);
+        UpstreamSummary {
+            tileset: tileset.name(),
+            fetches,
+            error_rate: if fetches == 0 {
+                0.0
+            } else {
+                failed as f64 / fetches as f64
+            },
+            p50_ms: percentile(&latencies, 0.5),
+            p95_ms: percentile(&latencies, 0.95),
+            last_success: upstream
+                .last_success
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs()),
+        }
+    }
+}
+
+fn upstreams() -> &'static Mutex<Upstreams> {
+    UPSTREAMS.get_or_init(Default::default)
+}
+
+// Records a fetch sent to a tileset's upstream, and how it went
+pub fn record(tileset: TileSet, elapsed: Duration, ok: bool) {
+    upstreams()
+        .lock()
+        .unwrap()
+        .record(tileset, Instant::now(), elapsed, ok);
+}
+
+// The share of a tileset's fetches over the window that failed
+pub fn error_rate(tileset: TileSet) -> f64 {
+    upstreams()
+        .lock()
+        .unwrap()
+        .summary(tileset, Instant::now())
+        .error_rate
+}
+
+// The state of every tileset that's fetched from an upstream
+pub fn summaries() -> Vec<UpstreamSummary> {
+    let now = Instant::now();
+    let mut upstreams = upstreams().lock().unwrap();
+    TileSet::all()
+        .into_iter()
+        .filter(|tileset| !tileset.url_pattern().is_empty())
+        .map(|tileset| upstreams.summary(tileset, now))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let mut upstreams = Upstreams::default();
+        let start = Instant::now();
+        let empty = upstreams.summary(TileSet::Osm, start);
+        assert_eq!(
+            (empty.fetches, empty.p50_ms, empty.last_success),
+            (0, None, None)
+        );
+
+        for ms in 1..=20 {
+            let ok = ms % 10 != 0;
+            let elapsed = Duration::from_millis(ms);
+            upstreams.record(TileSet::Osm, start, elapsed, ok);
+        }
+        let osm = upstreams.summary(TileSet::Osm, start);
+        assert_eq!(osm.fetches, 20);
+        assert!((osm.error_rate - 0.1).abs() < 1e-9);
+        assert!((osm.p50_ms.unwrap() - 11.0).abs() < 1e-6);
+        assert!((osm.p95_ms.unwrap() - 19.0).abs() < 1e-6);
+        assert!(osm.last_success.is_some());
+
+        // Fetches age out of the window
+        let later = upstreams.summary(TileSet::Osm, start + WINDOW);
+        assert_eq!((later.fetches, later.error_rate), (0, 0.0));
+        assert!(later.last_success.is_some());
+    }
+}
@@ -0,0 +1,317 @@
+// ! # url_guard
+// ! Guards outbound requests to URLs that didn't come from our own source code
+// ! (custom tile templates, marker icons, ...). Before anything goes over the wire we
+// ! check the scheme, resolve the host and refuse to talk to anything that lives on a
+// ! private, loopback or link-local network, so the service can't be used to probe the
+// ! cluster it runs in. Redirects are followed by hand so every hop gets the same checks.
+// !
+// ! Checking a URL up front isn't enough on its own: the host could resolve to a public
+// ! address when it's checked and a private one when the client connects. Guarded
+// ! transports (see transport::guarded) resolve through resolve_public when they connect,
+// ! so the addresses that are checked are the ones that are connected to.
+
+use crate::transport::{self, Response, Transport};
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use http::Uri;
+use log::warn;
+use opentelemetry::Context;
+use std::env;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::OnceLock;
+
+// How many redirects we'll follow before giving up
+pub const MAX_REDIRECTS: u8 = 3;
+
+// The most we'll read from a single guarded response
+pub const MAX_RESPONSE_BYTES: usize = 5 * 1024 * 1024;
+
+// Whether private addresses are allowed at all. This is off unless ALLOW_PRIVATE_UPSTREAMS
+// is set, which is handy when running against a tile server on localhost.
+fn allow_private() -> bool {
+    static ALLOW: OnceLock<bool> = OnceLock::new();
+    *ALLOW.get_or_init(|| {
+        env::var("ALLOW_PRIVATE_UPSTREAMS")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false)
+    })
+}
+
+// Checks that a URL is safe to fetch: http(s) only, and every address the host
+// resolves to has to be publicly routable.
+pub async fn validate_url(url: &str) -> Result<()> {
+    let uri: Uri = url
+        .parse()
+        .map_err(|e| anyhow!("Invalid URL {}: {}", url, e))?;
+
+    let default_port = match uri.scheme_str() {
+        Some("https") => 443,
+        Some("http") => 80,
+        other => {
+            return Err(anyhow!(
+                "Refusing to fetch {}: unsupported scheme {:?}",
+                url,
+                other
+            ))
+        }
+    };
+
+    let host = uri
+        .host()
+        .ok_or_else(|| anyhow!("Refusing to fetch {}: no host", url))?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = uri.port_u16().unwrap_or(default_port);
+
+    if allow_private() {
+        return Ok(());
+    }
+    resolve_public(host, port)
+        .await
+        .map_err(|e| anyhow!("Refusing to fetch {}: {:#}", transport::redact_url(url), e))?;
+    Ok(())
+}
+
+// Resolves a host for a guarded request, failing if any address it resolves to isn't
+// publicly routable, unless private addresses are allowed
+pub async fn resolve_public(host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| anyhow!("Failed to resolve {}: {}", host, e))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(anyhow!("Failed to resolve {}: no addresses", host));
+    }
+    if allow_private() {
+        return Ok(addrs);
+    }
+    if let Some(addr) = addrs.iter().find(|addr| is_forbidden_ip(&addr.ip())) {
+        warn!("Blocked outbound connection to {} ({})", host, addr.ip());
+        return Err(anyhow!("{} is not a public address", addr.ip()));
+    }
+    Ok(addrs)
+}
+
+// Performs a GET against a URL we don't control, validating the target (and the target of
+// any redirect) before each request is sent.
+pub async fn guarded_get(
+    transport: &dyn Transport,
+    url: &str,
+    headers: &[(String, String)],
+    user_agent: &str,
+    cx: Context,
+) -> Result<Response> {
+    let mut url = url.to_string();
+
+    for _ in 0..=MAX_REDIRECTS {
+        validate_url(&url).await?;
+
+        let response =
+            transport::traced_get(transport, &url, headers.to_vec(), user_agent, cx.clone())
+                .await?;
+        if !response.is_redirection() {
+            return Ok(response);
+        }
+
+        // We only follow absolute redirects; anything else is unusual enough for a tile
+        // server that we'd rather fail loudly.
+        url = response
+            .location
+            .filter(|location| is_absolute(location))
+            .ok_or_else(|| anyhow!("Request to {} redirected without a location", url))?;
+    }
+
+    Err(anyhow!(
+        "Too many redirects (more than {}) fetching {}",
+        MAX_REDIRECTS,
+        url
+    ))
+}
+
+// Performs a POST against a URL we don't control, such as a webhook, after validating it.
+// Redirects aren't followed, as a POST body shouldn't be replayed somewhere else.
+pub async fn guarded_post(
+    transport: &dyn Transport,
+    url: &str,
+    headers: Vec<(String, String)>,
+    body: Bytes,
+    user_agent: &str,
+    cx: Context,
+) -> Result<Response> {
+    validate_url(url).await?;
+    transport::traced_post(transport, url, headers, body, user_agent, cx).await
+}
+
+fn is_absolute(location: &str) -> bool {
+    location.starts_with("http://") || location.starts_with("https://")
+}
+
+// Is this an address we should never be talking to?
+fn is_forbidden_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => is_forbidden_ipv4(v4),
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_forbidden_ipv4(&v4),
+            None => is_forbidden_ipv6(v6),
+        },
+    }
+}
+
+fn is_forbidden_ipv4(ip: &Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // 0.0.0.0/8 - "this network"
+        || octets[0] == 0
+        // 100.64.0.0/10 - carrier-grade NAT, commonly used for pod networks
+        || (octets[0] == 100 && (octets[1] & 0xc0) == 64)
+}
+
+fn is_forbidden_ipv6(ip: &Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // fc00::/7 - unique local
+        || (first & 0xfe00) == 0xfc00
+        // fe80::/10 - link local
+        || (first & 0xffc0) == 0xfe80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::LocalBoxFuture;
+    use std::cell::RefCell;
+
+    // Redirects every request to the next URL in line, remembering what was asked for.
+    // Public IP literals keep validate_url from needing DNS.
+    struct Redirecting {
+        requested: RefCell<Vec<String>>,
+    }
+
+    impl Transport for Redirecting {
+        fn send<'a>(
+            &'a self,
+            _method: http::Method,
+            url: &'a str,
+            _headers: &'a [(String, String)],
+            _body: Bytes,
+        ) -> LocalBoxFuture<'a, Result<Response>> {
+            let mut requested = self.requested.borrow_mut();
+            requested.push(url.to_string());
+            let response = Response {
+                status: 302,
+                content_type: None,
+                location: Some(format!("https://8.8.8.8/{}", requested.len())),
+                body: Bytes::new(),
+            };
+            Box::pin(async move { Ok(response) })
+        }
+    }
+
+    #[test]
+    fn test_forbidden_ipv4() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+        ] {
+            assert!(
+                is_forbidden_ip(&ip.parse().unwrap()),
+                "{} should be blocked",
+                ip
+            );
+        }
+        assert!(!is_forbidden_ip(&"8.8.8.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_forbidden_ipv6() {
+        for ip in ["::1", "::", "fd00::1", "fe80::1", "::ffff:10.0.0.1"] {
+            assert!(
+                is_forbidden_ip(&ip.parse().unwrap()),
+                "{} should be blocked",
+                ip
+            );
+        }
+        assert!(!is_forbidden_ip(&"2a00:1450:4001::1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_validate_url_rejects_bad_schemes() {
+        assert!(validate_url("file:///etc/passwd").await.is_err());
+        assert!(validate_url("gopher://example.com/").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_guarded_get_stops_redirecting() {
+        let transport = Redirecting {
+            requested: RefCell::new(Vec::new()),
+        };
+        let err = guarded_get(&transport, "https://8.8.8.8/", &[], "test", Context::new())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Too many redirects"));
+        assert_eq!(
+            transport.requested.borrow().len(),
+            MAX_REDIRECTS as usize + 1
+        );
+        assert_eq!(transport.requested.borrow()[1], "https://8.8.8.8/1");
+    }
+
+    #[tokio::test]
+    async fn test_guarded_post_does_not_follow_redirects() {
+        let transport = Redirecting {
+            requested: RefCell::new(Vec::new()),
+        };
+        let response = guarded_post(
+            &transport,
+            "https://8.8.8.8/hook",
+            Vec::new(),
+            Bytes::from_static(b"{}"),
+            "test",
+            Context::new(),
+        )
+        .await
+        .unwrap();
+        assert!(response.is_redirection());
+        assert_eq!(transport.requested.borrow().len(), 1);
+
+        let err = guarded_post(
+            &transport,
+            "http://10.0.0.1/hook",
+            Vec::new(),
+            Bytes::new(),
+            "test",
+            Context::new(),
+        )
+        .await;
+        assert!(err.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_public() {
+        assert!(resolve_public("localhost", 80).await.is_err());
+        let addrs = resolve_public("8.8.8.8", 443).await.unwrap();
+        assert_eq!(addrs, vec!["8.8.8.8:443".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_validate_url_rejects_private_literals() {
+        assert!(validate_url("http://127.0.0.1:8080/").await.is_err());
+        assert!(validate_url("http://[::1]/").await.is_err());
+        assert!(validate_url("http://169.254.169.254/latest/meta-data")
+            .await
+            .is_err());
+    }
+}
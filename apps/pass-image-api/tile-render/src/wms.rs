@@ -0,0 +1,106 @@
+// ! # wms
+// ! Tilesets served by a WMS 1.3.0 server instead of as XYZ tiles, as many internal geo
+// ! servers only are. TILESET_<NAME>_SOURCE=wms:<url> takes the server's URL along with
+// ! the layers to draw:
+// !
+// !     TILESET_OSM_SOURCE=wms:https://geo.example.com/wms?LAYERS=topo,roads&STYLES=
+// !
+// ! Each tile becomes a GetMap request for its EPSG:3857 bounding box at 256x256, so the
+// ! tiles go through the same HTTP fetch as any other: the cache, integrity checks,
+// ! upstream stats and the mosaic. FORMAT defaults to image/png and STYLES to the layers'
+// ! default styles. SERVICE, VERSION, REQUEST, CRS, BBOX, WIDTH and HEIGHT are filled in
+// ! for each tile, replacing any in the URL. A server that answers with a
+// ! ServiceException rather than an image fails the content type check like any other
+// ! tile that isn't one.
+
+use crate::coordinates::tile_to_mercator_bbox;
+use anyhow::{anyhow, Result};
+
+// The placeholder in a GetMap pattern that each tile's bounding box is filled in for
+pub const BBOX_PLACEHOLDER: &str = "{bbox}";
+
+// Parameters set for every GetMap request, whatever the configured URL has. SRS is 1.1's
+// name for CRS.
+const FILLED_IN: [&str; 8] = [
+    "SERVICE", "VERSION", "REQUEST", "CRS", "SRS", "BBOX", "WIDTH", "HEIGHT",
+];
+
+// Builds the GetMap URL pattern for a WMS server's URL, with a {bbox} placeholder for
+// tile_url to fill in
+pub fn getmap_pattern(url: &str) -> Result<String> {
+    let (base, query) = url.split_once('?').unwrap_or((url, ""));
+    if !base.starts_with("https://") && !base.starts_with("http://") {
+        return Err(anyhow!("WMS URL {} must be http or https", url));
+    }
+    let params: Vec<(&str, &str)> = query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| param.split_once('=').unwrap_or((param, "")))
+        .filter(|(name, _)| !FILLED_IN.iter().any(|f| f.eq_ignore_ascii_case(name)))
+        .collect();
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| *value)
+    };
+    if param("LAYERS").unwrap_or_default().is_empty() {
+        return Err(anyhow!("WMS URL {} needs a LAYERS parameter", url));
+    }
+
+    let mut pattern = format!(
+        "{}?SERVICE=WMS&VERSION=1.3.0&REQUEST=GetMap&CRS=EPSG:3857&BBOX={}&WIDTH=256&HEIGHT=256",
+        base, BBOX_PLACEHOLDER
+    );
+    if param("FORMAT").is_none() {
+        pattern.push_str("&FORMAT=image/png");
+    }
+    if param("STYLES").is_none() {
+        pattern.push_str("&STYLES=");
+    }
+    for (name, value) in params {
+        pattern.push_str(&format!("&{}={}", name, value));
+    }
+    Ok(pattern)
+}
+
+// The BBOX of a tile's GetMap request. WMS 1.3.0 takes EPSG:3857's axes in the order
+// it defines them, easting then northing, and centimeters are plenty.
+pub fn bbox(x: u32, y: u32, z: u32) -> String {
+    let [min_x, min_y, max_x, max_y] = tile_to_mercator_bbox(x, y, z);
+    format!("{:.2},{:.2},{:.2},{:.2}", min_x, min_y, max_x, max_y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_getmap_pattern() {
+        let pattern = getmap_pattern(
+            "https://geo.example.com/wms?service=WMS&LAYERS=topo,roads&CRS=EPSG:2056&map=/srv/a.map",
+        )
+        .unwrap();
+        assert_eq!(
+            pattern,
+            "https://geo.example.com/wms?SERVICE=WMS&VERSION=1.3.0&REQUEST=GetMap\
+             &CRS=EPSG:3857&BBOX={bbox}&WIDTH=256&HEIGHT=256&FORMAT=image/png&STYLES=\
+             &LAYERS=topo,roads&map=/srv/a.map"
+        );
+        let jpeg = getmap_pattern("http://wms.test/?LAYERS=a&FORMAT=image/jpeg&STYLES=x").unwrap();
+        assert!(jpeg.ends_with("&HEIGHT=256&LAYERS=a&FORMAT=image/jpeg&STYLES=x"));
+
+        for invalid in [
+            "https://geo.example.com/wms",
+            "https://geo.example.com/wms?LAYERS=",
+            "ftp://geo.example.com/wms?LAYERS=topo",
+        ] {
+            assert!(getmap_pattern(invalid).is_err(), "{}", invalid);
+        }
+
+        assert_eq!(
+            bbox(1, 0, 1),
+            "0.00,0.00,20037508.34,20037508.34".to_string()
+        );
+    }
+}
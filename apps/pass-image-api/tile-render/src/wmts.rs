@@ -0,0 +1,430 @@
+// ! # wmts
+// ! Tilesets added by a WMTS server's GetCapabilities URL, so any national mapping agency
+// ! that publishes Web Mercator tiles can be used without working out its tile URLs:
+// !
+// !     TILESET_OSM_SOURCE=wmts:https://wmts.example.gov/1.0.0/WMTSCapabilities.xml#topo
+// !
+// ! The part after # picks the layer; without it the capabilities must have only one.
+// ! The capabilities are fetched once at startup and the layer's tile URL pattern worked
+// ! out from them: the first of its tile matrix sets that's Web Mercator with 256px tiles
+// ! (one tile at its first matrix, then twice as many each way at every level), PNG if
+// ! it's offered and JPEG otherwise, its default style and dimension values, and the REST
+// ! ResourceURL template or else the KVP GetTile endpoint. From then on the tileset is
+// ! fetched over HTTP like a TILESET_<NAME>_URL override, so it's cached and checked like
+// ! any other upstream. Matrix identifiers have to end in the zoom, like 14 or
+// ! EPSG:3857:14, which covers the sets agencies publish for web maps.
+
+use crate::tiles::TileSet;
+use crate::{transport, url_guard};
+use anyhow::{anyhow, Context as _, Result};
+use log::info;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::collections::HashMap;
+use std::env;
+use std::sync::OnceLock;
+use tile_geometry::coordinates::MERCATOR_HALF_WIDTH_M;
+
+// Capabilities documents list every layer, and national agencies have hundreds
+const MAX_CAPABILITIES_BYTES: usize = 64 * 1024 * 1024;
+
+static PATTERNS: OnceLock<HashMap<&'static str, String>> = OnceLock::new();
+
+// Just enough of an XML element to find our way around a capabilities document.
+// Names are local, without their namespace prefix.
+#[derive(Debug, Default)]
+struct Element {
+    name: String,
+    attributes: Vec<(String, String)>,
+    text: String,
+    children: Vec<Element>,
+}
+
+impl Element {
+    fn start(start: &BytesStart) -> Result<Element> {
+        let mut attributes = Vec::new();
+        for attribute in start.attributes() {
+            let attribute = attribute?;
+            let name = String::from_utf8_lossy(attribute.key.local_name().as_ref()).into_owned();
+            attributes.push((name, attribute.unescape_value()?.into_owned()));
+        }
+        Ok(Element {
+            name: String::from_utf8_lossy(start.local_name().as_ref()).into_owned(),
+            attributes,
+            ..Default::default()
+        })
+    }
+
+    fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> {
+        self.children.iter().filter(move |child| child.name == name)
+    }
+
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    // The text of the named child, e.g. an ows:Identifier
+    fn text_of(&self, name: &str) -> Option<&str> {
+        self.child(name).map(|child| child.text.trim())
+    }
+
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+fn parse_xml(xml: &str) -> Result<Element> {
+    let mut reader = Reader::from_str(xml);
+    let mut stack = vec![Element::default()];
+    loop {
+        match reader.read_event()? {
+            Event::Start(start) => stack.push(Element::start(&start)?),
+            Event::Empty(start) => {
+                let element = Element::start(&start)?;
+                stack
+                    .last_mut()
+                    .expect("there's a root")
+                    .children
+                    .push(element);
+            }
+            Event::End(_) => {
+                let element = stack.pop().expect("the reader checks tags match");
+                let parent = stack.last_mut().ok_or_else(|| anyhow!("Unbalanced XML"))?;
+                parent.children.push(element);
+            }
+            Event::Text(text) => {
+                let element = stack.last_mut().expect("there's a root");
+                element.text.push_str(&text.unescape()?);
+            }
+            Event::CData(data) => {
+                let element = stack.last_mut().expect("there's a root");
+                element.text.push_str(&String::from_utf8_lossy(&data));
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    let document = stack.pop().filter(|_| stack.is_empty());
+    document
+        .and_then(|mut document| document.children.pop())
+        .ok_or_else(|| anyhow!("Unbalanced XML"))
+}
+
+// The prefix of a Web Mercator tile matrix set's matrix identifiers, which are the prefix
+// followed by the zoom, or why the set can't be used
+fn mercator_prefix(set: &Element) -> Result<String> {
+    let crs = set.text_of("SupportedCRS").unwrap_or_default();
+    if !crs.ends_with(":3857") && !crs.ends_with(":900913") {
+        return Err(anyhow!("its CRS is {}, not EPSG:3857", crs));
+    }
+    let mut prefix = None;
+    for matrix in set.children("TileMatrix") {
+        let id = matrix.text_of("Identifier").unwrap_or_default();
+        let number = |name: &str| -> Option<f64> { matrix.text_of(name)?.parse().ok() };
+        if number("TileWidth") != Some(256.0) || number("TileHeight") != Some(256.0) {
+            return Err(anyhow!("matrix {} doesn't have 256px tiles", id));
+        }
+        let width = number("MatrixWidth").unwrap_or_default();
+        let zoom = width.log2();
+        if zoom.fract() != 0.0 || number("MatrixHeight") != Some(width) {
+            return Err(anyhow!("matrix {} isn't a Web Mercator zoom level", id));
+        }
+        let corner: Vec<f64> = matrix
+            .text_of("TopLeftCorner")
+            .unwrap_or_default()
+            .split_whitespace()
+            .filter_map(|v| v.parse().ok())
+            .collect();
+        let expected = [-MERCATOR_HALF_WIDTH_M, MERCATOR_HALF_WIDTH_M];
+        if corner.len() != 2
+            || corner
+                .iter()
+                .zip(expected)
+                .any(|(c, e)| (c - e).abs() > 1.0)
+        {
+            return Err(anyhow!("matrix {} doesn't start at the world's corner", id));
+        }
+        let Some(matrix_prefix) = id.strip_suffix(&(zoom as u32).to_string()) else {
+            return Err(anyhow!("matrix {} isn't named after its zoom", id));
+        };
+        if *prefix.get_or_insert(matrix_prefix) != matrix_prefix {
+            return Err(anyhow!("its matrices aren't named consistently"));
+        }
+    }
+    prefix
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("it has no matrices"))
+}
+
+// Works out a layer's {z}/{x}/{y} tile URL pattern from a capabilities document. The
+// layer can be left out if it's the only one.
+pub fn tile_pattern(capabilities: &str, layer: Option<&str>) -> Result<String> {
+    let root = parse_xml(capabilities).context("parsing capabilities")?;
+    let contents = root
+        .child("Contents")
+        .ok_or_else(|| anyhow!("The capabilities have no Contents"))?;
+    let layers: Vec<&Element> = contents.children("Layer").collect();
+    let layer = match (layer, layers.as_slice()) {
+        (Some(id), _) => layers
+            .iter()
+            .find(|l| l.text_of("Identifier") == Some(id))
+            .ok_or_else(|| anyhow!("The capabilities have no layer {}", id))?,
+        (None, [only]) => only,
+        (None, _) => {
+            return Err(anyhow!(
+                "The capabilities have {} layers; pick one with #<layer>",
+                layers.len()
+            ))
+        }
+    };
+    let layer_id = layer.text_of("Identifier").unwrap_or_default();
+
+    // The first linked set we can mosaic
+    let mut reasons = Vec::new();
+    let mut found = None;
+    for link in layer.children("TileMatrixSetLink") {
+        let id = link.text_of("TileMatrixSet").unwrap_or_default();
+        let set = contents
+            .children("TileMatrixSet")
+            .find(|set| set.text_of("Identifier") == Some(id));
+        match set.map(mercator_prefix) {
+            Some(Ok(prefix)) => {
+                found = Some((id, prefix));
+                break;
+            }
+            Some(Err(e)) => reasons.push(format!("{}: {}", id, e)),
+            None => reasons.push(format!("{}: it isn't in the capabilities", id)),
+        }
+    }
+    let Some((set_id, prefix)) = found else {
+        return Err(anyhow!(
+            "{} has no Web Mercator tile matrix set with 256px tiles ({})",
+            layer_id,
+            reasons.join("; ")
+        ));
+    };
+
+    let formats: Vec<&str> = layer.children("Format").map(|f| f.text.trim()).collect();
+    let format = ["image/png", "image/jpeg"]
+        .into_iter()
+        .find(|f| formats.contains(f))
+        .ok_or_else(|| anyhow!("{} comes as {:?}, not PNG or JPEG", layer_id, formats))?;
+    let style = layer
+        .children("Style")
+        .find(|s| s.attribute("isDefault") == Some("true"))
+        .or_else(|| layer.child("Style"))
+        .and_then(|s| s.text_of("Identifier"))
+        .unwrap_or("default");
+    let dimensions: Vec<(&str, &str)> = layer
+        .children("Dimension")
+        .filter_map(|d| Some((d.text_of("Identifier")?, d.text_of("Default")?)))
+        .collect();
+    let matrix = format!("{}{{z}}", prefix);
+
+    let resource = layer
+        .children("ResourceURL")
+        .filter(|r| r.attribute("resourceType") == Some("tile"))
+        .find(|r| r.attribute("format") == Some(format))
+        .and_then(|r| r.attribute("template"));
+    let pattern = match resource {
+        Some(template) => {
+            let mut pattern = template
+                .replace("{TileMatrixSet}", set_id)
+                .replace("{TileMatrix}", &matrix)
+                .replace("{TileRow}", "{y}")
+                .replace("{TileCol}", "{x}")
+                .replace("{Style}", style);
+            for (id, default) in &dimensions {
+                pattern = pattern.replace(&format!("{{{}}}", id), default);
+            }
+            pattern
+        }
+        None => {
+            let endpoint = get_tile_endpoint(&root)
+                .ok_or_else(|| anyhow!("{} has neither a ResourceURL nor GetTile", layer_id))?;
+            let endpoint = endpoint.trim_end_matches(['?', '&']);
+            let separator = if endpoint.contains('?') { "&" } else { "?" };
+            let mut pattern = format!(
+                "{}{}SERVICE=WMTS&REQUEST=GetTile&VERSION=1.0.0&LAYER={}&STYLE={}&FORMAT={}\
+                 &TILEMATRIXSET={}&TILEMATRIX={}&TILEROW={{y}}&TILECOL={{x}}",
+                endpoint, separator, layer_id, style, format, set_id, matrix
+            );
+            for (id, default) in &dimensions {
+                pattern.push_str(&format!("&{}={}", id, default));
+            }
+            pattern
+        }
+    };
+    let leftover = pattern
+        .replace("{z}", "")
+        .replace("{x}", "")
+        .replace("{y}", "")
+        .replace("{token}", "");
+    if leftover.contains('{') {
+        return Err(anyhow!("Couldn't fill in every placeholder in {}", pattern));
+    }
+    Ok(pattern)
+}
+
+// Where KVP GetTile requests go, from the capabilities' OperationsMetadata
+fn get_tile_endpoint(root: &Element) -> Option<&str> {
+    root.child("OperationsMetadata")?
+        .children("Operation")
+        .find(|op| op.attribute("name") == Some("GetTile"))?
+        .child("DCP")?
+        .child("HTTP")?
+        .children("Get")
+        .filter_map(|get| get.attribute("href"))
+        .next()
+}
+
+async fn fetch_capabilities(tileset: TileSet, url: &str) -> Result<String> {
+    let transport = transport::for_tileset(tileset, MAX_CAPABILITIES_BYTES)?;
+    let response = url_guard::guarded_get(
+        transport.as_ref(),
+        url,
+        &tileset.headers(),
+        "dd-sdlc-demo",
+        opentelemetry::Context::current(),
+    )
+    .await?;
+    if response.status != 200 {
+        return Err(anyhow!("status {}", response.status));
+    }
+    Ok(String::from_utf8(response.body.to_vec())?)
+}
+
+// Fetches the capabilities of every tileset with a wmts: TILESET_<NAME>_SOURCE and works
+// out its tile URLs. This should be called once at startup, after the registry is loaded
+// and before TileSources::from_env.
+pub async fn init_from_env() -> Result<()> {
+    let mut patterns = HashMap::new();
+    for tileset in TileSet::all() {
+        let var = format!("TILESET_{}_SOURCE", tileset.name().to_uppercase());
+        let Some(source) = env::var(&var)
+            .ok()
+            .and_then(|source| source.strip_prefix("wmts:").map(str::to_string))
+        else {
+            continue;
+        };
+        let (url, layer) = match source.split_once('#') {
+            Some((url, layer)) => (url, Some(layer)),
+            None => (source.as_str(), None),
+        };
+        let capabilities = fetch_capabilities(tileset, url)
+            .await
+            .with_context(|| format!("fetching {}", transport::redact_url(url)))?;
+        let pattern =
+            tile_pattern(&capabilities, layer).with_context(|| format!("Invalid {}", var))?;
+        info!(
+            "Fetching {} tiles from {}",
+            tileset.name(),
+            transport::redact_url(&pattern)
+        );
+        patterns.insert(tileset.name(), pattern);
+    }
+    let _ = PATTERNS.set(patterns);
+    Ok(())
+}
+
+// The tile URL pattern worked out for a wmts: tileset
+pub fn pattern(tileset: TileSet) -> Option<&'static str> {
+    PATTERNS.get()?.get(tileset.name()).map(String::as_str)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A trimmed down capabilities document, in the shape national agencies publish
+    fn capabilities(layer_extra: &str, set_crs: &str) -> String {
+        let matrices: String = (0..3)
+            .map(|z| {
+                format!(
+                    "<TileMatrix><ows:Identifier>{z}</ows:Identifier>\
+                     <ScaleDenominator>1</ScaleDenominator>\
+                     <TopLeftCorner>-20037508.3427892 20037508.3427892</TopLeftCorner>\
+                     <TileWidth>256</TileWidth><TileHeight>256</TileHeight>\
+                     <MatrixWidth>{n}</MatrixWidth><MatrixHeight>{n}</MatrixHeight>\
+                     </TileMatrix>",
+                    z = z,
+                    n = 1 << z
+                )
+            })
+            .collect();
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <Capabilities xmlns="http://www.opengis.net/wmts/1.0"
+                xmlns:ows="http://www.opengis.net/ows/1.1"
+                xmlns:xlink="http://www.w3.org/1999/xlink" version="1.0.0">
+              <ows:OperationsMetadata>
+                <ows:Operation name="GetTile"><ows:DCP><ows:HTTP>
+                  <ows:Get xlink:href="https://wmts.example.gov/kvp?"/>
+                </ows:HTTP></ows:DCP></ows:Operation>
+              </ows:OperationsMetadata>
+              <Contents>
+                <Layer>
+                  <ows:Identifier>topo</ows:Identifier>
+                  <Style isDefault="true"><ows:Identifier>default</ows:Identifier></Style>
+                  <Format>image/jpeg</Format>
+                  <Format>image/png</Format>
+                  <Dimension><ows:Identifier>Time</ows:Identifier><Default>current</Default></Dimension>
+                  <TileMatrixSetLink><TileMatrixSet>lv95</TileMatrixSet></TileMatrixSetLink>
+                  <TileMatrixSetLink><TileMatrixSet>3857</TileMatrixSet></TileMatrixSetLink>
+                  {}
+                </Layer>
+                <Layer><ows:Identifier>aerial</ows:Identifier></Layer>
+                <TileMatrixSet>
+                  <ows:Identifier>lv95</ows:Identifier>
+                  <ows:SupportedCRS>urn:ogc:def:crs:EPSG::2056</ows:SupportedCRS>
+                </TileMatrixSet>
+                <TileMatrixSet>
+                  <ows:Identifier>3857</ows:Identifier>
+                  <ows:SupportedCRS>{}</ows:SupportedCRS>
+                  {}
+                </TileMatrixSet>
+              </Contents>
+            </Capabilities>"#,
+            layer_extra, set_crs, matrices
+        )
+    }
+
+    #[test]
+    fn test_tile_pattern() {
+        let rest = capabilities(
+            r#"<ResourceURL format="image/jpeg" resourceType="tile"
+                template="https://wmts.example.gov/1.0.0/topo/{Style}/{Time}/{TileMatrixSet}/{TileMatrix}/{TileCol}/{TileRow}.jpeg"/>
+               <ResourceURL format="image/png" resourceType="tile"
+                template="https://wmts.example.gov/1.0.0/topo/{Style}/{Time}/{TileMatrixSet}/{TileMatrix}/{TileCol}/{TileRow}.png"/>"#,
+            "urn:ogc:def:crs:EPSG::3857",
+        );
+        assert_eq!(
+            tile_pattern(&rest, Some("topo")).unwrap(),
+            "https://wmts.example.gov/1.0.0/topo/default/current/3857/{z}/{x}/{y}.png"
+        );
+
+        // Without a ResourceURL, tiles come from the KVP endpoint
+        let kvp = capabilities("", "EPSG:3857");
+        assert_eq!(
+            tile_pattern(&kvp, Some("topo")).unwrap(),
+            "https://wmts.example.gov/kvp?SERVICE=WMTS&REQUEST=GetTile&VERSION=1.0.0\
+             &LAYER=topo&STYLE=default&FORMAT=image/png&TILEMATRIXSET=3857&TILEMATRIX={z}\
+             &TILEROW={y}&TILECOL={x}&Time=current"
+        );
+
+        // There are two layers to pick from, and only one usable tile matrix set
+        assert!(tile_pattern(&kvp, None).is_err());
+        assert!(tile_pattern(&kvp, Some("roads")).is_err());
+        let swiss_only = capabilities("", "urn:ogc:def:crs:EPSG::2056");
+        let error = tile_pattern(&swiss_only, Some("topo")).unwrap_err();
+        assert!(
+            format!("{:#}", error).contains("not EPSG:3857"),
+            "{:#}",
+            error
+        );
+        assert!(tile_pattern("<Capabilities><Contents>", None).is_err());
+    }
+}